@@ -0,0 +1,137 @@
+use candid::Principal;
+use ic_cdk_macros::*;
+
+use crate::audit::{self, AuditAction};
+use crate::capacity;
+use crate::i18n;
+use crate::metrics;
+use crate::text_index;
+use crate::validation::{self, normalize_phone, Validator};
+use crate::{User, DRIVERS, SHIPMENTS, USERS};
+
+// Change the caller's contact details. A changed email or phone must be verified again.
+#[update]
+fn update_profile(name: String, email: String, phone: String) -> Result<User, String> {
+    metrics::observe("update_profile", || {
+        let caller = ic_cdk::caller();
+        let mut v = Validator::new();
+        v.required("name", &name, validation::MAX_NAME_LEN);
+        v.email("email", &email);
+        v.phone("phone", &phone);
+        v.finish()?;
+
+        let (user, changed) = USERS.with(|users| {
+            let mut users_map = users.borrow_mut();
+            let user = users_map.get_mut(&caller).ok_or_else(|| "User not registered".to_string())?;
+            let mut changed = Vec::new();
+            if user.name != name {
+                user.name = name;
+                changed.push("name");
+            }
+            if user.email != email {
+                user.email = email;
+                user.email_verified = false;
+                changed.push("email");
+            }
+            if normalize_phone(&user.phone) != normalize_phone(&phone) {
+                user.phone_verified = false;
+                changed.push("phone");
+            }
+            user.phone = phone;
+            Ok::<_, String>((user.clone(), changed))
+        })?;
+        text_index::index_user(&user);
+
+        // Keep the driver record's contact details in step with the account
+        DRIVERS.with(|drivers| {
+            if let Some(driver) = drivers.borrow_mut().get_mut(&caller) {
+                driver.name = user.name.clone();
+                driver.phone = user.phone.clone();
+            }
+        });
+
+        if !changed.is_empty() {
+            audit::record(caller, caller, AuditAction::ProfileUpdated, format!("Changed {}", changed.join(", ")));
+        }
+        Ok(user)
+    })
+}
+
+// Language for the caller's notifications and display strings; None for the default
+#[update]
+fn set_language(lang: Option<String>) -> Result<User, String> {
+    metrics::observe("set_language", || {
+        let caller = ic_cdk::caller();
+        if let Some(lang) = &lang {
+            i18n::validate_lang(lang)?;
+        }
+        USERS.with(|users| {
+            let mut users_map = users.borrow_mut();
+            let user = users_map.get_mut(&caller).ok_or_else(|| "User not registered".to_string())?;
+            user.lang = lang;
+            Ok(user.clone())
+        })
+    })
+}
+
+// Deactivated accounts cannot create shipments and drivers drop out of matching.
+// Drivers must hand over or finish their active shipments first.
+#[update]
+fn deactivate_account(reason: Option<String>) -> Result<User, String> {
+    metrics::observe("deactivate_account", || {
+        let caller = ic_cdk::caller();
+        if let Some(reason) = &reason {
+            let mut v = Validator::new();
+            v.max_len("reason", reason, validation::MAX_TEXT_LEN);
+            v.finish()?;
+        }
+        if has_active_deliveries(caller) {
+            return Err("Cannot deactivate while assigned to active shipments".to_string());
+        }
+
+        let user = set_active(caller, false)?;
+        DRIVERS.with(|drivers| {
+            if let Some(driver) = drivers.borrow_mut().get_mut(&caller) {
+                driver.is_available = false;
+            }
+        });
+        audit::record(caller, caller, AuditAction::AccountDeactivated, reason.unwrap_or_default());
+        Ok(user)
+    })
+}
+
+#[update]
+fn reactivate_account() -> Result<User, String> {
+    metrics::observe("reactivate_account", || {
+        let caller = ic_cdk::caller();
+        let user = set_active(caller, true)?;
+        audit::record(caller, caller, AuditAction::AccountReactivated, String::new());
+        Ok(user)
+    })
+}
+
+// Accounts without a user record (drivers registered before accounts existed) count as active
+pub(crate) fn is_active(principal: &Principal) -> bool {
+    USERS.with(|users| users.borrow().get(principal).is_none_or(|u| u.is_active))
+}
+
+fn set_active(caller: Principal, active: bool) -> Result<User, String> {
+    USERS.with(|users| {
+        let mut users_map = users.borrow_mut();
+        let user = users_map.get_mut(&caller).ok_or_else(|| "User not registered".to_string())?;
+        if user.is_active == active {
+            return Err(if active { "Account is already active" } else { "Account is already deactivated" }.to_string());
+        }
+        user.is_active = active;
+        Ok(user.clone())
+    })
+}
+
+fn has_active_deliveries(driver_id: Principal) -> bool {
+    SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .values()
+            .any(|s| s.driver_id == Some(driver_id) && capacity::is_carrying(&s.status))
+    })
+}
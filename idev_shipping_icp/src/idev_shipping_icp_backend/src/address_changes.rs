@@ -0,0 +1,236 @@
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+
+use crate::event_store::{self, ShipmentEvent};
+use crate::metrics;
+use crate::money::Money;
+use crate::notifications::{self, NotificationKind};
+use crate::payments;
+use crate::permissions::{self, Permission};
+use crate::suspensions::{self, BlockedAction};
+use crate::tracking;
+use crate::validation::Validator;
+use crate::{idempotency, is_recipient, postal_codes, price_shipment, refunds, sla, text_index, zones};
+use crate::{Address, CostLineItem, PaymentStatus, Shipment, ShipmentStatus, TrackingEvent, TrackingEventKind, SHIPMENTS};
+
+// What a paid shipment's sender owes or is owed after the change, in the
+// currency they paid in
+enum Difference {
+    None,
+    Charge(Money),
+    Refund(Money),
+}
+
+// Redirect a shipment that is not out for delivery yet. The shipment is priced
+// again for the new address; a paid shipment's sender is charged the difference,
+// or refunded it when the new address is cheaper. Linked recipients may change
+// the address as long as the price does not go up.
+#[update]
+async fn update_delivery_address(
+    shipment_id: String,
+    new_address: Address,
+    idempotency_key: Option<String>,
+) -> Result<Shipment, String> {
+    metrics::observe_async("update_delivery_address", async move {
+        let caller = ic_cdk::caller();
+        if let Some(shipment) = idempotency::begin(caller, idempotency_key.as_deref(), "update_delivery_address")? {
+            return Ok(shipment);
+        }
+        let result = change(caller, &shipment_id, new_address).await;
+        idempotency::finish(caller, idempotency_key.as_deref(), &result);
+        result
+    })
+    .await
+}
+
+async fn change(caller: Principal, shipment_id: &str, address: Address) -> Result<Shipment, String> {
+    let mut v = Validator::new();
+    v.address("new_address", &address);
+    v.finish()?;
+    let address = postal_codes::normalize(address);
+
+    // One change per shipment at a time, held across the ledger calls
+    let _change = payments::lock(format!("address:{}", shipment_id))
+        .ok_or_else(|| "The delivery address of this shipment is already being changed".to_string())?;
+    let shipment = SHIPMENTS
+        .with(|shipments| shipments.borrow().get(shipment_id).cloned())
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    let is_sender = shipment.sender_id == caller;
+    let is_support = permissions::has(&caller, Permission::UpdateAnyShipment);
+    if !is_sender && !is_support && !is_recipient(&shipment, &caller, None) {
+        return Err("Unauthorized to change the delivery address".to_string());
+    }
+    check_editable(&shipment)?;
+
+    let updated = reprice(&shipment, address)?;
+    if updated.price.amount_e8s > shipment.price.amount_e8s && !is_sender && !is_support {
+        return Err("Only the sender can make a change that raises the price".to_string());
+    }
+    let charged = match difference(&shipment, &updated)? {
+        Difference::None => None,
+        Difference::Charge(amount) => {
+            // The sender's ICRC-2 approval pays for it, so nobody else can
+            if !is_sender {
+                return Err("Only the sender can pay for a change that raises the price".to_string());
+            }
+            Some(charge(caller, shipment_id, amount).await?)
+        },
+        Difference::Refund(amount) => {
+            refunds::refund_difference(caller, shipment_id, amount).await?;
+            None
+        },
+    };
+    apply(caller, shipment_id, &shipment, updated, charged)
+}
+
+fn check_editable(shipment: &Shipment) -> Result<(), String> {
+    if !matches!(
+        shipment.status,
+        ShipmentStatus::Created | ShipmentStatus::PickupScheduled | ShipmentStatus::PickedUp | ShipmentStatus::InTransit
+    ) {
+        return Err("The delivery address can only be changed before the shipment is out for delivery".to_string());
+    }
+    if shipment.pudo_id.is_some() {
+        return Err("Shipment is addressed to a pickup point".to_string());
+    }
+    if shipment.legs.is_some() {
+        return Err("Shipment is routed through hubs".to_string());
+    }
+    match shipment.payment_status {
+        PaymentStatus::Processing => Err("A payment for this shipment is in progress".to_string()),
+        PaymentStatus::Refunded => Err("Shipment has been refunded".to_string()),
+        _ => Ok(()),
+    }
+}
+
+// The shipment as it would be delivered to `address`: the same package, service
+// level and insurance, with the promos and credits it was bought with
+fn reprice(shipment: &Shipment, address: Address) -> Result<Shipment, String> {
+    // Fails for addresses outside every serviced zone
+    let zones = zones::resolve_shipment_zones(&shipment.pickup_address, &address)?;
+    let mut breakdown = price_shipment(
+        &shipment.pickup_address,
+        &address,
+        &shipment.package_details,
+        &zones,
+        &shipment.service_level,
+    );
+    if let Some(policy) = &shipment.insurance {
+        breakdown.charges.push(CostLineItem { label: "Insurance premium".to_string(), amount: policy.premium });
+        breakdown.subtotal = breakdown.subtotal.add(policy.premium);
+        breakdown.total = breakdown.total.add(policy.premium);
+    }
+    // Deductions were redeemed at checkout; they keep applying up to the new total
+    for deduction in &shipment.cost_breakdown.deductions {
+        let amount = deduction.amount.min(breakdown.total);
+        breakdown.total = breakdown.total.saturating_sub(amount);
+        breakdown.deductions.push(CostLineItem { label: deduction.label.clone(), amount });
+    }
+
+    let price = breakdown.total;
+    let sla_deadline = shipment.created_at + sla::target_nanos(&shipment.service_level, &zones);
+    let mut updated = shipment.clone();
+    updated.delivery_address = address;
+    updated.delivery_zone_id = zones.delivery_zone_id;
+    updated.cost_breakdown = breakdown;
+    updated.price = price;
+    updated.cost = price.to_decimal();
+    updated.quoted_price = shipment.exchange_rate.as_ref().map(|rate| rate.convert(price));
+    updated.sla_deadline = sla_deadline;
+    updated.estimated_delivery = Some(sla_deadline);
+    Ok(updated)
+}
+
+// Unpaid and cash on delivery shipments simply take the new price
+fn difference(shipment: &Shipment, updated: &Shipment) -> Result<Difference, String> {
+    let payment = match (&shipment.payment_status, &shipment.payment) {
+        (PaymentStatus::Paid, Some(payment)) => payment,
+        _ => return Ok(Difference::None),
+    };
+    let currency = payment.amount.currency;
+    let before = payments::amount_due(shipment, currency)?;
+    let after = payments::amount_due(updated, currency)?;
+    Ok(if after.amount_e8s > before.amount_e8s {
+        Difference::Charge(after.saturating_sub(before))
+    } else if after.amount_e8s < before.amount_e8s {
+        Difference::Refund(before.saturating_sub(after))
+    } else {
+        Difference::None
+    })
+}
+
+// Collect `amount` into the shipment's escrow; returns the ledger it was paid on
+async fn charge(caller: Principal, shipment_id: &str, amount: Money) -> Result<(Principal, Money), String> {
+    suspensions::check(caller, BlockedAction::Payment)?;
+    // A refund paying out of the escrow meanwhile would miss the new funds
+    let _escrow = payments::lock(payments::escrow_lock_key(shipment_id))
+        .ok_or_else(|| "Escrow is in use by a refund".to_string())?;
+    let ledger = payments::ledger()?;
+    let memo = format!("address:{}", shipment_id).into_bytes();
+    payments::collect(&ledger, caller, payments::escrow_subaccount(shipment_id), amount, memo)
+        .await
+        .map_err(|failure| failure.message())?;
+    Ok((ledger.canister_id, amount))
+}
+
+// Only the fields the change touches are written back; the rest of the shipment
+// may have moved on during the ledger calls
+fn apply(
+    caller: Principal,
+    shipment_id: &str,
+    previous: &Shipment,
+    updated: Shipment,
+    charged: Option<(Principal, Money)>,
+) -> Result<Shipment, String> {
+    let now = time();
+    let shipment = SHIPMENTS.with(|shipments| {
+        let mut shipments = shipments.borrow_mut();
+        let shipment = shipments
+            .get_mut(shipment_id)
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        if let (Some((ledger, amount)), Some(payment)) = (charged, shipment.payment.as_mut()) {
+            payment.ledger.get_or_insert(ledger);
+            payment.amount = payment.amount.add(amount);
+        }
+        shipment.delivery_address = updated.delivery_address;
+        shipment.delivery_zone_id = updated.delivery_zone_id;
+        shipment.cost_breakdown = updated.cost_breakdown;
+        shipment.price = updated.price;
+        shipment.cost = updated.cost;
+        shipment.quoted_price = updated.quoted_price;
+        shipment.sla_deadline = updated.sla_deadline;
+        shipment.estimated_delivery = updated.estimated_delivery;
+        shipment.touch(now);
+        tracking::record(shipment, TrackingEvent {
+            timestamp: now,
+            status: shipment.status.clone(),
+            location: None,
+            description: format!("Delivery address changed to {}", shipment.delivery_address.city),
+            updated_by: caller,
+            kind: Some(TrackingEventKind::AddressChanged {
+                previous_price: previous.price,
+                new_price: shipment.price,
+            }),
+        });
+        let event = ShipmentEvent::DeliveryAddressChanged {
+            address: Box::new(shipment.delivery_address.clone()),
+            delivery_zone_id: shipment.delivery_zone_id.clone(),
+            cost_breakdown: Box::new(shipment.cost_breakdown.clone()),
+            quoted_price: shipment.quoted_price,
+            sla_deadline: shipment.sla_deadline,
+            payment: shipment.payment.clone(),
+            tracking: shipment.tracking_history.last().cloned().expect("tracking event just recorded"),
+        };
+        event_store::record(shipment, caller, event);
+        Ok::<_, String>(shipment.clone())
+    })?;
+    text_index::index_shipment(&shipment);
+    notifications::notify_parties(
+        &shipment,
+        caller,
+        NotificationKind::StatusChange,
+        format!("The delivery address of shipment {} was changed", shipment.id),
+    );
+    Ok(shipment)
+}
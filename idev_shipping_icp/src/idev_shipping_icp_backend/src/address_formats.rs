@@ -0,0 +1,195 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use regex::Regex;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::audit::{self, AuditAction};
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::validation::MAX_ADDRESS_FIELD_LEN;
+use crate::{Address, AddressExtras};
+
+// Keeps compiled patterns small
+const MAX_PATTERN_LEN: usize = 200;
+
+// Parts of an address a country may require beyond street, city and country
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum AddressField {
+    State,
+    PostalCode,
+    Building,
+    Floor,
+    Landmark,
+    What3Words,
+}
+
+// How addresses in one country are written. Addresses in countries without a
+// format only get the generic checks.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AddressFormat {
+    // ISO 3166-1 alpha-2, as addresses carry it
+    pub country: String,
+    pub required_fields: Vec<AddressField>,
+    // Regex the whole postal code must match, e.g. "\d{5}(-\d{4})?"; checked
+    // against the trimmed, upper-cased code
+    pub postal_code_pattern: Option<String>,
+    pub updated_at: u64,
+}
+
+thread_local! {
+    static FORMATS: RefCell<BTreeMap<String, AddressFormat>> = RefCell::new(default_formats());
+    // Compiled postal code patterns, by country
+    static PATTERNS: RefCell<BTreeMap<String, Regex>> = RefCell::new(compile_all(&default_formats()));
+}
+
+fn default_formats() -> BTreeMap<String, AddressFormat> {
+    let format = |country: &str, required_fields: Vec<AddressField>, pattern: &str| AddressFormat {
+        country: country.to_string(),
+        required_fields,
+        postal_code_pattern: Some(pattern.to_string()),
+        updated_at: 0,
+    };
+    [
+        format("US", vec![AddressField::State, AddressField::PostalCode], r"\d{5}(-\d{4})?"),
+        format("CA", vec![AddressField::State, AddressField::PostalCode], r"[A-Z]\d[A-Z] ?\d[A-Z]\d"),
+        format("GB", vec![AddressField::PostalCode], r"[A-Z]{1,2}\d[A-Z\d]? ?\d[A-Z]{2}"),
+        format("DE", vec![AddressField::PostalCode], r"\d{5}"),
+        format("FR", vec![AddressField::PostalCode], r"\d{5}"),
+        format("IN", vec![AddressField::State, AddressField::PostalCode], r"\d{6}"),
+    ]
+    .into_iter()
+    .map(|f| (f.country.clone(), f))
+    .collect()
+}
+
+// Admin configuration; replaces the country's format
+#[update]
+fn set_address_format(format: AddressFormat) -> Result<AddressFormat, String> {
+    metrics::observe("set_address_format", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        let country = format.country.trim().to_uppercase();
+        if country.len() != 2 || !country.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err("Country must be an ISO 3166-1 alpha-2 code".to_string());
+        }
+        let pattern = match &format.postal_code_pattern {
+            Some(pattern) => Some(compile(pattern)?),
+            None => None,
+        };
+        let mut required_fields = format.required_fields;
+        required_fields.dedup();
+
+        let format = AddressFormat {
+            country: country.clone(),
+            required_fields,
+            postal_code_pattern: format.postal_code_pattern,
+            updated_at: time(),
+        };
+        FORMATS.with(|f| f.borrow_mut().insert(country.clone(), format.clone()));
+        PATTERNS.with(|p| match pattern {
+            Some(pattern) => p.borrow_mut().insert(country.clone(), pattern),
+            None => p.borrow_mut().remove(&country),
+        });
+        audit::record(caller, caller, AuditAction::SettingsChanged, format!("Address format for {} set", country));
+        Ok(format)
+    })
+}
+
+#[update]
+fn remove_address_format(country: String) -> Result<(), String> {
+    metrics::observe("remove_address_format", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        let country = country.trim().to_uppercase();
+        FORMATS
+            .with(|f| f.borrow_mut().remove(&country))
+            .ok_or_else(|| "No address format for this country".to_string())?;
+        PATTERNS.with(|p| p.borrow_mut().remove(&country));
+        audit::record(caller, caller, AuditAction::SettingsChanged, format!("Address format for {} removed", country));
+        Ok(())
+    })
+}
+
+// For address forms
+#[query]
+fn get_address_format(country: String) -> Option<AddressFormat> {
+    FORMATS.with(|f| f.borrow().get(&country.trim().to_uppercase()).cloned())
+}
+
+#[query]
+fn list_address_formats() -> Vec<AddressFormat> {
+    FORMATS.with(|f| f.borrow().values().cloned().collect())
+}
+
+// Field errors for `address` under its country's format, as (field, message)
+pub(crate) fn check(address: &Address) -> Vec<(&'static str, String)> {
+    let country = address.country.trim().to_uppercase();
+    let Some(format) = FORMATS.with(|f| f.borrow().get(&country).cloned()) else {
+        return Vec::new();
+    };
+    let mut errors = Vec::new();
+    let extras = address.extras.clone().unwrap_or_default();
+    for field in &format.required_fields {
+        let (name, value) = match field {
+            AddressField::State => ("state", Some(&address.state)),
+            AddressField::PostalCode => ("postal_code", Some(&address.postal_code)),
+            AddressField::Building => ("extras.building", extras.building.as_ref()),
+            AddressField::Floor => ("extras.floor", extras.floor.as_ref()),
+            AddressField::Landmark => ("extras.landmark", extras.landmark.as_ref()),
+            AddressField::What3Words => ("extras.what3words", extras.what3words.as_ref()),
+        };
+        if value.is_none_or(|v| v.trim().is_empty()) {
+            errors.push((name, format!("is required for addresses in {}", country)));
+        }
+    }
+    let postal_code = address.postal_code.trim().to_uppercase();
+    let mismatch = PATTERNS.with(|p| p.borrow().get(&country).is_some_and(|re| !re.is_match(&postal_code)));
+    if !postal_code.is_empty() && mismatch {
+        errors.push(("postal_code", format!("is not a valid postal code for {}", country)));
+    }
+    errors
+}
+
+// Field errors for the structured extras, whatever the country
+pub(crate) fn check_extras(extras: &AddressExtras) -> Vec<(&'static str, String)> {
+    let mut errors = Vec::new();
+    let fields = [
+        ("extras.building", &extras.building),
+        ("extras.floor", &extras.floor),
+        ("extras.landmark", &extras.landmark),
+        ("extras.what3words", &extras.what3words),
+    ];
+    for (name, value) in fields {
+        if value.as_ref().is_some_and(|v| v.len() > MAX_ADDRESS_FIELD_LEN) {
+            errors.push((name, format!("must be at most {} bytes", MAX_ADDRESS_FIELD_LEN)));
+        }
+    }
+    if let Some(words) = &extras.what3words {
+        if !is_what3words(words) {
+            errors.push(("extras.what3words", "must be three words joined by dots".to_string()));
+        }
+    }
+    errors
+}
+
+// "///filled.count.soap" or "filled.count.soap"
+fn is_what3words(value: &str) -> bool {
+    let words: Vec<&str> = value.trim().trim_start_matches("///").split('.').collect();
+    words.len() == 3 && words.iter().all(|w| !w.is_empty() && w.chars().all(char::is_alphabetic))
+}
+
+fn compile(pattern: &str) -> Result<Regex, String> {
+    if pattern.len() > MAX_PATTERN_LEN {
+        return Err(format!("Postal code pattern must be at most {} bytes", MAX_PATTERN_LEN));
+    }
+    Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| format!("Invalid postal code pattern: {}", e))
+}
+
+fn compile_all(formats: &BTreeMap<String, AddressFormat>) -> BTreeMap<String, Regex> {
+    formats
+        .iter()
+        .filter_map(|(country, f)| Some((country.clone(), compile(f.postal_code_pattern.as_ref()?).ok()?)))
+        .collect()
+}
@@ -0,0 +1,147 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::metrics;
+use crate::validation::{self, Validator};
+use crate::{Address, USERS};
+
+const MAX_SAVED_ADDRESSES: usize = 50;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SavedAddress {
+    pub id: String,
+    pub owner: Principal,
+    // "Home", "Warehouse 2", ...
+    pub label: String,
+    pub address: Address,
+    pub is_default_pickup: bool,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+thread_local! {
+    static ADDRESS_BOOK: RefCell<HashMap<Principal, Vec<SavedAddress>>> = RefCell::new(HashMap::new());
+    static ADDRESS_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Save a new address, or replace the one with `address_id`
+#[update]
+fn save_address(
+    address_id: Option<String>,
+    label: String,
+    address: Address,
+    default_pickup: Option<bool>,
+) -> Result<SavedAddress, String> {
+    metrics::observe("save_address", || {
+        let caller = ic_cdk::caller();
+        if !USERS.with(|users| users.borrow().contains_key(&caller)) {
+            return Err("User not registered".to_string());
+        }
+        let mut v = Validator::new();
+        v.required("label", &label, validation::MAX_NAME_LEN);
+        v.address("address", &address);
+        v.finish()?;
+
+        ADDRESS_BOOK.with(|book| {
+            let mut book = book.borrow_mut();
+            let entries = book.entry(caller).or_default();
+            let now = time();
+            let index = match address_id {
+                Some(id) => {
+                    let index = entries
+                        .iter()
+                        .position(|a| a.id == id)
+                        .ok_or_else(|| "Saved address not found".to_string())?;
+                    let entry = &mut entries[index];
+                    entry.label = label;
+                    entry.address = address;
+                    entry.updated_at = now;
+                    index
+                },
+                None => {
+                    if entries.len() >= MAX_SAVED_ADDRESSES {
+                        return Err(format!("At most {} addresses can be saved", MAX_SAVED_ADDRESSES));
+                    }
+                    let id = ADDRESS_COUNTER.with(|counter| {
+                        let mut c = counter.borrow_mut();
+                        *c += 1;
+                        format!("AD{:06}", *c)
+                    });
+                    entries.push(SavedAddress {
+                        id,
+                        owner: caller,
+                        label,
+                        address,
+                        is_default_pickup: false,
+                        created_at: now,
+                        updated_at: now,
+                    });
+                    entries.len() - 1
+                },
+            };
+            match default_pickup {
+                Some(true) => {
+                    for (i, entry) in entries.iter_mut().enumerate() {
+                        entry.is_default_pickup = i == index;
+                    }
+                },
+                Some(false) => entries[index].is_default_pickup = false,
+                None => {},
+            }
+            Ok(entries[index].clone())
+        })
+    })
+}
+
+#[query]
+fn list_addresses() -> Vec<SavedAddress> {
+    addresses_of(ic_cdk::caller())
+}
+
+#[update]
+fn delete_address(address_id: String) -> Result<(), String> {
+    metrics::observe("delete_address", || {
+        let caller = ic_cdk::caller();
+        ADDRESS_BOOK.with(|book| {
+            let mut book = book.borrow_mut();
+            let entries = book.get_mut(&caller).ok_or_else(|| "Saved address not found".to_string())?;
+            let before = entries.len();
+            entries.retain(|a| a.id != address_id);
+            if entries.len() == before {
+                return Err("Saved address not found".to_string());
+            }
+            Ok(())
+        })
+    })
+}
+
+pub(crate) fn addresses_of(owner: Principal) -> Vec<SavedAddress> {
+    ADDRESS_BOOK.with(|book| book.borrow().get(&owner).cloned().unwrap_or_default())
+}
+
+// One of the owner's saved addresses, by id
+pub(crate) fn resolve(owner: Principal, address_id: &str) -> Result<Address, String> {
+    ADDRESS_BOOK.with(|book| {
+        book.borrow()
+            .get(&owner)
+            .and_then(|entries| entries.iter().find(|a| a.id == address_id))
+            .map(|a| a.address.clone())
+            .ok_or_else(|| format!("Saved address {} not found", address_id))
+    })
+}
+
+pub(crate) fn default_pickup(owner: Principal) -> Option<Address> {
+    ADDRESS_BOOK.with(|book| {
+        book.borrow()
+            .get(&owner)
+            .and_then(|entries| entries.iter().find(|a| a.is_default_pickup))
+            .map(|a| a.address.clone())
+    })
+}
+
+pub(crate) fn clear(owner: Principal) {
+    ADDRESS_BOOK.with(|book| book.borrow_mut().remove(&owner));
+}
@@ -0,0 +1,630 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::events::ShipmentEventKind;
+use crate::money::{Money, BASE_CURRENCY};
+use crate::offers::{self, OfferStatus};
+use crate::permissions::{self, Permission};
+use crate::reattempts::{self, DeliveryException};
+use crate::service_level::ServiceLevel;
+use crate::tracking;
+use crate::{Coordinates, PaymentMethod, PaymentStatus, Shipment, ShipmentStatus, DRIVERS, SHIPMENTS};
+
+const NANOS_PER_DAY: u64 = 86_400_000_000_000;
+const MAX_BUCKETS: u64 = 400;
+// Heatmap cells are kept at this geohash precision (about 150 m) and merged
+// into coarser ones when queried
+const MAX_GEOHASH_PRECISION: u8 = 7;
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum StatsGranularity {
+    Daily,
+    // Weeks start on Monday
+    Weekly,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StatsBucket {
+    // Start of the day or week, in nanoseconds
+    pub period_start: u64,
+    pub created: u32,
+    pub delivered: u32,
+    pub cancelled: u32,
+    // Price of the shipments delivered in the period
+    pub revenue: Money,
+    // From creation to delivery; None when nothing was delivered
+    pub average_delivery_secs: Option<u64>,
+    // Cancelled over created in the period
+    pub cancellation_rate: f64,
+}
+
+// [from, to) in nanoseconds
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ReportPeriod {
+    pub from: u64,
+    pub to: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DriverPerformance {
+    pub driver_id: Principal,
+    // Shipments the driver delivered in the period
+    pub delivery_count: u32,
+    pub on_time_bps: u32,
+    // From assignment to pickup
+    pub average_pickup_latency_secs: Option<u64>,
+    // Accepted over answered or expired offers made in the period
+    pub offers_received: u32,
+    pub acceptance_bps: u32,
+    pub average_rating: f64,
+    // Pickup to delivery, for shipments with coordinates on both ends
+    pub distance_km: f64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HeatmapCell {
+    pub geohash: String,
+    pub center: Coordinates,
+    pub deliveries: u32,
+}
+
+// How long after creation each funnel stage may be reached to count
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FunnelWindows {
+    pub paid_within_secs: u64,
+    pub picked_up_within_secs: u64,
+    pub delivered_within_secs: u64,
+}
+
+impl Default for FunnelWindows {
+    fn default() -> Self {
+        FunnelWindows {
+            paid_within_secs: 24 * 60 * 60,
+            picked_up_within_secs: 2 * 24 * 60 * 60,
+            delivered_within_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+// Each stage counts shipments that also reached the stages before it. Shipments
+// dropping out are counted once, at the first stage they missed.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct FunnelCounts {
+    pub created: u32,
+    pub paid: u32,
+    pub picked_up: u32,
+    pub delivered: u32,
+    pub cancelled: u32,
+    // Failed or returned to sender
+    pub failed: u32,
+    // Still unpaid, and not cancelled, after the payment window
+    pub abandoned_unpaid: u32,
+    // Still open but past the window for their next stage
+    pub overdue: u32,
+    // Still within the window for their next stage
+    pub in_progress: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ZoneFunnel {
+    // None for shipments outside every zone
+    pub zone_id: Option<String>,
+    pub counts: FunnelCounts,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ServiceLevelFunnel {
+    pub service_level: ServiceLevel,
+    pub counts: FunnelCounts,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FunnelReport {
+    pub from: u64,
+    pub to: u64,
+    pub windows: FunnelWindows,
+    pub overall: FunnelCounts,
+    // By delivery zone
+    pub by_zone: Vec<ZoneFunnel>,
+    pub by_service_level: Vec<ServiceLevelFunnel>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ExceptionCount {
+    pub exception: DeliveryException,
+    pub count: u32,
+    // Share of all delivery attempts in the period
+    pub rate_bps: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DriverExceptions {
+    pub driver_id: Principal,
+    pub attempts: u32,
+    pub exceptions: u32,
+    pub rate_bps: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ExceptionReport {
+    pub from: u64,
+    pub to: u64,
+    // Deliveries plus failed attempts
+    pub attempts: u32,
+    pub exceptions: Vec<ExceptionCount>,
+    // Highest exception rate first
+    pub by_driver: Vec<DriverExceptions>,
+}
+
+#[derive(Clone, Copy)]
+enum FunnelOutcome {
+    Delivered,
+    Cancelled,
+    Failed,
+    AbandonedUnpaid,
+    Overdue,
+    InProgress,
+}
+
+// Counters for one day, updated as events are published
+#[derive(Clone, Debug, Default)]
+struct DayRollup {
+    created: u32,
+    delivered: u32,
+    cancelled: u32,
+    revenue_e8s: u128,
+    delivery_secs_total: u64,
+}
+
+thread_local! {
+    // Keyed by days since the epoch
+    static DAILY_ROLLUPS: RefCell<BTreeMap<u64, DayRollup>> = RefCell::new(BTreeMap::new());
+    // Deliveries per day and cell
+    static DELIVERY_CELLS: RefCell<BTreeMap<(u64, String), u32>> = RefCell::new(BTreeMap::new());
+}
+
+// Buckets covering [from, to), empty ones included
+#[query]
+fn get_stats_timeseries(from: u64, to: u64, granularity: StatsGranularity) -> Result<Vec<StatsBucket>, String> {
+    if to <= from {
+        return Err("Time range is empty".to_string());
+    }
+    let days_per_bucket = match granularity {
+        StatsGranularity::Daily => 1,
+        StatsGranularity::Weekly => 7,
+    };
+    let first = bucket_start(from / NANOS_PER_DAY, granularity);
+    let last_day = (to - 1) / NANOS_PER_DAY;
+    if (last_day - first) / days_per_bucket >= MAX_BUCKETS {
+        return Err(format!("At most {} buckets can be requested", MAX_BUCKETS));
+    }
+
+    DAILY_ROLLUPS.with(|rollups| {
+        let rollups = rollups.borrow();
+        let mut buckets = Vec::new();
+        let mut start = first;
+        while start <= last_day {
+            let mut total = DayRollup::default();
+            for (_, day) in rollups.range(start..start + days_per_bucket) {
+                total.created += day.created;
+                total.delivered += day.delivered;
+                total.cancelled += day.cancelled;
+                total.revenue_e8s += day.revenue_e8s;
+                total.delivery_secs_total += day.delivery_secs_total;
+            }
+            buckets.push(StatsBucket {
+                period_start: start * NANOS_PER_DAY,
+                created: total.created,
+                delivered: total.delivered,
+                cancelled: total.cancelled,
+                revenue: Money {
+                    amount_e8s: total.revenue_e8s,
+                    currency: BASE_CURRENCY,
+                },
+                average_delivery_secs: (total.delivered > 0)
+                    .then(|| total.delivery_secs_total / total.delivered as u64),
+                cancellation_rate: if total.created == 0 {
+                    0.0
+                } else {
+                    total.cancelled as f64 / total.created as f64
+                },
+            });
+            start += days_per_bucket;
+        }
+        Ok(buckets)
+    })
+}
+
+// Deliveries in [from, to) per geohash cell, busiest first. Admin only.
+#[query]
+fn get_delivery_heatmap(from: u64, to: u64, precision: u8) -> Result<Vec<HeatmapCell>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ViewReports)?;
+    if to <= from {
+        return Err("Time range is empty".to_string());
+    }
+    if precision == 0 || precision > MAX_GEOHASH_PRECISION {
+        return Err(format!("Precision must be between 1 and {}", MAX_GEOHASH_PRECISION));
+    }
+    let first_day = from / NANOS_PER_DAY;
+    let last_day = (to - 1) / NANOS_PER_DAY;
+    if last_day - first_day >= MAX_BUCKETS {
+        return Err(format!("At most {} days can be requested", MAX_BUCKETS));
+    }
+
+    let mut counts: BTreeMap<String, u32> = BTreeMap::new();
+    DELIVERY_CELLS.with(|cells| {
+        let start = (first_day, String::new());
+        for ((_, geohash), count) in cells.borrow().range(start..).take_while(|((day, _), _)| *day <= last_day) {
+            *counts.entry(geohash[..precision as usize].to_string()).or_default() += count;
+        }
+    });
+    let mut cells: Vec<HeatmapCell> = counts
+        .into_iter()
+        .map(|(geohash, deliveries)| HeatmapCell {
+            center: geohash_center(&geohash),
+            geohash,
+            deliveries,
+        })
+        .collect();
+    cells.sort_by(|a, b| b.deliveries.cmp(&a.deliveries).then_with(|| a.geohash.cmp(&b.geohash)));
+    Ok(cells)
+}
+
+// Shipments created in [from, to), followed through the funnel. Admin only.
+#[query]
+fn get_shipment_funnel(from: u64, to: u64, windows: Option<FunnelWindows>) -> Result<FunnelReport, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ViewReports)?;
+    if to <= from {
+        return Err("Time range is empty".to_string());
+    }
+    let windows = windows.unwrap_or_default();
+
+    let now = time();
+    let mut overall = FunnelCounts::default();
+    let mut by_zone: HashMap<Option<String>, FunnelCounts> = HashMap::new();
+    let mut by_service_level: HashMap<ServiceLevel, FunnelCounts> = HashMap::new();
+    SHIPMENTS.with(|shipments| {
+        for shipment in shipments.borrow().values().filter(|s| s.created_at >= from && s.created_at < to) {
+            let (stages, outcome) = follow(shipment, &windows, now);
+            for counts in [
+                &mut overall,
+                by_zone.entry(shipment.delivery_zone_id.clone()).or_default(),
+                by_service_level.entry(shipment.service_level.clone()).or_default(),
+            ] {
+                count(counts, stages, outcome);
+            }
+        }
+    });
+
+    let mut by_zone: Vec<ZoneFunnel> =
+        by_zone.into_iter().map(|(zone_id, counts)| ZoneFunnel { zone_id, counts }).collect();
+    by_zone.sort_by(|a, b| a.zone_id.cmp(&b.zone_id));
+    let by_service_level = ServiceLevel::ALL
+        .iter()
+        .filter_map(|level| {
+            by_service_level.remove(level).map(|counts| ServiceLevelFunnel {
+                service_level: level.clone(),
+                counts,
+            })
+        })
+        .collect();
+    Ok(FunnelReport {
+        from,
+        to,
+        windows,
+        overall,
+        by_zone,
+        by_service_level,
+    })
+}
+
+// Lifetime figures when no period is given. Drivers see their own; admins anyone's.
+#[query]
+fn get_driver_performance(
+    driver_id: Option<Principal>,
+    period: Option<ReportPeriod>,
+) -> Result<DriverPerformance, String> {
+    let caller = ic_cdk::caller();
+    let driver_id = driver_id.unwrap_or(caller);
+    if driver_id != caller && !permissions::has(&caller, Permission::ViewReports) {
+        return Err("Unauthorized to view driver performance".to_string());
+    }
+    let rating = DRIVERS
+        .with(|drivers| drivers.borrow().get(&driver_id).map(|d| d.rating))
+        .ok_or_else(|| "Driver not found".to_string())?;
+    let (from, to) = period.map_or((0, u64::MAX), |p| (p.from, p.to));
+    if to <= from {
+        return Err("Report period is empty".to_string());
+    }
+
+    let mut delivery_count: u32 = 0;
+    let mut on_time: u32 = 0;
+    let mut distance_km = 0.0;
+    let mut pickup_latencies = Vec::new();
+    SHIPMENTS.with(|shipments| {
+        for shipment in shipments.borrow().values().filter(|s| s.driver_id == Some(driver_id)) {
+            let created_in_period = shipment.created_at >= from && shipment.created_at < to;
+            if let Some(latency) = pickup_latency(shipment).filter(|_| created_in_period) {
+                pickup_latencies.push(latency);
+            }
+            let Some(delivered_at) = shipment.actual_delivery else {
+                continue;
+            };
+            if !matches!(shipment.status, ShipmentStatus::Delivered) || delivered_at < from || delivered_at >= to {
+                continue;
+            }
+            delivery_count += 1;
+            if shipment.estimated_delivery.is_some_and(|target| delivered_at <= target) {
+                on_time += 1;
+            }
+            if let (Some(pickup), Some(delivery)) =
+                (&shipment.pickup_address.coordinates, &shipment.delivery_address.coordinates)
+            {
+                distance_km += pickup.distance_km(delivery);
+            }
+        }
+    });
+
+    let offers = offers::offers_to(&driver_id, from, to);
+    let answered = offers
+        .iter()
+        .filter(|o| matches!(o.status, OfferStatus::Accepted | OfferStatus::Declined | OfferStatus::Expired))
+        .count() as u32;
+    let accepted = offers.iter().filter(|o| o.status == OfferStatus::Accepted).count() as u32;
+    Ok(DriverPerformance {
+        driver_id,
+        delivery_count,
+        on_time_bps: (on_time * 10_000).checked_div(delivery_count).unwrap_or(0),
+        average_pickup_latency_secs: (!pickup_latencies.is_empty())
+            .then(|| pickup_latencies.iter().sum::<u64>() / pickup_latencies.len() as u64),
+        offers_received: offers.len() as u32,
+        acceptance_bps: (accepted * 10_000).checked_div(answered).unwrap_or(0),
+        average_rating: rating,
+        distance_km,
+    })
+}
+
+// Delivery exceptions reported in the period, against all attempts made in it.
+// Lifetime figures when no period is given.
+#[query]
+fn get_exception_report(period: Option<ReportPeriod>) -> Result<ExceptionReport, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ViewReports)?;
+    let (from, to) = period.map_or((0, u64::MAX), |p| (p.from, p.to));
+    if to <= from {
+        return Err("Report period is empty".to_string());
+    }
+
+    // Driver -> (attempts, exceptions)
+    let mut drivers: BTreeMap<Principal, (u32, u32)> = BTreeMap::new();
+    let mut deliveries: u32 = 0;
+    SHIPMENTS.with(|shipments| {
+        for shipment in shipments.borrow().values() {
+            if shipment.actual_delivery.is_some_and(|at| at >= from && at < to) {
+                deliveries += 1;
+                if let Some(driver_id) = shipment.driver_id {
+                    drivers.entry(driver_id).or_default().0 += 1;
+                }
+            }
+        }
+    });
+    let failures = reattempts::failures_between(from, to);
+    for failure in &failures {
+        if let Some(driver_id) = failure.driver_id {
+            let entry = drivers.entry(driver_id).or_default();
+            entry.0 += 1;
+            entry.1 += 1;
+        }
+    }
+
+    let attempts = deliveries + failures.len() as u32;
+    let exceptions = DeliveryException::ALL
+        .iter()
+        .map(|&exception| {
+            let count = failures.iter().filter(|f| f.exception == exception).count() as u32;
+            ExceptionCount {
+                exception,
+                count,
+                rate_bps: (count * 10_000).checked_div(attempts).unwrap_or(0),
+            }
+        })
+        .collect();
+    let mut by_driver: Vec<DriverExceptions> = drivers
+        .into_iter()
+        .map(|(driver_id, (attempts, exceptions))| DriverExceptions {
+            driver_id,
+            attempts,
+            exceptions,
+            rate_bps: (exceptions * 10_000).checked_div(attempts).unwrap_or(0),
+        })
+        .collect();
+    by_driver.sort_by(|a, b| b.rate_bps.cmp(&a.rate_bps).then_with(|| b.exceptions.cmp(&a.exceptions)));
+    Ok(ExceptionReport {
+        from,
+        to,
+        attempts,
+        exceptions,
+        by_driver,
+    })
+}
+
+// The number of stages after creation reached in time, and how the shipment ended up
+fn follow(shipment: &Shipment, windows: &FunnelWindows, now: u64) -> (u8, FunnelOutcome) {
+    let first_event = |status: fn(&ShipmentStatus) -> bool| {
+        tracking::with_events(shipment, |events| events.iter().find(|e| status(&e.status)).map(|e| e.timestamp))
+    };
+    // Cash on delivery shipments are paid at the door, so they pass the payment stage
+    let paid_at = match (&shipment.payment, &shipment.payment_method) {
+        (Some(payment), _) => Some(payment.paid_at),
+        (None, Some(PaymentMethod::CashOnDelivery)) => Some(shipment.created_at),
+        _ if matches!(shipment.payment_status, PaymentStatus::Paid | PaymentStatus::Refunded) => {
+            Some(shipment.created_at)
+        },
+        _ => None,
+    };
+    let stages = [
+        (paid_at, windows.paid_within_secs),
+        (first_event(|s| matches!(s, ShipmentStatus::PickedUp)), windows.picked_up_within_secs),
+        (first_event(|s| matches!(s, ShipmentStatus::Delivered)), windows.delivered_within_secs),
+    ];
+
+    let mut reached = 0;
+    for (at, within_secs) in stages {
+        let deadline = shipment.created_at.saturating_add(within_secs.saturating_mul(1_000_000_000));
+        if at.is_some_and(|at| at <= deadline) {
+            reached += 1;
+            continue;
+        }
+        let outcome = match shipment.status {
+            ShipmentStatus::Cancelled => FunnelOutcome::Cancelled,
+            ShipmentStatus::Failed | ShipmentStatus::Returned => FunnelOutcome::Failed,
+            _ if now <= deadline => FunnelOutcome::InProgress,
+            _ if reached == 0 => FunnelOutcome::AbandonedUnpaid,
+            _ => FunnelOutcome::Overdue,
+        };
+        return (reached, outcome);
+    }
+    (reached, FunnelOutcome::Delivered)
+}
+
+fn count(counts: &mut FunnelCounts, stages: u8, outcome: FunnelOutcome) {
+    counts.created += 1;
+    counts.paid += (stages >= 1) as u32;
+    counts.picked_up += (stages >= 2) as u32;
+    counts.delivered += (stages >= 3) as u32;
+    match outcome {
+        FunnelOutcome::Delivered => {},
+        FunnelOutcome::Cancelled => counts.cancelled += 1,
+        FunnelOutcome::Failed => counts.failed += 1,
+        FunnelOutcome::AbandonedUnpaid => counts.abandoned_unpaid += 1,
+        FunnelOutcome::Overdue => counts.overdue += 1,
+        FunnelOutcome::InProgress => counts.in_progress += 1,
+    }
+}
+
+// Seconds from the shipment's pickup being scheduled to it being picked up
+fn pickup_latency(shipment: &Shipment) -> Option<u64> {
+    tracking::with_events(shipment, |history| {
+        let scheduled = history.iter().find(|e| matches!(e.status, ShipmentStatus::PickupScheduled))?;
+        let picked_up = history.iter().find(|e| matches!(e.status, ShipmentStatus::PickedUp))?;
+        Some(picked_up.timestamp.saturating_sub(scheduled.timestamp) / 1_000_000_000)
+    })
+}
+
+// Called for every published shipment event
+pub(crate) fn record_event(shipment: &Shipment, kind: &ShipmentEventKind) {
+    let now = time();
+    match kind {
+        ShipmentEventKind::Created => update_day(now, |day| day.created += 1),
+        ShipmentEventKind::Cancelled => update_day(now, |day| day.cancelled += 1),
+        // A delivery confirmed again after a rejected dispute is not counted twice
+        ShipmentEventKind::Delivered if first_delivery(shipment) => {
+            let delivered_at = shipment.actual_delivery.unwrap_or(now);
+            let secs = delivered_at.saturating_sub(shipment.created_at) / 1_000_000_000;
+            let revenue = if shipment.price.currency == BASE_CURRENCY { shipment.price.amount_e8s } else { 0 };
+            update_day(now, |day| {
+                day.delivered += 1;
+                day.revenue_e8s += revenue;
+                day.delivery_secs_total += secs;
+            });
+            if let Some(coordinates) = &shipment.delivery_address.coordinates {
+                let key = (now / NANOS_PER_DAY, geohash(coordinates, MAX_GEOHASH_PRECISION));
+                DELIVERY_CELLS.with(|cells| *cells.borrow_mut().entry(key).or_default() += 1);
+            }
+        },
+        _ => {},
+    }
+}
+
+// Deliveries and their revenue on the UTC day containing `timestamp`
+pub(crate) fn day_deliveries(timestamp: u64) -> (u32, Money) {
+    let day = DAILY_ROLLUPS.with(|rollups| rollups.borrow().get(&(timestamp / NANOS_PER_DAY)).cloned());
+    let day = day.unwrap_or_default();
+    (
+        day.delivered,
+        Money {
+            amount_e8s: day.revenue_e8s,
+            currency: BASE_CURRENCY,
+        },
+    )
+}
+
+fn first_delivery(shipment: &Shipment) -> bool {
+    tracking::with_events(shipment, |events| {
+        events
+            .iter()
+            .filter(|e| matches!(e.status, ShipmentStatus::Delivered))
+            .count()
+            <= 1
+    })
+}
+
+fn update_day(timestamp: u64, update: impl FnOnce(&mut DayRollup)) {
+    DAILY_ROLLUPS.with(|rollups| update(rollups.borrow_mut().entry(timestamp / NANOS_PER_DAY).or_default()));
+}
+
+// Day number of the first day of the bucket containing `day`
+fn bucket_start(day: u64, granularity: StatsGranularity) -> u64 {
+    match granularity {
+        StatsGranularity::Daily => day,
+        // The epoch fell on a Thursday
+        StatsGranularity::Weekly => day - (day + 3) % 7,
+    }
+}
+
+fn geohash(coordinates: &Coordinates, precision: u8) -> String {
+    let mut lat = (-90.0, 90.0);
+    let mut lon = (-180.0, 180.0);
+    let mut hash = String::with_capacity(precision as usize);
+    let mut even_bit = true;
+    while hash.len() < precision as usize {
+        let mut index = 0;
+        for _ in 0..5 {
+            let (range, value) = if even_bit {
+                (&mut lon, coordinates.longitude)
+            } else {
+                (&mut lat, coordinates.latitude)
+            };
+            let mid = (range.0 + range.1) / 2.0;
+            index <<= 1;
+            if value >= mid {
+                index |= 1;
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even_bit = !even_bit;
+        }
+        hash.push(GEOHASH_ALPHABET[index] as char);
+    }
+    hash
+}
+
+fn geohash_center(hash: &str) -> Coordinates {
+    let mut lat = (-90.0, 90.0);
+    let mut lon = (-180.0, 180.0);
+    let mut even_bit = true;
+    for c in hash.bytes() {
+        let index = GEOHASH_ALPHABET.iter().position(|&a| a == c).unwrap_or(0);
+        for bit in (0..5).rev() {
+            let range = if even_bit { &mut lon } else { &mut lat };
+            let mid = (range.0 + range.1) / 2.0;
+            if (index >> bit) & 1 == 1 {
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even_bit = !even_bit;
+        }
+    }
+    Coordinates {
+        latitude: (lat.0 + lat.1) / 2.0,
+        longitude: (lon.0 + lon.1) / 2.0,
+    }
+}
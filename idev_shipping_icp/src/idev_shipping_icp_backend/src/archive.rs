@@ -0,0 +1,228 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use crate::memory::{self, StableMemory};
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::text_index::{self, EntityKind};
+use crate::tracking;
+use crate::{Shipment, ShipmentStatus, SHIPMENTS, TRACKING_TOKENS};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ArchivePolicy {
+    pub enabled: bool,
+    // Finished shipments untouched for this long move to the archive
+    pub min_age_days: u64,
+    // Shipments moved per timer run, to bound instructions per message
+    pub batch_size: u32,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct ArchiveStats {
+    pub archived_shipments: u64,
+    pub uncompressed_bytes: u64,
+    pub compressed_bytes: u64,
+    pub last_run_at: Option<u64>,
+    pub last_run_archived: u32,
+}
+
+// Heap-side archive bookkeeping carried across upgrades; the archive itself
+// already lives in stable memory
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StableArchiveState {
+    policy: ArchivePolicy,
+    stats: ArchiveStats,
+}
+
+thread_local! {
+    static ARCHIVE_POLICY: RefCell<ArchivePolicy> = RefCell::new(ArchivePolicy {
+        enabled: true,
+        min_age_days: 180,
+        batch_size: 200,
+    });
+    static ARCHIVE_STATS: RefCell<ArchiveStats> = RefCell::new(ArchiveStats::default());
+    // Shipment id -> LZ4-compressed candid encoding of the shipment
+    static ARCHIVE: RefCell<StableBTreeMap<String, Vec<u8>, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::SHIPMENT_ARCHIVE)));
+}
+
+// Admin configuration
+#[update]
+fn set_archive_policy(policy: ArchivePolicy) -> Result<ArchivePolicy, String> {
+    metrics::observe("set_archive_policy", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if policy.min_age_days == 0 || policy.batch_size == 0 {
+            return Err("Archive age and batch size must be positive".to_string());
+        }
+        ARCHIVE_POLICY.with(|p| *p.borrow_mut() = policy.clone());
+        Ok(policy)
+    })
+}
+
+#[query]
+fn get_archive_policy() -> ArchivePolicy {
+    ARCHIVE_POLICY.with(|p| p.borrow().clone())
+}
+
+// Run one archival batch now instead of waiting for the timer
+#[update]
+fn run_archival() -> Result<u32, String> {
+    metrics::observe("run_archival", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        Ok(archive_batch())
+    })
+}
+
+#[query]
+fn get_archived_shipment(shipment_id: String) -> Result<Option<Shipment>, String> {
+    let caller = ic_cdk::caller();
+    let shipment = match load(&shipment_id)? {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    let party = shipment.sender_id == caller
+        || shipment.driver_id == Some(caller)
+        || shipment.recipient_id == Some(caller);
+    if !party && !permissions::has(&caller, Permission::ViewAllShipments) {
+        return Err("Unauthorized to view archived shipment".to_string());
+    }
+    Ok(Some(shipment))
+}
+
+#[query]
+fn get_archive_stats() -> Result<ArchiveStats, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ViewReports)?;
+    Ok(ARCHIVE_STATS.with(|s| s.borrow().clone()))
+}
+
+// Timer job
+pub(crate) fn archive_old_shipments() {
+    if ARCHIVE_POLICY.with(|p| p.borrow().enabled) {
+        archive_batch();
+    }
+}
+
+pub(crate) fn stable_state() -> StableArchiveState {
+    StableArchiveState {
+        policy: ARCHIVE_POLICY.with(|p| p.borrow().clone()),
+        stats: ARCHIVE_STATS.with(|s| s.borrow().clone()),
+    }
+}
+
+pub(crate) fn restore_stable_state(state: StableArchiveState) {
+    ARCHIVE_POLICY.with(|p| *p.borrow_mut() = state.policy);
+    ARCHIVE_STATS.with(|s| *s.borrow_mut() = state.stats);
+}
+
+// Move the oldest eligible shipments out of the heap, oldest first
+fn archive_batch() -> u32 {
+    let now = time();
+    let policy = ARCHIVE_POLICY.with(|p| p.borrow().clone());
+    let cutoff = now.saturating_sub(policy.min_age_days * NANOS_PER_DAY);
+
+    let mut eligible: Vec<(u64, String)> = SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .values()
+            .filter(|s| {
+                s.updated_at < cutoff
+                    && matches!(
+                        s.status,
+                        ShipmentStatus::Delivered | ShipmentStatus::Cancelled | ShipmentStatus::Returned
+                    )
+            })
+            .map(|s| (s.updated_at, s.id.clone()))
+            .collect()
+    });
+    eligible.sort();
+    eligible.truncate(policy.batch_size as usize);
+
+    let mut archived_ids = HashSet::new();
+    let mut uncompressed_bytes = 0;
+    let mut compressed_bytes = 0;
+    for (_, shipment_id) in eligible {
+        let shipment = match SHIPMENTS.with(|shipments| shipments.borrow_mut().remove(&shipment_id)) {
+            Some(s) => tracking::full_record(&s),
+            None => continue,
+        };
+        tracking::forget(&shipment_id);
+        text_index::remove(EntityKind::Shipment, &shipment_id);
+        let encoded = candid::encode_one(&shipment).expect("failed to encode shipment");
+        let compressed = lz4_flex::compress_prepend_size(&encoded);
+        uncompressed_bytes += encoded.len() as u64;
+        compressed_bytes += compressed.len() as u64;
+        ARCHIVE.with(|archive| archive.borrow_mut().insert(shipment_id.clone(), compressed));
+        archived_ids.insert(shipment_id);
+    }
+    TRACKING_TOKENS.with(|tokens| tokens.borrow_mut().retain(|_, id| !archived_ids.contains(id)));
+    let archived = archived_ids.len() as u32;
+
+    ARCHIVE_STATS.with(|stats| {
+        let mut stats = stats.borrow_mut();
+        stats.archived_shipments += archived as u64;
+        stats.uncompressed_bytes += uncompressed_bytes;
+        stats.compressed_bytes += compressed_bytes;
+        stats.last_run_at = Some(now);
+        stats.last_run_archived = archived;
+    });
+    archived
+}
+
+pub(crate) fn len() -> u64 {
+    ARCHIVE.with(|archive| archive.borrow().len())
+}
+
+// Apply `f` to every archived shipment, storing back the ones it changed. Visits
+// the whole archive, so only for rare admin operations.
+pub(crate) fn rewrite(mut f: impl FnMut(&mut Shipment) -> bool) -> u32 {
+    let ids: Vec<String> = ARCHIVE.with(|archive| archive.borrow().iter().map(|(id, _)| id).collect());
+    let mut changed = 0;
+    for id in ids {
+        let mut shipment = match load(&id) {
+            Ok(Some(s)) => s,
+            _ => continue,
+        };
+        if f(&mut shipment) {
+            let encoded = candid::encode_one(&shipment).expect("failed to encode shipment");
+            ARCHIVE.with(|archive| archive.borrow_mut().insert(id, lz4_flex::compress_prepend_size(&encoded)));
+            changed += 1;
+        }
+    }
+    changed
+}
+
+// Compressed entries as stored, for backups
+pub(crate) fn entries() -> Vec<(String, Vec<u8>)> {
+    ARCHIVE.with(|archive| archive.borrow().iter().collect())
+}
+
+pub(crate) fn restore_entries(entries: Vec<(String, Vec<u8>)>) {
+    ARCHIVE.with(|archive| {
+        let mut archive = archive.borrow_mut();
+        for (id, compressed) in entries {
+            archive.insert(id, compressed);
+        }
+    });
+}
+
+pub(crate) fn contains(shipment_id: &str) -> bool {
+    ARCHIVE.with(|archive| archive.borrow().contains_key(&shipment_id.to_string()))
+}
+
+fn load(shipment_id: &str) -> Result<Option<Shipment>, String> {
+    let compressed = match ARCHIVE.with(|archive| archive.borrow().get(&shipment_id.to_string())) {
+        Some(bytes) => bytes,
+        None => return Ok(None),
+    };
+    let encoded = lz4_flex::decompress_size_prepended(&compressed).map_err(|e| e.to_string())?;
+    candid::decode_one(&encoded).map(Some).map_err(|e| e.to_string())
+}
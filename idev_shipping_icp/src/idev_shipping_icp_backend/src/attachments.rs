@@ -0,0 +1,344 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::confirmation;
+use crate::memory::{self, StableMemory};
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::validation::{Validator, MAX_NAME_LEN};
+use crate::{Shipment, DRIVERS, SHIPMENTS};
+
+const MAX_ATTACHMENT_BYTES: u64 = 8 * 1024 * 1024;
+// Stays under the ingress message limit
+const CHUNK_BYTES: usize = 1024 * 1024;
+const MAX_OPEN_UPLOADS: usize = 5;
+const UPLOAD_TTL_NANOS: u64 = 60 * 60 * 1_000_000_000;
+const ALLOWED_CONTENT_TYPES: [&str; 5] = ["image/jpeg", "image/png", "image/webp", "image/heic", "application/pdf"];
+
+// What a file belongs to; decides who may add and read it
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum AttachmentTarget {
+    Shipment(String),
+    Dispute(String),
+    DriverProfile(Principal),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Attachment {
+    pub id: String,
+    pub target: AttachmentTarget,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: u64,
+    // Hex SHA-256 of the content, checked when the upload finishes
+    pub sha256: String,
+    pub chunk_count: u32,
+    pub uploaded_by: Principal,
+    pub uploaded_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UploadSession {
+    pub upload_id: String,
+    pub chunk_bytes: u32,
+    pub expires_at: u64,
+}
+
+struct PendingUpload {
+    attachment: Attachment,
+    data: Vec<u8>,
+    next_chunk: u32,
+    expires_at: u64,
+}
+
+// Metadata carried across upgrades; contents already live in stable memory
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct StableAttachmentState {
+    attachments: Vec<Attachment>,
+    counter: u64,
+}
+
+thread_local! {
+    static ATTACHMENTS: RefCell<BTreeMap<String, Attachment>> = RefCell::new(BTreeMap::new());
+    static UPLOADS: RefCell<HashMap<String, PendingUpload>> = RefCell::new(HashMap::new());
+    static ATTACHMENT_COUNTER: RefCell<u64> = RefCell::new(0);
+    // Attachment id -> content
+    static BLOBS: RefCell<StableBTreeMap<String, Vec<u8>, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::ATTACHMENT_BLOBS)));
+}
+
+// Declare a file up front, then send it with upload_attachment_chunk in order
+#[update]
+fn start_attachment_upload(
+    target: AttachmentTarget,
+    file_name: String,
+    content_type: String,
+    size: u64,
+    sha256: String,
+) -> Result<UploadSession, String> {
+    metrics::observe("start_attachment_upload", || {
+        let caller = ic_cdk::caller();
+        if !can_add(&target, caller) {
+            return Err("Unauthorized to attach files here".to_string());
+        }
+        let mut v = Validator::new();
+        v.max_len("file_name", &file_name, MAX_NAME_LEN);
+        v.finish()?;
+        if file_name.trim().is_empty() {
+            return Err("File name cannot be empty".to_string());
+        }
+        if !ALLOWED_CONTENT_TYPES.contains(&content_type.as_str()) {
+            return Err(format!("Content type must be one of {}", ALLOWED_CONTENT_TYPES.join(", ")));
+        }
+        if size == 0 || size > MAX_ATTACHMENT_BYTES {
+            return Err(format!("Size must be between 1 and {} bytes", MAX_ATTACHMENT_BYTES));
+        }
+        let sha256 = sha256.to_ascii_lowercase();
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err("Invalid SHA-256 hash".to_string());
+        }
+
+        let now = time();
+        let open = UPLOADS.with(|u| {
+            u.borrow()
+                .values()
+                .filter(|p| p.attachment.uploaded_by == caller && p.expires_at > now)
+                .count()
+        });
+        if open >= MAX_OPEN_UPLOADS {
+            return Err(format!("At most {} uploads may be in progress", MAX_OPEN_UPLOADS));
+        }
+
+        let id = ATTACHMENT_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            format!("AT{:06}", *c)
+        });
+        let expires_at = now + UPLOAD_TTL_NANOS;
+        let attachment = Attachment {
+            id: id.clone(),
+            target,
+            file_name,
+            content_type,
+            size,
+            sha256,
+            chunk_count: size.div_ceil(CHUNK_BYTES as u64) as u32,
+            uploaded_by: caller,
+            uploaded_at: now,
+        };
+        UPLOADS.with(|u| {
+            u.borrow_mut().insert(
+                id.clone(),
+                PendingUpload {
+                    attachment,
+                    data: Vec::new(),
+                    next_chunk: 0,
+                    expires_at,
+                },
+            )
+        });
+        Ok(UploadSession {
+            upload_id: id,
+            chunk_bytes: CHUNK_BYTES as u32,
+            expires_at,
+        })
+    })
+}
+
+// Every chunk but the last must be exactly chunk_bytes long
+#[update]
+fn upload_attachment_chunk(upload_id: String, index: u32, bytes: Vec<u8>) -> Result<u32, String> {
+    metrics::observe("upload_attachment_chunk", || {
+        let caller = ic_cdk::caller();
+        UPLOADS.with(|u| {
+            let mut uploads = u.borrow_mut();
+            let upload = uploads
+                .get_mut(&upload_id)
+                .filter(|p| p.attachment.uploaded_by == caller && p.expires_at > time())
+                .ok_or_else(|| "Upload not found".to_string())?;
+            if index != upload.next_chunk {
+                return Err(format!("Expected chunk {}", upload.next_chunk));
+            }
+            let is_last = index + 1 == upload.attachment.chunk_count;
+            let expected = if is_last {
+                upload.attachment.size as usize - upload.data.len()
+            } else {
+                CHUNK_BYTES
+            };
+            if index >= upload.attachment.chunk_count || bytes.len() != expected {
+                return Err(format!("Chunk {} must be {} bytes", index, expected));
+            }
+            upload.data.extend_from_slice(&bytes);
+            upload.next_chunk += 1;
+            Ok(upload.attachment.chunk_count - upload.next_chunk)
+        })
+    })
+}
+
+#[update]
+fn finish_attachment_upload(upload_id: String) -> Result<Attachment, String> {
+    metrics::observe("finish_attachment_upload", || {
+        let caller = ic_cdk::caller();
+        let upload = UPLOADS.with(|u| {
+            let mut uploads = u.borrow_mut();
+            match uploads.get(&upload_id) {
+                Some(p) if p.attachment.uploaded_by == caller => Ok(uploads.remove(&upload_id).expect("upload exists")),
+                _ => Err("Upload not found".to_string()),
+            }
+        })?;
+        if upload.next_chunk != upload.attachment.chunk_count {
+            return Err("Upload is incomplete; start again".to_string());
+        }
+        let digest: String = Sha256::digest(&upload.data).iter().map(|b| format!("{:02x}", b)).collect();
+        if digest != upload.attachment.sha256 {
+            return Err("Content does not match the declared SHA-256".to_string());
+        }
+
+        let attachment = upload.attachment;
+        BLOBS.with(|b| b.borrow_mut().insert(attachment.id.clone(), upload.data));
+        ATTACHMENTS.with(|a| a.borrow_mut().insert(attachment.id.clone(), attachment.clone()));
+        Ok(attachment)
+    })
+}
+
+#[query]
+fn get_attachments(target: AttachmentTarget) -> Result<Vec<Attachment>, String> {
+    let caller = ic_cdk::caller();
+    if !can_read(&target, caller) {
+        return Err("Unauthorized to view these attachments".to_string());
+    }
+    Ok(for_target(&target))
+}
+
+#[query]
+fn get_attachment_chunk(attachment_id: String, index: u32) -> Result<Vec<u8>, String> {
+    let caller = ic_cdk::caller();
+    let attachment = ATTACHMENTS
+        .with(|a| a.borrow().get(&attachment_id).cloned())
+        .ok_or_else(|| "Attachment not found".to_string())?;
+    if !can_read(&attachment.target, caller) {
+        return Err("Unauthorized to view this attachment".to_string());
+    }
+    if index >= attachment.chunk_count {
+        return Err("Chunk out of range".to_string());
+    }
+    let data = BLOBS
+        .with(|b| b.borrow().get(&attachment_id))
+        .ok_or_else(|| "Attachment content missing".to_string())?;
+    let start = index as usize * CHUNK_BYTES;
+    let end = (start + CHUNK_BYTES).min(data.len());
+    Ok(data[start..end].to_vec())
+}
+
+// The uploader or platform staff may remove a file
+#[update]
+fn delete_attachment(attachment_id: String) -> Result<(), String> {
+    metrics::observe("delete_attachment", || {
+        let caller = ic_cdk::caller();
+        let attachment = ATTACHMENTS
+            .with(|a| a.borrow().get(&attachment_id).cloned())
+            .ok_or_else(|| "Attachment not found".to_string())?;
+        if attachment.uploaded_by != caller && !permissions::has(&caller, Permission::ManagePlatform) {
+            return Err("Unauthorized to delete this attachment".to_string());
+        }
+        if let AttachmentTarget::Dispute(dispute_id) = &attachment.target {
+            if confirmation::find_dispute(dispute_id).is_some_and(|d| d.is_open()) {
+                return Err("Evidence cannot be removed while the dispute is open".to_string());
+            }
+        }
+        ATTACHMENTS.with(|a| a.borrow_mut().remove(&attachment_id));
+        BLOBS.with(|b| b.borrow_mut().remove(&attachment_id));
+        Ok(())
+    })
+}
+
+pub(crate) fn for_target(target: &AttachmentTarget) -> Vec<Attachment> {
+    ATTACHMENTS.with(|a| a.borrow().values().filter(|a| &a.target == target).cloned().collect())
+}
+
+// Whether `attachment_id` is a file `uploaded_by` attached to the shipment, e.g. as
+// delivery evidence
+pub(crate) fn uploaded_to_shipment(shipment_id: &str, attachment_id: &str, uploaded_by: Principal) -> bool {
+    ATTACHMENTS.with(|a| {
+        a.borrow().get(attachment_id).is_some_and(|a| {
+            a.target == AttachmentTarget::Shipment(shipment_id.to_string()) && a.uploaded_by == uploaded_by
+        })
+    })
+}
+
+// Timer job: drop uploads that were never finished
+pub(crate) fn prune_uploads() {
+    let now = time();
+    UPLOADS.with(|u| u.borrow_mut().retain(|_, p| p.expires_at > now));
+}
+
+pub(crate) fn stable_state() -> StableAttachmentState {
+    StableAttachmentState {
+        attachments: ATTACHMENTS.with(|a| a.borrow().values().cloned().collect()),
+        counter: ATTACHMENT_COUNTER.with(|c| *c.borrow()),
+    }
+}
+
+pub(crate) fn restore_stable_state(state: StableAttachmentState) {
+    ATTACHMENTS.with(|a| *a.borrow_mut() = state.attachments.into_iter().map(|a| (a.id.clone(), a)).collect());
+    ATTACHMENT_COUNTER.with(|c| *c.borrow_mut() = state.counter);
+}
+
+// Contents by attachment id, for backups
+pub(crate) fn blobs() -> Vec<(String, Vec<u8>)> {
+    BLOBS.with(|b| b.borrow().iter().collect())
+}
+
+pub(crate) fn restore_blobs(blobs: Vec<(String, Vec<u8>)>) {
+    BLOBS.with(|b| {
+        let mut stored = b.borrow_mut();
+        for (id, data) in blobs {
+            stored.insert(id, data);
+        }
+    });
+}
+
+fn is_party(shipment: &Shipment, principal: Principal) -> bool {
+    shipment.sender_id == principal || shipment.recipient_id == Some(principal) || shipment.driver_id == Some(principal)
+}
+
+fn shipment(shipment_id: &str) -> Option<Shipment> {
+    SHIPMENTS.with(|shipments| shipments.borrow().get(shipment_id).cloned())
+}
+
+fn can_add(target: &AttachmentTarget, caller: Principal) -> bool {
+    match target {
+        AttachmentTarget::Shipment(id) => shipment(id).is_some_and(|s| is_party(&s, caller)),
+        AttachmentTarget::Dispute(id) => confirmation::find_dispute(id).is_some_and(|d| {
+            d.is_open()
+                && (shipment(&d.shipment_id).is_some_and(|s| is_party(&s, caller))
+                    || permissions::has(&caller, Permission::ResolveDisputes))
+        }),
+        // Drivers upload their own verification documents
+        AttachmentTarget::DriverProfile(driver_id) => {
+            *driver_id == caller && DRIVERS.with(|drivers| drivers.borrow().contains_key(driver_id))
+        },
+    }
+}
+
+fn can_read(target: &AttachmentTarget, caller: Principal) -> bool {
+    match target {
+        AttachmentTarget::Shipment(id) => {
+            shipment(id).is_some_and(|s| is_party(&s, caller))
+                || permissions::has(&caller, Permission::ViewAllShipments)
+        },
+        AttachmentTarget::Dispute(id) => {
+            let dispute = confirmation::find_dispute(id);
+            dispute.is_some_and(|d| shipment(&d.shipment_id).is_some_and(|s| is_party(&s, caller)))
+                || permissions::has(&caller, Permission::ResolveDisputes)
+        },
+        AttachmentTarget::DriverProfile(driver_id) => {
+            *driver_id == caller || permissions::has(&caller, Permission::ReviewCompliance)
+        },
+    }
+}
@@ -0,0 +1,86 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use crate::permissions::{self, Permission};
+
+const MAX_AUDIT_EVENTS: usize = 10_000;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum AuditAction {
+    ProfileUpdated,
+    AccountDeactivated,
+    AccountReactivated,
+    ErasureRequested,
+    ErasureRejected,
+    DataErased,
+    FeesWithdrawn,
+    SettingsChanged,
+    PermissionsChanged,
+    AccountSuspended,
+    SuspensionLifted,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AuditEvent {
+    pub id: u64,
+    pub timestamp: u64,
+    pub actor: Principal,
+    // Account the action applies to
+    pub subject: Principal,
+    pub action: AuditAction,
+    pub details: String,
+}
+
+thread_local! {
+    static AUDIT_LOG: RefCell<VecDeque<AuditEvent>> = RefCell::new(VecDeque::new());
+    static AUDIT_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Newest first. Admins may read any account's events, others only their own.
+#[query]
+fn get_audit_log(subject: Option<Principal>, limit: Option<u32>) -> Result<Vec<AuditEvent>, String> {
+    let caller = ic_cdk::caller();
+    if !permissions::has(&caller, Permission::ReviewCompliance) && subject != Some(caller) {
+        return Err("Unauthorized to view audit log".to_string());
+    }
+    let limit = limit.unwrap_or(100) as usize;
+    Ok(AUDIT_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .rev()
+            .filter(|e| subject.is_none_or(|s| e.subject == s))
+            .take(limit)
+            .cloned()
+            .collect()
+    }))
+}
+
+// Oldest first
+pub(crate) fn events_for(subject: Principal) -> Vec<AuditEvent> {
+    AUDIT_LOG.with(|log| log.borrow().iter().filter(|e| e.subject == subject).cloned().collect())
+}
+
+pub(crate) fn record(actor: Principal, subject: Principal, action: AuditAction, details: String) {
+    let id = AUDIT_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        *c += 1;
+        *c
+    });
+    AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        log.push_back(AuditEvent {
+            id,
+            timestamp: time(),
+            actor,
+            subject,
+            action,
+            details,
+        });
+        if log.len() > MAX_AUDIT_EVENTS {
+            log.pop_front();
+        }
+    });
+}
@@ -0,0 +1,266 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+use crate::archive::{self, StableArchiveState};
+use crate::attachments::{self, StableAttachmentState};
+use crate::event_store;
+use crate::ids::{self, StableIdState};
+use crate::live_location;
+use crate::metrics;
+use crate::pii;
+use crate::text_index;
+use crate::tracking;
+use crate::{
+    is_controller, Driver, ReturnRequest, Shipment, User, DRIVERS, RETURN_COUNTER, RETURN_REQUESTS, SHIPMENTS,
+    SHORT_CODES, TRACKING_TOKENS, USERS,
+};
+
+// Stays under the reply and ingress message limits
+const CHUNK_BYTES: usize = 1024 * 1024;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct BackupChunk {
+    pub index: u32,
+    pub chunk_count: u32,
+    pub data: Vec<u8>,
+    // Hex SHA-256 of `data`
+    pub sha256: String,
+    // Hex SHA-256 of the whole snapshot, the same on every chunk
+    pub snapshot_sha256: String,
+    pub taken_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RestoreSummary {
+    pub taken_at: u64,
+    pub users: u32,
+    pub shipments: u32,
+    pub drivers: u32,
+    pub return_requests: u32,
+    pub archived_shipments: u32,
+    pub attachments: u32,
+}
+
+// Records and the counters that keep ids unique. Derived state (tracking store,
+// search index) is rebuilt on restore; shard assignments and settings are not
+// carried, since they name canisters of the old deployment.
+#[derive(CandidType, Deserialize)]
+struct Snapshot {
+    taken_at: u64,
+    users: Vec<User>,
+    // With full tracking history inline
+    shipments: Vec<Shipment>,
+    drivers: Vec<Driver>,
+    return_requests: Vec<ReturnRequest>,
+    tracking_tokens: Vec<(String, String)>,
+    short_codes: Vec<(String, String)>,
+    return_counter: u64,
+    ids: StableIdState,
+    archive: StableArchiveState,
+    // Compressed, as stored
+    archived_shipments: Vec<(String, Vec<u8>)>,
+    attachments: StableAttachmentState,
+    attachment_blobs: Vec<(String, Vec<u8>)>,
+    // Recipient phone hashes only match under it
+    pii_salt: Option<Vec<u8>>,
+}
+
+// LZ4-compressed candid encoding of a Snapshot
+struct Export {
+    bytes: Vec<u8>,
+    sha256: String,
+    taken_at: u64,
+}
+
+struct Import {
+    chunk_count: u32,
+    snapshot_sha256: String,
+    taken_at: u64,
+    data: Vec<u8>,
+    next_chunk: u32,
+}
+
+thread_local! {
+    static EXPORT: RefCell<Option<Export>> = RefCell::new(None);
+    static IMPORT: RefCell<Option<Import>> = RefCell::new(None);
+}
+
+// Chunk 0 takes a fresh snapshot; later chunks come from it, so a download in
+// progress is consistent even while the canister keeps changing
+#[update]
+fn export_backup(chunk: u32) -> Result<BackupChunk, String> {
+    metrics::observe("export_backup", || {
+        require_controller()?;
+        if chunk == 0 {
+            let bytes = take_snapshot();
+            let export = Export {
+                sha256: hex_sha256(&bytes),
+                bytes,
+                taken_at: time(),
+            };
+            EXPORT.with(|e| *e.borrow_mut() = Some(export));
+        }
+        EXPORT.with(|e| {
+            let export = e.borrow();
+            let export = export.as_ref().ok_or_else(|| "Request chunk 0 to take a snapshot".to_string())?;
+            let chunk_count = export.bytes.len().div_ceil(CHUNK_BYTES).max(1) as u32;
+            if chunk >= chunk_count {
+                return Err(format!("The snapshot has {} chunks", chunk_count));
+            }
+            let start = chunk as usize * CHUNK_BYTES;
+            let data = export.bytes[start..(start + CHUNK_BYTES).min(export.bytes.len())].to_vec();
+            Ok(BackupChunk {
+                index: chunk,
+                chunk_count,
+                sha256: hex_sha256(&data),
+                data,
+                snapshot_sha256: export.sha256.clone(),
+                taken_at: export.taken_at,
+            })
+        })
+    })
+}
+
+// Chunks go in order; chunk 0 discards any restore in progress. Returns the
+// number of chunks still expected.
+#[update]
+fn import_backup(chunk: BackupChunk) -> Result<u32, String> {
+    metrics::observe("import_backup", || {
+        require_controller()?;
+        if chunk.data.len() > CHUNK_BYTES || chunk.index >= chunk.chunk_count {
+            return Err("Malformed chunk".to_string());
+        }
+        if hex_sha256(&chunk.data) != chunk.sha256 {
+            return Err(format!("Chunk {} does not match its SHA-256", chunk.index));
+        }
+        IMPORT.with(|i| {
+            let mut import = i.borrow_mut();
+            if chunk.index == 0 {
+                *import = Some(Import {
+                    chunk_count: chunk.chunk_count,
+                    snapshot_sha256: chunk.snapshot_sha256.clone(),
+                    taken_at: chunk.taken_at,
+                    data: Vec::new(),
+                    next_chunk: 0,
+                });
+            }
+            let import = import.as_mut().ok_or_else(|| "Send chunk 0 first".to_string())?;
+            if chunk.snapshot_sha256 != import.snapshot_sha256 || chunk.chunk_count != import.chunk_count {
+                return Err("Chunk belongs to a different snapshot".to_string());
+            }
+            if chunk.index != import.next_chunk {
+                return Err(format!("Expected chunk {}", import.next_chunk));
+            }
+            import.data.extend_from_slice(&chunk.data);
+            import.next_chunk += 1;
+            Ok(import.chunk_count - import.next_chunk)
+        })
+    })
+}
+
+// Loads the imported snapshot. Only into an empty canister, so a restore never
+// mixes with live records: reinstall first to recover in place.
+#[update]
+fn finalize_restore() -> Result<RestoreSummary, String> {
+    metrics::observe("finalize_restore", || {
+        require_controller()?;
+        let import = IMPORT
+            .with(|i| i.borrow_mut().take())
+            .ok_or_else(|| "No restore in progress".to_string())?;
+        if import.next_chunk != import.chunk_count {
+            let missing = import.chunk_count - import.next_chunk;
+            IMPORT.with(|i| *i.borrow_mut() = Some(import));
+            return Err(format!("{} chunks are still missing", missing));
+        }
+        if hex_sha256(&import.data) != import.snapshot_sha256 {
+            return Err("Snapshot does not match its SHA-256; import it again".to_string());
+        }
+        let is_empty = SHIPMENTS.with(|s| s.borrow().is_empty())
+            && USERS.with(|u| u.borrow().is_empty())
+            && DRIVERS.with(|d| d.borrow().is_empty())
+            && archive::len() == 0;
+        if !is_empty {
+            return Err("The canister already holds records; restore into a fresh install".to_string());
+        }
+
+        let encoded = lz4_flex::decompress_size_prepended(&import.data).map_err(|e| e.to_string())?;
+        let snapshot: Snapshot = candid::decode_one(&encoded).map_err(|e| e.to_string())?;
+        if snapshot.taken_at != import.taken_at {
+            return Err("Snapshot time does not match its chunks".to_string());
+        }
+        Ok(restore(snapshot))
+    })
+}
+
+fn take_snapshot() -> Vec<u8> {
+    let snapshot = Snapshot {
+        taken_at: time(),
+        users: USERS.with(|u| u.borrow().values().cloned().collect()),
+        shipments: SHIPMENTS.with(|s| s.borrow().values().map(tracking::full_record).collect()),
+        drivers: DRIVERS.with(|d| d.borrow().values().cloned().collect()),
+        return_requests: RETURN_REQUESTS.with(|r| r.borrow().values().cloned().collect()),
+        tracking_tokens: TRACKING_TOKENS.with(|t| t.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect()),
+        short_codes: SHORT_CODES.with(|c| c.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect()),
+        return_counter: RETURN_COUNTER.with(|c| *c.borrow()),
+        ids: ids::stable_state(),
+        archive: archive::stable_state(),
+        archived_shipments: archive::entries(),
+        attachments: attachments::stable_state(),
+        attachment_blobs: attachments::blobs(),
+        pii_salt: pii::salt().map(|salt| salt.to_vec()),
+    };
+    let encoded = candid::encode_one(&snapshot).expect("failed to encode snapshot");
+    lz4_flex::compress_prepend_size(&encoded)
+}
+
+fn restore(snapshot: Snapshot) -> RestoreSummary {
+    let summary = RestoreSummary {
+        taken_at: snapshot.taken_at,
+        users: snapshot.users.len() as u32,
+        shipments: snapshot.shipments.len() as u32,
+        drivers: snapshot.drivers.len() as u32,
+        return_requests: snapshot.return_requests.len() as u32,
+        archived_shipments: snapshot.archived_shipments.len() as u32,
+        attachments: snapshot.attachment_blobs.len() as u32,
+    };
+    for user in snapshot.users {
+        text_index::index_user(&user);
+        USERS.with(|u| u.borrow_mut().insert(user.id, user));
+    }
+    for mut shipment in snapshot.shipments {
+        tracking::attach(&mut shipment);
+        text_index::index_shipment(&shipment);
+        event_store::compact(&shipment, ic_cdk::caller());
+        SHIPMENTS.with(|s| s.borrow_mut().insert(shipment.id.clone(), shipment));
+    }
+    for driver in &snapshot.drivers {
+        live_location::index(driver.id, driver.current_location.as_ref());
+    }
+    DRIVERS.with(|d| *d.borrow_mut() = snapshot.drivers.into_iter().map(|d| (d.id, d)).collect());
+    let return_requests = snapshot.return_requests.into_iter().map(|r| (r.id.clone(), r)).collect();
+    RETURN_REQUESTS.with(|r| *r.borrow_mut() = return_requests);
+    TRACKING_TOKENS.with(|t| *t.borrow_mut() = snapshot.tracking_tokens.into_iter().collect());
+    SHORT_CODES.with(|c| *c.borrow_mut() = snapshot.short_codes.into_iter().collect());
+    RETURN_COUNTER.with(|c| *c.borrow_mut() = snapshot.return_counter);
+    ids::restore_stable_state(snapshot.ids);
+    archive::restore_stable_state(snapshot.archive);
+    archive::restore_entries(snapshot.archived_shipments);
+    attachments::restore_stable_state(snapshot.attachments);
+    attachments::restore_blobs(snapshot.attachment_blobs);
+    pii::restore_salt(snapshot.pii_salt.and_then(|salt| salt.try_into().ok()));
+    summary
+}
+
+fn require_controller() -> Result<(), String> {
+    if !is_controller(&ic_cdk::caller()) {
+        return Err("Only canister controllers can back up or restore state".to_string());
+    }
+    Ok(())
+}
+
+fn hex_sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
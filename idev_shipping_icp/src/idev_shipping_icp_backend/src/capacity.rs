@@ -0,0 +1,77 @@
+use candid::Principal;
+use ic_cdk_macros::*;
+
+use crate::errors::{CapacityResource, ShippingError};
+use crate::permissions::{self, Permission};
+use crate::vehicles;
+use crate::{Driver, PackageDetails, Shipment, ShipmentStatus, DRIVERS, SHIPMENTS};
+
+// Dry run of the assignment capacity check with the typed error. `Ok(None)` means
+// the package fits.
+#[query]
+fn check_driver_capacity(shipment_id: String, driver_id: Principal) -> Result<Option<ShippingError>, String> {
+    let caller = ic_cdk::caller();
+    if caller != driver_id && !permissions::has(&caller, Permission::AssignDriver) {
+        return Err("Unauthorized to check driver capacity".to_string());
+    }
+    let driver = DRIVERS
+        .with(|drivers| drivers.borrow().get(&driver_id).cloned())
+        .ok_or_else(|| "Driver not registered".to_string())?;
+
+    SHIPMENTS.with(|shipments| {
+        let shipments_map = shipments.borrow();
+        let shipment = shipments_map
+            .get(&shipment_id)
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        Ok(check_capacity(&driver, &shipment.package_details, &shipment_id, shipments_map.values()).err())
+    })
+}
+
+// A driver's load is every package they hold or are about to collect
+pub(crate) fn is_carrying(status: &ShipmentStatus) -> bool {
+    matches!(
+        status,
+        ShipmentStatus::PickupScheduled
+            | ShipmentStatus::PickedUp
+            | ShipmentStatus::InTransit
+            | ShipmentStatus::OutForDelivery
+    )
+}
+
+pub(crate) fn volume_cm3(package: &PackageDetails) -> f64 {
+    package.dimensions.length * package.dimensions.width * package.dimensions.height
+}
+
+// Check that `package` fits next to what the driver already carries. Used by every
+// assignment path. Declared capacities are capped by the vehicle type's limits, which
+// also apply when a capacity is zero or less.
+pub(crate) fn check_capacity<'a>(
+    driver: &Driver,
+    package: &PackageDetails,
+    shipment_id: &str,
+    shipments: impl Iterator<Item = &'a Shipment>,
+) -> Result<(), ShippingError> {
+    let (weight, volume) = shipments
+        .filter(|s| s.driver_id == Some(driver.id) && s.id != shipment_id && is_carrying(&s.status))
+        .fold((0.0, 0.0), |(w, v), s| {
+            (w + s.package_details.weight, v + volume_cm3(&s.package_details))
+        });
+
+    let (weight_capacity, volume_capacity) = vehicles::limits(&driver.vehicle_info);
+    if weight + package.weight > weight_capacity {
+        return Err(ShippingError::CapacityExceeded {
+            resource: CapacityResource::WeightKg,
+            required: package.weight,
+            available: (weight_capacity - weight).max(0.0),
+        });
+    }
+    let required = volume_cm3(package);
+    if volume + required > volume_capacity {
+        return Err(ShippingError::CapacityExceeded {
+            resource: CapacityResource::VolumeCm3,
+            required,
+            available: (volume_capacity - volume).max(0.0),
+        });
+    }
+    Ok(())
+}
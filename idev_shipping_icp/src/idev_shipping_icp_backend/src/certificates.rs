@@ -0,0 +1,150 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::attachments::{self, AttachmentTarget};
+use crate::confirmation;
+use crate::guards::{self, RateLimitedAction};
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::signing::{self, SigningPurpose};
+use crate::tracking;
+use crate::{Shipment, SHIPMENTS};
+
+const CERTIFICATE_VERSION: u32 = 1;
+
+// One line of the certificate: canonical JSON (sorted keys, no whitespace) and
+// SHA-256(previous entry's hash || canonical), both hex-encoded hashes
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CertificateEntry {
+    pub canonical: String,
+    pub hash: String,
+}
+
+// Verifiers recompute the chain from the entries, check that `header` names its
+// last hash, then check `signature` over SHA-256(header) against the canister's
+// ShipmentCertificate signing key
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShipmentCertificate {
+    pub shipment_id: String,
+    pub issued_at: u64,
+    pub entries: Vec<CertificateEntry>,
+    pub header: String,
+    pub key_name: String,
+    // 64-byte secp256k1 r || s
+    pub signature: Vec<u8>,
+}
+
+// Tracking history plus proofs of delivery (attachments and dispute outcomes),
+// hash-chained and signed with the canister's threshold-ECDSA key
+#[update]
+async fn export_shipment_certificate(shipment_id: String) -> Result<ShipmentCertificate, String> {
+    metrics::observe_async("export_shipment_certificate", async move {
+        let caller = ic_cdk::caller();
+        let shipment = SHIPMENTS
+            .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        let party = shipment.sender_id == caller || shipment.recipient_id == Some(caller);
+        if !party
+            && !permissions::has(&caller, Permission::ViewAllShipments)
+            && !permissions::has(&caller, Permission::ResolveDisputes)
+        {
+            return Err("Unauthorized to export this shipment".to_string());
+        }
+        guards::check_rate_limit(caller, RateLimitedAction::SignDocument)?;
+
+        let issued_at = time();
+        let mut previous = [0u8; 32];
+        let entries: Vec<CertificateEntry> = entries(&shipment)
+            .into_iter()
+            .map(|value| {
+                let canonical = value.to_string();
+                let mut hasher = Sha256::new();
+                hasher.update(previous);
+                hasher.update(canonical.as_bytes());
+                previous = hasher.finalize().into();
+                CertificateEntry {
+                    canonical,
+                    hash: hex(&previous),
+                }
+            })
+            .collect();
+        let header = json!({
+            "version": CERTIFICATE_VERSION,
+            "canister_id": ic_cdk::id().to_text(),
+            "shipment_id": shipment.id,
+            "issued_at": issued_at,
+            "entry_count": entries.len(),
+            "chain_head": hex(&previous),
+        })
+        .to_string();
+
+        let digest: [u8; 32] = Sha256::digest(header.as_bytes()).into();
+        let signed = signing::sign(SigningPurpose::ShipmentCertificate, digest, shipment.sender_id).await?;
+        Ok(ShipmentCertificate {
+            shipment_id,
+            issued_at,
+            entries,
+            header,
+            key_name: signed.key_name,
+            signature: signed.signature,
+        })
+    })
+    .await
+}
+
+fn entries(shipment: &Shipment) -> Vec<Value> {
+    let mut entries = vec![json!({
+        "type": "shipment",
+        "id": shipment.id,
+        "sender": shipment.sender_id.to_text(),
+        "recipient": shipment.recipient_id.map(|p| p.to_text()),
+        "created_at": shipment.created_at,
+        "status": format!("{:?}", shipment.status),
+        "actual_delivery": shipment.actual_delivery,
+    })];
+    tracking::with_events(shipment, |events| {
+        entries.extend(events.iter().map(|event| {
+            json!({
+                "type": "tracking_event",
+                "timestamp": event.timestamp,
+                "status": format!("{:?}", event.status),
+                "location": event.location,
+                "description": event.description,
+                "updated_by": event.updated_by.to_text(),
+                "detail": event.kind.as_ref().map(|k| format!("{:?}", k)),
+            })
+        }))
+    });
+    entries.extend(attachments::for_target(&AttachmentTarget::Shipment(shipment.id.clone())).iter().map(|a| {
+        json!({
+            "type": "attachment",
+            "id": a.id,
+            "file_name": a.file_name,
+            "content_type": a.content_type,
+            "size": a.size,
+            "sha256": a.sha256,
+            "uploaded_by": a.uploaded_by.to_text(),
+            "uploaded_at": a.uploaded_at,
+        })
+    }));
+    if let Some(dispute) = confirmation::latest_dispute(&shipment.id) {
+        entries.push(json!({
+            "type": "dispute",
+            "id": dispute.id,
+            "opened_by": dispute.opened_by.to_text(),
+            "reason": dispute.reason,
+            "status": format!("{:?}", dispute.status),
+            "resolution": dispute.resolution,
+            "created_at": dispute.created_at,
+            "resolved_at": dispute.resolved_at,
+        }));
+    }
+    entries
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,272 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::event_store::{self, ShipmentEvent};
+use crate::metrics;
+use crate::money::{Money, BASE_CURRENCY};
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::validation::{self, Validator};
+use crate::{PaymentMethod, PaymentStatus, Shipment, ShipmentStatus, DRIVERS, SHIPMENTS};
+
+// Cash collected by drivers is a liability until an admin records its remittance.
+// COD is only offered in the base currency, so balances are single-currency.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CodPolicy {
+    // Drivers owing this much or more get no new COD shipments; zero disables the limit
+    pub max_outstanding: Money,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CodCollection {
+    pub shipment_id: String,
+    pub driver_id: Principal,
+    pub amount_due: Money,
+    pub amount_collected: Money,
+    pub collected_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CodRemittance {
+    pub id: String,
+    pub driver_id: Principal,
+    pub amount: Money,
+    // Bank transfer or receipt reference
+    pub reference: Option<String>,
+    pub recorded_by: Principal,
+    pub recorded_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CodDriverBalance {
+    pub driver_id: Principal,
+    pub collected: Money,
+    pub collection_count: u32,
+    pub remitted: Money,
+    pub outstanding: Money,
+    pub blocked: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CodSettlementReport {
+    pub generated_at: u64,
+    pub since: u64,
+    pub drivers: Vec<CodDriverBalance>,
+    pub total_collected: Money,
+    pub total_remitted: Money,
+    pub total_outstanding: Money,
+}
+
+thread_local! {
+    static COD_POLICY: RefCell<CodPolicy> = RefCell::new(CodPolicy { max_outstanding: Money::zero(BASE_CURRENCY) });
+    static COLLECTIONS: RefCell<HashMap<String, CodCollection>> = RefCell::new(HashMap::new());
+    static REMITTANCES: RefCell<Vec<CodRemittance>> = RefCell::new(Vec::new());
+    static REMITTANCE_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Admin configuration
+#[update]
+fn set_cod_policy(policy: CodPolicy) -> Result<CodPolicy, String> {
+    metrics::observe("set_cod_policy", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManageFinances)?;
+        if policy.max_outstanding.currency != BASE_CURRENCY {
+            return Err(format!("COD limits are set in {:?}", BASE_CURRENCY));
+        }
+        COD_POLICY.with(|p| *p.borrow_mut() = policy.clone());
+        Ok(policy)
+    })
+}
+
+#[query]
+fn get_cod_policy() -> CodPolicy {
+    COD_POLICY.with(|p| p.borrow().clone())
+}
+
+// The assigned driver records the cash taken from the recipient, before marking
+// the shipment delivered
+#[update]
+fn record_cod_collection(shipment_id: String, amount: Money) -> Result<CodCollection, String> {
+    metrics::observe("record_cod_collection", || {
+        let caller = ic_cdk::caller();
+        SHIPMENTS.with(|shipments| {
+            let mut shipments_map = shipments.borrow_mut();
+            let shipment = shipments_map
+                .get_mut(&shipment_id)
+                .ok_or_else(|| "Shipment not found".to_string())?;
+            if shipment.driver_id != Some(caller) {
+                return Err("Only the assigned driver can record a collection".to_string());
+            }
+            if shipment.payment_method != Some(PaymentMethod::CashOnDelivery) {
+                return Err("Shipment is not cash on delivery".to_string());
+            }
+            if !matches!(shipment.status, ShipmentStatus::OutForDelivery) {
+                return Err("Cash is collected when the shipment is out for delivery".to_string());
+            }
+            if COLLECTIONS.with(|c| c.borrow().contains_key(&shipment_id)) {
+                return Err("Collection already recorded".to_string());
+            }
+            let amount_due = shipment.price;
+            if amount.currency != amount_due.currency {
+                return Err(format!("Collections are recorded in {:?}", amount_due.currency));
+            }
+            if amount.amount_e8s < amount_due.amount_e8s {
+                return Err(format!(
+                    "Collected amount is less than the {:.2} {} due",
+                    amount_due.to_decimal(),
+                    amount_due.currency.symbol()
+                ));
+            }
+
+            let collection = CodCollection {
+                shipment_id: shipment_id.clone(),
+                driver_id: caller,
+                amount_due,
+                amount_collected: amount,
+                collected_at: time(),
+            };
+            COLLECTIONS.with(|c| c.borrow_mut().insert(shipment_id.clone(), collection.clone()));
+            shipment.payment_status = PaymentStatus::Paid;
+            shipment.touch(time());
+            event_store::record(shipment, caller, ShipmentEvent::CashCollected);
+            notifications::notify(
+                shipment.sender_id,
+                NotificationKind::Payment,
+                Some(&shipment_id),
+                format!("Cash payment collected for shipment {}", shipment_id),
+            );
+            Ok(collection)
+        })
+    })
+}
+
+// Admins record cash handed over by a driver, reducing what they owe
+#[update]
+fn record_cod_remittance(driver_id: Principal, amount: Money, reference: Option<String>) -> Result<CodRemittance, String> {
+    metrics::observe("record_cod_remittance", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManageFinances)?;
+        if let Some(reference) = &reference {
+            let mut v = Validator::new();
+            v.max_len("reference", reference, validation::MAX_NAME_LEN);
+            v.finish()?;
+        }
+        if amount.currency != BASE_CURRENCY || amount.is_zero() {
+            return Err(format!("Remittances must be a positive amount in {:?}", BASE_CURRENCY));
+        }
+        let outstanding = balance_for(&driver_id, 0).outstanding;
+        if amount.amount_e8s > outstanding.amount_e8s {
+            return Err("Remittance exceeds the driver's outstanding balance".to_string());
+        }
+
+        let id = REMITTANCE_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            format!("CR{:06}", *c)
+        });
+        let remittance = CodRemittance {
+            id,
+            driver_id,
+            amount,
+            reference,
+            recorded_by: caller,
+            recorded_at: time(),
+        };
+        REMITTANCES.with(|r| r.borrow_mut().push(remittance.clone()));
+        Ok(remittance)
+    })
+}
+
+// Collected, remitted and outstanding cash per driver. Totals cover activity since
+// `since`; outstanding balances are always all-time.
+#[query]
+fn get_cod_settlement_report(since: Option<u64>) -> Result<CodSettlementReport, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ManageFinances)?;
+    let since = since.unwrap_or(0);
+    let mut driver_ids: Vec<Principal> = COLLECTIONS.with(|c| c.borrow().values().map(|c| c.driver_id).collect());
+    driver_ids.extend(REMITTANCES.with(|r| r.borrow().iter().map(|r| r.driver_id).collect::<Vec<_>>()));
+    driver_ids.sort();
+    driver_ids.dedup();
+
+    let drivers: Vec<CodDriverBalance> = driver_ids.iter().map(|d| balance_for(d, since)).collect();
+    Ok(CodSettlementReport {
+        generated_at: time(),
+        since,
+        total_collected: Money::sum(drivers.iter().map(|d| d.collected), BASE_CURRENCY),
+        total_remitted: Money::sum(drivers.iter().map(|d| d.remitted), BASE_CURRENCY),
+        total_outstanding: Money::sum(drivers.iter().map(|d| d.outstanding), BASE_CURRENCY),
+        drivers,
+    })
+}
+
+#[query]
+fn get_cod_balance(driver_id: Option<Principal>) -> Result<CodDriverBalance, String> {
+    let caller = ic_cdk::caller();
+    let driver_id = driver_id.unwrap_or(caller);
+    if driver_id != caller && !permissions::has(&caller, Permission::ManageFinances) {
+        return Err("Unauthorized to view COD balance".to_string());
+    }
+    if !DRIVERS.with(|drivers| drivers.borrow().contains_key(&driver_id)) {
+        return Err("Driver not registered".to_string());
+    }
+    Ok(balance_for(&driver_id, 0))
+}
+
+// COD shipments go only to drivers under the outstanding-balance limit
+pub(crate) fn check_assignment(shipment: &Shipment, driver_id: &Principal) -> Result<(), String> {
+    if shipment.payment_method != Some(PaymentMethod::CashOnDelivery) {
+        return Ok(());
+    }
+    if balance_for(driver_id, 0).blocked {
+        return Err("Driver must remit outstanding cash before taking COD shipments".to_string());
+    }
+    Ok(())
+}
+
+// COD shipments cannot be delivered until the cash has been recorded
+pub(crate) fn check_collected(shipment: &Shipment) -> Result<(), String> {
+    if shipment.payment_method == Some(PaymentMethod::CashOnDelivery)
+        && !COLLECTIONS.with(|c| c.borrow().contains_key(&shipment.id))
+    {
+        return Err("Record the cash collected before completing delivery".to_string());
+    }
+    Ok(())
+}
+
+fn balance_for(driver_id: &Principal, since: u64) -> CodDriverBalance {
+    let zero = Money::zero(BASE_CURRENCY);
+    let (mut collected, mut collected_all, mut collection_count) = (zero, zero, 0);
+    COLLECTIONS.with(|c| {
+        for collection in c.borrow().values().filter(|c| c.driver_id == *driver_id) {
+            collected_all = collected_all.add(collection.amount_collected);
+            if collection.collected_at >= since {
+                collected = collected.add(collection.amount_collected);
+                collection_count += 1;
+            }
+        }
+    });
+    let (mut remitted, mut remitted_all) = (zero, zero);
+    REMITTANCES.with(|r| {
+        for remittance in r.borrow().iter().filter(|r| r.driver_id == *driver_id) {
+            remitted_all = remitted_all.add(remittance.amount);
+            if remittance.recorded_at >= since {
+                remitted = remitted.add(remittance.amount);
+            }
+        }
+    });
+
+    let outstanding = collected_all.saturating_sub(remitted_all);
+    let limit = COD_POLICY.with(|p| p.borrow().max_outstanding);
+    CodDriverBalance {
+        driver_id: *driver_id,
+        collected,
+        collection_count,
+        remitted,
+        outstanding,
+        blocked: !limit.is_zero() && outstanding.amount_e8s >= limit.amount_e8s,
+    }
+}
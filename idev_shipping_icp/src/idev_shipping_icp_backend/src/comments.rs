@@ -0,0 +1,145 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::validation::{Validator, MAX_TEXT_LEN};
+use crate::SHIPMENTS;
+
+const MAX_COMMENTS_PER_SHIPMENT: usize = 1_000;
+const MAX_EDITS: usize = 50;
+const MAX_PAGE_SIZE: u32 = 100;
+
+// Earlier text of an edited comment
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CommentRevision {
+    pub text: String,
+    pub replaced_at: u64,
+}
+
+// Internal notes for support and operations; never shown to the shipment's parties
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShipmentComment {
+    pub id: u64,
+    pub shipment_id: String,
+    pub author: Principal,
+    pub text: String,
+    pub created_at: u64,
+    pub edited_at: Option<u64>,
+    pub history: Vec<CommentRevision>,
+}
+
+thread_local! {
+    static COMMENTS: RefCell<HashMap<String, Vec<ShipmentComment>>> = RefCell::new(HashMap::new());
+    static COMMENT_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+#[update]
+fn add_shipment_comment(shipment_id: String, text: String) -> Result<ShipmentComment, String> {
+    metrics::observe("add_shipment_comment", || {
+        let caller = ic_cdk::caller();
+        require_staff(&caller)?;
+        if !SHIPMENTS.with(|shipments| shipments.borrow().contains_key(&shipment_id)) {
+            return Err("Shipment not found".to_string());
+        }
+        let text = validate_text(text)?;
+        let count = COMMENTS.with(|c| c.borrow().get(&shipment_id).map_or(0, |list| list.len()));
+        if count >= MAX_COMMENTS_PER_SHIPMENT {
+            return Err(format!("Shipments are limited to {} comments", MAX_COMMENTS_PER_SHIPMENT));
+        }
+
+        let id = COMMENT_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            *c
+        });
+        let comment = ShipmentComment {
+            id,
+            shipment_id: shipment_id.clone(),
+            author: caller,
+            text,
+            created_at: time(),
+            edited_at: None,
+            history: Vec::new(),
+        };
+        COMMENTS.with(|c| c.borrow_mut().entry(shipment_id).or_default().push(comment.clone()));
+        Ok(comment)
+    })
+}
+
+// Authors edit their own comments; the replaced text is kept
+#[update]
+fn edit_shipment_comment(shipment_id: String, comment_id: u64, text: String) -> Result<ShipmentComment, String> {
+    metrics::observe("edit_shipment_comment", || {
+        let caller = ic_cdk::caller();
+        require_staff(&caller)?;
+        let text = validate_text(text)?;
+        COMMENTS.with(|c| {
+            let mut comments = c.borrow_mut();
+            let comment = comments
+                .get_mut(&shipment_id)
+                .and_then(|list| list.iter_mut().find(|comment| comment.id == comment_id))
+                .ok_or_else(|| "Comment not found".to_string())?;
+            if comment.author != caller {
+                return Err("Only the author can edit a comment".to_string());
+            }
+            if comment.history.len() >= MAX_EDITS {
+                return Err(format!("Comments can be edited at most {} times", MAX_EDITS));
+            }
+            if comment.text == text {
+                return Ok(comment.clone());
+            }
+            let now = time();
+            let previous = std::mem::replace(&mut comment.text, text);
+            comment.history.push(CommentRevision { text: previous, replaced_at: now });
+            comment.edited_at = Some(now);
+            Ok(comment.clone())
+        })
+    })
+}
+
+// Newest first
+#[query]
+fn get_shipment_comments(
+    shipment_id: String,
+    offset: Option<u32>,
+    limit: Option<u32>,
+) -> Result<Vec<ShipmentComment>, String> {
+    let caller = ic_cdk::caller();
+    require_staff(&caller)?;
+    Ok(COMMENTS.with(|c| {
+        c.borrow()
+            .get(&shipment_id)
+            .map(|list| {
+                list.iter()
+                    .rev()
+                    .skip(offset.unwrap_or(0) as usize)
+                    .take(limit.unwrap_or(MAX_PAGE_SIZE).min(MAX_PAGE_SIZE) as usize)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }))
+}
+
+// Support staff and dispute handlers
+fn require_staff(caller: &Principal) -> Result<(), String> {
+    if permissions::has(caller, Permission::ViewAllShipments) || permissions::has(caller, Permission::ResolveDisputes) {
+        return Ok(());
+    }
+    Err("Only staff can use shipment comments".to_string())
+}
+
+fn validate_text(text: String) -> Result<String, String> {
+    let text = text.trim().to_string();
+    if text.is_empty() {
+        return Err("Comment cannot be empty".to_string());
+    }
+    let mut v = Validator::new();
+    v.max_len("text", &text, MAX_TEXT_LEN);
+    v.finish()?;
+    Ok(text)
+}
@@ -0,0 +1,285 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::event_store;
+use crate::events;
+use crate::fees;
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::settings;
+use crate::tracking;
+use crate::{is_recipient, Shipment, ShipmentStatus, TrackingEvent, SHIPMENTS};
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Dispute {
+    pub id: String,
+    pub shipment_id: String,
+    pub opened_by: Principal,
+    pub reason: String,
+    pub status: DisputeStatus,
+    pub resolution: Option<String>,
+    pub created_at: u64,
+    pub resolved_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum DisputeStatus {
+    Open,
+    Upheld,
+    Rejected,
+}
+
+impl Dispute {
+    pub(crate) fn is_open(&self) -> bool {
+        matches!(self.status, DisputeStatus::Open)
+    }
+}
+
+thread_local! {
+    static DISPUTES: RefCell<HashMap<String, Dispute>> = RefCell::new(HashMap::new());
+    static DISPUTE_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Recipient confirmation endpoints
+#[update]
+fn confirm_delivery(shipment_id: String, tracking_token: Option<String>) -> Result<Shipment, String> {
+    metrics::observe("confirm_delivery", || {
+        let caller = ic_cdk::caller();
+
+        SHIPMENTS.with(|shipments| {
+            let mut shipments_map = shipments.borrow_mut();
+            let shipment = shipments_map
+                .get_mut(&shipment_id)
+                .ok_or_else(|| "Shipment not found".to_string())?;
+            if !is_recipient(shipment, &caller, tracking_token.as_deref()) {
+                return Err("Unauthorized to confirm delivery".to_string());
+            }
+            if !matches!(shipment.status, ShipmentStatus::AwaitingConfirmation) {
+                return Err("Shipment is not awaiting confirmation".to_string());
+            }
+
+            mark_confirmed(shipment, caller, "Delivery confirmed by recipient");
+            Ok(shipment.clone())
+        })
+    })
+}
+
+#[update]
+fn dispute_delivery(
+    shipment_id: String,
+    reason: String,
+    tracking_token: Option<String>,
+) -> Result<Dispute, String> {
+    metrics::observe("dispute_delivery", || {
+        let caller = ic_cdk::caller();
+
+        SHIPMENTS.with(|shipments| {
+            let mut shipments_map = shipments.borrow_mut();
+            let shipment = shipments_map
+                .get_mut(&shipment_id)
+                .ok_or_else(|| "Shipment not found".to_string())?;
+            if !is_recipient(shipment, &caller, tracking_token.as_deref()) {
+                return Err("Unauthorized to dispute delivery".to_string());
+            }
+            if !matches!(shipment.status, ShipmentStatus::AwaitingConfirmation) {
+                return Err("Shipment is not awaiting confirmation".to_string());
+            }
+
+            shipment.status = ShipmentStatus::Disputed;
+            shipment.touch(time());
+            tracking::record(shipment, TrackingEvent {
+                timestamp: time(),
+                status: ShipmentStatus::Disputed,
+                location: None,
+                description: "Delivery disputed by recipient".to_string(),
+                updated_by: caller,
+                kind: None,
+            });
+            event_store::status_changed(shipment, caller);
+            notifications::notify_parties(
+                shipment,
+                caller,
+                NotificationKind::Dispute,
+                format!("Delivery of shipment {} was disputed: {}", shipment.id, reason),
+            );
+            events::publish_status_change(shipment);
+            Ok(())
+        })?;
+
+        Ok(open(shipment_id, caller, reason))
+    })
+}
+
+// A driver reported the parcel damaged at the door. The shipment stays failed
+// while the dispute is open, and resolving it leaves the shipment as it is.
+pub(crate) fn open_exception_dispute(shipment: &Shipment, reporter: Principal, reason: String) -> Dispute {
+    notifications::notify_parties(
+        shipment,
+        reporter,
+        NotificationKind::Dispute,
+        format!("Shipment {} was reported {}; a dispute was opened", shipment.id, reason),
+    );
+    open(shipment.id.clone(), reporter, reason)
+}
+
+fn open(shipment_id: String, opened_by: Principal, reason: String) -> Dispute {
+    let dispute_id = DISPUTE_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        *c += 1;
+        format!("DP{:06}", *c)
+    });
+
+    let dispute = Dispute {
+        id: dispute_id.clone(),
+        shipment_id,
+        opened_by,
+        reason,
+        status: DisputeStatus::Open,
+        resolution: None,
+        created_at: time(),
+        resolved_at: None,
+    };
+
+    DISPUTES.with(|disputes| {
+        disputes.borrow_mut().insert(dispute_id, dispute.clone());
+    });
+    dispute
+}
+
+// Admin adjudication: an upheld dispute fails the delivery, a rejected one confirms it.
+// Disputes over failed deliveries only record the outcome.
+#[update]
+fn resolve_dispute(dispute_id: String, upheld: bool, resolution: String) -> Result<Dispute, String> {
+    metrics::observe("resolve_dispute", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ResolveDisputes)?;
+
+        let dispute = DISPUTES.with(|disputes| {
+            let mut disputes_map = disputes.borrow_mut();
+            let dispute = disputes_map
+                .get_mut(&dispute_id)
+                .ok_or_else(|| "Dispute not found".to_string())?;
+            if !matches!(dispute.status, DisputeStatus::Open) {
+                return Err("Dispute already resolved".to_string());
+            }
+
+            dispute.status = if upheld { DisputeStatus::Upheld } else { DisputeStatus::Rejected };
+            dispute.resolution = Some(resolution.clone());
+            dispute.resolved_at = Some(time());
+            Ok(dispute.clone())
+        })?;
+
+        SHIPMENTS.with(|shipments| {
+            if let Some(shipment) = shipments.borrow_mut().get_mut(&dispute.shipment_id) {
+                if !matches!(shipment.status, ShipmentStatus::Disputed) {
+                    return;
+                }
+                if upheld {
+                    shipment.status = ShipmentStatus::Failed;
+                    shipment.actual_delivery = None;
+                    shipment.touch(time());
+                    tracking::record(shipment, TrackingEvent {
+                        timestamp: time(),
+                        status: ShipmentStatus::Failed,
+                        location: None,
+                        description: format!("Dispute upheld: {}", resolution),
+                        updated_by: caller,
+                        kind: None,
+                    });
+                    event_store::status_changed(shipment, caller);
+                    notifications::notify_parties(
+                        shipment,
+                        caller,
+                        NotificationKind::Dispute,
+                        format!("Dispute on shipment {} upheld: {}", shipment.id, resolution),
+                    );
+                    events::publish_status_change(shipment);
+                } else {
+                    mark_confirmed(shipment, caller, &format!("Dispute rejected: {}", resolution));
+                }
+            }
+        });
+
+        Ok(dispute)
+    })
+}
+
+#[query]
+fn get_disputes() -> Vec<Dispute> {
+    let caller = ic_cdk::caller();
+    DISPUTES.with(|disputes| {
+        disputes
+            .borrow()
+            .values()
+            .filter(|d| d.opened_by == caller || permissions::has(&caller, Permission::ResolveDisputes))
+            .cloned()
+            .collect()
+    })
+}
+
+pub(crate) fn open_dispute_count() -> u32 {
+    DISPUTES.with(|disputes| disputes.borrow().values().filter(|d| d.is_open()).count() as u32)
+}
+
+pub(crate) fn find_dispute(dispute_id: &str) -> Option<Dispute> {
+    DISPUTES.with(|disputes| disputes.borrow().get(dispute_id).cloned())
+}
+
+// Most recent dispute opened on a shipment
+pub(crate) fn latest_dispute(shipment_id: &str) -> Option<Dispute> {
+    DISPUTES.with(|disputes| {
+        disputes
+            .borrow()
+            .values()
+            .filter(|d| d.shipment_id == shipment_id)
+            .max_by_key(|d| d.created_at)
+            .cloned()
+    })
+}
+
+// Timer job: confirm deliveries whose confirmation window has lapsed
+pub(crate) fn auto_confirm_deliveries() {
+    let now = time();
+    let window = settings::confirmation_window_nanos();
+    let canister = ic_cdk::id();
+
+    SHIPMENTS.with(|shipments| {
+        for shipment in shipments.borrow_mut().values_mut() {
+            let delivered_at = shipment.actual_delivery.unwrap_or(shipment.updated_at);
+            if matches!(shipment.status, ShipmentStatus::AwaitingConfirmation)
+                && now.saturating_sub(delivered_at) >= window
+            {
+                mark_confirmed(shipment, canister, "Delivery auto-confirmed after confirmation window");
+            }
+        }
+    });
+}
+
+fn mark_confirmed(shipment: &mut Shipment, updated_by: Principal, description: &str) {
+    shipment.status = ShipmentStatus::Delivered;
+    shipment.touch(time());
+    if shipment.actual_delivery.is_none() {
+        shipment.actual_delivery = Some(time());
+    }
+    tracking::record(shipment, TrackingEvent {
+        timestamp: time(),
+        status: ShipmentStatus::Delivered,
+        location: None,
+        description: description.to_string(),
+        updated_by,
+        kind: None,
+    });
+    event_store::status_changed(shipment, updated_by);
+    notifications::notify_parties(
+        shipment,
+        updated_by,
+        NotificationKind::StatusChange,
+        format!("Delivery of shipment {} confirmed", shipment.id),
+    );
+    fees::accrue(shipment);
+    events::publish_status_change(shipment);
+}
@@ -0,0 +1,265 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+
+use crate::event_store::{self, ShipmentEvent};
+use crate::metrics;
+use crate::money::{Money, BASE_CURRENCY};
+use crate::pii;
+use crate::text_index;
+use crate::tracking;
+use crate::validation::{Validator, MAX_TEXT_LEN};
+use crate::zones;
+use crate::{
+    apply_status_update, price_shipment, Coordinates, CostBreakdown, Dimensions, PackageDetails, PaymentStatus,
+    Shipment, ShipmentStatus, TrackingEvent, SHIPMENTS,
+};
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+// Shipments created this close together are considered together
+const WINDOW_NANOS: u64 = 24 * NANOS_PER_HOUR;
+const SAME_PICKUP_KM: f64 = 0.5;
+const NEARBY_DELIVERY_KM: f64 = 2.0;
+// Deliveries this close are treated as the same address
+const SAME_ADDRESS_KM: f64 = 0.05;
+const MAX_GROUP_SIZE: usize = 10;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ConsolidationKind {
+    // Same recipient and address: one shipment carrying all the packages
+    MergePackages,
+    // Nearby addresses: worth handing to one driver on one route
+    SharedRoute,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ConsolidationSuggestion {
+    pub kind: ConsolidationKind,
+    pub shipment_ids: Vec<String>,
+    pub current_total: Money,
+    // What the merged shipment would cost; None for shared routes
+    pub consolidated_total: Option<Money>,
+    pub delivery_spread_km: f64,
+}
+
+// Groups among the caller's shipments that have not been paid or picked up yet
+#[query]
+fn get_consolidation_suggestions() -> Vec<ConsolidationSuggestion> {
+    let caller = ic_cdk::caller();
+    let mut open: Vec<Shipment> = SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .values()
+            .filter(|s| s.sender_id == caller && pending(s) && delivery_point(s).is_some())
+            .cloned()
+            .collect()
+    });
+    open.sort_by_key(|s| s.created_at);
+
+    let mut grouped = vec![false; open.len()];
+    let mut suggestions = Vec::new();
+    for (i, seed) in open.iter().enumerate() {
+        if grouped[i] {
+            continue;
+        }
+        let members: Vec<usize> = (i..open.len())
+            .filter(|&j| !grouped[j] && nearby(seed, &open[j]))
+            .take(MAX_GROUP_SIZE)
+            .collect();
+        if members.len() < 2 {
+            continue;
+        }
+        for &j in &members {
+            grouped[j] = true;
+        }
+        let group: Vec<&Shipment> = members.iter().map(|&j| &open[j]).collect();
+        let mergeable = group.iter().all(|s| mergeable(seed, s));
+        let consolidated_total = if mergeable {
+            merged_price(&group).ok().map(|(_, breakdown)| breakdown.total)
+        } else {
+            None
+        };
+        suggestions.push(ConsolidationSuggestion {
+            kind: if consolidated_total.is_some() {
+                ConsolidationKind::MergePackages
+            } else {
+                ConsolidationKind::SharedRoute
+            },
+            shipment_ids: group.iter().map(|s| s.id.clone()).collect(),
+            current_total: Money::sum(group.iter().map(|s| s.price), BASE_CURRENCY),
+            consolidated_total,
+            delivery_spread_km: spread_km(&group),
+        });
+    }
+    suggestions
+}
+
+// Merge the shipments into the oldest one, which is repriced for the combined
+// packages; the others are cancelled
+#[update]
+fn accept_consolidation(shipment_ids: Vec<String>) -> Result<Shipment, String> {
+    metrics::observe("accept_consolidation", || {
+        let caller = ic_cdk::caller();
+        let mut ids = shipment_ids;
+        ids.sort();
+        ids.dedup();
+        if !(2..=MAX_GROUP_SIZE).contains(&ids.len()) {
+            return Err(format!("Merge between 2 and {} shipments", MAX_GROUP_SIZE));
+        }
+        let mut group: Vec<Shipment> = SHIPMENTS.with(|shipments| {
+            let shipments = shipments.borrow();
+            ids.iter()
+                .map(|id| {
+                    shipments
+                        .get(id)
+                        .filter(|s| s.sender_id == caller)
+                        .cloned()
+                        .ok_or_else(|| format!("Shipment {} not found", id))
+                })
+                .collect::<Result<_, String>>()
+        })?;
+        group.sort_by_key(|s| s.created_at);
+        if let Some(s) = group.iter().find(|s| !pending(s)) {
+            return Err(format!("Shipment {} has already been paid or dispatched", s.id));
+        }
+        let primary = group[0].clone();
+        if let Some(s) = group.iter().find(|s| !nearby(&primary, s) || !mergeable(&primary, s)) {
+            return Err(format!("Shipment {} cannot be merged with {}", s.id, primary.id));
+        }
+        let refs: Vec<&Shipment> = group.iter().collect();
+        let (package_details, cost_breakdown) = merged_price(&refs)?;
+        let merged_ids: Vec<String> = group[1..].iter().map(|s| s.id.clone()).collect();
+
+        let now = time();
+        SHIPMENTS.with(|shipments| {
+            let mut shipments = shipments.borrow_mut();
+            for id in &merged_ids {
+                if let Some(shipment) = shipments.get_mut(id) {
+                    let description = format!("Merged into shipment {}", primary.id);
+                    apply_status_update(shipment, ShipmentStatus::Cancelled, None, description, caller, now);
+                }
+            }
+            let shipment = shipments.get_mut(&primary.id).expect("primary shipment exists");
+            shipment.package_details = package_details;
+            shipment.price = cost_breakdown.total;
+            shipment.cost = cost_breakdown.total.to_decimal();
+            shipment.cost_breakdown = cost_breakdown;
+            shipment.touch(now);
+            text_index::index_shipment(shipment);
+            tracking::record(shipment, TrackingEvent {
+                timestamp: now,
+                status: shipment.status.clone(),
+                location: None,
+                description: format!("Consolidated with {}", merged_ids.join(", ")),
+                updated_by: caller,
+                kind: None,
+            });
+            let tracking = shipment.tracking_history.last().cloned().expect("tracking event just recorded");
+            let event = ShipmentEvent::Consolidated {
+                package_details: Box::new(shipment.package_details.clone()),
+                cost_breakdown: Box::new(shipment.cost_breakdown.clone()),
+                tracking,
+            };
+            event_store::record(shipment, caller, event);
+            Ok(shipment.clone())
+        })
+    })
+}
+
+// Created but not yet paid, assigned or routed
+fn pending(shipment: &Shipment) -> bool {
+    matches!(shipment.status, ShipmentStatus::Created)
+        && matches!(shipment.payment_status, PaymentStatus::Pending)
+        && shipment.payment.is_none()
+        && shipment.driver_id.is_none()
+        && shipment.legs.is_none()
+}
+
+fn delivery_point(shipment: &Shipment) -> Option<&Coordinates> {
+    shipment.delivery_address.coordinates.as_ref()
+}
+
+fn nearby(a: &Shipment, b: &Shipment) -> bool {
+    let same_pickup = match (&a.pickup_address.coordinates, &b.pickup_address.coordinates) {
+        (Some(p), Some(q)) => p.distance_km(q) <= SAME_PICKUP_KM,
+        _ => false,
+    };
+    let close_delivery = match (delivery_point(a), delivery_point(b)) {
+        (Some(p), Some(q)) => p.distance_km(q) <= NEARBY_DELIVERY_KM,
+        _ => false,
+    };
+    same_pickup && close_delivery && a.created_at.abs_diff(b.created_at) <= WINDOW_NANOS
+}
+
+// Merging keeps one price, so anything applied per shipment rules it out:
+// credits and promos, insurance, foreign-currency quotes
+fn mergeable(a: &Shipment, b: &Shipment) -> bool {
+    let same_address = match (delivery_point(a), delivery_point(b)) {
+        (Some(p), Some(q)) => p.distance_km(q) <= SAME_ADDRESS_KM,
+        _ => false,
+    };
+    let plain =
+        |s: &Shipment| s.cost_breakdown.deductions.is_empty() && s.insurance.is_none() && s.exchange_rate.is_none();
+    same_address
+        && plain(a)
+        && plain(b)
+        && pii::same_recipient_phone(a, b)
+        && a.recipient_id == b.recipient_id
+        && a.pudo_id == b.pudo_id
+        && a.store_id == b.store_id
+        && a.service_level == b.service_level
+        && a.payment_method == b.payment_method
+        && a.requires_confirmation == b.requires_confirmation
+}
+
+// The packages as one: weights and values add up, packages stack
+fn merged_price(group: &[&Shipment]) -> Result<(PackageDetails, CostBreakdown), String> {
+    let primary = group[0];
+    let packages: Vec<&PackageDetails> = group.iter().map(|s| &s.package_details).collect();
+    let declared = Money::sum(packages.iter().map(|p| p.declared_value()), BASE_CURRENCY);
+    let mut handling: Vec<_> = packages.iter().flat_map(|p| p.handling.clone().unwrap_or_default()).collect();
+    handling.sort();
+    handling.dedup();
+    let instructions: Vec<&str> = packages.iter().filter_map(|p| p.special_instructions.as_deref()).collect();
+    let package = PackageDetails {
+        description: truncate(packages.iter().map(|p| p.description.as_str()).collect::<Vec<_>>().join("; ")),
+        weight: packages.iter().map(|p| p.weight).sum(),
+        dimensions: Dimensions {
+            length: packages.iter().map(|p| p.dimensions.length).fold(0.0, f64::max),
+            width: packages.iter().map(|p| p.dimensions.width).fold(0.0, f64::max),
+            height: packages.iter().map(|p| p.dimensions.height).sum(),
+        },
+        value: declared.to_decimal(),
+        declared_value: Some(declared),
+        handling: Some(handling),
+        special_instructions: (!instructions.is_empty()).then(|| truncate(instructions.join("; "))),
+    };
+    let mut v = Validator::new();
+    v.package("package_details", &package);
+    v.finish()?;
+
+    let zones = zones::resolve_shipment_zones(&primary.pickup_address, &primary.delivery_address)?;
+    let breakdown = price_shipment(
+        &primary.pickup_address,
+        &primary.delivery_address,
+        &package,
+        &zones,
+        &primary.service_level,
+    );
+    Ok((package, breakdown))
+}
+
+fn truncate(text: String) -> String {
+    text.chars().take(MAX_TEXT_LEN).collect()
+}
+
+fn spread_km(group: &[&Shipment]) -> f64 {
+    let points: Vec<&Coordinates> = group.iter().filter_map(|s| delivery_point(s)).collect();
+    let mut spread: f64 = 0.0;
+    for (i, p) in points.iter().enumerate() {
+        for q in &points[i + 1..] {
+            spread = spread.max(p.distance_km(q));
+        }
+    }
+    spread
+}
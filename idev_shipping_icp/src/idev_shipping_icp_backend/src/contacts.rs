@@ -0,0 +1,299 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::resource_usage::{self, ResourceFeature};
+use crate::validation::normalize_phone;
+use crate::{generate_otp, User, USERS};
+
+const CODE_TTL_NANOS: u64 = 10 * 60 * 1_000_000_000;
+const RESEND_COOLDOWN_NANOS: u64 = 60 * 1_000_000_000;
+const MAX_ATTEMPTS: u32 = 5;
+const MAX_RESPONSE_BYTES: u64 = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub enum ContactChannel {
+    Email,
+    Phone,
+}
+
+// How verification codes reach the user. A relay is a trusted off-chain service
+// that drains codes with take_verification_messages; an HTTPS provider receives
+// each code in a POST as soon as it is issued.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum CodeDelivery {
+    Relay { relay: Principal },
+    Https { url: String, api_key: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct VerificationMessage {
+    pub channel: ContactChannel,
+    pub destination: String,
+    pub code: String,
+    pub expires_at: u64,
+}
+
+#[derive(Clone, Debug)]
+struct PendingCode {
+    destination: String,
+    code: String,
+    issued_at: u64,
+    expires_at: u64,
+    attempts: u32,
+}
+
+thread_local! {
+    static CODE_DELIVERY: RefCell<Option<CodeDelivery>> = RefCell::new(None);
+    static PENDING_CODES: RefCell<HashMap<(Principal, ContactChannel), PendingCode>> = RefCell::new(HashMap::new());
+    // Codes waiting for the relay
+    static OUTBOX: RefCell<Vec<VerificationMessage>> = RefCell::new(Vec::new());
+}
+
+// Admin configuration
+#[update]
+fn set_code_delivery(delivery: CodeDelivery) -> Result<(), String> {
+    metrics::observe("set_code_delivery", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if let CodeDelivery::Https { url, .. } = &delivery {
+            if !url.starts_with("https://") {
+                return Err("Provider URL must use https".to_string());
+            }
+        }
+        CODE_DELIVERY.with(|d| *d.borrow_mut() = Some(delivery));
+        OUTBOX.with(|outbox| outbox.borrow_mut().clear());
+        Ok(())
+    })
+}
+
+// Send a fresh code to the caller's email or phone; returns when it expires
+#[update]
+async fn request_contact_verification(channel: ContactChannel) -> Result<u64, String> {
+    metrics::observe_async("request_contact_verification", async move {
+        let caller = ic_cdk::caller();
+        let message = issue_code(caller, channel)?;
+        let expires_at = message.expires_at;
+
+        let delivery = CODE_DELIVERY
+            .with(|d| d.borrow().clone())
+            .ok_or_else(|| "Contact verification is not configured".to_string())?;
+        match delivery {
+            CodeDelivery::Relay { .. } => OUTBOX.with(|outbox| outbox.borrow_mut().push(message)),
+            CodeDelivery::Https { url, api_key } => {
+                if let Err(e) = send(caller, &url, &api_key, &message).await {
+                    PENDING_CODES.with(|pending| pending.borrow_mut().remove(&(caller, channel)));
+                    return Err(format!("Failed to send verification code: {}", e));
+                }
+            },
+        }
+        Ok(expires_at)
+    })
+    .await
+}
+
+// Called by the relay; returns and forgets every queued code
+#[update]
+fn take_verification_messages() -> Result<Vec<VerificationMessage>, String> {
+    metrics::observe("take_verification_messages", || {
+        let caller = ic_cdk::caller();
+        let is_relay = CODE_DELIVERY.with(|d| matches!(*d.borrow(), Some(CodeDelivery::Relay { relay }) if relay == caller));
+        if !is_relay {
+            return Err("Unauthorized to take verification messages".to_string());
+        }
+        let now = time();
+        Ok(OUTBOX.with(|outbox| {
+            outbox
+                .borrow_mut()
+                .drain(..)
+                .filter(|m| m.expires_at > now)
+                .collect()
+        }))
+    })
+}
+
+// Confirm a code sent by request_contact_verification
+#[update]
+fn verify_contact(code: String) -> Result<User, String> {
+    metrics::observe("verify_contact", || {
+        let caller = ic_cdk::caller();
+        let now = time();
+        let (channel, destination) = PENDING_CODES.with(|pending| {
+            let mut pending_map = pending.borrow_mut();
+            let channels = [ContactChannel::Email, ContactChannel::Phone];
+            if let Some(channel) = channels.into_iter().find(|c| {
+                pending_map
+                    .get(&(caller, *c))
+                    .is_some_and(|p| p.expires_at > now && p.code == code)
+            }) {
+                let p = pending_map.remove(&(caller, channel)).expect("code was just found");
+                return Ok((channel, p.destination));
+            }
+
+            // Every live code for the caller counts the failed attempt
+            let mut any_live = false;
+            for channel in channels {
+                let key = (caller, channel);
+                let Some(p) = pending_map.get_mut(&key) else { continue };
+                p.attempts += 1;
+                if p.expires_at <= now || p.attempts >= MAX_ATTEMPTS {
+                    pending_map.remove(&key);
+                } else {
+                    any_live = true;
+                }
+            }
+            if any_live {
+                Err("Invalid verification code".to_string())
+            } else {
+                Err("No active verification code; request a new one".to_string())
+            }
+        })?;
+
+        USERS.with(|users| {
+            let mut users_map = users.borrow_mut();
+            let user = users_map.get_mut(&caller).ok_or_else(|| "User not registered".to_string())?;
+            let (current, verified) = match channel {
+                ContactChannel::Email => (&user.email, &mut user.email_verified),
+                ContactChannel::Phone => (&user.phone, &mut user.phone_verified),
+            };
+            if *current != destination {
+                return Err("Contact details changed since the code was sent".to_string());
+            }
+            *verified = true;
+            Ok(user.clone())
+        })
+    })
+}
+
+// Sensitive flows (driver registration, payouts) need both contacts verified
+pub(crate) fn require_verified_contacts(caller: Principal) -> Result<User, String> {
+    let user = USERS
+        .with(|users| users.borrow().get(&caller).cloned())
+        .ok_or_else(|| "User not registered".to_string())?;
+    if !user.email_verified {
+        return Err("Email address must be verified".to_string());
+    }
+    if !user.phone_verified {
+        return Err("Phone number must be verified".to_string());
+    }
+    Ok(user)
+}
+
+// The caller's phone number if it has been verified, in normalized form
+pub(crate) fn verified_phone(caller: Principal) -> Option<String> {
+    USERS.with(|users| {
+        users
+            .borrow()
+            .get(&caller)
+            .filter(|u| u.phone_verified)
+            .map(|u| normalize_phone(&u.phone))
+    })
+}
+
+fn issue_code(caller: Principal, channel: ContactChannel) -> Result<VerificationMessage, String> {
+    let user = USERS
+        .with(|users| users.borrow().get(&caller).cloned())
+        .ok_or_else(|| "User not registered".to_string())?;
+    let (destination, verified) = match channel {
+        ContactChannel::Email => (user.email, user.email_verified),
+        ContactChannel::Phone => (user.phone, user.phone_verified),
+    };
+    if verified {
+        return Err(format!("{:?} is already verified", channel));
+    }
+
+    let now = time();
+    PENDING_CODES.with(|pending| {
+        let mut pending_map = pending.borrow_mut();
+        if let Some(p) = pending_map.get(&(caller, channel)) {
+            if p.issued_at + RESEND_COOLDOWN_NANOS > now {
+                return Err("A code was sent recently; try again in a minute".to_string());
+            }
+        }
+        let code = generate_otp(&format!("{}:{:?}", caller, channel));
+        let expires_at = now + CODE_TTL_NANOS;
+        pending_map.insert(
+            (caller, channel),
+            PendingCode {
+                destination: destination.clone(),
+                code: code.clone(),
+                issued_at: now,
+                expires_at,
+                attempts: 0,
+            },
+        );
+        Ok(VerificationMessage {
+            channel,
+            destination,
+            code,
+            expires_at,
+        })
+    })
+}
+
+#[query]
+fn verification_transform(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+async fn send(caller: Principal, url: &str, api_key: &str, message: &VerificationMessage) -> Result<(), String> {
+    let body = serde_json::json!({
+        "channel": match message.channel {
+            ContactChannel::Email => "email",
+            ContactChannel::Phone => "sms",
+        },
+        "to": message.destination,
+        "code": message.code,
+    })
+    .to_string();
+
+    let request_bytes = (url.len() + body.len()) as u64;
+    resource_usage::record(
+        caller,
+        None,
+        ResourceFeature::VerificationOutcall,
+        resource_usage::outcall_cycles(request_bytes, MAX_RESPONSE_BYTES),
+        0,
+    );
+
+    let request = CanisterHttpRequestArgument {
+        url: url.to_string(),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader { name: "Content-Type".to_string(), value: "application/json".to_string() },
+            HttpHeader { name: "Authorization".to_string(), value: format!("Bearer {}", api_key) },
+            // Replicas each send the request; providers deduplicate on this key
+            HttpHeader {
+                name: "Idempotency-Key".to_string(),
+                value: format!("{}-{:?}-{}", caller, message.channel, message.expires_at),
+            },
+        ],
+        body: Some(body.into_bytes()),
+        transform: Some(TransformContext::new(verification_transform, Vec::new())),
+    };
+
+    match http_request(request).await {
+        Ok((response,)) => {
+            let status = response.status.to_string().parse::<u16>().map_err(|_| "Invalid status code".to_string())?;
+            if (200..300).contains(&status) {
+                Ok(())
+            } else {
+                Err(format!("HTTP {}", status))
+            }
+        },
+        Err((code, message)) => Err(format!("{:?}: {}", code, message)),
+    }
+}
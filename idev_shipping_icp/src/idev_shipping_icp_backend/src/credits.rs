@@ -0,0 +1,333 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::metrics;
+use crate::money::{Money, BASE_CURRENCY};
+use crate::permissions::{self, Permission};
+use crate::{CostBreakdown, CostLineItem};
+
+// Account credit. Balance credits are consumed at checkout in the order the
+// sources are declared here, then oldest grant first.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CreditEntry {
+    pub id: String,
+    pub owner: Principal,
+    pub source: CreditSource,
+    pub amount: Money,
+    pub remaining: Money,
+    pub reference: Option<String>,
+    pub granted_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
+pub enum CreditSource {
+    SlaCompensation,
+    Referral,
+    LoyaltyRedemption,
+    Goodwill,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CreditSummary {
+    pub balance: Money,
+    pub entries: Vec<CreditEntry>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Promo {
+    pub code: String,
+    pub discount: PromoDiscount,
+    pub max_redemptions: Option<u32>,
+    pub redemptions: u32,
+    pub expires_at: Option<u64>,
+    pub is_active: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum PromoDiscount {
+    // Basis points of the running total, 1..=10000
+    PercentageBps(u32),
+    Fixed(Money),
+}
+
+#[derive(Clone, Debug, Default)]
+struct Redemption {
+    promo_code: Option<String>,
+    credits: Vec<(String, Money)>,
+}
+
+thread_local! {
+    static CREDITS: RefCell<HashMap<String, CreditEntry>> = RefCell::new(HashMap::new());
+    static PROMOS: RefCell<HashMap<String, Promo>> = RefCell::new(HashMap::new());
+    static REDEMPTIONS: RefCell<HashMap<String, Redemption>> = RefCell::new(HashMap::new());
+    static CREDIT_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+#[query]
+fn get_my_credits() -> CreditSummary {
+    let caller = ic_cdk::caller();
+    let now = time();
+    let entries: Vec<CreditEntry> = CREDITS.with(|credits| {
+        credits
+            .borrow()
+            .values()
+            .filter(|c| c.owner == caller)
+            .cloned()
+            .collect()
+    });
+    let balance = Money::sum(
+        entries.iter().filter(|c| is_spendable(c, now)).map(|c| c.remaining),
+        BASE_CURRENCY,
+    );
+
+    CreditSummary { balance, entries }
+}
+
+// Admin tooling
+#[update]
+fn grant_credit(
+    owner: Principal,
+    source: CreditSource,
+    amount: Money,
+    expires_at: Option<u64>,
+    reference: Option<String>,
+) -> Result<CreditEntry, String> {
+    metrics::observe("grant_credit", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManageFinances)?;
+        if amount.currency != BASE_CURRENCY || amount.is_zero() {
+            return Err(format!("Credits must be a positive amount in {:?}", BASE_CURRENCY));
+        }
+
+        Ok(grant(owner, source, amount, expires_at, reference))
+    })
+}
+
+#[update]
+fn create_promo(
+    code: String,
+    discount: PromoDiscount,
+    max_redemptions: Option<u32>,
+    expires_at: Option<u64>,
+) -> Result<Promo, String> {
+    metrics::observe("create_promo", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManageFinances)?;
+        match discount {
+            PromoDiscount::PercentageBps(bps) if bps == 0 || bps > 10_000 => {
+                return Err("Percentage discount must be between 1 and 10000 basis points".to_string())
+            },
+            PromoDiscount::Fixed(a) if a.currency != BASE_CURRENCY || a.is_zero() => {
+                return Err(format!("Fixed discount must be a positive amount in {:?}", BASE_CURRENCY))
+            },
+            _ => {},
+        }
+
+        let code = code.trim().to_uppercase();
+        if code.is_empty() {
+            return Err("Promo code cannot be empty".to_string());
+        }
+
+        let promo = Promo {
+            code: code.clone(),
+            discount,
+            max_redemptions,
+            redemptions: 0,
+            expires_at,
+            is_active: true,
+        };
+
+        PROMOS.with(|promos| {
+            let mut promos_map = promos.borrow_mut();
+            if promos_map.contains_key(&code) {
+                return Err("Promo code already exists".to_string());
+            }
+            promos_map.insert(code, promo.clone());
+            Ok(promo)
+        })
+    })
+}
+
+#[update]
+fn deactivate_promo(code: String) -> Result<Promo, String> {
+    metrics::observe("deactivate_promo", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManageFinances)?;
+
+        PROMOS.with(|promos| {
+            match promos.borrow_mut().get_mut(&code.trim().to_uppercase()) {
+                Some(promo) => {
+                    promo.is_active = false;
+                    Ok(promo.clone())
+                },
+                None => Err("Promo not found".to_string()),
+            }
+        })
+    })
+}
+
+#[query]
+fn get_promos() -> Result<Vec<Promo>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ManageFinances)?;
+    Ok(PROMOS.with(|promos| promos.borrow().values().cloned().collect()))
+}
+
+pub(crate) fn grant(
+    owner: Principal,
+    source: CreditSource,
+    amount: Money,
+    expires_at: Option<u64>,
+    reference: Option<String>,
+) -> CreditEntry {
+    let credit_id = CREDIT_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        *c += 1;
+        format!("CR{:06}", *c)
+    });
+
+    let entry = CreditEntry {
+        id: credit_id.clone(),
+        owner,
+        source,
+        amount,
+        remaining: amount,
+        reference,
+        granted_at: time(),
+        expires_at,
+    };
+
+    CREDITS.with(|credits| {
+        credits.borrow_mut().insert(credit_id, entry.clone());
+    });
+
+    entry
+}
+
+// Checkout: promo discount first, then account credits in deterministic order.
+// Nothing is consumed unless every step succeeds.
+pub(crate) fn apply_at_checkout(
+    owner: Principal,
+    shipment_id: &str,
+    breakdown: &mut CostBreakdown,
+    promo_code: Option<&str>,
+    apply_credits: bool,
+) -> Result<(), String> {
+    let now = time();
+    let mut redemption = Redemption::default();
+
+    if let Some(code) = promo_code {
+        let code = code.trim().to_uppercase();
+        let promo = PROMOS
+            .with(|promos| promos.borrow().get(&code).cloned())
+            .ok_or_else(|| "Invalid promo code".to_string())?;
+        if !promo.is_active || promo.expires_at.map(|e| e <= now).unwrap_or(false) {
+            return Err("Promo code has expired".to_string());
+        }
+        if promo.max_redemptions.map(|m| promo.redemptions >= m).unwrap_or(false) {
+            return Err("Promo code has been fully redeemed".to_string());
+        }
+
+        let discount = match promo.discount {
+            PromoDiscount::PercentageBps(bps) => breakdown.total.mul_ratio(bps as u128, 10_000),
+            PromoDiscount::Fixed(a) => a,
+        };
+        deduct(breakdown, format!("Promo {}", code), discount);
+        redemption.promo_code = Some(code);
+    }
+
+    if apply_credits {
+        let mut available: Vec<CreditEntry> = CREDITS.with(|credits| {
+            credits
+                .borrow()
+                .values()
+                .filter(|c| c.owner == owner && is_spendable(c, now))
+                .cloned()
+                .collect()
+        });
+        available.sort_by(|a, b| {
+            a.source
+                .cmp(&b.source)
+                .then(a.granted_at.cmp(&b.granted_at))
+                .then(a.id.cmp(&b.id))
+        });
+
+        for credit in available {
+            if breakdown.total.is_zero() {
+                break;
+            }
+            let applied = deduct(breakdown, format!("{:?} credit {}", credit.source, credit.id), credit.remaining);
+            redemption.credits.push((credit.id, applied));
+        }
+    }
+
+    // Commit the consumption
+    if let Some(code) = &redemption.promo_code {
+        PROMOS.with(|promos| {
+            if let Some(promo) = promos.borrow_mut().get_mut(code) {
+                promo.redemptions += 1;
+            }
+        });
+    }
+    CREDITS.with(|credits| {
+        let mut credits_map = credits.borrow_mut();
+        for (credit_id, applied) in &redemption.credits {
+            if let Some(credit) = credits_map.get_mut(credit_id) {
+                credit.remaining = credit.remaining.saturating_sub(*applied);
+            }
+        }
+    });
+    if redemption.promo_code.is_some() || !redemption.credits.is_empty() {
+        REDEMPTIONS.with(|redemptions| {
+            redemptions.borrow_mut().insert(shipment_id.to_string(), redemption);
+        });
+    }
+
+    Ok(())
+}
+
+// Promo redeemed by a shipment that was not cancelled
+pub(crate) fn promo_code_for(shipment_id: &str) -> Option<String> {
+    REDEMPTIONS.with(|redemptions| redemptions.borrow().get(shipment_id).and_then(|r| r.promo_code.clone()))
+}
+
+// Return promo usage and credit consumed by a shipment, e.g. when it is cancelled
+pub(crate) fn release_credits(shipment_id: &str) {
+    let redemption = match REDEMPTIONS.with(|redemptions| redemptions.borrow_mut().remove(shipment_id)) {
+        Some(r) => r,
+        None => return,
+    };
+
+    if let Some(code) = &redemption.promo_code {
+        PROMOS.with(|promos| {
+            if let Some(promo) = promos.borrow_mut().get_mut(code) {
+                promo.redemptions = promo.redemptions.saturating_sub(1);
+            }
+        });
+    }
+    CREDITS.with(|credits| {
+        let mut credits_map = credits.borrow_mut();
+        for (credit_id, applied) in &redemption.credits {
+            if let Some(credit) = credits_map.get_mut(credit_id) {
+                credit.remaining = credit.remaining.add(*applied);
+            }
+        }
+    });
+}
+
+fn deduct(breakdown: &mut CostBreakdown, label: String, amount: Money) -> Money {
+    let applied = amount.min(breakdown.total);
+    if !applied.is_zero() {
+        breakdown.deductions.push(CostLineItem { label, amount: applied });
+        breakdown.total = breakdown.total.saturating_sub(applied);
+    }
+    applied
+}
+
+fn is_spendable(credit: &CreditEntry, now: u64) -> bool {
+    !credit.remaining.is_zero() && credit.expires_at.map(|e| e > now).unwrap_or(true)
+}
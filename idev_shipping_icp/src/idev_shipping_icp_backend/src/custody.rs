@@ -0,0 +1,282 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::event_store;
+use crate::hubs::{self, LegEndpoint, LegStatus};
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::tracking;
+use crate::{generate_otp, Shipment, TrackingEvent, TrackingEventKind, SHIPMENTS};
+
+const HANDOVER_TTL_NANOS: u64 = 15 * 60 * 1_000_000_000;
+const MAX_CODE_ATTEMPTS: u32 = 5;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum CustodyTransferStatus {
+    Pending,
+    Completed,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CustodyTransfer {
+    pub id: String,
+    pub shipment_id: String,
+    pub from: Principal,
+    pub to: Principal,
+    // Where the handover happens, when at a hub
+    pub hub_id: Option<String>,
+    pub status: CustodyTransferStatus,
+    pub requested_at: u64,
+    pub expires_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+// Returned to the releasing party only; the receiver enters the code to confirm
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CustodyHandover {
+    pub transfer: CustodyTransfer,
+    pub code: String,
+}
+
+struct HandoverCode {
+    code: String,
+    failed_attempts: u32,
+}
+
+thread_local! {
+    static TRANSFERS: RefCell<HashMap<String, CustodyTransfer>> = RefCell::new(HashMap::new());
+    // Pending transfer id -> its code
+    static CODES: RefCell<HashMap<String, HandoverCode>> = RefCell::new(HashMap::new());
+    // Shipment id -> whoever confirmed the latest transfer
+    static CUSTODIANS: RefCell<HashMap<String, Principal>> = RefCell::new(HashMap::new());
+    static TRANSFER_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// The current holder starts a handover to a driver or hub staff on the
+// shipment's route. Replaces any earlier pending handover.
+#[update]
+fn request_custody_transfer(
+    shipment_id: String,
+    to: Principal,
+    hub_id: Option<String>,
+) -> Result<CustodyHandover, String> {
+    metrics::observe("request_custody_transfer", || {
+        let caller = ic_cdk::caller();
+        let shipment = SHIPMENTS
+            .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        match custodian(&shipment) {
+            Some(holder) if holder == caller => {},
+            Some(_) => return Err("Only the current holder can hand the package over".to_string()),
+            None => return Err("Nobody has taken custody of the package yet".to_string()),
+        }
+        if to == caller {
+            return Err("Cannot hand a package over to yourself".to_string());
+        }
+        if hub_id.as_ref().is_some_and(|h| !on_route(&shipment, h)) {
+            return Err("Hub is not on this shipment's route".to_string());
+        }
+        let eligible = carries(&shipment, to) || hub_id.as_ref().is_some_and(|h| hubs::is_hub_staff_at(h, &to));
+        if !eligible {
+            return Err("Receiver is not a driver or hub staff on this shipment's route".to_string());
+        }
+
+        let now = time();
+        let previous: Vec<String> = TRANSFERS.with(|t| {
+            t.borrow()
+                .values()
+                .filter(|t| t.shipment_id == shipment_id && t.status == CustodyTransferStatus::Pending)
+                .map(|t| t.id.clone())
+                .collect()
+        });
+        for id in previous {
+            close(&id, CustodyTransferStatus::Cancelled);
+        }
+
+        let id = TRANSFER_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            format!("CT{:06}", *c)
+        });
+        let transfer = CustodyTransfer {
+            id: id.clone(),
+            shipment_id: shipment_id.clone(),
+            from: caller,
+            to,
+            hub_id,
+            status: CustodyTransferStatus::Pending,
+            requested_at: now,
+            expires_at: now + HANDOVER_TTL_NANOS,
+            completed_at: None,
+        };
+        let code = generate_otp(&format!("custody:{}", id));
+        TRANSFERS.with(|t| t.borrow_mut().insert(id.clone(), transfer.clone()));
+        CODES.with(|c| {
+            c.borrow_mut().insert(
+                id,
+                HandoverCode {
+                    code: code.clone(),
+                    failed_attempts: 0,
+                },
+            )
+        });
+        notifications::notify(
+            to,
+            NotificationKind::Assignment,
+            Some(&shipment_id),
+            format!("A handover of shipment {} is waiting for your confirmation", shipment_id),
+        );
+        Ok(CustodyHandover { transfer, code })
+    })
+}
+
+// The receiver confirms with the code shown by the releasing party
+#[update]
+fn confirm_custody_transfer(transfer_id: String, code: String) -> Result<CustodyTransfer, String> {
+    metrics::observe("confirm_custody_transfer", || {
+        let caller = ic_cdk::caller();
+        let transfer = TRANSFERS
+            .with(|t| t.borrow().get(&transfer_id).cloned())
+            .filter(|t| t.to == caller && t.status == CustodyTransferStatus::Pending)
+            .ok_or_else(|| "Handover not found".to_string())?;
+        let now = time();
+        if now > transfer.expires_at {
+            close(&transfer_id, CustodyTransferStatus::Cancelled);
+            return Err("Handover has expired; ask for a new code".to_string());
+        }
+        CODES.with(|codes| {
+            let mut codes = codes.borrow_mut();
+            let entry = codes.get_mut(&transfer_id).ok_or_else(|| "Handover not found".to_string())?;
+            if entry.failed_attempts >= MAX_CODE_ATTEMPTS {
+                return Err("Too many failed attempts; ask for a new code".to_string());
+            }
+            if entry.code != code {
+                entry.failed_attempts += 1;
+                return Err("Invalid handover code".to_string());
+            }
+            Ok(())
+        })?;
+
+        let location = transfer.hub_id.as_deref().and_then(hubs::find).map(|h| h.name);
+        SHIPMENTS.with(|shipments| {
+            let mut shipments = shipments.borrow_mut();
+            let shipment = shipments
+                .get_mut(&transfer.shipment_id)
+                .ok_or_else(|| "Shipment not found".to_string())?;
+            shipment.touch(now);
+            tracking::record(shipment, TrackingEvent {
+                timestamp: now,
+                status: shipment.status.clone(),
+                location,
+                description: format!("Custody transferred from {} to {}", transfer.from, transfer.to),
+                updated_by: caller,
+                kind: Some(TrackingEventKind::CustodyTransferred {
+                    from: transfer.from,
+                    to: transfer.to,
+                    hub_id: transfer.hub_id.clone(),
+                }),
+            });
+            event_store::tracked(shipment, caller);
+            Ok::<_, String>(())
+        })?;
+        CUSTODIANS.with(|c| c.borrow_mut().insert(transfer.shipment_id.clone(), caller));
+        close(&transfer_id, CustodyTransferStatus::Completed);
+        notifications::notify(
+            transfer.from,
+            NotificationKind::StatusChange,
+            Some(&transfer.shipment_id),
+            format!("Handover of shipment {} was confirmed", transfer.shipment_id),
+        );
+        Ok(TRANSFERS.with(|t| t.borrow().get(&transfer_id).cloned().expect("transfer exists")))
+    })
+}
+
+#[update]
+fn cancel_custody_transfer(transfer_id: String) -> Result<CustodyTransfer, String> {
+    metrics::observe("cancel_custody_transfer", || {
+        let caller = ic_cdk::caller();
+        let pending = TRANSFERS.with(|t| {
+            t.borrow()
+                .get(&transfer_id)
+                .is_some_and(|t| t.from == caller && t.status == CustodyTransferStatus::Pending)
+        });
+        if !pending {
+            return Err("Handover not found".to_string());
+        }
+        close(&transfer_id, CustodyTransferStatus::Cancelled);
+        Ok(TRANSFERS.with(|t| t.borrow().get(&transfer_id).cloned().expect("transfer exists")))
+    })
+}
+
+// Oldest first; visible to the shipment's parties and anyone on its route
+#[query]
+fn get_custody_chain(shipment_id: String) -> Result<Vec<CustodyTransfer>, String> {
+    let caller = ic_cdk::caller();
+    let shipment = SHIPMENTS
+        .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    let mut chain: Vec<CustodyTransfer> = TRANSFERS.with(|t| {
+        t.borrow()
+            .values()
+            .filter(|t| t.shipment_id == shipment_id)
+            .cloned()
+            .collect()
+    });
+    let involved = shipment.sender_id == caller
+        || shipment.recipient_id == Some(caller)
+        || carries(&shipment, caller)
+        || chain.iter().any(|t| t.from == caller || t.to == caller);
+    if !involved && !permissions::has(&caller, Permission::ViewAllShipments) {
+        return Err("Unauthorized to view custody of this shipment".to_string());
+    }
+    chain.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(chain)
+}
+
+// Whoever confirmed the latest handover, otherwise the driver carrying the
+// package: the shipment's driver or the driver of the furthest leg under way
+pub(crate) fn custodian(shipment: &Shipment) -> Option<Principal> {
+    if let Some(holder) = CUSTODIANS.with(|c| c.borrow().get(&shipment.id).copied()) {
+        return Some(holder);
+    }
+    match &shipment.legs {
+        Some(legs) => legs
+            .iter()
+            .rev()
+            .find(|l| matches!(l.status, LegStatus::InTransit | LegStatus::Completed))
+            .and_then(|l| l.driver_id),
+        None => shipment.driver_id,
+    }
+}
+
+fn carries(shipment: &Shipment, principal: Principal) -> bool {
+    shipment.driver_id == Some(principal)
+        || shipment
+            .legs
+            .as_ref()
+            .is_some_and(|legs| legs.iter().any(|l| l.driver_id == Some(principal)))
+}
+
+fn on_route(shipment: &Shipment, hub_id: &str) -> bool {
+    shipment.legs.as_ref().is_some_and(|legs| {
+        legs.iter()
+            .any(|l| l.from == LegEndpoint::Hub(hub_id.to_string()) || l.to == LegEndpoint::Hub(hub_id.to_string()))
+    })
+}
+
+fn close(transfer_id: &str, status: CustodyTransferStatus) {
+    TRANSFERS.with(|t| {
+        if let Some(transfer) = t.borrow_mut().get_mut(transfer_id) {
+            if status == CustodyTransferStatus::Completed {
+                transfer.completed_at = Some(time());
+            }
+            transfer.status = status;
+        }
+    });
+    CODES.with(|c| c.borrow_mut().remove(transfer_id));
+}
@@ -0,0 +1,282 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::attachments;
+use crate::event_store;
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::tracking;
+use crate::validation::{self, Validator};
+use crate::{is_recipient, Shipment, ShipmentStatus, TrackingEvent, TrackingEventKind, SHIPMENTS, USERS};
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+// When the recipient would like the delivery, in minutes since midnight UTC
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DeliveryWindow {
+    pub start_minute: u16,
+    pub end_minute: u16,
+}
+
+// How a recipient wants parcels handed over. Set on their account for every
+// shipment linked to them, or on one shipment, which wins.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct DeliveryPreferences {
+    pub leave_at_door: bool,
+    pub require_signature: bool,
+    pub neighbor_allowed: bool,
+    pub preferred_window: Option<DeliveryWindow>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ProofKind {
+    // Signed for by the recipient
+    Signature,
+    // Handed to the recipient without a signature
+    InPerson,
+    // Left at the door, with a photo of where
+    LeftAtDoor,
+    // Given to a neighbour
+    Neighbor,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DeliveryProof {
+    pub kind: ProofKind,
+    // The signature or the photo, uploaded by the driver as a shipment attachment
+    pub attachment_id: Option<String>,
+    // Who signed for or took the parcel
+    pub received_by: Option<String>,
+}
+
+thread_local! {
+    static USER_PREFERENCES: RefCell<HashMap<Principal, DeliveryPreferences>> = RefCell::new(HashMap::new());
+    static SHIPMENT_PREFERENCES: RefCell<HashMap<String, DeliveryPreferences>> = RefCell::new(HashMap::new());
+}
+
+// Defaults for shipments linked to the caller; None clears them
+#[update]
+fn set_my_delivery_preferences(preferences: Option<DeliveryPreferences>) -> Result<Option<DeliveryPreferences>, String> {
+    metrics::observe("set_my_delivery_preferences", || {
+        let caller = ic_cdk::caller();
+        if !USERS.with(|users| users.borrow().contains_key(&caller)) {
+            return Err("User not registered".to_string());
+        }
+        if let Some(preferences) = &preferences {
+            validate(preferences)?;
+        }
+        USER_PREFERENCES.with(|p| match &preferences {
+            Some(preferences) => p.borrow_mut().insert(caller, preferences.clone()),
+            None => p.borrow_mut().remove(&caller),
+        });
+        Ok(preferences)
+    })
+}
+
+#[query]
+fn get_my_delivery_preferences() -> Option<DeliveryPreferences> {
+    USER_PREFERENCES.with(|p| p.borrow().get(&ic_cdk::caller()).cloned())
+}
+
+// The sender or recipient sets how one shipment is handed over, up to the moment
+// it is delivered. None falls back to the recipient's account defaults.
+#[update]
+fn set_shipment_delivery_preferences(
+    shipment_id: String,
+    preferences: Option<DeliveryPreferences>,
+    tracking_token: Option<String>,
+) -> Result<Option<DeliveryPreferences>, String> {
+    metrics::observe("set_shipment_delivery_preferences", || {
+        let caller = ic_cdk::caller();
+        if let Some(preferences) = &preferences {
+            validate(preferences)?;
+        }
+        let shipment = SHIPMENTS
+            .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        if shipment.sender_id != caller && !is_recipient(&shipment, &caller, tracking_token.as_deref()) {
+            return Err("Unauthorized to set delivery preferences".to_string());
+        }
+        if !matches!(
+            shipment.status,
+            ShipmentStatus::Created
+                | ShipmentStatus::PickupScheduled
+                | ShipmentStatus::PickedUp
+                | ShipmentStatus::InTransit
+                | ShipmentStatus::OutForDelivery
+        ) {
+            return Err("Delivery preferences can only be changed before delivery".to_string());
+        }
+        if shipment.pudo_id.is_some() {
+            return Err("Shipment is delivered to a pickup point".to_string());
+        }
+
+        SHIPMENT_PREFERENCES.with(|p| match &preferences {
+            Some(preferences) => p.borrow_mut().insert(shipment_id.clone(), preferences.clone()),
+            None => p.borrow_mut().remove(&shipment_id),
+        });
+        if let Some(driver_id) = shipment.driver_id {
+            notifications::notify(
+                driver_id,
+                NotificationKind::StatusChange,
+                Some(&shipment_id),
+                format!("Delivery preferences for shipment {} changed", shipment_id),
+            );
+        }
+        Ok(preferences)
+    })
+}
+
+// The preferences in effect for a shipment, for its parties
+#[query]
+fn get_delivery_preferences(
+    shipment_id: String,
+    tracking_token: Option<String>,
+) -> Result<Option<DeliveryPreferences>, String> {
+    let caller = ic_cdk::caller();
+    let shipment = SHIPMENTS
+        .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    let is_party = shipment.sender_id == caller
+        || shipment.driver_id == Some(caller)
+        || is_recipient(&shipment, &caller, tracking_token.as_deref());
+    if !is_party && !permissions::has(&caller, Permission::ViewAllShipments) {
+        return Err("Unauthorized to view delivery preferences".to_string());
+    }
+    Ok(for_shipment(&shipment))
+}
+
+// The assigned driver records how the parcel was handed over, before marking the
+// shipment delivered. Required for shipments with delivery preferences, and must
+// be a kind they accept.
+#[update]
+fn record_proof_of_delivery(shipment_id: String, proof: DeliveryProof) -> Result<Shipment, String> {
+    metrics::observe("record_proof_of_delivery", || {
+        let caller = ic_cdk::caller();
+        if let Some(received_by) = &proof.received_by {
+            let mut v = Validator::new();
+            v.required("received_by", received_by, validation::MAX_NAME_LEN);
+            v.finish()?;
+        }
+        SHIPMENTS.with(|shipments| {
+            let mut shipments_map = shipments.borrow_mut();
+            let shipment = shipments_map
+                .get_mut(&shipment_id)
+                .ok_or_else(|| "Shipment not found".to_string())?;
+            if shipment.driver_id != Some(caller) {
+                return Err("Only the assigned driver can record proof of delivery".to_string());
+            }
+            if !matches!(shipment.status, ShipmentStatus::OutForDelivery) {
+                return Err("Proof of delivery is recorded when the shipment is out for delivery".to_string());
+            }
+            let preferences = for_shipment(shipment).unwrap_or_default();
+            if !acceptable_proofs(&preferences).contains(&proof.kind) {
+                return Err(format!("{:?} is not an accepted proof of delivery for this shipment", proof.kind));
+            }
+            check_evidence(shipment, caller, &proof, &preferences)?;
+
+            let now = time();
+            tracking::record(shipment, TrackingEvent {
+                timestamp: now,
+                status: shipment.status.clone(),
+                location: None,
+                description: format!("Proof of delivery recorded: {:?}", proof.kind),
+                updated_by: caller,
+                kind: Some(TrackingEventKind::DeliveryProofRecorded { proof }),
+            });
+            shipment.touch(now);
+            event_store::tracked(shipment, caller);
+            Ok(shipment.clone())
+        })
+    })
+}
+
+// Shipment preferences, else the linked recipient's defaults
+pub(crate) fn for_shipment(shipment: &Shipment) -> Option<DeliveryPreferences> {
+    SHIPMENT_PREFERENCES.with(|p| p.borrow().get(&shipment.id).cloned()).or_else(|| {
+        let recipient = shipment.recipient_id?;
+        USER_PREFERENCES.with(|p| p.borrow().get(&recipient).cloned())
+    })
+}
+
+pub(crate) fn clear(user_id: Principal) {
+    USER_PREFERENCES.with(|p| p.borrow_mut().remove(&user_id));
+}
+
+// A signature is always enough; anything less only when the preferences allow it
+pub(crate) fn acceptable_proofs(preferences: &DeliveryPreferences) -> Vec<ProofKind> {
+    let mut kinds = vec![ProofKind::Signature];
+    if !preferences.require_signature {
+        kinds.push(ProofKind::InPerson);
+        if preferences.leave_at_door {
+            kinds.push(ProofKind::LeftAtDoor);
+        }
+    }
+    if preferences.neighbor_allowed {
+        kinds.push(ProofKind::Neighbor);
+    }
+    kinds
+}
+
+// Shipments with preferences cannot be delivered without an accepted proof
+// recorded on the current delivery run
+pub(crate) fn check_proof(shipment: &Shipment) -> Result<(), String> {
+    let Some(preferences) = for_shipment(shipment) else {
+        return Ok(());
+    };
+    let recorded = tracking::with_events(shipment, |events| {
+        events
+            .iter()
+            .rev()
+            .take_while(|e| !(e.kind.is_none() && matches!(e.status, ShipmentStatus::OutForDelivery)))
+            .find_map(|e| match &e.kind {
+                Some(TrackingEventKind::DeliveryProofRecorded { proof }) => Some(proof.kind),
+                _ => None,
+            })
+    });
+    match recorded {
+        Some(kind) if acceptable_proofs(&preferences).contains(&kind) => Ok(()),
+        Some(kind) => Err(format!("{:?} is no longer an accepted proof of delivery; record another", kind)),
+        None => Err("Record proof of delivery before completing delivery".to_string()),
+    }
+}
+
+fn validate(preferences: &DeliveryPreferences) -> Result<(), String> {
+    if preferences.leave_at_door && preferences.require_signature {
+        return Err("Parcels left at the door cannot be signed for".to_string());
+    }
+    if let Some(window) = &preferences.preferred_window {
+        if window.end_minute > MINUTES_PER_DAY || window.start_minute >= window.end_minute {
+            return Err("Invalid delivery window".to_string());
+        }
+    }
+    Ok(())
+}
+
+fn check_evidence(
+    shipment: &Shipment,
+    driver_id: Principal,
+    proof: &DeliveryProof,
+    preferences: &DeliveryPreferences,
+) -> Result<(), String> {
+    let needs_attachment = match proof.kind {
+        ProofKind::Signature | ProofKind::LeftAtDoor => true,
+        ProofKind::Neighbor => preferences.require_signature,
+        ProofKind::InPerson => false,
+    };
+    let needs_receiver = matches!(proof.kind, ProofKind::Signature | ProofKind::Neighbor);
+    if needs_receiver && proof.received_by.is_none() {
+        return Err(format!("{:?} proof must name who took the parcel", proof.kind));
+    }
+    match &proof.attachment_id {
+        Some(attachment_id) if !attachments::uploaded_to_shipment(&shipment.id, attachment_id, driver_id) => {
+            Err("Attachment not found on this shipment".to_string())
+        },
+        None if needs_attachment => Err(format!("{:?} proof needs a signature or photo attachment", proof.kind)),
+        _ => Ok(()),
+    }
+}
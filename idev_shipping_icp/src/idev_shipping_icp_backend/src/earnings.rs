@@ -0,0 +1,271 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::contacts;
+use crate::metrics;
+use crate::money::Money;
+use crate::notifications::{self, NotificationKind};
+use crate::payments::{self, TransferFailure};
+use crate::permissions::{self, Permission};
+use crate::{idempotency, ShipmentStatus, DRIVERS, SHIPMENTS};
+
+// Driver earnings are held on the payment ledger in a per-driver subaccount of
+// this canister and tracked here entry by entry. Tips go to the driver in full;
+// no platform fee is taken from them.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum EarningKind {
+    Tip,
+    // Paid out to the driver's own account; amounts are debits
+    Withdrawal,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EarningEntry {
+    pub id: u64,
+    pub driver_id: Principal,
+    pub kind: EarningKind,
+    pub amount: Money,
+    pub shipment_id: Option<String>,
+    // Tipper for tips, the driver for withdrawals
+    pub counterparty: Principal,
+    pub block_index: Nat,
+    pub created_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EarningsReport {
+    pub driver_id: Principal,
+    // One amount per currency
+    pub balance: Vec<Money>,
+    pub tips: Vec<Money>,
+    pub tip_count: u32,
+    pub withdrawn: Vec<Money>,
+    pub entries: Vec<EarningEntry>,
+}
+
+thread_local! {
+    static EARNINGS: RefCell<HashMap<Principal, Vec<EarningEntry>>> = RefCell::new(HashMap::new());
+    static BALANCES: RefCell<HashMap<Principal, Vec<Money>>> = RefCell::new(HashMap::new());
+    static EARNING_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Tip the driver of a delivered shipment. The sender or linked recipient must
+// first approve this canister for the amount plus the ledger fee.
+#[update]
+async fn tip_driver(shipment_id: String, amount: Money, idempotency_key: Option<String>) -> Result<EarningEntry, String> {
+    metrics::observe_async("tip_driver", async move {
+        let caller = ic_cdk::caller();
+        if let Some(entry) = idempotency::begin(caller, idempotency_key.as_deref(), "tip_driver")? {
+            return Ok(entry);
+        }
+        let result = tip(caller, &shipment_id, amount).await;
+        idempotency::finish(caller, idempotency_key.as_deref(), &result);
+        result
+    })
+    .await
+}
+
+// Pay out part of the caller's balance to their own ledger account. The ledger
+// fee is deducted from the amount sent.
+#[update]
+async fn withdraw_earnings(amount: Money, idempotency_key: Option<String>) -> Result<EarningEntry, String> {
+    metrics::observe_async("withdraw_earnings", async move {
+        let caller = ic_cdk::caller();
+        if let Some(entry) = idempotency::begin(caller, idempotency_key.as_deref(), "withdraw_earnings")? {
+            return Ok(entry);
+        }
+        let result = withdraw(caller, amount).await;
+        idempotency::finish(caller, idempotency_key.as_deref(), &result);
+        result
+    })
+    .await
+}
+
+#[query]
+fn get_driver_earnings(driver_id: Option<Principal>, since: Option<u64>) -> Result<EarningsReport, String> {
+    let caller = ic_cdk::caller();
+    let driver_id = driver_id.unwrap_or(caller);
+    if driver_id != caller && !permissions::has(&caller, Permission::ManageFinances) {
+        return Err("Unauthorized to view earnings".to_string());
+    }
+
+    let since = since.unwrap_or(0);
+    let entries: Vec<EarningEntry> = EARNINGS.with(|earnings| {
+        earnings
+            .borrow()
+            .get(&driver_id)
+            .map(|entries| entries.iter().filter(|e| e.created_at >= since).cloned().collect())
+            .unwrap_or_default()
+    });
+    let mut tips = Vec::new();
+    let mut withdrawn = Vec::new();
+    let mut tip_count = 0;
+    for entry in &entries {
+        match entry.kind {
+            EarningKind::Tip => {
+                add_to(&mut tips, entry.amount);
+                tip_count += 1;
+            },
+            EarningKind::Withdrawal => add_to(&mut withdrawn, entry.amount),
+        }
+    }
+
+    Ok(EarningsReport {
+        driver_id,
+        balance: BALANCES.with(|balances| balances.borrow().get(&driver_id).cloned().unwrap_or_default()),
+        tips,
+        tip_count,
+        withdrawn,
+        entries,
+    })
+}
+
+// Number of tips and their total per currency, across all drivers
+pub(crate) fn tip_totals() -> (u32, Vec<Money>) {
+    EARNINGS.with(|earnings| {
+        let mut count = 0;
+        let mut totals = Vec::new();
+        for entry in earnings.borrow().values().flatten().filter(|e| e.kind == EarningKind::Tip) {
+            count += 1;
+            add_to(&mut totals, entry.amount);
+        }
+        (count, totals)
+    })
+}
+
+async fn tip(caller: Principal, shipment_id: &str, amount: Money) -> Result<EarningEntry, String> {
+    let shipment = SHIPMENTS
+        .with(|shipments| shipments.borrow().get(shipment_id).cloned())
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    if shipment.sender_id != caller && shipment.recipient_id != Some(caller) {
+        return Err("Unauthorized to tip for shipment".to_string());
+    }
+    if !matches!(shipment.status, ShipmentStatus::Delivered) {
+        return Err("Tips can only be given after delivery".to_string());
+    }
+    let driver_id = shipment.driver_id.ok_or_else(|| "Shipment has no driver".to_string())?;
+    let already_tipped = EARNINGS.with(|earnings| {
+        earnings.borrow().get(&driver_id).is_some_and(|entries| {
+            entries.iter().any(|e| {
+                e.kind == EarningKind::Tip && e.counterparty == caller && e.shipment_id.as_deref() == Some(shipment_id)
+            })
+        })
+    });
+    if already_tipped {
+        return Err("Shipment has already been tipped".to_string());
+    }
+
+    let ledger = payments::ledger()?;
+    if amount.currency != ledger.currency {
+        return Err(format!("Tips must be paid in {:?}", ledger.currency));
+    }
+    if amount.is_zero() {
+        return Err("Tip amount must be positive".to_string());
+    }
+
+    // The check above holds only while no other tip from the caller is out
+    let _lock = payments::lock(format!("tip:{}:{}", caller, shipment_id))
+        .ok_or_else(|| "A tip for this shipment is already in progress".to_string())?;
+    let memo = format!("tip:{}", shipment_id).into_bytes();
+    let block_index = payments::collect(&ledger, caller, earnings_subaccount(&driver_id), amount, memo)
+        .await
+        .map_err(TransferFailure::message)?;
+
+    let entry = record(driver_id, EarningKind::Tip, amount, Some(shipment_id.to_string()), caller, block_index);
+    BALANCES.with(|balances| add_to(balances.borrow_mut().entry(driver_id).or_default(), amount));
+    notifications::notify(
+        driver_id,
+        NotificationKind::Payment,
+        Some(shipment_id),
+        format!("You received a tip of {:.2} {} for shipment {}", amount.to_decimal(), amount.currency.symbol(), shipment_id),
+    );
+    Ok(entry)
+}
+
+async fn withdraw(caller: Principal, amount: Money) -> Result<EarningEntry, String> {
+    if !DRIVERS.with(|drivers| drivers.borrow().contains_key(&caller)) {
+        return Err("Driver not registered".to_string());
+    }
+    contacts::require_verified_contacts(caller)?;
+    let ledger = payments::ledger()?;
+    if amount.currency != ledger.currency {
+        return Err(format!("Withdrawals are paid in {:?}", ledger.currency));
+    }
+    let fee = payments::transfer_fee(&ledger).await?;
+    if amount.amount_e8s <= fee.amount_e8s {
+        return Err("Amount must exceed the ledger fee".to_string());
+    }
+
+    // Debit before the transfer so concurrent withdrawals cannot overdraw
+    BALANCES.with(|balances| {
+        let mut balances_map = balances.borrow_mut();
+        let balance = balances_map.entry(caller).or_default();
+        let available = balance.iter().find(|m| m.currency == amount.currency).map_or(0, |m| m.amount_e8s);
+        if available < amount.amount_e8s {
+            return Err("Insufficient earnings balance".to_string());
+        }
+        subtract_from(balance, amount);
+        Ok(())
+    })?;
+
+    let memo = format!("payout:{}", time()).into_bytes();
+    match payments::send(&ledger, earnings_subaccount(&caller), caller, amount.saturating_sub(fee), memo).await {
+        Ok(block_index) => Ok(record(caller, EarningKind::Withdrawal, amount, None, caller, block_index)),
+        Err(failure) => {
+            BALANCES.with(|balances| add_to(balances.borrow_mut().entry(caller).or_default(), amount));
+            Err(failure.message())
+        },
+    }
+}
+
+fn record(
+    driver_id: Principal,
+    kind: EarningKind,
+    amount: Money,
+    shipment_id: Option<String>,
+    counterparty: Principal,
+    block_index: Nat,
+) -> EarningEntry {
+    let id = EARNING_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        *c += 1;
+        *c
+    });
+    let entry = EarningEntry {
+        id,
+        driver_id,
+        kind,
+        amount,
+        shipment_id,
+        counterparty,
+        block_index,
+        created_at: time(),
+    };
+    EARNINGS.with(|earnings| earnings.borrow_mut().entry(driver_id).or_default().push(entry.clone()));
+    entry
+}
+
+// Subaccount holding one driver's earnings
+fn earnings_subaccount(driver_id: &Principal) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"earnings:");
+    hasher.update(driver_id.as_slice());
+    hasher.finalize().to_vec()
+}
+
+pub(crate) fn add_to(totals: &mut Vec<Money>, amount: Money) {
+    match totals.iter_mut().find(|m| m.currency == amount.currency) {
+        Some(total) => *total = total.add(amount),
+        None => totals.push(amount),
+    }
+}
+
+pub(crate) fn subtract_from(totals: &mut [Money], amount: Money) {
+    if let Some(total) = totals.iter_mut().find(|m| m.currency == amount.currency) {
+        *total = total.saturating_sub(amount);
+    }
+}
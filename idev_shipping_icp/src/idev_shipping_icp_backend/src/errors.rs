@@ -0,0 +1,83 @@
+use candid::{CandidType, Deserialize};
+use std::fmt;
+
+use crate::validation::FieldError;
+use crate::Shipment;
+
+// Typed failures for callers that need to react to the cause, not just show it.
+// Endpoints with a `Result<_, String>` interface return the Display text.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum ShippingError {
+    CapacityExceeded {
+        resource: CapacityResource,
+        required: f64,
+        available: f64,
+    },
+    RateLimited {
+        retry_after_secs: u64,
+    },
+    Validation {
+        errors: Vec<FieldError>,
+    },
+    // The shipment changed after the caller read `expected_version`; retry against `current`
+    Conflict {
+        expected_version: u64,
+        current: Box<Shipment>,
+    },
+    // Any other failure of an endpoint with a typed interface
+    Rejected {
+        message: String,
+    },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum CapacityResource {
+    WeightKg,
+    VolumeCm3,
+}
+
+impl fmt::Display for ShippingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShippingError::CapacityExceeded {
+                resource,
+                required,
+                available,
+            } => write!(
+                f,
+                "Capacity exceeded: {:?} required {:.2}, available {:.2}",
+                resource, required, available
+            ),
+            ShippingError::RateLimited { retry_after_secs } => {
+                write!(f, "Rate limit exceeded, retry after {} seconds", retry_after_secs)
+            },
+            ShippingError::Validation { errors } => {
+                let fields: Vec<String> = errors.iter().map(|e| format!("{} {}", e.field, e.message)).collect();
+                write!(f, "Invalid input: {}", fields.join("; "))
+            },
+            ShippingError::Conflict {
+                expected_version,
+                current,
+            } => write!(
+                f,
+                "Shipment {} changed: expected version {}, current version {}",
+                current.id,
+                expected_version,
+                current.current_version()
+            ),
+            ShippingError::Rejected { message } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<ShippingError> for String {
+    fn from(e: ShippingError) -> Self {
+        e.to_string()
+    }
+}
+
+impl From<String> for ShippingError {
+    fn from(message: String) -> Self {
+        ShippingError::Rejected { message }
+    }
+}
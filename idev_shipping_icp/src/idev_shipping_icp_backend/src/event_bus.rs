@@ -0,0 +1,200 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::events::ShipmentEventKind;
+use crate::metrics;
+use crate::outbox::{self, AttemptError, OutboxCommand, OutboxEntry, OutboxStatus};
+use crate::permissions::{self, Permission};
+use crate::resource_usage::{self, ResourceFeature};
+use crate::settings;
+use crate::{Shipment, ShipmentStatus};
+
+// Payload delivered to subscribers' `on_shipment_event` method. `seq` is globally
+// increasing, so subscribers can discard anything they have already applied.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShipmentLifecycleEvent {
+    pub seq: u64,
+    pub kind: ShipmentEventKind,
+    pub shipment_id: String,
+    pub status: ShipmentStatus,
+    pub sender_id: Principal,
+    pub driver_id: Option<Principal>,
+    pub occurred_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EventSubscription {
+    pub canister_id: Principal,
+    pub event_kinds: Vec<ShipmentEventKind>,
+    pub pending: u32,
+    pub dead_letters: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DeadLetter {
+    pub canister_id: Principal,
+    pub event: ShipmentLifecycleEvent,
+    pub attempts: u32,
+    pub last_error: String,
+}
+
+thread_local! {
+    static SUBSCRIPTIONS: RefCell<HashMap<Principal, Vec<ShipmentEventKind>>> = RefCell::new(HashMap::new());
+    static EVENT_SEQ: RefCell<u64> = RefCell::new(0);
+}
+
+// Admin management of subscriber canisters
+#[update]
+fn subscribe(canister_id: Principal, event_kinds: Vec<ShipmentEventKind>) -> Result<EventSubscription, String> {
+    metrics::observe("subscribe", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if event_kinds.is_empty() {
+            return Err("Subscribe to at least one event kind".to_string());
+        }
+
+        SUBSCRIPTIONS.with(|subscriptions| {
+            subscriptions.borrow_mut().insert(canister_id, event_kinds);
+        });
+        Ok(subscription_info(canister_id))
+    })
+}
+
+#[update]
+fn unsubscribe(canister_id: Principal) -> Result<(), String> {
+    metrics::observe("unsubscribe", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+
+        let removed = SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow_mut().remove(&canister_id));
+        if removed.is_none() {
+            return Err("Subscription not found".to_string());
+        }
+        outbox::discard_where(|e| delivers_to(e, canister_id));
+        Ok(())
+    })
+}
+
+#[query]
+fn get_event_subscriptions() -> Result<Vec<EventSubscription>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ManagePlatform)?;
+
+    let canisters: Vec<Principal> =
+        SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().keys().cloned().collect());
+    Ok(canisters.into_iter().map(subscription_info).collect())
+}
+
+#[query]
+fn get_dead_letters() -> Result<Vec<DeadLetter>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ManagePlatform)?;
+    Ok(outbox::entries_where(|e| e.status == OutboxStatus::DeadLettered)
+        .into_iter()
+        .filter_map(|e| match e.command {
+            OutboxCommand::DeliverEvent { canister_id, event } => Some(DeadLetter {
+                canister_id,
+                event,
+                attempts: e.attempts,
+                last_error: e.last_error.unwrap_or_default(),
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+// Put dead-lettered events for a subscriber back into its outbox, e.g. after a fix downstream
+#[update]
+fn requeue_dead_letters(canister_id: Principal) -> Result<u32, String> {
+    metrics::observe("requeue_dead_letters", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+
+        Ok(outbox::requeue_where(|e| delivers_to(e, canister_id)))
+    })
+}
+
+// Record the event in the outbox for every subscribed canister, in one lane per
+// subscriber so events arrive in order
+pub(crate) fn enqueue(shipment: &Shipment, kind: &ShipmentEventKind) {
+    let subscribers: Vec<Principal> = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions
+            .borrow()
+            .iter()
+            .filter(|(_, kinds)| kinds.contains(kind))
+            .map(|(canister, _)| *canister)
+            .collect()
+    });
+    if subscribers.is_empty() {
+        return;
+    }
+
+    let seq = EVENT_SEQ.with(|counter| {
+        let mut c = counter.borrow_mut();
+        *c += 1;
+        *c
+    });
+    let event = ShipmentLifecycleEvent {
+        seq,
+        kind: kind.clone(),
+        shipment_id: shipment.id.clone(),
+        status: shipment.status.clone(),
+        sender_id: shipment.sender_id,
+        driver_id: shipment.driver_id,
+        occurred_at: time(),
+    };
+
+    for canister in subscribers {
+        outbox::enqueue(
+            None,
+            Some(format!("event_bus:{}", canister)),
+            OutboxCommand::DeliverEvent {
+                canister_id: canister,
+                event: event.clone(),
+            },
+            settings::event_bus_max_attempts(),
+        );
+    }
+}
+
+// Outbox executor
+pub(crate) async fn deliver(canister: Principal, event: &ShipmentLifecycleEvent) -> Result<(), AttemptError> {
+    let payload_bytes = candid::encode_one(event).map(|b| b.len() as u64).unwrap_or(0);
+    resource_usage::record(
+        event.sender_id,
+        Some(&event.shipment_id),
+        ResourceFeature::EventBusCall,
+        resource_usage::xnet_call_cycles(payload_bytes),
+        0,
+    );
+    ic_cdk::call::<_, ()>(canister, "on_shipment_event", (event.clone(),))
+        .await
+        .map_err(|(code, message)| AttemptError::Retry(format!("{:?}: {}", code, message)))
+}
+
+fn delivers_to(entry: &OutboxEntry, canister: Principal) -> bool {
+    matches!(&entry.command, OutboxCommand::DeliverEvent { canister_id, .. } if *canister_id == canister)
+}
+
+fn subscription_info(canister_id: Principal) -> EventSubscription {
+    let event_kinds = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions
+            .borrow()
+            .get(&canister_id)
+            .cloned()
+            .unwrap_or_default()
+    });
+    let entries = outbox::entries_where(|e| delivers_to(e, canister_id));
+    let pending = entries.iter().filter(|e| e.status == OutboxStatus::Pending).count() as u32;
+    let dead_letters = entries.iter().filter(|e| e.status == OutboxStatus::DeadLettered).count() as u32;
+
+    EventSubscription {
+        canister_id,
+        event_kinds,
+        pending,
+        dead_letters,
+    }
+}
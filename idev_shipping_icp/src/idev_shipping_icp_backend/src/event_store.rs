@@ -0,0 +1,400 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+use crate::hubs::ShipmentLeg;
+use crate::memory::{self, StableMemory};
+use crate::metrics;
+use crate::money::Money;
+use crate::payments::PaymentRecord;
+use crate::permissions::{self, Permission};
+use crate::refunds::Adjustment;
+use crate::sharding::{self, ShipmentLocation};
+use crate::text_index;
+use crate::updates;
+use crate::{Address, CostBreakdown, PackageDetails, PaymentStatus, Shipment, ShipmentStatus, TrackingEvent, SHIPMENTS};
+
+// Sequence numbers are zero-padded so a shipment's events sort in order
+const SEQUENCE_DIGITS: usize = 10;
+
+// Every change to a stored shipment, in order. The `Shipment` record is the
+// projection of its stream; `replay_shipment` rebuilds it from here.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum ShipmentEvent {
+    ShipmentCreated {
+        shipment: Box<Shipment>,
+    },
+    DriverAssigned {
+        driver_id: Principal,
+        tracking: TrackingEvent,
+    },
+    // The driver gave the shipment back before pickup
+    DriverReleased {
+        tracking: TrackingEvent,
+    },
+    StatusChanged {
+        status: ShipmentStatus,
+        actual_delivery: Option<u64>,
+        tracking: TrackingEvent,
+    },
+    // A ledger call collecting the payment went out
+    PaymentStarted,
+    PaymentReceived {
+        payment: PaymentRecord,
+    },
+    PaymentFailed,
+    // The call ended without an outcome and the payment is back at `status`
+    PaymentAbandoned {
+        status: PaymentStatus,
+    },
+    // Cash on delivery was handed to the driver
+    CashCollected,
+    RefundIssued {
+        adjustment: Adjustment,
+        payment_status: PaymentStatus,
+    },
+    // A tracking event was added or annotated without a status change of its own
+    TrackingRecorded {
+        tracking: TrackingEvent,
+    },
+    RecipientLinked {
+        recipient_id: Principal,
+    },
+    // Repriced for the new address; `payment` includes any difference charged
+    DeliveryAddressChanged {
+        address: Box<Address>,
+        delivery_zone_id: Option<String>,
+        cost_breakdown: Box<CostBreakdown>,
+        quoted_price: Option<Money>,
+        sla_deadline: u64,
+        payment: Option<PaymentRecord>,
+        tracking: TrackingEvent,
+    },
+    // Other pending shipments were merged into this one
+    Consolidated {
+        package_details: Box<PackageDetails>,
+        cost_breakdown: Box<CostBreakdown>,
+        tracking: TrackingEvent,
+    },
+    // Routed through hubs, or a leg moved on
+    LegsChanged {
+        legs: Option<Vec<ShipmentLeg>>,
+        driver_id: Option<Principal>,
+    },
+    // The sender's ciphertext replaced any plaintext instructions
+    InstructionsEncrypted {
+        ciphertext: Option<Vec<u8>>,
+    },
+    // Replaces everything before it, e.g. once personal data has been scrubbed
+    Compacted {
+        shipment: Box<Shipment>,
+    },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RecordedEvent {
+    pub shipment_id: String,
+    pub sequence: u64,
+    // Shipment version once the event applied
+    pub version: u64,
+    pub recorded_at: u64,
+    pub actor: Principal,
+    pub event: ShipmentEvent,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ReplayReport {
+    pub shipment_id: String,
+    pub events: u32,
+    pub version: u64,
+    // Whether the stored record equals the projection of its events
+    pub in_sync: bool,
+    pub repaired: bool,
+}
+
+thread_local! {
+    // "<shipment id>#<sequence>" -> candid-encoded RecordedEvent
+    static EVENT_LOG: RefCell<StableBTreeMap<String, Vec<u8>, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::SHIPMENT_EVENTS)));
+    // Shipment id -> sequence number its next event gets, so appending never reads the stream
+    static NEXT_SEQUENCES: RefCell<StableBTreeMap<String, u64, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::SHIPMENT_EVENT_SEQUENCES)));
+}
+
+// Oldest first. Parties see their own shipments' streams, support any.
+#[query]
+fn get_shipment_event_log(shipment_id: String) -> Result<Vec<RecordedEvent>, String> {
+    can_view(&ic_cdk::caller(), &shipment_id)?;
+    Ok(events_of(&shipment_id))
+}
+
+// Rebuild a shipment from its events and compare with the stored record. With
+// `repair`, a record that drifted (e.g. after a bug in a mutation) is replaced
+// by the projection.
+#[update]
+fn replay_shipment(shipment_id: String, repair: bool) -> Result<ReplayReport, String> {
+    metrics::observe("replay_shipment", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        // Archived and offloaded shipments are kept elsewhere, not missing from here
+        if matches!(sharding::locate(&shipment_id), ShipmentLocation::Archived | ShipmentLocation::Shard(_)) {
+            return Err("Shipment has moved out of hot storage".to_string());
+        }
+        let events = events_of(&shipment_id);
+        let projected = project(&events).ok_or_else(|| "Shipment has no recorded events".to_string())?;
+        let stored = SHIPMENTS.with(|shipments| shipments.borrow().get(&shipment_id).cloned());
+        let in_sync = stored.as_ref().is_some_and(|s| same_record(s, &projected));
+        let repaired = repair && !in_sync;
+        if repaired {
+            text_index::index_shipment(&projected);
+            SHIPMENTS.with(|shipments| shipments.borrow_mut().insert(shipment_id.clone(), projected.clone()));
+        }
+        Ok(ReplayReport {
+            shipment_id,
+            events: events.len() as u32,
+            version: projected.current_version(),
+            in_sync,
+            repaired,
+        })
+    })
+}
+
+// Append an event for the change just made to `shipment`
+pub(crate) fn record(shipment: &Shipment, actor: Principal, event: ShipmentEvent) {
+    append(shipment, actor, event, next_sequence(&shipment.id));
+}
+
+fn append(shipment: &Shipment, actor: Principal, event: ShipmentEvent, sequence: u64) {
+    let recorded = RecordedEvent {
+        shipment_id: shipment.id.clone(),
+        sequence,
+        version: shipment.current_version(),
+        recorded_at: time(),
+        actor,
+        event,
+    };
+    let encoded = candid::encode_one(&recorded).expect("failed to encode shipment event");
+    EVENT_LOG.with(|log| log.borrow_mut().insert(key(&shipment.id, sequence), encoded));
+    NEXT_SEQUENCES.with(|next| next.borrow_mut().insert(shipment.id.clone(), sequence + 1));
+    updates::publish(&recorded, shipment);
+}
+
+// The helpers below read the event off `shipment` as just changed, whose latest
+// tracking event describes the change
+pub(crate) fn status_changed(shipment: &Shipment, actor: Principal) {
+    if let Some(tracking) = shipment.tracking_history.last().cloned() {
+        let event = ShipmentEvent::StatusChanged {
+            status: shipment.status.clone(),
+            actual_delivery: shipment.actual_delivery,
+            tracking,
+        };
+        record(shipment, actor, event);
+    }
+}
+
+pub(crate) fn driver_assigned(shipment: &Shipment, actor: Principal) {
+    let tracking = shipment.tracking_history.last().cloned();
+    let event = match (shipment.driver_id, tracking) {
+        (Some(driver_id), Some(tracking)) => ShipmentEvent::DriverAssigned { driver_id, tracking },
+        (None, Some(tracking)) => ShipmentEvent::DriverReleased { tracking },
+        (_, None) => return,
+    };
+    record(shipment, actor, event);
+}
+
+pub(crate) fn tracked(shipment: &Shipment, actor: Principal) {
+    if let Some(tracking) = shipment.tracking_history.last().cloned() {
+        record(shipment, actor, ShipmentEvent::TrackingRecorded { tracking });
+    }
+}
+
+// Collapse the stream into one snapshot of `shipment`, dropping earlier events
+// and whatever personal data they held. Also starts the stream of shipments
+// that arrive without one, e.g. from a backup.
+pub(crate) fn compact(shipment: &Shipment, actor: Principal) {
+    // Sequence numbers carry on, so readers holding an old one see the gap
+    let sequence = next_sequence(&shipment.id);
+    forget(&shipment.id);
+    let event = ShipmentEvent::Compacted {
+        shipment: Box::new(shipment.clone()),
+    };
+    append(shipment, actor, event, sequence);
+}
+
+// Drop a shipment's stream, for records leaving this canister
+pub(crate) fn forget(shipment_id: &str) {
+    let keys: Vec<String> = EVENT_LOG.with(|log| stream(&log.borrow(), shipment_id).map(|(k, _)| k).collect());
+    EVENT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        for key in &keys {
+            log.remove(key);
+        }
+    });
+    NEXT_SEQUENCES.with(|next| next.borrow_mut().remove(&shipment_id.to_string()));
+}
+
+pub(crate) fn can_view(caller: &Principal, shipment_id: &str) -> Result<(), String> {
+    let party = SHIPMENTS.with(|shipments| {
+        shipments.borrow().get(shipment_id).is_some_and(|s| {
+            s.sender_id == *caller || s.driver_id == Some(*caller) || s.recipient_id == Some(*caller)
+        })
+    });
+    if !party && !permissions::has(caller, Permission::ViewAllShipments) {
+        return Err("Unauthorized to view shipment events".to_string());
+    }
+    Ok(())
+}
+
+// Events with a sequence number above `after`, oldest first
+pub(crate) fn events_after(shipment_id: &str, after: Option<u64>, limit: usize) -> Vec<RecordedEvent> {
+    let start = key(shipment_id, after.map_or(0, |seq| seq + 1));
+    let prefix = format!("{}#", shipment_id);
+    EVENT_LOG.with(|log| {
+        log.borrow()
+            .range(start..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter_map(|(_, bytes)| candid::decode_one(&bytes).ok())
+            .take(limit)
+            .collect()
+    })
+}
+
+// None once compacted away
+pub(crate) fn event_at(shipment_id: &str, sequence: u64) -> Option<RecordedEvent> {
+    EVENT_LOG
+        .with(|log| log.borrow().get(&key(shipment_id, sequence)))
+        .and_then(|bytes| candid::decode_one(&bytes).ok())
+}
+
+pub(crate) fn events_of(shipment_id: &str) -> Vec<RecordedEvent> {
+    EVENT_LOG.with(|log| {
+        stream(&log.borrow(), shipment_id)
+            .filter_map(|(_, bytes)| candid::decode_one(&bytes).ok())
+            .collect()
+    })
+}
+
+// Fold a stream into the shipment it describes
+pub(crate) fn project(events: &[RecordedEvent]) -> Option<Shipment> {
+    let mut shipment: Option<Shipment> = None;
+    for recorded in events {
+        match &recorded.event {
+            ShipmentEvent::ShipmentCreated { shipment: s } | ShipmentEvent::Compacted { shipment: s } => {
+                shipment = Some((**s).clone())
+            },
+            event => {
+                let Some(s) = shipment.as_mut() else { continue };
+                apply(s, event);
+                s.updated_at = recorded.recorded_at;
+            },
+        }
+        if let Some(s) = shipment.as_mut() {
+            s.version = Some(recorded.version);
+        }
+    }
+    shipment
+}
+
+fn apply(shipment: &mut Shipment, event: &ShipmentEvent) {
+    match event {
+        ShipmentEvent::DriverAssigned { driver_id, tracking } => {
+            shipment.driver_id = Some(*driver_id);
+            shipment.status = tracking.status.clone();
+            shipment.tracking_history = vec![tracking.clone()];
+        },
+        ShipmentEvent::DriverReleased { tracking } => {
+            shipment.driver_id = None;
+            shipment.status = tracking.status.clone();
+            shipment.tracking_history = vec![tracking.clone()];
+        },
+        ShipmentEvent::StatusChanged {
+            status,
+            actual_delivery,
+            tracking,
+        } => {
+            shipment.status = status.clone();
+            shipment.actual_delivery = *actual_delivery;
+            shipment.tracking_history = vec![tracking.clone()];
+        },
+        ShipmentEvent::PaymentReceived { payment } => {
+            shipment.payment_status = PaymentStatus::Paid;
+            shipment.payment = Some(payment.clone());
+        },
+        ShipmentEvent::PaymentStarted => shipment.payment_status = PaymentStatus::Processing,
+        ShipmentEvent::PaymentFailed => shipment.payment_status = PaymentStatus::Failed,
+        ShipmentEvent::PaymentAbandoned { status } => shipment.payment_status = status.clone(),
+        ShipmentEvent::CashCollected => shipment.payment_status = PaymentStatus::Paid,
+        ShipmentEvent::RefundIssued {
+            adjustment,
+            payment_status,
+        } => {
+            shipment.adjustments.get_or_insert_with(Vec::new).push(adjustment.clone());
+            shipment.payment_status = payment_status.clone();
+        },
+        ShipmentEvent::TrackingRecorded { tracking } => shipment.tracking_history = vec![tracking.clone()],
+        ShipmentEvent::RecipientLinked { recipient_id } => shipment.recipient_id = Some(*recipient_id),
+        ShipmentEvent::DeliveryAddressChanged {
+            address,
+            delivery_zone_id,
+            cost_breakdown,
+            quoted_price,
+            sla_deadline,
+            payment,
+            tracking,
+        } => {
+            shipment.delivery_address = (**address).clone();
+            shipment.delivery_zone_id = delivery_zone_id.clone();
+            shipment.price = cost_breakdown.total;
+            shipment.cost = cost_breakdown.total.to_decimal();
+            shipment.cost_breakdown = (**cost_breakdown).clone();
+            shipment.quoted_price = *quoted_price;
+            shipment.sla_deadline = *sla_deadline;
+            shipment.estimated_delivery = Some(*sla_deadline);
+            shipment.payment = payment.clone();
+            shipment.tracking_history = vec![tracking.clone()];
+        },
+        ShipmentEvent::Consolidated {
+            package_details,
+            cost_breakdown,
+            tracking,
+        } => {
+            shipment.package_details = (**package_details).clone();
+            shipment.price = cost_breakdown.total;
+            shipment.cost = cost_breakdown.total.to_decimal();
+            shipment.cost_breakdown = (**cost_breakdown).clone();
+            shipment.tracking_history = vec![tracking.clone()];
+        },
+        ShipmentEvent::LegsChanged { legs, driver_id } => {
+            shipment.legs = legs.clone();
+            shipment.driver_id = *driver_id;
+        },
+        ShipmentEvent::InstructionsEncrypted { ciphertext } => {
+            shipment.encrypted_instructions = ciphertext.clone();
+            shipment.package_details.special_instructions = None;
+        },
+        ShipmentEvent::ShipmentCreated { .. } | ShipmentEvent::Compacted { .. } => {},
+    }
+}
+
+// Shipment has no PartialEq; its candid encoding stands in
+fn same_record(a: &Shipment, b: &Shipment) -> bool {
+    candid::encode_one(a).ok() == candid::encode_one(b).ok()
+}
+
+fn next_sequence(shipment_id: &str) -> u64 {
+    NEXT_SEQUENCES.with(|next| next.borrow().get(&shipment_id.to_string())).unwrap_or(0)
+}
+
+fn stream<'a>(
+    log: &'a StableBTreeMap<String, Vec<u8>, StableMemory>,
+    shipment_id: &str,
+) -> impl Iterator<Item = (String, Vec<u8>)> + 'a {
+    let prefix = format!("{}#", shipment_id);
+    log.range(prefix.clone()..).take_while(move |(k, _)| k.starts_with(&prefix))
+}
+
+fn key(shipment_id: &str, sequence: u64) -> String {
+    format!("{}#{:0width$}", shipment_id, sequence, width = SEQUENCE_DIGITS)
+}
@@ -0,0 +1,39 @@
+use candid::{CandidType, Deserialize};
+
+use crate::{analytics, event_bus, loyalty, nft_receipts, referrals, webhooks, Shipment, ShipmentStatus};
+
+// Shipment lifecycle events fanned out to webhooks and subscribed canisters
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ShipmentEventKind {
+    Created,
+    DriverAssigned,
+    StatusChanged,
+    Delivered,
+    Disputed,
+    Cancelled,
+}
+
+pub(crate) fn publish(shipment: &Shipment, kind: ShipmentEventKind) {
+    analytics::record_event(shipment, &kind);
+    webhooks::enqueue_event(shipment, &kind);
+    event_bus::enqueue(shipment, &kind);
+    if kind == ShipmentEventKind::Delivered {
+        nft_receipts::enqueue(shipment);
+        loyalty::award(shipment);
+        referrals::on_delivered(shipment);
+    }
+    if kind == ShipmentEventKind::Cancelled {
+        loyalty::revoke(shipment, "cancelled");
+    }
+}
+
+// Publish the event matching the shipment's current status
+pub(crate) fn publish_status_change(shipment: &Shipment) {
+    let kind = match shipment.status {
+        ShipmentStatus::Delivered => ShipmentEventKind::Delivered,
+        ShipmentStatus::Disputed => ShipmentEventKind::Disputed,
+        ShipmentStatus::Cancelled => ShipmentEventKind::Cancelled,
+        _ => ShipmentEventKind::StatusChanged,
+    };
+    publish(shipment, kind);
+}
@@ -0,0 +1,164 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::money::{Currency, Money, BASE_CURRENCY};
+use crate::resource_usage::{self, ResourceFeature};
+
+// IC Exchange Rate Canister on the uzr34 system subnet
+const XRC_CANISTER_ID: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
+// Cycles attached to each get_exchange_rate call; unused cycles are refunded
+const XRC_CALL_CYCLES: u128 = 1_000_000_000;
+const RATE_TTL_NANOS: u64 = 10 * 60 * 1_000_000_000;
+
+// Rate used to convert a base-currency amount: quote = base * rate / 10^decimals
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AppliedRate {
+    pub base: Currency,
+    pub quote: Currency,
+    pub rate: u64,
+    pub decimals: u32,
+    pub rate_timestamp: u64,
+    pub fetched_at: u64,
+}
+
+impl AppliedRate {
+    pub fn convert(&self, amount: Money) -> Money {
+        let converted = amount.mul_ratio(self.rate as u128, 10u128.pow(self.decimals));
+        Money {
+            amount_e8s: converted.amount_e8s,
+            currency: self.quote,
+        }
+    }
+}
+
+// XRC interface types
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum AssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Asset {
+    symbol: String,
+    class: AssetClass,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct GetExchangeRateRequest {
+    base_asset: Asset,
+    quote_asset: Asset,
+    timestamp: Option<u64>,
+}
+
+// Only the fields read here; candid skips the rest of the record
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ExchangeRateMetadata {
+    decimals: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ExchangeRate {
+    timestamp: u64,
+    rate: u64,
+    metadata: ExchangeRateMetadata,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct OtherError {
+    code: u32,
+    description: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum ExchangeRateError {
+    AnonymousPrincipalNotAllowed,
+    Pending,
+    CryptoBaseAssetNotFound,
+    CryptoQuoteAssetNotFound,
+    StablecoinRateNotFound,
+    StablecoinRateTooFewRates,
+    StablecoinRateZeroRate,
+    ForexInvalidTimestamp,
+    ForexBaseAssetNotFound,
+    ForexQuoteAssetNotFound,
+    ForexAssetsNotFound,
+    RateLimited,
+    NotEnoughCycles,
+    FailedToAcceptCycles,
+    InconsistentRatesReceived,
+    Other(OtherError),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum GetExchangeRateResult {
+    Ok(ExchangeRate),
+    Err(ExchangeRateError),
+}
+
+thread_local! {
+    static RATE_CACHE: RefCell<HashMap<Currency, AppliedRate>> = RefCell::new(HashMap::new());
+}
+
+#[query]
+fn get_exchange_rates() -> Vec<AppliedRate> {
+    RATE_CACHE.with(|cache| cache.borrow().values().cloned().collect())
+}
+
+// Rate from the base currency to `quote`, served from cache while fresh. XRC fees
+// are attributed to `account`.
+pub(crate) async fn rate_for(quote: Currency, account: Principal) -> Result<AppliedRate, String> {
+    let now = time();
+    let cached = RATE_CACHE.with(|cache| cache.borrow().get(&quote).cloned());
+    if let Some(rate) = cached {
+        if now.saturating_sub(rate.fetched_at) < RATE_TTL_NANOS {
+            return Ok(rate);
+        }
+    }
+
+    let request = GetExchangeRateRequest {
+        base_asset: asset(BASE_CURRENCY),
+        quote_asset: asset(quote),
+        timestamp: None,
+    };
+    let xrc = Principal::from_text(XRC_CANISTER_ID).map_err(|e| e.to_string())?;
+    resource_usage::record(account, None, ResourceFeature::ExchangeRateCall, XRC_CALL_CYCLES, 0);
+    let (result,): (GetExchangeRateResult,) =
+        ic_cdk::api::call::call_with_payment128(xrc, "get_exchange_rate", (request,), XRC_CALL_CYCLES)
+            .await
+            .map_err(|(code, message)| format!("Exchange rate call failed: {:?}: {}", code, message))?;
+
+    match result {
+        GetExchangeRateResult::Ok(rate) => {
+            let applied = AppliedRate {
+                base: BASE_CURRENCY,
+                quote,
+                rate: rate.rate,
+                decimals: rate.metadata.decimals,
+                rate_timestamp: rate.timestamp,
+                fetched_at: time(),
+            };
+            RATE_CACHE.with(|cache| cache.borrow_mut().insert(quote, applied.clone()));
+            Ok(applied)
+        },
+        GetExchangeRateResult::Err(ExchangeRateError::Other(e)) => Err(format!(
+            "Exchange rate unavailable for {:?}: {} ({})",
+            quote, e.description, e.code
+        )),
+        GetExchangeRateResult::Err(e) => Err(format!("Exchange rate unavailable for {:?}: {:?}", quote, e)),
+    }
+}
+
+fn asset(currency: Currency) -> Asset {
+    let class = match currency {
+        Currency::Icp => AssetClass::Cryptocurrency,
+        _ => AssetClass::FiatCurrency,
+    };
+    Asset {
+        symbol: currency.symbol().to_string(),
+        class,
+    }
+}
@@ -0,0 +1,368 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::audit::{self, AuditAction};
+use crate::earnings::{add_to, subtract_from};
+use crate::metrics;
+use crate::money::{Currency, Money};
+use crate::outbox::{self, AttemptError, OutboxCommand};
+use crate::payments::{self, TransferFailure};
+use crate::permissions::{self, Permission};
+use crate::settings::{self, SettingsPatch};
+use crate::{idempotency, is_controller, PaymentStatus, Shipment};
+
+const BPS_DENOMINATOR: u32 = 10_000;
+// Retries stay well inside the ledger's deduplication window, so a sweep whose
+// reply was lost is never paid twice
+const MAX_SWEEP_ATTEMPTS: u32 = 8;
+
+// The platform's cut of every delivered shipment paid on the ledger. Fees are
+// moved from the shipment's escrow into the treasury subaccount through the outbox;
+// cash on delivery fees are settled through remittances instead.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FeePolicy {
+    // Of the net paid amount, in basis points
+    pub percentage_bps: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum FeeStatus {
+    // Still in the shipment's escrow
+    Pending,
+    Swept { block_index: Nat },
+    // The sweep was dead-lettered; requeueing its outbox entry retries it
+    Failed(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FeeEntry {
+    pub shipment_id: String,
+    pub amount: Money,
+    pub percentage_bps: u32,
+    pub status: FeeStatus,
+    // Charged to the escrow for the sweep
+    pub ledger_fee: Option<Money>,
+    pub accrued_at: u64,
+    pub swept_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TreasuryWithdrawal {
+    pub id: u64,
+    pub account: Principal,
+    // Debited from the treasury; the ledger fee comes out of it
+    pub amount: Money,
+    pub block_index: Nat,
+    pub withdrawn_by: Principal,
+    pub withdrawn_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Treasury {
+    // One amount per currency
+    pub balance: Vec<Money>,
+    // Accrued but not yet swept
+    pub pending: Vec<Money>,
+    pub subaccount: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FeeReport {
+    pub from: u64,
+    pub to: u64,
+    pub shipment_count: u32,
+    pub accrued: Vec<Money>,
+    pub swept: Vec<Money>,
+    pub withdrawn: Vec<Money>,
+    pub entries: Vec<FeeEntry>,
+    pub withdrawals: Vec<TreasuryWithdrawal>,
+}
+
+thread_local! {
+    static FEES: RefCell<HashMap<String, FeeEntry>> = RefCell::new(HashMap::new());
+    static TREASURY_BALANCE: RefCell<Vec<Money>> = RefCell::new(Vec::new());
+    static WITHDRAWALS: RefCell<Vec<TreasuryWithdrawal>> = RefCell::new(Vec::new());
+    static WITHDRAWAL_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Admin configuration; applies to shipments delivered from now on. Kept in the
+// platform settings.
+#[update]
+fn set_fee_policy(policy: FeePolicy) -> Result<FeePolicy, String> {
+    metrics::observe("set_fee_policy", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManageFinances)?;
+        let patch = SettingsPatch {
+            platform_fee_bps: Some(policy.percentage_bps),
+            ..Default::default()
+        };
+        settings::apply(caller, patch)?;
+        Ok(policy)
+    })
+}
+
+#[query]
+fn get_fee_policy() -> FeePolicy {
+    FeePolicy {
+        percentage_bps: settings::platform_fee_bps(),
+    }
+}
+
+#[query]
+fn get_treasury() -> Result<Treasury, String> {
+    let caller = ic_cdk::caller();
+    if !permissions::has(&caller, Permission::ManageFinances) && !is_controller(&caller) {
+        return Err("Unauthorized to view the treasury".to_string());
+    }
+    let mut pending = Vec::new();
+    FEES.with(|fees| {
+        for entry in fees.borrow().values().filter(|e| e.status == FeeStatus::Pending) {
+            add_to(&mut pending, entry.amount);
+        }
+    });
+    Ok(Treasury {
+        balance: TREASURY_BALANCE.with(|b| b.borrow().clone()),
+        pending,
+        subaccount: treasury_subaccount(),
+    })
+}
+
+// Fees accrued and withdrawals made in [from, to)
+#[query]
+fn get_fee_report(from: u64, to: Option<u64>) -> Result<FeeReport, String> {
+    let caller = ic_cdk::caller();
+    if !permissions::has(&caller, Permission::ManageFinances) && !is_controller(&caller) {
+        return Err("Unauthorized to view fee reports".to_string());
+    }
+    let to = to.unwrap_or(u64::MAX);
+    if to <= from {
+        return Err("Report period is empty".to_string());
+    }
+
+    let mut entries: Vec<FeeEntry> = FEES.with(|fees| {
+        fees.borrow()
+            .values()
+            .filter(|e| e.accrued_at >= from && e.accrued_at < to)
+            .cloned()
+            .collect()
+    });
+    entries.sort_by_key(|e| e.accrued_at);
+    let withdrawals: Vec<TreasuryWithdrawal> = WITHDRAWALS.with(|w| {
+        w.borrow()
+            .iter()
+            .filter(|w| w.withdrawn_at >= from && w.withdrawn_at < to)
+            .cloned()
+            .collect()
+    });
+
+    let mut accrued = Vec::new();
+    let mut swept = Vec::new();
+    for entry in &entries {
+        add_to(&mut accrued, entry.amount);
+        if matches!(entry.status, FeeStatus::Swept { .. }) {
+            add_to(&mut swept, entry.amount);
+        }
+    }
+    let mut withdrawn = Vec::new();
+    for withdrawal in &withdrawals {
+        add_to(&mut withdrawn, withdrawal.amount);
+    }
+    Ok(FeeReport {
+        from,
+        to,
+        shipment_count: entries.len() as u32,
+        accrued,
+        swept,
+        withdrawn,
+        entries,
+        withdrawals,
+    })
+}
+
+// Controller only: pay part of the treasury out to `account`. The ledger fee is
+// deducted from the amount sent.
+#[update]
+async fn withdraw_fees(
+    account: Principal,
+    amount: Money,
+    idempotency_key: Option<String>,
+) -> Result<TreasuryWithdrawal, String> {
+    metrics::observe_async("withdraw_fees", async move {
+        let caller = ic_cdk::caller();
+        if let Some(withdrawal) = idempotency::begin(caller, idempotency_key.as_deref(), "withdraw_fees")? {
+            return Ok(withdrawal);
+        }
+        let result = withdraw(caller, account, amount).await;
+        idempotency::finish(caller, idempotency_key.as_deref(), &result);
+        result
+    })
+    .await
+}
+
+// Called when a shipment is delivered
+pub(crate) fn accrue(shipment: &Shipment) {
+    let Some(payment) = &shipment.payment else {
+        return;
+    };
+    if payment.ledger.is_none() || !matches!(shipment.payment_status, PaymentStatus::Paid) {
+        return;
+    }
+    if FEES.with(|fees| fees.borrow().contains_key(&shipment.id)) {
+        return;
+    }
+    let net_paid = shipment
+        .adjustments
+        .iter()
+        .flatten()
+        .fold(payment.amount, |left, a| left.saturating_sub(a.amount).saturating_sub(a.ledger_fee));
+    let percentage_bps = settings::platform_fee_bps();
+    let amount = net_paid.mul_ratio(percentage_bps as u128, BPS_DENOMINATOR as u128);
+    if amount.is_zero() {
+        return;
+    }
+    let entry = FeeEntry {
+        shipment_id: shipment.id.clone(),
+        amount,
+        percentage_bps,
+        status: FeeStatus::Pending,
+        ledger_fee: None,
+        accrued_at: time(),
+        swept_at: None,
+    };
+    FEES.with(|fees| fees.borrow_mut().insert(shipment.id.clone(), entry));
+    outbox::enqueue(
+        Some(format!("fee:{}", shipment.id)),
+        None,
+        OutboxCommand::SweepFee {
+            shipment_id: shipment.id.clone(),
+            amount,
+        },
+        MAX_SWEEP_ATTEMPTS,
+    );
+}
+
+// What the fee takes out of a shipment's escrow, so refunds leave it in place
+pub(crate) fn reserved(shipment_id: &str, currency: Currency) -> Money {
+    FEES.with(|fees| match fees.borrow().get(shipment_id) {
+        Some(entry) if !matches!(entry.status, FeeStatus::Failed(_)) => {
+            entry.amount.add(entry.ledger_fee.unwrap_or(Money::zero(currency)))
+        },
+        _ => Money::zero(currency),
+    })
+}
+
+// Outbox executor. `created_at` is fixed per sweep, so the ledger treats a retry
+// of a transfer that already went through as a duplicate.
+pub(crate) async fn sweep(shipment_id: &str, amount: Money, created_at: u64) -> Result<(), AttemptError> {
+    let already_swept = FEES.with(|fees| {
+        fees.borrow()
+            .get(shipment_id)
+            .is_some_and(|e| matches!(e.status, FeeStatus::Swept { .. }))
+    });
+    if already_swept {
+        return Ok(());
+    }
+    let ledger = payments::ledger().map_err(AttemptError::Retry)?;
+    if amount.currency != ledger.currency {
+        return Err(AttemptError::Permanent("Paid on a different ledger".to_string()));
+    }
+    // Waits out a refund from the same escrow
+    let _lock = payments::lock(payments::escrow_lock_key(shipment_id))
+        .ok_or_else(|| AttemptError::Retry("Escrow is in use by a refund".to_string()))?;
+    let ledger_fee = payments::transfer_fee(&ledger).await.map_err(AttemptError::Retry)?;
+    let escrow = payments::escrow_subaccount(shipment_id);
+    let memo = format!("fee:{}", shipment_id).into_bytes();
+    match payments::move_funds(&ledger, escrow, treasury_subaccount(), amount, memo, created_at).await {
+        Ok(block_index) => {
+            set_status(shipment_id, FeeStatus::Swept { block_index }, Some(ledger_fee));
+            TREASURY_BALANCE.with(|b| add_to(&mut b.borrow_mut(), amount));
+            Ok(())
+        },
+        Err(TransferFailure::Unreachable(message)) => Err(AttemptError::Retry(message)),
+        Err(TransferFailure::Rejected(message)) => Err(AttemptError::Permanent(message)),
+    }
+}
+
+pub(crate) fn sweep_failed(shipment_id: &str, error: &str) {
+    set_status(shipment_id, FeeStatus::Failed(error.to_string()), None);
+}
+
+fn set_status(shipment_id: &str, status: FeeStatus, ledger_fee: Option<Money>) {
+    FEES.with(|fees| {
+        if let Some(entry) = fees.borrow_mut().get_mut(shipment_id) {
+            if matches!(status, FeeStatus::Swept { .. }) {
+                entry.swept_at = Some(time());
+            }
+            entry.status = status;
+            entry.ledger_fee = ledger_fee;
+        }
+    });
+}
+
+async fn withdraw(caller: Principal, account: Principal, amount: Money) -> Result<TreasuryWithdrawal, String> {
+    if !is_controller(&caller) {
+        return Err("Only canister controllers can withdraw fees".to_string());
+    }
+    let ledger = payments::ledger()?;
+    if amount.currency != ledger.currency {
+        return Err(format!("Withdrawals are paid in {:?}", ledger.currency));
+    }
+    let fee = payments::transfer_fee(&ledger).await?;
+    if amount.amount_e8s <= fee.amount_e8s {
+        return Err("Amount must exceed the ledger fee".to_string());
+    }
+
+    // Debit before the transfer so concurrent withdrawals cannot overdraw
+    TREASURY_BALANCE.with(|balance| {
+        let mut balance = balance.borrow_mut();
+        let available = balance.iter().find(|m| m.currency == amount.currency).map_or(0, |m| m.amount_e8s);
+        if available < amount.amount_e8s {
+            return Err("Insufficient treasury balance".to_string());
+        }
+        subtract_from(&mut balance, amount);
+        Ok(())
+    })?;
+
+    let memo = format!("treasury:{}", time()).into_bytes();
+    let block_index = match payments::send(&ledger, treasury_subaccount(), account, amount.saturating_sub(fee), memo).await
+    {
+        Ok(block_index) => block_index,
+        Err(failure) => {
+            TREASURY_BALANCE.with(|b| add_to(&mut b.borrow_mut(), amount));
+            return Err(failure.message());
+        },
+    };
+
+    let id = WITHDRAWAL_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        *c += 1;
+        *c
+    });
+    let withdrawal = TreasuryWithdrawal {
+        id,
+        account,
+        amount,
+        block_index: block_index.clone(),
+        withdrawn_by: caller,
+        withdrawn_at: time(),
+    };
+    WITHDRAWALS.with(|w| w.borrow_mut().push(withdrawal.clone()));
+    audit::record(
+        caller,
+        account,
+        AuditAction::FeesWithdrawn,
+        format!("{:.2} {} at block {}", amount.to_decimal(), amount.currency.symbol(), block_index),
+    );
+    Ok(withdrawal)
+}
+
+// Subaccount holding swept platform fees
+fn treasury_subaccount() -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"treasury");
+    hasher.finalize().to_vec()
+}
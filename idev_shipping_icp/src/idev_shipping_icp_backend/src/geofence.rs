@@ -0,0 +1,189 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use crate::event_store;
+use crate::handling;
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::resource_usage;
+use crate::tracking;
+use crate::{apply_status_update, Coordinates, Shipment, ShipmentStatus, TrackingEvent, TrackingEventKind, SHIPMENTS};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub enum Fence {
+    Pickup,
+    Delivery,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum GeofenceMode {
+    Off,
+    // Record the entry and tell the driver which update is due
+    Suggest,
+    // Record the entry and make the update on the driver's behalf
+    Apply,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GeofencePolicy {
+    pub mode: GeofenceMode,
+    pub radius_m: u32,
+}
+
+// A fence the driver just entered and the status it implies, if any
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GeofenceTrigger {
+    pub shipment_id: String,
+    pub fence: Fence,
+    pub distance_m: u32,
+    pub status: Option<ShipmentStatus>,
+    pub applied: bool,
+}
+
+thread_local! {
+    static GEOFENCE_POLICY: RefCell<GeofencePolicy> = RefCell::new(GeofencePolicy {
+        mode: GeofenceMode::Suggest,
+        radius_m: 150,
+    });
+    // (shipment id, fence, status at entry) already reported, so a driver
+    // lingering inside a fence triggers it once
+    static TRIGGERED: RefCell<HashSet<(String, Fence, String)>> = RefCell::new(HashSet::new());
+}
+
+#[update]
+fn set_geofence_policy(policy: GeofencePolicy) -> Result<GeofencePolicy, String> {
+    metrics::observe("set_geofence_policy", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if !(25..=5_000).contains(&policy.radius_m) {
+            return Err("Radius must be between 25 and 5000 meters".to_string());
+        }
+        GEOFENCE_POLICY.with(|p| *p.borrow_mut() = policy.clone());
+        Ok(policy)
+    })
+}
+
+#[query]
+fn get_geofence_policy() -> GeofencePolicy {
+    GEOFENCE_POLICY.with(|p| p.borrow().clone())
+}
+
+// Check the driver's directly assigned shipments against a new position.
+// Entering the pickup fence picks the package up, entering the delivery fence
+// sends it out for delivery, and entering it again once out marks the arrival.
+pub(crate) fn on_location(driver_id: Principal, at: &Coordinates) -> Vec<GeofenceTrigger> {
+    let policy = GEOFENCE_POLICY.with(|p| p.borrow().clone());
+    if policy.mode == GeofenceMode::Off {
+        return Vec::new();
+    }
+    let now = time();
+    let mut triggers = Vec::new();
+    SHIPMENTS.with(|shipments| {
+        let mut shipments = shipments.borrow_mut();
+        // Routed shipments move with their legs
+        let assigned = shipments
+            .values_mut()
+            .filter(|s| s.driver_id == Some(driver_id) && s.legs.is_none());
+        for shipment in assigned {
+            let Some((fence, distance_m)) = entered(shipment, at, policy.radius_m) else {
+                continue;
+            };
+            let key = (shipment.id.clone(), fence.clone(), format!("{:?}", shipment.status));
+            if !TRIGGERED.with(|t| t.borrow_mut().insert(key)) {
+                continue;
+            }
+            let status = transition(shipment, &fence);
+            let applied = policy.mode == GeofenceMode::Apply && status.is_some();
+            let kind = TrackingEventKind::GeofenceEntered {
+                fence: fence.clone(),
+                coordinates: at.clone(),
+                distance_m,
+            };
+            let description = match fence {
+                Fence::Pickup => "Driver reached the pickup address".to_string(),
+                Fence::Delivery => "Driver reached the delivery address".to_string(),
+            };
+            match status.clone().filter(|_| applied) {
+                Some(next) => {
+                    apply_status_update(shipment, next, None, description, driver_id, now);
+                    tracking::tag_latest(shipment, kind);
+                    event_store::tracked(shipment, driver_id);
+                },
+                None => {
+                    tracking::record(shipment, TrackingEvent {
+                        timestamp: now,
+                        status: shipment.status.clone(),
+                        location: None,
+                        description,
+                        updated_by: driver_id,
+                        kind: Some(kind),
+                    });
+                    shipment.touch(now);
+                    event_store::tracked(shipment, driver_id);
+                    if let Some(next) = &status {
+                        notifications::notify(
+                            driver_id,
+                            NotificationKind::StatusChange,
+                            Some(&shipment.id),
+                            format!("You are at the address for shipment {}; mark it {:?}", shipment.id, next),
+                        );
+                    }
+                },
+            }
+            resource_usage::record_instructions(shipment.sender_id, Some(&shipment.id));
+            triggers.push(GeofenceTrigger {
+                shipment_id: shipment.id.clone(),
+                fence,
+                distance_m,
+                status,
+                applied,
+            });
+        }
+    });
+    triggers
+}
+
+// Timer job: forget entries of shipments that are gone or finished
+pub(crate) fn prune_triggers() {
+    SHIPMENTS.with(|shipments| {
+        let shipments = shipments.borrow();
+        TRIGGERED.with(|t| {
+            t.borrow_mut().retain(|(id, _, _)| {
+                shipments.get(id).is_some_and(|s| {
+                    !matches!(
+                        s.status,
+                        ShipmentStatus::Delivered | ShipmentStatus::Returned | ShipmentStatus::Cancelled
+                    )
+                })
+            })
+        });
+    });
+}
+
+// The fence relevant to the shipment's stage, when the driver is inside it
+fn entered(shipment: &Shipment, at: &Coordinates, radius_m: u32) -> Option<(Fence, u32)> {
+    let (fence, center) = match shipment.status {
+        ShipmentStatus::PickupScheduled => (Fence::Pickup, shipment.pickup_address.coordinates.as_ref()?),
+        ShipmentStatus::PickedUp | ShipmentStatus::InTransit | ShipmentStatus::OutForDelivery => {
+            (Fence::Delivery, shipment.delivery_address.coordinates.as_ref()?)
+        },
+        _ => return None,
+    };
+    let distance_m = (at.distance_km(center) * 1000.0).round() as u32;
+    (distance_m <= radius_m).then_some((fence, distance_m))
+}
+
+// Arrival at an address already out for delivery is recorded without a status change
+fn transition(shipment: &Shipment, fence: &Fence) -> Option<ShipmentStatus> {
+    match (fence, &shipment.status) {
+        (Fence::Pickup, ShipmentStatus::PickupScheduled) => {
+            handling::check_acknowledged(shipment).ok().map(|_| ShipmentStatus::PickedUp)
+        },
+        (Fence::Delivery, ShipmentStatus::PickedUp | ShipmentStatus::InTransit) => Some(ShipmentStatus::OutForDelivery),
+        _ => None,
+    }
+}
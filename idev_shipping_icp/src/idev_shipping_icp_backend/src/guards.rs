@@ -0,0 +1,208 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::errors::ShippingError;
+use crate::metrics;
+use crate::permissions::{self, Permission};
+
+const NANOS_PER_MINUTE: u64 = 60_000_000_000;
+// Buckets hold thousandths of a token so slow refill rates still accrue between calls
+const MILLI: u64 = 1_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub enum RateLimitedAction {
+    RegisterUser,
+    RegisterDriver,
+    CreateShipment,
+    CreateReturnRequest,
+    // Each signature costs the canister cycles
+    SignDocument,
+    // So does each vetKD key derivation
+    DeriveKey,
+    // Driver app pings, batched so a few calls a minute suffice
+    Heartbeat,
+}
+
+// Token bucket: up to `capacity` calls in a burst, refilled at `refill_per_minute`
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct BucketPolicy {
+    pub capacity: u32,
+    pub refill_per_minute: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RateLimitRule {
+    pub action: RateLimitedAction,
+    pub per_principal: BucketPolicy,
+    pub global: BucketPolicy,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Bucket {
+    tokens_milli: u64,
+    updated_at: u64,
+}
+
+// Rate limiter state carried across upgrades in stable memory
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct StableRateLimits {
+    rules: Vec<RateLimitRule>,
+    principal_buckets: Vec<((Principal, RateLimitedAction), Bucket)>,
+    global_buckets: Vec<(RateLimitedAction, Bucket)>,
+}
+
+thread_local! {
+    static RULES: RefCell<HashMap<RateLimitedAction, RateLimitRule>> = RefCell::new(default_rules());
+    static PRINCIPAL_BUCKETS: RefCell<HashMap<(Principal, RateLimitedAction), Bucket>> = RefCell::new(HashMap::new());
+    static GLOBAL_BUCKETS: RefCell<HashMap<RateLimitedAction, Bucket>> = RefCell::new(HashMap::new());
+}
+
+fn default_rules() -> HashMap<RateLimitedAction, RateLimitRule> {
+    let rule = |action, capacity, refill_per_minute, global_capacity, global_refill_per_minute| RateLimitRule {
+        action,
+        per_principal: BucketPolicy {
+            capacity,
+            refill_per_minute,
+        },
+        global: BucketPolicy {
+            capacity: global_capacity,
+            refill_per_minute: global_refill_per_minute,
+        },
+    };
+    [
+        rule(RateLimitedAction::RegisterUser, 3, 1, 200, 60),
+        rule(RateLimitedAction::RegisterDriver, 3, 1, 100, 30),
+        rule(RateLimitedAction::CreateShipment, 30, 10, 2_000, 600),
+        rule(RateLimitedAction::CreateReturnRequest, 10, 2, 500, 120),
+        rule(RateLimitedAction::SignDocument, 5, 1, 200, 30),
+        rule(RateLimitedAction::DeriveKey, 10, 2, 500, 60),
+        rule(RateLimitedAction::Heartbeat, 10, 6, 20_000, 6_000),
+    ]
+    .into_iter()
+    .map(|r| (r.action, r))
+    .collect()
+}
+
+// Admin configuration
+#[update]
+fn set_rate_limit(rule: RateLimitRule) -> Result<RateLimitRule, String> {
+    metrics::observe("set_rate_limit", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        for policy in [&rule.per_principal, &rule.global] {
+            if policy.capacity == 0 || policy.refill_per_minute == 0 {
+                return Err("Bucket capacity and refill rate must be positive".to_string());
+            }
+        }
+        RULES.with(|rules| rules.borrow_mut().insert(rule.action, rule.clone()));
+        Ok(rule)
+    })
+}
+
+#[query]
+fn get_rate_limits() -> Vec<RateLimitRule> {
+    let mut rules: Vec<RateLimitRule> = RULES.with(|rules| rules.borrow().values().cloned().collect());
+    rules.sort_by_key(|r| format!("{:?}", r.action));
+    rules
+}
+
+// Take one token from both the caller's and the global bucket for `action`, or
+// report how long until both have one available
+pub(crate) fn check_rate_limit(caller: Principal, action: RateLimitedAction) -> Result<(), ShippingError> {
+    let rule = match RULES.with(|rules| rules.borrow().get(&action).cloned()) {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    let now = time();
+
+    PRINCIPAL_BUCKETS.with(|principal_buckets| {
+        GLOBAL_BUCKETS.with(|global_buckets| {
+            let mut principal_buckets = principal_buckets.borrow_mut();
+            let mut global_buckets = global_buckets.borrow_mut();
+            let own = principal_buckets
+                .entry((caller, action))
+                .or_insert_with(|| full_bucket(&rule.per_principal, now));
+            refill(own, &rule.per_principal, now);
+            let global = global_buckets
+                .entry(action)
+                .or_insert_with(|| full_bucket(&rule.global, now));
+            refill(global, &rule.global, now);
+
+            let wait = retry_after(own, &rule.per_principal).max(retry_after(global, &rule.global));
+            if wait > 0 {
+                return Err(ShippingError::RateLimited {
+                    retry_after_secs: wait.div_ceil(1_000_000_000),
+                });
+            }
+            own.tokens_milli -= MILLI;
+            global.tokens_milli -= MILLI;
+            Ok(())
+        })
+    })
+}
+
+// Timer job: per-principal buckets that have refilled carry no state worth keeping
+pub(crate) fn prune_buckets() {
+    let now = time();
+    let rules = RULES.with(|rules| rules.borrow().clone());
+    PRINCIPAL_BUCKETS.with(|buckets| {
+        buckets.borrow_mut().retain(|(_, action), bucket| match rules.get(action) {
+            Some(rule) => {
+                refill(bucket, &rule.per_principal, now);
+                bucket.tokens_milli < rule.per_principal.capacity as u64 * MILLI
+            },
+            None => false,
+        });
+    });
+}
+
+pub(crate) fn stable_state() -> StableRateLimits {
+    StableRateLimits {
+        rules: RULES.with(|rules| rules.borrow().values().cloned().collect()),
+        principal_buckets: PRINCIPAL_BUCKETS.with(|b| b.borrow().iter().map(|(k, v)| (*k, v.clone())).collect()),
+        global_buckets: GLOBAL_BUCKETS.with(|b| b.borrow().iter().map(|(k, v)| (*k, v.clone())).collect()),
+    }
+}
+
+pub(crate) fn restore_stable_state(state: StableRateLimits) {
+    RULES.with(|rules| {
+        let mut rules = rules.borrow_mut();
+        for rule in state.rules {
+            rules.insert(rule.action, rule);
+        }
+    });
+    PRINCIPAL_BUCKETS.with(|b| *b.borrow_mut() = state.principal_buckets.into_iter().collect());
+    GLOBAL_BUCKETS.with(|b| *b.borrow_mut() = state.global_buckets.into_iter().collect());
+}
+
+fn full_bucket(policy: &BucketPolicy, now: u64) -> Bucket {
+    Bucket {
+        tokens_milli: policy.capacity as u64 * MILLI,
+        updated_at: now,
+    }
+}
+
+fn refill(bucket: &mut Bucket, policy: &BucketPolicy, now: u64) {
+    let elapsed = now.saturating_sub(bucket.updated_at) as u128;
+    let earned = elapsed * policy.refill_per_minute as u128 * MILLI as u128 / NANOS_PER_MINUTE as u128;
+    if earned == 0 {
+        // Keep accruing from the last refill rather than discarding the partial amount
+        return;
+    }
+    let max = policy.capacity as u64 * MILLI;
+    bucket.tokens_milli = (bucket.tokens_milli as u128 + earned).min(max as u128) as u64;
+    bucket.updated_at = now;
+}
+
+// Nanoseconds until the bucket holds a whole token
+fn retry_after(bucket: &Bucket, policy: &BucketPolicy) -> u64 {
+    if bucket.tokens_milli >= MILLI {
+        return 0;
+    }
+    let missing = (MILLI - bucket.tokens_milli) as u128;
+    let rate = policy.refill_per_minute as u128 * MILLI as u128;
+    (missing * NANOS_PER_MINUTE as u128).div_ceil(rate) as u64
+}
@@ -0,0 +1,118 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+
+use crate::event_store;
+use crate::metrics;
+use crate::money::{Money, BASE_CURRENCY};
+use crate::notifications::{self, NotificationKind};
+use crate::tracking;
+use crate::{CostLineItem, PackageDetails, Shipment, ShipmentStatus, TrackingEvent, TrackingEventKind, SHIPMENTS};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
+pub enum HandlingClass {
+    Fragile,
+    Perishable,
+    TemperatureControlled,
+    Hazardous,
+    Oversized,
+}
+
+impl HandlingClass {
+    pub const ALL: [HandlingClass; 5] = [
+        HandlingClass::Fragile,
+        HandlingClass::Perishable,
+        HandlingClass::TemperatureControlled,
+        HandlingClass::Hazardous,
+        HandlingClass::Oversized,
+    ];
+
+    fn surcharge(self) -> Money {
+        let units = match self {
+            HandlingClass::Fragile => 5,
+            HandlingClass::Perishable => 4,
+            HandlingClass::TemperatureControlled => 12,
+            HandlingClass::Hazardous => 20,
+            HandlingClass::Oversized => 15,
+        };
+        Money::from_units(units, BASE_CURRENCY)
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            HandlingClass::Fragile => "Fragile handling",
+            HandlingClass::Perishable => "Perishable handling",
+            HandlingClass::TemperatureControlled => "Temperature-controlled handling",
+            HandlingClass::Hazardous => "Hazardous materials handling",
+            HandlingClass::Oversized => "Oversized handling",
+        }
+    }
+}
+
+// The assigned driver confirms at pickup that they have seen the package's
+// handling classes; required before a package with any class is picked up
+#[update]
+fn acknowledge_handling(shipment_id: String, classes: Vec<HandlingClass>) -> Result<Shipment, String> {
+    metrics::observe("acknowledge_handling", || {
+        let caller = ic_cdk::caller();
+        SHIPMENTS.with(|shipments| {
+            let mut shipments_map = shipments.borrow_mut();
+            let shipment = shipments_map
+                .get_mut(&shipment_id)
+                .ok_or_else(|| "Shipment not found".to_string())?;
+            if shipment.driver_id != Some(caller) {
+                return Err("Only the assigned driver can acknowledge handling".to_string());
+            }
+            if !matches!(shipment.status, ShipmentStatus::PickupScheduled) {
+                return Err("Handling is acknowledged at pickup".to_string());
+            }
+            let required = shipment.package_details.handling_classes();
+            if let Some(missing) = required.iter().find(|c| !classes.contains(c)) {
+                return Err(format!("Acknowledgement must include {:?}", missing));
+            }
+
+            tracking::record(shipment, TrackingEvent {
+                timestamp: time(),
+                status: shipment.status.clone(),
+                location: None,
+                description: "Handling requirements acknowledged by driver".to_string(),
+                updated_by: caller,
+                kind: Some(TrackingEventKind::HandlingAcknowledged { classes: required.to_vec() }),
+            });
+            shipment.touch(time());
+            event_store::tracked(shipment, caller);
+            notifications::notify(
+                shipment.sender_id,
+                NotificationKind::StatusChange,
+                Some(&shipment_id),
+                format!("Driver acknowledged handling for shipment {}", shipment_id),
+            );
+            Ok(shipment.clone())
+        })
+    })
+}
+
+pub(crate) fn surcharges(package: &PackageDetails) -> Vec<CostLineItem> {
+    package
+        .handling_classes()
+        .iter()
+        .map(|class| CostLineItem { label: class.label().to_string(), amount: class.surcharge() })
+        .collect()
+}
+
+// Pickup of a package with handling classes needs the current driver's acknowledgement
+pub(crate) fn check_acknowledged(shipment: &Shipment) -> Result<(), String> {
+    if shipment.package_details.handling_classes().is_empty() {
+        return Ok(());
+    }
+    let acknowledged = tracking::with_events(shipment, |events| {
+        events.iter().any(|e| {
+            matches!(e.kind, Some(TrackingEventKind::HandlingAcknowledged { .. }))
+                && Some(e.updated_by) == shipment.driver_id
+        })
+    });
+    if !acknowledged {
+        return Err("The driver must acknowledge handling requirements before pickup".to_string());
+    }
+    Ok(())
+}
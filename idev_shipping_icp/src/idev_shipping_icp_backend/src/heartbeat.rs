@@ -0,0 +1,165 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::geofence::{self, GeofenceTrigger};
+use crate::guards::{self, RateLimitedAction};
+use crate::live_location;
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::validation::Validator;
+use crate::{capacity, Coordinates, DRIVERS, SHIPMENTS};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const MAX_PINGS_PER_HEARTBEAT: usize = 60;
+// Pings buffered on the device for longer than this are dropped
+const MAX_PING_AGE_SECS: u64 = 60 * 60;
+// Device clocks running ahead by up to this much are tolerated
+const MAX_CLOCK_SKEW_SECS: u64 = 60;
+const MAX_APP_VERSION_LEN: usize = 32;
+// Interval suggested to the app: often while carrying shipments, rarely otherwise
+const ACTIVE_INTERVAL_SECS: u32 = 30;
+const IDLE_INTERVAL_SECS: u32 = 300;
+const LOW_BATTERY_PERCENT: u8 = 20;
+
+// One GPS fix taken on the device, possibly some time before it was sent
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LocationPing {
+    pub coordinates: Coordinates,
+    pub recorded_at: u64,
+    pub accuracy_m: Option<f64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DeviceStatus {
+    pub driver_id: Principal,
+    pub battery_percent: Option<u8>,
+    pub app_version: String,
+    pub last_heartbeat_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HeartbeatAck {
+    pub accepted: u32,
+    // Older than the newest ping already received, or too old to use
+    pub ignored: u32,
+    pub next_heartbeat_secs: u32,
+    pub triggers: Vec<GeofenceTrigger>,
+}
+
+thread_local! {
+    static DEVICES: RefCell<HashMap<Principal, DeviceStatus>> = RefCell::new(HashMap::new());
+    // Device time of each driver's newest ping, so late batches cannot move them back
+    static LATEST_PING: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+}
+
+// Mobile clients call this instead of update_driver_location, sending the pings
+// collected since the last call in one message. Only the newest ping moves the
+// driver and is checked against geofences; the call itself shows the app is alive
+// even when it has no fix.
+#[update]
+fn heartbeat(locations: Vec<LocationPing>, battery: Option<u8>, app_version: String) -> Result<HeartbeatAck, String> {
+    metrics::observe("heartbeat", || {
+        let caller = ic_cdk::caller();
+        if !DRIVERS.with(|drivers| drivers.borrow().contains_key(&caller)) {
+            return Err("Driver not registered".to_string());
+        }
+        guards::check_rate_limit(caller, RateLimitedAction::Heartbeat)?;
+        let now = time();
+        validate(&locations, battery, &app_version, now)?;
+
+        let oldest = now.saturating_sub(MAX_PING_AGE_SECS * NANOS_PER_SEC);
+        let newest_seen = LATEST_PING.with(|l| l.borrow().get(&caller).copied());
+        let fresh: Vec<&LocationPing> = locations
+            .iter()
+            .filter(|p| p.recorded_at >= oldest && newest_seen.is_none_or(|seen| p.recorded_at > seen))
+            .collect();
+        let accepted = fresh.len() as u32;
+        let latest = fresh.into_iter().max_by_key(|p| p.recorded_at);
+
+        let triggers = match latest {
+            Some(ping) => {
+                DRIVERS.with(|drivers| {
+                    if let Some(driver) = drivers.borrow_mut().get_mut(&caller) {
+                        driver.current_location = Some(ping.coordinates.clone());
+                    }
+                });
+                LATEST_PING.with(|l| l.borrow_mut().insert(caller, ping.recorded_at));
+                live_location::record_report(caller, &ping.coordinates, ping.recorded_at.min(now));
+                geofence::on_location(caller, &ping.coordinates)
+            },
+            None => Vec::new(),
+        };
+
+        let status = DeviceStatus {
+            driver_id: caller,
+            battery_percent: battery,
+            app_version,
+            last_heartbeat_at: now,
+        };
+        let next_heartbeat_secs = interval_for(&status);
+        DEVICES.with(|d| d.borrow_mut().insert(caller, status));
+        Ok(HeartbeatAck {
+            accepted,
+            ignored: locations.len() as u32 - accepted,
+            next_heartbeat_secs,
+            triggers,
+        })
+    })
+}
+
+// Dispatch view of a driver's app, e.g. to tell a dead battery from a dead zone
+#[query]
+fn get_driver_device_status(driver_id: Principal) -> Result<DeviceStatus, String> {
+    let caller = ic_cdk::caller();
+    if caller != driver_id {
+        permissions::require(&caller, Permission::AssignDriver)?;
+    }
+    DEVICES
+        .with(|d| d.borrow().get(&driver_id).cloned())
+        .ok_or_else(|| "No heartbeat received from this driver".to_string())
+}
+
+pub(crate) fn last_heartbeat(driver_id: &Principal) -> Option<u64> {
+    DEVICES.with(|d| d.borrow().get(driver_id).map(|s| s.last_heartbeat_at))
+}
+
+fn validate(locations: &[LocationPing], battery: Option<u8>, app_version: &str, now: u64) -> Result<(), String> {
+    if locations.len() > MAX_PINGS_PER_HEARTBEAT {
+        return Err(format!("At most {} locations per heartbeat", MAX_PINGS_PER_HEARTBEAT));
+    }
+    let mut v = Validator::new();
+    v.required("app_version", app_version, MAX_APP_VERSION_LEN);
+    for (i, ping) in locations.iter().enumerate() {
+        v.coordinates(&format!("locations[{}].coordinates", i), &ping.coordinates);
+        if let Some(accuracy) = ping.accuracy_m {
+            v.non_negative(&format!("locations[{}].accuracy_m", i), accuracy);
+        }
+    }
+    v.finish()?;
+    if locations.iter().any(|p| p.recorded_at > now + MAX_CLOCK_SKEW_SECS * NANOS_PER_SEC) {
+        return Err("Location recorded in the future".to_string());
+    }
+    if battery.is_some_and(|b| b > 100) {
+        return Err("Battery must be a percentage".to_string());
+    }
+    Ok(())
+}
+
+// Longer while the driver carries nothing or the battery is low
+fn interval_for(status: &DeviceStatus) -> u32 {
+    let carrying = SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .values()
+            .any(|s| s.driver_id == Some(status.driver_id) && capacity::is_carrying(&s.status))
+    });
+    let interval = if carrying { ACTIVE_INTERVAL_SECS } else { IDLE_INTERVAL_SECS };
+    if status.battery_percent.is_some_and(|b| b < LOW_BATTERY_PERCENT) {
+        interval * 2
+    } else {
+        interval
+    }
+}
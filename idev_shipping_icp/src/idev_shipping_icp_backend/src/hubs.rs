@@ -0,0 +1,404 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::errors::ShippingError;
+use crate::event_store::{self, ShipmentEvent};
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::validation::{self, Validator, MAX_NAME_LEN};
+use crate::{
+    apply_status_update, assignable_driver, capacity, cod, handling, vehicles, zones, Address, Shipment, ShipmentStatus,
+    SHIPMENTS,
+};
+
+const MAX_HUB_STAFF: usize = 200;
+const MAX_ROUTE_HUBS: usize = 8;
+
+// Sorting and transfer facility for hub-and-spoke routing
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Hub {
+    pub id: String,
+    pub name: String,
+    pub address: Address,
+    pub zone_id: Option<String>,
+    // May receive packages at the hub and scan them as warehouse staff
+    pub staff: Vec<Principal>,
+    pub is_active: bool,
+    pub created_at: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum LegEndpoint {
+    Pickup,
+    Hub(String),
+    Delivery,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum LegStatus {
+    Pending,
+    Assigned,
+    InTransit,
+    Completed,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LegEvent {
+    pub timestamp: u64,
+    pub status: LegStatus,
+    pub location: Option<String>,
+    pub updated_by: Principal,
+}
+
+// One hop of a routed shipment, carried by its own driver
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShipmentLeg {
+    pub index: u32,
+    pub from: LegEndpoint,
+    pub to: LegEndpoint,
+    pub driver_id: Option<Principal>,
+    pub status: LegStatus,
+    pub events: Vec<LegEvent>,
+}
+
+thread_local! {
+    static HUBS: RefCell<HashMap<String, Hub>> = RefCell::new(HashMap::new());
+    static HUB_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Hub management (admin only)
+#[update]
+fn create_hub(name: String, address: Address, zone_id: Option<String>) -> Result<Hub, String> {
+    metrics::observe("create_hub", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        let mut v = Validator::new();
+        v.required("name", &name, MAX_NAME_LEN);
+        v.address("address", &address);
+        v.finish()?;
+        if let Some(zone_id) = &zone_id {
+            if !zones::zone_exists(zone_id) {
+                return Err("Delivery zone not found".to_string());
+            }
+        }
+
+        let hub_id = HUB_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            format!("HB{:06}", *c)
+        });
+        let hub = Hub {
+            id: hub_id.clone(),
+            name,
+            address,
+            zone_id,
+            staff: Vec::new(),
+            is_active: true,
+            created_at: time(),
+        };
+        HUBS.with(|hubs| hubs.borrow_mut().insert(hub_id, hub.clone()));
+        Ok(hub)
+    })
+}
+
+#[update]
+fn update_hub(
+    hub_id: String,
+    name: Option<String>,
+    address: Option<Address>,
+    staff: Option<Vec<Principal>>,
+    is_active: Option<bool>,
+) -> Result<Hub, String> {
+    metrics::observe("update_hub", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        let mut v = Validator::new();
+        if let Some(name) = &name {
+            v.required("name", name, MAX_NAME_LEN);
+        }
+        if let Some(address) = &address {
+            v.address("address", address);
+        }
+        v.finish()?;
+        if staff.as_ref().is_some_and(|s| s.len() > MAX_HUB_STAFF) {
+            return Err(format!("A hub can have at most {} staff", MAX_HUB_STAFF));
+        }
+
+        HUBS.with(|hubs| {
+            let mut hubs_map = hubs.borrow_mut();
+            let hub = hubs_map.get_mut(&hub_id).ok_or_else(|| "Hub not found".to_string())?;
+            if let Some(name) = name {
+                hub.name = name;
+            }
+            if let Some(address) = address {
+                hub.address = address;
+            }
+            if let Some(mut staff) = staff {
+                staff.sort();
+                staff.dedup();
+                hub.staff = staff;
+            }
+            if let Some(is_active) = is_active {
+                hub.is_active = is_active;
+            }
+            Ok(hub.clone())
+        })
+    })
+}
+
+#[query]
+fn get_hubs() -> Vec<Hub> {
+    let mut hubs: Vec<Hub> = HUBS.with(|hubs| hubs.borrow().values().cloned().collect());
+    hubs.sort_by(|a, b| a.id.cmp(&b.id));
+    hubs
+}
+
+// Route an unassigned shipment through the given hubs, in order: pickup -> first
+// hub, hub -> hub, last hub -> delivery. An empty list removes the route.
+#[update]
+fn plan_shipment_route(shipment_id: String, hub_ids: Vec<String>) -> Result<Shipment, String> {
+    metrics::observe("plan_shipment_route", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::UpdateAnyShipment)?;
+        if hub_ids.len() > MAX_ROUTE_HUBS {
+            return Err(format!("A route can pass through at most {} hubs", MAX_ROUTE_HUBS));
+        }
+        for (i, hub_id) in hub_ids.iter().enumerate() {
+            if hub_ids[..i].contains(hub_id) {
+                return Err(format!("Hub {} appears twice in the route", hub_id));
+            }
+            let active = HUBS.with(|hubs| hubs.borrow().get(hub_id).map(|h| h.is_active));
+            match active {
+                Some(true) => {},
+                Some(false) => return Err(format!("Hub {} is not active", hub_id)),
+                None => return Err(format!("Hub {} not found", hub_id)),
+            }
+        }
+
+        let legs = (!hub_ids.is_empty()).then(|| {
+            let mut stops = vec![LegEndpoint::Pickup];
+            stops.extend(hub_ids.into_iter().map(LegEndpoint::Hub));
+            stops.push(LegEndpoint::Delivery);
+            stops
+                .windows(2)
+                .enumerate()
+                .map(|(i, pair)| ShipmentLeg {
+                    index: i as u32,
+                    from: pair[0].clone(),
+                    to: pair[1].clone(),
+                    driver_id: None,
+                    status: LegStatus::Pending,
+                    events: Vec::new(),
+                })
+                .collect()
+        });
+
+        SHIPMENTS.with(|shipments| {
+            let mut shipments_map = shipments.borrow_mut();
+            let shipment = shipments_map
+                .get_mut(&shipment_id)
+                .ok_or_else(|| "Shipment not found".to_string())?;
+            if !matches!(shipment.status, ShipmentStatus::Created) || shipment.driver_id.is_some() {
+                return Err("Only shipments without a driver can be routed".to_string());
+            }
+            shipment.legs = legs;
+            shipment.touch(time());
+            let event = ShipmentEvent::LegsChanged {
+                legs: shipment.legs.clone(),
+                driver_id: shipment.driver_id,
+            };
+            event_store::record(shipment, caller, event);
+            Ok(shipment.clone())
+        })
+    })
+}
+
+// Admins assign any leg; drivers may take a leg for themselves
+#[update]
+fn assign_leg_driver(shipment_id: String, leg_index: u32, driver_id: Principal) -> Result<Shipment, String> {
+    metrics::observe("assign_leg_driver", || {
+        Ok(assign_leg(ic_cdk::caller(), &shipment_id, leg_index, driver_id)?)
+    })
+}
+
+fn assign_leg(
+    caller: Principal,
+    shipment_id: &str,
+    leg_index: u32,
+    driver_id: Principal,
+) -> Result<Shipment, ShippingError> {
+    if caller != driver_id && !permissions::has(&caller, Permission::AssignDriver) {
+        return Err("Unauthorized to assign driver".to_string().into());
+    }
+    let driver = assignable_driver(&driver_id)?;
+
+    SHIPMENTS.with(|shipments| {
+        let mut shipments_map = shipments.borrow_mut();
+        if let Some(shipment) = shipments_map.get(shipment_id) {
+            capacity::check_capacity(&driver, &shipment.package_details, shipment_id, shipments_map.values())?;
+            cod::check_assignment(shipment, &driver_id)?;
+            vehicles::check_vehicle(&driver, shipment)?;
+        }
+        let shipment = shipments_map
+            .get_mut(shipment_id)
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        let leg = leg_mut(shipment, leg_index)?;
+        if !matches!(leg.status, LegStatus::Pending | LegStatus::Assigned) {
+            return Err("Leg is already under way".to_string().into());
+        }
+        leg.driver_id = Some(driver_id);
+        leg.status = LegStatus::Assigned;
+        leg.events.push(LegEvent {
+            timestamp: time(),
+            status: LegStatus::Assigned,
+            location: None,
+            updated_by: caller,
+        });
+        notifications::notify(
+            driver_id,
+            NotificationKind::Assignment,
+            Some(shipment_id),
+            format!("Assigned to leg {} of shipment {}", leg_index + 1, shipment_id),
+        );
+        roll_up(shipment, caller);
+        Ok(shipment.clone())
+    })
+}
+
+// The leg's driver starts and completes it; staff of the destination hub may
+// complete it when they receive the package
+#[update]
+fn update_leg_status(
+    shipment_id: String,
+    leg_index: u32,
+    status: LegStatus,
+    location: Option<String>,
+) -> Result<Shipment, String> {
+    metrics::observe("update_leg_status", || {
+        let caller = ic_cdk::caller();
+        if let Some(location) = &location {
+            let mut v = Validator::new();
+            v.max_len("location", location, validation::MAX_TEXT_LEN);
+            v.finish()?;
+        }
+
+        SHIPMENTS.with(|shipments| {
+            let mut shipments_map = shipments.borrow_mut();
+            let shipment = shipments_map
+                .get_mut(&shipment_id)
+                .ok_or_else(|| "Shipment not found".to_string())?;
+            if matches!(shipment.status, ShipmentStatus::Cancelled) {
+                return Err("Shipment has been cancelled".to_string());
+            }
+            let previous_done = leg_index == 0
+                || shipment
+                    .legs
+                    .as_ref()
+                    .and_then(|legs| legs.get(leg_index as usize - 1))
+                    .is_some_and(|l| l.status == LegStatus::Completed);
+            let final_leg = shipment.legs.as_ref().is_some_and(|legs| leg_index as usize + 1 == legs.len());
+            if final_leg && status == LegStatus::Completed {
+                cod::check_collected(shipment)?;
+            }
+            if leg_index == 0 && status == LegStatus::InTransit {
+                handling::check_acknowledged(shipment)?;
+            }
+            let leg = leg_mut(shipment, leg_index)?;
+
+            // The leg driver, or anyone allowed to act on any shipment
+            let can_move = leg.driver_id == Some(caller) || permissions::has(&caller, Permission::UpdateAnyShipment);
+            let at_destination = match &leg.to {
+                LegEndpoint::Hub(hub_id) => is_hub_staff_at(hub_id, &caller),
+                _ => false,
+            };
+            match (&leg.status, &status) {
+                (LegStatus::Assigned, LegStatus::InTransit) if can_move => {
+                    if !previous_done {
+                        return Err("The previous leg has not reached the hub yet".to_string());
+                    }
+                },
+                (LegStatus::InTransit, LegStatus::Completed) if can_move || at_destination => {},
+                (LegStatus::Assigned, LegStatus::InTransit) | (LegStatus::InTransit, LegStatus::Completed) => {
+                    return Err("Unauthorized to update leg".to_string());
+                },
+                _ => return Err(format!("Cannot move leg from {:?} to {:?}", leg.status, status)),
+            }
+
+            leg.status = status.clone();
+            leg.events.push(LegEvent {
+                timestamp: time(),
+                status,
+                location,
+                updated_by: caller,
+            });
+            roll_up(shipment, caller);
+            Ok(shipment.clone())
+        })
+    })
+}
+
+// Staff of any active hub count as warehouse staff when scanning
+pub(crate) fn find(hub_id: &str) -> Option<Hub> {
+    HUBS.with(|hubs| hubs.borrow().get(hub_id).cloned())
+}
+
+pub(crate) fn is_hub_staff(principal: &Principal) -> bool {
+    HUBS.with(|hubs| hubs.borrow().values().any(|h| h.is_active && h.staff.contains(principal)))
+}
+
+pub(crate) fn is_hub_staff_at(hub_id: &str, principal: &Principal) -> bool {
+    HUBS.with(|hubs| hubs.borrow().get(hub_id).is_some_and(|h| h.staff.contains(principal)))
+}
+
+fn leg_mut(shipment: &mut Shipment, leg_index: u32) -> Result<&mut ShipmentLeg, String> {
+    shipment
+        .legs
+        .as_mut()
+        .ok_or_else(|| "Shipment is not routed through hubs".to_string())?
+        .get_mut(leg_index as usize)
+        .ok_or_else(|| "Leg not found".to_string())
+}
+
+// Derive the overall status and current driver from the legs, recording a
+// shipment-level tracking event whenever the overall status changes
+fn roll_up(shipment: &mut Shipment, caller: Principal) {
+    let Some(legs) = &shipment.legs else { return };
+    let last = legs.len() - 1;
+    let (status, driver_id, description) = match legs.iter().position(|l| l.status != LegStatus::Completed) {
+        None => (
+            ShipmentStatus::Delivered,
+            legs[last].driver_id,
+            "Final leg completed".to_string(),
+        ),
+        Some(i) => {
+            let leg = &legs[i];
+            let status = match (&leg.status, i) {
+                (LegStatus::Pending, 0) => ShipmentStatus::Created,
+                (LegStatus::Assigned, 0) => ShipmentStatus::PickupScheduled,
+                (LegStatus::InTransit, 0) => ShipmentStatus::PickedUp,
+                (LegStatus::InTransit, i) if i == last => ShipmentStatus::OutForDelivery,
+                _ => ShipmentStatus::InTransit,
+            };
+            let description = match &leg.from {
+                LegEndpoint::Hub(hub_id) if leg.status != LegStatus::InTransit => format!("At hub {}", hub_id),
+                _ => format!("Leg {} of {}: {:?}", i + 1, legs.len(), leg.status),
+            };
+            (status, leg.driver_id.or(shipment.driver_id), description)
+        },
+    };
+
+    shipment.driver_id = driver_id;
+    shipment.touch(time());
+    if status != shipment.status {
+        apply_status_update(shipment, status, None, description, caller, time());
+    }
+    let event = ShipmentEvent::LegsChanged {
+        legs: shipment.legs.clone(),
+        driver_id: shipment.driver_id,
+    };
+    event_store::record(shipment, caller, event);
+}
@@ -0,0 +1,253 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::audit::{self, AuditAction};
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::reattempts::DeliveryException;
+use crate::validation::{self, Validator};
+use crate::{ShipmentStatus, USERS};
+
+// Language of the built-in texts, and of anyone without a preference
+pub(crate) const DEFAULT_LANG: &str = "en";
+
+// A text stored as a catalog key and its arguments, rendered in the reader's
+// language when read. Placeholders in templates are written `{name}`.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LocalizedText {
+    pub key: String,
+    pub args: Vec<(String, TextArg)>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum TextArg {
+    Plain(String),
+    // Another catalog key, rendered in the same language
+    Key(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Translation {
+    pub key: String,
+    pub lang: String,
+    pub template: String,
+    pub updated_by: Principal,
+    pub updated_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LocalizedString {
+    pub key: String,
+    // Language the text is in; the default one when no translation exists
+    pub lang: String,
+    pub text: String,
+}
+
+thread_local! {
+    // Key -> language -> admin translation
+    static CATALOG: RefCell<BTreeMap<String, BTreeMap<String, Translation>>> = RefCell::new(BTreeMap::new());
+}
+
+// Admin configuration. Only keys with a built-in text can be translated, and a
+// translation may use only the placeholders the built-in text has.
+#[update]
+fn set_translation(key: String, lang: String, template: String) -> Result<Translation, String> {
+    metrics::observe("set_translation", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        validate_lang(&lang)?;
+        let mut v = Validator::new();
+        v.required("template", &template, validation::MAX_TEXT_LEN);
+        v.finish()?;
+        let builtin = builtin(&key).ok_or_else(|| format!("Unknown text key {}", key))?;
+        let allowed = placeholders(builtin);
+        if let Some(unknown) = placeholders(&template).into_iter().find(|p| !allowed.contains(p)) {
+            return Err(format!("Placeholder {{{}}} is not available for {}", unknown, key));
+        }
+
+        let translation = Translation {
+            key: key.clone(),
+            lang: lang.clone(),
+            template,
+            updated_by: caller,
+            updated_at: time(),
+        };
+        CATALOG.with(|c| c.borrow_mut().entry(key.clone()).or_default().insert(lang.clone(), translation.clone()));
+        audit::record(caller, caller, AuditAction::SettingsChanged, format!("Translated {} into {}", key, lang));
+        Ok(translation)
+    })
+}
+
+#[update]
+fn remove_translation(key: String, lang: String) -> Result<(), String> {
+    metrics::observe("remove_translation", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        let removed = CATALOG.with(|c| {
+            let mut catalog = c.borrow_mut();
+            let removed = catalog.get_mut(&key).and_then(|langs| langs.remove(&lang));
+            if catalog.get(&key).is_some_and(|langs| langs.is_empty()) {
+                catalog.remove(&key);
+            }
+            removed
+        });
+        removed.ok_or_else(|| "Translation not found".to_string())?;
+        audit::record(caller, caller, AuditAction::SettingsChanged, format!("Removed {} translation of {}", lang, key));
+        Ok(())
+    })
+}
+
+// Every admin translation, optionally in one language
+#[query]
+fn get_translations(lang: Option<String>) -> Vec<Translation> {
+    CATALOG.with(|c| {
+        c.borrow()
+            .values()
+            .flat_map(|langs| langs.values())
+            .filter(|t| lang.as_ref().is_none_or(|lang| t.lang == *lang))
+            .cloned()
+            .collect()
+    })
+}
+
+// Display strings for frontends, e.g. "status." for shipment statuses or
+// "failure." for delivery failure reasons. Templates come with their
+// placeholders unfilled. Defaults to the caller's language.
+#[query]
+fn get_localized_strings(prefix: Option<String>, lang: Option<String>) -> Vec<LocalizedString> {
+    let lang = lang.unwrap_or_else(|| lang_of(&ic_cdk::caller()));
+    builtin_keys()
+        .into_iter()
+        .filter(|key| prefix.as_ref().is_none_or(|prefix| key.starts_with(prefix.as_str())))
+        .map(|key| {
+            let (lang, text) = template(&key, &lang);
+            LocalizedString { key, lang, text }
+        })
+        .collect()
+}
+
+impl LocalizedText {
+    pub(crate) fn new(key: &str) -> Self {
+        LocalizedText {
+            key: key.to_string(),
+            args: Vec::new(),
+        }
+    }
+
+    pub(crate) fn arg(mut self, name: &str, value: impl ToString) -> Self {
+        self.args.push((name.to_string(), TextArg::Plain(value.to_string())));
+        self
+    }
+
+    pub(crate) fn with(mut self, name: &str, value: TextArg) -> Self {
+        self.args.push((name.to_string(), value));
+        self
+    }
+
+    pub(crate) fn render(&self, lang: &str) -> String {
+        let mut text = template(&self.key, lang).1;
+        for (name, value) in &self.args {
+            let value = match value {
+                TextArg::Plain(value) => value.clone(),
+                TextArg::Key(key) => template(key, lang).1,
+            };
+            text = text.replace(&format!("{{{}}}", name), &value);
+        }
+        text
+    }
+}
+
+pub(crate) fn validate_lang(lang: &str) -> Result<(), String> {
+    // A language subtag, optionally with a region: "en", "pt-BR"
+    let mut parts = lang.split('-');
+    let language = parts.next().unwrap_or_default();
+    let region = parts.next();
+    let valid = (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_lowercase())
+        && region.is_none_or(|r| r.len() == 2 && r.chars().all(|c| c.is_ascii_uppercase()))
+        && parts.next().is_none();
+    if valid {
+        Ok(())
+    } else {
+        Err("Language must be a code like \"en\" or \"pt-BR\"".to_string())
+    }
+}
+
+pub(crate) fn lang_of(user_id: &Principal) -> String {
+    USERS
+        .with(|users| users.borrow().get(user_id).and_then(|u| u.lang.clone()))
+        .unwrap_or_else(|| DEFAULT_LANG.to_string())
+}
+
+pub(crate) fn status_key(status: &ShipmentStatus) -> String {
+    format!("status.{:?}", status)
+}
+
+pub(crate) fn status_arg(status: &ShipmentStatus) -> TextArg {
+    TextArg::Key(status_key(status))
+}
+
+pub(crate) fn failure_arg(exception: DeliveryException) -> TextArg {
+    TextArg::Key(format!("failure.{:?}", exception))
+}
+
+// The text for `key` in `lang`, falling back to the bare language ("pt" for
+// "pt-BR"), then to the built-in text. Returns the language it is in.
+fn template(key: &str, lang: &str) -> (String, String) {
+    let base = lang.split('-').next().unwrap_or(lang);
+    let translated = CATALOG.with(|c| {
+        let catalog = c.borrow();
+        let langs = catalog.get(key)?;
+        langs.get(lang).or_else(|| langs.get(base)).map(|t| (t.lang.clone(), t.template.clone()))
+    });
+    translated.unwrap_or_else(|| (DEFAULT_LANG.to_string(), builtin(key).unwrap_or(key).to_string()))
+}
+
+fn placeholders(template: &str) -> Vec<&str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|rest| rest.split_once('}').map(|(name, _)| name))
+        .collect()
+}
+
+fn builtin_keys() -> Vec<String> {
+    let statuses = ShipmentStatus::ALL.iter().map(status_key);
+    let failures = DeliveryException::ALL.iter().map(|e| format!("failure.{:?}", e));
+    let notifications = ["notification.status_changed", "notification.attempt_failed", "notification.returning"]
+        .into_iter()
+        .map(str::to_string);
+    statuses.chain(failures).chain(notifications).collect()
+}
+
+// English texts every key starts out with
+fn builtin(key: &str) -> Option<&'static str> {
+    Some(match key {
+        "status.Created" => "Created",
+        "status.PickupScheduled" => "Pickup scheduled",
+        "status.PickedUp" => "Picked up",
+        "status.InTransit" => "In transit",
+        "status.OutForDelivery" => "Out for delivery",
+        "status.AtPickupPoint" => "At pickup point",
+        "status.AwaitingConfirmation" => "Awaiting confirmation",
+        "status.Delivered" => "Delivered",
+        "status.Disputed" => "Disputed",
+        "status.Failed" => "Delivery failed",
+        "status.Returned" => "Returned",
+        "status.Cancelled" => "Cancelled",
+        "failure.RecipientAbsent" => "recipient absent",
+        "failure.AddressNotFound" => "address not found",
+        "failure.Refused" => "refused by recipient",
+        "failure.DamagedInTransit" => "damaged in transit",
+        "failure.AccessRestricted" => "access restricted",
+        "notification.status_changed" => "Shipment {shipment_id} is now {status}",
+        "notification.attempt_failed" => {
+            "Delivery attempt {attempt} for shipment {shipment_id} failed ({reason}); attempt {next} is scheduled"
+        },
+        "notification.returning" => "Shipment {shipment_id} is being returned as {return_id}",
+        _ => return None,
+    })
+}
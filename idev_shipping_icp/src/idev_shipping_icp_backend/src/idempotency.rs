@@ -0,0 +1,96 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+const MAX_KEY_LEN: usize = 64;
+// Successful responses are replayed for a day
+const RESULT_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// A call that trapped after an await never completes; its key frees up after this
+const IN_FLIGHT_TTL_NANOS: u64 = 5 * 60 * 1_000_000_000;
+
+struct CachedCall {
+    method: &'static str,
+    // Candid-encoded response, None while the first call is still running
+    response: Option<Vec<u8>>,
+    recorded_at: u64,
+}
+
+thread_local! {
+    static IDEMPOTENCY_CACHE: RefCell<HashMap<(Principal, String), CachedCall>> = RefCell::new(HashMap::new());
+}
+
+// Claim `key` for a call to `method`. Returns the original response when the key was
+// already used successfully; otherwise the caller runs the call and reports back with
+// `finish`. Calls without a key are never cached.
+pub(crate) fn begin<T>(caller: Principal, key: Option<&str>, method: &'static str) -> Result<Option<T>, String>
+where
+    T: CandidType + for<'de> Deserialize<'de>,
+{
+    let key = match key {
+        Some(k) => k,
+        None => return Ok(None),
+    };
+    if key.is_empty() || key.len() > MAX_KEY_LEN || !key.chars().all(|c| c.is_ascii_graphic()) {
+        return Err(format!("Idempotency key must be 1-{} printable ASCII characters", MAX_KEY_LEN));
+    }
+
+    let now = time();
+    IDEMPOTENCY_CACHE.with(|cache| {
+        let mut cache_map = cache.borrow_mut();
+        let cache_key = (caller, key.to_string());
+        if let Some(entry) = cache_map.get(&cache_key).filter(|e| !is_expired(e, now)) {
+            if entry.method != method {
+                return Err("Idempotency key was already used for a different request".to_string());
+            }
+            return match &entry.response {
+                Some(bytes) => candid::decode_one(bytes).map(Some).map_err(|e| e.to_string()),
+                None => Err("A request with this idempotency key is still in progress".to_string()),
+            };
+        }
+        cache_map.insert(
+            cache_key,
+            CachedCall {
+                method,
+                response: None,
+                recorded_at: now,
+            },
+        );
+        Ok(None)
+    })
+}
+
+// Record the outcome of a call started with `begin`. Only successes are kept, so a
+// failed call can be retried with the same key.
+pub(crate) fn finish<T: CandidType>(caller: Principal, key: Option<&str>, result: &Result<T, String>) {
+    let key = match key {
+        Some(k) => k,
+        None => return,
+    };
+    IDEMPOTENCY_CACHE.with(|cache| {
+        let mut cache_map = cache.borrow_mut();
+        let cache_key = (caller, key.to_string());
+        match result.as_ref().ok().and_then(|value| candid::encode_one(value).ok()) {
+            Some(bytes) => {
+                if let Some(entry) = cache_map.get_mut(&cache_key) {
+                    entry.response = Some(bytes);
+                    entry.recorded_at = time();
+                }
+            },
+            None => {
+                cache_map.remove(&cache_key);
+            },
+        }
+    });
+}
+
+// Timer job: drop expired responses and abandoned in-flight markers
+pub(crate) fn prune_expired() {
+    let now = time();
+    IDEMPOTENCY_CACHE.with(|cache| cache.borrow_mut().retain(|_, entry| !is_expired(entry, now)));
+}
+
+fn is_expired(entry: &CachedCall, now: u64) -> bool {
+    let ttl = if entry.response.is_some() { RESULT_TTL_NANOS } else { IN_FLIGHT_TTL_NANOS };
+    now.saturating_sub(entry.recorded_at) >= ttl
+}
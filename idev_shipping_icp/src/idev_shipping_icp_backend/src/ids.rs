@@ -0,0 +1,124 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::api::time;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// Crockford base32, as used by ULIDs
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const RANDOM_BITS: u32 = 80;
+const NANOS_PER_MILLI: u64 = 1_000_000;
+const RESEED_RETRY: Duration = Duration::from_secs(5);
+
+// Generator state carried across upgrades, so ids stay ordered and short codes
+// are never issued twice
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct StableIdState {
+    last_millis: u64,
+    last_random: u128,
+    // Short codes issued so far, by prefix
+    counters: BTreeMap<String, u64>,
+}
+
+thread_local! {
+    // From the management canister's raw_rand; drawn fresh after every install or upgrade
+    static SEED: RefCell<Option<[u8; 32]>> = RefCell::new(None);
+    static DRAWS: RefCell<u64> = RefCell::new(0);
+    static STATE: RefCell<StableIdState> = RefCell::new(StableIdState::default());
+}
+
+// Fetch a seed once the current message has finished; called from init and post_upgrade
+pub(crate) fn schedule_seeding() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(seed()));
+}
+
+async fn seed() {
+    match raw_rand().await {
+        Ok((bytes,)) => {
+            let seed: [u8; 32] = Sha256::digest(&bytes).into();
+            SEED.with(|s| *s.borrow_mut() = Some(seed));
+        },
+        Err(_) => {
+            ic_cdk_timers::set_timer(RESEED_RETRY, || ic_cdk::spawn(seed()));
+        },
+    }
+}
+
+// "<prefix>_<ULID>": 48 bits of milliseconds then 80 random bits. Ids issued in
+// the same millisecond increment the random part, so they sort in issue order.
+pub(crate) fn new_id(prefix: &str) -> Result<String, String> {
+    let random = draw()?;
+    let fresh = u128::from_be_bytes(random[..16].try_into().expect("digest is 32 bytes")) >> (128 - RANDOM_BITS);
+    let now = time() / NANOS_PER_MILLI;
+    let (millis, random) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if now > state.last_millis {
+            state.last_millis = now;
+            state.last_random = fresh;
+        } else if state.last_random + 1 < 1 << RANDOM_BITS {
+            state.last_random += 1;
+        } else {
+            state.last_millis += 1;
+            state.last_random = fresh;
+        }
+        (state.last_millis, state.last_random)
+    });
+    Ok(format!("{}_{}", prefix, encode((millis as u128) << RANDOM_BITS | random)))
+}
+
+// Human-readable sequential code such as SH000042
+pub(crate) fn next_code(prefix: &str) -> String {
+    let n = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let counter = state.counters.entry(prefix.to_string()).or_default();
+        *counter += 1;
+        *counter
+    });
+    format!("{}{:06}", prefix, n)
+}
+
+pub(crate) fn issued(prefix: &str) -> u64 {
+    STATE.with(|state| state.borrow().counters.get(prefix).copied().unwrap_or(0))
+}
+
+// Numeric part of a short code
+pub(crate) fn code_number(code: &str, prefix: &str) -> Option<u64> {
+    code.strip_prefix(prefix)?.parse().ok()
+}
+
+// Secret randomness for tokens; None until the seed has arrived
+pub(crate) fn entropy() -> Option<[u8; 32]> {
+    draw().ok()
+}
+
+pub(crate) fn stable_state() -> StableIdState {
+    STATE.with(|state| state.borrow().clone())
+}
+
+pub(crate) fn restore_stable_state(state: StableIdState) {
+    STATE.with(|s| *s.borrow_mut() = state);
+}
+
+fn draw() -> Result<[u8; 32], String> {
+    let seed = SEED
+        .with(|s| *s.borrow())
+        .ok_or_else(|| "Id generator is not ready yet; retry shortly".to_string())?;
+    let n = DRAWS.with(|d| {
+        let mut d = d.borrow_mut();
+        *d += 1;
+        *d
+    });
+    let mut hasher = Sha256::new();
+    hasher.update(seed);
+    hasher.update(n.to_be_bytes());
+    Ok(hasher.finalize().into())
+}
+
+fn encode(value: u128) -> String {
+    (0..26)
+        .rev()
+        .map(|i| ALPHABET[((value >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
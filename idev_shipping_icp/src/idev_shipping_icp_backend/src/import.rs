@@ -0,0 +1,522 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::handling::HandlingClass;
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::suspensions::{self, BlockedAction};
+use crate::validation::Validator;
+use crate::{insert_shipment, resource_usage, Address, Dimensions, NewShipment, PackageDetails, ShipmentOptions, USERS};
+
+const MAX_CHUNK_BYTES: usize = 256 * 1024;
+const MAX_UPLOAD_BYTES: usize = 2 * 1024 * 1024;
+const MAX_ROWS: usize = 1000;
+
+// Column names for CSV headers and JSON object keys
+const REQUIRED_FIELDS: [&str; 14] = [
+    "recipient_name",
+    "recipient_phone",
+    "pickup_street",
+    "pickup_city",
+    "pickup_country",
+    "delivery_street",
+    "delivery_city",
+    "delivery_country",
+    "description",
+    "weight",
+    "length",
+    "width",
+    "height",
+    "value",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ImportFormat {
+    Csv,
+    Json,
+}
+
+// Uploading -> Validated (rows staged) -> Committed, or Cancelled at any point before commit
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ImportState {
+    Uploading,
+    Validated,
+    Committed,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ImportRowError {
+    // 1-based data row, not counting the CSV header
+    pub row: u32,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ImportBatch {
+    pub id: String,
+    pub owner: Principal,
+    pub format: ImportFormat,
+    pub state: ImportState,
+    pub chunks_received: u32,
+    pub bytes_received: u64,
+    pub total_rows: u32,
+    pub staged_rows: u32,
+    pub errors: Vec<ImportRowError>,
+    pub shipment_ids: Vec<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+struct StagedRow {
+    row: u32,
+    shipment: NewShipment,
+}
+
+#[derive(Default)]
+struct ImportData {
+    content: String,
+    staged: Vec<StagedRow>,
+}
+
+thread_local! {
+    static IMPORT_BATCHES: RefCell<HashMap<String, ImportBatch>> = RefCell::new(HashMap::new());
+    static IMPORT_DATA: RefCell<HashMap<String, ImportData>> = RefCell::new(HashMap::new());
+    static IMPORT_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+#[update]
+fn start_shipment_import(format: ImportFormat) -> Result<ImportBatch, String> {
+    metrics::observe("start_shipment_import", || {
+        let caller = ic_cdk::caller();
+        let user = USERS.with(|users| users.borrow().get(&caller).cloned());
+        match user {
+            Some(u) if !u.is_active => return Err("Account is deactivated".to_string()),
+            Some(_) => permissions::require(&caller, Permission::ImportShipments)?,
+            None => return Err("User not registered".to_string()),
+        }
+        suspensions::check(caller, BlockedAction::CreateShipment)?;
+
+        let batch_id = IMPORT_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            format!("IM{:06}", *c)
+        });
+
+        let batch = ImportBatch {
+            id: batch_id.clone(),
+            owner: caller,
+            format,
+            state: ImportState::Uploading,
+            chunks_received: 0,
+            bytes_received: 0,
+            total_rows: 0,
+            staged_rows: 0,
+            errors: Vec::new(),
+            shipment_ids: Vec::new(),
+            created_at: time(),
+            updated_at: time(),
+        };
+
+        IMPORT_BATCHES.with(|batches| {
+            batches.borrow_mut().insert(batch_id.clone(), batch.clone());
+        });
+        IMPORT_DATA.with(|data| {
+            data.borrow_mut().insert(batch_id, ImportData::default());
+        });
+
+        Ok(batch)
+    })
+}
+
+// Chunks are concatenated in order; `chunk_index` must be the next expected index,
+// so a retried upload of the same chunk is rejected rather than duplicated
+#[update]
+fn upload_import_chunk(batch_id: String, chunk_index: u32, data: String) -> Result<ImportBatch, String> {
+    metrics::observe("upload_import_chunk", || {
+        let caller = ic_cdk::caller();
+        if data.len() > MAX_CHUNK_BYTES {
+            return Err(format!("Chunks must be at most {} bytes", MAX_CHUNK_BYTES));
+        }
+
+        let batch = owned_batch(&batch_id, caller)?;
+        if batch.state != ImportState::Uploading {
+            return Err("Import is no longer accepting uploads".to_string());
+        }
+        if chunk_index != batch.chunks_received {
+            return Err(format!("Expected chunk {}", batch.chunks_received));
+        }
+        if batch.bytes_received as usize + data.len() > MAX_UPLOAD_BYTES {
+            return Err(format!("Imports must be at most {} bytes", MAX_UPLOAD_BYTES));
+        }
+
+        IMPORT_DATA.with(|import_data| {
+            if let Some(d) = import_data.borrow_mut().get_mut(&batch_id) {
+                d.content.push_str(&data);
+            }
+        });
+        update_batch(&batch_id, |b| {
+            b.chunks_received += 1;
+            b.bytes_received += data.len() as u64;
+        })
+    })
+}
+
+// Parse and validate every row. Valid rows are staged; nothing is created until commit.
+#[update]
+fn validate_shipment_import(batch_id: String) -> Result<ImportBatch, String> {
+    metrics::observe("validate_shipment_import", || {
+        let caller = ic_cdk::caller();
+        let batch = owned_batch(&batch_id, caller)?;
+        if batch.state != ImportState::Uploading {
+            return Err("Import has already been validated".to_string());
+        }
+
+        let content = IMPORT_DATA.with(|data| {
+            data.borrow()
+                .get(&batch_id)
+                .map(|d| d.content.clone())
+                .unwrap_or_default()
+        });
+        let rows = match batch.format {
+            ImportFormat::Csv => parse_csv(&content)?,
+            ImportFormat::Json => parse_json(&content)?,
+        };
+        if rows.is_empty() {
+            return Err("Import contains no rows".to_string());
+        }
+        if rows.len() > MAX_ROWS {
+            return Err(format!("Imports are limited to {} rows", MAX_ROWS));
+        }
+
+        let total_rows = rows.len() as u32;
+        let mut staged = Vec::new();
+        let mut errors = Vec::new();
+        for (i, row) in rows.iter().enumerate() {
+            let row_number = i as u32 + 1;
+            match parse_row(row) {
+                Ok(shipment) => staged.push(StagedRow {
+                    row: row_number,
+                    shipment,
+                }),
+                Err(message) => errors.push(ImportRowError {
+                    row: row_number,
+                    message,
+                }),
+            }
+        }
+
+        let staged_rows = staged.len() as u32;
+        IMPORT_DATA.with(|data| {
+            if let Some(d) = data.borrow_mut().get_mut(&batch_id) {
+                d.content.clear();
+                d.staged = staged;
+            }
+        });
+        update_batch(&batch_id, |b| {
+            b.state = ImportState::Validated;
+            b.total_rows = total_rows;
+            b.staged_rows = staged_rows;
+            b.errors = errors;
+        })
+    })
+}
+
+// Create shipments for every staged row. Rows that fail at creation time (e.g. a
+// pickup point that went away) are reported alongside the validation errors.
+#[update]
+fn commit_shipment_import(batch_id: String) -> Result<ImportBatch, String> {
+    metrics::observe("commit_shipment_import", || {
+        let caller = ic_cdk::caller();
+        let batch = owned_batch(&batch_id, caller)?;
+        suspensions::check(caller, BlockedAction::CreateShipment)?;
+        if batch.state != ImportState::Validated {
+            return Err("Import must be validated before it is committed".to_string());
+        }
+
+        let staged = IMPORT_DATA
+            .with(|data| data.borrow_mut().remove(&batch_id))
+            .map(|d| d.staged)
+            .unwrap_or_default();
+
+        let mut shipment_ids = Vec::new();
+        let mut errors = Vec::new();
+        for StagedRow { row, shipment } in staged {
+            match insert_shipment(caller, shipment, ShipmentOptions::default(), None) {
+                Ok(s) => shipment_ids.push(s.id),
+                Err(message) => errors.push(ImportRowError { row, message }),
+            }
+        }
+        resource_usage::record_instructions(caller, None);
+
+        update_batch(&batch_id, |b| {
+            b.state = ImportState::Committed;
+            b.shipment_ids = shipment_ids;
+            b.errors.extend(errors);
+            b.errors.sort_by_key(|e| e.row);
+        })
+    })
+}
+
+#[update]
+fn cancel_shipment_import(batch_id: String) -> Result<ImportBatch, String> {
+    metrics::observe("cancel_shipment_import", || {
+        let caller = ic_cdk::caller();
+        let batch = owned_batch(&batch_id, caller)?;
+        if matches!(batch.state, ImportState::Committed | ImportState::Cancelled) {
+            return Err("Import is already closed".to_string());
+        }
+
+        IMPORT_DATA.with(|data| data.borrow_mut().remove(&batch_id));
+        update_batch(&batch_id, |b| b.state = ImportState::Cancelled)
+    })
+}
+
+#[query]
+fn get_shipment_import(batch_id: String) -> Result<ImportBatch, String> {
+    owned_batch(&batch_id, ic_cdk::caller())
+}
+
+#[query]
+fn get_my_shipment_imports() -> Vec<ImportBatch> {
+    let caller = ic_cdk::caller();
+    IMPORT_BATCHES.with(|batches| {
+        batches
+            .borrow()
+            .values()
+            .filter(|b| b.owner == caller)
+            .cloned()
+            .collect()
+    })
+}
+
+fn owned_batch(batch_id: &str, caller: Principal) -> Result<ImportBatch, String> {
+    match IMPORT_BATCHES.with(|batches| batches.borrow().get(batch_id).cloned()) {
+        Some(b) if b.owner == caller => Ok(b),
+        Some(_) => Err("Unauthorized to access import".to_string()),
+        None => Err("Import not found".to_string()),
+    }
+}
+
+fn update_batch(batch_id: &str, f: impl FnOnce(&mut ImportBatch)) -> Result<ImportBatch, String> {
+    IMPORT_BATCHES.with(|batches| {
+        let mut batches_map = batches.borrow_mut();
+        let batch = batches_map
+            .get_mut(batch_id)
+            .ok_or_else(|| "Import not found".to_string())?;
+        f(batch);
+        batch.updated_at = time();
+        Ok(batch.clone())
+    })
+}
+
+// Both formats are normalized to flat JSON objects keyed by column name
+fn parse_json(content: &str) -> Result<Vec<Map<String, Value>>, String> {
+    let value: Value = serde_json::from_str(content).map_err(|e| format!("Invalid JSON: {}", e))?;
+    match value {
+        Value::Array(items) => items
+            .into_iter()
+            .enumerate()
+            .map(|(i, item)| match item {
+                Value::Object(obj) => Ok(obj),
+                _ => Err(format!("Row {} is not a JSON object", i + 1)),
+            })
+            .collect(),
+        _ => Err("JSON import must be an array of objects".to_string()),
+    }
+}
+
+fn parse_csv(content: &str) -> Result<Vec<Map<String, Value>>, String> {
+    let mut records = split_csv(content)?.into_iter();
+    let header: Vec<String> = match records.next() {
+        Some(h) => h.into_iter().map(|c| c.trim().to_lowercase()).collect(),
+        None => return Ok(Vec::new()),
+    };
+
+    records
+        .enumerate()
+        .map(|(i, record)| {
+            if record.len() != header.len() {
+                return Err(format!(
+                    "Row {} has {} columns, expected {}",
+                    i + 1,
+                    record.len(),
+                    header.len()
+                ));
+            }
+            Ok(header
+                .iter()
+                .cloned()
+                .zip(record.into_iter().map(Value::String))
+                .collect())
+        })
+        .collect()
+}
+
+// RFC 4180 style: comma separated, double-quoted fields may contain commas,
+// newlines and doubled quotes. Blank lines are skipped.
+fn split_csv(content: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = content.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes => {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    in_quotes = false;
+                }
+            },
+            '"' if field.is_empty() => in_quotes = true,
+            ',' if !in_quotes => record.push(std::mem::take(&mut field)),
+            '\r' if !in_quotes => {},
+            '\n' if !in_quotes => {
+                record.push(std::mem::take(&mut field));
+                if record.iter().any(|f| !f.is_empty()) {
+                    records.push(std::mem::take(&mut record));
+                } else {
+                    record.clear();
+                }
+            },
+            _ => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err("Unterminated quoted field".to_string());
+    }
+    record.push(field);
+    if record.iter().any(|f| !f.is_empty()) {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn parse_row(row: &Map<String, Value>) -> Result<NewShipment, String> {
+    let missing: Vec<&str> = REQUIRED_FIELDS
+        .iter()
+        .copied()
+        .filter(|f| text(row, f).map(|v| v.is_empty()).unwrap_or(true))
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("Missing required fields: {}", missing.join(", ")));
+    }
+
+    let weight = number(row, "weight")?;
+    let dimensions = Dimensions {
+        length: number(row, "length")?,
+        width: number(row, "width")?,
+        height: number(row, "height")?,
+    };
+    let value = number(row, "value")?;
+    if weight <= 0.0 {
+        return Err("Weight must be positive".to_string());
+    }
+    if dimensions.length <= 0.0 || dimensions.width <= 0.0 || dimensions.height <= 0.0 {
+        return Err("Dimensions must be positive".to_string());
+    }
+    if value < 0.0 {
+        return Err("Value cannot be negative".to_string());
+    }
+
+    let shipment = NewShipment {
+        recipient_name: required(row, "recipient_name"),
+        recipient_phone: required(row, "recipient_phone"),
+        pickup_address: address(row, "pickup"),
+        delivery_address: address(row, "delivery"),
+        package_details: PackageDetails {
+            description: required(row, "description"),
+            weight,
+            dimensions,
+            value,
+            declared_value: None,
+            handling: Some(handling_classes(row)?),
+            special_instructions: text(row, "special_instructions").filter(|s| !s.is_empty()),
+        },
+    };
+
+    let mut v = Validator::new();
+    v.shipment(
+        &shipment.recipient_name,
+        &shipment.recipient_phone,
+        &shipment.pickup_address,
+        &shipment.delivery_address,
+        &shipment.package_details,
+    );
+    v.finish()?;
+    Ok(shipment)
+}
+
+fn address(row: &Map<String, Value>, prefix: &str) -> Address {
+    let field = |name: &str| text(row, &format!("{}_{}", prefix, name)).unwrap_or_default();
+    Address {
+        street: field("street"),
+        city: field("city"),
+        state: field("state"),
+        postal_code: field("postal_code"),
+        country: field("country"),
+        coordinates: None,
+        extras: None,
+    }
+}
+
+fn text(row: &Map<String, Value>, name: &str) -> Option<String> {
+    match row.get(name)? {
+        Value::String(s) => Some(s.trim().to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn required(row: &Map<String, Value>, name: &str) -> String {
+    text(row, name).unwrap_or_default()
+}
+
+fn number(row: &Map<String, Value>, name: &str) -> Result<f64, String> {
+    let n = match row.get(name) {
+        Some(Value::Number(n)) => n.as_f64(),
+        Some(Value::String(s)) => s.trim().parse::<f64>().ok(),
+        _ => None,
+    };
+    n.filter(|n| n.is_finite())
+        .ok_or_else(|| format!("{} must be a number", name))
+}
+
+// Comma-separated class names in `handling`, plus the legacy `fragile` flag
+fn handling_classes(row: &Map<String, Value>) -> Result<Vec<HandlingClass>, String> {
+    let mut classes = Vec::new();
+    for name in text(row, "handling").unwrap_or_default().split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let normalized: String = name.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+        let class = HandlingClass::ALL
+            .into_iter()
+            .find(|c| format!("{:?}", c).eq_ignore_ascii_case(&normalized))
+            .ok_or_else(|| format!("Unknown handling class {}", name))?;
+        classes.push(class);
+    }
+    if flag(row, "fragile")? {
+        classes.push(HandlingClass::Fragile);
+    }
+    Ok(classes)
+}
+
+fn flag(row: &Map<String, Value>, name: &str) -> Result<bool, String> {
+    match row.get(name) {
+        None | Some(Value::Null) => Ok(false),
+        Some(Value::Bool(b)) => Ok(*b),
+        Some(Value::String(s)) => match s.trim().to_lowercase().as_str() {
+            "" | "false" | "no" | "0" => Ok(false),
+            "true" | "yes" | "1" => Ok(true),
+            _ => Err(format!("{} must be true or false", name)),
+        },
+        Some(_) => Err(format!("{} must be true or false", name)),
+    }
+}
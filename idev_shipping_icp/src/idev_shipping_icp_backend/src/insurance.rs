@@ -0,0 +1,400 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::confirmation;
+use crate::metrics;
+use crate::money::{Money, BASE_CURRENCY};
+use crate::notifications::{self, NotificationKind};
+use crate::payments;
+use crate::permissions::{self, Permission};
+use crate::validation::{self, Validator};
+use crate::{idempotency, CostBreakdown, CostLineItem, PackageDetails, ShipmentStatus, SHIPMENTS};
+
+const MAX_EVIDENCE_ITEMS: usize = 20;
+
+// Premiums are charged with the shipment price; approved claims are paid from the
+// insurance pool subaccount, which the platform keeps funded on the payment ledger.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct InsuranceTerms {
+    pub premium_bps: u32,
+    pub min_premium: Money,
+    // Declared value above this is not covered
+    pub max_coverage: Money,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct InsurancePolicy {
+    pub coverage: Money,
+    pub premium: Money,
+    pub purchased_at: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ClaimKind {
+    Damage,
+    Loss,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ClaimStatus {
+    Open,
+    Approved,
+    Rejected,
+    Paid,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EvidenceItem {
+    pub description: String,
+    pub url: Option<String>,
+    // Hex SHA-256 of the referenced file, so it can be checked later
+    pub content_hash: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ClaimEvidence {
+    pub item: EvidenceItem,
+    pub added_by: Principal,
+    pub added_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Claim {
+    pub id: String,
+    pub shipment_id: String,
+    pub claimant: Principal,
+    pub kind: ClaimKind,
+    pub description: String,
+    pub amount_claimed: Money,
+    pub evidence: Vec<ClaimEvidence>,
+    // The recipient's delivery dispute this claim relates to, if any
+    pub dispute_id: Option<String>,
+    pub status: ClaimStatus,
+    pub approved_amount: Option<Money>,
+    pub adjudicated_by: Option<Principal>,
+    pub adjudicated_at: Option<u64>,
+    pub adjudication_note: Option<String>,
+    pub payout_block_index: Option<Nat>,
+    pub paid_at: Option<u64>,
+    pub created_at: u64,
+}
+
+thread_local! {
+    static INSURANCE_TERMS: RefCell<InsuranceTerms> = RefCell::new(InsuranceTerms {
+        premium_bps: 150,
+        min_premium: Money::from_units(1, BASE_CURRENCY),
+        max_coverage: Money::from_units(5_000, BASE_CURRENCY),
+    });
+    static CLAIMS: RefCell<HashMap<String, Claim>> = RefCell::new(HashMap::new());
+    static CLAIM_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Admin configuration
+#[update]
+fn set_insurance_terms(terms: InsuranceTerms) -> Result<InsuranceTerms, String> {
+    metrics::observe("set_insurance_terms", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if terms.min_premium.currency != BASE_CURRENCY || terms.max_coverage.currency != BASE_CURRENCY {
+            return Err(format!("Insurance terms are set in {:?}", BASE_CURRENCY));
+        }
+        if terms.premium_bps > 10_000 {
+            return Err("Premium rate cannot exceed 100%".to_string());
+        }
+        INSURANCE_TERMS.with(|t| *t.borrow_mut() = terms.clone());
+        Ok(terms)
+    })
+}
+
+#[query]
+fn get_insurance_terms() -> InsuranceTerms {
+    INSURANCE_TERMS.with(|t| t.borrow().clone())
+}
+
+// Ledger subaccount of this canister that claims are paid from
+#[query]
+fn get_insurance_pool_subaccount() -> Vec<u8> {
+    pool_subaccount()
+}
+
+// The sender of an insured shipment claims for damage or loss
+#[update]
+fn file_claim(
+    shipment_id: String,
+    kind: ClaimKind,
+    description: String,
+    amount_claimed: Money,
+    evidence: Vec<EvidenceItem>,
+) -> Result<Claim, String> {
+    metrics::observe("file_claim", || {
+        let caller = ic_cdk::caller();
+        let mut v = Validator::new();
+        v.required("description", &description, validation::MAX_TEXT_LEN);
+        v.finish()?;
+        validate_evidence(&evidence, 0)?;
+
+        let shipment = SHIPMENTS
+            .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        if shipment.sender_id != caller {
+            return Err("Only the sender can file a claim".to_string());
+        }
+        let policy = shipment.insurance.as_ref().ok_or_else(|| "Shipment is not insured".to_string())?;
+        let eligible = match kind {
+            ClaimKind::Damage => matches!(
+                shipment.status,
+                ShipmentStatus::Delivered
+                    | ShipmentStatus::AwaitingConfirmation
+                    | ShipmentStatus::Disputed
+                    | ShipmentStatus::Returned
+            ),
+            ClaimKind::Loss => {
+                matches!(shipment.status, ShipmentStatus::Failed | ShipmentStatus::Disputed)
+                    || (shipment.actual_delivery.is_none()
+                        && !matches!(shipment.status, ShipmentStatus::Cancelled)
+                        && time() > shipment.sla_deadline)
+            },
+        };
+        if !eligible {
+            return Err(format!("Shipment is not eligible for a {:?} claim", kind));
+        }
+        if amount_claimed.currency != policy.coverage.currency || amount_claimed.is_zero() {
+            return Err(format!("Claims must be a positive amount in {:?}", policy.coverage.currency));
+        }
+        if amount_claimed.amount_e8s > policy.coverage.amount_e8s {
+            return Err("Claimed amount exceeds the insured coverage".to_string());
+        }
+        let has_claim = CLAIMS.with(|claims| {
+            claims
+                .borrow()
+                .values()
+                .any(|c| c.shipment_id == shipment_id && c.status != ClaimStatus::Rejected)
+        });
+        if has_claim {
+            return Err("Shipment already has a claim".to_string());
+        }
+
+        let claim_id = CLAIM_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            format!("CL{:06}", *c)
+        });
+        let now = time();
+        let claim = Claim {
+            id: claim_id.clone(),
+            dispute_id: confirmation::latest_dispute(&shipment_id).map(|d| d.id),
+            shipment_id: shipment_id.clone(),
+            claimant: caller,
+            kind,
+            description,
+            amount_claimed,
+            evidence: evidence
+                .into_iter()
+                .map(|item| ClaimEvidence { item, added_by: caller, added_at: now })
+                .collect(),
+            status: ClaimStatus::Open,
+            approved_amount: None,
+            adjudicated_by: None,
+            adjudicated_at: None,
+            adjudication_note: None,
+            payout_block_index: None,
+            paid_at: None,
+            created_at: now,
+        };
+        CLAIMS.with(|claims| claims.borrow_mut().insert(claim_id, claim.clone()));
+        Ok(claim)
+    })
+}
+
+#[update]
+fn add_claim_evidence(claim_id: String, evidence: Vec<EvidenceItem>) -> Result<Claim, String> {
+    metrics::observe("add_claim_evidence", || {
+        let caller = ic_cdk::caller();
+        CLAIMS.with(|claims| {
+            let mut claims_map = claims.borrow_mut();
+            let claim = claims_map.get_mut(&claim_id).ok_or_else(|| "Claim not found".to_string())?;
+            if claim.claimant != caller && !permissions::has(&caller, Permission::ResolveDisputes) {
+                return Err("Unauthorized to add evidence".to_string());
+            }
+            if claim.status != ClaimStatus::Open {
+                return Err("Claim is no longer open".to_string());
+            }
+            validate_evidence(&evidence, claim.evidence.len())?;
+            let now = time();
+            claim
+                .evidence
+                .extend(evidence.into_iter().map(|item| ClaimEvidence { item, added_by: caller, added_at: now }));
+            Ok(claim.clone())
+        })
+    })
+}
+
+// Admin adjudication: approve some or all of the claimed amount, or reject it
+#[update]
+fn adjudicate_claim(claim_id: String, approved_amount: Option<Money>, note: String) -> Result<Claim, String> {
+    metrics::observe("adjudicate_claim", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ResolveDisputes)?;
+        let mut v = Validator::new();
+        v.required("note", &note, validation::MAX_TEXT_LEN);
+        v.finish()?;
+
+        let claim = CLAIMS.with(|claims| {
+            let mut claims_map = claims.borrow_mut();
+            let claim = claims_map.get_mut(&claim_id).ok_or_else(|| "Claim not found".to_string())?;
+            if claim.status != ClaimStatus::Open {
+                return Err("Claim already adjudicated".to_string());
+            }
+            if let Some(amount) = &approved_amount {
+                if amount.currency != claim.amount_claimed.currency
+                    || amount.is_zero()
+                    || amount.amount_e8s > claim.amount_claimed.amount_e8s
+                {
+                    return Err("Approved amount must be positive and at most the claimed amount".to_string());
+                }
+            }
+            claim.status = if approved_amount.is_some() { ClaimStatus::Approved } else { ClaimStatus::Rejected };
+            claim.approved_amount = approved_amount;
+            claim.adjudicated_by = Some(caller);
+            claim.adjudicated_at = Some(time());
+            claim.adjudication_note = Some(note.clone());
+            Ok(claim.clone())
+        })?;
+
+        let message = match &claim.approved_amount {
+            Some(amount) => format!(
+                "Claim {} approved for {:.2} {}: {}",
+                claim.id,
+                amount.to_decimal(),
+                amount.currency.symbol(),
+                note
+            ),
+            None => format!("Claim {} rejected: {}", claim.id, note),
+        };
+        notifications::notify(claim.claimant, NotificationKind::Payment, Some(&claim.shipment_id), message);
+        Ok(claim)
+    })
+}
+
+// Pay an approved claim to the claimant's ledger account
+#[update]
+async fn pay_claim(claim_id: String, idempotency_key: Option<String>) -> Result<Claim, String> {
+    metrics::observe_async("pay_claim", async move {
+        let caller = ic_cdk::caller();
+        if let Some(claim) = idempotency::begin(caller, idempotency_key.as_deref(), "pay_claim")? {
+            return Ok(claim);
+        }
+        let result = pay(caller, &claim_id).await;
+        idempotency::finish(caller, idempotency_key.as_deref(), &result);
+        result
+    })
+    .await
+}
+
+#[query]
+fn get_claims(shipment_id: Option<String>) -> Vec<Claim> {
+    let caller = ic_cdk::caller();
+    let mut claims: Vec<Claim> = CLAIMS.with(|claims| {
+        claims
+            .borrow()
+            .values()
+            .filter(|c| c.claimant == caller || permissions::has(&caller, Permission::ResolveDisputes))
+            .filter(|c| shipment_id.as_ref().is_none_or(|id| c.shipment_id == *id))
+            .cloned()
+            .collect()
+    });
+    claims.sort_by(|a, b| a.id.cmp(&b.id));
+    claims
+}
+
+// Add the premium to a quote or new shipment and return the policy it buys
+pub(crate) fn add_premium(breakdown: &mut CostBreakdown, package: &PackageDetails) -> Result<InsurancePolicy, String> {
+    let declared = package.declared_value();
+    if declared.is_zero() {
+        return Err("Insurance requires a declared value".to_string());
+    }
+    let terms = INSURANCE_TERMS.with(|t| t.borrow().clone());
+    let coverage = declared.min(terms.max_coverage);
+    let premium = coverage.mul_ratio(terms.premium_bps as u128, 10_000);
+    let premium = if premium.amount_e8s < terms.min_premium.amount_e8s { terms.min_premium } else { premium };
+
+    breakdown.charges.push(CostLineItem { label: "Insurance premium".to_string(), amount: premium });
+    breakdown.subtotal = breakdown.subtotal.add(premium);
+    breakdown.total = breakdown.total.add(premium);
+    Ok(InsurancePolicy {
+        coverage,
+        premium,
+        purchased_at: time(),
+    })
+}
+
+async fn pay(caller: Principal, claim_id: &str) -> Result<Claim, String> {
+    permissions::require(&caller, Permission::ResolveDisputes)?;
+    let ledger = payments::ledger()?;
+    // Mark as paid before the transfer so the claim cannot be paid twice
+    let claim = CLAIMS.with(|claims| {
+        let mut claims_map = claims.borrow_mut();
+        let claim = claims_map.get_mut(claim_id).ok_or_else(|| "Claim not found".to_string())?;
+        if claim.status != ClaimStatus::Approved {
+            return Err("Only approved claims can be paid".to_string());
+        }
+        if claim.amount_claimed.currency != ledger.currency {
+            return Err(format!("Claims in {:?} cannot be paid on this ledger", claim.amount_claimed.currency));
+        }
+        claim.status = ClaimStatus::Paid;
+        Ok(claim.clone())
+    })?;
+
+    let amount = claim.approved_amount.unwrap_or(claim.amount_claimed);
+    let memo = format!("claim:{}", claim.id).into_bytes();
+    let result = payments::send(&ledger, pool_subaccount(), claim.claimant, amount, memo).await;
+    CLAIMS.with(|claims| {
+        let mut claims_map = claims.borrow_mut();
+        let claim = claims_map.get_mut(claim_id).ok_or_else(|| "Claim not found".to_string())?;
+        match result {
+            Ok(block_index) => {
+                claim.payout_block_index = Some(block_index);
+                claim.paid_at = Some(time());
+                notifications::notify(
+                    claim.claimant,
+                    NotificationKind::Payment,
+                    Some(&claim.shipment_id),
+                    format!("Claim {} has been paid", claim.id),
+                );
+                Ok(claim.clone())
+            },
+            Err(failure) => {
+                claim.status = ClaimStatus::Approved;
+                Err(failure.message())
+            },
+        }
+    })
+}
+
+fn validate_evidence(evidence: &[EvidenceItem], existing: usize) -> Result<(), String> {
+    if existing + evidence.len() > MAX_EVIDENCE_ITEMS {
+        return Err(format!("A claim can have at most {} evidence items", MAX_EVIDENCE_ITEMS));
+    }
+    let mut v = Validator::new();
+    for (i, item) in evidence.iter().enumerate() {
+        v.required(&format!("evidence[{}].description", i), &item.description, validation::MAX_TEXT_LEN);
+        if let Some(url) = &item.url {
+            v.max_len(&format!("evidence[{}].url", i), url, validation::MAX_TEXT_LEN);
+        }
+        if let Some(hash) = &item.content_hash {
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("evidence[{}].content_hash must be a hex SHA-256 digest", i));
+            }
+        }
+    }
+    v.finish()?;
+    Ok(())
+}
+
+fn pool_subaccount() -> Vec<u8> {
+    Sha256::digest(b"insurance-pool").to_vec()
+}
@@ -0,0 +1,318 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::{Driver, VerificationStatus, DRIVERS};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Drivers are reminded this many days before a document expires
+const WARNING_DAYS: [u64; 2] = [30, 7];
+
+// Driver verification documents. Only content hashes are stored on-chain; the
+// documents themselves are reviewed off-chain against these hashes.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DriverDocument {
+    pub kind: DocumentKind,
+    pub sha256: String,
+    pub submitted_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum DocumentKind {
+    DriversLicense,
+    Insurance,
+    VehicleRegistration,
+    VehicleInspection,
+}
+
+impl DocumentKind {
+    // Documents that lapse must be submitted with their expiry date
+    fn expires(&self) -> bool {
+        !matches!(self, DocumentKind::VehicleRegistration)
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DocumentSubmission {
+    pub kind: DocumentKind,
+    pub sha256: String,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DocumentRenewal {
+    pub driver_id: Principal,
+    pub document: DriverDocument,
+}
+
+thread_local! {
+    // Replacement documents awaiting review, one per driver and kind
+    static RENEWALS: RefCell<HashMap<Principal, Vec<DriverDocument>>> = RefCell::new(HashMap::new());
+    // (driver, document hash, days ahead) reminders already sent
+    static WARNED: RefCell<HashSet<(Principal, String, u64)>> = RefCell::new(HashSet::new());
+}
+
+fn required_documents(driver: &Driver) -> Vec<DocumentKind> {
+    let mut required = vec![DocumentKind::DriversLicense, DocumentKind::Insurance, DocumentKind::VehicleRegistration];
+    if driver.vehicle_info.vehicle_type.needs_plate() {
+        required.push(DocumentKind::VehicleInspection);
+    }
+    required
+}
+
+// Driver submits (or resubmits) the full document set for review
+#[update]
+fn submit_driver_documents(documents: Vec<DocumentSubmission>) -> Result<Driver, String> {
+    metrics::observe("submit_driver_documents", || {
+        let caller = ic_cdk::caller();
+        for doc in &documents {
+            validate_submission(doc)?;
+        }
+
+        DRIVERS.with(|drivers| {
+            match drivers.borrow_mut().get_mut(&caller) {
+                Some(driver) => {
+                    if matches!(driver.verification_status, VerificationStatus::Verified) {
+                        return Err("Driver is already verified".to_string());
+                    }
+                    for required in required_documents(driver) {
+                        if !documents.iter().any(|d| d.kind == required) {
+                            return Err(format!("Missing required document: {:?}", required));
+                        }
+                    }
+                    driver.documents = documents.into_iter().map(document).collect();
+                    RENEWALS.with(|r| r.borrow_mut().remove(&caller));
+                    driver.verification_status = VerificationStatus::Pending;
+                    Ok(driver.clone())
+                },
+                None => Err("Driver not registered".to_string()),
+            }
+        })
+    })
+}
+
+// A document replacing one that is about to expire, or already has. The driver
+// keeps their status until the renewal is reviewed.
+#[update]
+fn renew_driver_document(submission: DocumentSubmission) -> Result<DocumentRenewal, String> {
+    metrics::observe("renew_driver_document", || {
+        let caller = ic_cdk::caller();
+        validate_submission(&submission)?;
+        let driver = DRIVERS
+            .with(|drivers| drivers.borrow().get(&caller).cloned())
+            .ok_or_else(|| "Driver not registered".to_string())?;
+        if !matches!(driver.verification_status, VerificationStatus::Verified | VerificationStatus::Expired(_)) {
+            return Err("Only approved drivers renew documents; submit the full set instead".to_string());
+        }
+        let renewed = document(submission);
+        RENEWALS.with(|r| {
+            let mut renewals = r.borrow_mut();
+            let pending = renewals.entry(caller).or_default();
+            pending.retain(|d| d.kind != renewed.kind);
+            pending.push(renewed.clone());
+        });
+        Ok(DocumentRenewal { driver_id: caller, document: renewed })
+    })
+}
+
+#[query]
+fn get_pending_document_renewals() -> Result<Vec<DocumentRenewal>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ReviewCompliance)?;
+    Ok(RENEWALS.with(|r| {
+        r.borrow()
+            .iter()
+            .flat_map(|(driver_id, docs)| {
+                docs.iter().map(|d| DocumentRenewal { driver_id: *driver_id, document: d.clone() })
+            })
+            .collect()
+    }))
+}
+
+// Approving swaps the renewed documents in; a driver held back for expired
+// documents is verified again once none remain expired
+#[update]
+fn approve_document_renewals(driver_id: Principal) -> Result<Driver, String> {
+    metrics::observe("approve_document_renewals", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ReviewCompliance)?;
+        let renewed = RENEWALS
+            .with(|r| r.borrow_mut().remove(&driver_id))
+            .ok_or_else(|| "Driver has no pending renewals".to_string())?;
+        let now = time();
+        DRIVERS.with(|drivers| {
+            let mut drivers = drivers.borrow_mut();
+            let driver = drivers
+                .get_mut(&driver_id)
+                .ok_or_else(|| "Driver not registered".to_string())?;
+            for doc in renewed {
+                driver.documents.retain(|d| d.kind != doc.kind);
+                driver.documents.push(doc);
+            }
+            if matches!(driver.verification_status, VerificationStatus::Expired(_)) {
+                let expired = expired_kinds(driver, now);
+                driver.verification_status = if expired.is_empty() {
+                    VerificationStatus::Verified
+                } else {
+                    VerificationStatus::Expired(expired)
+                };
+            }
+            Ok(driver.clone())
+        })
+    })
+}
+
+#[update]
+fn reject_document_renewals(driver_id: Principal, reason: String) -> Result<(), String> {
+    metrics::observe("reject_document_renewals", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ReviewCompliance)?;
+        RENEWALS
+            .with(|r| r.borrow_mut().remove(&driver_id))
+            .ok_or_else(|| "Driver has no pending renewals".to_string())?;
+        notifications::notify(
+            driver_id,
+            NotificationKind::System,
+            None,
+            format!("Your document renewal was rejected: {}", reason),
+        );
+        Ok(())
+    })
+}
+
+// Admin review queue
+#[query]
+fn get_pending_driver_verifications() -> Result<Vec<Driver>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ReviewCompliance)?;
+
+    Ok(DRIVERS.with(|drivers| {
+        drivers
+            .borrow()
+            .values()
+            .filter(|d| matches!(d.verification_status, VerificationStatus::Pending))
+            .cloned()
+            .collect()
+    }))
+}
+
+#[update]
+fn approve_driver(driver_id: Principal) -> Result<Driver, String> {
+    metrics::observe("approve_driver", || {
+        set_verification_status(driver_id, VerificationStatus::Verified)
+    })
+}
+
+#[update]
+fn reject_driver(driver_id: Principal, reason: String) -> Result<Driver, String> {
+    metrics::observe("reject_driver", || {
+        set_verification_status(driver_id, VerificationStatus::Rejected(reason))
+    })
+}
+
+fn set_verification_status(driver_id: Principal, status: VerificationStatus) -> Result<Driver, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ReviewCompliance)?;
+
+    DRIVERS.with(|drivers| {
+        match drivers.borrow_mut().get_mut(&driver_id) {
+            Some(driver) => {
+                if !matches!(driver.verification_status, VerificationStatus::Pending) {
+                    return Err("Driver has no pending verification".to_string());
+                }
+                driver.verification_status = status;
+                Ok(driver.clone())
+            },
+            None => Err("Driver not registered".to_string()),
+        }
+    })
+}
+
+// Drivers waiting on a first review, and drivers with renewals waiting
+pub(crate) fn review_queue_counts() -> (u32, u32) {
+    let pending = DRIVERS.with(|drivers| {
+        drivers
+            .borrow()
+            .values()
+            .filter(|d| matches!(d.verification_status, VerificationStatus::Pending))
+            .count() as u32
+    });
+    (pending, RENEWALS.with(|r| r.borrow().len() as u32))
+}
+
+// Timer job: remind drivers of documents nearing expiry, and take verified
+// drivers with an expired document out of matching
+pub(crate) fn check_document_expiry() {
+    let now = time();
+    let mut reminders = Vec::new();
+    DRIVERS.with(|drivers| {
+        for driver in drivers.borrow_mut().values_mut() {
+            if !matches!(driver.verification_status, VerificationStatus::Verified) {
+                continue;
+            }
+            let expired = expired_kinds(driver, now);
+            if !expired.is_empty() {
+                reminders.push((
+                    driver.id,
+                    format!("Expired documents: {:?}. Renew them to receive deliveries again.", expired),
+                ));
+                driver.verification_status = VerificationStatus::Expired(expired);
+                continue;
+            }
+            for doc in &driver.documents {
+                let Some(expires_at) = doc.expires_at else {
+                    continue;
+                };
+                let days_left = (expires_at - now) / NANOS_PER_DAY;
+                let Some(&days) = WARNING_DAYS.iter().filter(|d| days_left < **d).min() else {
+                    continue;
+                };
+                let first = WARNED.with(|w| w.borrow_mut().insert((driver.id, doc.sha256.clone(), days)));
+                if first {
+                    reminders.push((
+                        driver.id,
+                        format!("Your {:?} expires in {} days", doc.kind, days_left.max(1)),
+                    ));
+                }
+            }
+        }
+    });
+    for (driver_id, message) in reminders {
+        notifications::notify(driver_id, NotificationKind::System, None, message);
+    }
+}
+
+fn expired_kinds(driver: &Driver, now: u64) -> Vec<DocumentKind> {
+    driver
+        .documents
+        .iter()
+        .filter(|d| d.expires_at.is_some_and(|e| e <= now))
+        .map(|d| d.kind.clone())
+        .collect()
+}
+
+fn validate_submission(doc: &DocumentSubmission) -> Result<(), String> {
+    if doc.sha256.len() != 64 || !doc.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid SHA-256 hash for {:?}", doc.kind));
+    }
+    match doc.expires_at {
+        None if doc.kind.expires() => Err(format!("{:?} needs an expiry date", doc.kind)),
+        Some(expires_at) if expires_at <= time() => Err(format!("{:?} has already expired", doc.kind)),
+        _ => Ok(()),
+    }
+}
+
+fn document(submission: DocumentSubmission) -> DriverDocument {
+    DriverDocument {
+        kind: submission.kind,
+        sha256: submission.sha256.to_lowercase(),
+        submitted_at: time(),
+        expires_at: submission.expires_at,
+    }
+}
@@ -0,0 +1,164 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use sha2::{Digest, Sha256};
+
+use crate::handling::HandlingClass;
+use crate::permissions::{self, Permission};
+use crate::service_level::ServiceLevel;
+use crate::{tracking_token_for, Address, Dimensions, Shipment, SHIPMENTS, USERS};
+
+// Compact QR payload: "IDV1|<shipment id>|<tracking token>|<flags>|<check>", where
+// flags are one letter per handling flag and check is the first four hex digits of
+// SHA-256 over everything before it, so misreads are rejected.
+const QR_PREFIX: &str = "IDV1";
+const QR_CHECK_LEN: usize = 4;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum HandlingFlag {
+    Fragile,
+    Perishable,
+    TemperatureControlled,
+    Hazardous,
+    Oversized,
+    // The recipient must confirm receipt
+    SignatureRequired,
+    // Delivered to a pickup point rather than the door
+    PickupPoint,
+}
+
+impl HandlingFlag {
+    fn code(&self) -> char {
+        match self {
+            HandlingFlag::Fragile => 'F',
+            HandlingFlag::Perishable => 'R',
+            HandlingFlag::TemperatureControlled => 'T',
+            HandlingFlag::Hazardous => 'H',
+            HandlingFlag::Oversized => 'O',
+            HandlingFlag::SignatureRequired => 'S',
+            HandlingFlag::PickupPoint => 'P',
+        }
+    }
+}
+
+// Everything needed to print a label, so renderers never re-derive data
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShippingLabel {
+    pub shipment_id: String,
+    // Code 128 content
+    pub barcode: String,
+    pub qr_payload: String,
+    pub sender_name: String,
+    pub from: Address,
+    pub recipient_name: String,
+    pub recipient_phone: String,
+    pub to: Address,
+    pub service_level: ServiceLevel,
+    pub weight_kg: f64,
+    pub dimensions: Dimensions,
+    pub handling: Vec<HandlingFlag>,
+    pub special_instructions: Option<String>,
+    pub pudo_id: Option<String>,
+    pub pickup_zone_id: Option<String>,
+    pub delivery_zone_id: Option<String>,
+    pub store_id: Option<String>,
+    pub created_at: u64,
+    pub generated_at: u64,
+}
+
+// Decoded QR payload
+pub(crate) struct QrContent {
+    pub shipment_id: String,
+    pub tracking_token: String,
+}
+
+#[query]
+fn generate_label(shipment_id: String) -> Result<ShippingLabel, String> {
+    let caller = ic_cdk::caller();
+    let shipment = SHIPMENTS
+        .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    let is_party = shipment.sender_id == caller || shipment.driver_id == Some(caller);
+    if !is_party && !permissions::has(&caller, Permission::ViewAllShipments) {
+        return Err("Unauthorized to generate label".to_string());
+    }
+    let tracking_token = tracking_token_for(&shipment_id).ok_or_else(|| "Tracking token not found".to_string())?;
+
+    let handling = handling_flags(&shipment);
+    let sender_name = USERS
+        .with(|users| users.borrow().get(&shipment.sender_id).map(|u| u.name.clone()))
+        .unwrap_or_default();
+    Ok(ShippingLabel {
+        barcode: shipment.id.clone(),
+        qr_payload: encode_qr(&shipment.id, &tracking_token, &handling),
+        sender_name,
+        from: shipment.pickup_address.clone(),
+        recipient_name: shipment.recipient_name.clone(),
+        recipient_phone: shipment.recipient_phone.clone(),
+        to: shipment.delivery_address.clone(),
+        service_level: shipment.service_level.clone(),
+        weight_kg: shipment.package_details.weight,
+        dimensions: shipment.package_details.dimensions.clone(),
+        handling,
+        special_instructions: shipment.package_details.special_instructions.clone(),
+        pudo_id: shipment.pudo_id.clone(),
+        pickup_zone_id: shipment.pickup_zone_id.clone(),
+        delivery_zone_id: shipment.delivery_zone_id.clone(),
+        store_id: shipment.store_id.clone(),
+        created_at: shipment.created_at,
+        generated_at: time(),
+        shipment_id,
+    })
+}
+
+fn handling_flags(shipment: &Shipment) -> Vec<HandlingFlag> {
+    let mut flags: Vec<HandlingFlag> = shipment
+        .package_details
+        .handling_classes()
+        .iter()
+        .map(|class| match class {
+            HandlingClass::Fragile => HandlingFlag::Fragile,
+            HandlingClass::Perishable => HandlingFlag::Perishable,
+            HandlingClass::TemperatureControlled => HandlingFlag::TemperatureControlled,
+            HandlingClass::Hazardous => HandlingFlag::Hazardous,
+            HandlingClass::Oversized => HandlingFlag::Oversized,
+        })
+        .collect();
+    if shipment.requires_confirmation {
+        flags.push(HandlingFlag::SignatureRequired);
+    }
+    if shipment.pudo_id.is_some() {
+        flags.push(HandlingFlag::PickupPoint);
+    }
+    flags
+}
+
+fn encode_qr(shipment_id: &str, tracking_token: &str, handling: &[HandlingFlag]) -> String {
+    let flags: String = handling.iter().map(HandlingFlag::code).collect();
+    let body = format!("{}|{}|{}|{}", QR_PREFIX, shipment_id, tracking_token, flags);
+    let check = checksum(&body);
+    format!("{}|{}", body, check)
+}
+
+pub(crate) fn decode_qr(payload: &str) -> Result<QrContent, String> {
+    let (body, check) = payload
+        .trim()
+        .rsplit_once('|')
+        .ok_or_else(|| "Unrecognized label payload".to_string())?;
+    let parts: Vec<&str> = body.split('|').collect();
+    if parts.len() != 4 || parts[0] != QR_PREFIX {
+        return Err("Unrecognized label payload".to_string());
+    }
+    if !checksum(body).eq_ignore_ascii_case(check) {
+        return Err("Label payload checksum mismatch".to_string());
+    }
+    Ok(QrContent {
+        shipment_id: parts[1].to_string(),
+        tracking_token: parts[2].to_string(),
+    })
+}
+
+fn checksum(body: &str) -> String {
+    let digest = Sha256::digest(body.as_bytes());
+    digest.iter().take(QR_CHECK_LEN / 2).map(|b| format!("{:02x}", b)).collect()
+}
@@ -1,8 +1,116 @@
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::time;
 use ic_cdk_macros::*;
+use sha2::{Digest, Sha256};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::time::Duration;
+
+use errors::ShippingError;
+use event_store::ShipmentEvent;
+use events::ShipmentEventKind;
+use metadata::MetadataEntry;
+use exchange::AppliedRate;
+use guards::RateLimitedAction;
+use i18n::LocalizedText;
+use money::{Currency, Money, BASE_CURRENCY};
+use notifications::NotificationKind;
+use permissions::Permission;
+use resource_usage::ResourceFeature;
+use service_keys::ServiceScope;
+use service_level::{ServiceLevel, ServiceLevelPerformance};
+use suspensions::BlockedAction;
+use validation::Validator;
+
+mod accounts;
+mod address_changes;
+mod address_formats;
+mod addresses;
+mod analytics;
+mod archive;
+mod attachments;
+mod audit;
+mod backup;
+mod capacity;
+mod certificates;
+mod cod;
+mod comments;
+mod confirmation;
+mod consolidation;
+mod contacts;
+mod credits;
+mod custody;
+mod delivery_preferences;
+mod earnings;
+mod errors;
+mod event_bus;
+mod event_store;
+mod events;
+mod exchange;
+mod fees;
+mod geofence;
+mod guards;
+mod handling;
+mod heartbeat;
+mod hubs;
+mod i18n;
+mod idempotency;
+mod ids;
+mod import;
+mod insurance;
+mod kyc;
+mod labels;
+mod live_location;
+mod liveness;
+mod loyalty;
+mod memory;
+mod messages;
+mod metadata;
+mod metrics;
+mod money;
+mod nft_receipts;
+mod notifications;
+mod offers;
+mod outbox;
+mod overview;
+mod payments;
+mod permissions;
+mod pii;
+mod postal_codes;
+mod privacy;
+mod pudo;
+mod reattempts;
+mod receipts;
+mod recipients;
+mod recurring;
+mod referrals;
+mod refunds;
+mod reliability;
+mod resource_usage;
+mod routes;
+mod scans;
+mod search;
+mod service_keys;
+mod service_level;
+mod settings;
+mod sharding;
+mod shifts;
+mod signing;
+mod sla;
+mod stores;
+mod surge;
+mod suspensions;
+mod sync;
+mod templates;
+mod text_index;
+mod tracking;
+mod updates;
+mod v2;
+mod validation;
+mod vehicles;
+mod vetkeys;
+mod webhooks;
+mod zones;
 
 // Data structures for the shipping platform
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -11,9 +119,13 @@ pub struct User {
     pub name: String,
     pub email: String,
     pub phone: String,
+    pub email_verified: bool,
+    pub phone_verified: bool,
     pub user_type: UserType,
     pub created_at: u64,
     pub is_active: bool,
+    // Language notifications and display strings are given in; None for the default
+    pub lang: Option<String>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -39,9 +151,168 @@ pub struct Shipment {
     pub updated_at: u64,
     pub estimated_delivery: Option<u64>,
     pub actual_delivery: Option<u64>,
+    // Only the latest event; the full history is paged from `get_tracking_events`.
+    // Archived and offloaded records carry it all.
     pub tracking_history: Vec<TrackingEvent>,
     pub payment_status: PaymentStatus,
+    pub payment: Option<payments::PaymentRecord>,
+    // None for shipments created before payment methods, which pay on the ledger
+    pub payment_method: Option<PaymentMethod>,
+    // Decimal view of `price` for clients of the original f64 interface
     pub cost: f64,
+    pub price: Money,
+    // What the customer pays when quoted in another currency, and the rate used
+    pub quoted_price: Option<Money>,
+    pub exchange_rate: Option<AppliedRate>,
+    pub cost_breakdown: CostBreakdown,
+    pub pudo_id: Option<String>,
+    pub recipient_id: Option<Principal>,
+    pub requires_confirmation: bool,
+    pub metadata: Vec<MetadataEntry>,
+    pub pickup_zone_id: Option<String>,
+    pub delivery_zone_id: Option<String>,
+    pub store_id: Option<String>,
+    pub service_level: ServiceLevel,
+    pub sla_deadline: u64,
+    // Hub-and-spoke route; None for direct pickup-to-delivery shipments
+    pub legs: Option<Vec<hubs::ShipmentLeg>>,
+    pub insurance: Option<insurance::InsurancePolicy>,
+    // Refunds paid back out of the escrowed payment
+    pub adjustments: Option<Vec<refunds::Adjustment>>,
+    // Sequential code such as SH000042 for support and labels; shipments created
+    // before random ids have theirs as the id
+    pub short_code: Option<String>,
+    // ICRC-7 receipt minted on confirmed delivery of a high-value shipment
+    pub receipt_token_id: Option<u64>,
+    // Recurring schedule that created the shipment
+    pub recurrence_id: Option<String>,
+    // Bumped on every change, for optimistic concurrency; None on shipments stored
+    // before versioning, which count as version 0
+    pub version: Option<u64>,
+    // Salted hash of the normalized recipient phone, for matching when
+    // `recipient_phone` only holds a masked form
+    pub recipient_phone_hash: Option<String>,
+    pub pii_scrubbed_at: Option<u64>,
+    // Special instructions encrypted by the client with vetKD; see vetkeys.rs
+    pub encrypted_instructions: Option<Vec<u8>>,
+}
+
+// Compact view returned by list endpoints; `get_shipment` has the full record
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShipmentSummary {
+    pub id: String,
+    pub short_code: Option<String>,
+    pub recipient_name: String,
+    pub status: ShipmentStatus,
+    pub payment_status: PaymentStatus,
+    pub cost: f64,
+    pub price: Money,
+    pub estimated_delivery: Option<u64>,
+    pub last_event: Option<TrackingEvent>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub version: u64,
+}
+
+impl Shipment {
+    pub(crate) fn summary(&self) -> ShipmentSummary {
+        ShipmentSummary {
+            id: self.id.clone(),
+            short_code: self.short_code.clone(),
+            recipient_name: self.recipient_name.clone(),
+            status: self.status.clone(),
+            payment_status: self.payment_status.clone(),
+            cost: self.cost,
+            price: self.price,
+            estimated_delivery: self.estimated_delivery,
+            last_event: self.tracking_history.last().cloned(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            version: self.current_version(),
+        }
+    }
+
+    pub(crate) fn current_version(&self) -> u64 {
+        self.version.unwrap_or(0)
+    }
+
+    // Record a change; every write to a stored shipment goes through here
+    pub(crate) fn touch(&mut self, now: u64) {
+        self.updated_at = now;
+        self.version = Some(self.current_version() + 1);
+    }
+
+    // Callers that pass the version they read must still be looking at it
+    pub(crate) fn check_version(&self, expected_version: Option<u64>) -> Result<(), ShippingError> {
+        match expected_version {
+            Some(expected) if expected != self.current_version() => Err(ShippingError::Conflict {
+                expected_version: expected,
+                current: Box::new(self.clone()),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Optional settings supplied when creating a shipment
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct ShipmentOptions {
+    pub pudo_id: Option<String>,
+    pub recipient_id: Option<Principal>,
+    pub require_confirmation: Option<bool>,
+    pub promo_code: Option<String>,
+    pub apply_credits: Option<bool>,
+    pub metadata: Option<Vec<MetadataEntry>>,
+    pub currency: Option<Currency>,
+    // Create the shipment on behalf of a store the caller owns or works at,
+    // optionally collecting from the store's default pickup location
+    pub store_id: Option<String>,
+    pub use_store_pickup: Option<bool>,
+    pub service_level: Option<ServiceLevel>,
+    pub payment_method: Option<PaymentMethod>,
+    // Buy insurance covering the declared value
+    pub insured: Option<bool>,
+    // Address book entries to use instead of the inline addresses
+    pub pickup_address_id: Option<String>,
+    pub delivery_address_id: Option<String>,
+    pub use_default_pickup: Option<bool>,
+}
+
+// Price and delivery target for a prospective shipment, before promos and credits
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Quote {
+    pub cost_breakdown: CostBreakdown,
+    pub price: Money,
+    pub service_level: ServiceLevel,
+    pub sla_target_hours: u64,
+    pub estimated_delivery: u64,
+}
+
+// Itemized pricing: charges add up to the subtotal, deductions are applied in order
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct CostBreakdown {
+    pub charges: Vec<CostLineItem>,
+    pub deductions: Vec<CostLineItem>,
+    pub subtotal: Money,
+    pub total: Money,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CostLineItem {
+    pub label: String,
+    pub amount: Money,
+}
+
+impl CostBreakdown {
+    fn from_charges(charges: Vec<CostLineItem>) -> Self {
+        let subtotal = Money::sum(charges.iter().map(|c| c.amount), BASE_CURRENCY);
+        CostBreakdown {
+            charges,
+            deductions: Vec::new(),
+            subtotal,
+            total: subtotal,
+        }
+    }
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -52,6 +323,17 @@ pub struct Address {
     pub postal_code: String,
     pub country: String,
     pub coordinates: Option<Coordinates>,
+    pub extras: Option<AddressExtras>,
+}
+
+// Details that help a driver find the door, beyond the postal address
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct AddressExtras {
+    pub building: Option<String>,
+    pub floor: Option<String>,
+    pub landmark: Option<String>,
+    // "///filled.count.soap"
+    pub what3words: Option<String>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -60,16 +342,42 @@ pub struct Coordinates {
     pub longitude: f64,
 }
 
+impl Coordinates {
+    // Great-circle distance
+    fn distance_km(&self, other: &Coordinates) -> f64 {
+        const EARTH_RADIUS_KM: f64 = 6371.0;
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct PackageDetails {
     pub description: String,
     pub weight: f64,
     pub dimensions: Dimensions,
+    // Legacy decimal value, used when `declared_value` is not supplied
     pub value: f64,
-    pub fragile: bool,
+    pub declared_value: Option<Money>,
+    // None on records archived before handling classes
+    pub handling: Option<Vec<handling::HandlingClass>>,
     pub special_instructions: Option<String>,
 }
 
+impl PackageDetails {
+    fn declared_value(&self) -> Money {
+        self.declared_value
+            .unwrap_or_else(|| Money::from_decimal(self.value, BASE_CURRENCY))
+    }
+
+    fn handling_classes(&self) -> &[handling::HandlingClass] {
+        self.handling.as_deref().unwrap_or_default()
+    }
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct Dimensions {
     pub length: f64,
@@ -77,27 +385,94 @@ pub struct Dimensions {
     pub height: f64,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
 pub enum ShipmentStatus {
     Created,
     PickupScheduled,
     PickedUp,
     InTransit,
     OutForDelivery,
+    AtPickupPoint,
+    AwaitingConfirmation,
     Delivered,
+    Disputed,
     Failed,
     Returned,
     Cancelled,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
+impl ShipmentStatus {
+    pub const ALL: [ShipmentStatus; 12] = [
+        ShipmentStatus::Created,
+        ShipmentStatus::PickupScheduled,
+        ShipmentStatus::PickedUp,
+        ShipmentStatus::InTransit,
+        ShipmentStatus::OutForDelivery,
+        ShipmentStatus::AtPickupPoint,
+        ShipmentStatus::AwaitingConfirmation,
+        ShipmentStatus::Delivered,
+        ShipmentStatus::Disputed,
+        ShipmentStatus::Failed,
+        ShipmentStatus::Returned,
+        ShipmentStatus::Cancelled,
+    ];
+
+    // Forward transitions a shipment may take through its lifecycle
+    fn can_transition_to(&self, next: &ShipmentStatus) -> bool {
+        use ShipmentStatus::*;
+        matches!(
+            (self, next),
+            (Created, PickupScheduled | Cancelled)
+                | (PickupScheduled, PickedUp | Failed | Cancelled)
+                | (PickedUp, InTransit | OutForDelivery | AtPickupPoint | Failed)
+                | (InTransit, OutForDelivery | AtPickupPoint | Failed)
+                | (OutForDelivery, Delivered | AwaitingConfirmation | AtPickupPoint | Failed)
+                | (AtPickupPoint, Delivered | Returned)
+                | (AwaitingConfirmation, Delivered | Disputed)
+                | (Disputed, Delivered | Failed)
+                | (Failed, PickupScheduled | Returned | Cancelled)
+                | (Delivered, Returned)
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
 pub enum PaymentStatus {
     Pending,
+    // A ledger call collecting the payment is out
+    Processing,
     Paid,
     Failed,
     Refunded,
 }
 
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub enum PaymentMethod {
+    // Paid up front through the payment ledger
+    #[default]
+    Ledger,
+    // Paid in cash to the driver, who later remits it
+    CashOnDelivery,
+}
+
+// One item of a batch status update
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StatusUpdate {
+    pub shipment_id: String,
+    pub status: ShipmentStatus,
+    pub location: Option<String>,
+    pub description: String,
+    pub expected_version: Option<u64>,
+}
+
+// `result` holds the status the shipment ended in, e.g. AwaitingConfirmation for
+// a delivery that needs sign-off
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StatusUpdateResult {
+    pub shipment_id: String,
+    pub result: Result<ShipmentStatus, ShippingError>,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct TrackingEvent {
     pub timestamp: u64,
@@ -105,6 +480,36 @@ pub struct TrackingEvent {
     pub location: Option<String>,
     pub description: String,
     pub updated_by: Principal,
+    // None for plain status updates
+    pub kind: Option<TrackingEventKind>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum TrackingEventKind {
+    Scanned {
+        role: scans::ScannerRole,
+        coordinates: Option<Coordinates>,
+    },
+    HandlingAcknowledged {
+        classes: Vec<handling::HandlingClass>,
+    },
+    CustodyTransferred {
+        from: Principal,
+        to: Principal,
+        hub_id: Option<String>,
+    },
+    GeofenceEntered {
+        fence: geofence::Fence,
+        coordinates: Coordinates,
+        distance_m: u32,
+    },
+    AddressChanged {
+        previous_price: Money,
+        new_price: Money,
+    },
+    DeliveryProofRecorded {
+        proof: delivery_preferences::DeliveryProof,
+    },
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -114,17 +519,32 @@ pub struct Driver {
     pub phone: String,
     pub vehicle_info: VehicleInfo,
     pub current_location: Option<Coordinates>,
+    // Whether the driver is on shift; recomputed from the schedule when drivers are listed
     pub is_available: bool,
     pub rating: f64,
     pub total_deliveries: u32,
     pub joined_at: u64,
+    pub verification_status: VerificationStatus,
+    pub documents: Vec<kyc::DriverDocument>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum VerificationStatus {
+    Unsubmitted,
+    Pending,
+    Verified,
+    Rejected(String),
+    // Verified, then held back from matching until these documents are renewed
+    Expired(Vec<kyc::DocumentKind>),
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct VehicleInfo {
-    pub vehicle_type: String,
+    pub vehicle_type: vehicles::VehicleType,
     pub license_plate: String,
+    // Maximum load in kg, and optionally in cubic centimetres of package volume
     pub capacity: f64,
+    pub volume_capacity: Option<f64>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -132,12 +552,23 @@ pub struct ReturnRequest {
     pub id: String,
     pub shipment_id: String,
     pub requester_id: Principal,
+    pub initiator: ReturnInitiator,
     pub reason: String,
     pub status: ReturnStatus,
+    // The store owner for store shipments, the sender for recipient returns of
+    // other shipments; None when only admins may decide
+    pub approver: Option<Principal>,
+    pub review_note: Option<String>,
     pub created_at: u64,
     pub processed_at: Option<u64>,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ReturnInitiator {
+    Sender,
+    Recipient,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum ReturnStatus {
     Requested,
@@ -151,38 +582,158 @@ pub enum ReturnStatus {
 thread_local! {
     static USERS: RefCell<HashMap<Principal, User>> = RefCell::new(HashMap::new());
     static SHIPMENTS: RefCell<HashMap<String, Shipment>> = RefCell::new(HashMap::new());
+    static TRACKING_TOKENS: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
     static DRIVERS: RefCell<HashMap<Principal, Driver>> = RefCell::new(HashMap::new());
     static RETURN_REQUESTS: RefCell<HashMap<String, ReturnRequest>> = RefCell::new(HashMap::new());
-    static SHIPMENT_COUNTER: RefCell<u64> = RefCell::new(0);
+    // Short code to shipment id
+    static SHORT_CODES: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
     static RETURN_COUNTER: RefCell<u64> = RefCell::new(0);
 }
 
+// Shipment ids are "sh_<ULID>"; short codes "SH<number>"
+const SHIPMENT_ID_PREFIX: &str = "sh";
+const SHIPMENT_CODE_PREFIX: &str = "SH";
+
+// Roughly one driver route of stops per call
+const MAX_STATUS_BATCH_SIZE: usize = 50;
+
+const JOB_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+// Canister lifecycle
+#[init]
+fn init(args: Option<sharding::InitArgs>) {
+    sharding::init(args);
+    ids::schedule_seeding();
+    start_timers();
+}
+
+// State kept in stable memory across upgrades
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct StableState {
+    rate_limits: guards::StableRateLimits,
+    archive: Option<archive::StableArchiveState>,
+    sharding: Option<sharding::StableShardingState>,
+    ids: Option<ids::StableIdState>,
+    attachments: Option<attachments::StableAttachmentState>,
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let state = StableState {
+        rate_limits: guards::stable_state(),
+        archive: Some(archive::stable_state()),
+        sharding: Some(sharding::stable_state()),
+        ids: Some(ids::stable_state()),
+        attachments: Some(attachments::stable_state()),
+    };
+    memory::save_upgrade_state(&state);
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // Nothing is saved when upgrading from a version without pre_upgrade
+    if let Some(state) = memory::load_upgrade_state::<StableState>() {
+        guards::restore_stable_state(state.rate_limits);
+        if let Some(archive) = state.archive {
+            archive::restore_stable_state(archive);
+        }
+        if let Some(sharding) = state.sharding {
+            sharding::restore_stable_state(sharding);
+        }
+        if let Some(ids) = state.ids {
+            ids::restore_stable_state(ids);
+        }
+        if let Some(attachments) = state.attachments {
+            attachments::restore_stable_state(attachments);
+        }
+    }
+    ids::schedule_seeding();
+    start_timers();
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(JOB_INTERVAL, run_periodic_jobs);
+    ic_cdk_timers::set_timer_interval(DISPATCH_INTERVAL, run_dispatch_jobs);
+}
+
+fn run_periodic_jobs() {
+    confirmation::auto_confirm_deliveries();
+    sla::check_sla();
+    notifications::compact_notifications();
+    messages::prune_threads();
+    geofence::prune_triggers();
+    idempotency::prune_expired();
+    outbox::prune_completed();
+    updates::prune_feed();
+    attachments::prune_uploads();
+    guards::prune_buckets();
+    tracking::compact_events();
+    kyc::check_document_expiry();
+    loyalty::expire_points();
+    pii::scrub_old_shipments();
+    archive::archive_old_shipments();
+    sharding::schedule_rebalance();
+}
+
+// Outbound side effects run on a shorter interval
+fn run_dispatch_jobs() {
+    outbox::process();
+    nft_receipts::process_mints();
+    offers::expire_due_offers();
+    liveness::check_driver_liveness();
+    surge::refresh_levels();
+    reattempts::start_due_reattempts();
+    recurring::run_due();
+}
+
 // User management functions
 #[update]
-fn register_user(name: String, email: String, phone: String, user_type: UserType) -> Result<User, String> {
-    let caller = ic_cdk::caller();
-    
-    // Check if user already exists
-    let user_exists = USERS.with(|users| users.borrow().contains_key(&caller));
-    if user_exists {
-        return Err("User already registered".to_string());
-    }
-
-    let user = User {
-        id: caller,
-        name,
-        email,
-        phone,
-        user_type,
-        created_at: time(),
-        is_active: true,
-    };
+fn register_user(
+    name: String,
+    email: String,
+    phone: String,
+    user_type: UserType,
+    referral_code: Option<String>,
+) -> Result<User, String> {
+    metrics::observe("register_user", || {
+        let caller = ic_cdk::caller();
+        guards::check_rate_limit(caller, RateLimitedAction::RegisterUser)?;
+        let mut v = Validator::new();
+        v.required("name", &name, validation::MAX_NAME_LEN);
+        v.email("email", &email);
+        v.phone("phone", &phone);
+        v.finish()?;
 
-    USERS.with(|users| {
-        users.borrow_mut().insert(caller, user.clone());
-    });
+        // Check if user already exists
+        let user_exists = USERS.with(|users| users.borrow().contains_key(&caller));
+        if user_exists {
+            return Err("User already registered".to_string());
+        }
+        if let Some(code) = &referral_code {
+            referrals::attribute(caller, &phone, &email, code)?;
+        }
 
-    Ok(user)
+        let user = User {
+            id: caller,
+            name,
+            email,
+            phone,
+            email_verified: false,
+            phone_verified: false,
+            user_type,
+            created_at: time(),
+            is_active: true,
+            lang: None,
+        };
+
+        USERS.with(|users| {
+            users.borrow_mut().insert(caller, user.clone());
+        });
+        text_index::index_user(&user);
+
+        Ok(user)
+    })
 }
 
 #[query]
@@ -198,35 +749,170 @@ fn get_current_user() -> Option<User> {
 
 // Shipment management functions
 #[update]
-fn create_shipment(
+async fn create_shipment(
     recipient_name: String,
     recipient_phone: String,
     pickup_address: Address,
     delivery_address: Address,
     package_details: PackageDetails,
+    options: Option<ShipmentOptions>,
+    idempotency_key: Option<String>,
 ) -> Result<Shipment, String> {
-    let caller = ic_cdk::caller();
-    
-    // Verify user exists and is authorized
+    metrics::observe_async("create_shipment", async move {
+        let caller = service_keys::acting_principal(ic_cdk::caller(), ServiceScope::CreateShipments)?;
+        if let Some(shipment) = idempotency::begin(caller, idempotency_key.as_deref(), "create_shipment")? {
+            return Ok(shipment);
+        }
+        let result = place_shipment(
+            caller,
+            NewShipment {
+                recipient_name,
+                recipient_phone,
+                pickup_address,
+                delivery_address,
+                package_details,
+            },
+            options.unwrap_or_default(),
+        )
+        .await;
+        idempotency::finish(caller, idempotency_key.as_deref(), &result);
+        result
+    })
+    .await
+}
+
+async fn place_shipment(caller: Principal, new: NewShipment, options: ShipmentOptions) -> Result<Shipment, String> {
+    guards::check_rate_limit(caller, RateLimitedAction::CreateShipment)?;
+
+    check_sender(caller)?;
+    if let Some(metadata) = &options.metadata {
+        metadata::validate_metadata(metadata)?;
+    }
+
+    // Fetch the quote rate before touching any state
+    let exchange_rate = match options.currency {
+        Some(currency) if currency != BASE_CURRENCY => {
+            let rate = exchange::rate_for(currency, caller).await?;
+            // The account may have been deactivated or suspended during the call
+            check_sender(caller)?;
+            Some(rate)
+        },
+        _ => None,
+    };
+
+    let shipment = insert_shipment(caller, new, options, exchange_rate)?;
+    resource_usage::record_instructions(caller, Some(&shipment.id));
+
+    Ok(shipment)
+}
+
+// Verify user exists and is authorized
+fn check_sender(caller: Principal) -> Result<(), String> {
     let user = USERS.with(|users| users.borrow().get(&caller).cloned());
     match user {
-        Some(u) => match u.user_type {
-            UserType::Customer | UserType::StoreOwner => {},
-            _ => return Err("Unauthorized to create shipments".to_string()),
-        },
+        Some(u) if !u.is_active => return Err("Account is deactivated".to_string()),
+        Some(_) => permissions::require(&caller, Permission::CreateShipment)?,
         None => return Err("User not registered".to_string()),
     }
+    suspensions::check(caller, BlockedAction::CreateShipment)
+}
 
-    let shipment_id = SHIPMENT_COUNTER.with(|counter| {
-        let mut c = counter.borrow_mut();
-        *c += 1;
-        format!("SH{:06}", *c)
-    });
+struct NewShipment {
+    recipient_name: String,
+    recipient_phone: String,
+    pickup_address: Address,
+    delivery_address: Address,
+    package_details: PackageDetails,
+}
+
+// Price and store a new shipment for an already authorized sender. Shared by
+// interactive creation and bulk import.
+fn insert_shipment(
+    caller: Principal,
+    new: NewShipment,
+    options: ShipmentOptions,
+    exchange_rate: Option<AppliedRate>,
+) -> Result<Shipment, String> {
+    let NewShipment {
+        recipient_name,
+        recipient_phone,
+        pickup_address,
+        delivery_address,
+        mut package_details,
+    } = new;
+
+    // Saved addresses stand in for the inline ones, which are then ignored
+    let pickup_address = match (&options.pickup_address_id, options.use_default_pickup.unwrap_or(false)) {
+        (Some(address_id), _) => addresses::resolve(caller, address_id)?,
+        (None, true) => addresses::default_pickup(caller).ok_or_else(|| "No default pickup address saved".to_string())?,
+        (None, false) => pickup_address,
+    };
+    let delivery_address = match &options.delivery_address_id {
+        Some(address_id) => addresses::resolve(caller, address_id)?,
+        None => delivery_address,
+    };
+    let mut v = Validator::new();
+    v.shipment(&recipient_name, &recipient_phone, &pickup_address, &delivery_address, &package_details);
+    v.finish()?;
+
+    let pickup_address = match &options.store_id {
+        Some(store_id) => {
+            let store = stores::store_for_shipment(store_id, caller)?;
+            if options.use_store_pickup.unwrap_or(false) {
+                store.default_pickup_address.unwrap_or(store.address)
+            } else {
+                pickup_address
+            }
+        },
+        None => pickup_address,
+    };
+
+    if let Some(classes) = &mut package_details.handling {
+        classes.sort();
+        classes.dedup();
+    }
 
-    // Calculate cost based on distance and package details
-    let cost = calculate_shipping_cost(&pickup_address, &delivery_address, &package_details);
+    // Keep both representations of the declared value in sync
+    let declared_value = package_details.declared_value();
+    package_details.declared_value = Some(declared_value);
+    package_details.value = declared_value.to_decimal();
 
-    let shipment = Shipment {
+    // Shipments addressed to a pickup point are delivered to the point's address
+    let delivery_address = match &options.pudo_id {
+        Some(pudo_id) => pudo::pudo_delivery_address(pudo_id)?,
+        None => delivery_address,
+    };
+    let pickup_address = postal_codes::normalize(pickup_address);
+    let delivery_address = postal_codes::normalize(delivery_address);
+    let zones = zones::resolve_shipment_zones(&pickup_address, &delivery_address)?;
+    let service_level = options.service_level.clone().unwrap_or_default();
+    let payment_method = options.payment_method.clone().unwrap_or_default();
+    if payment_method == PaymentMethod::CashOnDelivery && exchange_rate.is_some() {
+        return Err(format!("Cash on delivery is only available in {:?}", BASE_CURRENCY));
+    }
+    let sla_deadline = time() + sla::target_nanos(&service_level, &zones);
+
+    let shipment_id = ids::new_id(SHIPMENT_ID_PREFIX)?;
+    let short_code = ids::next_code(SHIPMENT_CODE_PREFIX);
+    let (recipient_phone, recipient_phone_hash) = pii::protect_phone(recipient_phone)?;
+
+    // Calculate cost based on distance and package details, then apply promos and credits
+    let mut cost_breakdown =
+        price_shipment(&pickup_address, &delivery_address, &package_details, &zones, &service_level);
+    let insurance = match options.insured {
+        Some(true) => Some(insurance::add_premium(&mut cost_breakdown, &package_details)?),
+        _ => None,
+    };
+    credits::apply_at_checkout(
+        caller,
+        &shipment_id,
+        &mut cost_breakdown,
+        options.promo_code.as_deref(),
+        options.apply_credits.unwrap_or(true),
+    )?;
+    let price = cost_breakdown.total;
+
+    let mut shipment = Shipment {
         id: shipment_id.clone(),
         sender_id: caller,
         recipient_name,
@@ -238,7 +924,7 @@ fn create_shipment(
         driver_id: None,
         created_at: time(),
         updated_at: time(),
-        estimated_delivery: None,
+        estimated_delivery: Some(sla_deadline),
         actual_delivery: None,
         tracking_history: vec![TrackingEvent {
             timestamp: time(),
@@ -246,15 +932,137 @@ fn create_shipment(
             location: None,
             description: "Shipment created".to_string(),
             updated_by: caller,
+            kind: None,
         }],
         payment_status: PaymentStatus::Pending,
-        cost,
+        payment: None,
+        payment_method: Some(payment_method),
+        cost: price.to_decimal(),
+        price,
+        quoted_price: exchange_rate.as_ref().map(|rate| rate.convert(price)),
+        exchange_rate,
+        cost_breakdown,
+        pudo_id: options.pudo_id,
+        recipient_id: options.recipient_id,
+        requires_confirmation: options.require_confirmation.unwrap_or(false),
+        metadata: options.metadata.unwrap_or_default(),
+        pickup_zone_id: zones.pickup_zone_id,
+        delivery_zone_id: zones.delivery_zone_id,
+        store_id: options.store_id,
+        service_level,
+        sla_deadline,
+        legs: None,
+        insurance,
+        adjustments: None,
+        short_code: Some(short_code.clone()),
+        receipt_token_id: None,
+        recurrence_id: None,
+        version: Some(1),
+        recipient_phone_hash,
+        pii_scrubbed_at: None,
+        encrypted_instructions: None,
     };
 
+    let tracking_token = generate_token(&shipment_id);
+    TRACKING_TOKENS.with(|tokens| {
+        tokens.borrow_mut().insert(tracking_token, shipment_id.clone());
+    });
+    SHORT_CODES.with(|codes| codes.borrow_mut().insert(short_code, shipment_id.clone()));
+
+    tracking::attach(&mut shipment);
+    text_index::index_shipment(&shipment);
     SHIPMENTS.with(|shipments| {
         shipments.borrow_mut().insert(shipment_id, shipment.clone());
     });
+    event_store::record(
+        &shipment,
+        caller,
+        ShipmentEvent::ShipmentCreated {
+            shipment: Box::new(shipment.clone()),
+        },
+    );
+
+    events::publish(&shipment, ShipmentEventKind::Created);
+
+    Ok(shipment)
+}
+
+#[query]
+fn get_quote(
+    pickup_address: Address,
+    delivery_address: Address,
+    package_details: PackageDetails,
+    options: Option<ShipmentOptions>,
+) -> Result<Quote, String> {
+    let mut v = Validator::new();
+    v.package("package_details", &package_details);
+    v.finish()?;
+    let options = options.unwrap_or_default();
+    let delivery_address = match &options.pudo_id {
+        Some(pudo_id) => pudo::pudo_delivery_address(pudo_id)?,
+        None => delivery_address,
+    };
+    let pickup_address = postal_codes::normalize(pickup_address);
+    let delivery_address = postal_codes::normalize(delivery_address);
+    let zones = zones::resolve_shipment_zones(&pickup_address, &delivery_address)?;
+    let service_level = options.service_level.unwrap_or_default();
+
+    let mut cost_breakdown =
+        price_shipment(&pickup_address, &delivery_address, &package_details, &zones, &service_level);
+    if options.insured == Some(true) {
+        insurance::add_premium(&mut cost_breakdown, &package_details)?;
+    }
+    Ok(Quote {
+        price: cost_breakdown.total,
+        cost_breakdown,
+        sla_target_hours: service_level.target_hours() + zones.sla_extra_hours,
+        estimated_delivery: time() + sla::target_nanos(&service_level, &zones),
+        service_level,
+    })
+}
 
+// List price of a shipment: distance and package charges, zone surcharge, and the
+// service level adjustment
+fn price_shipment(
+    pickup: &Address,
+    delivery: &Address,
+    package: &PackageDetails,
+    zones: &zones::ShipmentZones,
+    service_level: &ServiceLevel,
+) -> CostBreakdown {
+    let mut charges = calculate_shipping_charges(pickup, delivery, package);
+    charges.extend(vehicles::surcharge(package, zones));
+    if let Some((zone_name, surcharge)) = &zones.surcharge {
+        charges.push(CostLineItem { label: format!("Zone surcharge {}", zone_name), amount: *surcharge });
+    }
+    let mut breakdown = CostBreakdown::from_charges(charges);
+    surge::apply_pricing(zones, &mut breakdown);
+    service_level::apply_pricing(service_level, &mut breakdown);
+    breakdown
+}
+
+// Look a shipment up by its short code. Codes are sequential, so unlike ids
+// they only resolve for the shipment's parties.
+#[query]
+fn find_shipment_by_code(short_code: String) -> Result<Shipment, String> {
+    let caller = ic_cdk::caller();
+    let code = short_code.trim().to_uppercase();
+    let shipment = SHORT_CODES
+        .with(|codes| codes.borrow().get(&code).cloned())
+        .or_else(|| ids::code_number(&code, SHIPMENT_CODE_PREFIX).map(|_| code.clone()))
+        .and_then(|id| SHIPMENTS.with(|shipments| shipments.borrow().get(&id).cloned()))
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    let is_party = shipment.sender_id == caller
+        || shipment.driver_id == Some(caller)
+        || is_recipient(&shipment, &caller, None)
+        || shipment
+            .store_id
+            .as_deref()
+            .and_then(stores::find)
+            .is_some_and(|store| stores::is_member(&store, caller));
+    if !is_party && !permissions::has(&caller, Permission::ViewAllShipments) {
+        return Err("Shipment not found".to_string());
+    }
     Ok(shipment)
 }
 
@@ -263,19 +1071,62 @@ fn get_shipment(shipment_id: String) -> Option<Shipment> {
     SHIPMENTS.with(|shipments| shipments.borrow().get(&shipment_id).cloned())
 }
 
+// Tracking tokens let recipients follow and act on a shipment without an account
 #[query]
-fn get_user_shipments() -> Vec<Shipment> {
+fn get_tracking_token(shipment_id: String) -> Result<String, String> {
     let caller = ic_cdk::caller();
+    let is_sender = SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .get(&shipment_id)
+            .map(|s| s.sender_id == caller)
+            .unwrap_or(false)
+    });
+    if !is_sender {
+        return Err("Unauthorized to view tracking token".to_string());
+    }
+
+    tracking_token_for(&shipment_id).ok_or_else(|| "Tracking token not found".to_string())
+}
+
+fn tracking_token_for(shipment_id: &str) -> Option<String> {
+    TRACKING_TOKENS.with(|tokens| {
+        tokens
+            .borrow()
+            .iter()
+            .find(|(_, id)| *id == shipment_id)
+            .map(|(token, _)| token.clone())
+    })
+}
+
+#[query]
+fn get_shipment_by_token(tracking_token: String) -> Option<Shipment> {
+    let shipment_id = TRACKING_TOKENS.with(|tokens| tokens.borrow().get(&tracking_token).cloned())?;
+    SHIPMENTS.with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+}
+
+// Version 1 interface, unpaged; see v2_get_user_shipments
+#[query]
+fn get_user_shipments() -> Vec<ShipmentSummary> {
+    user_shipments(ic_cdk::caller())
+}
+
+pub(crate) fn user_shipments(caller: Principal) -> Vec<ShipmentSummary> {
+    // Revoked or unscoped service keys see nothing
+    let Ok(caller) = service_keys::acting_principal(caller, ServiceScope::ReadShipments) else {
+        return Vec::new();
+    };
     SHIPMENTS.with(|shipments| {
         shipments
             .borrow()
             .values()
             .filter(|s| s.sender_id == caller)
-            .cloned()
+            .map(Shipment::summary)
             .collect()
     })
 }
 
+// Version 1 interface, kept for existing clients; see v2_update_shipment_status
 #[update]
 fn update_shipment_status(
     shipment_id: String,
@@ -283,49 +1134,207 @@ fn update_shipment_status(
     location: Option<String>,
     description: String,
 ) -> Result<Shipment, String> {
-    let caller = ic_cdk::caller();
-    
+    metrics::observe("update_shipment_status", || {
+        let caller = ic_cdk::caller();
+        Ok(set_shipment_status(caller, &shipment_id, new_status, location, description, None)?)
+    })
+}
+
+pub(crate) fn set_shipment_status(
+    caller: Principal,
+    shipment_id: &str,
+    new_status: ShipmentStatus,
+    location: Option<String>,
+    description: String,
+    expected_version: Option<u64>,
+) -> Result<Shipment, ShippingError> {
+    validate_status_text(location.as_deref(), &description)?;
+    let can_update_any = permissions::has(&caller, Permission::UpdateAnyShipment);
+
     SHIPMENTS.with(|shipments| {
         let mut shipments_map = shipments.borrow_mut();
-        match shipments_map.get_mut(&shipment_id) {
+        match shipments_map.get_mut(shipment_id) {
             Some(shipment) => {
-                // Verify authorization
-                if shipment.sender_id != caller && shipment.driver_id != Some(caller) {
-                    // Check if caller is admin
-                    let user = USERS.with(|users| users.borrow().get(&caller).cloned());
-                    match user {
-                        Some(u) => match u.user_type {
-                            UserType::Admin => {},
-                            _ => return Err("Unauthorized to update shipment".to_string()),
-                        },
-                        None => return Err("User not registered".to_string()),
-                    }
-                }
-
-                shipment.status = new_status.clone();
-                shipment.updated_at = time();
-                
-                // Add tracking event
-                shipment.tracking_history.push(TrackingEvent {
-                    timestamp: time(),
-                    status: new_status,
-                    location,
-                    description,
-                    updated_by: caller,
-                });
-
-                // Set actual delivery time if delivered
-                if matches!(shipment.status, ShipmentStatus::Delivered) {
-                    shipment.actual_delivery = Some(time());
-                }
+                check_status_update(shipment, caller, can_update_any, &new_status)?;
+                shipment.check_version(expected_version)?;
+                apply_status_update(shipment, new_status, location, description, caller, time());
+                resource_usage::record_instructions(shipment.sender_id, Some(&shipment.id));
 
                 Ok(shipment.clone())
             },
-            None => Err("Shipment not found".to_string()),
+            None => Err("Shipment not found".to_string().into()),
+        }
+    })
+}
+
+// Items apply in order and each on its own: a rejected item leaves its shipment
+// untouched and the rest still run. Results carry the new status rather than the
+// shipment.
+#[update]
+fn update_statuses_batch(updates: Vec<StatusUpdate>) -> Result<Vec<StatusUpdateResult>, String> {
+    metrics::observe("update_statuses_batch", || {
+        let caller = ic_cdk::caller();
+        if updates.len() > MAX_STATUS_BATCH_SIZE {
+            return Err(format!("At most {} updates per batch", MAX_STATUS_BATCH_SIZE));
         }
+        let can_update_any = permissions::has(&caller, Permission::UpdateAnyShipment);
+        let now = time();
+
+        SHIPMENTS.with(|shipments| {
+            let mut shipments_map = shipments.borrow_mut();
+            let results = updates
+                .into_iter()
+                .map(|update| {
+                    let started = ic_cdk::api::performance_counter(0);
+                    let result = match shipments_map.get_mut(&update.shipment_id) {
+                        Some(shipment) => validate_status_text(update.location.as_deref(), &update.description)
+                            .and_then(|()| {
+                                check_status_update(shipment, caller, can_update_any, &update.status)
+                                    .map_err(ShippingError::from)
+                            })
+                            .and_then(|()| shipment.check_version(update.expected_version))
+                            .map(|()| {
+                                let StatusUpdate { status, location, description, .. } = update;
+                                apply_status_update(shipment, status, location, description, caller, now);
+                                resource_usage::record(
+                                    shipment.sender_id,
+                                    Some(&shipment.id),
+                                    ResourceFeature::UpdateInstructions,
+                                    0,
+                                    ic_cdk::api::performance_counter(0).saturating_sub(started),
+                                );
+                                shipment.status.clone()
+                            }),
+                        None => Err("Shipment not found".to_string().into()),
+                    };
+                    StatusUpdateResult { shipment_id: update.shipment_id, result }
+                })
+                .collect();
+            Ok(results)
+        })
     })
 }
 
+// The free text a status update writes into the tracking history
+pub(crate) fn validate_status_text(location: Option<&str>, description: &str) -> Result<(), ShippingError> {
+    let mut v = Validator::new();
+    v.max_len("description", description, validation::MAX_TEXT_LEN);
+    if let Some(location) = location {
+        v.max_len("location", location, validation::MAX_TEXT_LEN);
+    }
+    v.finish()
+}
+
+// Whether `caller` may move the shipment to `new_status` outside the offline sync path
+pub(crate) fn check_status_update(
+    shipment: &Shipment,
+    caller: Principal,
+    can_update_any: bool,
+    new_status: &ShipmentStatus,
+) -> Result<(), String> {
+    // Verify authorization
+    if shipment.sender_id != caller
+        && shipment.driver_id != Some(caller)
+        && !stores::can_manage_shipment(shipment, caller)
+        && !can_update_any
+    {
+        permissions::require(&caller, Permission::UpdateAnyShipment)?;
+    }
+
+    // Routed shipments move with their legs
+    if shipment.legs.is_some() && !can_update_any {
+        return Err("Shipment is routed through hubs; update its legs".to_string());
+    }
+
+    // Confirmation and disputes are settled through their own endpoints
+    if matches!(shipment.status, ShipmentStatus::AwaitingConfirmation | ShipmentStatus::Disputed) && !can_update_any {
+        return Err("Shipment is awaiting recipient confirmation".to_string());
+    }
+
+    if !shipment.status.can_transition_to(new_status) {
+        return Err(format!("Invalid transition from {:?} to {:?}", shipment.status, new_status));
+    }
+    // Once picked up the parcel is on its way; only support can cancel it then
+    if matches!(new_status, ShipmentStatus::Cancelled)
+        && !can_update_any
+        && !matches!(shipment.status, ShipmentStatus::Created | ShipmentStatus::PickupScheduled)
+    {
+        return Err("Shipments can only be cancelled before pickup".to_string());
+    }
+    // Senders cannot deliver their own parcels
+    if matches!(new_status, ShipmentStatus::Delivered | ShipmentStatus::AwaitingConfirmation)
+        && shipment.driver_id != Some(caller)
+        && !can_update_any
+    {
+        return Err("Only the assigned driver can mark a shipment delivered".to_string());
+    }
+
+    match new_status {
+        ShipmentStatus::PickedUp => handling::check_acknowledged(shipment),
+        ShipmentStatus::Delivered => {
+            cod::check_collected(shipment)?;
+            delivery_preferences::check_proof(shipment)
+        },
+        ShipmentStatus::Failed => Err("Use report_delivery_failure to record why delivery failed".to_string()),
+        _ => Ok(()),
+    }
+}
+
+// Shared by the online and offline status update paths. `timestamp` is when the
+// change happened, which for offline events is the client-recorded time.
+fn apply_status_update(
+    shipment: &mut Shipment,
+    new_status: ShipmentStatus,
+    location: Option<String>,
+    description: String,
+    updated_by: Principal,
+    timestamp: u64,
+) {
+    // Deliveries that need recipient sign-off wait for confirmation first
+    let new_status = if matches!(new_status, ShipmentStatus::Delivered) && shipment.requires_confirmation {
+        ShipmentStatus::AwaitingConfirmation
+    } else {
+        new_status
+    };
+
+    shipment.status = new_status.clone();
+    shipment.touch(time());
+
+    // Add tracking event
+    tracking::record(shipment, TrackingEvent {
+        timestamp,
+        status: new_status,
+        location,
+        description,
+        updated_by,
+        kind: None,
+    });
+
+    // Set actual delivery time if delivered
+    if matches!(shipment.status, ShipmentStatus::Delivered | ShipmentStatus::AwaitingConfirmation) {
+        shipment.actual_delivery = Some(timestamp);
+    }
+    event_store::status_changed(shipment, updated_by);
+
+    // Cancelled shipments give back any credit spent on them
+    if matches!(shipment.status, ShipmentStatus::Cancelled) {
+        credits::release_credits(&shipment.id);
+    }
+    if matches!(shipment.status, ShipmentStatus::Delivered) {
+        fees::accrue(shipment);
+    }
+
+    notifications::notify_parties_localized(
+        shipment,
+        updated_by,
+        NotificationKind::StatusChange,
+        LocalizedText::new("notification.status_changed")
+            .arg("shipment_id", &shipment.id)
+            .with("status", i18n::status_arg(&shipment.status)),
+    );
+    events::publish_status_change(shipment);
+}
+
 // Driver management functions
 #[update]
 fn register_driver(
@@ -333,104 +1342,253 @@ fn register_driver(
     phone: String,
     vehicle_info: VehicleInfo,
 ) -> Result<Driver, String> {
-    let caller = ic_cdk::caller();
-    
-    // Check if driver already exists
-    let driver_exists = DRIVERS.with(|drivers| drivers.borrow().contains_key(&caller));
-    if driver_exists {
-        return Err("Driver already registered".to_string());
-    }
-
-    let driver = Driver {
-        id: caller,
-        name,
-        phone,
-        vehicle_info,
-        current_location: None,
-        is_available: true,
-        rating: 5.0,
-        total_deliveries: 0,
-        joined_at: time(),
-    };
+    metrics::observe("register_driver", || {
+        let caller = ic_cdk::caller();
+        guards::check_rate_limit(caller, RateLimitedAction::RegisterDriver)?;
+        let mut v = Validator::new();
+        v.required("name", &name, validation::MAX_NAME_LEN);
+        v.phone("phone", &phone);
+        v.vehicle("vehicle_info", &vehicle_info);
+        v.finish()?;
 
-    DRIVERS.with(|drivers| {
-        drivers.borrow_mut().insert(caller, driver.clone());
-    });
+        let user = contacts::require_verified_contacts(caller)?;
+        if validation::normalize_phone(&phone) != validation::normalize_phone(&user.phone) {
+            return Err("Driver phone must match the verified account phone".to_string());
+        }
+
+        // Check if driver already exists
+        let driver_exists = DRIVERS.with(|drivers| drivers.borrow().contains_key(&caller));
+        if driver_exists {
+            return Err("Driver already registered".to_string());
+        }
+
+        let driver = Driver {
+            id: caller,
+            name,
+            phone,
+            vehicle_info,
+            current_location: None,
+            is_available: true,
+            rating: 5.0,
+            total_deliveries: 0,
+            joined_at: time(),
+            verification_status: VerificationStatus::Unsubmitted,
+            documents: Vec::new(),
+        };
+
+        DRIVERS.with(|drivers| {
+            drivers.borrow_mut().insert(caller, driver.clone());
+        });
 
-    Ok(driver)
+        Ok(driver)
+    })
+}
+
+// Drivers report their position; entering a pickup or delivery geofence may
+// move their shipments along
+#[update]
+fn update_driver_location(coordinates: Coordinates) -> Result<Vec<geofence::GeofenceTrigger>, String> {
+    metrics::observe("update_driver_location", || {
+        let caller = ic_cdk::caller();
+        let mut v = Validator::new();
+        v.coordinates("coordinates", &coordinates);
+        v.finish()?;
+        DRIVERS.with(|drivers| {
+            let mut drivers = drivers.borrow_mut();
+            let driver = drivers.get_mut(&caller).ok_or_else(|| "Driver not registered".to_string())?;
+            driver.current_location = Some(coordinates.clone());
+            Ok::<_, String>(())
+        })?;
+        live_location::record_report(caller, &coordinates, time());
+        Ok(geofence::on_location(caller, &coordinates))
+    })
 }
 
+// Verified drivers on shift at `at`, e.g. the pickup window; defaults to now.
+// Locations are left out for anyone but dispatch.
 #[query]
-fn get_available_drivers() -> Vec<Driver> {
+fn get_available_drivers(at: Option<u64>) -> Vec<Driver> {
+    let at = at.unwrap_or_else(time);
+    let dispatch = permissions::has(&ic_cdk::caller(), Permission::AssignDriver);
     DRIVERS.with(|drivers| {
         drivers
             .borrow()
             .values()
-            .filter(|d| d.is_available)
-            .cloned()
+            .filter(|d| matches!(d.verification_status, VerificationStatus::Verified))
+            .filter(|d| accounts::is_active(&d.id))
+            .filter(|d| shifts::is_on_shift(&d.id, at))
+            .map(|d| Driver {
+                is_available: true,
+                current_location: d.current_location.clone().filter(|_| dispatch),
+                ..d.clone()
+            })
             .collect()
     })
 }
 
+// Version 1 interface, kept for existing clients; see v2_assign_driver_to_shipment
 #[update]
 fn assign_driver_to_shipment(shipment_id: String, driver_id: Principal) -> Result<Shipment, String> {
-    let caller = ic_cdk::caller();
-    
-    // Verify caller is admin or the driver themselves
-    let user = USERS.with(|users| users.borrow().get(&caller).cloned());
-    let is_authorized = match user {
-        Some(u) => matches!(u.user_type, UserType::Admin) || caller == driver_id,
-        None => false,
-    };
+    metrics::observe("assign_driver_to_shipment", || {
+        Ok(request_assignment(ic_cdk::caller(), &shipment_id, driver_id, None)?)
+    })
+}
 
-    if !is_authorized {
-        return Err("Unauthorized to assign driver".to_string());
+// Dispatchers may assign anyone; drivers only themselves
+pub(crate) fn request_assignment(
+    caller: Principal,
+    shipment_id: &str,
+    driver_id: Principal,
+    expected_version: Option<u64>,
+) -> Result<Shipment, ShippingError> {
+    if caller != driver_id {
+        permissions::require(&caller, Permission::AssignDriver)?;
+    } else if !USERS.with(|users| users.borrow().contains_key(&caller)) {
+        return Err("User not registered".to_string().into());
+    } else {
+        suspensions::check(caller, BlockedAction::AcceptDelivery)?;
     }
+    SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .get(shipment_id)
+            .map_or(Ok(()), |s| s.check_version(expected_version))
+    })?;
+    assign_driver(shipment_id, driver_id, caller)
+}
+
+// Assign a driver on behalf of `caller`; shared by direct assignment and accepted offers
+fn assign_driver(shipment_id: &str, driver_id: Principal, caller: Principal) -> Result<Shipment, ShippingError> {
+    let driver = assignable_driver(&driver_id)?;
 
     SHIPMENTS.with(|shipments| {
         let mut shipments_map = shipments.borrow_mut();
-        match shipments_map.get_mut(&shipment_id) {
+        if let Some(shipment) = shipments_map.get(shipment_id) {
+            capacity::check_capacity(&driver, &shipment.package_details, shipment_id, shipments_map.values())?;
+            cod::check_assignment(shipment, &driver_id)?;
+            vehicles::check_vehicle(&driver, shipment)?;
+        }
+        match shipments_map.get_mut(shipment_id) {
+            Some(shipment) if shipment.legs.is_some() => {
+                Err("Shipment is routed through hubs; assign drivers to its legs".to_string().into())
+            },
             Some(shipment) => {
                 shipment.driver_id = Some(driver_id);
                 shipment.status = ShipmentStatus::PickupScheduled;
-                shipment.updated_at = time();
-                
-                shipment.tracking_history.push(TrackingEvent {
+                shipment.touch(time());
+
+                tracking::record(shipment, TrackingEvent {
                     timestamp: time(),
                     status: ShipmentStatus::PickupScheduled,
                     location: None,
                     description: "Driver assigned and pickup scheduled".to_string(),
                     updated_by: caller,
+                    kind: None,
                 });
+                event_store::driver_assigned(shipment, caller);
+
+                notifications::notify_parties(
+                    shipment,
+                    caller,
+                    NotificationKind::Assignment,
+                    format!("Driver assigned to shipment {}", shipment.id),
+                );
+                events::publish(shipment, ShipmentEventKind::DriverAssigned);
+                offers::record_assignment(driver_id, time());
 
                 Ok(shipment.clone())
             },
-            None => Err("Shipment not found".to_string()),
+            None => Err("Shipment not found".to_string().into()),
         }
     })
 }
 
+// Only active drivers who passed verification may receive packages
+fn assignable_driver(driver_id: &Principal) -> Result<Driver, String> {
+    let driver = DRIVERS.with(|drivers| drivers.borrow().get(driver_id).cloned());
+    match driver {
+        Some(_) if !accounts::is_active(driver_id) => Err("Driver account is deactivated".to_string()),
+        Some(_) if suspensions::is_suspended(driver_id) => Err("Driver is suspended".to_string()),
+        Some(d) if matches!(d.verification_status, VerificationStatus::Verified) => Ok(d),
+        Some(_) => Err("Driver is not verified".to_string()),
+        None => Err("Driver not registered".to_string()),
+    }
+}
+
 // Return management functions
 #[update]
-fn create_return_request(shipment_id: String, reason: String) -> Result<ReturnRequest, String> {
-    let caller = ic_cdk::caller();
-    
+fn create_return_request(
+    shipment_id: String,
+    reason: String,
+    idempotency_key: Option<String>,
+    tracking_token: Option<String>,
+) -> Result<ReturnRequest, String> {
+    metrics::observe("create_return_request", || {
+        let caller = ic_cdk::caller();
+        if let Some(return_request) = idempotency::begin(caller, idempotency_key.as_deref(), "create_return_request")? {
+            return Ok(return_request);
+        }
+        let result = open_return_request(caller, shipment_id, reason, tracking_token.as_deref());
+        idempotency::finish(caller, idempotency_key.as_deref(), &result);
+        result
+    })
+}
+
+// The sender, the linked recipient or a tracking-token holder may ask for a
+// return, within the store's return window after delivery
+fn open_return_request(
+    caller: Principal,
+    shipment_id: String,
+    reason: String,
+    tracking_token: Option<&str>,
+) -> Result<ReturnRequest, String> {
+    guards::check_rate_limit(caller, RateLimitedAction::CreateReturnRequest)?;
+    let mut v = Validator::new();
+    v.max_len("reason", &reason, validation::MAX_TEXT_LEN);
+    v.finish()?;
     // Verify shipment exists and caller is authorized
     let shipment = SHIPMENTS.with(|shipments| {
         shipments.borrow().get(&shipment_id).cloned()
     });
 
-    match shipment {
+    let (initiator, approver) = match shipment {
         Some(s) => {
-            if s.sender_id != caller {
+            let initiator = if s.sender_id == caller {
+                ReturnInitiator::Sender
+            } else if is_recipient(&s, &caller, tracking_token) {
+                ReturnInitiator::Recipient
+            } else {
                 return Err("Unauthorized to request return".to_string());
-            }
+            };
             if !matches!(s.status, ShipmentStatus::Delivered) {
                 return Err("Can only return delivered shipments".to_string());
             }
+
+            let store = s.store_id.as_deref().and_then(stores::find);
+            let window_days = store.as_ref().map_or(stores::DEFAULT_RETURN_WINDOW_DAYS, |st| st.return_window_days);
+            let delivered_at = s.actual_delivery.unwrap_or(s.updated_at);
+            if time().saturating_sub(delivered_at) > window_days as u64 * 24 * 60 * 60 * 1_000_000_000 {
+                return Err(format!("Returns must be requested within {} days of delivery", window_days));
+            }
+            let already_open = RETURN_REQUESTS.with(|returns| {
+                returns.borrow().values().any(|r| {
+                    r.shipment_id == shipment_id
+                        && matches!(r.status, ReturnStatus::Requested | ReturnStatus::Approved | ReturnStatus::InProgress)
+                })
+            });
+            if already_open {
+                return Err("A return is already open for this shipment".to_string());
+            }
+
+            let approver = match (store, &initiator) {
+                (Some(store), _) => Some(store.owner),
+                (None, ReturnInitiator::Recipient) => Some(s.sender_id),
+                (None, ReturnInitiator::Sender) => None,
+            };
+            (initiator, approver)
         },
         None => return Err("Shipment not found".to_string()),
-    }
+    };
 
     let return_id = RETURN_COUNTER.with(|counter| {
         let mut c = counter.borrow_mut();
@@ -442,8 +1600,11 @@ fn create_return_request(shipment_id: String, reason: String) -> Result<ReturnRe
         id: return_id.clone(),
         shipment_id,
         requester_id: caller,
+        initiator,
         reason,
         status: ReturnStatus::Requested,
+        approver,
+        review_note: None,
         created_at: time(),
         processed_at: None,
     };
@@ -451,10 +1612,58 @@ fn create_return_request(shipment_id: String, reason: String) -> Result<ReturnRe
     RETURN_REQUESTS.with(|returns| {
         returns.borrow_mut().insert(return_id, return_request.clone());
     });
+    if let Some(approver) = approver {
+        notifications::notify(
+            approver,
+            NotificationKind::System,
+            Some(&return_request.shipment_id),
+            format!("Return {} requested for shipment {}", return_request.id, return_request.shipment_id),
+        );
+    }
 
     Ok(return_request)
 }
 
+#[update]
+fn review_return_request(return_id: String, approve: bool, note: Option<String>) -> Result<ReturnRequest, String> {
+    metrics::observe("review_return_request", || {
+        let caller = ic_cdk::caller();
+        if let Some(note) = &note {
+            let mut v = Validator::new();
+            v.max_len("note", note, validation::MAX_TEXT_LEN);
+            v.finish()?;
+        }
+
+        let return_request = RETURN_REQUESTS.with(|returns| {
+            let mut returns_map = returns.borrow_mut();
+            let request = returns_map
+                .get_mut(&return_id)
+                .ok_or_else(|| "Return request not found".to_string())?;
+            if request.approver != Some(caller) && !permissions::has(&caller, Permission::ResolveDisputes) {
+                return Err("Unauthorized to review return request".to_string());
+            }
+            if !matches!(request.status, ReturnStatus::Requested) {
+                return Err("Return request already reviewed".to_string());
+            }
+            request.status = if approve { ReturnStatus::Approved } else { ReturnStatus::Rejected };
+            request.review_note = note;
+            request.processed_at = Some(time());
+            Ok(request.clone())
+        })?;
+
+        // Token holders may be anonymous and have no inbox of their own
+        if return_request.requester_id != Principal::anonymous() {
+            notifications::notify(
+                return_request.requester_id,
+                NotificationKind::System,
+                Some(&return_request.shipment_id),
+                format!("Return {} was {:?}", return_request.id, return_request.status),
+            );
+        }
+        Ok(return_request)
+    })
+}
+
 #[query]
 fn get_return_requests() -> Vec<ReturnRequest> {
     let caller = ic_cdk::caller();
@@ -462,25 +1671,95 @@ fn get_return_requests() -> Vec<ReturnRequest> {
         returns
             .borrow()
             .values()
-            .filter(|r| r.requester_id == caller)
+            .filter(|r| r.requester_id == caller || r.approver == Some(caller))
             .cloned()
             .collect()
     })
 }
 
 // Utility functions
-fn calculate_shipping_cost(
+fn is_controller(principal: &Principal) -> bool {
+    let bytes = principal.as_slice();
+    unsafe { ic0::is_controller(bytes.as_ptr() as i32, bytes.len() as i32) == 1 }
+}
+
+fn is_recipient(shipment: &Shipment, caller: &Principal, tracking_token: Option<&str>) -> bool {
+    if shipment.recipient_id == Some(*caller) {
+        return true;
+    }
+    match tracking_token {
+        Some(token) => TRACKING_TOKENS.with(|tokens| {
+            tokens.borrow().get(token).map(|id| *id == shipment.id).unwrap_or(false)
+        }),
+        None => false,
+    }
+}
+
+// Whether the parcel was handed over by its driver, confirmed by its linked recipient
+// or collected with the recipient's code at a pickup point. Deliveries recorded by the
+// sender or by support alone earn no rewards.
+pub(crate) fn carrier_delivered(shipment: &Shipment) -> bool {
+    tracking::with_events(shipment, |events| {
+        let mut previous = None;
+        for event in events {
+            let by = event.updated_by;
+            if by != shipment.sender_id {
+                let handed_over = shipment.driver_id == Some(by)
+                    && matches!(event.status, ShipmentStatus::AwaitingConfirmation | ShipmentStatus::Delivered);
+                let confirmed = matches!(event.status, ShipmentStatus::Delivered)
+                    && (shipment.recipient_id == Some(by) || previous == Some(&ShipmentStatus::AtPickupPoint));
+                if handed_over || confirmed {
+                    return true;
+                }
+            }
+            previous = Some(&event.status);
+        }
+        false
+    })
+}
+
+fn seeded_digest(seed: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(seed.as_bytes());
+    hasher.update(time().to_be_bytes());
+    if let Some(entropy) = ids::entropy() {
+        hasher.update(entropy);
+    }
+    hasher.update(ic_cdk::api::canister_balance128().to_be_bytes());
+    hasher.finalize().into()
+}
+
+// Derive a 6-digit one-time code from the given seed and the current time
+fn generate_otp(seed: &str) -> String {
+    let digest = seeded_digest(seed);
+    let n = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+    format!("{:06}", n % 1_000_000)
+}
+
+// Derive an opaque 32-character hex token from the given seed and the current time
+fn generate_token(seed: &str) -> String {
+    seeded_digest(seed)[..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn calculate_shipping_charges(
     _pickup: &Address,
     _delivery: &Address,
     package: &PackageDetails,
-) -> f64 {
+) -> Vec<CostLineItem> {
     // Simple cost calculation based on weight and value
-    let base_cost = 10.0;
-    let weight_cost = package.weight * 2.0;
-    let value_cost = package.value * 0.01;
-    let fragile_cost = if package.fragile { 5.0 } else { 0.0 };
-    
-    base_cost + weight_cost + value_cost + fragile_cost
+    let settings = settings::current();
+    let weight_grams = (package.weight.max(0.0) * 1000.0).round() as u128;
+    let mut charges = vec![
+        CostLineItem { label: "Base".to_string(), amount: settings.base_price },
+        CostLineItem { label: "Weight".to_string(), amount: settings.price_per_kg.mul_ratio(weight_grams, 1000) },
+        CostLineItem {
+            label: "Declared value".to_string(),
+            amount: package.declared_value().mul_ratio(settings.declared_value_bps as u128, 10_000),
+        },
+    ];
+    charges.extend(handling::surcharges(package));
+
+    charges
 }
 
 // Analytics and reporting functions
@@ -490,7 +1769,7 @@ fn get_platform_stats() -> PlatformStats {
     let total_shipments = SHIPMENTS.with(|shipments| shipments.borrow().len() as u32);
     let total_drivers = DRIVERS.with(|drivers| drivers.borrow().len() as u32);
     
-    let (delivered_shipments, pending_shipments) = SHIPMENTS.with(|shipments| {
+    let (delivered_shipments, pending_shipments, service_levels) = SHIPMENTS.with(|shipments| {
         let shipments_map = shipments.borrow();
         let delivered = shipments_map
             .values()
@@ -500,8 +1779,10 @@ fn get_platform_stats() -> PlatformStats {
             .values()
             .filter(|s| !matches!(s.status, ShipmentStatus::Delivered | ShipmentStatus::Cancelled))
             .count() as u32;
-        (delivered, pending)
+        let all: Vec<&Shipment> = shipments_map.values().collect();
+        (delivered, pending, service_level::performance(&all))
     });
+    let (tips_paid, tip_volume) = earnings::tip_totals();
 
     PlatformStats {
         total_users,
@@ -509,6 +1790,9 @@ fn get_platform_stats() -> PlatformStats {
         total_drivers,
         delivered_shipments,
         pending_shipments,
+        service_levels,
+        tips_paid,
+        tip_volume,
     }
 }
 
@@ -519,6 +1803,10 @@ pub struct PlatformStats {
     pub total_drivers: u32,
     pub delivered_shipments: u32,
     pub pending_shipments: u32,
+    pub service_levels: Vec<ServiceLevelPerformance>,
+    // Driver tips, kept apart from shipment revenue; one amount per currency
+    pub tips_paid: u32,
+    pub tip_volume: Vec<Money>,
 }
 
 // Export candid interface
@@ -0,0 +1,294 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::custody;
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::validation::Validator;
+use crate::{Coordinates, Shipment, ShipmentStatus, DRIVERS, SHIPMENTS};
+
+const GEOHASH_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+// Cells of roughly 5 x 5 km
+const COARSE_GEOHASH_LEN: usize = 5;
+// Driver index cells, about 4.9 km on a side at the equator
+const INDEX_GEOHASH_LEN: usize = 5;
+// Cell height at INDEX_GEOHASH_LEN, in degrees; width is the same
+const INDEX_CELL_DEGREES: f64 = 180.0 / 4096.0;
+const KM_PER_DEGREE: f64 = 111.32;
+const MAX_NEARBY_RADIUS_KM: f64 = 100.0;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum LocationPrecision {
+    Precise,
+    // The centre of the geohash cell the driver is in
+    Coarse,
+    Hidden,
+}
+
+// Customers only ever see a driver while the driver is collecting or carrying
+// their shipment
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LocationSettings {
+    pub customer_precision: LocationPrecision,
+}
+
+impl Default for LocationSettings {
+    fn default() -> Self {
+        LocationSettings {
+            customer_precision: LocationPrecision::Coarse,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LiveLocation {
+    pub shipment_id: String,
+    pub precision: LocationPrecision,
+    pub coordinates: Coordinates,
+    pub geohash: Option<String>,
+    pub reported_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct NearbyDriver {
+    pub driver_id: Principal,
+    pub name: String,
+    pub coordinates: Coordinates,
+    pub distance_km: f64,
+    pub reported_at: Option<u64>,
+}
+
+thread_local! {
+    static LOCATION_SETTINGS: RefCell<HashMap<Principal, LocationSettings>> = RefCell::new(HashMap::new());
+    static REPORTED_AT: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+    // Geohash cell -> drivers last seen in it, and each driver's cell
+    static CELLS: RefCell<HashMap<String, BTreeSet<Principal>>> = RefCell::new(HashMap::new());
+    static DRIVER_CELLS: RefCell<HashMap<Principal, String>> = RefCell::new(HashMap::new());
+}
+
+#[update]
+fn set_location_settings(settings: LocationSettings) -> Result<LocationSettings, String> {
+    metrics::observe("set_location_settings", || {
+        let caller = ic_cdk::caller();
+        if !DRIVERS.with(|drivers| drivers.borrow().contains_key(&caller)) {
+            return Err("Driver not registered".to_string());
+        }
+        LOCATION_SETTINGS.with(|s| s.borrow_mut().insert(caller, settings.clone()));
+        Ok(settings)
+    })
+}
+
+#[query]
+fn get_location_settings() -> LocationSettings {
+    settings_of(&ic_cdk::caller())
+}
+
+// Where the driver carrying the shipment is. Dispatch sees the precise position;
+// the shipment's sender and recipient see what the driver's settings allow
+// while the shipment is under way.
+#[query]
+fn get_shipment_live_location(shipment_id: String) -> Result<LiveLocation, String> {
+    let caller = ic_cdk::caller();
+    let shipment = SHIPMENTS
+        .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    let dispatch = permissions::has(&caller, Permission::AssignDriver);
+    let customer = shipment.sender_id == caller || shipment.recipient_id == Some(caller);
+    if !dispatch && !customer {
+        return Err("Unauthorized to view this shipment's location".to_string());
+    }
+
+    let unavailable = || "Live location is not available".to_string();
+    let driver_id = custody::custodian(&shipment).ok_or_else(unavailable)?;
+    let location = DRIVERS
+        .with(|drivers| drivers.borrow().get(&driver_id).and_then(|d| d.current_location.clone()))
+        .ok_or_else(unavailable)?;
+    let precision = if dispatch {
+        LocationPrecision::Precise
+    } else if on_delivery(&shipment) {
+        settings_of(&driver_id).customer_precision
+    } else {
+        LocationPrecision::Hidden
+    };
+
+    let reported_at = last_report(&driver_id);
+    match precision {
+        LocationPrecision::Hidden => Err(unavailable()),
+        LocationPrecision::Precise => Ok(LiveLocation {
+            shipment_id,
+            precision,
+            coordinates: location,
+            geohash: None,
+            reported_at,
+        }),
+        LocationPrecision::Coarse => {
+            let (geohash, centre) = geohash(&location, COARSE_GEOHASH_LEN);
+            Ok(LiveLocation {
+                shipment_id,
+                precision,
+                coordinates: centre,
+                geohash: Some(geohash),
+                reported_at,
+            })
+        },
+    }
+}
+
+// Drivers with a known location within `radius_km`, nearest first. Reads only the
+// index cells overlapping the radius.
+#[query]
+fn get_drivers_near(coordinates: Coordinates, radius_km: f64) -> Result<Vec<NearbyDriver>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::AssignDriver)?;
+    let mut v = Validator::new();
+    v.coordinates("coordinates", &coordinates);
+    v.finish()?;
+    if !(radius_km > 0.0 && radius_km <= MAX_NEARBY_RADIUS_KM) {
+        return Err(format!("Radius must be between 0 and {} km", MAX_NEARBY_RADIUS_KM));
+    }
+
+    let candidates: BTreeSet<Principal> = CELLS.with(|cells| {
+        let cells = cells.borrow();
+        cells_within(&coordinates, radius_km)
+            .iter()
+            .filter_map(|cell| cells.get(cell))
+            .flatten()
+            .copied()
+            .collect()
+    });
+    let mut nearby: Vec<NearbyDriver> = DRIVERS.with(|drivers| {
+        let drivers = drivers.borrow();
+        candidates
+            .into_iter()
+            .filter_map(|id| drivers.get(&id))
+            .filter_map(|d| {
+                let at = d.current_location.clone()?;
+                let distance_km = at.distance_km(&coordinates);
+                (distance_km <= radius_km).then(|| NearbyDriver {
+                    driver_id: d.id,
+                    name: d.name.clone(),
+                    coordinates: at,
+                    distance_km,
+                    reported_at: last_report(&d.id),
+                })
+            })
+            .collect()
+    });
+    nearby.sort_by(|a, b| a.distance_km.total_cmp(&b.distance_km).then_with(|| a.driver_id.cmp(&b.driver_id)));
+    Ok(nearby)
+}
+
+// `reported_at` is when the device took the fix, which for batched pings can be
+// a little before the call
+pub(crate) fn record_report(driver_id: Principal, at: &Coordinates, reported_at: u64) {
+    REPORTED_AT.with(|r| r.borrow_mut().insert(driver_id, reported_at));
+    index(driver_id, Some(at));
+}
+
+// Move the driver to the cell of `at`, or out of the index
+pub(crate) fn index(driver_id: Principal, at: Option<&Coordinates>) {
+    let cell = at.map(|at| geohash(at, INDEX_GEOHASH_LEN).0);
+    let previous = DRIVER_CELLS.with(|c| match &cell {
+        Some(cell) => c.borrow_mut().insert(driver_id, cell.clone()),
+        None => c.borrow_mut().remove(&driver_id),
+    });
+    if previous == cell {
+        return;
+    }
+    CELLS.with(|cells| {
+        let mut cells = cells.borrow_mut();
+        if let Some(previous) = previous {
+            if let Some(drivers) = cells.get_mut(&previous) {
+                drivers.remove(&driver_id);
+                if drivers.is_empty() {
+                    cells.remove(&previous);
+                }
+            }
+        }
+        if let Some(cell) = cell {
+            cells.entry(cell).or_default().insert(driver_id);
+        }
+    });
+}
+
+pub(crate) fn last_report(driver_id: &Principal) -> Option<u64> {
+    REPORTED_AT.with(|r| r.borrow().get(driver_id).copied())
+}
+
+// Index cells overlapping the box around the circle, found by stepping across the
+// box one cell at a time
+fn cells_within(centre: &Coordinates, radius_km: f64) -> BTreeSet<String> {
+    let lat_span = radius_km / KM_PER_DEGREE;
+    let lat_min = (centre.latitude - lat_span).max(-90.0);
+    let lat_max = (centre.latitude + lat_span).min(90.0);
+    // Widest at the latitude nearest a pole
+    let widest = lat_min.abs().max(lat_max.abs()).to_radians().cos();
+    let lon_span = if widest > 0.0 { radius_km / (KM_PER_DEGREE * widest) } else { 180.0 };
+    let (lon_min, lon_max) = if lon_span >= 180.0 {
+        (-180.0, 180.0)
+    } else {
+        (centre.longitude - lon_span, centre.longitude + lon_span)
+    };
+
+    let steps = |min: f64, max: f64| {
+        let count = ((max - min) / INDEX_CELL_DEGREES).ceil() as usize;
+        (0..=count).map(move |i| (min + i as f64 * INDEX_CELL_DEGREES).min(max))
+    };
+    let mut cells = BTreeSet::new();
+    for latitude in steps(lat_min, lat_max) {
+        for longitude in steps(lon_min, lon_max) {
+            // Wrap across the antimeridian
+            let longitude = (longitude + 180.0).rem_euclid(360.0) - 180.0;
+            cells.insert(geohash(&Coordinates { latitude, longitude }, INDEX_GEOHASH_LEN).0);
+        }
+    }
+    cells
+}
+
+fn settings_of(driver_id: &Principal) -> LocationSettings {
+    LOCATION_SETTINGS.with(|s| s.borrow().get(driver_id).cloned().unwrap_or_default())
+}
+
+fn on_delivery(shipment: &Shipment) -> bool {
+    matches!(
+        shipment.status,
+        ShipmentStatus::PickupScheduled
+            | ShipmentStatus::PickedUp
+            | ShipmentStatus::InTransit
+            | ShipmentStatus::OutForDelivery
+    )
+}
+
+// Standard geohash of `len` characters and the centre of its cell
+fn geohash(at: &Coordinates, len: usize) -> (String, Coordinates) {
+    let (mut lat, mut lon) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(len);
+    let mut even = true;
+    while hash.len() < len {
+        let mut index = 0;
+        for _ in 0..5 {
+            let (range, value) = if even {
+                (&mut lon, at.longitude)
+            } else {
+                (&mut lat, at.latitude)
+            };
+            let mid = (range.0 + range.1) / 2.0;
+            index <<= 1;
+            if value >= mid {
+                index |= 1;
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even = !even;
+        }
+        hash.push(GEOHASH_ALPHABET[index] as char);
+    }
+    let centre = Coordinates {
+        latitude: (lat.0 + lat.1) / 2.0,
+        longitude: (lon.0 + lon.1) / 2.0,
+    };
+    (hash, centre)
+}
@@ -0,0 +1,269 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::errors::ShippingError;
+use crate::event_store;
+use crate::events::{self, ShipmentEventKind};
+use crate::heartbeat;
+use crate::live_location;
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::offers;
+use crate::permissions::{self, Permission};
+use crate::tracking;
+use crate::{
+    accounts, assignable_driver, capacity, cod, shifts, vehicles, Coordinates, Shipment, ShipmentStatus,
+    TrackingEvent, DRIVERS, SHIPMENTS,
+};
+
+const NANOS_PER_MIN: u64 = 60 * 1_000_000_000;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LivenessPolicy {
+    pub enabled: bool,
+    // Drivers on a delivery who report no location for this long count as offline
+    pub stale_after_mins: u64,
+    // Hand stalled shipments to the best available driver without waiting for dispatch
+    pub auto_reassign: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum OfflineReason {
+    NoRecentLocation,
+    // Off shift or marked unavailable
+    Unavailable,
+    AccountInactive,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StalledShipment {
+    pub shipment_id: String,
+    pub driver_id: Principal,
+    pub status: ShipmentStatus,
+    pub reason: OfflineReason,
+    pub last_report_at: Option<u64>,
+    pub last_known_location: Option<Coordinates>,
+    pub detected_at: u64,
+    // Why the latest automatic reassignment did not go through
+    pub reassignment_error: Option<String>,
+}
+
+thread_local! {
+    static POLICY: RefCell<LivenessPolicy> = RefCell::new(LivenessPolicy {
+        enabled: true,
+        stale_after_mins: 15,
+        auto_reassign: false,
+    });
+    // Rebuilt by every check, so shipments whose driver came back drop out
+    static STALLED: RefCell<BTreeMap<String, StalledShipment>> = RefCell::new(BTreeMap::new());
+}
+
+// Admin configuration
+#[update]
+fn set_liveness_policy(policy: LivenessPolicy) -> Result<LivenessPolicy, String> {
+    metrics::observe("set_liveness_policy", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if policy.stale_after_mins == 0 {
+            return Err("Stale threshold must be positive".to_string());
+        }
+        POLICY.with(|p| *p.borrow_mut() = policy.clone());
+        Ok(policy)
+    })
+}
+
+#[query]
+fn get_liveness_policy() -> LivenessPolicy {
+    POLICY.with(|p| p.borrow().clone())
+}
+
+// Oldest detection first
+#[query]
+fn get_stalled_shipments() -> Result<Vec<StalledShipment>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::AssignDriver)?;
+    let mut stalled: Vec<StalledShipment> = STALLED.with(|s| s.borrow().values().cloned().collect());
+    stalled.sort_by(|a, b| a.detected_at.cmp(&b.detected_at).then_with(|| a.shipment_id.cmp(&b.shipment_id)));
+    Ok(stalled)
+}
+
+// Move an active shipment to another driver, keeping its status and history.
+// Without a driver, the best available one other than the current driver is picked.
+#[update]
+fn reassign_shipment_driver(shipment_id: String, driver_id: Option<Principal>) -> Result<Shipment, String> {
+    metrics::observe("reassign_shipment_driver", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::AssignDriver)?;
+        let reason = match STALLED.with(|s| s.borrow().get(&shipment_id).map(|s| s.reason.clone())) {
+            Some(reason) => describe(&reason).to_string(),
+            None => "reassigned by dispatch".to_string(),
+        };
+        let driver_id = driver_id
+            .or_else(|| replacement_for(&shipment_id))
+            .ok_or_else(|| "No driver is available for this shipment".to_string())?;
+        Ok(reassign(&shipment_id, driver_id, caller, &reason)?)
+    })
+}
+
+// Dispatch job: flag shipments whose driver went quiet or unavailable mid-delivery,
+// and reassign them when the policy allows
+pub(crate) fn check_driver_liveness() {
+    let policy = POLICY.with(|p| p.borrow().clone());
+    if !policy.enabled {
+        STALLED.with(|s| s.borrow_mut().clear());
+        return;
+    }
+    let now = time();
+    let cutoff = now.saturating_sub(policy.stale_after_mins.saturating_mul(NANOS_PER_MIN));
+    let active: Vec<(String, Principal, Principal, ShipmentStatus, u64)> = SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .values()
+            .filter(|s| s.legs.is_none() && is_under_way(&s.status))
+            .filter_map(|s| s.driver_id.map(|d| (s.id.clone(), d, s.sender_id, s.status.clone(), s.updated_at)))
+            .collect()
+    });
+
+    let previous = STALLED.with(|s| std::mem::take(&mut *s.borrow_mut()));
+    let mut stalled = BTreeMap::new();
+    for (shipment_id, driver_id, sender_id, status, updated_at) in active {
+        // A heartbeat without a fix still shows the app is running
+        let last_report_at = live_location::last_report(&driver_id).max(heartbeat::last_heartbeat(&driver_id));
+        // Drivers who never reported count from the shipment's last change
+        let reason = if !accounts::is_active(&driver_id) {
+            OfflineReason::AccountInactive
+        } else if !shifts::is_on_shift(&driver_id, now) {
+            OfflineReason::Unavailable
+        } else if last_report_at.unwrap_or(0).max(updated_at) < cutoff {
+            OfflineReason::NoRecentLocation
+        } else {
+            continue;
+        };
+        let known = previous.get(&shipment_id).filter(|s| s.driver_id == driver_id);
+        if known.is_none() {
+            notifications::notify(
+                sender_id,
+                NotificationKind::Assignment,
+                Some(&shipment_id),
+                format!("The driver of shipment {} appears to be offline; we are arranging another", shipment_id),
+            );
+        }
+        let entry = StalledShipment {
+            shipment_id: shipment_id.clone(),
+            driver_id,
+            status,
+            reason,
+            last_report_at,
+            last_known_location: DRIVERS
+                .with(|drivers| drivers.borrow().get(&driver_id).and_then(|d| d.current_location.clone())),
+            detected_at: known.map_or(now, |s| s.detected_at),
+            reassignment_error: None,
+        };
+        stalled.insert(shipment_id, entry);
+    }
+    STALLED.with(|s| *s.borrow_mut() = stalled);
+
+    if policy.auto_reassign {
+        let stalled: Vec<StalledShipment> = STALLED.with(|s| s.borrow().values().cloned().collect());
+        for entry in stalled {
+            let result = replacement_for(&entry.shipment_id)
+                .ok_or_else(|| "No driver is available".to_string())
+                .and_then(|driver_id| {
+                    reassign(&entry.shipment_id, driver_id, ic_cdk::id(), describe(&entry.reason)).map_err(String::from)
+                });
+            if let Err(e) = result {
+                STALLED.with(|s| {
+                    if let Some(entry) = s.borrow_mut().get_mut(&entry.shipment_id) {
+                        entry.reassignment_error = Some(e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn reassign(
+    shipment_id: &str,
+    driver_id: Principal,
+    caller: Principal,
+    reason: &str,
+) -> Result<Shipment, ShippingError> {
+    let driver = assignable_driver(&driver_id)?;
+    let now = time();
+    let (previous, shipment) = SHIPMENTS.with(|shipments| {
+        let mut shipments_map = shipments.borrow_mut();
+        let shipment = shipments_map.get(shipment_id).ok_or_else(|| "Shipment not found".to_string())?;
+        if shipment.legs.is_some() {
+            return Err("Shipment is routed through hubs; reassign its legs".to_string().into());
+        }
+        if !is_under_way(&shipment.status) {
+            return Err("Only shipments under way can be reassigned".to_string().into());
+        }
+        let previous = shipment.driver_id.ok_or_else(|| "Shipment has no driver".to_string())?;
+        if previous == driver_id {
+            return Err("Driver is already assigned to this shipment".to_string().into());
+        }
+        capacity::check_capacity(&driver, &shipment.package_details, shipment_id, shipments_map.values())?;
+        cod::check_assignment(shipment, &driver_id)?;
+        vehicles::check_vehicle(&driver, shipment)?;
+
+        let shipment = shipments_map.get_mut(shipment_id).expect("shipment exists");
+        shipment.driver_id = Some(driver_id);
+        shipment.touch(now);
+        // Same status, so the timeline continues where the previous driver left off
+        tracking::record(shipment, TrackingEvent {
+            timestamp: now,
+            status: shipment.status.clone(),
+            location: None,
+            description: format!("Driver replaced: {}", reason),
+            updated_by: caller,
+            kind: None,
+        });
+        event_store::driver_assigned(shipment, caller);
+        notifications::notify_parties(
+            shipment,
+            caller,
+            NotificationKind::Assignment,
+            format!("Shipment {} has a new driver", shipment.id),
+        );
+        events::publish(shipment, ShipmentEventKind::DriverAssigned);
+        Ok::<_, ShippingError>((previous, shipment.clone()))
+    })?;
+
+    offers::record_assignment(driver_id, now);
+    STALLED.with(|s| s.borrow_mut().remove(shipment_id));
+    notifications::notify(
+        previous,
+        NotificationKind::Assignment,
+        Some(shipment_id),
+        format!("Shipment {} was handed to another driver", shipment_id),
+    );
+    Ok(shipment)
+}
+
+fn replacement_for(shipment_id: &str) -> Option<Principal> {
+    let shipment = SHIPMENTS.with(|shipments| shipments.borrow().get(shipment_id).cloned())?;
+    let current: Vec<Principal> = shipment.driver_id.into_iter().collect();
+    offers::best_driver(&shipment, &current)
+}
+
+fn describe(reason: &OfflineReason) -> &'static str {
+    match reason {
+        OfflineReason::NoRecentLocation => "previous driver stopped reporting their location",
+        OfflineReason::Unavailable => "previous driver became unavailable",
+        OfflineReason::AccountInactive => "previous driver's account was deactivated",
+    }
+}
+
+fn is_under_way(status: &ShipmentStatus) -> bool {
+    matches!(
+        status,
+        ShipmentStatus::PickupScheduled
+            | ShipmentStatus::PickedUp
+            | ShipmentStatus::InTransit
+            | ShipmentStatus::OutForDelivery
+    )
+}
@@ -0,0 +1,474 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::credits::{self, CreditEntry, CreditSource};
+use crate::idempotency;
+use crate::metrics;
+use crate::money::{Money, BASE_CURRENCY};
+use crate::permissions::{self, Permission};
+use crate::validation::MAX_TEXT_LEN;
+use crate::{carrier_delivered, PaymentStatus, Shipment};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_HISTORY: usize = 500;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LoyaltyConfig {
+    pub enabled: bool,
+    // Points earned per whole unit paid for a delivered shipment
+    pub points_per_unit: u32,
+    // Points exchanged for one unit of account credit
+    pub points_per_unit_redeemed: u32,
+    pub min_redemption_points: u64,
+    // Points lapse this many days after they were earned or granted; None keeps them
+    pub expiry_days: Option<u32>,
+}
+
+impl Default for LoyaltyConfig {
+    fn default() -> Self {
+        LoyaltyConfig {
+            enabled: true,
+            points_per_unit: 10,
+            points_per_unit_redeemed: 1_000,
+            min_redemption_points: 1_000,
+            expiry_days: Some(365),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum PointsReason {
+    Shipment(String),
+    // Points exchanged for the named credit
+    Redemption(String),
+    Grant(String),
+    Referral(String),
+    Clawback(String),
+    Expiry,
+}
+
+// `points` is positive for earned and granted points, negative for the rest
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PointsTransaction {
+    pub id: u64,
+    pub reason: PointsReason,
+    pub points: i64,
+    pub created_at: u64,
+    pub granted_by: Option<Principal>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PointsBalance {
+    pub points: u64,
+    // Credit the balance would redeem for right now
+    pub redeemable_value: Money,
+    pub next_expiry: Option<(u64, u64)>,
+}
+
+// Points are earned in lots; spending draws on the lot that expires soonest
+#[derive(Clone, Debug)]
+struct Lot {
+    remaining: u64,
+    expires_at: Option<u64>,
+}
+
+impl Lot {
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|e| e > now)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Account {
+    lots: Vec<Lot>,
+    history: Vec<PointsTransaction>,
+}
+
+thread_local! {
+    static CONFIG: RefCell<LoyaltyConfig> = RefCell::new(LoyaltyConfig::default());
+    static ACCOUNTS: RefCell<BTreeMap<Principal, Account>> = RefCell::new(BTreeMap::new());
+    // Shipments that already earned, with the points they earned
+    static AWARDED: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+    static TRANSACTION_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Admin configuration; rates apply to points earned or redeemed afterwards
+#[update]
+fn set_loyalty_config(config: LoyaltyConfig) -> Result<LoyaltyConfig, String> {
+    metrics::observe("set_loyalty_config", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManageFinances)?;
+        if config.points_per_unit_redeemed == 0 {
+            return Err("Redemption rate must be positive".to_string());
+        }
+        if config.expiry_days == Some(0) {
+            return Err("Expiry must be at least one day".to_string());
+        }
+        CONFIG.with(|c| *c.borrow_mut() = config.clone());
+        Ok(config)
+    })
+}
+
+#[query]
+fn get_loyalty_config() -> LoyaltyConfig {
+    CONFIG.with(|c| c.borrow().clone())
+}
+
+#[query]
+fn get_my_points() -> PointsBalance {
+    balance_of(ic_cdk::caller())
+}
+
+// Newest first
+#[query]
+fn get_my_points_history() -> Vec<PointsTransaction> {
+    let caller = ic_cdk::caller();
+    ACCOUNTS.with(|a| {
+        a.borrow()
+            .get(&caller)
+            .map(|account| account.history.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+// Exchange points for account credit, applied at checkout like any other credit
+#[update]
+fn redeem_points(points: u64, idempotency_key: Option<String>) -> Result<CreditEntry, String> {
+    metrics::observe("redeem_points", || {
+        let caller = ic_cdk::caller();
+        if let Some(credit) = idempotency::begin(caller, idempotency_key.as_deref(), "redeem_points")? {
+            return Ok(credit);
+        }
+        let result = redeem(caller, points);
+        idempotency::finish(caller, idempotency_key.as_deref(), &result);
+        result
+    })
+}
+
+// Admin tooling
+#[update]
+fn grant_points(owner: Principal, points: u64, reason: String) -> Result<PointsBalance, String> {
+    metrics::observe("grant_points", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManageFinances)?;
+        let reason = admin_reason(reason)?;
+        if points == 0 || points > i64::MAX as u64 {
+            return Err("Points must be positive".to_string());
+        }
+        credit_points(owner, points, PointsReason::Grant(reason), Some(caller));
+        Ok(balance_of(owner))
+    })
+}
+
+// Takes back up to `points`; a clawback never drives the balance below zero
+#[update]
+fn clawback_points(owner: Principal, points: u64, reason: String) -> Result<PointsBalance, String> {
+    metrics::observe("clawback_points", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManageFinances)?;
+        let reason = admin_reason(reason)?;
+        let available = balance_of(owner).points;
+        if points == 0 || available == 0 {
+            return Err("No points to claw back".to_string());
+        }
+        debit_points(owner, points.min(available), PointsReason::Clawback(reason), Some(caller));
+        Ok(balance_of(owner))
+    })
+}
+
+#[query]
+fn get_points_history(owner: Principal) -> Result<Vec<PointsTransaction>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ManageFinances)?;
+    Ok(ACCOUNTS.with(|a| {
+        a.borrow()
+            .get(&owner)
+            .map(|account| account.history.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }))
+}
+
+// Called once a shipment is delivered; the sender earns on what they paid, for
+// parcels a driver handed over or the recipient confirmed
+pub(crate) fn award(shipment: &Shipment) {
+    let config = CONFIG.with(|c| c.borrow().clone());
+    if !config.enabled || config.points_per_unit == 0 {
+        return;
+    }
+    // Unpaid shipments earn nothing, however they got delivered
+    if !matches!(shipment.payment_status, PaymentStatus::Paid) || !carrier_delivered(shipment) {
+        return;
+    }
+    if AWARDED.with(|a| a.borrow().contains_key(&shipment.id)) {
+        return;
+    }
+    let units = shipment.price.amount_e8s / Money::from_units(1, BASE_CURRENCY).amount_e8s;
+    let points = u64::try_from(units).unwrap_or(u64::MAX).saturating_mul(config.points_per_unit as u64);
+    let points = points.min(i64::MAX as u64);
+    AWARDED.with(|a| a.borrow_mut().insert(shipment.id.clone(), points));
+    if points > 0 {
+        credit_points(shipment.sender_id, points, PointsReason::Shipment(shipment.id.clone()), None);
+    }
+}
+
+// Take back what a shipment earned once it is cancelled or refunded. Points already
+// spent stay spent; the shipment does not earn again.
+pub(crate) fn revoke(shipment: &Shipment, why: &str) {
+    let earned = AWARDED.with(|a| a.borrow_mut().insert(shipment.id.clone(), 0)).unwrap_or(0);
+    let points = earned.min(balance_of(shipment.sender_id).points);
+    if points > 0 {
+        let reason = PointsReason::Clawback(format!("Shipment {} {}", shipment.id, why));
+        debit_points(shipment.sender_id, points, reason, None);
+    }
+}
+
+// Points awarded by other programs, e.g. referrals
+pub(crate) fn grant(owner: Principal, points: u64, reference: String) {
+    credit_points(owner, points.min(i64::MAX as u64), PointsReason::Referral(reference), None);
+}
+
+// Timer job: drop lapsed lots and record what expired
+pub(crate) fn expire_points() {
+    let now = time();
+    let expired: Vec<(Principal, u64)> = ACCOUNTS.with(|a| {
+        a.borrow_mut()
+            .iter_mut()
+            .filter_map(|(owner, account)| {
+                let lapsed: u64 = account
+                    .lots
+                    .iter()
+                    .filter(|l| !l.is_live(now))
+                    .map(|l| l.remaining)
+                    .sum();
+                account.lots.retain(|l| l.remaining > 0 && l.is_live(now));
+                (lapsed > 0).then_some((*owner, lapsed))
+            })
+            .collect()
+    });
+    for (owner, points) in expired {
+        record(owner, PointsReason::Expiry, -(points as i64), None);
+    }
+}
+
+fn redeem(caller: Principal, points: u64) -> Result<CreditEntry, String> {
+    let config = CONFIG.with(|c| c.borrow().clone());
+    if !config.enabled {
+        return Err("The loyalty program is paused".to_string());
+    }
+    if points < config.min_redemption_points.max(1) {
+        return Err(format!("Redeem at least {} points", config.min_redemption_points.max(1)));
+    }
+    if points > balance_of(caller).points {
+        return Err("Not enough points".to_string());
+    }
+    let value = value_of(points, &config);
+    if value.is_zero() {
+        return Err("Points are worth less than the smallest credit".to_string());
+    }
+    let credit = credits::grant(caller, CreditSource::LoyaltyRedemption, value, None, None);
+    debit_points(caller, points, PointsReason::Redemption(credit.id.clone()), None);
+    Ok(credit)
+}
+
+fn balance_of(owner: Principal) -> PointsBalance {
+    let now = time();
+    let config = CONFIG.with(|c| c.borrow().clone());
+    let live: Vec<Lot> = ACCOUNTS.with(|a| {
+        a.borrow()
+            .get(&owner)
+            .map(|account| {
+                account
+                    .lots
+                    .iter()
+                    .filter(|l| l.is_live(now))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    });
+    let points = live.iter().map(|l| l.remaining).sum();
+    let next_expiry = live
+        .iter()
+        .filter_map(|l| l.expires_at)
+        .min()
+        .map(|at| (at, live.iter().filter(|l| l.expires_at == Some(at)).map(|l| l.remaining).sum()));
+    PointsBalance {
+        points,
+        redeemable_value: value_of(points, &config),
+        next_expiry,
+    }
+}
+
+fn value_of(points: u64, config: &LoyaltyConfig) -> Money {
+    Money::from_units(1, BASE_CURRENCY).mul_ratio(points as u128, config.points_per_unit_redeemed.max(1) as u128)
+}
+
+fn credit_points(owner: Principal, points: u64, reason: PointsReason, granted_by: Option<Principal>) {
+    let now = time();
+    let expires_at = CONFIG.with(|c| c.borrow().expiry_days).map(|d| now + d as u64 * NANOS_PER_DAY);
+    ACCOUNTS.with(|a| {
+        a.borrow_mut().entry(owner).or_default().lots.push(Lot {
+            remaining: points,
+            expires_at,
+        })
+    });
+    record(owner, reason, points as i64, granted_by);
+}
+
+// Spend from the lots that expire soonest; callers check the balance first
+fn debit_points(owner: Principal, points: u64, reason: PointsReason, granted_by: Option<Principal>) {
+    let now = time();
+    ACCOUNTS.with(|a| {
+        let mut accounts = a.borrow_mut();
+        let account = accounts.entry(owner).or_default();
+        account.lots.retain(|l| l.is_live(now));
+        account.lots.sort_by_key(|l| l.expires_at.unwrap_or(u64::MAX));
+        let mut left = points;
+        for lot in account.lots.iter_mut() {
+            let taken = lot.remaining.min(left);
+            lot.remaining -= taken;
+            left -= taken;
+            if left == 0 {
+                break;
+            }
+        }
+        account.lots.retain(|l| l.remaining > 0);
+    });
+    record(owner, reason, -(points as i64), granted_by);
+}
+
+fn record(owner: Principal, reason: PointsReason, points: i64, granted_by: Option<Principal>) {
+    let id = TRANSACTION_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        *c += 1;
+        *c
+    });
+    ACCOUNTS.with(|a| {
+        let mut accounts = a.borrow_mut();
+        let history = &mut accounts.entry(owner).or_default().history;
+        history.push(PointsTransaction {
+            id,
+            reason,
+            points,
+            created_at: time(),
+            granted_by,
+        });
+        if history.len() > MAX_HISTORY {
+            history.remove(0);
+        }
+    });
+}
+
+fn admin_reason(reason: String) -> Result<String, String> {
+    let reason = reason.trim().to_string();
+    if reason.is_empty() || reason.chars().count() > MAX_TEXT_LEN {
+        return Err(format!("Reason must be 1 to {} characters", MAX_TEXT_LEN));
+    }
+    Ok(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, CostBreakdown, Dimensions, PackageDetails, ShipmentStatus, TrackingEvent};
+
+    fn address() -> Address {
+        Address {
+            street: "1 Main St".to_string(),
+            city: "Springfield".to_string(),
+            state: "IL".to_string(),
+            postal_code: "62701".to_string(),
+            country: "US".to_string(),
+            coordinates: None,
+            extras: None,
+        }
+    }
+
+    fn delivered(id: &str, payment_status: PaymentStatus) -> Shipment {
+        let price = Money::from_units(50, BASE_CURRENCY);
+        Shipment {
+            id: id.to_string(),
+            sender_id: Principal::from_slice(&[1]),
+            recipient_name: "Recipient".to_string(),
+            recipient_phone: String::new(),
+            pickup_address: address(),
+            delivery_address: address(),
+            package_details: PackageDetails {
+                description: "Box".to_string(),
+                weight: 1.0,
+                dimensions: Dimensions { length: 10.0, width: 10.0, height: 10.0 },
+                value: 0.0,
+                declared_value: None,
+                handling: None,
+                special_instructions: None,
+            },
+            status: ShipmentStatus::Delivered,
+            driver_id: None,
+            created_at: 0,
+            updated_at: 0,
+            estimated_delivery: None,
+            actual_delivery: Some(0),
+            tracking_history: Vec::new(),
+            payment_status,
+            payment: None,
+            payment_method: None,
+            cost: price.to_decimal(),
+            price,
+            quoted_price: None,
+            exchange_rate: None,
+            cost_breakdown: CostBreakdown {
+                charges: Vec::new(),
+                deductions: Vec::new(),
+                subtotal: price,
+                total: price,
+            },
+            pudo_id: None,
+            recipient_id: None,
+            requires_confirmation: false,
+            metadata: Vec::new(),
+            pickup_zone_id: None,
+            delivery_zone_id: None,
+            store_id: None,
+            service_level: Default::default(),
+            sla_deadline: 0,
+            legs: None,
+            insurance: None,
+            adjustments: None,
+            short_code: None,
+            receipt_token_id: None,
+            recurrence_id: None,
+            version: None,
+            recipient_phone_hash: None,
+            pii_scrubbed_at: None,
+            encrypted_instructions: None,
+        }
+    }
+
+    #[test]
+    fn unpaid_delivered_shipment_earns_no_points() {
+        let shipment = delivered("SH1", PaymentStatus::Pending);
+        award(&shipment);
+        assert!(ACCOUNTS.with(|a| a.borrow().get(&shipment.sender_id).is_none()));
+        // Left unmarked, so the shipment can still earn once paid
+        assert!(!AWARDED.with(|a| a.borrow().contains_key(&shipment.id)));
+    }
+
+    #[test]
+    fn self_delivered_shipment_earns_no_points() {
+        let mut shipment = delivered("SH2", PaymentStatus::Paid);
+        shipment.tracking_history.push(TrackingEvent {
+            timestamp: 0,
+            status: ShipmentStatus::Delivered,
+            location: None,
+            description: "Delivered".to_string(),
+            updated_by: shipment.sender_id,
+            kind: None,
+        });
+        award(&shipment);
+        assert!(ACCOUNTS.with(|a| a.borrow().get(&shipment.sender_id).is_none()));
+        assert!(!AWARDED.with(|a| a.borrow().contains_key(&shipment.id)));
+    }
+}
@@ -0,0 +1,55 @@
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::{DefaultMemoryImpl, Memory};
+use std::cell::RefCell;
+
+pub(crate) type StableMemory = VirtualMemory<DefaultMemoryImpl>;
+
+// Stable memory regions. Ids are permanent: never reuse or renumber them.
+const UPGRADES: MemoryId = MemoryId::new(0);
+pub(crate) const SHIPMENT_ARCHIVE: MemoryId = MemoryId::new(1);
+pub(crate) const SETTINGS: MemoryId = MemoryId::new(2);
+pub(crate) const ATTACHMENT_BLOBS: MemoryId = MemoryId::new(3);
+pub(crate) const SHIPMENT_EVENTS: MemoryId = MemoryId::new(4);
+pub(crate) const OUTBOX: MemoryId = MemoryId::new(5);
+pub(crate) const OUTBOX_INTENTS: MemoryId = MemoryId::new(6);
+pub(crate) const UPDATE_FEED: MemoryId = MemoryId::new(7);
+pub(crate) const SHARD_SHIPMENTS: MemoryId = MemoryId::new(8);
+pub(crate) const SHIPMENT_EVENT_SEQUENCES: MemoryId = MemoryId::new(9);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub(crate) fn get(id: MemoryId) -> StableMemory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+// Heap state snapshot taken in pre_upgrade: a length-prefixed candid blob at the
+// start of the upgrades region
+pub(crate) fn save_upgrade_state<T: CandidType>(state: &T) {
+    let bytes = candid::encode_one(state).expect("failed to encode upgrade state");
+    let mut memory = get(UPGRADES);
+    let mut writer = Writer::new(&mut memory, 0);
+    writer
+        .write(&(bytes.len() as u64).to_le_bytes())
+        .and_then(|_| writer.write(&bytes))
+        .expect("failed to write upgrade state");
+}
+
+pub(crate) fn load_upgrade_state<T>() -> Option<T>
+where
+    T: CandidType + for<'de> Deserialize<'de>,
+{
+    let memory = get(UPGRADES);
+    if memory.size() == 0 {
+        return None;
+    }
+    let mut len = [0u8; 8];
+    memory.read(0, &mut len);
+    let mut bytes = vec![0u8; u64::from_le_bytes(len) as usize];
+    memory.read(len.len() as u64, &mut bytes);
+    candid::decode_one(&bytes).ok()
+}
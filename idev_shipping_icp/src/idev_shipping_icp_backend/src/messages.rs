@@ -0,0 +1,215 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+use crate::confirmation;
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::validation::{Validator, MAX_TEXT_LEN};
+use crate::{Shipment, ShipmentStatus, SHIPMENTS};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_MESSAGES_PER_THREAD: usize = 500;
+// Per sender and thread
+const BURST_WINDOW_NANOS: u64 = 10 * 60 * 1_000_000_000;
+const MAX_MESSAGES_PER_BURST: usize = 20;
+const PREVIEW_CHARS: usize = 80;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ChatRole {
+    Sender,
+    Recipient,
+    Driver,
+    // Staff joining a disputed shipment's thread
+    Support,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Message {
+    pub id: u64,
+    pub shipment_id: String,
+    pub author: Principal,
+    pub role: ChatRole,
+    pub text: String,
+    pub sent_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ChatPolicy {
+    // Threads of finished shipments are deleted this long after their last message
+    pub retention_days: u64,
+}
+
+thread_local! {
+    static THREADS: RefCell<HashMap<String, VecDeque<Message>>> = RefCell::new(HashMap::new());
+    static MESSAGE_COUNTER: RefCell<u64> = RefCell::new(0);
+    static CHAT_POLICY: RefCell<ChatPolicy> = RefCell::new(ChatPolicy { retention_days: 90 });
+}
+
+// Parties chat until the shipment is finished; disputed shipments stay open
+// to the parties and to support staff
+#[update]
+fn send_message(shipment_id: String, text: String) -> Result<Message, String> {
+    metrics::observe("send_message", || {
+        let caller = ic_cdk::caller();
+        let shipment = SHIPMENTS
+            .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        let role = role_of(&shipment, caller).ok_or_else(|| "Unauthorized to message on this shipment".to_string())?;
+        if finished(&shipment.status) {
+            return Err("Shipment is finished; its thread is read-only".to_string());
+        }
+        let text = text.trim().to_string();
+        if text.is_empty() {
+            return Err("Message cannot be empty".to_string());
+        }
+        let mut v = Validator::new();
+        v.max_len("text", &text, MAX_TEXT_LEN);
+        v.finish()?;
+
+        let now = time();
+        let (total, recent) = THREADS.with(|threads| {
+            threads.borrow().get(&shipment_id).map_or((0, 0), |thread| {
+                let recent = thread
+                    .iter()
+                    .filter(|m| m.author == caller && now.saturating_sub(m.sent_at) < BURST_WINDOW_NANOS)
+                    .count();
+                (thread.len(), recent)
+            })
+        });
+        if total >= MAX_MESSAGES_PER_THREAD {
+            return Err(format!("Thread is limited to {} messages", MAX_MESSAGES_PER_THREAD));
+        }
+        if recent >= MAX_MESSAGES_PER_BURST {
+            return Err("Too many messages; try again in a few minutes".to_string());
+        }
+
+        let id = MESSAGE_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            *c
+        });
+        let message = Message {
+            id,
+            shipment_id: shipment_id.clone(),
+            author: caller,
+            role,
+            text,
+            sent_at: now,
+        };
+        THREADS.with(|threads| {
+            threads
+                .borrow_mut()
+                .entry(shipment_id.clone())
+                .or_default()
+                .push_back(message.clone())
+        });
+
+        let preview: String = message.text.chars().take(PREVIEW_CHARS).collect();
+        notifications::notify_parties(
+            &shipment,
+            caller,
+            NotificationKind::Message,
+            format!("New message on shipment {}: {}", shipment_id, preview),
+        );
+        Ok(message)
+    })
+}
+
+// Messages with an id greater than `since`, oldest first
+#[query]
+fn get_messages(shipment_id: String, since: Option<u64>) -> Result<Vec<Message>, String> {
+    let caller = ic_cdk::caller();
+    let shipment = SHIPMENTS
+        .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    if role_of(&shipment, caller).is_none() {
+        return Err("Unauthorized to view messages on this shipment".to_string());
+    }
+    let since = since.unwrap_or(0);
+    Ok(THREADS.with(|threads| {
+        threads
+            .borrow()
+            .get(&shipment_id)
+            .map(|thread| thread.iter().filter(|m| m.id > since).cloned().collect())
+            .unwrap_or_default()
+    }))
+}
+
+#[update]
+fn set_chat_policy(policy: ChatPolicy) -> Result<ChatPolicy, String> {
+    metrics::observe("set_chat_policy", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if policy.retention_days == 0 {
+            return Err("Retention must be at least one day".to_string());
+        }
+        CHAT_POLICY.with(|p| *p.borrow_mut() = policy.clone());
+        Ok(policy)
+    })
+}
+
+#[query]
+fn get_chat_policy() -> ChatPolicy {
+    CHAT_POLICY.with(|p| p.borrow().clone())
+}
+
+// Timer job: drop threads of finished or archived shipments past retention
+pub(crate) fn prune_threads() {
+    let now = time();
+    let retention = CHAT_POLICY.with(|p| p.borrow().retention_days) * NANOS_PER_DAY;
+    let expired: Vec<String> = THREADS.with(|threads| {
+        threads
+            .borrow()
+            .iter()
+            .filter(|(_, thread)| thread.back().is_none_or(|m| now.saturating_sub(m.sent_at) > retention))
+            .map(|(id, _)| id.clone())
+            .collect()
+    });
+    let expired: Vec<String> = SHIPMENTS.with(|shipments| {
+        let shipments = shipments.borrow();
+        expired
+            .into_iter()
+            .filter(|id| shipments.get(id).is_none_or(|s| finished(&s.status)))
+            .collect()
+    });
+    THREADS.with(|threads| {
+        let mut threads = threads.borrow_mut();
+        for id in expired {
+            threads.remove(&id);
+        }
+    });
+}
+
+fn role_of(shipment: &Shipment, principal: Principal) -> Option<ChatRole> {
+    if shipment.sender_id == principal {
+        Some(ChatRole::Sender)
+    } else if shipment.recipient_id == Some(principal) {
+        Some(ChatRole::Recipient)
+    } else if shipment.driver_id == Some(principal) || on_leg(shipment, principal) {
+        Some(ChatRole::Driver)
+    } else if in_dispute(shipment) && permissions::has(&principal, Permission::ResolveDisputes) {
+        Some(ChatRole::Support)
+    } else {
+        None
+    }
+}
+
+fn on_leg(shipment: &Shipment, principal: Principal) -> bool {
+    shipment
+        .legs
+        .as_ref()
+        .is_some_and(|legs| legs.iter().any(|l| l.driver_id == Some(principal)))
+}
+
+fn in_dispute(shipment: &Shipment) -> bool {
+    matches!(shipment.status, ShipmentStatus::Disputed)
+        || confirmation::latest_dispute(&shipment.id).is_some_and(|d| d.is_open())
+}
+
+fn finished(status: &ShipmentStatus) -> bool {
+    matches!(status, ShipmentStatus::Delivered | ShipmentStatus::Returned | ShipmentStatus::Cancelled)
+}
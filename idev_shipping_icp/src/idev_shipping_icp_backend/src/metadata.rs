@@ -0,0 +1,69 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::*;
+
+use crate::permissions::{self, Permission};
+use crate::{Shipment, ShipmentSummary, SHIPMENTS};
+
+const MAX_ENTRIES: usize = 20;
+const MAX_KEY_LENGTH: usize = 64;
+const MAX_VALUE_LENGTH: usize = 512;
+const MAX_TOTAL_BYTES: usize = 4096;
+
+// Integrator-defined reference carried on a shipment, e.g. an order number
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MetadataEntry {
+    pub key: String,
+    pub value: String,
+}
+
+// Shipments carrying `key`, optionally with an exact `value`. Admins search all
+// shipments, everyone else only their own.
+#[query]
+fn get_shipments_by_metadata(key: String, value: Option<String>) -> Vec<ShipmentSummary> {
+    let caller = ic_cdk::caller();
+    let admin = permissions::has(&caller, Permission::ViewAllShipments);
+    SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .values()
+            .filter(|s| admin || s.sender_id == caller)
+            .filter(|s| {
+                s.metadata
+                    .iter()
+                    .any(|m| m.key == key && value.as_ref().map(|v| *v == m.value).unwrap_or(true))
+            })
+            .map(Shipment::summary)
+            .collect()
+    })
+}
+
+pub(crate) fn validate_metadata(entries: &[MetadataEntry]) -> Result<(), String> {
+    if entries.len() > MAX_ENTRIES {
+        return Err(format!("At most {} metadata entries are allowed", MAX_ENTRIES));
+    }
+
+    let mut total_bytes = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.key.is_empty() || entry.key.len() > MAX_KEY_LENGTH {
+            return Err(format!("Metadata keys must be 1 to {} characters", MAX_KEY_LENGTH));
+        }
+        if !entry
+            .key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | ':'))
+        {
+            return Err(format!("Invalid metadata key: {}", entry.key));
+        }
+        if entry.value.len() > MAX_VALUE_LENGTH {
+            return Err(format!("Metadata values must be at most {} bytes", MAX_VALUE_LENGTH));
+        }
+        if entries[..i].iter().any(|e| e.key == entry.key) {
+            return Err(format!("Duplicate metadata key: {}", entry.key));
+        }
+        total_bytes += entry.key.len() + entry.value.len();
+    }
+    if total_bytes > MAX_TOTAL_BYTES {
+        return Err(format!("Metadata must be at most {} bytes in total", MAX_TOTAL_BYTES));
+    }
+    Ok(())
+}
@@ -0,0 +1,196 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::future::Future;
+
+use crate::permissions::{self, Permission};
+use crate::{archive, notifications, stores, zones, DRIVERS, RETURN_REQUESTS, SHIPMENTS, TRACKING_TOKENS, USERS};
+
+const WASM_PAGE_BYTES: u64 = 64 * 1024;
+// Upper bounds of the instruction histogram buckets; a final bucket catches the rest
+const INSTRUCTION_BUCKETS: [u64; 6] = [100_000, 1_000_000, 10_000_000, 100_000_000, 1_000_000_000, 5_000_000_000];
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MethodCalls {
+    pub method: String,
+    pub calls: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct InstructionBucket {
+    // None for the overflow bucket
+    pub upper_bound: Option<u64>,
+    pub count: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MethodStats {
+    pub method: String,
+    pub calls: u64,
+    pub errors: u64,
+    pub total_instructions: u64,
+    pub max_instructions: u64,
+    pub instruction_histogram: Vec<InstructionBucket>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct MethodCounters {
+    calls: u64,
+    errors: u64,
+    total_instructions: u64,
+    max_instructions: u64,
+    histogram: [u64; INSTRUCTION_BUCKETS.len() + 1],
+}
+
+// Whether a method's return value reports a failure
+pub(crate) trait CallOutcome {
+    fn is_error(&self) -> bool;
+}
+
+impl<T, E> CallOutcome for Result<T, E> {
+    fn is_error(&self) -> bool {
+        self.is_err()
+    }
+}
+
+impl CallOutcome for u32 {
+    fn is_error(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CollectionSize {
+    pub collection: String,
+    pub entries: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CanisterMetrics {
+    pub timestamp: u64,
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub cycle_balance: u128,
+    // Update calls since the last install or upgrade; queries cannot persist counters
+    pub method_calls: Vec<MethodCalls>,
+    pub collections: Vec<CollectionSize>,
+}
+
+thread_local! {
+    static METHOD_COUNTERS: RefCell<BTreeMap<&'static str, MethodCounters>> = RefCell::new(BTreeMap::new());
+}
+
+#[query]
+fn get_canister_metrics() -> Result<CanisterMetrics, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ViewReports)?;
+
+    let method_calls = METHOD_COUNTERS.with(|counters| {
+        counters
+            .borrow()
+            .iter()
+            .map(|(method, c)| MethodCalls {
+                method: method.to_string(),
+                calls: c.calls,
+            })
+            .collect()
+    });
+    let collection = |name: &str, entries: usize| CollectionSize {
+        collection: name.to_string(),
+        entries: entries as u64,
+    };
+
+    Ok(CanisterMetrics {
+        timestamp: time(),
+        heap_memory_bytes: heap_memory_bytes(),
+        stable_memory_bytes: ic_cdk::api::stable::stable64_size() * WASM_PAGE_BYTES,
+        cycle_balance: ic_cdk::api::canister_balance128(),
+        method_calls,
+        collections: vec![
+            collection("users", USERS.with(|m| m.borrow().len())),
+            collection("shipments", SHIPMENTS.with(|m| m.borrow().len())),
+            collection("archived_shipments", archive::len() as usize),
+            collection("tracking_tokens", TRACKING_TOKENS.with(|m| m.borrow().len())),
+            collection("drivers", DRIVERS.with(|m| m.borrow().len())),
+            collection("return_requests", RETURN_REQUESTS.with(|m| m.borrow().len())),
+            collection("stores", stores::len()),
+            collection("delivery_zones", zones::len()),
+            collection("notifications", notifications::len()),
+        ],
+    })
+}
+
+// Per-method call, error and instruction statistics for capacity planning. Only
+// update calls are recorded because queries cannot persist state.
+#[query]
+fn get_method_stats() -> Result<Vec<MethodStats>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ViewReports)?;
+
+    Ok(METHOD_COUNTERS.with(|counters| {
+        counters
+            .borrow()
+            .iter()
+            .map(|(method, c)| MethodStats {
+                method: method.to_string(),
+                calls: c.calls,
+                errors: c.errors,
+                total_instructions: c.total_instructions,
+                max_instructions: c.max_instructions,
+                instruction_histogram: c
+                    .histogram
+                    .iter()
+                    .enumerate()
+                    .map(|(i, count)| InstructionBucket {
+                        upper_bound: INSTRUCTION_BUCKETS.get(i).copied(),
+                        count: *count,
+                    })
+                    .collect(),
+            })
+            .collect()
+    }))
+}
+
+// Run an update method and record its outcome and instruction usage
+pub(crate) fn observe<R: CallOutcome>(method: &'static str, f: impl FnOnce() -> R) -> R {
+    let result = f();
+    record_call(method, result.is_error());
+    result
+}
+
+// Async variant; instructions cover the message that completes the call, since
+// the counter restarts after every await
+pub(crate) async fn observe_async<R: CallOutcome>(method: &'static str, f: impl Future<Output = R>) -> R {
+    let result = f.await;
+    record_call(method, result.is_error());
+    result
+}
+
+fn record_call(method: &'static str, failed: bool) {
+    let instructions = ic_cdk::api::performance_counter(0);
+    let bucket = INSTRUCTION_BUCKETS
+        .iter()
+        .position(|bound| instructions < *bound)
+        .unwrap_or(INSTRUCTION_BUCKETS.len());
+    METHOD_COUNTERS.with(|counters| {
+        let mut counters = counters.borrow_mut();
+        let c = counters.entry(method).or_default();
+        c.calls += 1;
+        c.errors += failed as u64;
+        c.total_instructions += instructions;
+        c.max_instructions = c.max_instructions.max(instructions);
+        c.histogram[bucket] += 1;
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+fn heap_memory_bytes() -> u64 {
+    core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_BYTES
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_memory_bytes() -> u64 {
+    0
+}
@@ -0,0 +1,111 @@
+use candid::{CandidType, Deserialize};
+
+// Fixed-point scale: one whole currency unit is 10^8 e8s
+pub const E8S_PER_UNIT: u128 = 100_000_000;
+
+// Prices, credits and promos are held in the base currency; other currencies are
+// only used for the quoted amount a customer pays
+pub const BASE_CURRENCY: Currency = Currency::Usd;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub enum Currency {
+    #[default]
+    Usd,
+    Eur,
+    Gbp,
+    Chf,
+    Cad,
+    Jpy,
+    Icp,
+}
+
+impl Currency {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Currency::Usd => "USD",
+            Currency::Eur => "EUR",
+            Currency::Gbp => "GBP",
+            Currency::Chf => "CHF",
+            Currency::Cad => "CAD",
+            Currency::Jpy => "JPY",
+            Currency::Icp => "ICP",
+        }
+    }
+}
+
+// Monetary amount. Arithmetic is integer-only; the f64 conversions exist for the
+// legacy decimal fields of the candid interface and nothing else.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Money {
+    pub amount_e8s: u128,
+    pub currency: Currency,
+}
+
+impl Money {
+    pub fn zero(currency: Currency) -> Self {
+        Money { amount_e8s: 0, currency }
+    }
+
+    pub fn from_units(units: u64, currency: Currency) -> Self {
+        Money {
+            amount_e8s: units as u128 * E8S_PER_UNIT,
+            currency,
+        }
+    }
+
+    // Rounds to the nearest e8; negative and non-finite amounts become zero
+    pub fn from_decimal(amount: f64, currency: Currency) -> Self {
+        let amount_e8s = if amount.is_finite() && amount > 0.0 {
+            (amount * E8S_PER_UNIT as f64).round() as u128
+        } else {
+            0
+        };
+        Money { amount_e8s, currency }
+    }
+
+    pub fn to_decimal(self) -> f64 {
+        self.amount_e8s as f64 / E8S_PER_UNIT as f64
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.amount_e8s == 0
+    }
+
+    // Amounts of different currencies are never combined implicitly
+    pub fn add(self, other: Money) -> Money {
+        assert_eq!(self.currency, other.currency, "currency mismatch");
+        Money {
+            amount_e8s: self.amount_e8s + other.amount_e8s,
+            currency: self.currency,
+        }
+    }
+
+    pub fn saturating_sub(self, other: Money) -> Money {
+        assert_eq!(self.currency, other.currency, "currency mismatch");
+        Money {
+            amount_e8s: self.amount_e8s.saturating_sub(other.amount_e8s),
+            currency: self.currency,
+        }
+    }
+
+    pub fn min(self, other: Money) -> Money {
+        assert_eq!(self.currency, other.currency, "currency mismatch");
+        if other.amount_e8s < self.amount_e8s {
+            other
+        } else {
+            self
+        }
+    }
+
+    // self * numerator / denominator, rounding half up
+    pub fn mul_ratio(self, numerator: u128, denominator: u128) -> Money {
+        Money {
+            amount_e8s: (self.amount_e8s * numerator + denominator / 2) / denominator,
+            currency: self.currency,
+        }
+    }
+
+    pub fn sum<I: IntoIterator<Item = Money>>(amounts: I, currency: Currency) -> Money {
+        amounts.into_iter().fold(Money::zero(currency), Money::add)
+    }
+}
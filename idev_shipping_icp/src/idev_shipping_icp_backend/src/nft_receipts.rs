@@ -0,0 +1,236 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::metrics;
+use crate::money::{Money, BASE_CURRENCY};
+use crate::permissions::{self, Permission};
+use crate::resource_usage::{self, ResourceFeature};
+use crate::{Shipment, SHIPMENTS};
+
+const BASE_BACKOFF_NANOS: u64 = 60 * 1_000_000_000;
+const MAX_BACKOFF_DOUBLINGS: u32 = 10;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ReceiptHolder {
+    Sender,
+    // Falls back to the sender for recipients without an account
+    Recipient,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct NftReceiptConfig {
+    // ICRC-7 collection this canister is allowed to mint on; None disables minting
+    pub collection: Option<Principal>,
+    // Shipments declared at or above this value get a receipt
+    pub min_declared_value: Money,
+    pub holder: ReceiptHolder,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PendingMint {
+    pub shipment_id: String,
+    pub token_id: u64,
+    pub owner: Principal,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: u64,
+}
+
+// ICRC-7 leaves minting to each collection; this is the `icrc7_mint` method of
+// the collection the platform deploys
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum MetadataValue {
+    Text(String),
+    Nat(Nat),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct MintArg {
+    token_id: Nat,
+    to: Account,
+    metadata: Vec<(String, MetadataValue)>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum MintError {
+    // Minted by an earlier attempt whose reply was lost
+    TokenIdExists,
+    Unauthorized,
+    GenericError { error_code: Nat, message: String },
+}
+
+thread_local! {
+    static CONFIG: RefCell<NftReceiptConfig> = RefCell::new(NftReceiptConfig {
+        collection: None,
+        min_declared_value: Money::from_units(1_000, BASE_CURRENCY),
+        holder: ReceiptHolder::Sender,
+    });
+    static PENDING: RefCell<BTreeMap<String, PendingMint>> = RefCell::new(BTreeMap::new());
+    static IN_FLIGHT: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+    // Token id -> shipment id
+    static TOKENS: RefCell<HashMap<u64, String>> = RefCell::new(HashMap::new());
+    static TOKEN_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+#[update]
+fn set_nft_receipt_config(config: NftReceiptConfig) -> Result<NftReceiptConfig, String> {
+    metrics::observe("set_nft_receipt_config", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if config.min_declared_value.currency != BASE_CURRENCY {
+            return Err(format!("Threshold must be in {:?}", BASE_CURRENCY));
+        }
+        CONFIG.with(|c| *c.borrow_mut() = config.clone());
+        Ok(config)
+    })
+}
+
+#[query]
+fn get_nft_receipt_config() -> NftReceiptConfig {
+    CONFIG.with(|c| c.borrow().clone())
+}
+
+#[query]
+fn get_pending_receipt_mints() -> Result<Vec<PendingMint>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ManagePlatform)?;
+    Ok(PENDING.with(|p| p.borrow().values().cloned().collect()))
+}
+
+#[query]
+fn get_shipment_by_receipt_token(token_id: u64) -> Option<Shipment> {
+    let shipment_id = TOKENS.with(|t| t.borrow().get(&token_id).cloned())?;
+    SHIPMENTS.with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+}
+
+// Queue a receipt for a confirmed delivery of a high-value shipment. The token
+// id is fixed here so retries can't mint twice.
+pub(crate) fn enqueue(shipment: &Shipment) {
+    let config = CONFIG.with(|c| c.borrow().clone());
+    if config.collection.is_none() || shipment.receipt_token_id.is_some() {
+        return;
+    }
+    if shipment.package_details.declared_value().amount_e8s < config.min_declared_value.amount_e8s {
+        return;
+    }
+    if PENDING.with(|p| p.borrow().contains_key(&shipment.id)) {
+        return;
+    }
+    let owner = match config.holder {
+        ReceiptHolder::Recipient => shipment.recipient_id.unwrap_or(shipment.sender_id),
+        ReceiptHolder::Sender => shipment.sender_id,
+    };
+    let token_id = TOKEN_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        *c += 1;
+        *c
+    });
+    PENDING.with(|p| {
+        p.borrow_mut().insert(
+            shipment.id.clone(),
+            PendingMint {
+                shipment_id: shipment.id.clone(),
+                token_id,
+                owner,
+                attempts: 0,
+                last_error: None,
+                next_attempt_at: time(),
+            },
+        )
+    });
+}
+
+// Timer job: mint queued receipts, one call in flight per shipment
+pub(crate) fn process_mints() {
+    let Some(collection) = CONFIG.with(|c| c.borrow().collection) else {
+        return;
+    };
+    let now = time();
+    let ready: Vec<PendingMint> = PENDING.with(|p| {
+        IN_FLIGHT.with(|in_flight| {
+            let in_flight = in_flight.borrow();
+            p.borrow()
+                .values()
+                .filter(|m| m.next_attempt_at <= now && !in_flight.contains(&m.shipment_id))
+                .cloned()
+                .collect()
+        })
+    });
+    IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().extend(ready.iter().map(|m| m.shipment_id.clone())));
+
+    for mint in ready {
+        let Some(shipment) = SHIPMENTS.with(|shipments| shipments.borrow().get(&mint.shipment_id).cloned()) else {
+            IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&mint.shipment_id));
+            PENDING.with(|p| p.borrow_mut().remove(&mint.shipment_id));
+            continue;
+        };
+        let arg = MintArg {
+            token_id: Nat::from(mint.token_id),
+            to: Account {
+                owner: mint.owner,
+                subaccount: None,
+            },
+            metadata: vec![
+                ("shipment_id".to_string(), MetadataValue::Text(shipment.id.clone())),
+                (
+                    "delivered_at".to_string(),
+                    MetadataValue::Nat(Nat::from(shipment.actual_delivery.unwrap_or(shipment.updated_at))),
+                ),
+                ("canister_id".to_string(), MetadataValue::Text(ic_cdk::id().to_text())),
+            ],
+        };
+        let payload_bytes = candid::encode_one(&arg).map(|b| b.len() as u64).unwrap_or(0);
+        resource_usage::record(
+            shipment.sender_id,
+            Some(&shipment.id),
+            ResourceFeature::NftMint,
+            resource_usage::xnet_call_cycles(payload_bytes),
+            0,
+        );
+        ic_cdk::spawn(async move {
+            type MintResults = (Vec<Option<Result<Nat, MintError>>>,);
+            let result = ic_cdk::call::<_, MintResults>(collection, "icrc7_mint", (vec![arg],))
+                .await
+                .map_err(|(code, message)| format!("{:?}: {}", code, message))
+                .and_then(|(mut results,)| match results.pop().flatten() {
+                    Some(Ok(_)) | Some(Err(MintError::TokenIdExists)) => Ok(()),
+                    Some(Err(e)) => Err(format!("{:?}", e)),
+                    None => Err("Collection returned no result".to_string()),
+                });
+            finish_mint(&mint.shipment_id, mint.token_id, result);
+        });
+    }
+}
+
+fn finish_mint(shipment_id: &str, token_id: u64, result: Result<(), String>) {
+    IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(shipment_id));
+    match result {
+        Ok(()) => {
+            PENDING.with(|p| p.borrow_mut().remove(shipment_id));
+            TOKENS.with(|t| t.borrow_mut().insert(token_id, shipment_id.to_string()));
+            SHIPMENTS.with(|shipments| {
+                if let Some(shipment) = shipments.borrow_mut().get_mut(shipment_id) {
+                    shipment.receipt_token_id = Some(token_id);
+                }
+            });
+        },
+        Err(error) => PENDING.with(|p| {
+            if let Some(mint) = p.borrow_mut().get_mut(shipment_id) {
+                mint.attempts += 1;
+                mint.last_error = Some(error);
+                // Retried indefinitely, backing off to about once every 17 hours
+                let backoff = (mint.attempts - 1).min(MAX_BACKOFF_DOUBLINGS);
+                mint.next_attempt_at = time() + BASE_BACKOFF_NANOS * (1u64 << backoff);
+            }
+        }),
+    }
+}
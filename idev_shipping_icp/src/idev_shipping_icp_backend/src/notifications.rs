@@ -0,0 +1,221 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+use crate::i18n::{self, LocalizedText};
+use crate::metrics;
+use crate::resource_usage::{self, ResourceFeature};
+use crate::Shipment;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Retention policy: each inbox keeps at most MAX_PER_USER messages; compaction drops
+// read messages after READ_RETENTION_DAYS and everything after RETENTION_DAYS.
+const MAX_PER_USER: usize = 200;
+const READ_RETENTION_DAYS: u64 = 30;
+const RETENTION_DAYS: u64 = 90;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Notification {
+    pub id: u64,
+    pub kind: NotificationKind,
+    pub shipment_id: Option<String>,
+    // In the reader's language when `text` is set
+    pub message: String,
+    pub text: Option<LocalizedText>,
+    pub created_at: u64,
+    pub read: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum NotificationKind {
+    Assignment,
+    StatusChange,
+    Payment,
+    Dispute,
+    System,
+    Message,
+}
+
+thread_local! {
+    static NOTIFICATIONS: RefCell<HashMap<Principal, VecDeque<Notification>>> = RefCell::new(HashMap::new());
+    static NOTIFICATION_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Notifications with an id greater than `since`, oldest first
+#[query]
+fn get_my_notifications(since: Option<u64>) -> Vec<Notification> {
+    let caller = ic_cdk::caller();
+    let since = since.unwrap_or(0);
+    let lang = i18n::lang_of(&caller);
+    let mut inbox: Vec<Notification> = NOTIFICATIONS.with(|notifications| {
+        notifications
+            .borrow()
+            .get(&caller)
+            .map(|inbox| inbox.iter().filter(|n| n.id > since).cloned().collect())
+            .unwrap_or_default()
+    });
+    for n in &mut inbox {
+        if let Some(text) = &n.text {
+            n.message = text.render(&lang);
+        }
+    }
+    inbox
+}
+
+#[query]
+fn get_unread_notification_count() -> u32 {
+    let caller = ic_cdk::caller();
+    NOTIFICATIONS.with(|notifications| {
+        notifications
+            .borrow()
+            .get(&caller)
+            .map(|inbox| inbox.iter().filter(|n| !n.read).count() as u32)
+            .unwrap_or(0)
+    })
+}
+
+#[update]
+fn mark_read(notification_ids: Vec<u64>) -> u32 {
+    metrics::observe("mark_read", || {
+        let caller = ic_cdk::caller();
+        NOTIFICATIONS.with(|notifications| {
+            let mut marked = 0;
+            if let Some(inbox) = notifications.borrow_mut().get_mut(&caller) {
+                for n in inbox.iter_mut().filter(|n| !n.read && notification_ids.contains(&n.id)) {
+                    n.read = true;
+                    marked += 1;
+                }
+            }
+            marked
+        })
+    })
+}
+
+#[update]
+fn mark_all_read() -> u32 {
+    metrics::observe("mark_all_read", || {
+        let caller = ic_cdk::caller();
+        NOTIFICATIONS.with(|notifications| {
+            let mut marked = 0;
+            if let Some(inbox) = notifications.borrow_mut().get_mut(&caller) {
+                for n in inbox.iter_mut().filter(|n| !n.read) {
+                    n.read = true;
+                    marked += 1;
+                }
+            }
+            marked
+        })
+    })
+}
+
+pub(crate) fn notify(recipient: Principal, kind: NotificationKind, shipment_id: Option<&str>, message: String) {
+    push(recipient, kind, shipment_id, message, None);
+}
+
+// A notification each recipient reads in their own language
+pub(crate) fn notify_localized(
+    recipient: Principal,
+    kind: NotificationKind,
+    shipment_id: Option<&str>,
+    text: LocalizedText,
+) {
+    push(recipient, kind, shipment_id, text.render(i18n::DEFAULT_LANG), Some(text));
+}
+
+fn push(
+    recipient: Principal,
+    kind: NotificationKind,
+    shipment_id: Option<&str>,
+    message: String,
+    text: Option<LocalizedText>,
+) {
+    let id = NOTIFICATION_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        *c += 1;
+        *c
+    });
+
+    NOTIFICATIONS.with(|notifications| {
+        let mut notifications_map = notifications.borrow_mut();
+        let inbox = notifications_map.entry(recipient).or_default();
+        inbox.push_back(Notification {
+            id,
+            kind,
+            shipment_id: shipment_id.map(|s| s.to_string()),
+            message,
+            text,
+            created_at: time(),
+            read: false,
+        });
+        while inbox.len() > MAX_PER_USER {
+            inbox.pop_front();
+        }
+    });
+}
+
+// Notify sender, driver, and linked recipient of a shipment, except whoever caused the event
+pub(crate) fn notify_parties(shipment: &Shipment, actor: Principal, kind: NotificationKind, message: String) {
+    for party in parties(shipment, actor) {
+        notify(party, kind.clone(), Some(&shipment.id), message.clone());
+        resource_usage::record(shipment.sender_id, Some(&shipment.id), ResourceFeature::Notification, 0, 0);
+    }
+}
+
+pub(crate) fn notify_parties_localized(
+    shipment: &Shipment,
+    actor: Principal,
+    kind: NotificationKind,
+    text: LocalizedText,
+) {
+    for party in parties(shipment, actor) {
+        notify_localized(party, kind.clone(), Some(&shipment.id), text.clone());
+        resource_usage::record(shipment.sender_id, Some(&shipment.id), ResourceFeature::Notification, 0, 0);
+    }
+}
+
+fn parties(shipment: &Shipment, actor: Principal) -> Vec<Principal> {
+    let mut parties = vec![shipment.sender_id];
+    parties.extend(shipment.driver_id);
+    parties.extend(shipment.recipient_id);
+    parties.sort();
+    parties.dedup();
+    parties.retain(|p| *p != actor);
+    parties
+}
+
+pub(crate) fn inbox(principal: Principal) -> Vec<Notification> {
+    NOTIFICATIONS.with(|notifications| {
+        notifications
+            .borrow()
+            .get(&principal)
+            .map(|inbox| inbox.iter().cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+pub(crate) fn clear(principal: Principal) {
+    NOTIFICATIONS.with(|notifications| notifications.borrow_mut().remove(&principal));
+}
+
+// Messages across all inboxes
+pub(crate) fn len() -> usize {
+    NOTIFICATIONS.with(|notifications| notifications.borrow().values().map(|inbox| inbox.len()).sum())
+}
+
+// Timer job: apply the retention policy
+pub(crate) fn compact_notifications() {
+    let now = time();
+    NOTIFICATIONS.with(|notifications| {
+        let mut notifications_map = notifications.borrow_mut();
+        for inbox in notifications_map.values_mut() {
+            inbox.retain(|n| {
+                let age = now.saturating_sub(n.created_at);
+                age < RETENTION_DAYS * NANOS_PER_DAY && !(n.read && age >= READ_RETENTION_DAYS * NANOS_PER_DAY)
+            });
+        }
+        notifications_map.retain(|_, inbox| !inbox.is_empty());
+    });
+}
@@ -0,0 +1,566 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::errors::ShippingError;
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::reliability;
+use crate::suspensions::{self, BlockedAction};
+use crate::{
+    accounts, assign_driver, capacity, cod, shifts, vehicles, Driver, Shipment, ShipmentStatus, VerificationStatus,
+    DRIVERS, SHIPMENTS,
+};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const NANOS_PER_HOUR: u64 = 60 * 60 * NANOS_PER_SEC;
+
+// Shipments are offered to one driver at a time, best match first
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct OfferPolicy {
+    pub timeout_secs: u64,
+    // Drivers tried before matching gives up and the sender is told
+    pub max_offers_per_shipment: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum OfferStatus {
+    Pending,
+    Accepted,
+    Declined,
+    Expired,
+    // The shipment was cancelled or assigned some other way
+    Withdrawn,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DeliveryOffer {
+    pub id: String,
+    pub shipment_id: String,
+    pub driver_id: Principal,
+    pub status: OfferStatus,
+    pub offered_at: u64,
+    pub expires_at: u64,
+    pub responded_at: Option<u64>,
+    // Pickup distance from the driver's last reported location
+    pub distance_km: Option<f64>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct OfferStats {
+    pub offered: u64,
+    pub accepted: u64,
+    pub declined: u64,
+    pub expired: u64,
+    // Sum over accepted and declined offers, for the average response time
+    pub total_response_nanos: u64,
+}
+
+// Matching score, higher first. Drivers within `score_band` of the best score take
+// turns, longest since last picked first, so the top match does not take every job.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MatchingWeights {
+    // Points lost per km to the pickup
+    pub distance_per_km: f64,
+    // Assumed for drivers without a known location
+    pub unknown_distance_km: f64,
+    pub rating_per_star: f64,
+    // Points lost per recent assignment
+    pub recent_assignment_penalty: f64,
+    // An assignment counts half after this long
+    pub assignment_half_life_hours: u64,
+    pub score_band: f64,
+}
+
+// One driver as seen by matching for a shipment
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CandidateEvaluation {
+    pub driver_id: Principal,
+    // Why the driver cannot take the shipment; None when eligible
+    pub excluded_because: Option<String>,
+    pub distance_km: Option<f64>,
+    pub rating: f64,
+    // Decayed count of recent assignments
+    pub recent_assignments: f64,
+    pub last_picked_at: Option<u64>,
+    pub score: Option<f64>,
+    pub in_band: bool,
+    pub selected: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MatchExplanation {
+    pub shipment_id: String,
+    pub selected: Option<Principal>,
+    // Eligible drivers by score, then excluded ones
+    pub candidates: Vec<CandidateEvaluation>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct DriverLoad {
+    recent_assignments: f64,
+    updated_at: u64,
+    // Last offer or assignment
+    last_picked_at: Option<u64>,
+}
+
+thread_local! {
+    static MATCHING_WEIGHTS: RefCell<MatchingWeights> = RefCell::new(MatchingWeights {
+        distance_per_km: 1.0,
+        unknown_distance_km: 50.0,
+        rating_per_star: 2.0,
+        recent_assignment_penalty: 1.5,
+        assignment_half_life_hours: 24,
+        score_band: 2.0,
+    });
+    static DRIVER_LOAD: RefCell<HashMap<Principal, DriverLoad>> = RefCell::new(HashMap::new());
+    static OFFER_POLICY: RefCell<OfferPolicy> = RefCell::new(OfferPolicy {
+        timeout_secs: 120,
+        max_offers_per_shipment: 10,
+    });
+    static OFFERS: RefCell<HashMap<String, DeliveryOffer>> = RefCell::new(HashMap::new());
+    static OFFER_COUNTER: RefCell<u64> = RefCell::new(0);
+    static OFFER_STATS: RefCell<HashMap<Principal, OfferStats>> = RefCell::new(HashMap::new());
+}
+
+// Admin configuration
+#[update]
+fn set_offer_policy(policy: OfferPolicy) -> Result<OfferPolicy, String> {
+    metrics::observe("set_offer_policy", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if policy.timeout_secs == 0 || policy.max_offers_per_shipment == 0 {
+            return Err("Offer timeout and limit must be positive".to_string());
+        }
+        OFFER_POLICY.with(|p| *p.borrow_mut() = policy.clone());
+        Ok(policy)
+    })
+}
+
+#[query]
+fn get_offer_policy() -> OfferPolicy {
+    OFFER_POLICY.with(|p| p.borrow().clone())
+}
+
+// Admin configuration
+#[update]
+fn set_matching_weights(weights: MatchingWeights) -> Result<MatchingWeights, String> {
+    metrics::observe("set_matching_weights", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        let values = [
+            weights.distance_per_km,
+            weights.unknown_distance_km,
+            weights.rating_per_star,
+            weights.recent_assignment_penalty,
+            weights.score_band,
+        ];
+        if values.iter().any(|v| !v.is_finite() || *v < 0.0) {
+            return Err("Matching weights must be finite and non-negative".to_string());
+        }
+        if weights.assignment_half_life_hours == 0 {
+            return Err("Assignment half-life must be positive".to_string());
+        }
+        MATCHING_WEIGHTS.with(|w| *w.borrow_mut() = weights.clone());
+        Ok(weights)
+    })
+}
+
+#[query]
+fn get_matching_weights() -> MatchingWeights {
+    MATCHING_WEIGHTS.with(|w| w.borrow().clone())
+}
+
+// Every driver as matching would rank them for the shipment right now
+#[query]
+fn explain_driver_match(shipment_id: String) -> Result<MatchExplanation, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::AssignDriver)?;
+    let shipment = SHIPMENTS
+        .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    let candidates = evaluate(&shipment, &offers_for(&shipment_id), &[]);
+    Ok(MatchExplanation {
+        shipment_id,
+        selected: candidates.iter().find(|c| c.selected).map(|c| c.driver_id),
+        candidates,
+    })
+}
+
+// Start matching an unassigned shipment. Returns the first offer made.
+#[update]
+fn request_driver(shipment_id: String) -> Result<DeliveryOffer, String> {
+    metrics::observe("request_driver", || {
+        let caller = ic_cdk::caller();
+        let shipment = SHIPMENTS
+            .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        if shipment.sender_id != caller && !permissions::has(&caller, Permission::AssignDriver) {
+            return Err("Unauthorized to request a driver".to_string());
+        }
+        if !awaiting_driver(&shipment) {
+            return Err("Shipment is not awaiting a driver".to_string());
+        }
+        if pending_offer(&shipment_id).is_some() {
+            return Err("A driver offer is already pending".to_string());
+        }
+        offer_next(&shipment).ok_or_else(|| "No driver is available for this shipment".to_string())
+    })
+}
+
+#[update]
+fn accept_offer(offer_id: String) -> Result<Shipment, String> {
+    metrics::observe("accept_offer", || {
+        let caller = ic_cdk::caller();
+        suspensions::check(caller, BlockedAction::AcceptDelivery)?;
+        let offer = respond(&offer_id, caller)?;
+        let shipment = SHIPMENTS.with(|shipments| shipments.borrow().get(&offer.shipment_id).cloned());
+        if !shipment.as_ref().is_some_and(awaiting_driver) {
+            close(&offer_id, OfferStatus::Withdrawn);
+            return Err("Shipment is no longer awaiting a driver".to_string());
+        }
+
+        let shipment = assign_driver(&offer.shipment_id, caller, caller)?;
+        close(&offer_id, OfferStatus::Accepted);
+        Ok(shipment)
+    })
+}
+
+#[update]
+fn decline_offer(offer_id: String) -> Result<DeliveryOffer, String> {
+    metrics::observe("decline_offer", || {
+        let caller = ic_cdk::caller();
+        respond(&offer_id, caller)?;
+        let offer = close(&offer_id, OfferStatus::Declined);
+        reliability::check_acceptance(offer.driver_id);
+        offer_next_for(&offer.shipment_id);
+        Ok(offer)
+    })
+}
+
+// The caller's pending offers
+#[query]
+fn get_my_offers() -> Vec<DeliveryOffer> {
+    let caller = ic_cdk::caller();
+    let mut offers: Vec<DeliveryOffer> = OFFERS.with(|offers| {
+        offers
+            .borrow()
+            .values()
+            .filter(|o| o.driver_id == caller && o.status == OfferStatus::Pending)
+            .cloned()
+            .collect()
+    });
+    offers.sort_by_key(|o| o.expires_at);
+    offers
+}
+
+#[query]
+fn get_shipment_offers(shipment_id: String) -> Result<Vec<DeliveryOffer>, String> {
+    let caller = ic_cdk::caller();
+    let sender = SHIPMENTS.with(|shipments| shipments.borrow().get(&shipment_id).map(|s| s.sender_id));
+    if sender != Some(caller) && !permissions::has(&caller, Permission::ViewAllShipments) {
+        return Err("Unauthorized to view offers".to_string());
+    }
+    let mut offers = offers_for(&shipment_id);
+    offers.sort_by_key(|o| o.offered_at);
+    Ok(offers)
+}
+
+#[query]
+fn get_offer_stats(driver_id: Principal) -> Result<OfferStats, String> {
+    let caller = ic_cdk::caller();
+    if caller != driver_id && !permissions::has(&caller, Permission::ViewReports) {
+        return Err("Unauthorized to view offer stats".to_string());
+    }
+    Ok(stats_for(&driver_id))
+}
+
+pub(crate) fn stats_for(driver_id: &Principal) -> OfferStats {
+    OFFER_STATS.with(|stats| stats.borrow().get(driver_id).cloned().unwrap_or_default())
+}
+
+// Dispatch job; backstop for the per-offer expiry timers
+pub(crate) fn expire_due_offers() {
+    let now = time();
+    let due: Vec<String> = OFFERS.with(|offers| {
+        offers
+            .borrow()
+            .values()
+            .filter(|o| o.status == OfferStatus::Pending && o.expires_at <= now)
+            .map(|o| o.id.clone())
+            .collect()
+    });
+    for offer_id in due {
+        expire(&offer_id);
+    }
+}
+
+fn expire(offer_id: &str) {
+    let is_due = OFFERS.with(|offers| {
+        offers
+            .borrow()
+            .get(offer_id)
+            .is_some_and(|o| o.status == OfferStatus::Pending && o.expires_at <= time())
+    });
+    if is_due {
+        let offer = close(offer_id, OfferStatus::Expired);
+        reliability::check_acceptance(offer.driver_id);
+        offer_next_for(&offer.shipment_id);
+    }
+}
+
+// Check that `caller` may still answer the offer
+fn respond(offer_id: &str, caller: Principal) -> Result<DeliveryOffer, String> {
+    let offer = OFFERS
+        .with(|offers| offers.borrow().get(offer_id).cloned())
+        .ok_or_else(|| "Offer not found".to_string())?;
+    if offer.driver_id != caller {
+        return Err("Unauthorized to respond to offer".to_string());
+    }
+    if offer.status != OfferStatus::Pending {
+        return Err("Offer is no longer pending".to_string());
+    }
+    if offer.expires_at <= time() {
+        expire(offer_id);
+        return Err("Offer has expired".to_string());
+    }
+    Ok(offer)
+}
+
+fn close(offer_id: &str, status: OfferStatus) -> DeliveryOffer {
+    let now = time();
+    let offer = OFFERS.with(|offers| {
+        let mut offers_map = offers.borrow_mut();
+        let offer = offers_map.get_mut(offer_id).expect("offer exists");
+        offer.status = status.clone();
+        offer.responded_at = Some(now);
+        offer.clone()
+    });
+    OFFER_STATS.with(|stats| {
+        let mut stats_map = stats.borrow_mut();
+        let s = stats_map.entry(offer.driver_id).or_default();
+        match status {
+            OfferStatus::Accepted => s.accepted += 1,
+            OfferStatus::Declined => s.declined += 1,
+            OfferStatus::Expired => s.expired += 1,
+            OfferStatus::Pending | OfferStatus::Withdrawn => {},
+        }
+        if matches!(status, OfferStatus::Accepted | OfferStatus::Declined) {
+            s.total_response_nanos += now.saturating_sub(offer.offered_at);
+        }
+    });
+    offer
+}
+
+pub(crate) fn offer_next_for(shipment_id: &str) {
+    let shipment = SHIPMENTS.with(|shipments| shipments.borrow().get(shipment_id).cloned());
+    let Some(shipment) = shipment.filter(awaiting_driver) else { return };
+    if offer_next(&shipment).is_none() {
+        notifications::notify(
+            shipment.sender_id,
+            NotificationKind::Assignment,
+            Some(&shipment.id),
+            format!("No driver accepted shipment {}", shipment.id),
+        );
+    }
+}
+
+// Offer the shipment to the best driver not yet asked
+fn offer_next(shipment: &Shipment) -> Option<DeliveryOffer> {
+    let policy = OFFER_POLICY.with(|p| p.borrow().clone());
+    let previous = offers_for(&shipment.id);
+    if previous.len() >= policy.max_offers_per_shipment as usize {
+        return None;
+    }
+    let (driver, distance_km) = best_candidate(shipment, &previous, &[])?;
+
+    let now = time();
+    let id = OFFER_COUNTER.with(|counter| {
+        let mut c = counter.borrow_mut();
+        *c += 1;
+        format!("OF{:06}", *c)
+    });
+    let offer = DeliveryOffer {
+        id: id.clone(),
+        shipment_id: shipment.id.clone(),
+        driver_id: driver.id,
+        status: OfferStatus::Pending,
+        offered_at: now,
+        expires_at: now + policy.timeout_secs * NANOS_PER_SEC,
+        responded_at: None,
+        distance_km,
+    };
+    OFFERS.with(|offers| offers.borrow_mut().insert(id.clone(), offer.clone()));
+    OFFER_STATS.with(|stats| stats.borrow_mut().entry(driver.id).or_default().offered += 1);
+    DRIVER_LOAD.with(|l| l.borrow_mut().entry(driver.id).or_default().last_picked_at = Some(now));
+
+    ic_cdk_timers::set_timer(Duration::from_secs(policy.timeout_secs), move || expire(&id));
+    notifications::notify(
+        driver.id,
+        NotificationKind::Assignment,
+        Some(&shipment.id),
+        format!("New delivery offer for shipment {}; respond within {}s", shipment.id, policy.timeout_secs),
+    );
+    Some(offer)
+}
+
+// Best available driver for a shipment assigned outside the offer flow
+pub(crate) fn best_driver(shipment: &Shipment, excluded: &[Principal]) -> Option<Principal> {
+    best_candidate(shipment, &[], excluded).map(|(driver, _)| driver.id)
+}
+
+// Called on every driver assignment, however it was made
+pub(crate) fn record_assignment(driver_id: Principal, now: u64) {
+    let half_life = MATCHING_WEIGHTS.with(|w| w.borrow().assignment_half_life_hours);
+    DRIVER_LOAD.with(|l| {
+        let mut loads = l.borrow_mut();
+        let load = loads.entry(driver_id).or_default();
+        load.recent_assignments = decayed(load, now, half_life) + 1.0;
+        load.updated_at = now;
+        load.last_picked_at = Some(now);
+    });
+}
+
+fn best_candidate(
+    shipment: &Shipment,
+    previous: &[DeliveryOffer],
+    excluded: &[Principal],
+) -> Option<(Driver, Option<f64>)> {
+    let chosen = evaluate(shipment, previous, excluded).into_iter().find(|c| c.selected)?;
+    let driver = DRIVERS.with(|drivers| drivers.borrow().get(&chosen.driver_id).cloned())?;
+    Some((driver, chosen.distance_km))
+}
+
+// Score every driver and mark the one matching picks
+fn evaluate(shipment: &Shipment, previous: &[DeliveryOffer], excluded: &[Principal]) -> Vec<CandidateEvaluation> {
+    let now = time();
+    let weights = MATCHING_WEIGHTS.with(|w| w.borrow().clone());
+    let pickup = shipment.pickup_address.coordinates.clone();
+    let drivers: Vec<Driver> = DRIVERS.with(|drivers| drivers.borrow().values().cloned().collect());
+    let mut candidates: Vec<CandidateEvaluation> = SHIPMENTS.with(|shipments| {
+        let shipments = shipments.borrow();
+        drivers
+            .iter()
+            .map(|d| {
+                let excluded_because = if excluded.contains(&d.id) {
+                    Some("Driver is being replaced on this shipment".to_string())
+                } else {
+                    check_eligible(d, shipment, previous, now, &shipments).err().map(String::from)
+                };
+                let distance_km = match (&d.current_location, &pickup) {
+                    (Some(at), Some(pickup)) => Some(at.distance_km(pickup)),
+                    _ => None,
+                };
+                let load = DRIVER_LOAD.with(|l| l.borrow().get(&d.id).cloned()).unwrap_or_default();
+                let recent_assignments = decayed(&load, now, weights.assignment_half_life_hours);
+                let score = excluded_because.is_none().then(|| {
+                    weights.rating_per_star * d.rating
+                        - weights.distance_per_km * distance_km.unwrap_or(weights.unknown_distance_km)
+                        - weights.recent_assignment_penalty * recent_assignments
+                });
+                CandidateEvaluation {
+                    driver_id: d.id,
+                    excluded_because,
+                    distance_km,
+                    rating: d.rating,
+                    recent_assignments,
+                    last_picked_at: load.last_picked_at,
+                    score,
+                    in_band: false,
+                    selected: false,
+                }
+            })
+            .collect()
+    });
+    candidates.sort_by(|a, b| {
+        let by_score = match (a.score, b.score) {
+            (Some(a), Some(b)) => b.total_cmp(&a),
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        };
+        by_score.then_with(|| a.driver_id.cmp(&b.driver_id))
+    });
+
+    if let Some(best) = candidates.first().and_then(|c| c.score) {
+        for c in candidates.iter_mut() {
+            c.in_band = c.score.is_some_and(|s| s >= best - weights.score_band);
+        }
+        // Never-picked drivers first, then the longest waiting; ties go to the higher score
+        if let Some(chosen) = candidates.iter_mut().filter(|c| c.in_band).min_by_key(|c| c.last_picked_at) {
+            chosen.selected = true;
+        }
+    }
+    candidates
+}
+
+// Verified, active, unsuspended, on-shift drivers out of cooldown and not yet asked, with room and the
+// right vehicle for the package
+fn check_eligible(
+    driver: &Driver,
+    shipment: &Shipment,
+    previous: &[DeliveryOffer],
+    now: u64,
+    shipments: &HashMap<String, Shipment>,
+) -> Result<(), ShippingError> {
+    if !matches!(driver.verification_status, VerificationStatus::Verified) {
+        return Err("Driver is not verified".to_string().into());
+    }
+    if !accounts::is_active(&driver.id) {
+        return Err("Driver account is not active".to_string().into());
+    }
+    if !shifts::is_on_shift(&driver.id, now) {
+        return Err("Driver is off shift".to_string().into());
+    }
+    if suspensions::is_suspended(&driver.id) {
+        return Err("Driver is suspended".to_string().into());
+    }
+    if let Some(cooldown) = reliability::cooldown(&driver.id, now) {
+        return Err(format!("Driver is in a matching cooldown: {}", cooldown.reason).into());
+    }
+    if previous.iter().any(|o| o.driver_id == driver.id) {
+        return Err("Driver was already offered this shipment".to_string().into());
+    }
+    capacity::check_capacity(driver, &shipment.package_details, &shipment.id, shipments.values())?;
+    cod::check_assignment(shipment, &driver.id)?;
+    Ok(vehicles::check_vehicle(driver, shipment)?)
+}
+
+fn decayed(load: &DriverLoad, now: u64, half_life_hours: u64) -> f64 {
+    let elapsed = now.saturating_sub(load.updated_at) as f64;
+    let half_life = half_life_hours.max(1).saturating_mul(NANOS_PER_HOUR) as f64;
+    load.recent_assignments * 0.5f64.powf(elapsed / half_life)
+}
+
+fn awaiting_driver(shipment: &Shipment) -> bool {
+    shipment.driver_id.is_none() && shipment.legs.is_none() && matches!(shipment.status, ShipmentStatus::Created)
+}
+
+fn pending_offer(shipment_id: &str) -> Option<DeliveryOffer> {
+    offers_for(shipment_id).into_iter().find(|o| o.status == OfferStatus::Pending)
+}
+
+// Offers made to one driver in [from, to)
+pub(crate) fn offers_to(driver_id: &Principal, from: u64, to: u64) -> Vec<DeliveryOffer> {
+    OFFERS.with(|offers| {
+        offers
+            .borrow()
+            .values()
+            .filter(|o| o.driver_id == *driver_id && o.offered_at >= from && o.offered_at < to)
+            .cloned()
+            .collect()
+    })
+}
+
+fn offers_for(shipment_id: &str) -> Vec<DeliveryOffer> {
+    OFFERS.with(|offers| {
+        offers
+            .borrow()
+            .values()
+            .filter(|o| o.shipment_id == shipment_id)
+            .cloned()
+            .collect()
+    })
+}
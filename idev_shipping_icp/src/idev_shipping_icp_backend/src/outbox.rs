@@ -0,0 +1,305 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use crate::event_bus::{self, ShipmentLifecycleEvent};
+use crate::fees;
+use crate::memory::{self, StableMemory};
+use crate::metrics;
+use crate::money::Money;
+use crate::permissions::{self, Permission};
+use crate::webhooks::{self, SignedWebhookDelivery};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const BASE_BACKOFF_NANOS: u64 = 30 * 1_000_000_000;
+const MAX_BACKOFF_NANOS: u64 = 60 * 60 * 1_000_000_000;
+// Calls started per timer run
+const MAX_DISPATCH_PER_RUN: usize = 50;
+const DONE_RETENTION_DAYS: u64 = 7;
+
+// Side effects are recorded here in the same message as the state change that
+// calls for them, and carried out by a timer until they succeed or run out of
+// attempts. Entries live in stable memory, so nothing recorded is lost to a
+// trap in a later callback or to an upgrade.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum OutboxCommand {
+    // Move an accrued platform fee from the shipment's escrow to the treasury
+    SweepFee {
+        shipment_id: String,
+        amount: Money,
+    },
+    DeliverEvent {
+        canister_id: Principal,
+        event: ShipmentLifecycleEvent,
+    },
+    DeliverWebhook {
+        endpoint_id: String,
+        owner: Principal,
+        shipment_id: String,
+        url: String,
+        delivery: SignedWebhookDelivery,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum OutboxStatus {
+    Pending,
+    Done,
+    DeadLettered,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct OutboxEntry {
+    pub id: u64,
+    // Recording the same intent again is a no-op
+    pub intent_key: Option<String>,
+    // Entries sharing a lane run one at a time, oldest first
+    pub lane: Option<String>,
+    pub command: OutboxCommand,
+    pub status: OutboxStatus,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    pub next_attempt_at: u64,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+// How a failed attempt ended, as reported by the module that owns the command
+pub(crate) enum AttemptError {
+    Retry(String),
+    // Retrying cannot help, e.g. the ledger rejected the transfer
+    Permanent(String),
+}
+
+thread_local! {
+    // Entry id -> candid-encoded OutboxEntry
+    static OUTBOX: RefCell<StableBTreeMap<u64, Vec<u8>, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::OUTBOX)));
+    static INTENTS: RefCell<StableBTreeMap<String, u64, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::OUTBOX_INTENTS)));
+    // Not carried across upgrades: a call cut off by one is simply attempted again
+    static IN_FLIGHT: RefCell<HashSet<u64>> = RefCell::new(HashSet::new());
+}
+
+// Newest first
+#[query]
+fn get_outbox_entries(status: Option<OutboxStatus>, limit: Option<u32>) -> Result<Vec<OutboxEntry>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ManagePlatform)?;
+    let limit = limit.unwrap_or(100) as usize;
+    Ok(OUTBOX.with(|outbox| {
+        outbox
+            .borrow()
+            .iter()
+            .rev()
+            .map(|(_, bytes)| decode(&bytes))
+            .filter(|e| status.as_ref().is_none_or(|s| e.status == *s))
+            .take(limit)
+            .collect()
+    }))
+}
+
+// Give a dead-lettered entry a fresh set of attempts, e.g. after a fix downstream
+#[update]
+fn requeue_outbox_entry(id: u64) -> Result<OutboxEntry, String> {
+    metrics::observe("requeue_outbox_entry", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        let mut entry = load(id).ok_or_else(|| "Outbox entry not found".to_string())?;
+        if entry.status != OutboxStatus::DeadLettered {
+            return Err("Only dead-lettered entries can be requeued".to_string());
+        }
+        requeue(&mut entry);
+        Ok(entry)
+    })
+}
+
+// Record a side effect; returns the entry id, or that of the entry already
+// recorded under `intent_key`
+pub(crate) fn enqueue(
+    intent_key: Option<String>,
+    lane: Option<String>,
+    command: OutboxCommand,
+    max_attempts: u32,
+) -> u64 {
+    if let Some(id) = intent_key.as_ref().and_then(|key| INTENTS.with(|i| i.borrow().get(key))) {
+        return id;
+    }
+    let id = OUTBOX.with(|outbox| outbox.borrow().last_key_value().map_or(1, |(id, _)| id + 1));
+    let now = time();
+    if let Some(key) = &intent_key {
+        INTENTS.with(|i| i.borrow_mut().insert(key.clone(), id));
+    }
+    save(&OutboxEntry {
+        id,
+        intent_key,
+        lane,
+        command,
+        status: OutboxStatus::Pending,
+        attempts: 0,
+        max_attempts: max_attempts.max(1),
+        next_attempt_at: now,
+        last_error: None,
+        created_at: now,
+        completed_at: None,
+    });
+    id
+}
+
+// Oldest first
+pub(crate) fn entries_where(f: impl Fn(&OutboxEntry) -> bool) -> Vec<OutboxEntry> {
+    OUTBOX.with(|outbox| outbox.borrow().iter().map(|(_, bytes)| decode(&bytes)).filter(|e| f(e)).collect())
+}
+
+pub(crate) fn requeue_where(f: impl Fn(&OutboxEntry) -> bool) -> u32 {
+    let mut matching = entries_where(|e| e.status == OutboxStatus::DeadLettered && f(e));
+    for entry in &mut matching {
+        requeue(entry);
+    }
+    matching.len() as u32
+}
+
+// Drop pending entries whose target has gone away
+pub(crate) fn discard_where(f: impl Fn(&OutboxEntry) -> bool) {
+    for entry in entries_where(|e| e.status == OutboxStatus::Pending && f(e)) {
+        if !IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(&entry.id)) {
+            remove(&entry);
+        }
+    }
+}
+
+// Timer job: start every due entry that is first in its lane
+pub(crate) fn process() {
+    let now = time();
+    let due: Vec<OutboxEntry> = IN_FLIGHT.with(|in_flight| {
+        let in_flight = in_flight.borrow();
+        let mut busy_lanes = HashSet::new();
+        let mut due = Vec::new();
+        for entry in entries_where(|e| e.status == OutboxStatus::Pending) {
+            if due.len() >= MAX_DISPATCH_PER_RUN {
+                break;
+            }
+            // Later entries in a lane wait for the first, even while it backs off
+            if let Some(lane) = &entry.lane {
+                if !busy_lanes.insert(lane.clone()) {
+                    continue;
+                }
+            }
+            if in_flight.contains(&entry.id) || entry.next_attempt_at > now || !ready(&entry.command) {
+                continue;
+            }
+            due.push(entry);
+        }
+        due
+    });
+    IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().extend(due.iter().map(|e| e.id)));
+
+    for entry in due {
+        ic_cdk::spawn(async move {
+            let result = execute(&entry).await;
+            finish(entry.id, result);
+        });
+    }
+}
+
+// Timer job
+pub(crate) fn prune_completed() {
+    let cutoff = time().saturating_sub(DONE_RETENTION_DAYS * NANOS_PER_DAY);
+    for entry in entries_where(|e| e.status == OutboxStatus::Done && e.completed_at.is_some_and(|at| at < cutoff)) {
+        remove(&entry);
+    }
+}
+
+// Whether the command may start now; webhooks wait for outcall budget
+fn ready(command: &OutboxCommand) -> bool {
+    match command {
+        OutboxCommand::DeliverWebhook { .. } => webhooks::take_outcall(),
+        _ => true,
+    }
+}
+
+async fn execute(entry: &OutboxEntry) -> Result<(), AttemptError> {
+    match &entry.command {
+        OutboxCommand::SweepFee { shipment_id, amount } => fees::sweep(shipment_id, *amount, entry.created_at).await,
+        OutboxCommand::DeliverEvent { canister_id, event } => event_bus::deliver(*canister_id, event).await,
+        OutboxCommand::DeliverWebhook {
+            endpoint_id,
+            owner,
+            shipment_id,
+            url,
+            delivery,
+        } => webhooks::deliver(endpoint_id, *owner, shipment_id, url, delivery).await,
+    }
+}
+
+fn finish(id: u64, result: Result<(), AttemptError>) {
+    IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&id));
+    // Discarded while the call was out
+    let Some(mut entry) = load(id) else {
+        return;
+    };
+    let now = time();
+    entry.attempts += 1;
+    match result {
+        Ok(()) => {
+            entry.status = OutboxStatus::Done;
+            entry.completed_at = Some(now);
+            entry.last_error = None;
+        },
+        Err(AttemptError::Retry(error)) if entry.attempts < entry.max_attempts => {
+            let backoff = BASE_BACKOFF_NANOS.saturating_mul(1u64 << (entry.attempts - 1).min(16));
+            entry.next_attempt_at = now + backoff.min(MAX_BACKOFF_NANOS);
+            entry.last_error = Some(error);
+        },
+        Err(AttemptError::Retry(error) | AttemptError::Permanent(error)) => {
+            entry.status = OutboxStatus::DeadLettered;
+            entry.completed_at = Some(now);
+            dead_lettered(&entry.command, &error);
+            entry.last_error = Some(error);
+        },
+    }
+    save(&entry);
+}
+
+// Let the owning module record that the side effect will not happen
+fn dead_lettered(command: &OutboxCommand, error: &str) {
+    match command {
+        OutboxCommand::SweepFee { shipment_id, .. } => fees::sweep_failed(shipment_id, error),
+        OutboxCommand::DeliverWebhook { endpoint_id, delivery, .. } => {
+            webhooks::delivery_failed(endpoint_id, delivery.delivery_id)
+        },
+        OutboxCommand::DeliverEvent { .. } => {},
+    }
+}
+
+fn requeue(entry: &mut OutboxEntry) {
+    entry.status = OutboxStatus::Pending;
+    entry.attempts = 0;
+    entry.next_attempt_at = time();
+    entry.completed_at = None;
+    save(entry);
+}
+
+fn load(id: u64) -> Option<OutboxEntry> {
+    OUTBOX.with(|outbox| outbox.borrow().get(&id)).map(|bytes| decode(&bytes))
+}
+
+fn save(entry: &OutboxEntry) {
+    let encoded = candid::encode_one(entry).expect("failed to encode outbox entry");
+    OUTBOX.with(|outbox| outbox.borrow_mut().insert(entry.id, encoded));
+}
+
+fn remove(entry: &OutboxEntry) {
+    OUTBOX.with(|outbox| outbox.borrow_mut().remove(&entry.id));
+    if let Some(key) = &entry.intent_key {
+        INTENTS.with(|i| i.borrow_mut().remove(key));
+    }
+}
+
+fn decode(bytes: &[u8]) -> OutboxEntry {
+    candid::decode_one(bytes).expect("failed to decode outbox entry")
+}
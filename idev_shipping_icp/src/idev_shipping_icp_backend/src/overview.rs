@@ -0,0 +1,87 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+
+use crate::analytics;
+use crate::confirmation;
+use crate::kyc;
+use crate::money::Money;
+use crate::permissions::{self, Permission};
+use crate::sla;
+use crate::{ShipmentStatus, SHIPMENTS};
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StatusCount {
+    pub status: ShipmentStatus,
+    pub shipments: u32,
+}
+
+// Everything the admin dashboard opens with. Counts are in a fixed order so equal
+// state encodes to equal bytes.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AdminOverview {
+    pub generated_at: u64,
+    // Every status, in lifecycle order, zeros included
+    pub shipments_by_status: Vec<StatusCount>,
+    pub unassigned_shipments: u32,
+    pub oldest_unassigned_at: Option<u64>,
+    pub sla_at_risk: u32,
+    pub sla_breached: u32,
+    pub open_disputes: u32,
+    pub pending_driver_verifications: u32,
+    pub pending_document_renewals: u32,
+    // Since midnight UTC
+    pub deliveries_today: u32,
+    pub revenue_today: Money,
+}
+
+#[query]
+fn get_admin_overview() -> Result<AdminOverview, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ViewReports)?;
+    let now = time();
+
+    let mut counts = [0u32; ShipmentStatus::ALL.len()];
+    let mut unassigned_shipments = 0;
+    let mut oldest_unassigned_at: Option<u64> = None;
+    SHIPMENTS.with(|shipments| {
+        for shipment in shipments.borrow().values() {
+            if let Some(i) = ShipmentStatus::ALL.iter().position(|s| *s == shipment.status) {
+                counts[i] += 1;
+            }
+            let waiting = matches!(shipment.status, ShipmentStatus::Created)
+                && shipment.driver_id.is_none()
+                && shipment.legs.is_none();
+            if waiting {
+                unassigned_shipments += 1;
+                let created_at = shipment.created_at;
+                oldest_unassigned_at = Some(oldest_unassigned_at.map_or(created_at, |t| t.min(created_at)));
+            }
+        }
+    });
+    let shipments_by_status = ShipmentStatus::ALL
+        .iter()
+        .zip(counts)
+        .map(|(status, shipments)| StatusCount {
+            status: status.clone(),
+            shipments,
+        })
+        .collect();
+
+    let (sla_at_risk, sla_breached) = sla::open_flag_counts();
+    let (pending_driver_verifications, pending_document_renewals) = kyc::review_queue_counts();
+    let (deliveries_today, revenue_today) = analytics::day_deliveries(now);
+    Ok(AdminOverview {
+        generated_at: now,
+        shipments_by_status,
+        unassigned_shipments,
+        oldest_unassigned_at,
+        sla_at_risk,
+        sla_breached,
+        open_disputes: confirmation::open_dispute_count(),
+        pending_driver_verifications,
+        pending_document_renewals,
+        deliveries_today,
+        revenue_today,
+    })
+}
@@ -0,0 +1,495 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use crate::event_store::{self, ShipmentEvent};
+use crate::metrics;
+use crate::money::{Currency, Money, E8S_PER_UNIT};
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::suspensions::{self, BlockedAction};
+use crate::{idempotency, PaymentMethod, PaymentStatus, Shipment, ShipmentStatus, SHIPMENTS};
+
+// ICRC-2 ledger shipments are paid on. Amounts are moved from the sender into a
+// per-shipment escrow subaccount of this canister.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PaymentLedger {
+    pub canister_id: Principal,
+    // Currency the ledger token is denominated in, and its number of decimals
+    pub currency: Currency,
+    pub decimals: u8,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PaymentRecord {
+    pub ledger: Option<Principal>,
+    pub amount: Money,
+    pub escrow_subaccount: Vec<u8>,
+    // Ledger block of the transfer; None when nothing was owed
+    pub block_index: Option<Nat>,
+    pub paid_at: u64,
+}
+
+// ICRC-2 interface types
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum TransferFromResult {
+    Ok(Nat),
+    Err(TransferFromError),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct TransferArgs {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum TransferResult {
+    Ok(Nat),
+    Err(TransferError),
+}
+
+// Why a ledger transfer did not happen
+pub(crate) enum TransferFailure {
+    // The ledger could not be reached; the transfer may be retried as is
+    Unreachable(String),
+    Rejected(String),
+}
+
+impl TransferFailure {
+    pub(crate) fn message(self) -> String {
+        match self {
+            TransferFailure::Unreachable(message) => format!("Ledger call failed: {}", message),
+            TransferFailure::Rejected(message) => message,
+        }
+    }
+}
+
+thread_local! {
+    static PAYMENT_LEDGER: RefCell<Option<PaymentLedger>> = RefCell::new(None);
+    // Keys of flows with a ledger call out, see `lock`
+    static LOCKS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+// Held across the awaits of a flow that must not overlap with another under the
+// same key, e.g. two transfers out of one escrow. Released on drop, which the
+// cdk also does when a callback traps.
+pub(crate) struct FlowLock(String);
+
+impl Drop for FlowLock {
+    fn drop(&mut self) {
+        LOCKS.with(|locks| locks.borrow_mut().remove(&self.0));
+    }
+}
+
+// None while another flow holds the key
+pub(crate) fn lock(key: String) -> Option<FlowLock> {
+    LOCKS.with(|locks| locks.borrow_mut().insert(key.clone())).then_some(FlowLock(key))
+}
+
+pub(crate) fn escrow_lock_key(shipment_id: &str) -> String {
+    format!("escrow:{}", shipment_id)
+}
+
+// Marks a shipment's payment as Processing while it is collected. Should the
+// flow end without settling it either way, e.g. the ledger could not be reached
+// or the callback trapped, dropping the marker puts the prior status back.
+struct PaymentInFlight {
+    shipment_id: String,
+    caller: Principal,
+    prior: PaymentStatus,
+}
+
+impl PaymentInFlight {
+    fn claim(shipment: &mut Shipment, caller: Principal) -> Self {
+        let prior = std::mem::replace(&mut shipment.payment_status, PaymentStatus::Processing);
+        shipment.touch(time());
+        event_store::record(shipment, caller, ShipmentEvent::PaymentStarted);
+        PaymentInFlight {
+            shipment_id: shipment.id.clone(),
+            caller,
+            prior,
+        }
+    }
+}
+
+impl Drop for PaymentInFlight {
+    fn drop(&mut self) {
+        SHIPMENTS.with(|shipments| {
+            let mut shipments_map = shipments.borrow_mut();
+            let Some(s) = shipments_map.get_mut(&self.shipment_id) else {
+                return;
+            };
+            if s.payment_status == PaymentStatus::Processing {
+                s.payment_status = self.prior.clone();
+                s.touch(time());
+                let event = ShipmentEvent::PaymentAbandoned {
+                    status: self.prior.clone(),
+                };
+                event_store::record(s, self.caller, event);
+            }
+        });
+    }
+}
+
+// Admin configuration
+#[update]
+fn set_payment_ledger(ledger: PaymentLedger) -> Result<PaymentLedger, String> {
+    metrics::observe("set_payment_ledger", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManageFinances)?;
+        if ledger.decimals > 18 {
+            return Err("Ledger decimals must be at most 18".to_string());
+        }
+        PAYMENT_LEDGER.with(|l| *l.borrow_mut() = Some(ledger.clone()));
+        Ok(ledger)
+    })
+}
+
+#[query]
+fn get_payment_ledger() -> Option<PaymentLedger> {
+    PAYMENT_LEDGER.with(|l| l.borrow().clone())
+}
+
+// Pay for a shipment with an ICRC-2 transfer_from. The sender must first approve
+// this canister for the amount due plus the ledger fee.
+#[update]
+async fn pay_shipment(shipment_id: String, idempotency_key: Option<String>) -> Result<Shipment, String> {
+    metrics::observe_async("pay_shipment", async move {
+        let caller = ic_cdk::caller();
+        if let Some(shipment) = idempotency::begin(caller, idempotency_key.as_deref(), "pay_shipment")? {
+            return Ok(shipment);
+        }
+        let result = settle(caller, &shipment_id).await;
+        idempotency::finish(caller, idempotency_key.as_deref(), &result);
+        result
+    })
+    .await
+}
+
+async fn settle(caller: Principal, shipment_id: &str) -> Result<Shipment, String> {
+    // Checked and claimed in one step, so a second call made while the ledger
+    // call is out finds the payment Processing
+    let (shipment, _in_flight) = SHIPMENTS.with(|shipments| {
+        let mut shipments_map = shipments.borrow_mut();
+        let shipment = shipments_map
+            .get_mut(shipment_id)
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        if shipment.sender_id != caller {
+            return Err("Unauthorized to pay for shipment".to_string());
+        }
+        suspensions::check(caller, BlockedAction::Payment)?;
+        if matches!(shipment.status, ShipmentStatus::Cancelled) {
+            return Err("Cannot pay for a cancelled shipment".to_string());
+        }
+        if shipment.payment_method == Some(PaymentMethod::CashOnDelivery) {
+            return Err("Shipment is paid in cash on delivery".to_string());
+        }
+        match shipment.payment_status {
+            PaymentStatus::Pending | PaymentStatus::Failed => {},
+            PaymentStatus::Processing => return Err("A payment for this shipment is already in progress".to_string()),
+            _ => return Err("Shipment is already paid".to_string()),
+        }
+        let in_flight = PaymentInFlight::claim(shipment, caller);
+        Ok((shipment.clone(), in_flight))
+    })?;
+
+    let escrow_subaccount = escrow_subaccount(shipment_id);
+    if shipment.price.is_zero() {
+        return record_payment(
+            shipment_id,
+            caller,
+            PaymentRecord {
+                ledger: None,
+                amount: shipment.price,
+                escrow_subaccount,
+                block_index: None,
+                paid_at: time(),
+            },
+        );
+    }
+
+    let ledger = ledger()?;
+    let amount = amount_due(&shipment, ledger.currency)?;
+    let result = collect(&ledger, caller, escrow_subaccount.clone(), amount, shipment_id.as_bytes().to_vec()).await;
+    // The marker kept other flows off the payment during the call; anything else
+    // about the shipment may have changed
+    let block_index = match result {
+        Ok(block_index) => block_index,
+        Err(TransferFailure::Rejected(message)) => {
+            SHIPMENTS.with(|shipments| {
+                if let Some(s) = shipments.borrow_mut().get_mut(shipment_id) {
+                    s.payment_status = PaymentStatus::Failed;
+                    s.touch(time());
+                    event_store::record(s, caller, ShipmentEvent::PaymentFailed);
+                }
+            });
+            return Err(message);
+        },
+        Err(failure) => return Err(failure.message()),
+    };
+    // Funds are in escrow either way; a shipment cancelled meanwhile is refunded
+    // from there
+    record_payment(
+        shipment_id,
+        caller,
+        PaymentRecord {
+            ledger: Some(ledger.canister_id),
+            amount,
+            escrow_subaccount,
+            block_index: Some(block_index),
+            paid_at: time(),
+        },
+    )
+}
+
+fn record_payment(shipment_id: &str, caller: Principal, record: PaymentRecord) -> Result<Shipment, String> {
+    let shipment = SHIPMENTS.with(|shipments| {
+        let mut shipments_map = shipments.borrow_mut();
+        let shipment = shipments_map
+            .get_mut(shipment_id)
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        shipment.payment_status = PaymentStatus::Paid;
+        shipment.payment = Some(record.clone());
+        shipment.touch(time());
+        event_store::record(shipment, caller, ShipmentEvent::PaymentReceived { payment: record });
+        Ok::<Shipment, String>(shipment.clone())
+    })?;
+
+    notifications::notify_parties(
+        &shipment,
+        caller,
+        NotificationKind::Payment,
+        format!("Payment received for shipment {}", shipment.id),
+    );
+    Ok(shipment)
+}
+
+pub(crate) fn ledger() -> Result<PaymentLedger, String> {
+    PAYMENT_LEDGER
+        .with(|l| l.borrow().clone())
+        .ok_or_else(|| "Payments are not configured".to_string())
+}
+
+// Pull `amount` from `from` into a subaccount of this canister. The payer needs an
+// ICRC-2 approval covering the amount and the ledger fee.
+pub(crate) async fn collect(
+    ledger: &PaymentLedger,
+    from: Principal,
+    to_subaccount: Vec<u8>,
+    amount: Money,
+    memo: Vec<u8>,
+) -> Result<Nat, TransferFailure> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: from,
+            subaccount: None,
+        },
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(to_subaccount),
+        },
+        amount: to_ledger_units(amount, ledger.decimals),
+        fee: None,
+        memo: Some(memo),
+        created_at_time: Some(time()),
+    };
+
+    let (result,): (TransferFromResult,) = ic_cdk::call(ledger.canister_id, "icrc2_transfer_from", (args,))
+        .await
+        .map_err(|(code, message)| TransferFailure::Unreachable(format!("{:?}: {}", code, message)))?;
+    match result {
+        TransferFromResult::Ok(block_index) => Ok(block_index),
+        TransferFromResult::Err(TransferFromError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+        TransferFromResult::Err(e) => Err(TransferFailure::Rejected(transfer_from_error_message(e))),
+    }
+}
+
+// Send `amount` out of one of this canister's subaccounts. The ledger fee is
+// charged to the subaccount on top of the amount.
+pub(crate) async fn send(
+    ledger: &PaymentLedger,
+    from_subaccount: Vec<u8>,
+    to: Principal,
+    amount: Money,
+    memo: Vec<u8>,
+) -> Result<Nat, TransferFailure> {
+    let to = Account {
+        owner: to,
+        subaccount: None,
+    };
+    transfer(ledger, from_subaccount, to, amount, memo, time()).await
+}
+
+// Move `amount` between two of this canister's subaccounts, fee charged as for
+// `send`. Retries passing the same `created_at_time` are deduplicated by the ledger.
+pub(crate) async fn move_funds(
+    ledger: &PaymentLedger,
+    from_subaccount: Vec<u8>,
+    to_subaccount: Vec<u8>,
+    amount: Money,
+    memo: Vec<u8>,
+    created_at_time: u64,
+) -> Result<Nat, TransferFailure> {
+    let to = Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(to_subaccount),
+    };
+    transfer(ledger, from_subaccount, to, amount, memo, created_at_time).await
+}
+
+async fn transfer(
+    ledger: &PaymentLedger,
+    from_subaccount: Vec<u8>,
+    to: Account,
+    amount: Money,
+    memo: Vec<u8>,
+    created_at_time: u64,
+) -> Result<Nat, TransferFailure> {
+    let args = TransferArgs {
+        from_subaccount: Some(from_subaccount),
+        to,
+        amount: to_ledger_units(amount, ledger.decimals),
+        fee: None,
+        memo: Some(memo),
+        created_at_time: Some(created_at_time),
+    };
+
+    let (result,): (TransferResult,) = ic_cdk::call(ledger.canister_id, "icrc1_transfer", (args,))
+        .await
+        .map_err(|(code, message)| TransferFailure::Unreachable(format!("{:?}: {}", code, message)))?;
+    match result {
+        TransferResult::Ok(block_index) => Ok(block_index),
+        TransferResult::Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+        TransferResult::Err(e) => Err(TransferFailure::Rejected(transfer_error_message(e))),
+    }
+}
+
+// The ledger's transfer fee, in the ledger currency
+pub(crate) async fn transfer_fee(ledger: &PaymentLedger) -> Result<Money, String> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger.canister_id, "icrc1_fee", ())
+        .await
+        .map_err(|(code, message)| format!("Ledger call failed: {:?}: {}", code, message))?;
+    let units = u128::try_from(&fee.0).map_err(|_| "Ledger fee out of range".to_string())?;
+    let scale = 10u128.pow(ledger.decimals as u32);
+    Ok(Money {
+        amount_e8s: units,
+        currency: ledger.currency,
+    }
+    .mul_ratio(E8S_PER_UNIT, scale))
+}
+
+// Escrow subaccount holding the funds paid for one shipment
+pub(crate) fn escrow_subaccount(shipment_id: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"escrow:");
+    hasher.update(shipment_id.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+// The shipment price in the ledger's currency, using the quote taken at creation
+pub(crate) fn amount_due(shipment: &Shipment, currency: Currency) -> Result<Money, String> {
+    if shipment.price.currency == currency {
+        return Ok(shipment.price);
+    }
+    match shipment.quoted_price {
+        Some(quoted) if quoted.currency == currency => Ok(quoted),
+        _ => Err(format!("Shipment must be quoted in {:?} to be paid on this ledger", currency)),
+    }
+}
+
+fn to_ledger_units(amount: Money, decimals: u8) -> Nat {
+    let scale = 10u128.pow(decimals as u32);
+    Nat::from(amount.mul_ratio(scale, E8S_PER_UNIT).amount_e8s)
+}
+
+fn transfer_from_error_message(e: TransferFromError) -> String {
+    match e {
+        TransferFromError::BadFee { expected_fee } => format!("Ledger rejected the fee, expected {}", expected_fee),
+        TransferFromError::BadBurn { min_burn_amount } => {
+            format!("Ledger rejected the transfer as a burn below {}", min_burn_amount)
+        },
+        TransferFromError::InsufficientFunds { balance } => format!("Insufficient funds: balance is {}", balance),
+        TransferFromError::InsufficientAllowance { allowance } => {
+            format!("Insufficient allowance: approved amount is {}", allowance)
+        },
+        TransferFromError::TooOld => "Ledger rejected the transfer as too old".to_string(),
+        TransferFromError::CreatedInFuture { ledger_time } => {
+            format!("Ledger rejected the transfer as created in the future (ledger time {})", ledger_time)
+        },
+        TransferFromError::Duplicate { duplicate_of } => format!("Duplicate of ledger block {}", duplicate_of),
+        TransferFromError::TemporarilyUnavailable => "Ledger is temporarily unavailable".to_string(),
+        TransferFromError::GenericError { error_code, message } => {
+            format!("Ledger error {}: {}", error_code, message)
+        },
+    }
+}
+
+fn transfer_error_message(e: TransferError) -> String {
+    match e {
+        TransferError::BadFee { expected_fee } => format!("Ledger rejected the fee, expected {}", expected_fee),
+        TransferError::BadBurn { min_burn_amount } => {
+            format!("Ledger rejected the transfer as a burn below {}", min_burn_amount)
+        },
+        TransferError::InsufficientFunds { balance } => format!("Insufficient funds: balance is {}", balance),
+        TransferError::TooOld => "Ledger rejected the transfer as too old".to_string(),
+        TransferError::CreatedInFuture { ledger_time } => {
+            format!("Ledger rejected the transfer as created in the future (ledger time {})", ledger_time)
+        },
+        TransferError::Duplicate { duplicate_of } => format!("Duplicate of ledger block {}", duplicate_of),
+        TransferError::TemporarilyUnavailable => "Ledger is temporarily unavailable".to_string(),
+        TransferError::GenericError { error_code, message } => {
+            format!("Ledger error {}: {}", error_code, message)
+        },
+    }
+}
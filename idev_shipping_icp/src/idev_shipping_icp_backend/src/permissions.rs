@@ -0,0 +1,192 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::audit::{self, AuditAction};
+use crate::metrics;
+use crate::{UserType, USERS};
+
+// What a principal may do. Roles grant a default set; admins can grant or
+// revoke individual permissions per principal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub enum Permission {
+    CreateShipment,
+    ImportShipments,
+    ManageStores,
+    ManageWebhooks,
+    CreateServiceKeys,
+    AssignDriver,
+    // Read any shipment, not only those the caller is a party to
+    ViewAllShipments,
+    // Act on any shipment as if assigned to it
+    UpdateAnyShipment,
+    ManageFinances,
+    ResolveDisputes,
+    // Driver verification, data erasure and the audit log
+    ReviewCompliance,
+    ViewReports,
+    // Platform configuration: settings, zones, hubs, policies and infrastructure
+    ManagePlatform,
+    ManagePermissions,
+    SuspendAccounts,
+}
+
+const ALL: [Permission; 15] = [
+    Permission::CreateShipment,
+    Permission::ImportShipments,
+    Permission::ManageStores,
+    Permission::ManageWebhooks,
+    Permission::CreateServiceKeys,
+    Permission::AssignDriver,
+    Permission::ViewAllShipments,
+    Permission::UpdateAnyShipment,
+    Permission::ManageFinances,
+    Permission::ResolveDisputes,
+    Permission::ReviewCompliance,
+    Permission::ViewReports,
+    Permission::ManagePlatform,
+    Permission::ManagePermissions,
+    Permission::SuspendAccounts,
+];
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct PermissionOverride {
+    pub granted: Vec<Permission>,
+    // Takes precedence over both the role and grants
+    pub revoked: Vec<Permission>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PermissionSet {
+    pub principal: Principal,
+    pub role: Option<UserType>,
+    pub overrides: PermissionOverride,
+    pub effective: Vec<Permission>,
+}
+
+thread_local! {
+    static OVERRIDES: RefCell<HashMap<Principal, PermissionOverride>> = RefCell::new(HashMap::new());
+}
+
+fn role_permissions(role: &UserType) -> &'static [Permission] {
+    match role {
+        UserType::Customer => &[Permission::CreateShipment],
+        UserType::Driver => &[],
+        UserType::StoreOwner => &[
+            Permission::CreateShipment,
+            Permission::ImportShipments,
+            Permission::ManageStores,
+            Permission::ManageWebhooks,
+            Permission::CreateServiceKeys,
+        ],
+        UserType::Admin => &[
+            Permission::ImportShipments,
+            Permission::ManageWebhooks,
+            Permission::AssignDriver,
+            Permission::ViewAllShipments,
+            Permission::UpdateAnyShipment,
+            Permission::ManageFinances,
+            Permission::ResolveDisputes,
+            Permission::ReviewCompliance,
+            Permission::ViewReports,
+            Permission::ManagePlatform,
+            Permission::ManagePermissions,
+            Permission::SuspendAccounts,
+        ],
+    }
+}
+
+pub(crate) fn has(principal: &Principal, permission: Permission) -> bool {
+    let overrides = OVERRIDES.with(|o| o.borrow().get(principal).cloned()).unwrap_or_default();
+    if overrides.revoked.contains(&permission) {
+        return false;
+    }
+    if overrides.granted.contains(&permission) {
+        return true;
+    }
+    let role = USERS.with(|users| users.borrow().get(principal).map(|u| u.user_type.clone()));
+    role.is_some_and(|role| role_permissions(&role).contains(&permission))
+}
+
+// The guard every endpoint uses
+pub(crate) fn require(caller: &Principal, permission: Permission) -> Result<(), String> {
+    if has(caller, permission) {
+        Ok(())
+    } else {
+        Err(format!("Unauthorized: requires the {:?} permission", permission))
+    }
+}
+
+// Replaces the principal's overrides; empty lists fall back to the role
+#[update]
+fn set_permission_override(
+    principal: Principal,
+    granted: Vec<Permission>,
+    revoked: Vec<Permission>,
+) -> Result<PermissionSet, String> {
+    metrics::observe("set_permission_override", || {
+        let caller = ic_cdk::caller();
+        require(&caller, Permission::ManagePermissions)?;
+        if principal == caller && revoked.contains(&Permission::ManagePermissions) {
+            return Err("Cannot revoke your own ManagePermissions".to_string());
+        }
+        if let Some(p) = granted.iter().find(|p| revoked.contains(p)) {
+            return Err(format!("{:?} is both granted and revoked", p));
+        }
+
+        let new = PermissionOverride {
+            granted: dedup(granted),
+            revoked: dedup(revoked),
+        };
+        let old = OVERRIDES.with(|o| o.borrow().get(&principal).cloned()).unwrap_or_default();
+        OVERRIDES.with(|o| {
+            let mut o = o.borrow_mut();
+            if new.granted.is_empty() && new.revoked.is_empty() {
+                o.remove(&principal);
+            } else {
+                o.insert(principal, new.clone());
+            }
+        });
+        audit::record(
+            caller,
+            principal,
+            AuditAction::PermissionsChanged,
+            format!(
+                "granted: {:?} -> {:?}; revoked: {:?} -> {:?}",
+                old.granted, new.granted, old.revoked, new.revoked
+            ),
+        );
+        Ok(permission_set(principal))
+    })
+}
+
+// Anyone may check their own permissions
+#[query]
+fn get_permissions(principal: Option<Principal>) -> Result<PermissionSet, String> {
+    let caller = ic_cdk::caller();
+    let principal = principal.unwrap_or(caller);
+    if principal != caller {
+        require(&caller, Permission::ManagePermissions)?;
+    }
+    Ok(permission_set(principal))
+}
+
+fn permission_set(principal: Principal) -> PermissionSet {
+    PermissionSet {
+        principal,
+        role: USERS.with(|users| users.borrow().get(&principal).map(|u| u.user_type.clone())),
+        overrides: OVERRIDES.with(|o| o.borrow().get(&principal).cloned()).unwrap_or_default(),
+        effective: ALL.into_iter().filter(|p| has(&principal, *p)).collect(),
+    }
+}
+
+fn dedup(permissions: Vec<Permission>) -> Vec<Permission> {
+    let mut out = Vec::new();
+    for p in permissions {
+        if !out.contains(&p) {
+            out.push(p);
+        }
+    }
+    out
+}
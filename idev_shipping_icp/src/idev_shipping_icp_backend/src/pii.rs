@@ -0,0 +1,188 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+use crate::archive;
+use crate::event_store;
+use crate::ids;
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::privacy;
+use crate::text_index;
+use crate::validation::normalize_phone;
+use crate::{Shipment, ShipmentStatus, SHIPMENTS};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Digits left readable in a masked phone number
+const VISIBLE_PHONE_DIGITS: usize = 4;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PiiPolicy {
+    // Keep only a salted hash and a masked form of new recipient phones
+    pub hash_recipient_phones: bool,
+    // Finished shipments untouched for this long lose the recipient's name, phone
+    // and street address; None keeps them
+    pub scrub_after_days: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ScrubReport {
+    pub live_shipments: u32,
+    pub archived_shipments: u32,
+}
+
+thread_local! {
+    static POLICY: RefCell<PiiPolicy> = RefCell::new(PiiPolicy {
+        hash_recipient_phones: false,
+        scrub_after_days: None,
+    });
+    // Drawn on first use; hashes only match under the salt they were made with
+    static SALT: RefCell<Option<[u8; 32]>> = RefCell::new(None);
+}
+
+// Admin configuration
+#[update]
+fn set_pii_policy(policy: PiiPolicy) -> Result<PiiPolicy, String> {
+    metrics::observe("set_pii_policy", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if policy.scrub_after_days == Some(0) {
+            return Err("Scrub age must be positive".to_string());
+        }
+        POLICY.with(|p| *p.borrow_mut() = policy.clone());
+        Ok(policy)
+    })
+}
+
+#[query]
+fn get_pii_policy() -> PiiPolicy {
+    POLICY.with(|p| p.borrow().clone())
+}
+
+// Apply the scrub age to the archive too; visits every archived shipment
+#[update]
+fn scrub_shipment_pii() -> Result<ScrubReport, String> {
+    metrics::observe("scrub_shipment_pii", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        let cutoff = cutoff().ok_or_else(|| "No scrub age is configured".to_string())?;
+        let now = time();
+        Ok(ScrubReport {
+            live_shipments: scrub_live(cutoff, now),
+            archived_shipments: archive::rewrite(|s| scrub_if_due(s, cutoff, now)),
+        })
+    })
+}
+
+// The phone as a new shipment stores it, and its hash
+pub(crate) fn protect_phone(phone: String) -> Result<(String, Option<String>), String> {
+    let hash = hash_phone(&phone)?;
+    if POLICY.with(|p| p.borrow().hash_recipient_phones) {
+        Ok((mask_phone(&phone), Some(hash)))
+    } else {
+        Ok((phone, Some(hash)))
+    }
+}
+
+// Whether `phone` is the shipment's recipient phone, hashed or not
+pub(crate) fn is_recipient_phone(shipment: &Shipment, phone: &str) -> bool {
+    let phone = normalize_phone(phone);
+    if phone.is_empty() {
+        return false;
+    }
+    match &shipment.recipient_phone_hash {
+        Some(hash) => hash_phone(&phone).is_ok_and(|h| h == *hash),
+        None => normalize_phone(&shipment.recipient_phone) == phone,
+    }
+}
+
+pub(crate) fn same_recipient_phone(a: &Shipment, b: &Shipment) -> bool {
+    match (&a.recipient_phone_hash, &b.recipient_phone_hash) {
+        (Some(x), Some(y)) => x == y,
+        (Some(_), None) => is_recipient_phone(a, &b.recipient_phone),
+        (None, Some(_)) => is_recipient_phone(b, &a.recipient_phone),
+        (None, None) => normalize_phone(&a.recipient_phone) == normalize_phone(&b.recipient_phone),
+    }
+}
+
+// Timer job
+pub(crate) fn scrub_old_shipments() {
+    if let Some(cutoff) = cutoff() {
+        scrub_live(cutoff, time());
+    }
+}
+
+// For backups
+pub(crate) fn salt() -> Option<[u8; 32]> {
+    SALT.with(|s| *s.borrow())
+}
+
+pub(crate) fn restore_salt(salt: Option<[u8; 32]>) {
+    SALT.with(|s| *s.borrow_mut() = salt);
+}
+
+fn cutoff() -> Option<u64> {
+    let days = POLICY.with(|p| p.borrow().scrub_after_days)?;
+    Some(time().saturating_sub(days.saturating_mul(NANOS_PER_DAY)))
+}
+
+fn scrub_live(cutoff: u64, now: u64) -> u32 {
+    let scrubbed: Vec<Shipment> = SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow_mut()
+            .values_mut()
+            .filter_map(|s| scrub_if_due(s, cutoff, now).then(|| s.clone()))
+            .collect()
+    });
+    for shipment in &scrubbed {
+        text_index::index_shipment(shipment);
+    }
+    scrubbed.len() as u32
+}
+
+// The phone hash stays, so the recipient can still claim the shipment
+fn scrub_if_due(shipment: &mut Shipment, cutoff: u64, now: u64) -> bool {
+    let finished = matches!(
+        shipment.status,
+        ShipmentStatus::Delivered | ShipmentStatus::Cancelled | ShipmentStatus::Returned
+    );
+    if !finished || shipment.updated_at >= cutoff || shipment.pii_scrubbed_at.is_some() {
+        return false;
+    }
+    if shipment.recipient_phone_hash.is_none() {
+        shipment.recipient_phone_hash = hash_phone(&shipment.recipient_phone).ok();
+    }
+    // Already masked when phones were hashed at creation
+    if !shipment.recipient_phone.contains('*') {
+        shipment.recipient_phone = mask_phone(&shipment.recipient_phone);
+    }
+    privacy::redact_recipient(shipment);
+    shipment.pii_scrubbed_at = Some(now);
+    event_store::compact(shipment, ic_cdk::id());
+    true
+}
+
+// Salted SHA-256 of the normalized number, hex-encoded
+fn hash_phone(phone: &str) -> Result<String, String> {
+    let salt = match salt() {
+        Some(salt) => salt,
+        None => {
+            let salt = ids::entropy().ok_or_else(|| "Randomness is not ready yet; retry shortly".to_string())?;
+            SALT.with(|s| *s.borrow_mut() = Some(salt));
+            salt
+        },
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(normalize_phone(phone).as_bytes());
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// Only the last digits, e.g. "*******1234"
+fn mask_phone(phone: &str) -> String {
+    let digits = normalize_phone(phone);
+    let hidden = digits.len().saturating_sub(VISIBLE_PHONE_DIGITS);
+    format!("{}{}", "*".repeat(hidden), &digits[hidden..])
+}
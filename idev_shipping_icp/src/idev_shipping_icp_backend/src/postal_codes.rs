@@ -0,0 +1,149 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::validation::{Validator, MAX_ADDRESS_FIELD_LEN};
+use crate::zones::{self, normalize_postal_code};
+use crate::{Address, Coordinates};
+
+const MAX_BATCH: usize = 500;
+const MAX_PAGE_SIZE: u32 = 500;
+
+// What a postal code stands for. Addresses with a known code take its city and
+// state, and its centroid when they have no coordinates of their own.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PostalCodeReference {
+    pub country: String,
+    pub postal_code: String,
+    pub city: String,
+    pub state: Option<String>,
+    // Shipments from or to the code are placed in this zone, whatever else overlaps
+    pub zone_id: Option<String>,
+    pub centroid: Option<Coordinates>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PostalCodeEntry {
+    pub reference: PostalCodeReference,
+    pub updated_at: u64,
+}
+
+thread_local! {
+    // (country, normalized postal code) -> entry
+    static POSTAL_CODES: RefCell<BTreeMap<(String, String), PostalCodeEntry>> = RefCell::new(BTreeMap::new());
+}
+
+// Admin reference data; existing codes are replaced. Returns the number stored.
+#[update]
+fn upsert_postal_codes(references: Vec<PostalCodeReference>) -> Result<u32, String> {
+    metrics::observe("upsert_postal_codes", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if references.len() > MAX_BATCH {
+            return Err(format!("At most {} postal codes per call", MAX_BATCH));
+        }
+        let mut v = Validator::new();
+        for (i, r) in references.iter().enumerate() {
+            v.required(&format!("references[{}].country", i), &r.country, MAX_ADDRESS_FIELD_LEN);
+            v.required(&format!("references[{}].postal_code", i), &r.postal_code, MAX_ADDRESS_FIELD_LEN);
+            v.required(&format!("references[{}].city", i), &r.city, MAX_ADDRESS_FIELD_LEN);
+            if let Some(state) = &r.state {
+                v.max_len(&format!("references[{}].state", i), state, MAX_ADDRESS_FIELD_LEN);
+            }
+            if let Some(centroid) = &r.centroid {
+                v.coordinates(&format!("references[{}].centroid", i), centroid);
+            }
+        }
+        v.finish()?;
+        if let Some(zone_id) = references.iter().filter_map(|r| r.zone_id.as_ref()).find(|z| !zones::zone_exists(z)) {
+            return Err(format!("Delivery zone {} not found", zone_id));
+        }
+
+        let now = time();
+        POSTAL_CODES.with(|codes| {
+            let mut codes = codes.borrow_mut();
+            for r in &references {
+                let reference = PostalCodeReference {
+                    country: r.country.trim().to_uppercase(),
+                    postal_code: r.postal_code.trim().to_string(),
+                    city: r.city.trim().to_string(),
+                    state: r.state.as_ref().map(|s| s.trim().to_string()).filter(|s| !s.is_empty()),
+                    zone_id: r.zone_id.clone(),
+                    centroid: r.centroid.clone(),
+                };
+                let entry = PostalCodeEntry { reference, updated_at: now };
+                codes.insert(key(&r.country, &r.postal_code), entry);
+            }
+        });
+        Ok(references.len() as u32)
+    })
+}
+
+#[update]
+fn remove_postal_code(country: String, postal_code: String) -> Result<(), String> {
+    metrics::observe("remove_postal_code", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        POSTAL_CODES
+            .with(|codes| codes.borrow_mut().remove(&key(&country, &postal_code)))
+            .map(|_| ())
+            .ok_or_else(|| "Postal code not found".to_string())
+    })
+}
+
+// For address forms
+#[query]
+fn lookup_postal_code(country: String, postal_code: String) -> Option<PostalCodeEntry> {
+    POSTAL_CODES.with(|codes| codes.borrow().get(&key(&country, &postal_code)).cloned())
+}
+
+// In postal code order
+#[query]
+fn list_postal_codes(country: String, offset: Option<u32>, limit: Option<u32>) -> Vec<PostalCodeEntry> {
+    let country = country.trim().to_uppercase();
+    POSTAL_CODES.with(|codes| {
+        codes
+            .borrow()
+            .range((country.clone(), String::new())..)
+            .take_while(|((c, _), _)| *c == country)
+            .skip(offset.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(MAX_PAGE_SIZE).min(MAX_PAGE_SIZE) as usize)
+            .map(|(_, entry)| entry.clone())
+            .collect()
+    })
+}
+
+// The address in reference form: known codes set the city and state and fill in
+// missing coordinates; unknown codes only get the country tidied
+pub(crate) fn normalize(mut address: Address) -> Address {
+    address.country = address.country.trim().to_uppercase();
+    address.postal_code = address.postal_code.trim().to_string();
+    let Some(entry) = lookup(&address) else { return address };
+    let reference = entry.reference;
+    address.postal_code = reference.postal_code;
+    address.city = reference.city;
+    if let Some(state) = reference.state {
+        address.state = state;
+    }
+    if address.coordinates.is_none() {
+        address.coordinates = reference.centroid;
+    }
+    address
+}
+
+// The zone the address's postal code is pinned to
+pub(crate) fn zone_for(address: &Address) -> Option<String> {
+    lookup(address).and_then(|entry| entry.reference.zone_id)
+}
+
+fn lookup(address: &Address) -> Option<PostalCodeEntry> {
+    POSTAL_CODES.with(|codes| codes.borrow().get(&key(&address.country, &address.postal_code)).cloned())
+}
+
+fn key(country: &str, postal_code: &str) -> (String, String) {
+    (country.trim().to_uppercase(), normalize_postal_code(postal_code))
+}
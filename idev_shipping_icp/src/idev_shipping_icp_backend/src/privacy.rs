@@ -0,0 +1,389 @@
+use candid::parser::value::IDLValue;
+use candid::types::Label;
+use candid::{CandidType, Deserialize, IDLArgs, Principal, TypeEnv};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::addresses::{self, SavedAddress};
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::delivery_preferences;
+use crate::event_store;
+use crate::live_location;
+use crate::metrics;
+use crate::notifications::{self, Notification};
+use crate::permissions::{self, Permission};
+use crate::text_index;
+use crate::validation::{self, Validator};
+use crate::{
+    archive, Address, Driver, ReturnRequest, Shipment, ShipmentStatus, User, DRIVERS, RETURN_REQUESTS, SHIPMENTS, USERS,
+};
+
+const REDACTED: &str = "[erased]";
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum ExportFormat {
+    Candid,
+    Json,
+}
+
+// Every record in the canister that references the caller. Archived shipments
+// can be fetched one by one with get_archived_shipment.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PersonalData {
+    pub generated_at: u64,
+    pub user: Option<User>,
+    // Includes the driver's rating and verification documents
+    pub driver: Option<Driver>,
+    pub shipments_sent: Vec<Shipment>,
+    pub shipments_received: Vec<Shipment>,
+    pub shipments_delivered: Vec<Shipment>,
+    pub return_requests: Vec<ReturnRequest>,
+    pub notifications: Vec<Notification>,
+    pub audit_events: Vec<AuditEvent>,
+    pub erasure_requests: Vec<ErasureRequest>,
+    pub saved_addresses: Vec<SavedAddress>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DataExport {
+    pub format: ExportFormat,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ErasureStatus {
+    Pending,
+    Completed,
+    Rejected,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ErasureRequest {
+    pub id: String,
+    pub requester: Principal,
+    pub reason: Option<String>,
+    pub status: ErasureStatus,
+    pub requested_at: u64,
+    pub reviewed_by: Option<Principal>,
+    pub reviewed_at: Option<u64>,
+    pub review_note: Option<String>,
+    // Shipments, live and archived, whose personal fields were anonymized
+    pub shipments_anonymized: u32,
+}
+
+thread_local! {
+    static ERASURE_REQUESTS: RefCell<HashMap<String, ErasureRequest>> = RefCell::new(HashMap::new());
+    static ERASURE_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+#[query]
+fn export_my_data(format: Option<ExportFormat>) -> Result<DataExport, String> {
+    let caller = ic_cdk::caller();
+    let data = collect(caller);
+    let format = format.unwrap_or(ExportFormat::Candid);
+    let bytes = candid::encode_one(&data).map_err(|e| e.to_string())?;
+    let data = match format {
+        ExportFormat::Candid => bytes,
+        ExportFormat::Json => {
+            // Decode against the declared type so record fields keep their names
+            let args = IDLArgs::from_bytes_with_types(&bytes, &TypeEnv::new(), &[PersonalData::ty()])
+                .map_err(|e| e.to_string())?;
+            let value = args.args.first().map(to_json).unwrap_or(Value::Null);
+            serde_json::to_vec(&value).map_err(|e| e.to_string())?
+        },
+    };
+    Ok(DataExport { format, data })
+}
+
+// Ask for the caller's personal data to be erased. An admin reviews the request
+// before anything is changed.
+#[update]
+fn request_erasure(reason: Option<String>) -> Result<ErasureRequest, String> {
+    metrics::observe("request_erasure", || {
+        let caller = ic_cdk::caller();
+        if let Some(reason) = &reason {
+            let mut v = Validator::new();
+            v.max_len("reason", reason, validation::MAX_TEXT_LEN);
+            v.finish()?;
+        }
+        if !USERS.with(|users| users.borrow().contains_key(&caller)) {
+            return Err("User not registered".to_string());
+        }
+        let pending = ERASURE_REQUESTS.with(|requests| {
+            requests
+                .borrow()
+                .values()
+                .any(|r| r.requester == caller && r.status == ErasureStatus::Pending)
+        });
+        if pending {
+            return Err("An erasure request is already pending".to_string());
+        }
+
+        let id = ERASURE_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            format!("ER{:06}", *c)
+        });
+        let request = ErasureRequest {
+            id: id.clone(),
+            requester: caller,
+            reason,
+            status: ErasureStatus::Pending,
+            requested_at: time(),
+            reviewed_by: None,
+            reviewed_at: None,
+            review_note: None,
+            shipments_anonymized: 0,
+        };
+        ERASURE_REQUESTS.with(|requests| requests.borrow_mut().insert(id, request.clone()));
+        audit::record(caller, caller, AuditAction::ErasureRequested, request.id.clone());
+        Ok(request)
+    })
+}
+
+// Admin review queue
+#[query]
+fn get_erasure_requests(status: Option<ErasureStatus>) -> Result<Vec<ErasureRequest>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ReviewCompliance)?;
+    let mut requests: Vec<ErasureRequest> = ERASURE_REQUESTS.with(|requests| {
+        requests
+            .borrow()
+            .values()
+            .filter(|r| status.as_ref().is_none_or(|s| r.status == *s))
+            .cloned()
+            .collect()
+    });
+    requests.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(requests)
+}
+
+// Approving anonymizes the requester's personal data. Ids, statuses, amounts and
+// timestamps stay so shipment history, payments and statistics remain consistent.
+#[update]
+fn review_erasure_request(request_id: String, approve: bool, note: Option<String>) -> Result<ErasureRequest, String> {
+    metrics::observe("review_erasure_request", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ReviewCompliance)?;
+        let request = ERASURE_REQUESTS
+            .with(|requests| requests.borrow().get(&request_id).cloned())
+            .ok_or_else(|| "Erasure request not found".to_string())?;
+        if request.status != ErasureStatus::Pending {
+            return Err("Erasure request has already been reviewed".to_string());
+        }
+
+        let subject = request.requester;
+        let (status, shipments_anonymized) = if approve {
+            if has_open_shipments(subject) {
+                return Err("Requester still has shipments in progress".to_string());
+            }
+            (ErasureStatus::Completed, erase(subject))
+        } else {
+            (ErasureStatus::Rejected, 0)
+        };
+
+        let request = ERASURE_REQUESTS.with(|requests| {
+            let mut requests_map = requests.borrow_mut();
+            let r = requests_map.get_mut(&request_id).expect("request was just read");
+            r.status = status;
+            r.reviewed_by = Some(caller);
+            r.reviewed_at = Some(time());
+            r.review_note = note;
+            r.shipments_anonymized = shipments_anonymized;
+            r.clone()
+        });
+        let action = if approve { AuditAction::DataErased } else { AuditAction::ErasureRejected };
+        audit::record(caller, subject, action, request.id.clone());
+        Ok(request)
+    })
+}
+
+fn collect(caller: Principal) -> PersonalData {
+    let (shipments_sent, shipments_received, shipments_delivered) = SHIPMENTS.with(|shipments| {
+        let shipments = shipments.borrow();
+        let matching = |f: &dyn Fn(&Shipment) -> bool| -> Vec<Shipment> {
+            let mut list: Vec<Shipment> = shipments.values().filter(|s| f(s)).cloned().collect();
+            list.sort_by(|a, b| a.id.cmp(&b.id));
+            list
+        };
+        (
+            matching(&|s| s.sender_id == caller),
+            matching(&|s| s.recipient_id == Some(caller)),
+            matching(&|s| s.driver_id == Some(caller)),
+        )
+    });
+
+    PersonalData {
+        generated_at: time(),
+        user: USERS.with(|users| users.borrow().get(&caller).cloned()),
+        driver: DRIVERS.with(|drivers| drivers.borrow().get(&caller).cloned()),
+        shipments_sent,
+        shipments_received,
+        shipments_delivered,
+        return_requests: RETURN_REQUESTS.with(|requests| {
+            requests
+                .borrow()
+                .values()
+                .filter(|r| r.requester_id == caller)
+                .cloned()
+                .collect()
+        }),
+        notifications: notifications::inbox(caller),
+        audit_events: audit::events_for(caller),
+        erasure_requests: ERASURE_REQUESTS.with(|requests| {
+            requests
+                .borrow()
+                .values()
+                .filter(|r| r.requester == caller)
+                .cloned()
+                .collect()
+        }),
+        saved_addresses: addresses::addresses_of(caller),
+    }
+}
+
+fn has_open_shipments(subject: Principal) -> bool {
+    SHIPMENTS.with(|shipments| {
+        shipments.borrow().values().any(|s| {
+            (s.sender_id == subject || s.recipient_id == Some(subject) || s.driver_id == Some(subject))
+                && !matches!(
+                    s.status,
+                    ShipmentStatus::Delivered | ShipmentStatus::Cancelled | ShipmentStatus::Returned
+                )
+        })
+    })
+}
+
+// Returns the number of shipments that were anonymized
+fn erase(subject: Principal) -> u32 {
+    USERS.with(|users| {
+        if let Some(user) = users.borrow_mut().get_mut(&subject) {
+            user.name = REDACTED.to_string();
+            user.email = String::new();
+            user.phone = String::new();
+            user.email_verified = false;
+            user.phone_verified = false;
+            user.is_active = false;
+            text_index::index_user(user);
+        }
+    });
+    DRIVERS.with(|drivers| {
+        if let Some(driver) = drivers.borrow_mut().get_mut(&subject) {
+            driver.name = REDACTED.to_string();
+            driver.phone = String::new();
+            driver.current_location = None;
+            driver.is_available = false;
+            driver.documents.clear();
+        }
+    });
+    live_location::index(subject, None);
+    notifications::clear(subject);
+    addresses::clear(subject);
+    delivery_preferences::clear(subject);
+
+    let live = SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow_mut()
+            .values_mut()
+            .filter_map(|s| anonymize_shipment(s, subject).then(|| text_index::index_shipment(s)))
+            .count() as u32
+    });
+    live + archive::rewrite(|s| anonymize_shipment(s, subject))
+}
+
+// Blank the subject's personal fields on a shipment; returns whether anything changed
+fn anonymize_shipment(shipment: &mut Shipment, subject: Principal) -> bool {
+    let mut changed = false;
+    if shipment.sender_id == subject {
+        redact_address(&mut shipment.pickup_address);
+        changed = true;
+    }
+    if shipment.recipient_id == Some(subject) {
+        shipment.recipient_phone = String::new();
+        shipment.recipient_phone_hash = None;
+        redact_recipient(shipment);
+        changed = true;
+    }
+    // Earlier events still hold what was just erased
+    if changed {
+        event_store::compact(shipment, ic_cdk::id());
+    }
+    changed
+}
+
+// Blank the recipient's name and street address
+pub(crate) fn redact_recipient(shipment: &mut Shipment) {
+    shipment.recipient_name = REDACTED.to_string();
+    redact_address(&mut shipment.delivery_address);
+}
+
+// City, region and country are kept for aggregate statistics
+fn redact_address(address: &mut Address) {
+    address.street = REDACTED.to_string();
+    address.postal_code = String::new();
+    address.coordinates = None;
+}
+
+fn to_json(value: &IDLValue) -> Value {
+    match value {
+        IDLValue::Bool(b) => Value::Bool(*b),
+        IDLValue::Null | IDLValue::None | IDLValue::Reserved => Value::Null,
+        IDLValue::Text(s) | IDLValue::Number(s) => Value::String(s.clone()),
+        IDLValue::Float64(n) => serde_json::Number::from_f64(*n).map_or(Value::Null, Value::Number),
+        IDLValue::Float32(n) => serde_json::Number::from_f64(*n as f64).map_or(Value::Null, Value::Number),
+        IDLValue::Opt(v) => to_json(v),
+        IDLValue::Vec(items) => match items.iter().map(as_byte).collect::<Option<Vec<u8>>>() {
+            // Blobs are rendered as hex
+            Some(bytes) if !items.is_empty() => Value::String(bytes.iter().map(|b| format!("{:02x}", b)).collect()),
+            _ => Value::Array(items.iter().map(to_json).collect()),
+        },
+        IDLValue::Record(fields) => {
+            let mut map = Map::new();
+            for field in fields {
+                map.insert(label_name(&field.id), to_json(&field.val));
+            }
+            Value::Object(map)
+        },
+        IDLValue::Variant(variant) => {
+            let field = &variant.0;
+            match field.val {
+                IDLValue::Null => Value::String(label_name(&field.id)),
+                _ => {
+                    let mut map = Map::new();
+                    map.insert(label_name(&field.id), to_json(&field.val));
+                    Value::Object(map)
+                },
+            }
+        },
+        IDLValue::Principal(p) | IDLValue::Service(p) => Value::String(p.to_text()),
+        IDLValue::Func(p, method) => Value::String(format!("{}.{}", p.to_text(), method)),
+        // 64-bit and arbitrary precision integers are strings so JSON parsers keep them exact
+        IDLValue::Int(n) => Value::String(n.to_string()),
+        IDLValue::Nat(n) => Value::String(n.to_string()),
+        IDLValue::Nat64(n) => Value::String(n.to_string()),
+        IDLValue::Int64(n) => Value::String(n.to_string()),
+        IDLValue::Nat8(n) => Value::from(*n),
+        IDLValue::Nat16(n) => Value::from(*n),
+        IDLValue::Nat32(n) => Value::from(*n),
+        IDLValue::Int8(n) => Value::from(*n),
+        IDLValue::Int16(n) => Value::from(*n),
+        IDLValue::Int32(n) => Value::from(*n),
+    }
+}
+
+fn as_byte(value: &IDLValue) -> Option<u8> {
+    match value {
+        IDLValue::Nat8(b) => Some(*b),
+        _ => None,
+    }
+}
+
+fn label_name(label: &Label) -> String {
+    match label {
+        Label::Named(name) => name.clone(),
+        Label::Id(id) | Label::Unnamed(id) => id.to_string(),
+    }
+}
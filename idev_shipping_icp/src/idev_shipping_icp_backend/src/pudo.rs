@@ -0,0 +1,379 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::event_store;
+use crate::events;
+use crate::fees;
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::tracking;
+use crate::{generate_otp, Address, Shipment, ShipmentStatus, TrackingEvent, SHIPMENTS};
+
+const NANOS_PER_MINUTE: u64 = 60_000_000_000;
+const MAX_COLLECTION_ATTEMPTS: u32 = 5;
+
+// Pickup/drop-off point data structures
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PudoPoint {
+    pub id: String,
+    pub name: String,
+    pub kind: PudoKind,
+    pub address: Address,
+    pub capacity: u32,
+    pub opening_hours: Vec<OpeningHours>,
+    pub staff: Vec<Principal>,
+    pub is_active: bool,
+    pub created_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum PudoKind {
+    Locker,
+    PartnerShop,
+}
+
+// Opening window on a given weekday (0 = Monday), in minutes since midnight UTC.
+// A point without any opening hours is considered open around the clock.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct OpeningHours {
+    pub day_of_week: u8,
+    pub open_minute: u16,
+    pub close_minute: u16,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PudoAvailability {
+    pub point: PudoPoint,
+    pub occupied: u32,
+    pub is_open_now: bool,
+}
+
+#[derive(Clone, Debug)]
+struct PudoParcel {
+    pudo_id: String,
+    collection_code: String,
+    failed_attempts: u32,
+}
+
+thread_local! {
+    static PUDO_POINTS: RefCell<HashMap<String, PudoPoint>> = RefCell::new(HashMap::new());
+    static PUDO_PARCELS: RefCell<HashMap<String, PudoParcel>> = RefCell::new(HashMap::new());
+    static PUDO_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Admin management of pickup points
+#[update]
+fn create_pudo_point(
+    name: String,
+    kind: PudoKind,
+    address: Address,
+    capacity: u32,
+    opening_hours: Vec<OpeningHours>,
+) -> Result<PudoPoint, String> {
+    metrics::observe("create_pudo_point", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if capacity == 0 {
+            return Err("Capacity must be greater than zero".to_string());
+        }
+        validate_opening_hours(&opening_hours)?;
+
+        let pudo_id = PUDO_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            format!("PD{:06}", *c)
+        });
+
+        let point = PudoPoint {
+            id: pudo_id.clone(),
+            name,
+            kind,
+            address,
+            capacity,
+            opening_hours,
+            staff: Vec::new(),
+            is_active: true,
+            created_at: time(),
+        };
+
+        PUDO_POINTS.with(|points| {
+            points.borrow_mut().insert(pudo_id, point.clone());
+        });
+
+        Ok(point)
+    })
+}
+
+#[update]
+fn update_pudo_point(
+    pudo_id: String,
+    capacity: u32,
+    opening_hours: Vec<OpeningHours>,
+    is_active: bool,
+) -> Result<PudoPoint, String> {
+    metrics::observe("update_pudo_point", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if capacity == 0 {
+            return Err("Capacity must be greater than zero".to_string());
+        }
+        validate_opening_hours(&opening_hours)?;
+
+        PUDO_POINTS.with(|points| {
+            match points.borrow_mut().get_mut(&pudo_id) {
+                Some(point) => {
+                    point.capacity = capacity;
+                    point.opening_hours = opening_hours;
+                    point.is_active = is_active;
+                    Ok(point.clone())
+                },
+                None => Err("Pickup point not found".to_string()),
+            }
+        })
+    })
+}
+
+#[update]
+fn set_pudo_staff(pudo_id: String, staff: Vec<Principal>) -> Result<PudoPoint, String> {
+    metrics::observe("set_pudo_staff", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+
+        PUDO_POINTS.with(|points| {
+            match points.borrow_mut().get_mut(&pudo_id) {
+                Some(point) => {
+                    point.staff = staff;
+                    Ok(point.clone())
+                },
+                None => Err("Pickup point not found".to_string()),
+            }
+        })
+    })
+}
+
+#[query]
+fn get_pudo_points() -> Vec<PudoAvailability> {
+    let now = time();
+    PUDO_POINTS.with(|points| {
+        points
+            .borrow()
+            .values()
+            .filter(|p| p.is_active)
+            .map(|p| PudoAvailability {
+                point: p.clone(),
+                occupied: occupancy(&p.id),
+                is_open_now: is_open_at(p, now),
+            })
+            .collect()
+    })
+}
+
+// Staff scan-in: parcel arrives at the point and awaits collection
+#[update]
+fn pudo_scan_in(shipment_id: String) -> Result<Shipment, String> {
+    metrics::observe("pudo_scan_in", || {
+        let caller = ic_cdk::caller();
+        let pudo_id = shipment_pudo(&shipment_id)?;
+        let point = staffed_point(&pudo_id, &caller)?;
+
+        if PUDO_PARCELS.with(|parcels| parcels.borrow().contains_key(&shipment_id)) {
+            return Err("Parcel already checked in".to_string());
+        }
+        if occupancy(&pudo_id) >= point.capacity {
+            return Err("Pickup point is at full capacity".to_string());
+        }
+
+        let shipment = SHIPMENTS.with(|shipments| {
+            let mut shipments_map = shipments.borrow_mut();
+            let shipment = shipments_map
+                .get_mut(&shipment_id)
+                .ok_or_else(|| "Shipment not found".to_string())?;
+            if matches!(
+                shipment.status,
+                ShipmentStatus::Delivered | ShipmentStatus::Cancelled | ShipmentStatus::Returned
+            ) {
+                return Err("Shipment can no longer be checked in".to_string());
+            }
+
+            shipment.status = ShipmentStatus::AtPickupPoint;
+            shipment.touch(time());
+            tracking::record(shipment, TrackingEvent {
+                timestamp: time(),
+                status: ShipmentStatus::AtPickupPoint,
+                location: Some(point.name.clone()),
+                description: "Parcel checked in at pickup point and ready for collection".to_string(),
+                updated_by: caller,
+                kind: None,
+            });
+            event_store::status_changed(shipment, caller);
+            notifications::notify_parties(
+                shipment,
+                caller,
+                NotificationKind::StatusChange,
+                format!("Shipment {} is ready for collection at {}", shipment.id, point.name),
+            );
+            events::publish_status_change(shipment);
+            Ok(shipment.clone())
+        })?;
+
+        PUDO_PARCELS.with(|parcels| {
+            parcels.borrow_mut().insert(
+                shipment_id.clone(),
+                PudoParcel {
+                    pudo_id,
+                    collection_code: generate_otp(&shipment_id),
+                    failed_attempts: 0,
+                },
+            );
+        });
+
+        Ok(shipment)
+    })
+}
+
+// Only the sender may read the collection code and pass it to the recipient
+#[query]
+fn get_collection_code(shipment_id: String) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let is_sender = SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .get(&shipment_id)
+            .map(|s| s.sender_id == caller)
+            .unwrap_or(false)
+    });
+    if !is_sender {
+        return Err("Unauthorized to view collection code".to_string());
+    }
+
+    PUDO_PARCELS.with(|parcels| {
+        parcels
+            .borrow()
+            .get(&shipment_id)
+            .map(|p| p.collection_code.clone())
+            .ok_or_else(|| "Parcel is not awaiting collection".to_string())
+    })
+}
+
+// Staff scan-out: recipient presents the collection code
+#[update]
+fn pudo_confirm_collection(shipment_id: String, collection_code: String) -> Result<Shipment, String> {
+    metrics::observe("pudo_confirm_collection", || {
+        let caller = ic_cdk::caller();
+        let pudo_id = shipment_pudo(&shipment_id)?;
+        let point = staffed_point(&pudo_id, &caller)?;
+
+        PUDO_PARCELS.with(|parcels| {
+            let mut parcels_map = parcels.borrow_mut();
+            let parcel = parcels_map
+                .get_mut(&shipment_id)
+                .ok_or_else(|| "Parcel is not awaiting collection".to_string())?;
+            if parcel.failed_attempts >= MAX_COLLECTION_ATTEMPTS {
+                return Err("Too many failed attempts, contact support".to_string());
+            }
+            if parcel.collection_code != collection_code {
+                parcel.failed_attempts += 1;
+                return Err("Invalid collection code".to_string());
+            }
+            parcels_map.remove(&shipment_id);
+            Ok(())
+        })?;
+
+        SHIPMENTS.with(|shipments| {
+            let mut shipments_map = shipments.borrow_mut();
+            let shipment = shipments_map
+                .get_mut(&shipment_id)
+                .ok_or_else(|| "Shipment not found".to_string())?;
+
+            shipment.status = ShipmentStatus::Delivered;
+            shipment.touch(time());
+            shipment.actual_delivery = Some(time());
+            tracking::record(shipment, TrackingEvent {
+                timestamp: time(),
+                status: ShipmentStatus::Delivered,
+                location: Some(point.name.clone()),
+                description: "Collected by recipient at pickup point".to_string(),
+                updated_by: caller,
+                kind: None,
+            });
+            event_store::status_changed(shipment, caller);
+            notifications::notify_parties(
+                shipment,
+                caller,
+                NotificationKind::StatusChange,
+                format!("Shipment {} was collected at {}", shipment.id, point.name),
+            );
+            fees::accrue(shipment);
+            events::publish_status_change(shipment);
+            Ok(shipment.clone())
+        })
+    })
+}
+
+// Helpers used by shipment creation and the scan endpoints
+pub(crate) fn pudo_delivery_address(pudo_id: &str) -> Result<Address, String> {
+    PUDO_POINTS.with(|points| match points.borrow().get(pudo_id) {
+        Some(p) if p.is_active => Ok(p.address.clone()),
+        Some(_) => Err("Pickup point is not active".to_string()),
+        None => Err("Pickup point not found".to_string()),
+    })
+}
+
+fn shipment_pudo(shipment_id: &str) -> Result<String, String> {
+    SHIPMENTS.with(|shipments| match shipments.borrow().get(shipment_id) {
+        Some(s) => s
+            .pudo_id
+            .clone()
+            .ok_or_else(|| "Shipment is not addressed to a pickup point".to_string()),
+        None => Err("Shipment not found".to_string()),
+    })
+}
+
+fn staffed_point(pudo_id: &str, caller: &Principal) -> Result<PudoPoint, String> {
+    let point = PUDO_POINTS
+        .with(|points| points.borrow().get(pudo_id).cloned())
+        .ok_or_else(|| "Pickup point not found".to_string())?;
+    if !point.staff.contains(caller) {
+        return Err("Unauthorized: not staff at this pickup point".to_string());
+    }
+    if !point.is_active || !is_open_at(&point, time()) {
+        return Err("Pickup point is closed".to_string());
+    }
+    Ok(point)
+}
+
+fn occupancy(pudo_id: &str) -> u32 {
+    PUDO_PARCELS.with(|parcels| {
+        parcels
+            .borrow()
+            .values()
+            .filter(|p| p.pudo_id == pudo_id)
+            .count() as u32
+    })
+}
+
+fn is_open_at(point: &PudoPoint, now: u64) -> bool {
+    if point.opening_hours.is_empty() {
+        return true;
+    }
+    let minutes = now / NANOS_PER_MINUTE;
+    // 1970-01-01 was a Thursday
+    let day_of_week = ((minutes / 1440 + 3) % 7) as u8;
+    let minute_of_day = (minutes % 1440) as u16;
+    point.opening_hours.iter().any(|h| {
+        h.day_of_week == day_of_week && minute_of_day >= h.open_minute && minute_of_day < h.close_minute
+    })
+}
+
+pub(crate) fn validate_opening_hours(hours: &[OpeningHours]) -> Result<(), String> {
+    for h in hours {
+        if h.day_of_week > 6 || h.close_minute > 1440 || h.open_minute >= h.close_minute {
+            return Err("Invalid opening hours".to_string());
+        }
+    }
+    Ok(())
+}
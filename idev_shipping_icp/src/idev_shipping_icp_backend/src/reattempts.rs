@@ -0,0 +1,421 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::attachments;
+use crate::confirmation;
+use crate::i18n::{self, LocalizedText};
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::offers;
+use crate::permissions::{self, Permission};
+use crate::validation::{self, Validator};
+use crate::{
+    apply_status_update, assign_driver, assignable_driver, insert_shipment, NewShipment, ShipmentOptions, Shipment,
+    ShipmentStatus, SHIPMENTS, USERS,
+};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// Why a delivery attempt failed, as reported by the driver
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum DeliveryException {
+    RecipientAbsent,
+    AddressNotFound,
+    Refused,
+    DamagedInTransit,
+    AccessRestricted,
+}
+
+impl DeliveryException {
+    pub const ALL: [DeliveryException; 5] = [
+        DeliveryException::RecipientAbsent,
+        DeliveryException::AddressNotFound,
+        DeliveryException::Refused,
+        DeliveryException::DamagedInTransit,
+        DeliveryException::AccessRestricted,
+    ];
+}
+
+// What happens to the shipment after the exception
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum NextStep {
+    Reattempt,
+    Return,
+    // The shipment waits for the dispute to be resolved
+    Dispute { dispute_id: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ReattemptPolicy {
+    // Further attempts after the first failure; once used up the shipment is returned
+    pub max_reattempts: u32,
+    // How long after a failure the automatic reattempt window opens, and its length
+    pub delay_secs: u64,
+    pub window_secs: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FailedAttempt {
+    pub attempt: u32,
+    pub exception: DeliveryException,
+    pub note: Option<String>,
+    // Photo the driver uploaded as a shipment attachment
+    pub evidence: Option<String>,
+    pub next_step: NextStep,
+    pub driver_id: Option<Principal>,
+    pub failed_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ScheduledReattempt {
+    pub attempt: u32,
+    pub window_start: u64,
+    pub window_end: u64,
+    // None to match the nearest available driver when the window opens
+    pub driver_id: Option<Principal>,
+    pub scheduled_by: Principal,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct DeliveryAttempts {
+    pub shipment_id: String,
+    pub failures: Vec<FailedAttempt>,
+    pub next: Option<ScheduledReattempt>,
+    // The reverse shipment created once attempts ran out
+    pub return_shipment_id: Option<String>,
+    pub return_error: Option<String>,
+}
+
+thread_local! {
+    static REATTEMPT_POLICY: RefCell<ReattemptPolicy> = RefCell::new(ReattemptPolicy {
+        max_reattempts: 2,
+        delay_secs: 24 * 60 * 60,
+        window_secs: 4 * 60 * 60,
+    });
+    static ATTEMPTS: RefCell<HashMap<String, DeliveryAttempts>> = RefCell::new(HashMap::new());
+}
+
+// Admin configuration
+#[update]
+fn set_reattempt_policy(policy: ReattemptPolicy) -> Result<ReattemptPolicy, String> {
+    metrics::observe("set_reattempt_policy", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if policy.window_secs == 0 {
+            return Err("Reattempt window must not be empty".to_string());
+        }
+        REATTEMPT_POLICY.with(|p| *p.borrow_mut() = policy.clone());
+        Ok(policy)
+    })
+}
+
+#[query]
+fn get_reattempt_policy() -> ReattemptPolicy {
+    REATTEMPT_POLICY.with(|p| p.borrow().clone())
+}
+
+// The only way to fail a delivery: the driver or an admin records the exception,
+// which decides what happens next. Refused parcels go back, damaged ones are
+// disputed, and otherwise the next attempt is scheduled until attempts run out.
+#[update]
+fn report_delivery_failure(
+    shipment_id: String,
+    exception: DeliveryException,
+    location: Option<String>,
+    note: Option<String>,
+    evidence: Option<String>,
+) -> Result<DeliveryAttempts, String> {
+    metrics::observe("report_delivery_failure", || {
+        let caller = ic_cdk::caller();
+        let mut v = Validator::new();
+        if let Some(location) = &location {
+            v.max_len("location", location, validation::MAX_TEXT_LEN);
+        }
+        if let Some(note) = &note {
+            v.max_len("note", note, validation::MAX_TEXT_LEN);
+        }
+        v.finish()?;
+
+        let policy = REATTEMPT_POLICY.with(|p| p.borrow().clone());
+        let now = time();
+        let (shipment, attempts) = SHIPMENTS.with(|shipments| {
+            let mut shipments_map = shipments.borrow_mut();
+            let shipment = shipments_map
+                .get_mut(&shipment_id)
+                .ok_or_else(|| "Shipment not found".to_string())?;
+            if shipment.driver_id != Some(caller) && !permissions::has(&caller, Permission::UpdateAnyShipment) {
+                return Err("Unauthorized to report delivery failure".to_string());
+            }
+            if shipment.legs.is_some() {
+                return Err("Shipment is routed through hubs; update its legs".to_string());
+            }
+            let failable = shipment.status.can_transition_to(&ShipmentStatus::Failed);
+            if !failable || matches!(shipment.status, ShipmentStatus::Disputed) {
+                return Err(format!("Cannot fail a shipment that is {:?}", shipment.status));
+            }
+            if let Some(evidence) = &evidence {
+                if !attachments::uploaded_to_shipment(&shipment_id, evidence, caller) {
+                    return Err("Evidence must be a photo uploaded to this shipment".to_string());
+                }
+            }
+
+            apply_status_update(
+                shipment,
+                ShipmentStatus::Failed,
+                location,
+                format!("Delivery failed: {}", describe(exception)),
+                caller,
+                now,
+            );
+            let attempts = ATTEMPTS.with(|attempts| {
+                let mut attempts_map = attempts.borrow_mut();
+                let record = attempts_map.entry(shipment_id.clone()).or_insert_with(|| DeliveryAttempts {
+                    shipment_id: shipment_id.clone(),
+                    ..Default::default()
+                });
+                let attempt = record.failures.len() as u32 + 1;
+                let next_step = match exception {
+                    DeliveryException::Refused => NextStep::Return,
+                    DeliveryException::DamagedInTransit => {
+                        let dispute = confirmation::open_exception_dispute(shipment, caller, describe(exception));
+                        NextStep::Dispute { dispute_id: dispute.id }
+                    },
+                    _ if attempt <= policy.max_reattempts => NextStep::Reattempt,
+                    _ => NextStep::Return,
+                };
+                record.failures.push(FailedAttempt {
+                    attempt,
+                    exception,
+                    note,
+                    evidence,
+                    next_step: next_step.clone(),
+                    driver_id: shipment.driver_id,
+                    failed_at: now,
+                });
+                record.next = (next_step == NextStep::Reattempt).then(|| ScheduledReattempt {
+                    attempt: attempt + 1,
+                    window_start: now + policy.delay_secs * NANOS_PER_SEC,
+                    window_end: now + (policy.delay_secs + policy.window_secs) * NANOS_PER_SEC,
+                    driver_id: None,
+                    scheduled_by: ic_cdk::id(),
+                });
+                record.clone()
+            });
+            Ok((shipment.clone(), attempts))
+        })?;
+
+        let next_step = attempts.failures.last().map(|f| f.next_step.clone());
+        match (&attempts.next, next_step) {
+            (Some(next), _) => {
+                notifications::notify_localized(
+                    shipment.sender_id,
+                    NotificationKind::StatusChange,
+                    Some(&shipment_id),
+                    LocalizedText::new("notification.attempt_failed")
+                        .arg("attempt", next.attempt - 1)
+                        .arg("shipment_id", &shipment_id)
+                        .with("reason", i18n::failure_arg(exception))
+                        .arg("next", next.attempt),
+                );
+                Ok(attempts)
+            },
+            (None, Some(NextStep::Dispute { .. })) => Ok(attempts),
+            (None, _) => Ok(return_to_sender(&shipment)),
+        }
+    })
+}
+
+// The sender or an admin moves the next attempt's window, optionally choosing
+// the driver (admins only)
+#[update]
+fn reschedule_reattempt(
+    shipment_id: String,
+    window_start: u64,
+    driver_id: Option<Principal>,
+) -> Result<DeliveryAttempts, String> {
+    metrics::observe("reschedule_reattempt", || {
+        let caller = ic_cdk::caller();
+        let shipment = SHIPMENTS
+            .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        if shipment.sender_id != caller && !permissions::has(&caller, Permission::UpdateAnyShipment) {
+            return Err("Unauthorized to reschedule delivery".to_string());
+        }
+        if driver_id.is_some() && !permissions::has(&caller, Permission::AssignDriver) {
+            return Err("Only admins can choose the driver".to_string());
+        }
+        if let Some(driver_id) = &driver_id {
+            assignable_driver(driver_id)?;
+        }
+        if window_start < time() {
+            return Err("Reattempt window must be in the future".to_string());
+        }
+        let window_secs = REATTEMPT_POLICY.with(|p| p.borrow().window_secs);
+
+        ATTEMPTS.with(|attempts| {
+            let mut attempts_map = attempts.borrow_mut();
+            let next = attempts_map
+                .get_mut(&shipment_id)
+                .and_then(|record| record.next.as_mut())
+                .ok_or_else(|| "No reattempt is scheduled for this shipment".to_string())?;
+            next.window_start = window_start;
+            next.window_end = window_start + window_secs * NANOS_PER_SEC;
+            next.driver_id = driver_id;
+            next.scheduled_by = caller;
+            Ok(attempts_map[&shipment_id].clone())
+        })
+    })
+}
+
+#[query]
+fn get_delivery_attempts(shipment_id: String) -> Result<DeliveryAttempts, String> {
+    let caller = ic_cdk::caller();
+    let shipment = SHIPMENTS
+        .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    if shipment.sender_id != caller
+        && shipment.driver_id != Some(caller)
+        && shipment.recipient_id != Some(caller)
+        && !permissions::has(&caller, Permission::ViewAllShipments)
+    {
+        return Err("Unauthorized to view delivery attempts".to_string());
+    }
+    Ok(ATTEMPTS.with(|attempts| {
+        attempts.borrow().get(&shipment_id).cloned().unwrap_or(DeliveryAttempts {
+            shipment_id,
+            ..Default::default()
+        })
+    }))
+}
+
+// Timer job: hand shipments whose reattempt window has opened to a driver
+pub(crate) fn start_due_reattempts() {
+    let now = time();
+    let due: Vec<(String, Option<Principal>)> = ATTEMPTS.with(|attempts| {
+        attempts
+            .borrow()
+            .values()
+            .filter_map(|record| record.next.as_ref().map(|next| (record, next)))
+            .filter(|(_, next)| next.window_start <= now)
+            .map(|(record, next)| (record.shipment_id.clone(), next.driver_id))
+            .collect()
+    });
+
+    for (shipment_id, chosen) in due {
+        let Some(shipment) = SHIPMENTS.with(|shipments| shipments.borrow().get(&shipment_id).cloned()) else {
+            continue;
+        };
+        // Cancelled, returned or manually reassigned in the meantime
+        if !matches!(shipment.status, ShipmentStatus::Failed) {
+            ATTEMPTS.with(|attempts| {
+                if let Some(record) = attempts.borrow_mut().get_mut(&shipment_id) {
+                    record.next = None;
+                }
+            });
+            continue;
+        }
+        let previous = shipment.driver_id.filter(|d| assignable_driver(d).is_ok());
+        let Some(driver_id) = chosen.or_else(|| offers::best_driver(&shipment, &[])).or(previous) else {
+            // Nobody available yet; try again on the next run
+            continue;
+        };
+        if assign_driver(&shipment_id, driver_id, ic_cdk::id()).is_ok() {
+            ATTEMPTS.with(|attempts| {
+                if let Some(record) = attempts.borrow_mut().get_mut(&shipment_id) {
+                    let window_end = record.next.take().map(|next| next.window_end);
+                    SHIPMENTS.with(|shipments| {
+                        if let Some(shipment) = shipments.borrow_mut().get_mut(&shipment_id) {
+                            shipment.estimated_delivery = window_end;
+                        }
+                    });
+                }
+            });
+        }
+    }
+}
+
+// Attempts are used up: mark the shipment returned and book the reverse
+// shipment from the delivery address back to the sender
+fn return_to_sender(shipment: &Shipment) -> DeliveryAttempts {
+    let canister = ic_cdk::id();
+    SHIPMENTS.with(|shipments| {
+        if let Some(s) = shipments.borrow_mut().get_mut(&shipment.id) {
+            apply_status_update(
+                s,
+                ShipmentStatus::Returned,
+                None,
+                "Delivery attempts exhausted; returning to sender".to_string(),
+                canister,
+                time(),
+            );
+        }
+    });
+
+    let sender = USERS.with(|users| users.borrow().get(&shipment.sender_id).cloned());
+    let result = match sender {
+        Some(sender) => insert_shipment(
+            shipment.sender_id,
+            NewShipment {
+                recipient_name: sender.name,
+                recipient_phone: sender.phone,
+                pickup_address: shipment.delivery_address.clone(),
+                delivery_address: shipment.pickup_address.clone(),
+                package_details: shipment.package_details.clone(),
+            },
+            ShipmentOptions {
+                apply_credits: Some(false),
+                service_level: Some(shipment.service_level.clone()),
+                ..Default::default()
+            },
+            None,
+        ),
+        None => Err("Sender not found".to_string()),
+    };
+
+    ATTEMPTS.with(|attempts| {
+        let mut attempts_map = attempts.borrow_mut();
+        let record = attempts_map.entry(shipment.id.clone()).or_default();
+        match result {
+            Ok(reverse) => {
+                notifications::notify_localized(
+                    shipment.sender_id,
+                    NotificationKind::StatusChange,
+                    Some(&shipment.id),
+                    LocalizedText::new("notification.returning")
+                        .arg("shipment_id", &shipment.id)
+                        .arg("return_id", &reverse.id),
+                );
+                record.return_shipment_id = Some(reverse.id);
+            },
+            Err(e) => record.return_error = Some(e),
+        }
+        record.clone()
+    })
+}
+
+// Failed attempts reported in [from, to), for exception analytics
+pub(crate) fn failures_between(from: u64, to: u64) -> Vec<FailedAttempt> {
+    ATTEMPTS.with(|attempts| {
+        attempts
+            .borrow()
+            .values()
+            .flat_map(|record| record.failures.iter())
+            .filter(|f| f.failed_at >= from && f.failed_at < to)
+            .cloned()
+            .collect()
+    })
+}
+
+fn describe(exception: DeliveryException) -> String {
+    match exception {
+        DeliveryException::RecipientAbsent => "recipient absent",
+        DeliveryException::AddressNotFound => "address not found",
+        DeliveryException::Refused => "refused by recipient",
+        DeliveryException::DamagedInTransit => "damaged in transit",
+        DeliveryException::AccessRestricted => "access restricted",
+    }
+    .to_string()
+}
@@ -0,0 +1,144 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::confirmation;
+use crate::guards::{self, RateLimitedAction};
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::signing::{self, SigningPurpose};
+use crate::tracking;
+use crate::{Shipment, ShipmentStatus, SHIPMENTS};
+
+const RECEIPT_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ConfirmationMethod {
+    Recipient,
+    // The confirmation window lapsed without a dispute
+    Automatic,
+    // A dispute was rejected in the sender's favour
+    DisputeResolution,
+    // Shipments that did not ask for recipient sign-off
+    NotRequired,
+}
+
+// `payload` is canonical JSON (sorted keys, no whitespace) carrying every field
+// below; `signature` is a 64-byte secp256k1 r || s over SHA-256(payload) from the
+// canister's DeliveryReceipt signing key
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DeliveryReceipt {
+    pub shipment_id: String,
+    pub recipient_id: Option<Principal>,
+    pub confirmation: ConfirmationMethod,
+    pub confirmed_by: Principal,
+    pub delivered_at: u64,
+    pub confirmed_at: u64,
+    pub issued_at: u64,
+    pub payload: String,
+    pub key_name: String,
+    pub signature: Vec<u8>,
+}
+
+thread_local! {
+    static RECEIPTS: RefCell<HashMap<String, DeliveryReceipt>> = RefCell::new(HashMap::new());
+}
+
+// Signed once per shipment; later calls return the same receipt
+#[update]
+async fn issue_delivery_receipt(shipment_id: String) -> Result<DeliveryReceipt, String> {
+    metrics::observe_async("issue_delivery_receipt", async move {
+        let caller = ic_cdk::caller();
+        let shipment = SHIPMENTS
+            .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        if !can_view(&shipment, caller) {
+            return Err("Unauthorized to view this shipment's receipt".to_string());
+        }
+        if let Some(receipt) = RECEIPTS.with(|r| r.borrow().get(&shipment_id).cloned()) {
+            return Ok(receipt);
+        }
+        if !matches!(shipment.status, ShipmentStatus::Delivered) {
+            return Err("Shipment has not been delivered".to_string());
+        }
+        guards::check_rate_limit(caller, RateLimitedAction::SignDocument)?;
+
+        let (confirmation, confirmed_by, confirmed_at) = confirmation_of(&shipment);
+        let delivered_at = shipment.actual_delivery.unwrap_or(confirmed_at);
+        let issued_at = time();
+        let payload = json!({
+            "version": RECEIPT_VERSION,
+            "canister_id": ic_cdk::id().to_text(),
+            "shipment_id": shipment.id,
+            "recipient_id": shipment.recipient_id.map(|p| p.to_text()),
+            "confirmation": format!("{:?}", confirmation),
+            "confirmed_by": confirmed_by.to_text(),
+            "delivered_at": delivered_at,
+            "confirmed_at": confirmed_at,
+            "issued_at": issued_at,
+        })
+        .to_string();
+        let digest: [u8; 32] = Sha256::digest(payload.as_bytes()).into();
+        let signed = signing::sign(SigningPurpose::DeliveryReceipt, digest, shipment.sender_id).await?;
+
+        let receipt = DeliveryReceipt {
+            shipment_id: shipment_id.clone(),
+            recipient_id: shipment.recipient_id,
+            confirmation,
+            confirmed_by,
+            delivered_at,
+            confirmed_at,
+            issued_at,
+            payload,
+            key_name: signed.key_name,
+            signature: signed.signature,
+        };
+        // A concurrent call may have finished first; keep the receipt already handed out
+        Ok(RECEIPTS.with(|r| r.borrow_mut().entry(shipment_id).or_insert(receipt).clone()))
+    })
+    .await
+}
+
+#[query]
+fn get_delivery_receipt(shipment_id: String) -> Result<Option<DeliveryReceipt>, String> {
+    let caller = ic_cdk::caller();
+    let shipment = SHIPMENTS
+        .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    if !can_view(&shipment, caller) {
+        return Err("Unauthorized to view this shipment's receipt".to_string());
+    }
+    Ok(RECEIPTS.with(|r| r.borrow().get(&shipment_id).cloned()))
+}
+
+fn can_view(shipment: &Shipment, caller: Principal) -> bool {
+    shipment.sender_id == caller
+        || shipment.recipient_id == Some(caller)
+        || permissions::has(&caller, Permission::ViewAllShipments)
+}
+
+// How and when the shipment reached Delivered, from its latest Delivered event
+fn confirmation_of(shipment: &Shipment) -> (ConfirmationMethod, Principal, u64) {
+    let event = tracking::with_events(shipment, |events| {
+        events
+            .iter()
+            .rev()
+            .find(|e| matches!(e.status, ShipmentStatus::Delivered))
+            .map(|e| (e.updated_by, e.timestamp))
+    });
+    let (by, at) = event.unwrap_or((shipment.sender_id, shipment.updated_at));
+    let method = if !shipment.requires_confirmation {
+        ConfirmationMethod::NotRequired
+    } else if by == ic_cdk::id() {
+        ConfirmationMethod::Automatic
+    } else if confirmation::latest_dispute(&shipment.id).is_some_and(|d| !d.is_open()) {
+        ConfirmationMethod::DisputeResolution
+    } else {
+        ConfirmationMethod::Recipient
+    };
+    (method, by, at)
+}
@@ -0,0 +1,135 @@
+use candid::{CandidType, Deserialize, Principal};
+use hmac::{Hmac, Mac};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use sha2::Sha256;
+use std::cell::RefCell;
+
+use crate::contacts;
+use crate::event_store::{self, ShipmentEvent};
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::pii;
+use crate::validation::normalize_phone;
+use crate::{Shipment, ShipmentSummary, SHIPMENTS, USERS};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MIN_KEY_LENGTH: usize = 16;
+
+// Attestation from the off-chain phone verifier that the caller controls a phone
+// number. The verifier signs
+// hex(HMAC-SHA256(key, "{principal}.{normalized phone}.{expires_at}")).
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PhoneProof {
+    pub phone: String,
+    pub expires_at: u64,
+    pub signature: String,
+}
+
+thread_local! {
+    static PHONE_ATTESTATION_KEY: RefCell<Option<String>> = RefCell::new(None);
+}
+
+// Admin configuration; the key is shared with the phone verifier and never returned
+#[update]
+fn set_phone_attestation_key(key: String) -> Result<(), String> {
+    metrics::observe("set_phone_attestation_key", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if key.len() < MIN_KEY_LENGTH {
+            return Err(format!("Attestation key must be at least {} characters", MIN_KEY_LENGTH));
+        }
+        PHONE_ATTESTATION_KEY.with(|k| *k.borrow_mut() = Some(key));
+        Ok(())
+    })
+}
+
+// Claim unclaimed shipments addressed to the caller's verified phone number. Without
+// a proof, the phone verified on the caller's account is used.
+#[update]
+fn link_incoming_shipments(phone_proof: Option<PhoneProof>) -> Result<Vec<ShipmentSummary>, String> {
+    metrics::observe("link_incoming_shipments", || {
+        let caller = ic_cdk::caller();
+        if !USERS.with(|users| users.borrow().contains_key(&caller)) {
+            return Err("User not registered".to_string());
+        }
+        let phone = match phone_proof {
+            Some(proof) => verify_phone_proof(caller, &proof)?,
+            None => contacts::verified_phone(caller).ok_or_else(|| "Phone number must be verified".to_string())?,
+        };
+        Ok(link_shipments(caller, &phone).iter().map(Shipment::summary).collect())
+    })
+}
+
+// Shipments addressed to the caller
+#[query]
+fn get_incoming_shipments() -> Vec<ShipmentSummary> {
+    let caller = ic_cdk::caller();
+    SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .values()
+            .filter(|s| s.recipient_id == Some(caller))
+            .map(Shipment::summary)
+            .collect()
+    })
+}
+
+fn verify_phone_proof(caller: Principal, proof: &PhoneProof) -> Result<String, String> {
+    let key = PHONE_ATTESTATION_KEY
+        .with(|k| k.borrow().clone())
+        .ok_or_else(|| "Phone attestation is not configured".to_string())?;
+    if proof.expires_at < time() {
+        return Err("Phone proof has expired".to_string());
+    }
+    let phone = normalize_phone(&proof.phone);
+    if phone.is_empty() {
+        return Err("Phone proof has no phone number".to_string());
+    }
+
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}.{}", caller, phone, proof.expires_at).as_bytes());
+    let signature = decode_hex(&proof.signature).ok_or_else(|| "Invalid phone proof signature".to_string())?;
+    mac.verify_slice(&signature)
+        .map_err(|_| "Invalid phone proof signature".to_string())?;
+    Ok(phone)
+}
+
+fn link_shipments(recipient: Principal, phone: &str) -> Vec<Shipment> {
+    let now = time();
+    let linked: Vec<Shipment> = SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow_mut()
+            .values_mut()
+            .filter(|s| s.recipient_id.is_none() && pii::is_recipient_phone(s, phone))
+            .map(|s| {
+                s.recipient_id = Some(recipient);
+                s.touch(now);
+                event_store::record(s, recipient, ShipmentEvent::RecipientLinked { recipient_id: recipient });
+                s.clone()
+            })
+            .collect()
+    });
+
+    for shipment in &linked {
+        notifications::notify(
+            shipment.sender_id,
+            NotificationKind::StatusChange,
+            Some(&shipment.id),
+            format!("Recipient of shipment {} linked their account", shipment.id),
+        );
+    }
+    linked
+}
+
+pub(crate) fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| value.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
@@ -0,0 +1,377 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::templates::{self, TemplateOverrides};
+use crate::{place_shipment, SHIPMENTS, USERS};
+
+const NANOS_PER_MINUTE: u64 = 60_000_000_000;
+const MINUTES_PER_DAY: u64 = 1440;
+const MIN_INTERVAL_SECS: u64 = 60 * 60;
+const MAX_RECURRING_PER_OWNER: usize = 50;
+const MAX_PREVIEW: u32 = 52;
+// Consecutive failed runs before a schedule pauses itself
+const MAX_FAILURES: u32 = 3;
+const MAX_REMEMBERED_SHIPMENTS: usize = 100;
+
+// Times are minutes since midnight UTC
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum RecurrenceRule {
+    Interval { every_secs: u64 },
+    // 0 = Monday
+    Weekly { days_of_week: Vec<u8>, minute: u16 },
+    // Days past the 28th would skip short months
+    Monthly { day_of_month: u8, minute: u16 },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RecurrenceSchedule {
+    pub rule: RecurrenceRule,
+    pub starts_at: u64,
+    pub ends_at: Option<u64>,
+    pub max_occurrences: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum RecurrenceStatus {
+    Active,
+    Paused,
+    Cancelled,
+    // Past its end or out of occurrences
+    Finished,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RecurringShipment {
+    pub id: String,
+    pub owner: Principal,
+    pub template_id: String,
+    pub overrides: Option<TemplateOverrides>,
+    pub schedule: RecurrenceSchedule,
+    pub status: RecurrenceStatus,
+    pub next_run_at: Option<u64>,
+    pub occurrences: u32,
+    // Most recent first
+    pub shipment_ids: Vec<String>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+}
+
+thread_local! {
+    static RECURRING: RefCell<BTreeMap<String, RecurringShipment>> = RefCell::new(BTreeMap::new());
+    static IN_FLIGHT: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+    static RECURRING_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+#[update]
+fn create_recurring_shipment(
+    template_id: String,
+    schedule: RecurrenceSchedule,
+    overrides: Option<TemplateOverrides>,
+) -> Result<RecurringShipment, String> {
+    metrics::observe("create_recurring_shipment", || {
+        let caller = ic_cdk::caller();
+        if !USERS.with(|users| users.borrow().contains_key(&caller)) {
+            return Err("User not registered".to_string());
+        }
+        validate(&schedule)?;
+        // Fails early when the template is missing fields the overrides don't supply
+        templates::build(caller, &template_id, overrides.clone().unwrap_or_default())?;
+        let owned = RECURRING.with(|r| {
+            r.borrow()
+                .values()
+                .filter(|s| s.owner == caller && s.status != RecurrenceStatus::Cancelled)
+                .count()
+        });
+        if owned >= MAX_RECURRING_PER_OWNER {
+            return Err(format!("At most {} recurring shipments can be scheduled", MAX_RECURRING_PER_OWNER));
+        }
+        let next_run_at = next_occurrence(&schedule, time().saturating_sub(1), 0)
+            .ok_or_else(|| "Schedule has no upcoming occurrences".to_string())?;
+
+        let id = RECURRING_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            format!("RS{:06}", *c)
+        });
+        let recurring = RecurringShipment {
+            id: id.clone(),
+            owner: caller,
+            template_id,
+            overrides,
+            schedule,
+            status: RecurrenceStatus::Active,
+            next_run_at: Some(next_run_at),
+            occurrences: 0,
+            shipment_ids: Vec::new(),
+            consecutive_failures: 0,
+            last_error: None,
+            created_at: time(),
+        };
+        RECURRING.with(|r| r.borrow_mut().insert(id, recurring.clone()));
+        Ok(recurring)
+    })
+}
+
+#[update]
+fn pause_recurring_shipment(recurring_id: String) -> Result<RecurringShipment, String> {
+    metrics::observe("pause_recurring_shipment", || {
+        update_owned(&recurring_id, |r| {
+            if r.status != RecurrenceStatus::Active {
+                return Err("Recurring shipment is not active".to_string());
+            }
+            r.status = RecurrenceStatus::Paused;
+            r.next_run_at = None;
+            Ok(())
+        })
+    })
+}
+
+// Picks up from the next occurrence after now; missed ones are skipped
+#[update]
+fn resume_recurring_shipment(recurring_id: String) -> Result<RecurringShipment, String> {
+    metrics::observe("resume_recurring_shipment", || {
+        update_owned(&recurring_id, |r| {
+            if r.status != RecurrenceStatus::Paused {
+                return Err("Recurring shipment is not paused".to_string());
+            }
+            let next = next_occurrence(&r.schedule, time(), r.occurrences)
+                .ok_or_else(|| "Schedule has no upcoming occurrences".to_string())?;
+            r.status = RecurrenceStatus::Active;
+            r.next_run_at = Some(next);
+            r.consecutive_failures = 0;
+            Ok(())
+        })
+    })
+}
+
+#[update]
+fn cancel_recurring_shipment(recurring_id: String) -> Result<RecurringShipment, String> {
+    metrics::observe("cancel_recurring_shipment", || {
+        update_owned(&recurring_id, |r| {
+            if matches!(r.status, RecurrenceStatus::Cancelled | RecurrenceStatus::Finished) {
+                return Err("Recurring shipment has already ended".to_string());
+            }
+            r.status = RecurrenceStatus::Cancelled;
+            r.next_run_at = None;
+            Ok(())
+        })
+    })
+}
+
+#[query]
+fn list_recurring_shipments() -> Vec<RecurringShipment> {
+    let caller = ic_cdk::caller();
+    RECURRING.with(|r| r.borrow().values().filter(|s| s.owner == caller).cloned().collect())
+}
+
+// The next `count` times a schedule would run, e.g. before creating it
+#[query]
+fn preview_recurrence(schedule: RecurrenceSchedule, count: u32) -> Result<Vec<u64>, String> {
+    validate(&schedule)?;
+    let mut upcoming = Vec::new();
+    let mut after = time();
+    while upcoming.len() < count.min(MAX_PREVIEW) as usize {
+        let Some(next) = next_occurrence(&schedule, after, upcoming.len() as u32) else {
+            break;
+        };
+        upcoming.push(next);
+        after = next;
+    }
+    Ok(upcoming)
+}
+
+// Timer job: create the shipments of schedules that are due. The next run is
+// set before the shipment is placed so a slow call can't fire twice.
+pub(crate) fn run_due() {
+    let now = time();
+    let due: Vec<RecurringShipment> = RECURRING.with(|r| {
+        IN_FLIGHT.with(|in_flight| {
+            let in_flight = in_flight.borrow();
+            r.borrow()
+                .values()
+                .filter(|s| s.status == RecurrenceStatus::Active && s.next_run_at.is_some_and(|at| at <= now))
+                .filter(|s| !in_flight.contains(&s.id))
+                .cloned()
+                .collect()
+        })
+    });
+    for recurring in due {
+        IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(recurring.id.clone()));
+        RECURRING.with(|r| {
+            if let Some(r) = r.borrow_mut().get_mut(&recurring.id) {
+                r.next_run_at = next_occurrence(&r.schedule, now, r.occurrences + 1);
+            }
+        });
+        ic_cdk::spawn(async move {
+            let overrides = recurring.overrides.clone().unwrap_or_default();
+            let result = match templates::build(recurring.owner, &recurring.template_id, overrides) {
+                Ok((new, options)) => place_shipment(recurring.owner, new, options).await,
+                Err(e) => Err(e),
+            };
+            finish_run(&recurring.id, result.map(|s| s.id));
+        });
+    }
+}
+
+fn finish_run(recurring_id: &str, result: Result<String, String>) {
+    IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(recurring_id));
+    let Some(recurring) = RECURRING.with(|r| {
+        let mut r = r.borrow_mut();
+        let recurring = r.get_mut(recurring_id)?;
+        match &result {
+            Ok(shipment_id) => {
+                recurring.occurrences += 1;
+                recurring.consecutive_failures = 0;
+                recurring.last_error = None;
+                recurring.shipment_ids.insert(0, shipment_id.clone());
+                recurring.shipment_ids.truncate(MAX_REMEMBERED_SHIPMENTS);
+            },
+            Err(error) => {
+                recurring.consecutive_failures += 1;
+                recurring.last_error = Some(error.clone());
+                if recurring.consecutive_failures >= MAX_FAILURES {
+                    recurring.status = RecurrenceStatus::Paused;
+                    recurring.next_run_at = None;
+                }
+            },
+        }
+        if recurring.status == RecurrenceStatus::Active && recurring.next_run_at.is_none() {
+            recurring.status = RecurrenceStatus::Finished;
+        }
+        Some(recurring.clone())
+    }) else {
+        return;
+    };
+
+    match result {
+        Ok(shipment_id) => SHIPMENTS.with(|shipments| {
+            if let Some(shipment) = shipments.borrow_mut().get_mut(&shipment_id) {
+                shipment.recurrence_id = Some(recurring.id.clone());
+            }
+        }),
+        Err(error) => notifications::notify(
+            recurring.owner,
+            NotificationKind::System,
+            None,
+            match recurring.status {
+                RecurrenceStatus::Paused => {
+                    format!("Recurring shipment {} was paused after repeated failures: {}", recurring.id, error)
+                },
+                _ => format!("Recurring shipment {} could not be created: {}", recurring.id, error),
+            },
+        ),
+    }
+}
+
+fn update_owned(
+    recurring_id: &str,
+    change: impl FnOnce(&mut RecurringShipment) -> Result<(), String>,
+) -> Result<RecurringShipment, String> {
+    let caller = ic_cdk::caller();
+    RECURRING.with(|r| {
+        let mut r = r.borrow_mut();
+        let recurring = r
+            .get_mut(recurring_id)
+            .filter(|r| r.owner == caller)
+            .ok_or_else(|| "Recurring shipment not found".to_string())?;
+        change(recurring)?;
+        Ok(recurring.clone())
+    })
+}
+
+fn validate(schedule: &RecurrenceSchedule) -> Result<(), String> {
+    match &schedule.rule {
+        RecurrenceRule::Interval { every_secs } if *every_secs < MIN_INTERVAL_SECS => {
+            return Err(format!("Interval must be at least {} seconds", MIN_INTERVAL_SECS));
+        },
+        RecurrenceRule::Weekly { days_of_week, minute } => {
+            if days_of_week.is_empty() || days_of_week.iter().any(|d| *d > 6) {
+                return Err("Days of week must be between 0 (Monday) and 6".to_string());
+            }
+            if *minute as u64 >= MINUTES_PER_DAY {
+                return Err("Minute must be within the day".to_string());
+            }
+        },
+        RecurrenceRule::Monthly { day_of_month, minute } => {
+            if !(1..=28).contains(day_of_month) {
+                return Err("Day of month must be between 1 and 28".to_string());
+            }
+            if *minute as u64 >= MINUTES_PER_DAY {
+                return Err("Minute must be within the day".to_string());
+            }
+        },
+        RecurrenceRule::Interval { .. } => {},
+    }
+    if schedule.ends_at.is_some_and(|end| end <= schedule.starts_at) {
+        return Err("Schedule must end after it starts".to_string());
+    }
+    if schedule.max_occurrences == Some(0) {
+        return Err("Schedule needs at least one occurrence".to_string());
+    }
+    Ok(())
+}
+
+// First run strictly after `after`, given `done` runs so far
+fn next_occurrence(schedule: &RecurrenceSchedule, after: u64, done: u32) -> Option<u64> {
+    if schedule.max_occurrences.is_some_and(|max| done >= max) {
+        return None;
+    }
+    let after = after.max(schedule.starts_at.saturating_sub(1));
+    let next = match &schedule.rule {
+        RecurrenceRule::Interval { every_secs } => {
+            let every = every_secs * 1_000_000_000;
+            let elapsed = after + 1 - schedule.starts_at;
+            schedule.starts_at + elapsed.div_ceil(every) * every
+        },
+        RecurrenceRule::Weekly { days_of_week, minute } => {
+            let today = after / NANOS_PER_MINUTE / MINUTES_PER_DAY;
+            (today..today + 8)
+                // 1970-01-01 was a Thursday
+                .filter(|day| days_of_week.contains(&(((day + 3) % 7) as u8)))
+                .map(|day| (day * MINUTES_PER_DAY + *minute as u64) * NANOS_PER_MINUTE)
+                .find(|at| *at > after)?
+        },
+        RecurrenceRule::Monthly { day_of_month, minute } => {
+            let (mut year, mut month, _) = civil_from_days(after / NANOS_PER_MINUTE / MINUTES_PER_DAY);
+            loop {
+                let day = days_from_civil(year, month, *day_of_month as u64);
+                let at = (day * MINUTES_PER_DAY + *minute as u64) * NANOS_PER_MINUTE;
+                if at > after {
+                    break at;
+                }
+                (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+            }
+        },
+    };
+    (schedule.ends_at.is_none_or(|end| next <= end)).then_some(next)
+}
+
+// Proleptic Gregorian calendar from days since 1970-01-01 (dates after the epoch only)
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
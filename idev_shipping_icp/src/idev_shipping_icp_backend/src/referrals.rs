@@ -0,0 +1,302 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::credits::{self, CreditSource};
+use crate::loyalty;
+use crate::metrics;
+use crate::money::{Money, BASE_CURRENCY};
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::pii;
+use crate::validation;
+use crate::{carrier_delivered, PaymentStatus, Shipment, USERS};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum ReferralReward {
+    Credit(Money),
+    Points(u64),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ReferralConfig {
+    pub enabled: bool,
+    pub referrer_reward: Option<ReferralReward>,
+    pub referee_reward: Option<ReferralReward>,
+    // Referral credit lapses after this many days; None keeps it
+    pub credit_expiry_days: Option<u32>,
+    // Referrals past this many rewarded ones go to review instead of paying out
+    pub max_rewarded_per_referrer: u32,
+}
+
+impl Default for ReferralConfig {
+    fn default() -> Self {
+        ReferralConfig {
+            enabled: true,
+            referrer_reward: Some(ReferralReward::Credit(Money::from_units(10, BASE_CURRENCY))),
+            referee_reward: Some(ReferralReward::Credit(Money::from_units(5, BASE_CURRENCY))),
+            credit_expiry_days: Some(90),
+            max_rewarded_per_referrer: 20,
+        }
+    }
+}
+
+// Signs that the referrer and referee may be the same person
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ReferralFlag {
+    SharedPhone,
+    SharedEmail,
+    // The qualifying shipment went to the referrer
+    ShippedToReferrer,
+    ReferrerLimitReached,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ReferralStatus {
+    // Waiting for the referee's first paid delivery
+    Pending,
+    UnderReview,
+    Rewarded,
+    Rejected(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Referral {
+    pub referrer: Principal,
+    pub referee: Principal,
+    pub code: String,
+    pub status: ReferralStatus,
+    pub flags: Vec<ReferralFlag>,
+    pub qualifying_shipment_id: Option<String>,
+    pub registered_at: u64,
+    pub resolved_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ReferralSummary {
+    pub code: String,
+    pub referrals: Vec<Referral>,
+}
+
+thread_local! {
+    static CONFIG: RefCell<ReferralConfig> = RefCell::new(ReferralConfig::default());
+    static CODES: RefCell<HashMap<String, Principal>> = RefCell::new(HashMap::new());
+    static CODE_OF: RefCell<HashMap<Principal, String>> = RefCell::new(HashMap::new());
+    // Keyed by referee; a user is referred at most once
+    static REFERRALS: RefCell<HashMap<Principal, Referral>> = RefCell::new(HashMap::new());
+}
+
+// Admin configuration
+#[update]
+fn set_referral_config(config: ReferralConfig) -> Result<ReferralConfig, String> {
+    metrics::observe("set_referral_config", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManageFinances)?;
+        for reward in config.referrer_reward.iter().chain(config.referee_reward.iter()) {
+            match reward {
+                ReferralReward::Credit(m) if m.currency != BASE_CURRENCY || m.is_zero() => {
+                    return Err(format!("Credit rewards must be positive amounts in {}", BASE_CURRENCY.symbol()));
+                },
+                ReferralReward::Points(0) => return Err("Points rewards must be positive".to_string()),
+                _ => {},
+            }
+        }
+        CONFIG.with(|c| *c.borrow_mut() = config.clone());
+        Ok(config)
+    })
+}
+
+#[query]
+fn get_referral_config() -> ReferralConfig {
+    CONFIG.with(|c| c.borrow().clone())
+}
+
+// The caller's code, created on first request, with everyone who signed up with it
+#[update]
+fn get_my_referrals() -> Result<ReferralSummary, String> {
+    metrics::observe("get_my_referrals", || {
+        let caller = ic_cdk::caller();
+        if !USERS.with(|users| users.borrow().contains_key(&caller)) {
+            return Err("User not registered".to_string());
+        }
+        let code = code_for(caller);
+        let mut referrals: Vec<Referral> = REFERRALS.with(|r| {
+            r.borrow()
+                .values()
+                .filter(|r| r.referrer == caller)
+                .cloned()
+                .collect()
+        });
+        referrals.sort_by_key(|r| r.registered_at);
+        Ok(ReferralSummary { code, referrals })
+    })
+}
+
+#[query]
+fn get_referrals_under_review() -> Result<Vec<Referral>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ReviewCompliance)?;
+    Ok(REFERRALS.with(|r| {
+        r.borrow()
+            .values()
+            .filter(|r| r.status == ReferralStatus::UnderReview)
+            .cloned()
+            .collect()
+    }))
+}
+
+#[update]
+fn approve_referral(referee: Principal) -> Result<Referral, String> {
+    metrics::observe("approve_referral", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ReviewCompliance)?;
+        let referral = under_review(referee)?;
+        Ok(reward(referral))
+    })
+}
+
+#[update]
+fn reject_referral(referee: Principal, reason: String) -> Result<Referral, String> {
+    metrics::observe("reject_referral", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ReviewCompliance)?;
+        let mut referral = under_review(referee)?;
+        referral.status = ReferralStatus::Rejected(reason);
+        referral.resolved_at = Some(time());
+        REFERRALS.with(|r| r.borrow_mut().insert(referee, referral.clone()));
+        Ok(referral)
+    })
+}
+
+// Registration with a code; checked before the new user is stored so a bad code
+// fails the registration
+pub(crate) fn attribute(referee: Principal, phone: &str, email: &str, code: &str) -> Result<(), String> {
+    if !CONFIG.with(|c| c.borrow().enabled) {
+        return Err("Referrals are not available".to_string());
+    }
+    let code = code.trim().to_uppercase();
+    let referrer = CODES
+        .with(|codes| codes.borrow().get(&code).copied())
+        .ok_or_else(|| "Invalid referral code".to_string())?;
+    if referrer == referee {
+        return Err("You cannot refer yourself".to_string());
+    }
+    let mut flags = Vec::new();
+    if let Some(user) = USERS.with(|users| users.borrow().get(&referrer).cloned()) {
+        if validation::normalize_phone(&user.phone) == validation::normalize_phone(phone) {
+            flags.push(ReferralFlag::SharedPhone);
+        }
+        if user.email.trim().eq_ignore_ascii_case(email.trim()) {
+            flags.push(ReferralFlag::SharedEmail);
+        }
+    }
+    REFERRALS.with(|r| {
+        r.borrow_mut().insert(
+            referee,
+            Referral {
+                referrer,
+                referee,
+                code,
+                status: ReferralStatus::Pending,
+                flags,
+                qualifying_shipment_id: None,
+                registered_at: time(),
+                resolved_at: None,
+            },
+        )
+    });
+    Ok(())
+}
+
+// Called on every delivery; the referee's first paid one handed over by a driver
+// or confirmed by its recipient settles the referral
+pub(crate) fn on_delivered(shipment: &Shipment) {
+    if !matches!(shipment.payment_status, PaymentStatus::Paid) || !carrier_delivered(shipment) {
+        return;
+    }
+    let Some(mut referral) = REFERRALS.with(|r| r.borrow().get(&shipment.sender_id).cloned()) else {
+        return;
+    };
+    if referral.status != ReferralStatus::Pending {
+        return;
+    }
+    referral.qualifying_shipment_id = Some(shipment.id.clone());
+    let referrer = USERS.with(|users| users.borrow().get(&referral.referrer).cloned());
+    if shipment.recipient_id == Some(referral.referrer)
+        || referrer.is_some_and(|u| pii::is_recipient_phone(shipment, &u.phone))
+    {
+        referral.flags.push(ReferralFlag::ShippedToReferrer);
+    }
+    let rewarded = REFERRALS.with(|r| {
+        r.borrow()
+            .values()
+            .filter(|r| r.referrer == referral.referrer && r.status == ReferralStatus::Rewarded)
+            .count() as u32
+    });
+    if rewarded >= CONFIG.with(|c| c.borrow().max_rewarded_per_referrer) {
+        referral.flags.push(ReferralFlag::ReferrerLimitReached);
+    }
+
+    if referral.flags.is_empty() {
+        reward(referral);
+    } else {
+        referral.status = ReferralStatus::UnderReview;
+        REFERRALS.with(|r| r.borrow_mut().insert(referral.referee, referral));
+    }
+}
+
+fn under_review(referee: Principal) -> Result<Referral, String> {
+    REFERRALS
+        .with(|r| r.borrow().get(&referee).cloned())
+        .filter(|r| r.status == ReferralStatus::UnderReview)
+        .ok_or_else(|| "No referral under review for this user".to_string())
+}
+
+fn reward(mut referral: Referral) -> Referral {
+    let config = CONFIG.with(|c| c.borrow().clone());
+    let reference = format!("Referral of {}", referral.referee.to_text());
+    let grants = [
+        (referral.referrer, config.referrer_reward.clone()),
+        (referral.referee, config.referee_reward.clone()),
+    ];
+    for (owner, reward) in grants {
+        let message = match reward {
+            Some(ReferralReward::Credit(amount)) => {
+                let expires_at = config.credit_expiry_days.map(|d| time() + d as u64 * NANOS_PER_DAY);
+                credits::grant(owner, CreditSource::Referral, amount, expires_at, Some(reference.clone()));
+                format!("You received {} {} referral credit", amount.to_decimal(), BASE_CURRENCY.symbol())
+            },
+            Some(ReferralReward::Points(points)) => {
+                loyalty::grant(owner, points, reference.clone());
+                format!("You received {} referral points", points)
+            },
+            None => continue,
+        };
+        notifications::notify(owner, NotificationKind::Payment, None, message);
+    }
+    referral.status = ReferralStatus::Rewarded;
+    referral.resolved_at = Some(time());
+    REFERRALS.with(|r| r.borrow_mut().insert(referral.referee, referral.clone()));
+    referral
+}
+
+// Derived from the principal, lengthened on the rare collision
+fn code_for(owner: Principal) -> String {
+    if let Some(code) = CODE_OF.with(|c| c.borrow().get(&owner).cloned()) {
+        return code;
+    }
+    let digest: String = Sha256::digest(owner.as_slice()).iter().map(|b| format!("{:02X}", b)).collect();
+    let code = (8..=digest.len())
+        .step_by(2)
+        .map(|len| digest[..len].to_string())
+        .find(|code| !CODES.with(|codes| codes.borrow().contains_key(code)))
+        .unwrap_or(digest);
+    CODES.with(|codes| codes.borrow_mut().insert(code.clone(), owner));
+    CODE_OF.with(|c| c.borrow_mut().insert(owner, code.clone()));
+    code
+}
@@ -0,0 +1,357 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+
+use crate::event_store::{self, ShipmentEvent};
+use crate::fees;
+use crate::loyalty;
+use crate::metrics;
+use crate::money::Money;
+use crate::notifications::{self, NotificationKind};
+use crate::payments::{self, PaymentRecord};
+use crate::permissions::{self, Permission};
+use crate::tracking;
+use crate::validation::{self, Validator};
+use crate::{idempotency, PaymentStatus, ReturnStatus, Shipment, ShipmentStatus, RETURN_REQUESTS, SHIPMENTS};
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum AdjustmentReason {
+    // Full refunds
+    Cancellation,
+    // Id of the approved return request
+    Return(String),
+    // Partial refunds
+    LateDeliveryCredit,
+    DamageCompensation,
+    // Refund of a cancelled shipment less the fee the platform keeps
+    CancellationFeeRetained { fee: Money },
+    // The shipment got cheaper, e.g. after its delivery address changed
+    PriceReduced,
+    Other(String),
+}
+
+// One refund out of a shipment's escrow. The paid amount less every adjustment's
+// amount and ledger fee is what the platform keeps.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Adjustment {
+    pub id: u32,
+    pub reason: AdjustmentReason,
+    // What reached the sender
+    pub amount: Money,
+    // Charged to the escrow by the ledger for the transfer
+    pub ledger_fee: Money,
+    // None when nothing was paid on the ledger
+    pub block_index: Option<Nat>,
+    pub note: Option<String>,
+    pub created_by: Principal,
+    pub created_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PaymentSummary {
+    pub shipment_id: String,
+    pub paid: Money,
+    pub refunded: Money,
+    pub ledger_fees: Money,
+    pub net_paid: Money,
+    pub adjustments: Vec<Adjustment>,
+}
+
+// Pay everything left in escrow back to the sender of a cancelled shipment or one
+// with an approved return. The ledger fee for the transfer is deducted from it.
+#[update]
+async fn process_refund(shipment_id: String, idempotency_key: Option<String>) -> Result<Shipment, String> {
+    metrics::observe_async("process_refund", async move {
+        let caller = ic_cdk::caller();
+        if let Some(shipment) = idempotency::begin(caller, idempotency_key.as_deref(), "process_refund")? {
+            return Ok(shipment);
+        }
+        let result = refund(caller, &shipment_id, None, None, None).await;
+        idempotency::finish(caller, idempotency_key.as_deref(), &result);
+        result
+    })
+    .await
+}
+
+// Admin refund of part of the payment: a late delivery credit, damage
+// compensation, or a cancellation refund that keeps a fee
+#[update]
+async fn process_partial_refund(
+    shipment_id: String,
+    amount: Money,
+    reason: AdjustmentReason,
+    note: Option<String>,
+    idempotency_key: Option<String>,
+) -> Result<Shipment, String> {
+    metrics::observe_async("process_partial_refund", async move {
+        let caller = ic_cdk::caller();
+        if let Some(shipment) = idempotency::begin(caller, idempotency_key.as_deref(), "process_partial_refund")? {
+            return Ok(shipment);
+        }
+        let result = refund(caller, &shipment_id, Some(amount), Some(reason), note).await;
+        idempotency::finish(caller, idempotency_key.as_deref(), &result);
+        result
+    })
+    .await
+}
+
+#[query]
+fn get_payment_summary(shipment_id: String) -> Result<PaymentSummary, String> {
+    let caller = ic_cdk::caller();
+    let shipment = SHIPMENTS
+        .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    if shipment.sender_id != caller && !permissions::has(&caller, Permission::ManageFinances) {
+        return Err("Unauthorized to view payment".to_string());
+    }
+    let payment = shipment.payment.as_ref().ok_or_else(|| "Shipment has not been paid".to_string())?;
+    let adjustments = shipment.adjustments.clone().unwrap_or_default();
+    let currency = payment.amount.currency;
+    let refunded = Money::sum(adjustments.iter().map(|a| a.amount), currency);
+    let ledger_fees = Money::sum(adjustments.iter().map(|a| a.ledger_fee), currency);
+    Ok(PaymentSummary {
+        shipment_id,
+        paid: payment.amount,
+        net_paid: payment.amount.saturating_sub(refunded).saturating_sub(ledger_fees),
+        refunded,
+        ledger_fees,
+        adjustments,
+    })
+}
+
+// Full refunds pass no amount and take their reason from the shipment's state
+async fn refund(
+    caller: Principal,
+    shipment_id: &str,
+    amount: Option<Money>,
+    reason: Option<AdjustmentReason>,
+    note: Option<String>,
+) -> Result<Shipment, String> {
+    if amount.is_some() && !permissions::has(&caller, Permission::ManageFinances) {
+        return Err("Unauthorized to issue partial refunds".to_string());
+    }
+    if let Some(note) = &note {
+        let mut v = Validator::new();
+        v.max_len("note", note, validation::MAX_TEXT_LEN);
+        v.finish()?;
+    }
+    let shipment = SHIPMENTS
+        .with(|shipments| shipments.borrow().get(shipment_id).cloned())
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    if shipment.sender_id != caller && !permissions::has(&caller, Permission::ManageFinances) {
+        return Err("Unauthorized to refund shipment".to_string());
+    }
+    let reason = match reason {
+        Some(reason) => validate_reason(&shipment, reason)?,
+        None => full_refund_reason(&shipment)?,
+    };
+    issue(caller, shipment, amount, reason, note).await
+}
+
+// Pay the sender back what a change took off the price. Authorized by the change.
+pub(crate) async fn refund_difference(caller: Principal, shipment_id: &str, amount: Money) -> Result<Shipment, String> {
+    let shipment = SHIPMENTS
+        .with(|shipments| shipments.borrow().get(shipment_id).cloned())
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    issue(caller, shipment, Some(amount), AdjustmentReason::PriceReduced, None).await
+}
+
+async fn issue(
+    caller: Principal,
+    shipment: Shipment,
+    amount: Option<Money>,
+    reason: AdjustmentReason,
+    note: Option<String>,
+) -> Result<Shipment, String> {
+    let shipment_id = shipment.id.as_str();
+    match shipment.payment_status {
+        PaymentStatus::Paid => {},
+        PaymentStatus::Refunded => return Err("Shipment has already been refunded".to_string()),
+        _ => return Err("Shipment has not been paid".to_string()),
+    }
+    let payment = shipment
+        .payment
+        .clone()
+        .ok_or_else(|| "Shipment was not paid on the ledger".to_string())?;
+
+    // One transfer out of the escrow at a time, so a concurrent refund or fee
+    // sweep cannot overdraw it
+    let lock = payments::lock(payments::escrow_lock_key(shipment_id))
+        .ok_or_else(|| "A refund for this shipment is already in progress".to_string())?;
+    let result = transfer(&shipment, &payment, amount, &reason).await;
+    drop(lock);
+    let (amount, ledger_fee, block_index) = result?;
+
+    SHIPMENTS.with(|shipments| {
+        let mut shipments_map = shipments.borrow_mut();
+        let shipment = shipments_map
+            .get_mut(shipment_id)
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        let closes_payment = !matches!(
+            reason,
+            AdjustmentReason::LateDeliveryCredit
+                | AdjustmentReason::DamageCompensation
+                | AdjustmentReason::PriceReduced
+                | AdjustmentReason::Other(_)
+        );
+        let adjustments = shipment.adjustments.get_or_insert_with(Vec::new);
+        let adjustment = Adjustment {
+            id: adjustments.len() as u32 + 1,
+            reason,
+            amount,
+            ledger_fee,
+            block_index,
+            note,
+            created_by: caller,
+            created_at: time(),
+        };
+        adjustments.push(adjustment.clone());
+        if closes_payment || remaining(shipment, &payment).is_zero() {
+            shipment.payment_status = PaymentStatus::Refunded;
+            loyalty::revoke(shipment, "refunded");
+        }
+        shipment.touch(time());
+        let event = ShipmentEvent::RefundIssued {
+            adjustment,
+            payment_status: shipment.payment_status.clone(),
+        };
+        event_store::record(shipment, caller, event);
+        notifications::notify_parties(
+            shipment,
+            caller,
+            NotificationKind::Payment,
+            format!(
+                "Refund of {:.2} {} issued for shipment {}",
+                amount.to_decimal(),
+                amount.currency.symbol(),
+                shipment.id
+            ),
+        );
+        Ok(shipment.clone())
+    })
+}
+
+fn full_refund_reason(shipment: &Shipment) -> Result<AdjustmentReason, String> {
+    if matches!(shipment.status, ShipmentStatus::Cancelled) {
+        // A parcel that was carried or handed over has cost the service; support settles
+        // those with a partial refund
+        if was_picked_up(shipment) {
+            return Err("Shipments cancelled after pickup cannot be refunded in full".to_string());
+        }
+        return Ok(AdjustmentReason::Cancellation);
+    }
+    approved_return(shipment)
+        .map(AdjustmentReason::Return)
+        .ok_or_else(|| "Only cancelled shipments or approved returns can be refunded".to_string())
+}
+
+fn was_picked_up(shipment: &Shipment) -> bool {
+    tracking::with_events(shipment, |events| {
+        events.iter().any(|e| {
+            matches!(
+                e.status,
+                ShipmentStatus::PickedUp
+                    | ShipmentStatus::InTransit
+                    | ShipmentStatus::OutForDelivery
+                    | ShipmentStatus::AtPickupPoint
+                    | ShipmentStatus::AwaitingConfirmation
+                    | ShipmentStatus::Delivered
+            )
+        })
+    })
+}
+
+fn validate_reason(shipment: &Shipment, reason: AdjustmentReason) -> Result<AdjustmentReason, String> {
+    match &reason {
+        AdjustmentReason::Cancellation | AdjustmentReason::Return(_) => {
+            Err("Use process_refund for full refunds".to_string())
+        },
+        AdjustmentReason::PriceReduced => Err("Price reductions are refunded by the change that made them".to_string()),
+        AdjustmentReason::CancellationFeeRetained { fee } => {
+            if !matches!(shipment.status, ShipmentStatus::Cancelled) {
+                return Err("Cancellation fees apply to cancelled shipments".to_string());
+            }
+            if fee.is_zero() {
+                return Err("Cancellation fee must be positive".to_string());
+            }
+            Ok(reason)
+        },
+        AdjustmentReason::Other(text) => {
+            let mut v = Validator::new();
+            v.required("reason", text, validation::MAX_NAME_LEN);
+            v.finish()?;
+            Ok(reason)
+        },
+        AdjustmentReason::LateDeliveryCredit | AdjustmentReason::DamageCompensation => Ok(reason),
+    }
+}
+
+fn approved_return(shipment: &Shipment) -> Option<String> {
+    RETURN_REQUESTS.with(|returns| {
+        returns
+            .borrow()
+            .values()
+            .find(|r| {
+                r.shipment_id == shipment.id
+                    && matches!(r.status, ReturnStatus::Approved | ReturnStatus::InProgress | ReturnStatus::Completed)
+            })
+            .map(|r| r.id.clone())
+    })
+}
+
+// Still in escrow: the payment less earlier refunds and their ledger fees, and
+// any platform fee taken on delivery
+fn remaining(shipment: &Shipment, payment: &PaymentRecord) -> Money {
+    shipment
+        .adjustments
+        .iter()
+        .flatten()
+        .fold(payment.amount, |left, a| left.saturating_sub(a.amount).saturating_sub(a.ledger_fee))
+        .saturating_sub(fees::reserved(&shipment.id, payment.amount.currency))
+}
+
+// Returns the amount sent, the ledger fee paid and the block index
+async fn transfer(
+    shipment: &Shipment,
+    payment: &PaymentRecord,
+    requested: Option<Money>,
+    reason: &AdjustmentReason,
+) -> Result<(Money, Money, Option<Nat>), String> {
+    let left = remaining(shipment, payment);
+    let currency = payment.amount.currency;
+    if let Some(amount) = &requested {
+        if amount.currency != currency || amount.is_zero() {
+            return Err(format!("Refunds must be a positive amount in {:?}", currency));
+        }
+    }
+    if let AdjustmentReason::CancellationFeeRetained { fee } = reason {
+        if fee.currency != currency {
+            return Err(format!("Cancellation fee must be in {:?}", currency));
+        }
+    }
+    if payment.ledger.is_none() || payment.amount.is_zero() {
+        return Ok((Money::zero(currency), Money::zero(currency), None));
+    }
+
+    let ledger = payments::ledger()?;
+    if Some(ledger.canister_id) != payment.ledger {
+        return Err("Shipment was paid on a different ledger".to_string());
+    }
+    let fee = payments::transfer_fee(&ledger).await?;
+    // Full refunds send what is left after the fee; a retained cancellation fee
+    // comes off first
+    let amount = match (requested, reason) {
+        (_, AdjustmentReason::CancellationFeeRetained { fee: kept }) => left.saturating_sub(*kept).saturating_sub(fee),
+        (Some(amount), _) => amount,
+        (None, _) => left.saturating_sub(fee),
+    };
+    if amount.is_zero() || amount.add(fee).amount_e8s > left.amount_e8s {
+        return Err("Refund exceeds what remains of the payment after the ledger fee".to_string());
+    }
+
+    let memo = format!("refund:{}", shipment.id).into_bytes();
+    let block_index = payments::send(&ledger, payment.escrow_subaccount.clone(), shipment.sender_id, amount, memo)
+        .await
+        .map_err(|failure| failure.message())?;
+    Ok((amount, fee, Some(block_index)))
+}
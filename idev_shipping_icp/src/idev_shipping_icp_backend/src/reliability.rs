@@ -0,0 +1,254 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::event_store;
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::offers::{self, OfferStatus};
+use crate::permissions::{self, Permission};
+use crate::tracking;
+use crate::validation::{Validator, MAX_TEXT_LEN};
+use crate::{ShipmentStatus, TrackingEvent, DRIVERS, SHIPMENTS};
+
+const NANOS_PER_MIN: u64 = 60 * 1_000_000_000;
+const NANOS_PER_DAY: u64 = 24 * 60 * NANOS_PER_MIN;
+
+// Consequences for drivers who turn down most offers or drop jobs they accepted
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ReliabilityPolicy {
+    // Offers and cancellations count for this long
+    pub window_days: u64,
+    // The acceptance rate is only judged after this many answered offers
+    pub min_answered_offers: u32,
+    pub min_acceptance_bps: u32,
+    // Matching skips the driver this long after falling below the rate
+    pub low_acceptance_cooldown_mins: u64,
+    pub cancellation_cooldown_mins: u64,
+    // Taken off the driver's rating per cancellation
+    pub cancellation_rating_penalty: f64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Cancellation {
+    pub shipment_id: String,
+    pub reason: String,
+    pub cancelled_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Cooldown {
+    pub until: u64,
+    pub reason: String,
+}
+
+// What the driver sees, with the policy so the consequences are known up front
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DriverReliability {
+    pub driver_id: Principal,
+    pub offers_received: u32,
+    pub offers_accepted: u32,
+    pub offers_declined: u32,
+    pub offers_expired: u32,
+    // None until enough offers were answered
+    pub acceptance_bps: Option<u32>,
+    pub cancellations: Vec<Cancellation>,
+    pub rating: f64,
+    pub cooldown: Option<Cooldown>,
+    pub policy: ReliabilityPolicy,
+}
+
+thread_local! {
+    static POLICY: RefCell<ReliabilityPolicy> = RefCell::new(ReliabilityPolicy {
+        window_days: 30,
+        min_answered_offers: 10,
+        min_acceptance_bps: 5_000,
+        low_acceptance_cooldown_mins: 60,
+        cancellation_cooldown_mins: 120,
+        cancellation_rating_penalty: 0.1,
+    });
+    static CANCELLATIONS: RefCell<HashMap<Principal, Vec<Cancellation>>> = RefCell::new(HashMap::new());
+    static COOLDOWNS: RefCell<HashMap<Principal, Cooldown>> = RefCell::new(HashMap::new());
+}
+
+// Admin configuration
+#[update]
+fn set_reliability_policy(policy: ReliabilityPolicy) -> Result<ReliabilityPolicy, String> {
+    metrics::observe("set_reliability_policy", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if policy.window_days == 0 {
+            return Err("Window must be positive".to_string());
+        }
+        if policy.min_acceptance_bps > 10_000 {
+            return Err("Minimum acceptance rate cannot exceed 10000 bps".to_string());
+        }
+        if !policy.cancellation_rating_penalty.is_finite() || policy.cancellation_rating_penalty < 0.0 {
+            return Err("Rating penalty must be finite and non-negative".to_string());
+        }
+        POLICY.with(|p| *p.borrow_mut() = policy.clone());
+        Ok(policy)
+    })
+}
+
+#[query]
+fn get_reliability_policy() -> ReliabilityPolicy {
+    POLICY.with(|p| p.borrow().clone())
+}
+
+#[query]
+fn get_my_reliability() -> Result<DriverReliability, String> {
+    let caller = ic_cdk::caller();
+    if !DRIVERS.with(|drivers| drivers.borrow().contains_key(&caller)) {
+        return Err("Driver not found".to_string());
+    }
+    Ok(reliability_of(caller, time()))
+}
+
+#[query]
+fn get_driver_reliability(driver_id: Principal) -> Result<DriverReliability, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ViewReports)?;
+    if !DRIVERS.with(|drivers| drivers.borrow().contains_key(&driver_id)) {
+        return Err("Driver not found".to_string());
+    }
+    Ok(reliability_of(driver_id, time()))
+}
+
+// The assigned driver gives a shipment back before pickup. It goes back to
+// matching, and the driver takes the cancellation cooldown and rating penalty.
+#[update]
+fn cancel_accepted_delivery(shipment_id: String, reason: String) -> Result<DriverReliability, String> {
+    metrics::observe("cancel_accepted_delivery", || {
+        let caller = ic_cdk::caller();
+        let reason = reason.trim().to_string();
+        if reason.is_empty() {
+            return Err("A reason is required".to_string());
+        }
+        let mut v = Validator::new();
+        v.max_len("reason", &reason, MAX_TEXT_LEN);
+        v.finish()?;
+
+        let now = time();
+        let sender_id = SHIPMENTS.with(|shipments| {
+            let mut shipments = shipments.borrow_mut();
+            let shipment = shipments
+                .get_mut(&shipment_id)
+                .ok_or_else(|| "Shipment not found".to_string())?;
+            if shipment.driver_id != Some(caller) {
+                return Err("Only the assigned driver can cancel".to_string());
+            }
+            if !matches!(shipment.status, ShipmentStatus::PickupScheduled) {
+                return Err("Deliveries can only be cancelled before pickup".to_string());
+            }
+            shipment.driver_id = None;
+            shipment.status = ShipmentStatus::Created;
+            shipment.touch(now);
+            tracking::record(shipment, TrackingEvent {
+                timestamp: now,
+                status: ShipmentStatus::Created,
+                location: None,
+                description: "Driver cancelled the pickup; finding another driver".to_string(),
+                updated_by: caller,
+                kind: None,
+            });
+            event_store::driver_assigned(shipment, caller);
+            Ok(shipment.sender_id)
+        })?;
+
+        let policy = POLICY.with(|p| p.borrow().clone());
+        CANCELLATIONS.with(|c| {
+            c.borrow_mut().entry(caller).or_default().push(Cancellation {
+                shipment_id: shipment_id.clone(),
+                reason,
+                cancelled_at: now,
+            })
+        });
+        DRIVERS.with(|drivers| {
+            if let Some(driver) = drivers.borrow_mut().get_mut(&caller) {
+                driver.rating = (driver.rating - policy.cancellation_rating_penalty).max(0.0);
+            }
+        });
+        start_cooldown(
+            caller,
+            now,
+            policy.cancellation_cooldown_mins,
+            format!("Cancelled shipment {} after accepting it", shipment_id),
+        );
+
+        notifications::notify(
+            sender_id,
+            NotificationKind::Assignment,
+            Some(&shipment_id),
+            format!("The driver cancelled the pickup of shipment {}; finding another driver", shipment_id),
+        );
+        offers::offer_next_for(&shipment_id);
+        Ok(reliability_of(caller, now))
+    })
+}
+
+// Called when a driver declines or lets an offer expire
+pub(crate) fn check_acceptance(driver_id: Principal) {
+    let now = time();
+    let policy = POLICY.with(|p| p.borrow().clone());
+    if let Some(rate) = reliability_of(driver_id, now).acceptance_bps {
+        if rate < policy.min_acceptance_bps {
+            start_cooldown(
+                driver_id,
+                now,
+                policy.low_acceptance_cooldown_mins,
+                format!("Acceptance rate {} bps is below {} bps", rate, policy.min_acceptance_bps),
+            );
+        }
+    }
+}
+
+// Matching skips drivers in a cooldown
+pub(crate) fn cooldown(driver_id: &Principal, now: u64) -> Option<Cooldown> {
+    COOLDOWNS.with(|c| c.borrow().get(driver_id).filter(|c| c.until > now).cloned())
+}
+
+// A running longer cooldown is kept
+fn start_cooldown(driver_id: Principal, now: u64, minutes: u64, reason: String) {
+    if minutes == 0 {
+        return;
+    }
+    let until = now.saturating_add(minutes.saturating_mul(NANOS_PER_MIN));
+    COOLDOWNS.with(|c| {
+        let mut cooldowns = c.borrow_mut();
+        if cooldowns.get(&driver_id).is_none_or(|c| c.until < until) {
+            cooldowns.insert(driver_id, Cooldown { until, reason });
+        }
+    });
+}
+
+fn reliability_of(driver_id: Principal, now: u64) -> DriverReliability {
+    let policy = POLICY.with(|p| p.borrow().clone());
+    let from = now.saturating_sub(policy.window_days.saturating_mul(NANOS_PER_DAY));
+    let offers = offers::offers_to(&driver_id, from, now.saturating_add(1));
+    let count = |status: OfferStatus| offers.iter().filter(|o| o.status == status).count() as u32;
+    let accepted = count(OfferStatus::Accepted);
+    let declined = count(OfferStatus::Declined);
+    let expired = count(OfferStatus::Expired);
+    let answered = accepted + declined + expired;
+    let cancellations = CANCELLATIONS.with(|c| {
+        c.borrow()
+            .get(&driver_id)
+            .map(|list| list.iter().filter(|c| c.cancelled_at >= from).cloned().collect())
+            .unwrap_or_default()
+    });
+    DriverReliability {
+        driver_id,
+        offers_received: offers.len() as u32,
+        offers_accepted: accepted,
+        offers_declined: declined,
+        offers_expired: expired,
+        acceptance_bps: (answered > 0 && answered >= policy.min_answered_offers).then(|| accepted * 10_000 / answered),
+        cancellations,
+        rating: DRIVERS.with(|drivers| drivers.borrow().get(&driver_id).map_or(0.0, |d| d.rating)),
+        cooldown: cooldown(&driver_id, now),
+        policy,
+    }
+}
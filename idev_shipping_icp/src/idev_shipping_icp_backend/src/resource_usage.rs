@@ -0,0 +1,194 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::permissions::{self, Permission};
+use crate::{SHIPMENTS};
+
+// HTTPS outcall pricing on a 13-node application subnet
+const SUBNET_SIZE: u128 = 13;
+const OUTCALL_BASE_CYCLES: u128 = 3_000_000;
+const OUTCALL_PER_NODE_CYCLES: u128 = 60_000;
+const OUTCALL_REQUEST_BYTE_CYCLES: u128 = 400;
+const OUTCALL_RESPONSE_BYTE_CYCLES: u128 = 800;
+// Cost of an inter-canister call excluding payload bytes
+const XNET_CALL_CYCLES: u128 = 260_000;
+const XNET_BYTE_CYCLES: u128 = 1_000;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub enum ResourceFeature {
+    WebhookOutcall,
+    EventBusCall,
+    Notification,
+    UpdateInstructions,
+    ExchangeRateCall,
+    VerificationOutcall,
+    Signature,
+    NftMint,
+    KeyDerivation,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct FeatureUsage {
+    pub count: u64,
+    pub cycles: u128,
+    pub instructions: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FeatureUsageEntry {
+    pub feature: ResourceFeature,
+    pub usage: FeatureUsage,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ResourceReport {
+    pub account: Principal,
+    pub shipment_count: u32,
+    pub storage_bytes: u64,
+    pub total_cycles: u128,
+    pub features: Vec<FeatureUsageEntry>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShipmentResourceUsage {
+    pub shipment_id: String,
+    pub storage_bytes: u64,
+    pub features: Vec<FeatureUsageEntry>,
+}
+
+thread_local! {
+    static ACCOUNT_USAGE: RefCell<HashMap<Principal, HashMap<ResourceFeature, FeatureUsage>>> = RefCell::new(HashMap::new());
+    static SHIPMENT_USAGE: RefCell<HashMap<String, HashMap<ResourceFeature, FeatureUsage>>> = RefCell::new(HashMap::new());
+}
+
+// Callers see their own usage; admins may look at any account
+#[query]
+fn get_resource_report(account: Option<Principal>) -> Result<ResourceReport, String> {
+    let caller = ic_cdk::caller();
+    let account = account.unwrap_or(caller);
+    if account != caller && !permissions::has(&caller, Permission::ViewReports) {
+        return Err("Unauthorized to view resource usage".to_string());
+    }
+    Ok(build_report(account))
+}
+
+#[query]
+fn get_all_resource_reports() -> Result<Vec<ResourceReport>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ViewReports)?;
+
+    let accounts: Vec<Principal> = ACCOUNT_USAGE.with(|usage| usage.borrow().keys().cloned().collect());
+    let mut reports: Vec<ResourceReport> = accounts.into_iter().map(build_report).collect();
+    reports.sort_by_key(|r| std::cmp::Reverse(r.total_cycles));
+    Ok(reports)
+}
+
+#[query]
+fn get_shipment_resource_usage(shipment_id: String) -> Result<ShipmentResourceUsage, String> {
+    let caller = ic_cdk::caller();
+    let shipment = SHIPMENTS
+        .with(|shipments| shipments.borrow().get(&shipment_id).cloned())
+        .ok_or_else(|| "Shipment not found".to_string())?;
+    if shipment.sender_id != caller && !permissions::has(&caller, Permission::ViewReports) {
+        return Err("Unauthorized to view resource usage".to_string());
+    }
+
+    let features = SHIPMENT_USAGE.with(|usage| {
+        usage
+            .borrow()
+            .get(&shipment_id)
+            .map(to_entries)
+            .unwrap_or_default()
+    });
+    Ok(ShipmentResourceUsage {
+        shipment_id,
+        storage_bytes: encoded_size(&shipment),
+        features,
+    })
+}
+
+pub(crate) fn record(account: Principal, shipment_id: Option<&str>, feature: ResourceFeature, cycles: u128, instructions: u64) {
+    let add = |u: &mut FeatureUsage| {
+        u.count += 1;
+        u.cycles += cycles;
+        u.instructions += instructions;
+    };
+
+    ACCOUNT_USAGE.with(|usage| {
+        add(usage
+            .borrow_mut()
+            .entry(account)
+            .or_default()
+            .entry(feature.clone())
+            .or_default());
+    });
+    if let Some(shipment_id) = shipment_id {
+        SHIPMENT_USAGE.with(|usage| {
+            add(usage
+                .borrow_mut()
+                .entry(shipment_id.to_string())
+                .or_default()
+                .entry(feature)
+                .or_default());
+        });
+    }
+}
+
+// Instructions spent so far in the current update call
+pub(crate) fn record_instructions(account: Principal, shipment_id: Option<&str>) {
+    let instructions = ic_cdk::api::performance_counter(0);
+    record(account, shipment_id, ResourceFeature::UpdateInstructions, 0, instructions);
+}
+
+pub(crate) fn outcall_cycles(request_bytes: u64, max_response_bytes: u64) -> u128 {
+    (OUTCALL_BASE_CYCLES
+        + OUTCALL_PER_NODE_CYCLES * SUBNET_SIZE
+        + OUTCALL_REQUEST_BYTE_CYCLES * request_bytes as u128
+        + OUTCALL_RESPONSE_BYTE_CYCLES * max_response_bytes as u128)
+        * SUBNET_SIZE
+}
+
+pub(crate) fn xnet_call_cycles(payload_bytes: u64) -> u128 {
+    XNET_CALL_CYCLES + XNET_BYTE_CYCLES * payload_bytes as u128
+}
+
+fn build_report(account: Principal) -> ResourceReport {
+    let features = ACCOUNT_USAGE.with(|usage| {
+        usage
+            .borrow()
+            .get(&account)
+            .map(to_entries)
+            .unwrap_or_default()
+    });
+    let (shipment_count, storage_bytes) = SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .values()
+            .filter(|s| s.sender_id == account)
+            .fold((0u32, 0u64), |(count, bytes), s| (count + 1, bytes + encoded_size(s)))
+    });
+
+    ResourceReport {
+        account,
+        shipment_count,
+        storage_bytes,
+        total_cycles: features.iter().map(|f| f.usage.cycles).sum(),
+        features,
+    }
+}
+
+fn to_entries(usage: &HashMap<ResourceFeature, FeatureUsage>) -> Vec<FeatureUsageEntry> {
+    usage
+        .iter()
+        .map(|(feature, usage)| FeatureUsageEntry {
+            feature: feature.clone(),
+            usage: usage.clone(),
+        })
+        .collect()
+}
+
+fn encoded_size<T: CandidType>(value: &T) -> u64 {
+    candid::encode_one(value).map(|b| b.len() as u64).unwrap_or(0)
+}
@@ -0,0 +1,317 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::delivery_preferences::{self, DeliveryPreferences, ProofKind};
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::vehicles;
+use crate::{capacity, Address, Coordinates, Shipment, ShipmentStatus, DRIVERS, SHIPMENTS};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+// Planning assumption for arrival estimates; speed comes from the vehicle type
+const SERVICE_SECS_PER_STOP: u64 = 5 * 60;
+// Keeps 2-opt within one message's instruction limit
+const MAX_ROUTE_STOPS: usize = 80;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum StopKind {
+    Pickup,
+    Delivery,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RouteStop {
+    pub shipment_id: String,
+    pub kind: StopKind,
+    pub location: Coordinates,
+    pub eta: u64,
+    // Deliveries must happen by the shipment's estimated delivery
+    pub deadline: Option<u64>,
+    pub late: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DriverRoute {
+    pub driver_id: Principal,
+    pub stops: Vec<RouteStop>,
+    pub total_distance_km: f64,
+    // Shipments left out because an address has no coordinates
+    pub unrouted: Vec<String>,
+    pub optimized_at: u64,
+}
+
+// One shipment the driver is carrying or about to collect, with how the
+// recipient wants it handed over
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ManifestEntry {
+    pub shipment_id: String,
+    pub short_code: Option<String>,
+    pub status: ShipmentStatus,
+    pub recipient_name: String,
+    pub delivery_address: Address,
+    pub deadline: u64,
+    pub preferences: Option<DeliveryPreferences>,
+    // Proof of delivery has to be recorded when there are preferences
+    pub accepted_proofs: Vec<ProofKind>,
+}
+
+thread_local! {
+    static DRIVER_ROUTES: RefCell<HashMap<Principal, DriverRoute>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Clone)]
+struct Stop {
+    shipment_id: String,
+    kind: StopKind,
+    location: Coordinates,
+    deadline: Option<u64>,
+}
+
+// Order the driver's open stops: nearest neighbour from the driver's position,
+// then 2-opt. Pickups always come before their delivery, and fewer late
+// arrivals win over a shorter distance.
+#[update]
+fn optimize_route(driver_id: Principal) -> Result<DriverRoute, String> {
+    metrics::observe("optimize_route", || {
+        let caller = ic_cdk::caller();
+        if caller != driver_id {
+            permissions::require(&caller, Permission::AssignDriver)?;
+        }
+        let driver = DRIVERS
+            .with(|drivers| drivers.borrow().get(&driver_id).cloned())
+            .ok_or_else(|| "Driver not registered".to_string())?;
+
+        let (stops, unrouted) = open_stops(driver_id);
+        if stops.len() > MAX_ROUTE_STOPS {
+            return Err(format!("Routes are limited to {} stops", MAX_ROUTE_STOPS));
+        }
+        let now = time();
+        let start = driver.current_location.or_else(|| stops.first().map(|s| s.location.clone()));
+        let speed_kmh = vehicles::profile(driver.vehicle_info.vehicle_type).average_speed_kmh;
+        let mut order = nearest_neighbour(&stops, start.as_ref());
+        two_opt(&stops, &mut order, start.as_ref(), now, speed_kmh);
+
+        let (_, total_distance_km) = cost(&stops, &order, start.as_ref(), now, speed_kmh);
+        let etas = arrivals(&stops, &order, start.as_ref(), now, speed_kmh);
+        let route = DriverRoute {
+            driver_id,
+            stops: order
+                .iter()
+                .zip(etas)
+                .map(|(&i, eta)| {
+                    let stop = &stops[i];
+                    RouteStop {
+                        shipment_id: stop.shipment_id.clone(),
+                        kind: stop.kind.clone(),
+                        location: stop.location.clone(),
+                        eta,
+                        deadline: stop.deadline,
+                        late: stop.deadline.is_some_and(|d| eta > d),
+                    }
+                })
+                .collect(),
+            total_distance_km,
+            unrouted,
+            optimized_at: now,
+        };
+        DRIVER_ROUTES.with(|r| r.borrow_mut().insert(driver_id, route.clone()));
+        Ok(route)
+    })
+}
+
+#[query]
+fn get_driver_route(driver_id: Principal) -> Result<Option<DriverRoute>, String> {
+    let caller = ic_cdk::caller();
+    if caller != driver_id && !permissions::has(&caller, Permission::AssignDriver) {
+        return Err("Unauthorized to view this route".to_string());
+    }
+    Ok(DRIVER_ROUTES.with(|r| r.borrow().get(&driver_id).cloned()))
+}
+
+// The driver's open shipments, most urgent first
+#[query]
+fn get_driver_manifest(driver_id: Principal) -> Result<Vec<ManifestEntry>, String> {
+    let caller = ic_cdk::caller();
+    if caller != driver_id && !permissions::has(&caller, Permission::AssignDriver) {
+        return Err("Unauthorized to view this manifest".to_string());
+    }
+    let mut entries: Vec<ManifestEntry> = SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .values()
+            .filter(|s| s.driver_id == Some(driver_id) && capacity::is_carrying(&s.status))
+            .map(|s| {
+                let preferences = delivery_preferences::for_shipment(s);
+                ManifestEntry {
+                    shipment_id: s.id.clone(),
+                    short_code: s.short_code.clone(),
+                    status: s.status.clone(),
+                    recipient_name: s.recipient_name.clone(),
+                    delivery_address: s.delivery_address.clone(),
+                    deadline: s.estimated_delivery.unwrap_or(s.sla_deadline),
+                    accepted_proofs: delivery_preferences::acceptable_proofs(&preferences.clone().unwrap_or_default()),
+                    preferences,
+                }
+            })
+            .collect()
+    });
+    entries.sort_by(|a, b| a.deadline.cmp(&b.deadline).then_with(|| a.shipment_id.cmp(&b.shipment_id)));
+    Ok(entries)
+}
+
+// Stops for shipments assigned directly to the driver; routed shipments are
+// planned per leg
+fn open_stops(driver_id: Principal) -> (Vec<Stop>, Vec<String>) {
+    let mut stops = Vec::new();
+    let mut unrouted = Vec::new();
+    SHIPMENTS.with(|shipments| {
+        let shipments = shipments.borrow();
+        let mut assigned: Vec<&Shipment> = shipments
+            .values()
+            .filter(|s| s.driver_id == Some(driver_id) && s.legs.is_none())
+            .collect();
+        assigned.sort_by(|a, b| a.id.cmp(&b.id));
+        for shipment in assigned {
+            let needs_pickup = matches!(shipment.status, ShipmentStatus::PickupScheduled);
+            let in_hand = matches!(
+                shipment.status,
+                ShipmentStatus::PickedUp | ShipmentStatus::InTransit | ShipmentStatus::OutForDelivery
+            );
+            if !needs_pickup && !in_hand {
+                continue;
+            }
+            let pickup = shipment.pickup_address.coordinates.clone();
+            let delivery = shipment.delivery_address.coordinates.clone();
+            let (Some(delivery), true) = (delivery, !needs_pickup || pickup.is_some()) else {
+                unrouted.push(shipment.id.clone());
+                continue;
+            };
+            if let (true, Some(pickup)) = (needs_pickup, pickup) {
+                stops.push(Stop {
+                    shipment_id: shipment.id.clone(),
+                    kind: StopKind::Pickup,
+                    location: pickup,
+                    deadline: None,
+                });
+            }
+            stops.push(Stop {
+                shipment_id: shipment.id.clone(),
+                kind: StopKind::Delivery,
+                location: delivery,
+                deadline: Some(shipment.estimated_delivery.unwrap_or(shipment.sla_deadline)),
+            });
+        }
+    });
+    (stops, unrouted)
+}
+
+fn nearest_neighbour(stops: &[Stop], start: Option<&Coordinates>) -> Vec<usize> {
+    let mut order = Vec::with_capacity(stops.len());
+    let mut visited = vec![false; stops.len()];
+    let mut at = start.cloned();
+    while order.len() < stops.len() {
+        let next = (0..stops.len())
+            .filter(|&i| !visited[i] && ready(stops, &visited, i))
+            .min_by(|&a, &b| {
+                let da = at.as_ref().map_or(0.0, |p| p.distance_km(&stops[a].location));
+                let db = at.as_ref().map_or(0.0, |p| p.distance_km(&stops[b].location));
+                da.total_cmp(&db)
+            })
+            .expect("a pickup is always ready before its delivery");
+        visited[next] = true;
+        at = Some(stops[next].location.clone());
+        order.push(next);
+    }
+    order
+}
+
+// A delivery becomes ready once its pickup, if any, has been visited
+fn ready(stops: &[Stop], visited: &[bool], i: usize) -> bool {
+    stops[i].kind == StopKind::Pickup
+        || !stops
+            .iter()
+            .enumerate()
+            .any(|(j, s)| s.kind == StopKind::Pickup && s.shipment_id == stops[i].shipment_id && !visited[j])
+}
+
+fn two_opt(stops: &[Stop], order: &mut [usize], start: Option<&Coordinates>, now: u64, speed_kmh: f64) {
+    let mut best = cost(stops, order, start, now, speed_kmh);
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for i in 0..order.len() {
+            for j in i + 1..order.len() {
+                order[i..=j].reverse();
+                let candidate = cost(stops, order, start, now, speed_kmh);
+                if precedence_holds(stops, order) && better(candidate, best) {
+                    best = candidate;
+                    improved = true;
+                } else {
+                    order[i..=j].reverse();
+                }
+            }
+        }
+    }
+}
+
+fn precedence_holds(stops: &[Stop], order: &[usize]) -> bool {
+    let mut picked: Vec<&str> = Vec::new();
+    for &i in order {
+        let stop = &stops[i];
+        match stop.kind {
+            StopKind::Pickup => picked.push(&stop.shipment_id),
+            StopKind::Delivery => {
+                let has_pickup = stops
+                    .iter()
+                    .any(|s| s.kind == StopKind::Pickup && s.shipment_id == stop.shipment_id);
+                if has_pickup && !picked.contains(&stop.shipment_id.as_str()) {
+                    return false;
+                }
+            },
+        }
+    }
+    true
+}
+
+// (late stops, distance); compared with a small tolerance on distance
+fn better(candidate: (u32, f64), best: (u32, f64)) -> bool {
+    candidate.0 < best.0 || (candidate.0 == best.0 && candidate.1 < best.1 - 1e-9)
+}
+
+fn cost(stops: &[Stop], order: &[usize], start: Option<&Coordinates>, now: u64, speed_kmh: f64) -> (u32, f64) {
+    let late = order
+        .iter()
+        .zip(arrivals(stops, order, start, now, speed_kmh))
+        .filter(|(&i, eta)| stops[i].deadline.is_some_and(|d| *eta > d))
+        .count() as u32;
+    (late, legs_km(stops, order, start).iter().sum())
+}
+
+fn arrivals(stops: &[Stop], order: &[usize], start: Option<&Coordinates>, now: u64, speed_kmh: f64) -> Vec<u64> {
+    let mut clock = now;
+    legs_km(stops, order, start)
+        .into_iter()
+        .map(|km| {
+            clock += (km / speed_kmh * 3600.0) as u64 * NANOS_PER_SEC;
+            let eta = clock;
+            clock += SERVICE_SECS_PER_STOP * NANOS_PER_SEC;
+            eta
+        })
+        .collect()
+}
+
+// Distance driven to reach each stop in turn
+fn legs_km(stops: &[Stop], order: &[usize], start: Option<&Coordinates>) -> Vec<f64> {
+    let mut at = start;
+    order
+        .iter()
+        .map(|&i| {
+            let km = at.map_or(0.0, |p| p.distance_km(&stops[i].location));
+            at = Some(&stops[i].location);
+            km
+        })
+        .collect()
+}
@@ -0,0 +1,176 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use crate::event_store;
+use crate::handling;
+use crate::hubs;
+use crate::labels;
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::resource_usage;
+use crate::tracking;
+use crate::validation::{self, Validator};
+use crate::{
+    apply_status_update, Coordinates, Shipment, ShipmentStatus, TrackingEvent, TrackingEventKind, SHIPMENTS,
+    TRACKING_TOKENS,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ScannerRole {
+    Driver,
+    WarehouseStaff,
+    Admin,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ScanPolicy {
+    // Move the shipment forward on the scans that imply it: the assigned driver's
+    // first scan picks it up, a warehouse scan of a picked-up package puts it in transit
+    pub auto_advance: bool,
+}
+
+thread_local! {
+    static SCAN_POLICY: RefCell<ScanPolicy> = RefCell::new(ScanPolicy { auto_advance: true });
+    static WAREHOUSE_STAFF: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+}
+
+// Admin configuration
+#[update]
+fn set_scan_policy(policy: ScanPolicy) -> Result<ScanPolicy, String> {
+    metrics::observe("set_scan_policy", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        SCAN_POLICY.with(|p| *p.borrow_mut() = policy.clone());
+        Ok(policy)
+    })
+}
+
+#[update]
+fn set_warehouse_staff(staff_id: Principal, enabled: bool) -> Result<(), String> {
+    metrics::observe("set_warehouse_staff", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        WAREHOUSE_STAFF.with(|staff| {
+            let mut staff = staff.borrow_mut();
+            if enabled {
+                staff.insert(staff_id);
+            } else {
+                staff.remove(&staff_id);
+            }
+        });
+        Ok(())
+    })
+}
+
+// Record a label scan. `scan_payload` is the label's QR payload or a bare tracking token.
+#[update]
+fn record_scan(
+    scan_payload: String,
+    location: Option<String>,
+    coordinates: Option<Coordinates>,
+) -> Result<Shipment, String> {
+    metrics::observe("record_scan", || {
+        let caller = ic_cdk::caller();
+        let mut v = Validator::new();
+        if let Some(location) = &location {
+            v.max_len("location", location, validation::MAX_TEXT_LEN);
+        }
+        if let Some(coordinates) = &coordinates {
+            v.coordinates("coordinates", coordinates);
+        }
+        v.finish()?;
+        let shipment_id = resolve_payload(&scan_payload)?;
+
+        SHIPMENTS.with(|shipments| {
+            let mut shipments_map = shipments.borrow_mut();
+            let shipment = shipments_map
+                .get_mut(&shipment_id)
+                .ok_or_else(|| "Shipment not found".to_string())?;
+            let role = scanner_role(shipment, caller)?;
+            if matches!(shipment.status, ShipmentStatus::Cancelled) {
+                return Err("Shipment has been cancelled".to_string());
+            }
+
+            let kind = TrackingEventKind::Scanned {
+                role: role.clone(),
+                coordinates,
+            };
+            let description = format!("Scanned by {}", role_label(&role));
+            match auto_advance(shipment, &role) {
+                Some(next) => {
+                    apply_status_update(shipment, next, location, description, caller, time());
+                    tracking::tag_latest(shipment, kind);
+                    event_store::tracked(shipment, caller);
+                },
+                None => {
+                    tracking::record(shipment, TrackingEvent {
+                        timestamp: time(),
+                        status: shipment.status.clone(),
+                        location,
+                        description,
+                        updated_by: caller,
+                        kind: Some(kind),
+                    });
+                    shipment.touch(time());
+                    event_store::tracked(shipment, caller);
+                },
+            }
+            resource_usage::record_instructions(shipment.sender_id, Some(&shipment.id));
+            Ok(shipment.clone())
+        })
+    })
+}
+
+// The shipment a payload refers to. QR payloads must carry the shipment's current
+// tracking token, so labels from before a token change no longer scan.
+fn resolve_payload(payload: &str) -> Result<String, String> {
+    let (expected_id, token) = match labels::decode_qr(payload) {
+        Ok(content) => (Some(content.shipment_id), content.tracking_token),
+        Err(_) if !payload.contains('|') => (None, payload.trim().to_string()),
+        Err(e) => return Err(e),
+    };
+    let shipment_id = TRACKING_TOKENS
+        .with(|tokens| tokens.borrow().get(&token).cloned())
+        .ok_or_else(|| "Unknown tracking token".to_string())?;
+    if expected_id.is_some_and(|id| id != shipment_id) {
+        return Err("Label does not match the shipment".to_string());
+    }
+    Ok(shipment_id)
+}
+
+fn scanner_role(shipment: &Shipment, caller: Principal) -> Result<ScannerRole, String> {
+    if shipment.driver_id == Some(caller) {
+        Ok(ScannerRole::Driver)
+    } else if WAREHOUSE_STAFF.with(|staff| staff.borrow().contains(&caller)) || hubs::is_hub_staff(&caller) {
+        Ok(ScannerRole::WarehouseStaff)
+    } else if permissions::has(&caller, Permission::UpdateAnyShipment) {
+        Ok(ScannerRole::Admin)
+    } else {
+        Err("Unauthorized to scan shipment".to_string())
+    }
+}
+
+fn auto_advance(shipment: &Shipment, role: &ScannerRole) -> Option<ShipmentStatus> {
+    // Routed shipments move with their legs
+    if shipment.legs.is_some() || !SCAN_POLICY.with(|p| p.borrow().auto_advance) {
+        return None;
+    }
+    match (role, &shipment.status) {
+        (ScannerRole::Driver, ShipmentStatus::PickupScheduled) => {
+            handling::check_acknowledged(shipment).ok().map(|_| ShipmentStatus::PickedUp)
+        },
+        (ScannerRole::WarehouseStaff, ShipmentStatus::PickedUp) => Some(ShipmentStatus::InTransit),
+        _ => None,
+    }
+}
+
+fn role_label(role: &ScannerRole) -> &'static str {
+    match role {
+        ScannerRole::Driver => "driver",
+        ScannerRole::WarehouseStaff => "warehouse staff",
+        ScannerRole::Admin => "admin",
+    }
+}
@@ -0,0 +1,148 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+
+use crate::money::Money;
+use crate::permissions::{self, Permission};
+use crate::{PaymentStatus, Shipment, ShipmentStatus, ShipmentSummary, SHIPMENTS};
+
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+
+// All criteria are optional and combined with AND. `statuses` matches any of the
+// listed statuses; `city` matches the pickup or delivery city, ignoring case.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct ShipmentFilter {
+    pub statuses: Option<Vec<ShipmentStatus>>,
+    pub created_from: Option<u64>,
+    pub created_to: Option<u64>,
+    pub city: Option<String>,
+    pub driver_id: Option<Principal>,
+    pub sender_id: Option<Principal>,
+    pub payment_status: Option<PaymentStatus>,
+    pub recipient_name_prefix: Option<String>,
+    pub min_price: Option<Money>,
+    pub max_price: Option<Money>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum ShipmentSortField {
+    CreatedAt,
+    UpdatedAt,
+    Price,
+    RecipientName,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShipmentSort {
+    pub field: ShipmentSortField,
+    pub descending: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShipmentPage {
+    pub items: Vec<ShipmentSummary>,
+    pub total: u32,
+    pub offset: u32,
+    pub limit: u32,
+}
+
+// Admins search every shipment; other callers only shipments they sent or drive.
+// Defaults to newest first.
+#[query]
+fn search_shipments(
+    filter: ShipmentFilter,
+    sort: Option<ShipmentSort>,
+    offset: Option<u32>,
+    limit: Option<u32>,
+) -> ShipmentPage {
+    let caller = ic_cdk::caller();
+    let admin = permissions::has(&caller, Permission::ViewAllShipments);
+    let offset = offset.unwrap_or(0);
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let sort = sort.unwrap_or(ShipmentSort {
+        field: ShipmentSortField::CreatedAt,
+        descending: true,
+    });
+
+    let city = filter.city.as_ref().map(|c| c.trim().to_lowercase());
+    let name_prefix = filter.recipient_name_prefix.as_ref().map(|p| p.trim().to_lowercase());
+
+    let mut matches: Vec<Shipment> = SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .values()
+            .filter(|s| admin || s.sender_id == caller || s.driver_id == Some(caller))
+            .filter(|s| matches_filter(s, &filter, city.as_deref(), name_prefix.as_deref()))
+            .cloned()
+            .collect()
+    });
+
+    matches.sort_by(|a, b| {
+        let ordering = match sort.field {
+            ShipmentSortField::CreatedAt => a.created_at.cmp(&b.created_at),
+            ShipmentSortField::UpdatedAt => a.updated_at.cmp(&b.updated_at),
+            ShipmentSortField::Price => a.price.amount_e8s.cmp(&b.price.amount_e8s),
+            ShipmentSortField::RecipientName => a.recipient_name.to_lowercase().cmp(&b.recipient_name.to_lowercase()),
+        };
+        // Ties break on id so pages are stable
+        let ordering = ordering.then_with(|| a.id.cmp(&b.id));
+        if sort.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+
+    let total = matches.len() as u32;
+    let items = matches
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit as usize)
+        .map(|s| s.summary())
+        .collect();
+
+    ShipmentPage {
+        items,
+        total,
+        offset,
+        limit,
+    }
+}
+
+fn matches_filter(s: &Shipment, filter: &ShipmentFilter, city: Option<&str>, name_prefix: Option<&str>) -> bool {
+    if let Some(statuses) = &filter.statuses {
+        if !statuses.contains(&s.status) {
+            return false;
+        }
+    }
+    if filter.created_from.map(|from| s.created_at < from).unwrap_or(false)
+        || filter.created_to.map(|to| s.created_at > to).unwrap_or(false)
+    {
+        return false;
+    }
+    if let Some(city) = city {
+        if s.pickup_address.city.to_lowercase() != city && s.delivery_address.city.to_lowercase() != city {
+            return false;
+        }
+    }
+    if filter.driver_id.is_some() && s.driver_id != filter.driver_id {
+        return false;
+    }
+    if filter.sender_id.map(|id| s.sender_id != id).unwrap_or(false) {
+        return false;
+    }
+    if filter.payment_status.as_ref().map(|p| s.payment_status != *p).unwrap_or(false) {
+        return false;
+    }
+    if let Some(prefix) = name_prefix {
+        if !s.recipient_name.to_lowercase().starts_with(prefix) {
+            return false;
+        }
+    }
+    if filter.min_price.map(|min| s.price.amount_e8s < min.amount_e8s).unwrap_or(false)
+        || filter.max_price.map(|max| s.price.amount_e8s > max.amount_e8s).unwrap_or(false)
+    {
+        return false;
+    }
+    true
+}
@@ -0,0 +1,162 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::validation::{Validator, MAX_NAME_LEN};
+use crate::USERS;
+
+const MAX_KEYS_PER_OWNER: usize = 20;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ServiceScope {
+    CreateShipments,
+    // The owner's own shipments
+    ReadShipments,
+}
+
+// A backend identity allowed to act for its owner within the given scopes
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ServiceKey {
+    pub id: String,
+    pub owner: Principal,
+    pub principal: Principal,
+    pub label: String,
+    pub scopes: Vec<ServiceScope>,
+    pub created_at: u64,
+    pub revoked_at: Option<u64>,
+    // Only calls that change state are recorded
+    pub last_used_at: Option<u64>,
+}
+
+thread_local! {
+    static SERVICE_KEYS: RefCell<HashMap<String, ServiceKey>> = RefCell::new(HashMap::new());
+    // Service principal -> key id, for every key ever issued
+    static PRINCIPALS: RefCell<HashMap<Principal, String>> = RefCell::new(HashMap::new());
+    static KEY_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Register the principal of a server-side identity the store owner controls.
+// It must not be a registered user or have held a key before.
+#[update]
+fn create_service_key(label: String, scopes: Vec<ServiceScope>, service_principal: Principal) -> Result<ServiceKey, String> {
+    metrics::observe("create_service_key", || {
+        let caller = ic_cdk::caller();
+        let user = USERS.with(|users| users.borrow().get(&caller).cloned());
+        match user {
+            Some(u) if !u.is_active => return Err("Account is deactivated".to_string()),
+            Some(_) => permissions::require(&caller, Permission::CreateServiceKeys)?,
+            None => return Err("User not registered".to_string()),
+        }
+        if label.trim().is_empty() {
+            return Err("Label cannot be empty".to_string());
+        }
+        let mut v = Validator::new();
+        v.max_len("label", &label, MAX_NAME_LEN);
+        v.finish()?;
+        if scopes.is_empty() {
+            return Err("At least one scope is required".to_string());
+        }
+        if service_principal == Principal::anonymous() || service_principal == caller {
+            return Err("Service principal must be a separate identity".to_string());
+        }
+        if USERS.with(|users| users.borrow().contains_key(&service_principal)) {
+            return Err("Service principal is a registered user".to_string());
+        }
+        if PRINCIPALS.with(|p| p.borrow().contains_key(&service_principal)) {
+            return Err("Service principal already has a key".to_string());
+        }
+        let active = SERVICE_KEYS.with(|keys| {
+            keys.borrow()
+                .values()
+                .filter(|k| k.owner == caller && k.revoked_at.is_none())
+                .count()
+        });
+        if active >= MAX_KEYS_PER_OWNER {
+            return Err(format!("At most {} active service keys per owner", MAX_KEYS_PER_OWNER));
+        }
+
+        let mut deduped = Vec::new();
+        for scope in scopes {
+            if !deduped.contains(&scope) {
+                deduped.push(scope);
+            }
+        }
+        let id = KEY_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            format!("SK{:06}", *c)
+        });
+        let key = ServiceKey {
+            id: id.clone(),
+            owner: caller,
+            principal: service_principal,
+            label,
+            scopes: deduped,
+            created_at: time(),
+            revoked_at: None,
+            last_used_at: None,
+        };
+        PRINCIPALS.with(|p| p.borrow_mut().insert(service_principal, id.clone()));
+        SERVICE_KEYS.with(|keys| keys.borrow_mut().insert(id, key.clone()));
+        Ok(key)
+    })
+}
+
+// Takes effect immediately; revoked principals cannot be registered again
+#[update]
+fn revoke_service_key(key_id: String) -> Result<ServiceKey, String> {
+    metrics::observe("revoke_service_key", || {
+        let caller = ic_cdk::caller();
+        SERVICE_KEYS.with(|keys| {
+            let mut keys = keys.borrow_mut();
+            let key = keys.get_mut(&key_id).ok_or_else(|| "Service key not found".to_string())?;
+            if key.owner != caller {
+                return Err("Unauthorized to revoke this service key".to_string());
+            }
+            if key.revoked_at.is_some() {
+                return Err("Service key is already revoked".to_string());
+            }
+            key.revoked_at = Some(time());
+            Ok(key.clone())
+        })
+    })
+}
+
+#[query]
+fn list_service_keys() -> Vec<ServiceKey> {
+    let caller = ic_cdk::caller();
+    let mut keys: Vec<ServiceKey> = SERVICE_KEYS.with(|keys| {
+        keys.borrow()
+            .values()
+            .filter(|k| k.owner == caller)
+            .cloned()
+            .collect()
+    });
+    keys.sort_by(|a, b| a.id.cmp(&b.id));
+    keys
+}
+
+// The principal a call acts for: the owner when the caller is a service
+// principal holding the scope, otherwise the caller itself
+pub(crate) fn acting_principal(caller: Principal, scope: ServiceScope) -> Result<Principal, String> {
+    let Some(key_id) = PRINCIPALS.with(|p| p.borrow().get(&caller).cloned()) else {
+        return Ok(caller);
+    };
+    SERVICE_KEYS.with(|keys| {
+        let mut keys = keys.borrow_mut();
+        let key = keys.get_mut(&key_id).ok_or_else(|| "Service key not found".to_string())?;
+        if key.revoked_at.is_some() {
+            return Err("Service key has been revoked".to_string());
+        }
+        if !key.scopes.contains(&scope) {
+            return Err(format!("Service key lacks the {:?} scope", scope));
+        }
+        // Discarded when called from a query
+        key.last_used_at = Some(time());
+        Ok(key.owner)
+    })
+}
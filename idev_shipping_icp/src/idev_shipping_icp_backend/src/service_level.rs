@@ -0,0 +1,135 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::*;
+
+use crate::permissions::{self, Permission};
+use crate::{
+    CostBreakdown, CostLineItem, Shipment, ShipmentStatus, ShipmentSummary, VerificationStatus, DRIVERS, SHIPMENTS,
+};
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub enum ServiceLevel {
+    Express,
+    #[default]
+    Standard,
+    Economy,
+}
+
+impl ServiceLevel {
+    pub const ALL: [ServiceLevel; 3] = [ServiceLevel::Express, ServiceLevel::Standard, ServiceLevel::Economy];
+
+    // Price relative to Standard, in basis points
+    fn multiplier_bps(&self) -> u128 {
+        match self {
+            ServiceLevel::Express => 15_000,
+            ServiceLevel::Standard => 10_000,
+            ServiceLevel::Economy => 8_000,
+        }
+    }
+
+    pub fn target_hours(&self) -> u64 {
+        match self {
+            ServiceLevel::Express => 24,
+            ServiceLevel::Standard => 72,
+            ServiceLevel::Economy => 120,
+        }
+    }
+
+    pub fn target_nanos(&self) -> u64 {
+        self.target_hours() * NANOS_PER_HOUR
+    }
+
+    // Lower values are matched to drivers first
+    fn priority(&self) -> u8 {
+        match self {
+            ServiceLevel::Express => 0,
+            ServiceLevel::Standard => 1,
+            ServiceLevel::Economy => 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ServiceLevelPerformance {
+    pub service_level: ServiceLevel,
+    pub delivered: u32,
+    pub on_time: u32,
+    // Share of delivered shipments that arrived by their target, in basis points
+    pub on_time_bps: u32,
+}
+
+// Shipments waiting for a driver, highest service level first, then oldest first
+#[query]
+fn get_unassigned_shipments() -> Result<Vec<ShipmentSummary>, String> {
+    let caller = ic_cdk::caller();
+    let verified_driver = DRIVERS.with(|drivers| {
+        drivers
+            .borrow()
+            .get(&caller)
+            .map(|d| matches!(d.verification_status, VerificationStatus::Verified))
+            .unwrap_or(false)
+    });
+    if !verified_driver && !permissions::has(&caller, Permission::AssignDriver) {
+        return Err("Unauthorized to view unassigned shipments".to_string());
+    }
+
+    let mut shipments: Vec<Shipment> = SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .values()
+            .filter(|s| s.driver_id.is_none() && matches!(s.status, ShipmentStatus::Created))
+            .cloned()
+            .collect()
+    });
+    shipments.sort_by(|a, b| {
+        a.service_level
+            .priority()
+            .cmp(&b.service_level.priority())
+            .then(a.created_at.cmp(&b.created_at))
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    Ok(shipments.iter().map(Shipment::summary).collect())
+}
+
+// Express adds a surcharge line and Economy a discount on top of the standard price
+pub(crate) fn apply_pricing(level: &ServiceLevel, breakdown: &mut CostBreakdown) {
+    let multiplier = level.multiplier_bps();
+    let label = format!("{:?} service", level);
+    if multiplier > 10_000 {
+        let surcharge = breakdown.subtotal.mul_ratio(multiplier - 10_000, 10_000);
+        breakdown.charges.push(CostLineItem { label, amount: surcharge });
+        breakdown.subtotal = breakdown.subtotal.add(surcharge);
+        breakdown.total = breakdown.total.add(surcharge);
+    } else if multiplier < 10_000 {
+        let discount = breakdown.subtotal.mul_ratio(10_000 - multiplier, 10_000).min(breakdown.total);
+        breakdown.deductions.push(CostLineItem { label, amount: discount });
+        breakdown.total = breakdown.total.saturating_sub(discount);
+    }
+}
+
+pub(crate) fn performance(shipments: &[&Shipment]) -> Vec<ServiceLevelPerformance> {
+    ServiceLevel::ALL
+        .iter()
+        .map(|level| {
+            let delivered: Vec<&&Shipment> = shipments
+                .iter()
+                .filter(|s| s.service_level == *level && matches!(s.status, ShipmentStatus::Delivered))
+                .collect();
+            let on_time = delivered
+                .iter()
+                .filter(|s| match (s.actual_delivery, s.estimated_delivery) {
+                    (Some(actual), Some(target)) => actual <= target,
+                    _ => false,
+                })
+                .count() as u32;
+            let delivered = delivered.len() as u32;
+            ServiceLevelPerformance {
+                service_level: level.clone(),
+                delivered,
+                on_time,
+                on_time_bps: (on_time * 10_000).checked_div(delivered).unwrap_or(0),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,199 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use ic_stable_structures::StableCell;
+use std::cell::RefCell;
+
+use crate::audit::{self, AuditAction};
+use crate::memory::{self, StableMemory};
+use crate::metrics;
+use crate::money::{Money, BASE_CURRENCY};
+use crate::permissions::{self, Permission};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const MAX_BPS: u32 = 10_000;
+const MAX_ATTEMPTS: u32 = 50;
+const MAX_CONFIRMATION_WINDOW_SECS: u64 = 30 * 24 * 60 * 60;
+
+// Platform-wide values admins can change at runtime. Kept candid-encoded in its
+// own stable memory region, so fields added later must be Options.
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct Settings {
+    // Shipping cost: base plus a rate per kg plus a share of the declared value
+    pub base_price: Money,
+    pub price_per_kg: Money,
+    pub declared_value_bps: u32,
+    // Before a delivery awaiting recipient sign-off is confirmed automatically
+    pub confirmation_window_secs: u64,
+    // Platform cut of delivered shipments
+    pub platform_fee_bps: u32,
+    // Deliveries tried before an outbound event is dropped
+    pub webhook_max_attempts: u32,
+    pub event_bus_max_attempts: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            base_price: Money::from_units(10, BASE_CURRENCY),
+            price_per_kg: Money::from_units(2, BASE_CURRENCY),
+            declared_value_bps: 100,
+            confirmation_window_secs: 48 * 60 * 60,
+            platform_fee_bps: 1_000,
+            webhook_max_attempts: 6,
+            event_bus_max_attempts: 8,
+        }
+    }
+}
+
+// Fields left as None keep their current value
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct SettingsPatch {
+    pub base_price: Option<Money>,
+    pub price_per_kg: Option<Money>,
+    pub declared_value_bps: Option<u32>,
+    pub confirmation_window_secs: Option<u64>,
+    pub platform_fee_bps: Option<u32>,
+    pub webhook_max_attempts: Option<u32>,
+    pub event_bus_max_attempts: Option<u32>,
+}
+
+thread_local! {
+    // Empty until settings are first changed
+    static STORED: RefCell<StableCell<Vec<u8>, StableMemory>> = RefCell::new(
+        StableCell::init(memory::get(memory::SETTINGS), Vec::new()).expect("failed to init settings"),
+    );
+    // Decoded copy of the stored settings
+    static CACHE: RefCell<Option<Settings>> = RefCell::new(None);
+}
+
+#[query]
+fn get_settings() -> Settings {
+    current()
+}
+
+// Admin only. Each changed field is written to the audit log.
+#[update]
+fn update_settings(patch: SettingsPatch) -> Result<Settings, String> {
+    metrics::observe("update_settings", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        apply(caller, patch)
+    })
+}
+
+pub(crate) fn current() -> Settings {
+    if let Some(settings) = CACHE.with(|c| c.borrow().clone()) {
+        return settings;
+    }
+    let bytes = STORED.with(|s| s.borrow().get().clone());
+    let settings = if bytes.is_empty() {
+        Settings::default()
+    } else {
+        candid::decode_one(&bytes).expect("failed to decode settings")
+    };
+    CACHE.with(|c| *c.borrow_mut() = Some(settings.clone()));
+    settings
+}
+
+pub(crate) fn confirmation_window_nanos() -> u64 {
+    current().confirmation_window_secs * NANOS_PER_SEC
+}
+
+pub(crate) fn platform_fee_bps() -> u32 {
+    current().platform_fee_bps
+}
+
+pub(crate) fn webhook_max_attempts() -> u32 {
+    current().webhook_max_attempts
+}
+
+pub(crate) fn event_bus_max_attempts() -> u32 {
+    current().event_bus_max_attempts
+}
+
+// Validate and store a patch; callers check authorization
+pub(crate) fn apply(caller: Principal, patch: SettingsPatch) -> Result<Settings, String> {
+    let old = current();
+    let mut new = old.clone();
+    if let Some(price) = patch.base_price {
+        new.base_price = price;
+    }
+    if let Some(price) = patch.price_per_kg {
+        new.price_per_kg = price;
+    }
+    if let Some(bps) = patch.declared_value_bps {
+        new.declared_value_bps = bps;
+    }
+    if let Some(secs) = patch.confirmation_window_secs {
+        new.confirmation_window_secs = secs;
+    }
+    if let Some(bps) = patch.platform_fee_bps {
+        new.platform_fee_bps = bps;
+    }
+    if let Some(attempts) = patch.webhook_max_attempts {
+        new.webhook_max_attempts = attempts;
+    }
+    if let Some(attempts) = patch.event_bus_max_attempts {
+        new.event_bus_max_attempts = attempts;
+    }
+    validate(&new)?;
+
+    let changes = changes(&old, &new);
+    if changes.is_empty() {
+        return Ok(new);
+    }
+    let bytes = candid::encode_one(&new).map_err(|e| format!("Failed to encode settings: {}", e))?;
+    STORED.with(|s| s.borrow_mut().set(bytes)).map_err(|e| format!("Failed to store settings: {:?}", e))?;
+    CACHE.with(|c| *c.borrow_mut() = Some(new.clone()));
+    for change in changes {
+        audit::record(caller, caller, AuditAction::SettingsChanged, change);
+    }
+    Ok(new)
+}
+
+fn validate(settings: &Settings) -> Result<(), String> {
+    if settings.base_price.currency != BASE_CURRENCY || settings.price_per_kg.currency != BASE_CURRENCY {
+        return Err(format!("Prices must be in {:?}", BASE_CURRENCY));
+    }
+    if settings.declared_value_bps > MAX_BPS || settings.platform_fee_bps > MAX_BPS {
+        return Err("Percentages cannot exceed 100%".to_string());
+    }
+    if settings.confirmation_window_secs == 0 || settings.confirmation_window_secs > MAX_CONFIRMATION_WINDOW_SECS {
+        return Err(format!(
+            "Confirmation window must be between 1 and {} seconds",
+            MAX_CONFIRMATION_WINDOW_SECS
+        ));
+    }
+    for (name, attempts) in [
+        ("webhook_max_attempts", settings.webhook_max_attempts),
+        ("event_bus_max_attempts", settings.event_bus_max_attempts),
+    ] {
+        if attempts == 0 || attempts > MAX_ATTEMPTS {
+            return Err(format!("{} must be between 1 and {}", name, MAX_ATTEMPTS));
+        }
+    }
+    Ok(())
+}
+
+// One "field: old -> new" line per changed field
+fn changes(old: &Settings, new: &Settings) -> Vec<String> {
+    let money = |m: &Money| format!("{} {}", m.to_decimal(), m.currency.symbol());
+    let fields = [
+        ("base_price", money(&old.base_price), money(&new.base_price)),
+        ("price_per_kg", money(&old.price_per_kg), money(&new.price_per_kg)),
+        ("declared_value_bps", old.declared_value_bps.to_string(), new.declared_value_bps.to_string()),
+        (
+            "confirmation_window_secs",
+            old.confirmation_window_secs.to_string(),
+            new.confirmation_window_secs.to_string(),
+        ),
+        ("platform_fee_bps", old.platform_fee_bps.to_string(), new.platform_fee_bps.to_string()),
+        ("webhook_max_attempts", old.webhook_max_attempts.to_string(), new.webhook_max_attempts.to_string()),
+        ("event_bus_max_attempts", old.event_bus_max_attempts.to_string(), new.event_bus_max_attempts.to_string()),
+    ];
+    fields
+        .into_iter()
+        .filter(|(_, old, new)| old != new)
+        .map(|(name, old, new)| format!("{}: {} -> {}", name, old, new))
+        .collect()
+}
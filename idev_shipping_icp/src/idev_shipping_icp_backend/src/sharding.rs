@@ -0,0 +1,486 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::main::{
+    create_canister_with_extra_cycles, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::event_store;
+use crate::ids;
+use crate::memory::{self, StableMemory};
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::text_index::{self, EntityKind};
+use crate::tracking;
+use crate::{archive, Shipment, ShipmentStatus, SHIPMENTS, SHIPMENT_CODE_PREFIX, SHORT_CODES, TRACKING_TOKENS};
+
+// Finished shipments copied to a shard per call, to stay well below message size limits
+const OFFLOAD_BATCH: usize = 100;
+
+// The same wasm runs as the index (routing and active shipments) or as a shard
+// holding finished shipments for one id range on behalf of an index
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct InitArgs {
+    pub index_canister: Option<Principal>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShardingConfig {
+    pub enabled: bool,
+    // Shipment numbers per shard: shard k owns [k * shard_size, (k + 1) * shard_size)
+    pub shard_size: u64,
+    // A range's shard is spawned once this share of its ids is allocated, in basis points
+    pub spawn_threshold_bps: u32,
+    // Cycles given to each new shard on top of the creation fee
+    pub shard_cycles: u128,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ShardStatus {
+    Provisioning,
+    Active,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShardInfo {
+    pub range_start: u64,
+    pub range_end: u64,
+    pub canister_id: Option<Principal>,
+    pub status: ShardStatus,
+    pub shipment_count: u64,
+    pub created_at: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum ShipmentLocation {
+    Local,
+    Archived,
+    Shard(Principal),
+    NotFound,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StableShardingState {
+    index_canister: Option<Principal>,
+    config: ShardingConfig,
+    shards: Vec<ShardInfo>,
+    routes: Option<Vec<(String, u64)>>,
+}
+
+thread_local! {
+    // Set on shards: the index allowed to write to them
+    static INDEX_CANISTER: RefCell<Option<Principal>> = RefCell::new(None);
+    static SHARDING_CONFIG: RefCell<ShardingConfig> = RefCell::new(ShardingConfig {
+        enabled: false,
+        shard_size: 1_000_000,
+        spawn_threshold_bps: 9_000,
+        shard_cycles: 2_000_000_000_000,
+    });
+    static SHARD_WASM: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+    // Keyed by range start
+    static SHARDS: RefCell<BTreeMap<u64, ShardInfo>> = RefCell::new(BTreeMap::new());
+    static REBALANCING: RefCell<bool> = RefCell::new(false);
+    // Shipment numbers of offloaded shipments whose id does not carry one
+    static ROUTES: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+    // On shards: shipment id -> candid-encoded shipment with its full history, as
+    // handed over. SHIPMENTS is rebuilt from it after an upgrade.
+    static SHARD_SHIPMENTS: RefCell<StableBTreeMap<String, Vec<u8>, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::SHARD_SHIPMENTS)));
+}
+
+// Admin configuration (index)
+#[update]
+fn set_sharding_config(config: ShardingConfig) -> Result<ShardingConfig, String> {
+    metrics::observe("set_sharding_config", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if config.shard_size == 0 || config.spawn_threshold_bps > 10_000 {
+            return Err("Shard size must be positive and the threshold at most 10000 bps".to_string());
+        }
+        // Ranges already assigned to shards cannot move
+        let current = SHARDING_CONFIG.with(|c| c.borrow().shard_size);
+        let has_shards = SHARDS.with(|shards| !shards.borrow().is_empty());
+        if has_shards && config.shard_size != current {
+            return Err("Shard size cannot change once shards exist".to_string());
+        }
+        SHARDING_CONFIG.with(|c| *c.borrow_mut() = config.clone());
+        Ok(config)
+    })
+}
+
+#[query]
+fn get_sharding_config() -> ShardingConfig {
+    SHARDING_CONFIG.with(|c| c.borrow().clone())
+}
+
+// Wasm installed on new shards; normally this canister's own build
+#[update]
+fn set_shard_wasm(wasm: Vec<u8>) -> Result<u64, String> {
+    metrics::observe("set_shard_wasm", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if !wasm.starts_with(b"\0asm") && !wasm.starts_with(&[0x1f, 0x8b]) {
+            return Err("Shard module must be a wasm or gzipped wasm binary".to_string());
+        }
+        let len = wasm.len() as u64;
+        SHARD_WASM.with(|w| *w.borrow_mut() = wasm);
+        Ok(len)
+    })
+}
+
+#[query]
+fn get_shards() -> Result<Vec<ShardInfo>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ManagePlatform)?;
+    Ok(SHARDS.with(|shards| shards.borrow().values().cloned().collect()))
+}
+
+// Where a shipment's full record can be read: this canister, its archive, or a
+// shard's get_shipment
+#[query]
+fn locate_shipment(shipment_id: String) -> ShipmentLocation {
+    locate(&shipment_id)
+}
+
+pub(crate) fn locate(shipment_id: &str) -> ShipmentLocation {
+    if SHIPMENTS.with(|shipments| shipments.borrow().contains_key(shipment_id)) {
+        return ShipmentLocation::Local;
+    }
+    if archive::contains(shipment_id) {
+        return ShipmentLocation::Archived;
+    }
+    let number = shipment_number(shipment_id).or_else(|| ROUTES.with(|r| r.borrow().get(shipment_id).copied()));
+    match number.and_then(active_shard_for) {
+        Some(canister_id) => ShipmentLocation::Shard(canister_id),
+        None => ShipmentLocation::NotFound,
+    }
+}
+
+// Run spawning and offloading now instead of waiting for the timer
+#[update]
+async fn rebalance_shards() -> Result<(), String> {
+    metrics::observe_async("rebalance_shards", async move {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        rebalance().await;
+        Ok(())
+    })
+    .await
+}
+
+// Reinstall the current shard wasm on every shard. Their shipments are kept in
+// stable memory, the rest of their state in the upgrade snapshot.
+#[update]
+async fn upgrade_shards() -> Result<u32, String> {
+    metrics::observe_async("upgrade_shards", async move {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        let wasm = SHARD_WASM.with(|w| w.borrow().clone());
+        if wasm.is_empty() {
+            return Err("Shard wasm has not been uploaded".to_string());
+        }
+        let canisters: Vec<Principal> =
+            SHARDS.with(|shards| shards.borrow().values().filter_map(|s| s.canister_id).collect());
+        let mut upgraded = 0;
+        for canister_id in canisters {
+            install(canister_id, &wasm, CanisterInstallMode::Upgrade)
+                .await
+                .map_err(|e| format!("Upgrading shard {} failed: {}", canister_id, e))?;
+            upgraded += 1;
+        }
+        Ok(upgraded)
+    })
+    .await
+}
+
+// Shard endpoint: store finished shipments handed over by the index
+#[update]
+fn shard_put_shipments(shipments: Vec<Shipment>) -> Result<u32, String> {
+    metrics::observe("shard_put_shipments", || {
+        let caller = ic_cdk::caller();
+        if INDEX_CANISTER.with(|i| *i.borrow()) != Some(caller) {
+            return Err("Only the index canister can store shipments on a shard".to_string());
+        }
+        let count = shipments.len() as u32;
+        for shipment in shipments {
+            let encoded = candid::encode_one(&shipment).map_err(|e| e.to_string())?;
+            SHARD_SHIPMENTS.with(|stored| stored.borrow_mut().insert(shipment.id.clone(), encoded));
+            load_shard_shipment(shipment);
+        }
+        Ok(count)
+    })
+}
+
+pub(crate) fn init(args: Option<InitArgs>) {
+    if let Some(index) = args.and_then(|a| a.index_canister) {
+        INDEX_CANISTER.with(|i| *i.borrow_mut() = Some(index));
+    }
+}
+
+// Timer job (index only)
+pub(crate) fn schedule_rebalance() {
+    let is_index = INDEX_CANISTER.with(|i| i.borrow().is_none());
+    if is_index && SHARDING_CONFIG.with(|c| c.borrow().enabled) {
+        ic_cdk::spawn(rebalance());
+    }
+}
+
+pub(crate) fn stable_state() -> StableShardingState {
+    StableShardingState {
+        index_canister: INDEX_CANISTER.with(|i| *i.borrow()),
+        config: SHARDING_CONFIG.with(|c| c.borrow().clone()),
+        shards: SHARDS.with(|shards| shards.borrow().values().cloned().collect()),
+        routes: Some(ROUTES.with(|r| r.borrow().iter().map(|(id, n)| (id.clone(), *n)).collect())),
+    }
+}
+
+pub(crate) fn restore_stable_state(state: StableShardingState) {
+    INDEX_CANISTER.with(|i| *i.borrow_mut() = state.index_canister);
+    SHARDING_CONFIG.with(|c| *c.borrow_mut() = state.config);
+    SHARDS.with(|shards| *shards.borrow_mut() = state.shards.into_iter().map(|s| (s.range_start, s)).collect());
+    ROUTES.with(|r| *r.borrow_mut() = state.routes.unwrap_or_default().into_iter().collect());
+    if INDEX_CANISTER.with(|i| i.borrow().is_some()) {
+        reload_shard_shipments();
+    }
+}
+
+// Shards serve their shipments from the heap like the index does
+fn reload_shard_shipments() {
+    let shipments: Vec<Shipment> = SHARD_SHIPMENTS.with(|stored| {
+        stored
+            .borrow()
+            .iter()
+            .filter_map(|(_, bytes)| candid::decode_one(&bytes).ok())
+            .collect()
+    });
+    for shipment in shipments {
+        load_shard_shipment(shipment);
+    }
+}
+
+fn load_shard_shipment(mut shipment: Shipment) {
+    tracking::attach(&mut shipment);
+    text_index::index_shipment(&shipment);
+    SHIPMENTS.with(|map| map.borrow_mut().insert(shipment.id.clone(), shipment));
+}
+
+async fn rebalance() {
+    // Only one rebalance at a time; calls below interleave with other messages
+    if REBALANCING.with(|r| r.replace(true)) {
+        return;
+    }
+    spawn_due_shards().await;
+    offload_finished_shipments().await;
+    REBALANCING.with(|r| *r.borrow_mut() = false);
+}
+
+// Create and install shards for ranges past the spawn threshold, and retry
+// installs that failed earlier
+async fn spawn_due_shards() {
+    let wasm = SHARD_WASM.with(|w| w.borrow().clone());
+    if wasm.is_empty() {
+        return;
+    }
+    let config = SHARDING_CONFIG.with(|c| c.borrow().clone());
+    let allocated = ids::issued(SHIPMENT_CODE_PREFIX);
+
+    let mut range_start = 0;
+    while range_start <= allocated {
+        let threshold = range_start + config.shard_size * config.spawn_threshold_bps as u64 / 10_000;
+        if allocated < threshold {
+            break;
+        }
+        let existing = SHARDS.with(|shards| shards.borrow().get(&range_start).cloned());
+        let shard = match existing {
+            Some(s) if s.status == ShardStatus::Active => None,
+            Some(s) => Some(s),
+            None => Some(ShardInfo {
+                range_start,
+                range_end: range_start + config.shard_size,
+                canister_id: None,
+                status: ShardStatus::Provisioning,
+                shipment_count: 0,
+                created_at: time(),
+                last_error: None,
+            }),
+        };
+        if let Some(shard) = shard {
+            provision(shard, &wasm, config.shard_cycles).await;
+        }
+        range_start += config.shard_size;
+    }
+}
+
+async fn provision(mut shard: ShardInfo, wasm: &[u8], cycles: u128) {
+    save_shard(&shard);
+    let canister_id = match shard.canister_id {
+        Some(id) => id,
+        None => {
+            let arg = CreateCanisterArgument {
+                settings: Some(CanisterSettings {
+                    controllers: Some(vec![ic_cdk::id()]),
+                    compute_allocation: None,
+                    memory_allocation: None,
+                    freezing_threshold: None,
+                }),
+            };
+            match create_canister_with_extra_cycles(arg, cycles).await {
+                Ok((record,)) => record.canister_id,
+                Err((code, message)) => {
+                    shard.last_error = Some(format!("Creating shard failed: {:?}: {}", code, message));
+                    return save_shard(&shard);
+                },
+            }
+        },
+    };
+    shard.canister_id = Some(canister_id);
+    save_shard(&shard);
+
+    match install(canister_id, wasm, CanisterInstallMode::Install).await {
+        Ok(()) => {
+            shard.status = ShardStatus::Active;
+            shard.last_error = None;
+        },
+        Err(e) => shard.last_error = Some(format!("Installing shard failed: {}", e)),
+    }
+    save_shard(&shard);
+}
+
+async fn install(canister_id: Principal, wasm: &[u8], mode: CanisterInstallMode) -> Result<(), String> {
+    let init_args = Some(InitArgs {
+        index_canister: Some(ic_cdk::id()),
+    });
+    let arg = InstallCodeArgument {
+        mode,
+        canister_id,
+        wasm_module: wasm.to_vec(),
+        arg: candid::encode_one(init_args).map_err(|e| e.to_string())?,
+    };
+    install_code(arg)
+        .await
+        .map_err(|(code, message)| format!("{:?}: {}", code, message))
+}
+
+// Move finished shipments whose range has an active shard out of this canister.
+// Active shipments stay here because every lifecycle update mutates them.
+async fn offload_finished_shipments() {
+    let shards: Vec<ShardInfo> = SHARDS.with(|shards| {
+        shards
+            .borrow()
+            .values()
+            .filter(|s| s.status == ShardStatus::Active)
+            .cloned()
+            .collect()
+    });
+
+    for shard in shards {
+        let canister_id = match shard.canister_id {
+            Some(id) => id,
+            None => continue,
+        };
+        let batch: Vec<Shipment> = SHIPMENTS.with(|shipments| {
+            shipments
+                .borrow()
+                .values()
+                .filter(|s| {
+                    matches!(
+                        s.status,
+                        ShipmentStatus::Delivered | ShipmentStatus::Cancelled | ShipmentStatus::Returned
+                    ) && number_of(s).is_some_and(|n| n >= shard.range_start && n < shard.range_end)
+                })
+                .take(OFFLOAD_BATCH)
+                .map(tracking::full_record)
+                .collect()
+        });
+        if batch.is_empty() {
+            continue;
+        }
+
+        let result: Result<(Result<u32, String>,), _> =
+            ic_cdk::call(canister_id, "shard_put_shipments", (batch.clone(),)).await;
+        let error = match result {
+            Ok((Ok(_),)) => None,
+            Ok((Err(e),)) => Some(e),
+            Err((code, message)) => Some(format!("{:?}: {}", code, message)),
+        };
+        if let Some(e) = error {
+            SHARDS.with(|shards| {
+                if let Some(s) = shards.borrow_mut().get_mut(&shard.range_start) {
+                    s.last_error = Some(format!("Offloading failed: {}", e));
+                }
+            });
+            continue;
+        }
+
+        // The shard now owns these records; drop them unless they changed meanwhile
+        let moved: HashSet<String> = SHIPMENTS.with(|shipments| {
+            let mut shipments = shipments.borrow_mut();
+            let unchanged: Vec<String> = batch
+                .iter()
+                .filter(|sent| {
+                    shipments
+                        .get(&sent.id)
+                        .map(|current| current.updated_at == sent.updated_at)
+                        .unwrap_or(false)
+                })
+                .map(|sent| sent.id.clone())
+                .collect();
+            for id in &unchanged {
+                shipments.remove(id);
+                tracking::forget(id);
+                event_store::forget(id);
+                text_index::remove(EntityKind::Shipment, id);
+            }
+            unchanged.into_iter().collect()
+        });
+        TRACKING_TOKENS.with(|tokens| tokens.borrow_mut().retain(|_, id| !moved.contains(id)));
+        SHORT_CODES.with(|codes| codes.borrow_mut().retain(|_, id| !moved.contains(id)));
+        ROUTES.with(|routes| {
+            let mut routes = routes.borrow_mut();
+            for sent in batch.iter().filter(|s| moved.contains(&s.id) && shipment_number(&s.id).is_none()) {
+                if let Some(number) = number_of(sent) {
+                    routes.insert(sent.id.clone(), number);
+                }
+            }
+        });
+        SHARDS.with(|shards| {
+            if let Some(s) = shards.borrow_mut().get_mut(&shard.range_start) {
+                s.shipment_count += moved.len() as u64;
+                s.last_error = None;
+            }
+        });
+    }
+}
+
+fn save_shard(shard: &ShardInfo) {
+    SHARDS.with(|shards| shards.borrow_mut().insert(shard.range_start, shard.clone()));
+}
+
+fn active_shard_for(number: u64) -> Option<Principal> {
+    SHARDS.with(|shards| {
+        shards
+            .borrow()
+            .range(..=number)
+            .next_back()
+            .filter(|(_, s)| number < s.range_end && s.status == ShardStatus::Active)
+            .and_then(|(_, s)| s.canister_id)
+    })
+}
+
+// Numeric part of an id from before random ids, such as SH000042
+fn shipment_number(shipment_id: &str) -> Option<u64> {
+    ids::code_number(shipment_id, SHIPMENT_CODE_PREFIX)
+}
+
+// Shards are assigned by short code number
+fn number_of(shipment: &Shipment) -> Option<u64> {
+    match &shipment.short_code {
+        Some(code) => ids::code_number(code, SHIPMENT_CODE_PREFIX),
+        None => shipment_number(&shipment.id),
+    }
+}
@@ -0,0 +1,234 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::{accounts, zones, VerificationStatus, DRIVERS};
+
+const NANOS_PER_MINUTE: u64 = 60_000_000_000;
+const SLOT_MINUTES: u64 = 30;
+const MAX_COVERAGE_RANGE_NANOS: u64 = 7 * 24 * 60 * NANOS_PER_MINUTE;
+const MAX_UNAVAILABILITY: usize = 100;
+
+// Recurring weekly working window (0 = Monday), in minutes since midnight UTC.
+// Shifts past midnight are declared as two windows.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShiftWindow {
+    pub day_of_week: u8,
+    pub start_minute: u16,
+    pub end_minute: u16,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Unavailability {
+    pub id: u64,
+    pub start: u64,
+    pub end: u64,
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct DriverSchedule {
+    pub weekly_shifts: Vec<ShiftWindow>,
+    pub unavailability: Vec<Unavailability>,
+    // Delivery zones the driver works in, used for coverage reporting
+    pub zone_ids: Vec<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CoverageGap {
+    pub zone_id: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+thread_local! {
+    static SCHEDULES: RefCell<HashMap<Principal, DriverSchedule>> = RefCell::new(HashMap::new());
+    static UNAVAILABILITY_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Driver self-service
+#[update]
+fn set_driver_shifts(weekly_shifts: Vec<ShiftWindow>, zone_ids: Vec<String>) -> Result<DriverSchedule, String> {
+    metrics::observe("set_driver_shifts", || {
+        let caller = ic_cdk::caller();
+        require_driver(caller)?;
+        for w in &weekly_shifts {
+            if w.day_of_week > 6 || w.end_minute > 1440 || w.start_minute >= w.end_minute {
+                return Err("Invalid shift window".to_string());
+            }
+        }
+        if let Some(unknown) = zone_ids.iter().find(|z| !zones::zone_exists(z)) {
+            return Err(format!("Delivery zone not found: {}", unknown));
+        }
+
+        Ok(SCHEDULES.with(|schedules| {
+            let mut schedules_map = schedules.borrow_mut();
+            let schedule = schedules_map.entry(caller).or_default();
+            schedule.weekly_shifts = weekly_shifts;
+            schedule.zone_ids = zone_ids;
+            schedule.clone()
+        }))
+    })
+}
+
+#[update]
+fn add_driver_unavailability(start: u64, end: u64, reason: Option<String>) -> Result<Unavailability, String> {
+    metrics::observe("add_driver_unavailability", || {
+        let caller = ic_cdk::caller();
+        require_driver(caller)?;
+        if start >= end {
+            return Err("Unavailability must end after it starts".to_string());
+        }
+
+        let id = UNAVAILABILITY_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            *c
+        });
+        let entry = Unavailability { id, start, end, reason };
+
+        SCHEDULES.with(|schedules| {
+            let mut schedules_map = schedules.borrow_mut();
+            let schedule = schedules_map.entry(caller).or_default();
+            // Drop periods that are already over before enforcing the cap
+            let now = ic_cdk::api::time();
+            schedule.unavailability.retain(|u| u.end > now);
+            if schedule.unavailability.len() >= MAX_UNAVAILABILITY {
+                return Err(format!("At most {} unavailability periods can be scheduled", MAX_UNAVAILABILITY));
+            }
+            schedule.unavailability.push(entry.clone());
+            Ok(entry)
+        })
+    })
+}
+
+#[update]
+fn remove_driver_unavailability(id: u64) -> Result<(), String> {
+    metrics::observe("remove_driver_unavailability", || {
+        let caller = ic_cdk::caller();
+        SCHEDULES.with(|schedules| {
+            let mut schedules_map = schedules.borrow_mut();
+            let schedule = schedules_map
+                .get_mut(&caller)
+                .ok_or_else(|| "Unavailability not found".to_string())?;
+            let before = schedule.unavailability.len();
+            schedule.unavailability.retain(|u| u.id != id);
+            if schedule.unavailability.len() == before {
+                return Err("Unavailability not found".to_string());
+            }
+            Ok(())
+        })
+    })
+}
+
+#[query]
+fn get_driver_schedule(driver_id: Principal) -> Result<DriverSchedule, String> {
+    let caller = ic_cdk::caller();
+    if caller != driver_id && !permissions::has(&caller, Permission::AssignDriver) {
+        return Err("Unauthorized to view driver schedule".to_string());
+    }
+    Ok(SCHEDULES.with(|schedules| schedules.borrow().get(&driver_id).cloned().unwrap_or_default()))
+}
+
+// Admin report: windows in [from, to) during which no verified driver working the
+// zone is on shift, at half-hour resolution
+#[query]
+fn get_coverage_gaps(zone_id: Option<String>, from: u64, to: u64) -> Result<Vec<CoverageGap>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ViewReports)?;
+    if from >= to || to - from > MAX_COVERAGE_RANGE_NANOS {
+        return Err("Coverage range must be positive and at most 7 days".to_string());
+    }
+
+    let zone_ids = match zone_id {
+        Some(z) if zones::zone_exists(&z) => vec![z],
+        Some(_) => return Err("Delivery zone not found".to_string()),
+        None => zones::active_zone_ids(),
+    };
+    let verified = verified_drivers();
+    let slot = SLOT_MINUTES * NANOS_PER_MINUTE;
+
+    let mut gaps = Vec::new();
+    for zone_id in zone_ids {
+        let zone_drivers: Vec<Principal> = SCHEDULES.with(|schedules| {
+            let schedules_map = schedules.borrow();
+            verified
+                .iter()
+                .filter(|d| schedules_map.get(d).map(|s| s.zone_ids.contains(&zone_id)).unwrap_or(false))
+                .cloned()
+                .collect()
+        });
+
+        let mut open_gap: Option<CoverageGap> = None;
+        let mut t = from;
+        while t < to {
+            let end = (t + slot).min(to);
+            let covered = zone_drivers.iter().any(|d| is_on_shift(d, t));
+            match (&mut open_gap, covered) {
+                (Some(gap), false) => gap.end = end,
+                (None, false) => {
+                    open_gap = Some(CoverageGap {
+                        zone_id: zone_id.clone(),
+                        start: t,
+                        end,
+                    })
+                },
+                (Some(_), true) => gaps.extend(open_gap.take()),
+                (None, true) => {},
+            }
+            t = end;
+        }
+        gaps.extend(open_gap);
+    }
+
+    Ok(gaps)
+}
+
+// On shift at `at`: inside a weekly window and outside every unavailability period.
+// Drivers who never declared shifts are treated as always on shift.
+pub(crate) fn is_on_shift(driver_id: &Principal, at: u64) -> bool {
+    SCHEDULES.with(|schedules| {
+        let schedules_map = schedules.borrow();
+        let schedule = match schedules_map.get(driver_id) {
+            Some(s) => s,
+            None => return true,
+        };
+        if schedule.unavailability.iter().any(|u| at >= u.start && at < u.end) {
+            return false;
+        }
+        if schedule.weekly_shifts.is_empty() {
+            return true;
+        }
+
+        let minutes = at / NANOS_PER_MINUTE;
+        // 1970-01-01 was a Thursday
+        let day_of_week = ((minutes / 1440 + 3) % 7) as u8;
+        let minute_of_day = (minutes % 1440) as u16;
+        schedule.weekly_shifts.iter().any(|w| {
+            w.day_of_week == day_of_week && minute_of_day >= w.start_minute && minute_of_day < w.end_minute
+        })
+    })
+}
+
+fn verified_drivers() -> Vec<Principal> {
+    DRIVERS.with(|drivers| {
+        drivers
+            .borrow()
+            .values()
+            .filter(|d| matches!(d.verification_status, VerificationStatus::Verified))
+            .filter(|d| accounts::is_active(&d.id))
+            .map(|d| d.id)
+            .collect()
+    })
+}
+
+fn require_driver(caller: Principal) -> Result<(), String> {
+    let registered = DRIVERS.with(|drivers| drivers.borrow().contains_key(&caller));
+    if !registered {
+        return Err("Driver not registered".to_string());
+    }
+    Ok(())
+}
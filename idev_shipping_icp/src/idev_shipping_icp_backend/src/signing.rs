@@ -0,0 +1,123 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument, SignWithEcdsaArgument, SignWithEcdsaResponse,
+};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::resource_usage::{self, ResourceFeature};
+
+// Fee for one signature with the production key; unused cycles are refunded
+const SIGN_CYCLES: u128 = 26_153_846_153;
+
+// Signatures for different documents come from different derived keys, so one
+// kind of document can never be passed off as another
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub enum SigningPurpose {
+    ShipmentCertificate,
+    DeliveryReceipt,
+}
+
+impl SigningPurpose {
+    fn derivation_path(&self) -> Vec<Vec<u8>> {
+        let label: &[u8] = match self {
+            SigningPurpose::ShipmentCertificate => b"shipment-certificate",
+            SigningPurpose::DeliveryReceipt => b"delivery-receipt",
+        };
+        vec![label.to_vec()]
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SigningKey {
+    pub purpose: SigningPurpose,
+    pub key_name: String,
+    // SEC1-compressed secp256k1 public key
+    pub public_key: Vec<u8>,
+}
+
+pub(crate) struct Signature {
+    pub key_name: String,
+    pub signature: Vec<u8>,
+}
+
+thread_local! {
+    // `dfx_test_key` locally, `test_key_1` or `key_1` on mainnet
+    static KEY_NAME: RefCell<String> = RefCell::new("key_1".to_string());
+    static PUBLIC_KEYS: RefCell<HashMap<(String, SigningPurpose), Vec<u8>>> = RefCell::new(HashMap::new());
+}
+
+#[update]
+fn set_signing_key_name(key_name: String) -> Result<String, String> {
+    metrics::observe("set_signing_key_name", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if key_name.trim().is_empty() {
+            return Err("Key name cannot be empty".to_string());
+        }
+        KEY_NAME.with(|k| *k.borrow_mut() = key_name.clone());
+        Ok(key_name)
+    })
+}
+
+// The key external parties verify signatures against
+#[update]
+async fn get_signing_key(purpose: SigningPurpose) -> Result<SigningKey, String> {
+    metrics::observe_async("get_signing_key", async move {
+        let key_name = KEY_NAME.with(|k| k.borrow().clone());
+        let public_key = public_key(&key_name, purpose).await?;
+        Ok(SigningKey {
+            purpose,
+            key_name,
+            public_key,
+        })
+    })
+    .await
+}
+
+// Threshold-ECDSA signature over a SHA-256 digest. The fee is attributed to `account`.
+pub(crate) async fn sign(purpose: SigningPurpose, digest: [u8; 32], account: Principal) -> Result<Signature, String> {
+    let key_name = KEY_NAME.with(|k| k.borrow().clone());
+    let arg = SignWithEcdsaArgument {
+        message_hash: digest.to_vec(),
+        derivation_path: purpose.derivation_path(),
+        key_id: key_id(&key_name),
+    };
+    resource_usage::record(account, None, ResourceFeature::Signature, SIGN_CYCLES, 0);
+    let management = Principal::management_canister();
+    let (response,): (SignWithEcdsaResponse,) =
+        ic_cdk::api::call::call_with_payment128(management, "sign_with_ecdsa", (arg,), SIGN_CYCLES)
+            .await
+            .map_err(|(code, message)| format!("Signing failed: {:?}: {}", code, message))?;
+    Ok(Signature {
+        key_name,
+        signature: response.signature,
+    })
+}
+
+async fn public_key(key_name: &str, purpose: SigningPurpose) -> Result<Vec<u8>, String> {
+    let cache_key = (key_name.to_string(), purpose);
+    if let Some(key) = PUBLIC_KEYS.with(|keys| keys.borrow().get(&cache_key).cloned()) {
+        return Ok(key);
+    }
+    let arg = EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: purpose.derivation_path(),
+        key_id: key_id(key_name),
+    };
+    let (response,) = ecdsa_public_key(arg)
+        .await
+        .map_err(|(code, message)| format!("Public key lookup failed: {:?}: {}", code, message))?;
+    PUBLIC_KEYS.with(|keys| keys.borrow_mut().insert(cache_key, response.public_key.clone()));
+    Ok(response.public_key)
+}
+
+fn key_id(key_name: &str) -> EcdsaKeyId {
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: key_name.to_string(),
+    }
+}
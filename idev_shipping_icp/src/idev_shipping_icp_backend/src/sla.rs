@@ -0,0 +1,255 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::credits::{self, CreditSource};
+use crate::metrics;
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::service_level::ServiceLevel;
+use crate::zones::ShipmentZones;
+use crate::{Shipment, ShipmentStatus, SHIPMENTS, USERS};
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const NANOS_PER_DAY: u64 = 24 * NANOS_PER_HOUR;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SlaPolicy {
+    // Undelivered shipments this close to their deadline are reported as at risk
+    pub at_risk_window_hours: u64,
+    // Credit granted to the sender when a deadline is missed, in basis points of the price
+    pub compensation_enabled: bool,
+    pub compensation_bps: u32,
+    pub compensation_validity_days: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum SlaState {
+    AtRisk,
+    Breached,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AtRiskShipment {
+    pub shipment_id: String,
+    pub status: ShipmentStatus,
+    pub service_level: ServiceLevel,
+    pub sla_deadline: u64,
+    pub state: SlaState,
+    pub flagged_at: u64,
+    pub compensation_credit_id: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+struct SlaFlag {
+    state: SlaState,
+    flagged_at: u64,
+    compensation_credit_id: Option<String>,
+}
+
+thread_local! {
+    static SLA_POLICY: RefCell<SlaPolicy> = RefCell::new(SlaPolicy {
+        at_risk_window_hours: 6,
+        compensation_enabled: false,
+        compensation_bps: 1_000,
+        compensation_validity_days: Some(90),
+    });
+    static SLA_FLAGS: RefCell<HashMap<String, SlaFlag>> = RefCell::new(HashMap::new());
+}
+
+// Admin configuration
+#[update]
+fn set_sla_policy(policy: SlaPolicy) -> Result<SlaPolicy, String> {
+    metrics::observe("set_sla_policy", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if policy.compensation_bps > 10_000 {
+            return Err("Compensation cannot exceed the shipment price".to_string());
+        }
+        SLA_POLICY.with(|p| *p.borrow_mut() = policy.clone());
+        Ok(policy)
+    })
+}
+
+#[query]
+fn get_sla_policy() -> SlaPolicy {
+    SLA_POLICY.with(|p| p.borrow().clone())
+}
+
+// Shipments flagged by the SLA job, breached first, then by deadline
+#[query]
+fn get_at_risk_shipments() -> Result<Vec<AtRiskShipment>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ViewReports)?;
+
+    let mut entries: Vec<AtRiskShipment> = SLA_FLAGS.with(|flags| {
+        SHIPMENTS.with(|shipments| {
+            let shipments_map = shipments.borrow();
+            flags
+                .borrow()
+                .iter()
+                .filter_map(|(id, flag)| {
+                    let s = shipments_map.get(id)?;
+                    Some(AtRiskShipment {
+                        shipment_id: id.clone(),
+                        status: s.status.clone(),
+                        service_level: s.service_level.clone(),
+                        sla_deadline: s.sla_deadline,
+                        state: flag.state.clone(),
+                        flagged_at: flag.flagged_at,
+                        compensation_credit_id: flag.compensation_credit_id.clone(),
+                    })
+                })
+                .collect()
+        })
+    });
+    entries.sort_by(|a, b| {
+        (a.state != SlaState::Breached)
+            .cmp(&(b.state != SlaState::Breached))
+            .then(a.sla_deadline.cmp(&b.sla_deadline))
+            .then_with(|| a.shipment_id.cmp(&b.shipment_id))
+    });
+    Ok(entries)
+}
+
+pub(crate) fn target_nanos(level: &ServiceLevel, zones: &ShipmentZones) -> u64 {
+    level.target_nanos() + zones.sla_extra_hours * NANOS_PER_HOUR
+}
+
+// Flagged shipments still on their way: (at risk, past their deadline)
+pub(crate) fn open_flag_counts() -> (u32, u32) {
+    SLA_FLAGS.with(|flags| {
+        SHIPMENTS.with(|shipments| {
+            let shipments = shipments.borrow();
+            flags
+                .borrow()
+                .iter()
+                .filter(|(id, _)| {
+                    shipments.get(*id).is_some_and(|s| {
+                        !matches!(
+                            s.status,
+                            ShipmentStatus::Delivered
+                                | ShipmentStatus::AwaitingConfirmation
+                                | ShipmentStatus::Cancelled
+                                | ShipmentStatus::Returned
+                        )
+                    })
+                })
+                .fold((0, 0), |(at_risk, breached), (_, flag)| match flag.state {
+                    SlaState::AtRisk => (at_risk + 1, breached),
+                    SlaState::Breached => (at_risk, breached + 1),
+                })
+        })
+    })
+}
+
+// Timer job: flag shipments close to or past their deadline, alert the sender and
+// admins once per state, and compensate breaches when enabled
+pub(crate) fn check_sla() {
+    let now = time();
+    let policy = SLA_POLICY.with(|p| p.borrow().clone());
+    let at_risk_window = policy.at_risk_window_hours * NANOS_PER_HOUR;
+
+    let transitions: Vec<(Shipment, SlaState)> = SHIPMENTS.with(|shipments| {
+        SLA_FLAGS.with(|flags| {
+            let flags = flags.borrow();
+            shipments
+                .borrow()
+                .values()
+                .filter_map(|s| {
+                    let state = evaluate(s, now, at_risk_window)?;
+                    let current = flags.get(&s.id).map(|f| &f.state);
+                    // Only move forward: nothing -> at risk -> breached
+                    match (current, &state) {
+                        (Some(SlaState::Breached), _) | (Some(SlaState::AtRisk), SlaState::AtRisk) => None,
+                        _ => Some((s.clone(), state)),
+                    }
+                })
+                .collect()
+        })
+    });
+
+    // Shipments delivered in time no longer need attention
+    SLA_FLAGS.with(|flags| {
+        SHIPMENTS.with(|shipments| {
+            let shipments_map = shipments.borrow();
+            flags.borrow_mut().retain(|id, flag| {
+                flag.state == SlaState::Breached
+                    || shipments_map
+                        .get(id)
+                        .map(|s| evaluate(s, now, at_risk_window).is_some())
+                        .unwrap_or(false)
+            });
+        })
+    });
+
+    let admins: Vec<Principal> = USERS.with(|users| {
+        users
+            .borrow()
+            .values()
+            .filter(|u| permissions::has(&u.id, Permission::ViewReports))
+            .map(|u| u.id)
+            .collect()
+    });
+
+    for (shipment, state) in transitions {
+        let message = match state {
+            SlaState::AtRisk => format!("Shipment {} is at risk of missing its delivery target", shipment.id),
+            SlaState::Breached => format!("Shipment {} missed its delivery target", shipment.id),
+        };
+        let compensation_credit_id = match state {
+            SlaState::Breached => compensate(&shipment, &policy, now),
+            SlaState::AtRisk => None,
+        };
+
+        notifications::notify(shipment.sender_id, NotificationKind::System, Some(&shipment.id), message.clone());
+        for admin in &admins {
+            notifications::notify(*admin, NotificationKind::System, Some(&shipment.id), message.clone());
+        }
+
+        SLA_FLAGS.with(|flags| {
+            flags.borrow_mut().insert(
+                shipment.id.clone(),
+                SlaFlag {
+                    state,
+                    flagged_at: now,
+                    compensation_credit_id,
+                },
+            );
+        });
+    }
+}
+
+// State a shipment should be in, or None when it is on track or exempt
+fn evaluate(s: &Shipment, now: u64, at_risk_window: u64) -> Option<SlaState> {
+    match s.status {
+        ShipmentStatus::Cancelled | ShipmentStatus::Returned => None,
+        ShipmentStatus::Delivered | ShipmentStatus::AwaitingConfirmation => {
+            let delivered_at = s.actual_delivery.unwrap_or(s.updated_at);
+            (delivered_at > s.sla_deadline).then_some(SlaState::Breached)
+        },
+        _ if now > s.sla_deadline => Some(SlaState::Breached),
+        _ if now + at_risk_window > s.sla_deadline => Some(SlaState::AtRisk),
+        _ => None,
+    }
+}
+
+fn compensate(shipment: &Shipment, policy: &SlaPolicy, now: u64) -> Option<String> {
+    if !policy.compensation_enabled || policy.compensation_bps == 0 {
+        return None;
+    }
+    let amount = shipment.price.mul_ratio(policy.compensation_bps as u128, 10_000);
+    if amount.is_zero() {
+        return None;
+    }
+    let entry = credits::grant(
+        shipment.sender_id,
+        CreditSource::SlaCompensation,
+        amount,
+        policy.compensation_validity_days.map(|d| now + d * NANOS_PER_DAY),
+        Some(shipment.id.clone()),
+    );
+    Some(entry.id)
+}
@@ -0,0 +1,656 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use crate::analytics::ReportPeriod;
+use crate::credits;
+use crate::metrics;
+use crate::money::{Money, BASE_CURRENCY};
+use crate::notifications::{self, NotificationKind};
+use crate::permissions::{self, Permission};
+use crate::pudo::{validate_opening_hours, OpeningHours};
+use crate::validation::{Validator, MAX_NAME_LEN};
+use crate::{
+    Address, ReturnStatus, Shipment, ShipmentStatus, ShipmentSummary, RETURN_REQUESTS, SHIPMENTS, USERS,
+};
+
+const MAX_STAFF: usize = 50;
+const INVITATION_TTL_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_PAGE_SIZE: u32 = 100;
+const TOP_DESTINATIONS: usize = 10;
+pub(crate) const DEFAULT_RETURN_WINDOW_DAYS: u32 = 30;
+const MAX_RETURN_WINDOW_DAYS: u32 = 365;
+
+// Store data structures
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Store {
+    pub id: String,
+    pub owner: Principal,
+    pub name: String,
+    pub address: Address,
+    pub opening_hours: Vec<OpeningHours>,
+    // Where parcels are collected; the store address when not set
+    pub default_pickup_address: Option<Address>,
+    // Everyone but `owner`, who always holds the Owner role and cannot be removed
+    pub members: Vec<StoreMember>,
+    pub is_active: bool,
+    pub created_at: u64,
+    // Days after delivery during which recipients may request a return
+    pub return_window_days: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum StoreRole {
+    Owner,
+    // Runs day-to-day operations and the packers, but not the store's settings
+    Manager,
+    // Creates and looks up the store's shipments
+    Packer,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum StoreAction {
+    ManageSettings,
+    ManageMembers,
+    ViewReports,
+    ManageShipments,
+    CreateShipments,
+}
+
+impl StoreRole {
+    pub(crate) fn allows(self, action: StoreAction) -> bool {
+        match self {
+            StoreRole::Owner => true,
+            StoreRole::Manager => action != StoreAction::ManageSettings,
+            StoreRole::Packer => action == StoreAction::CreateShipments,
+        }
+    }
+
+    // Roles this role may invite, change or remove
+    fn can_manage(self, other: StoreRole) -> bool {
+        match self {
+            StoreRole::Owner => true,
+            StoreRole::Manager => other == StoreRole::Packer,
+            StoreRole::Packer => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StoreMember {
+    pub principal: Principal,
+    pub role: StoreRole,
+    pub added_by: Principal,
+    pub added_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StoreInvitation {
+    pub store_id: String,
+    pub store_name: String,
+    pub invitee: Principal,
+    pub role: StoreRole,
+    pub invited_by: Principal,
+    pub invited_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StoreSummary {
+    pub store_id: String,
+    pub total_shipments: u32,
+    pub active_shipments: u32,
+    pub delivered_shipments: u32,
+    pub cancelled_shipments: u32,
+    pub total_spend: Money,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DestinationCount {
+    pub city: String,
+    pub country: String,
+    pub shipments: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PromoRedemptionCount {
+    pub code: String,
+    pub redemptions: u32,
+}
+
+// Figures for the store's shipments created in the period
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StoreStats {
+    pub store_id: String,
+    pub shipment_count: u32,
+    pub delivered_count: u32,
+    // Price of shipments that were not cancelled
+    pub spend: Money,
+    // Delivered shipments with a return requested and not rejected
+    pub return_count: u32,
+    pub return_rate_bps: u32,
+    pub average_delivery_secs: Option<u64>,
+    pub top_destinations: Vec<DestinationCount>,
+    pub promo_redemptions: Vec<PromoRedemptionCount>,
+}
+
+thread_local! {
+    static STORES: RefCell<HashMap<String, Store>> = RefCell::new(HashMap::new());
+    static STORE_COUNTER: RefCell<u64> = RefCell::new(0);
+    // Keyed by (store id, invitee)
+    static INVITATIONS: RefCell<HashMap<(String, Principal), StoreInvitation>> = RefCell::new(HashMap::new());
+}
+
+// Store management
+#[update]
+fn create_store(
+    name: String,
+    address: Address,
+    opening_hours: Vec<OpeningHours>,
+    default_pickup_address: Option<Address>,
+) -> Result<Store, String> {
+    metrics::observe("create_store", || {
+        let caller = ic_cdk::caller();
+        if !USERS.with(|users| users.borrow().contains_key(&caller)) {
+            return Err("User not registered".to_string());
+        }
+        permissions::require(&caller, Permission::ManageStores)?;
+        if name.trim().is_empty() {
+            return Err("Store name cannot be empty".to_string());
+        }
+        let mut v = Validator::new();
+        v.max_len("name", &name, MAX_NAME_LEN);
+        v.address("address", &address);
+        if let Some(pickup) = &default_pickup_address {
+            v.address("default_pickup_address", pickup);
+        }
+        v.finish()?;
+        validate_opening_hours(&opening_hours)?;
+
+        let store_id = STORE_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            format!("ST{:06}", *c)
+        });
+
+        let store = Store {
+            id: store_id.clone(),
+            owner: caller,
+            name,
+            address,
+            opening_hours,
+            default_pickup_address,
+            members: Vec::new(),
+            is_active: true,
+            created_at: time(),
+            return_window_days: DEFAULT_RETURN_WINDOW_DAYS,
+        };
+
+        STORES.with(|stores| {
+            stores.borrow_mut().insert(store_id, store.clone());
+        });
+
+        Ok(store)
+    })
+}
+
+#[update]
+fn update_store(
+    store_id: String,
+    name: Option<String>,
+    address: Option<Address>,
+    opening_hours: Option<Vec<OpeningHours>>,
+    default_pickup_address: Option<Address>,
+    is_active: Option<bool>,
+    return_window_days: Option<u32>,
+) -> Result<Store, String> {
+    metrics::observe("update_store", || {
+        let caller = ic_cdk::caller();
+        if return_window_days.is_some_and(|days| days > MAX_RETURN_WINDOW_DAYS) {
+            return Err(format!("Return window cannot exceed {} days", MAX_RETURN_WINDOW_DAYS));
+        }
+        if let Some(hours) = &opening_hours {
+            validate_opening_hours(hours)?;
+        }
+        let mut v = Validator::new();
+        if let Some(name) = &name {
+            v.required("name", name, MAX_NAME_LEN);
+        }
+        for (field, address) in [("address", &address), ("default_pickup_address", &default_pickup_address)] {
+            if let Some(address) = address {
+                v.address(field, address);
+            }
+        }
+        v.finish()?;
+
+        with_store(&store_id, caller, StoreAction::ManageSettings, |store| {
+            if let Some(name) = name {
+                store.name = name;
+            }
+            if let Some(address) = address {
+                store.address = address;
+            }
+            if let Some(hours) = opening_hours {
+                store.opening_hours = hours;
+            }
+            if default_pickup_address.is_some() {
+                store.default_pickup_address = default_pickup_address;
+            }
+            if let Some(is_active) = is_active {
+                store.is_active = is_active;
+            }
+            if let Some(days) = return_window_days {
+                store.return_window_days = days;
+            }
+            Ok(())
+        })
+    })
+}
+
+// Members join by accepting; Managers may only invite Packers
+#[update]
+fn invite_store_member(store_id: String, invitee: Principal, role: StoreRole) -> Result<StoreInvitation, String> {
+    metrics::observe("invite_store_member", || {
+        let caller = ic_cdk::caller();
+        let registered = USERS.with(|users| users.borrow().contains_key(&invitee));
+        if !registered {
+            return Err("Invitee is not a registered user".to_string());
+        }
+        let store = with_store(&store_id, caller, StoreAction::ManageMembers, |store| {
+            check_manages(store, caller, role)?;
+            if role_of(store, invitee).is_some() {
+                return Err("Principal is already a member of this store".to_string());
+            }
+            if store.members.len() >= MAX_STAFF {
+                return Err(format!("Stores can have at most {} staff members", MAX_STAFF));
+            }
+            Ok(())
+        })?;
+
+        let now = time();
+        let invitation = StoreInvitation {
+            store_id: store_id.clone(),
+            store_name: store.name.clone(),
+            invitee,
+            role,
+            invited_by: caller,
+            invited_at: now,
+            expires_at: now + INVITATION_TTL_NANOS,
+        };
+        INVITATIONS.with(|i| i.borrow_mut().insert((store_id, invitee), invitation.clone()));
+        notifications::notify(
+            invitee,
+            NotificationKind::System,
+            None,
+            format!("You were invited to join {} as {:?}", store.name, role),
+        );
+        Ok(invitation)
+    })
+}
+
+#[query]
+fn get_my_store_invitations() -> Vec<StoreInvitation> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    INVITATIONS.with(|i| {
+        i.borrow()
+            .values()
+            .filter(|inv| inv.invitee == caller && inv.expires_at > now)
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+fn get_store_invitations(store_id: String) -> Result<Vec<StoreInvitation>, String> {
+    let caller = ic_cdk::caller();
+    let store = find(&store_id).ok_or_else(|| "Store not found".to_string())?;
+    if !can(&store, caller, StoreAction::ManageMembers) {
+        return Err("Unauthorized to manage store members".to_string());
+    }
+    let now = time();
+    Ok(INVITATIONS.with(|i| {
+        i.borrow()
+            .values()
+            .filter(|inv| inv.store_id == store_id && inv.expires_at > now)
+            .cloned()
+            .collect()
+    }))
+}
+
+#[update]
+fn accept_store_invitation(store_id: String) -> Result<Store, String> {
+    metrics::observe("accept_store_invitation", || {
+        let caller = ic_cdk::caller();
+        let invitation = INVITATIONS
+            .with(|i| i.borrow_mut().remove(&(store_id.clone(), caller)))
+            .filter(|inv| inv.expires_at > time())
+            .ok_or_else(|| "No pending invitation for this store".to_string())?;
+        STORES.with(|stores| {
+            let mut stores = stores.borrow_mut();
+            let store = stores.get_mut(&store_id).ok_or_else(|| "Store not found".to_string())?;
+            if role_of(store, caller).is_some() {
+                return Err("Principal is already a member of this store".to_string());
+            }
+            if store.members.len() >= MAX_STAFF {
+                return Err(format!("Stores can have at most {} staff members", MAX_STAFF));
+            }
+            store.members.push(StoreMember {
+                principal: caller,
+                role: invitation.role,
+                added_by: invitation.invited_by,
+                added_at: time(),
+            });
+            Ok(store.clone())
+        })
+    })
+}
+
+// Declined by the invitee or withdrawn by a member who could have sent it
+#[update]
+fn cancel_store_invitation(store_id: String, invitee: Principal) -> Result<(), String> {
+    metrics::observe("cancel_store_invitation", || {
+        let caller = ic_cdk::caller();
+        let key = (store_id.clone(), invitee);
+        let invitation = INVITATIONS
+            .with(|i| i.borrow().get(&key).cloned())
+            .ok_or_else(|| "No pending invitation for this store".to_string())?;
+        if caller != invitee {
+            let store = find(&store_id).ok_or_else(|| "Store not found".to_string())?;
+            if !can(&store, caller, StoreAction::ManageMembers) {
+                return Err("Unauthorized to manage store members".to_string());
+            }
+            check_manages(&store, caller, invitation.role)?;
+        }
+        INVITATIONS.with(|i| i.borrow_mut().remove(&key));
+        Ok(())
+    })
+}
+
+#[update]
+fn set_store_member_role(store_id: String, member: Principal, role: StoreRole) -> Result<Store, String> {
+    metrics::observe("set_store_member_role", || {
+        let caller = ic_cdk::caller();
+        with_store(&store_id, caller, StoreAction::ManageMembers, |store| {
+            let current = member_role(store, member)?;
+            check_manages(store, caller, current)?;
+            check_manages(store, caller, role)?;
+            if let Some(entry) = store.members.iter_mut().find(|m| m.principal == member) {
+                entry.role = role;
+            }
+            Ok(())
+        })
+    })
+}
+
+// Members may also leave on their own
+#[update]
+fn remove_store_member(store_id: String, member: Principal) -> Result<Store, String> {
+    metrics::observe("remove_store_member", || {
+        let caller = ic_cdk::caller();
+        // Any role may leave
+        let action = if caller == member {
+            StoreAction::CreateShipments
+        } else {
+            StoreAction::ManageMembers
+        };
+        with_store(&store_id, caller, action, |store| {
+            let role = member_role(store, member)?;
+            if caller != member {
+                check_manages(store, caller, role)?;
+            }
+            store.members.retain(|m| m.principal != member);
+            Ok(())
+        })
+    })
+}
+
+#[query]
+fn get_store(store_id: String) -> Option<Store> {
+    STORES.with(|stores| stores.borrow().get(&store_id).cloned())
+}
+
+// Stores the caller owns or works at
+#[query]
+fn get_my_stores() -> Vec<Store> {
+    let caller = ic_cdk::caller();
+    STORES.with(|stores| {
+        stores
+            .borrow()
+            .values()
+            .filter(|s| is_member(s, caller))
+            .cloned()
+            .collect()
+    })
+}
+
+// Newest first
+#[query]
+fn get_store_shipments(
+    store_id: String,
+    offset: Option<u32>,
+    limit: Option<u32>,
+) -> Result<Vec<ShipmentSummary>, String> {
+    let caller = ic_cdk::caller();
+    authorize_store_view(&store_id, caller, None)?;
+
+    let mut shipments = store_shipments(&store_id);
+    shipments.sort_by(|a, b| b.created_at.cmp(&a.created_at).then_with(|| b.id.cmp(&a.id)));
+    Ok(shipments
+        .into_iter()
+        .skip(offset.unwrap_or(0) as usize)
+        .take(limit.unwrap_or(MAX_PAGE_SIZE).min(MAX_PAGE_SIZE) as usize)
+        .map(|s| s.summary())
+        .collect())
+}
+
+#[query]
+fn get_store_summary(store_id: String) -> Result<StoreSummary, String> {
+    let caller = ic_cdk::caller();
+    authorize_store_view(&store_id, caller, Some(StoreAction::ViewReports))?;
+
+    let shipments = store_shipments(&store_id);
+    let count = |pred: fn(&ShipmentStatus) -> bool| shipments.iter().filter(|s| pred(&s.status)).count() as u32;
+    Ok(StoreSummary {
+        store_id,
+        total_shipments: shipments.len() as u32,
+        active_shipments: count(|s| {
+            !matches!(
+                s,
+                ShipmentStatus::Delivered | ShipmentStatus::Cancelled | ShipmentStatus::Returned | ShipmentStatus::Failed
+            )
+        }),
+        delivered_shipments: count(|s| matches!(s, ShipmentStatus::Delivered)),
+        cancelled_shipments: count(|s| matches!(s, ShipmentStatus::Cancelled)),
+        total_spend: Money::sum(
+            shipments
+                .iter()
+                .filter(|s| !matches!(s.status, ShipmentStatus::Cancelled))
+                .map(|s| s.price),
+            BASE_CURRENCY,
+        ),
+    })
+}
+
+// Lifetime figures when no period is given
+#[query]
+fn get_store_stats(store_id: String, period: Option<ReportPeriod>) -> Result<StoreStats, String> {
+    let caller = ic_cdk::caller();
+    authorize_store_view(&store_id, caller, Some(StoreAction::ViewReports))?;
+    let (from, to) = period.map_or((0, u64::MAX), |p| (p.from, p.to));
+    if to <= from {
+        return Err("Report period is empty".to_string());
+    }
+
+    let shipments: Vec<Shipment> = store_shipments(&store_id)
+        .into_iter()
+        .filter(|s| s.created_at >= from && s.created_at < to)
+        .collect();
+    let returned: HashSet<String> = RETURN_REQUESTS.with(|returns| {
+        returns
+            .borrow()
+            .values()
+            .filter(|r| !matches!(r.status, ReturnStatus::Rejected))
+            .map(|r| r.shipment_id.clone())
+            .collect()
+    });
+
+    let mut delivered_count = 0;
+    let mut return_count = 0;
+    let mut delivery_secs = Vec::new();
+    let mut destinations: HashMap<(String, String), u32> = HashMap::new();
+    let mut promos: HashMap<String, u32> = HashMap::new();
+    for shipment in &shipments {
+        let address = &shipment.delivery_address;
+        *destinations.entry((address.city.clone(), address.country.clone())).or_default() += 1;
+        if let Some(code) = credits::promo_code_for(&shipment.id) {
+            *promos.entry(code).or_default() += 1;
+        }
+        if let Some(delivered_at) = shipment.actual_delivery {
+            delivered_count += 1;
+            delivery_secs.push(delivered_at.saturating_sub(shipment.created_at) / 1_000_000_000);
+            if returned.contains(&shipment.id) {
+                return_count += 1;
+            }
+        }
+    }
+
+    let mut top_destinations: Vec<DestinationCount> = destinations
+        .into_iter()
+        .map(|((city, country), shipments)| DestinationCount { city, country, shipments })
+        .collect();
+    top_destinations.sort_by(|a, b| b.shipments.cmp(&a.shipments).then_with(|| a.city.cmp(&b.city)));
+    top_destinations.truncate(TOP_DESTINATIONS);
+    let mut promo_redemptions: Vec<PromoRedemptionCount> = promos
+        .into_iter()
+        .map(|(code, redemptions)| PromoRedemptionCount { code, redemptions })
+        .collect();
+    promo_redemptions.sort_by(|a, b| b.redemptions.cmp(&a.redemptions).then_with(|| a.code.cmp(&b.code)));
+
+    Ok(StoreStats {
+        store_id,
+        shipment_count: shipments.len() as u32,
+        delivered_count,
+        spend: Money::sum(
+            shipments
+                .iter()
+                .filter(|s| !matches!(s.status, ShipmentStatus::Cancelled))
+                .map(|s| s.price),
+            BASE_CURRENCY,
+        ),
+        return_count,
+        return_rate_bps: (return_count * 10_000).checked_div(delivered_count).unwrap_or(0),
+        average_delivery_secs: (!delivery_secs.is_empty())
+            .then(|| delivery_secs.iter().sum::<u64>() / delivery_secs.len() as u64),
+        top_destinations,
+        promo_redemptions,
+    })
+}
+
+// Store an order is placed for; the caller must own or work at an active store
+pub(crate) fn store_for_shipment(store_id: &str, caller: Principal) -> Result<Store, String> {
+    let store = STORES
+        .with(|stores| stores.borrow().get(store_id).cloned())
+        .ok_or_else(|| "Store not found".to_string())?;
+    if !is_member(&store, caller) {
+        return Err("Unauthorized to create shipments for this store".to_string());
+    }
+    if !store.is_active {
+        return Err("Store is not active".to_string());
+    }
+    Ok(store)
+}
+
+pub(crate) fn find(store_id: &str) -> Option<Store> {
+    STORES.with(|stores| stores.borrow().get(store_id).cloned())
+}
+
+pub(crate) fn len() -> usize {
+    STORES.with(|stores| stores.borrow().len())
+}
+
+pub(crate) fn is_member(store: &Store, principal: Principal) -> bool {
+    role_of(store, principal).is_some()
+}
+
+pub(crate) fn role_of(store: &Store, principal: Principal) -> Option<StoreRole> {
+    if store.owner == principal {
+        return Some(StoreRole::Owner);
+    }
+    store.members.iter().find(|m| m.principal == principal).map(|m| m.role)
+}
+
+pub(crate) fn can(store: &Store, principal: Principal, action: StoreAction) -> bool {
+    role_of(store, principal).is_some_and(|role| role.allows(action))
+}
+
+// Staff of the shipment's store who may act on it as its sender would
+pub(crate) fn can_manage_shipment(shipment: &Shipment, principal: Principal) -> bool {
+    shipment
+        .store_id
+        .as_deref()
+        .and_then(find)
+        .is_some_and(|store| can(&store, principal, StoreAction::ManageShipments))
+}
+
+fn member_role(store: &Store, member: Principal) -> Result<StoreRole, String> {
+    store
+        .members
+        .iter()
+        .find(|m| m.principal == member)
+        .map(|m| m.role)
+        .ok_or_else(|| "Principal is not a staff member of this store".to_string())
+}
+
+fn check_manages(store: &Store, caller: Principal, role: StoreRole) -> Result<(), String> {
+    match role_of(store, caller) {
+        Some(own) if own.can_manage(role) => Ok(()),
+        _ => Err(format!("Unauthorized to manage {:?} members", role)),
+    }
+}
+
+// Every member sees the store's shipments; reports need a role that allows them
+fn authorize_store_view(store_id: &str, caller: Principal, action: Option<StoreAction>) -> Result<(), String> {
+    let store = STORES
+        .with(|stores| stores.borrow().get(store_id).cloned())
+        .ok_or_else(|| "Store not found".to_string())?;
+    let allowed = match action {
+        Some(action) => can(&store, caller, action),
+        None => is_member(&store, caller),
+    };
+    if !allowed && !permissions::has(&caller, Permission::ViewAllShipments) {
+        return Err("Unauthorized to view store".to_string());
+    }
+    Ok(())
+}
+
+fn store_shipments(store_id: &str) -> Vec<Shipment> {
+    SHIPMENTS.with(|shipments| {
+        shipments
+            .borrow()
+            .values()
+            .filter(|s| s.store_id.as_deref() == Some(store_id))
+            .cloned()
+            .collect()
+    })
+}
+
+fn with_store(
+    store_id: &str,
+    caller: Principal,
+    action: StoreAction,
+    f: impl FnOnce(&mut Store) -> Result<(), String>,
+) -> Result<Store, String> {
+    STORES.with(|stores| {
+        match stores.borrow_mut().get_mut(store_id) {
+            Some(store) if can(store, caller, action) => {
+                f(store)?;
+                Ok(store.clone())
+            },
+            Some(_) => Err("Unauthorized to manage store".to_string()),
+            None => Err("Store not found".to_string()),
+        }
+    })
+}
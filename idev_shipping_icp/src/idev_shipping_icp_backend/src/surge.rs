@@ -0,0 +1,180 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::zones::{self, ShipmentZones};
+use crate::{
+    accounts, shifts, suspensions, CostBreakdown, CostLineItem, ShipmentStatus, VerificationStatus, DRIVERS, SHIPMENTS,
+};
+
+const REFRESH_NANOS: u64 = 5 * 60 * 1_000_000_000;
+// Multipliers move in steps of 0.1x so quotes don't jitter between refreshes
+const STEP_BPS: u32 = 1_000;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SurgeConfig {
+    pub enabled: bool,
+    // Open shipments per on-shift driver that supply keeps up with; surge grows
+    // in proportion beyond it
+    pub balanced_ratio: f64,
+    // Zones with fewer open shipments never surge
+    pub min_open_shipments: u32,
+    pub max_multiplier_bps: u32,
+}
+
+impl Default for SurgeConfig {
+    fn default() -> Self {
+        SurgeConfig {
+            enabled: true,
+            balanced_ratio: 2.0,
+            min_open_shipments: 5,
+            max_multiplier_bps: 20_000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SurgeLevel {
+    pub zone_id: String,
+    pub open_shipments: u32,
+    pub drivers: u32,
+    pub multiplier_bps: u32,
+    pub updated_at: u64,
+}
+
+thread_local! {
+    static CONFIG: RefCell<SurgeConfig> = RefCell::new(SurgeConfig::default());
+    static LEVELS: RefCell<BTreeMap<String, SurgeLevel>> = RefCell::new(BTreeMap::new());
+    static LAST_REFRESH: RefCell<u64> = RefCell::new(0);
+}
+
+// Admin configuration; takes effect at the next refresh
+#[update]
+fn set_surge_config(config: SurgeConfig) -> Result<SurgeConfig, String> {
+    metrics::observe("set_surge_config", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if !config.balanced_ratio.is_finite() || config.balanced_ratio <= 0.0 {
+            return Err("Balanced ratio must be positive".to_string());
+        }
+        if !(10_000..=50_000).contains(&config.max_multiplier_bps) {
+            return Err("Maximum multiplier must be between 1x and 5x".to_string());
+        }
+        CONFIG.with(|c| *c.borrow_mut() = config.clone());
+        LAST_REFRESH.with(|r| *r.borrow_mut() = 0);
+        Ok(config)
+    })
+}
+
+#[query]
+fn get_surge_config() -> SurgeConfig {
+    CONFIG.with(|c| c.borrow().clone())
+}
+
+// Current levels of every zone that is surging
+#[query]
+fn get_surge_levels() -> Vec<SurgeLevel> {
+    LEVELS.with(|l| l.borrow().values().filter(|l| l.multiplier_bps > 10_000).cloned().collect())
+}
+
+// Surge of the pickup zone, on top of the other charges
+pub(crate) fn apply_pricing(zones: &ShipmentZones, breakdown: &mut CostBreakdown) {
+    let Some(multiplier) = zones.pickup_zone_id.as_deref().and_then(multiplier_bps) else {
+        return;
+    };
+    let surcharge = breakdown.subtotal.mul_ratio((multiplier - 10_000) as u128, 10_000);
+    if surcharge.is_zero() {
+        return;
+    }
+    breakdown.charges.push(CostLineItem {
+        label: format!("Surge pricing {}.{}x", multiplier / 10_000, multiplier % 10_000 / 1_000),
+        amount: surcharge,
+    });
+    breakdown.subtotal = breakdown.subtotal.add(surcharge);
+    breakdown.total = breakdown.total.add(surcharge);
+}
+
+fn multiplier_bps(zone_id: &str) -> Option<u32> {
+    LEVELS
+        .with(|l| l.borrow().get(zone_id).map(|l| l.multiplier_bps))
+        .filter(|m| *m > 10_000)
+}
+
+// Timer job: recount demand (shipments waiting for a driver, by pickup zone) and
+// supply (on-shift drivers, by current position) in every polygon zone
+pub(crate) fn refresh_levels() {
+    let now = time();
+    if LAST_REFRESH.with(|r| now.saturating_sub(*r.borrow()) < REFRESH_NANOS) {
+        return;
+    }
+    LAST_REFRESH.with(|r| *r.borrow_mut() = now);
+    let config = CONFIG.with(|c| c.borrow().clone());
+    if !config.enabled {
+        LEVELS.with(|l| l.borrow_mut().clear());
+        return;
+    }
+
+    let mut counts: BTreeMap<String, (u32, u32)> =
+        zones::polygon_zone_ids().into_iter().map(|id| (id, (0, 0))).collect();
+    SHIPMENTS.with(|shipments| {
+        let shipments = shipments.borrow();
+        let waiting = shipments.values().filter(|s| {
+            matches!(s.status, ShipmentStatus::Created) && s.driver_id.is_none() && s.legs.is_none()
+        });
+        for shipment in waiting {
+            if let Some(count) = shipment.pickup_zone_id.as_ref().and_then(|z| counts.get_mut(z)) {
+                count.0 += 1;
+            }
+        }
+    });
+    DRIVERS.with(|drivers| {
+        let drivers = drivers.borrow();
+        let on_shift = drivers.values().filter(|d| {
+            matches!(d.verification_status, VerificationStatus::Verified)
+                && accounts::is_active(&d.id)
+                && !suspensions::is_suspended(&d.id)
+                && shifts::is_on_shift(&d.id, now)
+        });
+        for driver in on_shift {
+            let Some(location) = &driver.current_location else {
+                continue;
+            };
+            for zone_id in zones::polygon_zones_at(location) {
+                if let Some(count) = counts.get_mut(&zone_id) {
+                    count.1 += 1;
+                }
+            }
+        }
+    });
+
+    let levels = counts
+        .into_iter()
+        .map(|(zone_id, (open_shipments, drivers))| {
+            let level = SurgeLevel {
+                zone_id: zone_id.clone(),
+                open_shipments,
+                drivers,
+                multiplier_bps: multiplier_for(&config, open_shipments, drivers),
+                updated_at: now,
+            };
+            (zone_id, level)
+        })
+        .collect();
+    LEVELS.with(|l| *l.borrow_mut() = levels);
+}
+
+fn multiplier_for(config: &SurgeConfig, open_shipments: u32, drivers: u32) -> u32 {
+    if open_shipments < config.min_open_shipments {
+        return 10_000;
+    }
+    if drivers == 0 {
+        return config.max_multiplier_bps;
+    }
+    let ratio = open_shipments as f64 / drivers as f64;
+    let raw = (10_000.0 * ratio / config.balanced_ratio).min(config.max_multiplier_bps as f64) as u32;
+    (raw / STEP_BPS * STEP_BPS).clamp(10_000, config.max_multiplier_bps)
+}
@@ -0,0 +1,171 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+
+use crate::audit::{self, AuditAction};
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::validation::{Validator, MAX_TEXT_LEN};
+
+const MAX_BLOCKED_ATTEMPTS: usize = 10_000;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Suspension {
+    pub principal: Principal,
+    pub reason: String,
+    pub suspended_by: Principal,
+    pub suspended_at: u64,
+    // Indefinite when not set
+    pub expires_at: Option<u64>,
+    pub lifted_by: Option<Principal>,
+    pub lifted_at: Option<u64>,
+}
+
+impl Suspension {
+    fn is_active(&self, now: u64) -> bool {
+        self.lifted_at.is_none() && self.expires_at.is_none_or(|at| at > now)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum BlockedAction {
+    CreateShipment,
+    Payment,
+    AcceptDelivery,
+}
+
+// Something a suspended principal tried to do, kept for review
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct BlockedAttempt {
+    pub principal: Principal,
+    pub action: BlockedAction,
+    pub at: u64,
+}
+
+thread_local! {
+    // Latest suspension per principal, including lifted and expired ones
+    static SUSPENSIONS: RefCell<HashMap<Principal, Suspension>> = RefCell::new(HashMap::new());
+    static BLOCKED_ATTEMPTS: RefCell<VecDeque<BlockedAttempt>> = RefCell::new(VecDeque::new());
+}
+
+// Replaces any earlier suspension of the principal
+#[update]
+fn suspend_account(principal: Principal, reason: String, expires_at: Option<u64>) -> Result<Suspension, String> {
+    metrics::observe("suspend_account", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::SuspendAccounts)?;
+        if principal == caller {
+            return Err("Cannot suspend yourself".to_string());
+        }
+        if reason.trim().is_empty() {
+            return Err("A reason is required".to_string());
+        }
+        let mut v = Validator::new();
+        v.max_len("reason", &reason, MAX_TEXT_LEN);
+        v.finish()?;
+        let now = time();
+        if expires_at.is_some_and(|at| at <= now) {
+            return Err("Expiry must be in the future".to_string());
+        }
+
+        let suspension = Suspension {
+            principal,
+            reason,
+            suspended_by: caller,
+            suspended_at: now,
+            expires_at,
+            lifted_by: None,
+            lifted_at: None,
+        };
+        SUSPENSIONS.with(|s| s.borrow_mut().insert(principal, suspension.clone()));
+        let until = match expires_at {
+            Some(at) => format!("until {}", at),
+            None => "indefinitely".to_string(),
+        };
+        audit::record(
+            caller,
+            principal,
+            AuditAction::AccountSuspended,
+            format!("{} ({})", suspension.reason, until),
+        );
+        Ok(suspension)
+    })
+}
+
+#[update]
+fn lift_suspension(principal: Principal) -> Result<Suspension, String> {
+    metrics::observe("lift_suspension", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::SuspendAccounts)?;
+        let now = time();
+        let suspension = SUSPENSIONS.with(|s| {
+            let mut s = s.borrow_mut();
+            let suspension = s
+                .get_mut(&principal)
+                .filter(|s| s.is_active(now))
+                .ok_or_else(|| "Account is not suspended".to_string())?;
+            suspension.lifted_by = Some(caller);
+            suspension.lifted_at = Some(now);
+            Ok::<_, String>(suspension.clone())
+        })?;
+        audit::record(caller, principal, AuditAction::SuspensionLifted, String::new());
+        Ok(suspension)
+    })
+}
+
+#[query]
+fn get_suspensions(include_inactive: bool) -> Result<Vec<Suspension>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::SuspendAccounts)?;
+    let now = time();
+    let mut suspensions: Vec<Suspension> = SUSPENSIONS.with(|s| {
+        s.borrow()
+            .values()
+            .filter(|s| include_inactive || s.is_active(now))
+            .cloned()
+            .collect()
+    });
+    suspensions.sort_by_key(|s| Reverse(s.suspended_at));
+    Ok(suspensions)
+}
+
+// Newest first
+#[query]
+fn get_blocked_attempts(principal: Option<Principal>, limit: Option<u32>) -> Result<Vec<BlockedAttempt>, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::SuspendAccounts)?;
+    let limit = limit.unwrap_or(100) as usize;
+    Ok(BLOCKED_ATTEMPTS.with(|log| {
+        log.borrow()
+            .iter()
+            .rev()
+            .filter(|a| principal.is_none_or(|p| a.principal == p))
+            .take(limit)
+            .cloned()
+            .collect()
+    }))
+}
+
+pub(crate) fn is_suspended(principal: &Principal) -> bool {
+    let now = time();
+    SUSPENSIONS.with(|s| s.borrow().get(principal).is_some_and(|s| s.is_active(now)))
+}
+
+// Refuse the action for a suspended principal and log the attempt
+pub(crate) fn check(principal: Principal, action: BlockedAction) -> Result<(), String> {
+    let now = time();
+    let Some(suspension) = SUSPENSIONS.with(|s| s.borrow().get(&principal).filter(|s| s.is_active(now)).cloned()) else {
+        return Ok(());
+    };
+    BLOCKED_ATTEMPTS.with(|log| {
+        let mut log = log.borrow_mut();
+        log.push_back(BlockedAttempt { principal, action, at: now });
+        if log.len() > MAX_BLOCKED_ATTEMPTS {
+            log.pop_front();
+        }
+    });
+    Err(format!("Account is suspended: {}", suspension.reason))
+}
@@ -0,0 +1,280 @@
+use candid::{CandidType, Deserialize, Principal};
+use hmac::{Hmac, Mac};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use sha2::Sha256;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+use crate::errors::ShippingError;
+use crate::event_store;
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::recipients::decode_hex;
+use crate::tracking;
+use crate::validation::{self, Validator};
+use crate::{apply_status_update, check_status_update, Shipment, ShipmentStatus, TrackingEvent, SHIPMENTS, USERS};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAX_BATCH_SIZE: usize = 100;
+const MIN_KEY_LENGTH: usize = 16;
+const MAX_KEY_LENGTH: usize = 128;
+const MAX_REMEMBERED_EVENTS: usize = 1000;
+// Client clocks may run slightly ahead of the replica
+const MAX_CLOCK_SKEW_NANOS: u64 = 5 * 60 * 1_000_000_000;
+
+// An event recorded on the driver's device while offline; `client_event_id` must be
+// unique per device so retried uploads are recognised.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct OfflineEvent {
+    pub client_event_id: String,
+    pub shipment_id: String,
+    pub recorded_at: u64,
+    pub location: Option<String>,
+    pub kind: OfflineEventKind,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum OfflineEventKind {
+    StatusUpdate { status: ShipmentStatus, description: String },
+    Scan { description: String },
+}
+
+// The device signs each event as it is recorded with the key it registered while
+// online: hex(HMAC-SHA256(key, "{client_event_id}\n{shipment_id}\n{recorded_at}\n
+// {location}\n{kind}\n{description}")), where a missing location is empty and kind is
+// "Scan" or "StatusUpdate:{status}" with the status variant name.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SignedEvent {
+    pub event: OfflineEvent,
+    pub signature: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct OfflineEventResult {
+    pub client_event_id: String,
+    pub outcome: SyncOutcome,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum SyncOutcome {
+    Applied,
+    // Already received in an earlier upload
+    Duplicate,
+    // A newer server-side change won; the event is kept in the history for audit only
+    Superseded,
+    Rejected(String),
+}
+
+thread_local! {
+    static SYNCED_EVENTS: RefCell<HashMap<Principal, VecDeque<String>>> = RefCell::new(HashMap::new());
+    // Driver -> signing key of their device; never returned
+    static DEVICE_KEYS: RefCell<HashMap<Principal, String>> = RefCell::new(HashMap::new());
+}
+
+// Register the key the caller's device signs offline events with, replacing any
+// earlier one. Events signed with a replaced key are rejected.
+#[update]
+fn register_device_key(key: String) -> Result<(), String> {
+    metrics::observe("register_device_key", || {
+        let caller = ic_cdk::caller();
+        if !USERS.with(|users| users.borrow().contains_key(&caller)) {
+            return Err("User not registered".to_string());
+        }
+        if key.len() < MIN_KEY_LENGTH || key.len() > MAX_KEY_LENGTH {
+            return Err(format!("Device key must be {} to {} characters", MIN_KEY_LENGTH, MAX_KEY_LENGTH));
+        }
+        DEVICE_KEYS.with(|k| k.borrow_mut().insert(caller, key));
+        Ok(())
+    })
+}
+
+// Events are reconciled in client timestamp order (ties broken by event id), so the
+// same batch always produces the same result regardless of upload order.
+#[update]
+fn push_offline_events(events: Vec<SignedEvent>) -> Result<Vec<OfflineEventResult>, String> {
+    metrics::observe("push_offline_events", || {
+        let caller = ic_cdk::caller();
+        if events.len() > MAX_BATCH_SIZE {
+            return Err(format!("At most {} events per batch", MAX_BATCH_SIZE));
+        }
+        let key = DEVICE_KEYS
+            .with(|k| k.borrow().get(&caller).cloned())
+            .ok_or_else(|| "Register a device key before syncing offline events".to_string())?;
+
+        let mut events = events;
+        events.sort_by(|a, b| {
+            a.event
+                .recorded_at
+                .cmp(&b.event.recorded_at)
+                .then_with(|| a.event.client_event_id.cmp(&b.event.client_event_id))
+        });
+
+        let results = events
+            .into_iter()
+            .map(|SignedEvent { event, signature }| {
+                let client_event_id = event.client_event_id.clone();
+                let outcome = if !signed_by(&key, &event, &signature) {
+                    SyncOutcome::Rejected("Invalid event signature".to_string())
+                } else if already_synced(&caller, &client_event_id) {
+                    SyncOutcome::Duplicate
+                } else {
+                    let outcome = reconcile(caller, event);
+                    if !matches!(outcome, SyncOutcome::Rejected(_)) {
+                        remember(caller, client_event_id.clone());
+                    }
+                    outcome
+                };
+                OfflineEventResult { client_event_id, outcome }
+            })
+            .collect();
+
+        Ok(results)
+    })
+}
+
+fn reconcile(caller: Principal, event: OfflineEvent) -> SyncOutcome {
+    if let Err(e) = validate(&event) {
+        return SyncOutcome::Rejected(e.into());
+    }
+    if event.recorded_at > time() + MAX_CLOCK_SKEW_NANOS {
+        return SyncOutcome::Rejected("Event recorded in the future".to_string());
+    }
+    let can_update_any = permissions::has(&caller, Permission::UpdateAnyShipment);
+
+    SHIPMENTS.with(|shipments| {
+        let mut shipments_map = shipments.borrow_mut();
+        let shipment = match shipments_map.get_mut(&event.shipment_id) {
+            Some(s) => s,
+            None => return SyncOutcome::Rejected("Shipment not found".to_string()),
+        };
+        if shipment.driver_id != Some(caller) {
+            return SyncOutcome::Rejected("Not assigned to this shipment".to_string());
+        }
+
+        let last_change = shipment
+            .tracking_history
+            .last()
+            .map_or(shipment.created_at, |e| e.timestamp);
+
+        match event.kind {
+            OfflineEventKind::StatusUpdate { status, description } => {
+                if event.recorded_at < last_change {
+                    // Held to the same guards, from the status the shipment had back then
+                    let mut then = shipment.clone();
+                    then.status = status_at(shipment, event.recorded_at);
+                    if let Err(e) = check_status_update(&then, caller, can_update_any, &status) {
+                        return SyncOutcome::Rejected(e);
+                    }
+                    tracking::record_in_order(
+                        shipment,
+                        TrackingEvent {
+                            timestamp: event.recorded_at,
+                            status,
+                            location: event.location,
+                            description: format!("[offline, superseded] {}", description),
+                            updated_by: caller,
+                            kind: None,
+                        },
+                    );
+                    return SyncOutcome::Superseded;
+                }
+                // The same guards as online updates
+                if let Err(e) = check_status_update(shipment, caller, can_update_any, &status) {
+                    return SyncOutcome::Rejected(e);
+                }
+                apply_status_update(
+                    shipment,
+                    status,
+                    event.location,
+                    format!("[offline] {}", description),
+                    caller,
+                    event.recorded_at,
+                );
+                SyncOutcome::Applied
+            },
+            OfflineEventKind::Scan { description } => {
+                let status = shipment.status.clone();
+                tracking::record_in_order(
+                    shipment,
+                    TrackingEvent {
+                        timestamp: event.recorded_at,
+                        status,
+                        location: event.location,
+                        description: format!("[offline scan] {}", description),
+                        updated_by: caller,
+                        kind: None,
+                    },
+                );
+                shipment.touch(time());
+                event_store::tracked(shipment, caller);
+                SyncOutcome::Applied
+            },
+        }
+    })
+}
+
+fn status_at(shipment: &Shipment, timestamp: u64) -> ShipmentStatus {
+    tracking::with_events(shipment, |events| {
+        events
+            .iter()
+            .take_while(|e| e.timestamp <= timestamp)
+            .last()
+            .map(|e| e.status.clone())
+    })
+    .unwrap_or(ShipmentStatus::Created)
+}
+
+fn signed_by(key: &str, event: &OfflineEvent, signature: &str) -> bool {
+    let (kind, description) = match &event.kind {
+        OfflineEventKind::StatusUpdate { status, description } => (format!("StatusUpdate:{:?}", status), description),
+        OfflineEventKind::Scan { description } => ("Scan".to_string(), description),
+    };
+    let message = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        event.client_event_id,
+        event.shipment_id,
+        event.recorded_at,
+        event.location.as_deref().unwrap_or_default(),
+        kind,
+        description
+    );
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(message.as_bytes());
+    decode_hex(signature).is_some_and(|signature| mac.verify_slice(&signature).is_ok())
+}
+
+fn validate(event: &OfflineEvent) -> Result<(), ShippingError> {
+    let description = match &event.kind {
+        OfflineEventKind::StatusUpdate { description, .. } | OfflineEventKind::Scan { description } => description,
+    };
+    let mut v = Validator::new();
+    v.required("client_event_id", &event.client_event_id, validation::MAX_NAME_LEN);
+    v.max_len("description", description, validation::MAX_TEXT_LEN);
+    if let Some(location) = &event.location {
+        v.max_len("location", location, validation::MAX_TEXT_LEN);
+    }
+    v.finish()
+}
+
+fn already_synced(driver: &Principal, client_event_id: &str) -> bool {
+    SYNCED_EVENTS.with(|synced| {
+        synced
+            .borrow()
+            .get(driver)
+            .map(|ids| ids.iter().any(|id| id == client_event_id))
+            .unwrap_or(false)
+    })
+}
+
+fn remember(driver: Principal, client_event_id: String) {
+    SYNCED_EVENTS.with(|synced| {
+        let mut synced_map = synced.borrow_mut();
+        let ids = synced_map.entry(driver).or_default();
+        ids.push_back(client_event_id);
+        while ids.len() > MAX_REMEMBERED_EVENTS {
+            ids.pop_front();
+        }
+    });
+}
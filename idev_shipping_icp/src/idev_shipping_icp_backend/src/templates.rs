@@ -0,0 +1,247 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::metrics;
+use crate::service_level::ServiceLevel;
+use crate::stores;
+use crate::validation::{self, Validator};
+use crate::{
+    idempotency, place_shipment, Address, NewShipment, PackageDetails, Shipment, ShipmentOptions, USERS,
+};
+
+const MAX_TEMPLATES_PER_OWNER: usize = 100;
+
+// Reusable defaults for recurring shipments. Templates tied to a store are
+// shared with the store's staff.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TemplateFields {
+    pub name: String,
+    pub store_id: Option<String>,
+    pub package_details: PackageDetails,
+    pub service_level: Option<ServiceLevel>,
+    pub recipient_name: Option<String>,
+    pub recipient_phone: Option<String>,
+    pub pickup_address: Option<Address>,
+    pub delivery_address: Option<Address>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShipmentTemplate {
+    pub id: String,
+    pub owner: Principal,
+    pub fields: TemplateFields,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+// Per-order values that replace the template's
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct TemplateOverrides {
+    pub recipient_name: Option<String>,
+    pub recipient_phone: Option<String>,
+    pub pickup_address: Option<Address>,
+    pub delivery_address: Option<Address>,
+    pub package_details: Option<PackageDetails>,
+    pub options: Option<ShipmentOptions>,
+}
+
+thread_local! {
+    static TEMPLATES: RefCell<HashMap<String, ShipmentTemplate>> = RefCell::new(HashMap::new());
+    static TEMPLATE_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+#[update]
+fn create_template(fields: TemplateFields) -> Result<ShipmentTemplate, String> {
+    metrics::observe("create_template", || {
+        let caller = ic_cdk::caller();
+        if !USERS.with(|users| users.borrow().contains_key(&caller)) {
+            return Err("User not registered".to_string());
+        }
+        validate_fields(&fields, caller)?;
+        let owned = TEMPLATES.with(|templates| templates.borrow().values().filter(|t| t.owner == caller).count());
+        if owned >= MAX_TEMPLATES_PER_OWNER {
+            return Err(format!("At most {} templates can be saved", MAX_TEMPLATES_PER_OWNER));
+        }
+
+        let template_id = TEMPLATE_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            format!("TP{:06}", *c)
+        });
+        let template = ShipmentTemplate {
+            id: template_id.clone(),
+            owner: caller,
+            fields,
+            created_at: time(),
+            updated_at: time(),
+        };
+        TEMPLATES.with(|templates| templates.borrow_mut().insert(template_id, template.clone()));
+        Ok(template)
+    })
+}
+
+// Only the owner edits or deletes a template
+#[update]
+fn update_template(template_id: String, fields: TemplateFields) -> Result<ShipmentTemplate, String> {
+    metrics::observe("update_template", || {
+        let caller = ic_cdk::caller();
+        validate_fields(&fields, caller)?;
+        TEMPLATES.with(|templates| {
+            let mut templates_map = templates.borrow_mut();
+            let template = templates_map
+                .get_mut(&template_id)
+                .filter(|t| t.owner == caller)
+                .ok_or_else(|| "Template not found".to_string())?;
+            template.fields = fields;
+            template.updated_at = time();
+            Ok(template.clone())
+        })
+    })
+}
+
+#[update]
+fn delete_template(template_id: String) -> Result<(), String> {
+    metrics::observe("delete_template", || {
+        let caller = ic_cdk::caller();
+        TEMPLATES.with(|templates| {
+            let mut templates_map = templates.borrow_mut();
+            match templates_map.get(&template_id) {
+                Some(t) if t.owner == caller => {
+                    templates_map.remove(&template_id);
+                    Ok(())
+                },
+                _ => Err("Template not found".to_string()),
+            }
+        })
+    })
+}
+
+// The caller's own templates plus those of stores they belong to
+#[query]
+fn list_templates(store_id: Option<String>) -> Vec<ShipmentTemplate> {
+    let caller = ic_cdk::caller();
+    let mut templates: Vec<ShipmentTemplate> = TEMPLATES.with(|templates| {
+        templates
+            .borrow()
+            .values()
+            .filter(|t| can_use(t, caller))
+            .filter(|t| store_id.is_none() || t.fields.store_id == store_id)
+            .cloned()
+            .collect()
+    });
+    templates.sort_by(|a, b| a.id.cmp(&b.id));
+    templates
+}
+
+#[update]
+async fn create_shipment_from_template(
+    template_id: String,
+    overrides: Option<TemplateOverrides>,
+    idempotency_key: Option<String>,
+) -> Result<Shipment, String> {
+    metrics::observe_async("create_shipment_from_template", async move {
+        let caller = ic_cdk::caller();
+        if let Some(shipment) = idempotency::begin(caller, idempotency_key.as_deref(), "create_shipment_from_template")? {
+            return Ok(shipment);
+        }
+        let result = match build(caller, &template_id, overrides.unwrap_or_default()) {
+            Ok((new, options)) => place_shipment(caller, new, options).await,
+            Err(e) => Err(e),
+        };
+        idempotency::finish(caller, idempotency_key.as_deref(), &result);
+        result
+    })
+    .await
+}
+
+pub(crate) fn build(
+    caller: Principal,
+    template_id: &str,
+    overrides: TemplateOverrides,
+) -> Result<(NewShipment, ShipmentOptions), String> {
+    let template = TEMPLATES
+        .with(|templates| templates.borrow().get(template_id).cloned())
+        .filter(|t| can_use(t, caller))
+        .ok_or_else(|| "Template not found".to_string())?;
+    let fields = template.fields;
+    let missing = |field: &str| format!("Template has no {}; supply it in the overrides", field);
+
+    let mut options = overrides.options.unwrap_or_default();
+    if options.service_level.is_none() {
+        options.service_level = fields.service_level;
+    }
+    if options.store_id.is_none() {
+        options.store_id = fields.store_id;
+    }
+    // A saved pickup or delivery address in the options wins over the template's
+    let pickup_address = match overrides.pickup_address.or(fields.pickup_address) {
+        Some(address) => address,
+        None if options.pickup_address_id.is_some() || options.use_default_pickup == Some(true) => placeholder(),
+        None => return Err(missing("pickup address")),
+    };
+    let delivery_address = match overrides.delivery_address.or(fields.delivery_address) {
+        Some(address) => address,
+        None if options.delivery_address_id.is_some() => placeholder(),
+        None => return Err(missing("delivery address")),
+    };
+
+    let new = NewShipment {
+        recipient_name: overrides.recipient_name.or(fields.recipient_name).ok_or_else(|| missing("recipient name"))?,
+        recipient_phone: overrides
+            .recipient_phone
+            .or(fields.recipient_phone)
+            .ok_or_else(|| missing("recipient phone"))?,
+        pickup_address,
+        delivery_address,
+        package_details: overrides.package_details.unwrap_or(fields.package_details),
+    };
+    Ok((new, options))
+}
+
+fn validate_fields(fields: &TemplateFields, caller: Principal) -> Result<(), String> {
+    let mut v = Validator::new();
+    v.required("name", &fields.name, validation::MAX_NAME_LEN);
+    v.package("package_details", &fields.package_details);
+    if let Some(name) = &fields.recipient_name {
+        v.required("recipient_name", name, validation::MAX_NAME_LEN);
+    }
+    if let Some(phone) = &fields.recipient_phone {
+        v.phone("recipient_phone", phone);
+    }
+    if let Some(address) = &fields.pickup_address {
+        v.address("pickup_address", address);
+    }
+    if let Some(address) = &fields.delivery_address {
+        v.address("delivery_address", address);
+    }
+    v.finish()?;
+    if let Some(store_id) = &fields.store_id {
+        stores::store_for_shipment(store_id, caller)?;
+    }
+    Ok(())
+}
+
+fn can_use(template: &ShipmentTemplate, caller: Principal) -> bool {
+    template.owner == caller
+        || template
+            .fields
+            .store_id
+            .as_deref()
+            .is_some_and(|store_id| stores::store_for_shipment(store_id, caller).is_ok())
+}
+
+// Stands in for an inline address when a saved one is referenced instead
+fn placeholder() -> Address {
+    Address {
+        street: String::new(),
+        city: String::new(),
+        state: String::new(),
+        postal_code: String::new(),
+        country: String::new(),
+        coordinates: None,
+        extras: None,
+    }
+}
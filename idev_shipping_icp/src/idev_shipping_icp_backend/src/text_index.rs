@@ -0,0 +1,164 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::permissions::{self, Permission};
+use crate::{Shipment, ShipmentSummary, User, SHIPMENTS, USERS};
+
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 100;
+const MIN_WORD_LEN: usize = 2;
+const MAX_QUERY_WORDS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, CandidType, Deserialize)]
+pub enum EntityKind {
+    Shipment,
+    User,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum SearchHit {
+    Shipment(Box<ShipmentSummary>),
+    User(User),
+}
+
+type EntityKey = (EntityKind, String);
+
+thread_local! {
+    // Word -> records containing it; words are matched by prefix
+    static INDEX: RefCell<BTreeMap<String, BTreeSet<EntityKey>>> = RefCell::new(BTreeMap::new());
+    // The words each record was indexed under, so reindexing drops stale ones
+    static WORDS: RefCell<HashMap<EntityKey, Vec<String>>> = RefCell::new(HashMap::new());
+}
+
+// Records containing every word of `text`, each word matching as a prefix. Shipments
+// need ViewAllShipments and users ReviewCompliance; without a kind, every kind the
+// caller may see is searched.
+#[query]
+fn search(text: String, entity_kind: Option<EntityKind>, limit: Option<u32>) -> Result<Vec<SearchHit>, String> {
+    let caller = ic_cdk::caller();
+    let allowed = |kind: EntityKind| match kind {
+        EntityKind::Shipment => permissions::has(&caller, Permission::ViewAllShipments),
+        EntityKind::User => permissions::has(&caller, Permission::ReviewCompliance),
+    };
+    let kinds: Vec<EntityKind> = match entity_kind {
+        Some(kind) if allowed(kind) => vec![kind],
+        Some(kind) => return Err(format!("Unauthorized to search {:?} records", kind)),
+        None => [EntityKind::Shipment, EntityKind::User].into_iter().filter(|k| allowed(*k)).collect(),
+    };
+    if kinds.is_empty() {
+        return Err("Unauthorized to search".to_string());
+    }
+    let words = tokenize(&text);
+    if words.is_empty() {
+        return Err(format!("Search for at least one word of {} or more characters", MIN_WORD_LEN));
+    }
+    if words.len() > MAX_QUERY_WORDS {
+        return Err(format!("Search for at most {} words", MAX_QUERY_WORDS));
+    }
+
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT) as usize;
+    let keys: Vec<EntityKey> = matching(&words)
+        .into_iter()
+        .filter(|(kind, _)| kinds.contains(kind))
+        .take(limit)
+        .collect();
+    Ok(keys
+        .into_iter()
+        .filter_map(|(kind, id)| match kind {
+            EntityKind::Shipment => SHIPMENTS.with(|shipments| {
+                shipments.borrow().get(&id).map(|s| SearchHit::Shipment(Box::new(s.summary())))
+            }),
+            EntityKind::User => {
+                let principal = Principal::from_text(&id).ok()?;
+                USERS.with(|users| users.borrow().get(&principal).cloned().map(SearchHit::User))
+            },
+        })
+        .collect())
+}
+
+// Called whenever an indexed field of a shipment may have changed
+pub(crate) fn index_shipment(shipment: &Shipment) {
+    let mut text = vec![
+        shipment.id.as_str(),
+        shipment.recipient_name.as_str(),
+        shipment.package_details.description.as_str(),
+        shipment.pickup_address.city.as_str(),
+        shipment.delivery_address.city.as_str(),
+    ];
+    text.extend(shipment.short_code.as_deref());
+    store((EntityKind::Shipment, shipment.id.clone()), text.iter().flat_map(|t| tokenize(t)).collect());
+}
+
+pub(crate) fn index_user(user: &User) {
+    let id = user.id.to_text();
+    let words = [id.as_str(), user.name.as_str(), user.email.as_str()]
+        .iter()
+        .flat_map(|t| tokenize(t))
+        .collect();
+    store((EntityKind::User, id), words);
+}
+
+// For records leaving the canister
+pub(crate) fn remove(kind: EntityKind, id: &str) {
+    store((kind, id.to_string()), Vec::new());
+}
+
+fn store(key: EntityKey, mut words: Vec<String>) {
+    words.sort();
+    words.dedup();
+    let previous = WORDS.with(|w| {
+        let mut all = w.borrow_mut();
+        if words.is_empty() {
+            all.remove(&key)
+        } else {
+            all.insert(key.clone(), words.clone())
+        }
+    });
+    INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for word in previous.unwrap_or_default() {
+            if let Some(keys) = index.get_mut(&word) {
+                keys.remove(&key);
+                if keys.is_empty() {
+                    index.remove(&word);
+                }
+            }
+        }
+        for word in words {
+            index.entry(word).or_default().insert(key.clone());
+        }
+    });
+}
+
+// Records matching every word, in (kind, id) order
+fn matching(words: &[String]) -> BTreeSet<EntityKey> {
+    INDEX.with(|index| {
+        let index = index.borrow();
+        let mut result: Option<BTreeSet<EntityKey>> = None;
+        for word in words {
+            let found: BTreeSet<EntityKey> = index
+                .range(word.clone()..)
+                .take_while(|(w, _)| w.starts_with(word.as_str()))
+                .flat_map(|(_, keys)| keys.iter().cloned())
+                .collect();
+            result = Some(match result {
+                Some(r) => r.intersection(&found).cloned().collect(),
+                None => found,
+            });
+            if result.as_ref().is_some_and(|r| r.is_empty()) {
+                break;
+            }
+        }
+        result.unwrap_or_default()
+    })
+}
+
+// Lowercased alphanumeric words, short ones dropped
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() >= MIN_WORD_LEN)
+        .map(|w| w.to_lowercase())
+        .collect()
+}
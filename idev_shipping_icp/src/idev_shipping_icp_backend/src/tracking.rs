@@ -0,0 +1,184 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::{Shipment, TrackingEvent, TrackingEventKind, SHIPMENTS};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PAGE_SIZE: usize = 50;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CompactionPolicy {
+    pub enabled: bool,
+    // Location pings older than this are dropped when the status did not change
+    // around them
+    pub min_age_days: u64,
+}
+
+// Oldest first; `page` counts from zero
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TrackingEventPage {
+    pub events: Vec<TrackingEvent>,
+    pub page: u32,
+    pub total_events: u32,
+    pub has_more: bool,
+}
+
+thread_local! {
+    static POLICY: RefCell<CompactionPolicy> = RefCell::new(CompactionPolicy {
+        enabled: true,
+        min_age_days: 30,
+    });
+    // Full history of live shipments, keyed by shipment id. The shipment record
+    // only carries its latest event.
+    static EVENTS: RefCell<HashMap<String, Vec<TrackingEvent>>> = RefCell::new(HashMap::new());
+}
+
+// Admin configuration
+#[update]
+fn set_tracking_compaction_policy(policy: CompactionPolicy) -> Result<CompactionPolicy, String> {
+    metrics::observe("set_tracking_compaction_policy", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if policy.min_age_days == 0 {
+            return Err("Compaction age must be positive".to_string());
+        }
+        POLICY.with(|p| *p.borrow_mut() = policy.clone());
+        Ok(policy)
+    })
+}
+
+#[query]
+fn get_tracking_compaction_policy() -> CompactionPolicy {
+    POLICY.with(|p| p.borrow().clone())
+}
+
+#[query]
+fn get_tracking_events(shipment_id: String, page: Option<u32>) -> Result<TrackingEventPage, String> {
+    let page = page.unwrap_or(0);
+    SHIPMENTS.with(|shipments| {
+        let shipments = shipments.borrow();
+        let shipment = shipments
+            .get(&shipment_id)
+            .ok_or_else(|| "Shipment not found".to_string())?;
+        Ok(with_events(shipment, |events| {
+            let start = (page as usize).saturating_mul(PAGE_SIZE);
+            TrackingEventPage {
+                events: events.iter().skip(start).take(PAGE_SIZE).cloned().collect(),
+                page,
+                total_events: events.len() as u32,
+                has_more: events.len() > start.saturating_add(PAGE_SIZE),
+            }
+        }))
+    })
+}
+
+// Append an event to the shipment's history
+pub(crate) fn record(shipment: &mut Shipment, event: TrackingEvent) {
+    EVENTS.with(|e| {
+        let mut events = e.borrow_mut();
+        let history = events.entry(shipment.id.clone()).or_insert_with(|| shipment.tracking_history.clone());
+        history.push(event.clone());
+    });
+    shipment.tracking_history = vec![event];
+}
+
+// Insert an event by timestamp, for events reported after later ones
+pub(crate) fn record_in_order(shipment: &mut Shipment, event: TrackingEvent) {
+    let latest = EVENTS.with(|e| {
+        let mut events = e.borrow_mut();
+        let history = events.entry(shipment.id.clone()).or_insert_with(|| shipment.tracking_history.clone());
+        let position = history.partition_point(|e| e.timestamp <= event.timestamp);
+        history.insert(position, event);
+        history.last().cloned()
+    });
+    shipment.tracking_history = latest.into_iter().collect();
+}
+
+// Attach details to the event just recorded
+pub(crate) fn tag_latest(shipment: &mut Shipment, kind: TrackingEventKind) {
+    EVENTS.with(|e| {
+        if let Some(event) = e.borrow_mut().get_mut(&shipment.id).and_then(|h| h.last_mut()) {
+            event.kind = Some(kind.clone());
+        }
+    });
+    if let Some(event) = shipment.tracking_history.last_mut() {
+        event.kind = Some(kind);
+    }
+}
+
+// Run `f` over the full history; shipments the store has never seen carry it inline
+pub(crate) fn with_events<R>(shipment: &Shipment, f: impl FnOnce(&[TrackingEvent]) -> R) -> R {
+    EVENTS.with(|e| match e.borrow().get(&shipment.id) {
+        Some(history) => f(history),
+        None => f(&shipment.tracking_history),
+    })
+}
+
+// Move a shipment's inline history into the store, for new shipments and ones
+// handed over by another canister
+pub(crate) fn attach(shipment: &mut Shipment) {
+    let latest = shipment.tracking_history.last().cloned();
+    let history = std::mem::replace(&mut shipment.tracking_history, latest.into_iter().collect());
+    EVENTS.with(|e| e.borrow_mut().insert(shipment.id.clone(), history));
+}
+
+// The shipment with its full history inline, for records leaving the heap
+pub(crate) fn full_record(shipment: &Shipment) -> Shipment {
+    let mut full = shipment.clone();
+    full.tracking_history = with_events(shipment, |events| events.to_vec());
+    full
+}
+
+pub(crate) fn forget(shipment_id: &str) {
+    EVENTS.with(|e| e.borrow_mut().remove(shipment_id));
+}
+
+// Timer job: drop location pings past the policy age. The first and last event
+// of every run of the same status are kept, as are handling and custody records.
+pub(crate) fn compact_events() {
+    let policy = POLICY.with(|p| p.borrow().clone());
+    if !policy.enabled {
+        return;
+    }
+    let cutoff = time().saturating_sub(policy.min_age_days.saturating_mul(NANOS_PER_DAY));
+    EVENTS.with(|e| {
+        for history in e.borrow_mut().values_mut() {
+            compact(history, cutoff);
+        }
+    });
+}
+
+fn compact(history: &mut Vec<TrackingEvent>, cutoff: u64) {
+    if history.len() < 3 {
+        return;
+    }
+    let redundant: Vec<bool> = (0..history.len())
+        .map(|i| {
+            let event = &history[i];
+            i > 0
+                && i + 1 < history.len()
+                && event.timestamp < cutoff
+                && is_ping(event)
+                && history[i - 1].status == event.status
+                && history[i + 1].status == event.status
+        })
+        .collect();
+    let mut index = 0;
+    history.retain(|_| {
+        index += 1;
+        !redundant[index - 1]
+    });
+}
+
+fn is_ping(event: &TrackingEvent) -> bool {
+    match &event.kind {
+        None => event.location.is_some(),
+        Some(TrackingEventKind::Scanned { .. }) => true,
+        Some(_) => false,
+    }
+}
@@ -0,0 +1,144 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+use crate::event_store::{self, RecordedEvent};
+use crate::memory::{self, StableMemory};
+use crate::Shipment;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const FEED_RETENTION_DAYS: u64 = 30;
+const MAX_EVENTS_PER_CALL: usize = 100;
+// Feed entries read per get_my_updates call, whoever they belong to
+const MAX_SCANNED_PER_CALL: usize = 5_000;
+
+// Frontends keep the cursor of the last response and pass it back as `since_seq`
+// to receive only what changed since
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShipmentUpdates {
+    pub shipment_id: String,
+    pub events: Vec<RecordedEvent>,
+    // Sequence number of the last event returned, or `since_seq` when there was none
+    pub cursor: Option<u64>,
+    pub has_more: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UpdateFeed {
+    pub events: Vec<RecordedEvent>,
+    // Position in the feed up to which the caller is now in sync. It advances past
+    // other parties' entries too, so a call may return no events and a new cursor.
+    pub cursor: Option<u64>,
+    pub has_more: bool,
+}
+
+// Points at an event in the shipment's stream
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct FeedEntry {
+    shipment_id: String,
+    sequence: u64,
+    recorded_at: u64,
+    // Who the event is shown to: the shipment's parties when it was recorded, and
+    // whoever made the change
+    parties: Vec<Principal>,
+}
+
+thread_local! {
+    // Feed position -> candid-encoded FeedEntry, across all shipments
+    static FEED: RefCell<StableBTreeMap<u64, Vec<u8>, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::UPDATE_FEED)));
+}
+
+// Events of one shipment newer than `since_seq`, for a screen showing it
+#[query]
+fn get_shipment_updates(shipment_id: String, since_seq: Option<u64>) -> Result<ShipmentUpdates, String> {
+    event_store::can_view(&ic_cdk::caller(), &shipment_id)?;
+    let mut events = event_store::events_after(&shipment_id, since_seq, MAX_EVENTS_PER_CALL + 1);
+    let has_more = events.len() > MAX_EVENTS_PER_CALL;
+    events.truncate(MAX_EVENTS_PER_CALL);
+    Ok(ShipmentUpdates {
+        cursor: events.last().map(|e| e.sequence).or(since_seq),
+        shipment_id,
+        events,
+        has_more,
+    })
+}
+
+// Events of every shipment the caller sends, carries or receives, newer than
+// `since_seq`. A stream that was compacted in the meantime arrives as its snapshot.
+#[query]
+fn get_my_updates(since_seq: Option<u64>) -> UpdateFeed {
+    let caller = ic_cdk::caller();
+    let start = since_seq.map_or(0, |seq| seq + 1);
+    let mut events = Vec::new();
+    let mut cursor = since_seq;
+    let mut has_more = false;
+    FEED.with(|feed| {
+        for (scanned, (position, bytes)) in feed.borrow().range(start..).enumerate() {
+            if scanned >= MAX_SCANNED_PER_CALL || events.len() >= MAX_EVENTS_PER_CALL {
+                has_more = true;
+                break;
+            }
+            cursor = Some(position);
+            let entry = decode(&bytes);
+            if !entry.parties.contains(&caller) {
+                continue;
+            }
+            if let Some(event) = event_store::event_at(&entry.shipment_id, entry.sequence) {
+                events.push(event);
+            }
+        }
+    });
+    UpdateFeed {
+        events,
+        cursor,
+        has_more,
+    }
+}
+
+// Called by the event store for each event it records
+pub(crate) fn publish(recorded: &RecordedEvent, shipment: &Shipment) {
+    let parties = [Some(shipment.sender_id), shipment.driver_id, shipment.recipient_id, Some(recorded.actor)];
+    let mut parties: Vec<Principal> = parties.into_iter().flatten().filter(|p| *p != ic_cdk::id()).collect();
+    parties.sort();
+    parties.dedup();
+    let entry = FeedEntry {
+        shipment_id: recorded.shipment_id.clone(),
+        sequence: recorded.sequence,
+        recorded_at: recorded.recorded_at,
+        parties,
+    };
+    let encoded = candid::encode_one(&entry).expect("failed to encode feed entry");
+    FEED.with(|feed| {
+        let mut feed = feed.borrow_mut();
+        let position = feed.last_key_value().map_or(0, |(position, _)| position + 1);
+        feed.insert(position, encoded);
+    });
+}
+
+// Timer job: entries are in recording order, so pruning stops at the first one
+// still inside the retention window. The newest entry always stays, so positions
+// keep counting up from it rather than starting over.
+pub(crate) fn prune_feed() {
+    let cutoff = time().saturating_sub(FEED_RETENTION_DAYS * NANOS_PER_DAY);
+    let expired: Vec<u64> = FEED.with(|feed| {
+        let feed = feed.borrow();
+        let newest = feed.last_key_value().map(|(position, _)| position);
+        feed.iter()
+            .take_while(|(position, bytes)| Some(*position) != newest && decode(bytes).recorded_at < cutoff)
+            .map(|(position, _)| position)
+            .collect()
+    });
+    FEED.with(|feed| {
+        let mut feed = feed.borrow_mut();
+        for position in expired {
+            feed.remove(&position);
+        }
+    });
+}
+
+fn decode(bytes: &[u8]) -> FeedEntry {
+    candid::decode_one(bytes).expect("failed to decode feed entry")
+}
@@ -0,0 +1,102 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+
+use crate::errors::ShippingError;
+use crate::metrics;
+use crate::search::ShipmentPage;
+use crate::{request_assignment, set_shipment_status, user_shipments, Shipment, ShipmentStatus};
+
+// Bumped whenever methods are added under a new prefix; earlier methods keep working
+const API_VERSION: u32 = 2;
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+
+// Version 1 methods with a replacement, and what the replacement changes
+const DEPRECATED: [(&str, &str, &str); 3] = [
+    (
+        "update_shipment_status",
+        "v2_update_shipment_status",
+        "Typed errors; conflicting updates are rejected when expected_version is given",
+    ),
+    (
+        "assign_driver_to_shipment",
+        "v2_assign_driver_to_shipment",
+        "Typed errors; conflicting assignments are rejected when expected_version is given",
+    ),
+    ("get_user_shipments", "v2_get_user_shipments", "Paged, newest first"),
+];
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DeprecatedMethod {
+    pub method: String,
+    pub replacement: String,
+    pub changes: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ApiVersion {
+    pub version: u32,
+    // Every listed version is served side by side
+    pub supported_versions: Vec<u32>,
+    pub deprecated_methods: Vec<DeprecatedMethod>,
+}
+
+#[query]
+fn get_api_version() -> ApiVersion {
+    ApiVersion {
+        version: API_VERSION,
+        supported_versions: (1..=API_VERSION).collect(),
+        deprecated_methods: DEPRECATED
+            .iter()
+            .map(|(method, replacement, changes)| DeprecatedMethod {
+                method: method.to_string(),
+                replacement: replacement.to_string(),
+                changes: changes.to_string(),
+            })
+            .collect(),
+    }
+}
+
+#[update]
+fn v2_update_shipment_status(
+    shipment_id: String,
+    new_status: ShipmentStatus,
+    location: Option<String>,
+    description: String,
+    expected_version: Option<u64>,
+) -> Result<Shipment, ShippingError> {
+    metrics::observe("v2_update_shipment_status", || {
+        let caller = ic_cdk::caller();
+        set_shipment_status(caller, &shipment_id, new_status, location, description, expected_version)
+    })
+}
+
+#[update]
+fn v2_assign_driver_to_shipment(
+    shipment_id: String,
+    driver_id: Principal,
+    expected_version: Option<u64>,
+) -> Result<Shipment, ShippingError> {
+    metrics::observe("v2_assign_driver_to_shipment", || {
+        request_assignment(ic_cdk::caller(), &shipment_id, driver_id, expected_version)
+    })
+}
+
+// Newest first
+#[query]
+fn v2_get_user_shipments(offset: Option<u32>, limit: Option<u32>) -> ShipmentPage {
+    let offset = offset.unwrap_or(0);
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let mut shipments = user_shipments(ic_cdk::caller());
+    shipments.sort_by(|a, b| b.created_at.cmp(&a.created_at).then_with(|| b.id.cmp(&a.id)));
+    ShipmentPage {
+        total: shipments.len() as u32,
+        items: shipments
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .collect(),
+        offset,
+        limit,
+    }
+}
@@ -0,0 +1,198 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::*;
+
+use crate::address_formats;
+use crate::errors::ShippingError;
+use crate::money::BASE_CURRENCY;
+use crate::{Address, Coordinates, PackageDetails, VehicleInfo};
+
+// Size caps for free-text fields, in bytes
+pub(crate) const MAX_NAME_LEN: usize = 100;
+pub(crate) const MAX_EMAIL_LEN: usize = 254;
+pub(crate) const MAX_PHONE_LEN: usize = 32;
+pub(crate) const MAX_ADDRESS_FIELD_LEN: usize = 200;
+pub(crate) const MAX_TEXT_LEN: usize = 1_000;
+
+// E.164 allows at most 15 digits; shorter than 7 is never a reachable number
+const MIN_PHONE_DIGITS: usize = 7;
+const MAX_PHONE_DIGITS: usize = 15;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+// Check a prospective shipment without creating it, so forms can show every
+// problem at once
+#[query]
+fn validate_shipment_request(
+    recipient_name: String,
+    recipient_phone: String,
+    pickup_address: Address,
+    delivery_address: Address,
+    package_details: PackageDetails,
+) -> Vec<FieldError> {
+    let mut v = Validator::new();
+    v.shipment(
+        &recipient_name,
+        &recipient_phone,
+        &pickup_address,
+        &delivery_address,
+        &package_details,
+    );
+    v.errors
+}
+
+// Collects every field error instead of stopping at the first one
+#[derive(Default)]
+pub(crate) struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    fn error(&mut self, field: &str, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            field: field.to_string(),
+            message: message.into(),
+        });
+    }
+
+    pub(crate) fn required(&mut self, field: &str, value: &str, max: usize) {
+        if value.trim().is_empty() {
+            self.error(field, "is required");
+        } else {
+            self.max_len(field, value, max);
+        }
+    }
+
+    pub(crate) fn max_len(&mut self, field: &str, value: &str, max: usize) {
+        if value.len() > max {
+            self.error(field, format!("must be at most {} bytes", max));
+        }
+    }
+
+    pub(crate) fn email(&mut self, field: &str, value: &str) {
+        if value.len() > MAX_EMAIL_LEN {
+            return self.error(field, format!("must be at most {} bytes", MAX_EMAIL_LEN));
+        }
+        let valid = match value.split_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty()
+                    && !domain.contains('@')
+                    && domain.contains('.')
+                    && domain.split('.').all(|label| !label.is_empty())
+                    && !value.chars().any(|c| c.is_whitespace() || c.is_control())
+            },
+            None => false,
+        };
+        if !valid {
+            self.error(field, "is not a valid email address");
+        }
+    }
+
+    // Digits with an optional leading '+' and common separators
+    pub(crate) fn phone(&mut self, field: &str, value: &str) {
+        if value.len() > MAX_PHONE_LEN {
+            return self.error(field, format!("must be at most {} bytes", MAX_PHONE_LEN));
+        }
+        let body = value.trim().strip_prefix('+').unwrap_or(value.trim());
+        let digits = body.chars().filter(|c| c.is_ascii_digit()).count();
+        let allowed = body.chars().all(|c| c.is_ascii_digit() || matches!(c, ' ' | '-' | '(' | ')' | '.'));
+        if !allowed || !(MIN_PHONE_DIGITS..=MAX_PHONE_DIGITS).contains(&digits) {
+            self.error(
+                field,
+                format!("must be a phone number with {}-{} digits", MIN_PHONE_DIGITS, MAX_PHONE_DIGITS),
+            );
+        }
+    }
+
+    pub(crate) fn non_negative(&mut self, field: &str, value: f64) {
+        if !value.is_finite() || value < 0.0 {
+            self.error(field, "must be a non-negative number");
+        }
+    }
+
+    pub(crate) fn coordinates(&mut self, field: &str, coordinates: &Coordinates) {
+        if !(-90.0..=90.0).contains(&coordinates.latitude) {
+            self.error(&format!("{}.latitude", field), "must be between -90 and 90");
+        }
+        if !(-180.0..=180.0).contains(&coordinates.longitude) {
+            self.error(&format!("{}.longitude", field), "must be between -180 and 180");
+        }
+    }
+
+    pub(crate) fn address(&mut self, field: &str, address: &Address) {
+        self.required(&format!("{}.street", field), &address.street, MAX_ADDRESS_FIELD_LEN);
+        self.required(&format!("{}.city", field), &address.city, MAX_ADDRESS_FIELD_LEN);
+        self.max_len(&format!("{}.state", field), &address.state, MAX_ADDRESS_FIELD_LEN);
+        self.max_len(&format!("{}.postal_code", field), &address.postal_code, MAX_ADDRESS_FIELD_LEN);
+        self.required(&format!("{}.country", field), &address.country, MAX_ADDRESS_FIELD_LEN);
+        if let Some(coordinates) = &address.coordinates {
+            self.coordinates(&format!("{}.coordinates", field), coordinates);
+        }
+        let extras = address.extras.as_ref().map(address_formats::check_extras).unwrap_or_default();
+        for (name, message) in extras.into_iter().chain(address_formats::check(address)) {
+            self.error(&format!("{}.{}", field, name), message);
+        }
+    }
+
+    pub(crate) fn package(&mut self, field: &str, package: &PackageDetails) {
+        self.required(&format!("{}.description", field), &package.description, MAX_TEXT_LEN);
+        self.non_negative(&format!("{}.weight", field), package.weight);
+        self.non_negative(&format!("{}.dimensions.length", field), package.dimensions.length);
+        self.non_negative(&format!("{}.dimensions.width", field), package.dimensions.width);
+        self.non_negative(&format!("{}.dimensions.height", field), package.dimensions.height);
+        self.non_negative(&format!("{}.value", field), package.value);
+        if package.declared_value.is_some_and(|v| v.currency != BASE_CURRENCY) {
+            self.error(&format!("{}.declared_value", field), format!("must be in {:?}", BASE_CURRENCY));
+        }
+        if let Some(instructions) = &package.special_instructions {
+            self.max_len(&format!("{}.special_instructions", field), instructions, MAX_TEXT_LEN);
+        }
+    }
+
+    pub(crate) fn vehicle(&mut self, field: &str, vehicle: &VehicleInfo) {
+        if vehicle.vehicle_type.needs_plate() {
+            self.required(&format!("{}.license_plate", field), &vehicle.license_plate, MAX_NAME_LEN);
+        } else {
+            self.max_len(&format!("{}.license_plate", field), &vehicle.license_plate, MAX_NAME_LEN);
+        }
+        self.non_negative(&format!("{}.capacity", field), vehicle.capacity);
+        if let Some(volume) = vehicle.volume_capacity {
+            self.non_negative(&format!("{}.volume_capacity", field), volume);
+        }
+    }
+
+    pub(crate) fn shipment(
+        &mut self,
+        recipient_name: &str,
+        recipient_phone: &str,
+        pickup_address: &Address,
+        delivery_address: &Address,
+        package_details: &PackageDetails,
+    ) {
+        self.required("recipient_name", recipient_name, MAX_NAME_LEN);
+        self.phone("recipient_phone", recipient_phone);
+        self.address("pickup_address", pickup_address);
+        self.address("delivery_address", delivery_address);
+        self.package("package_details", package_details);
+    }
+
+    pub(crate) fn finish(self) -> Result<(), ShippingError> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ShippingError::Validation { errors: self.errors })
+        }
+    }
+}
+
+// Canonical form used to compare phone numbers: digits only
+pub(crate) fn normalize_phone(value: &str) -> String {
+    value.chars().filter(|c| c.is_ascii_digit()).collect()
+}
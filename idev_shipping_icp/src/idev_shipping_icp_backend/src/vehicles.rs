@@ -0,0 +1,182 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::capacity;
+use crate::handling::HandlingClass;
+use crate::metrics;
+use crate::money::{Money, BASE_CURRENCY};
+use crate::permissions::{self, Permission};
+use crate::zones::{self, ShipmentZones};
+use crate::{CostLineItem, Driver, PackageDetails, Shipment, VehicleInfo};
+
+// Ordered from the smallest vehicle to the largest
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
+pub enum VehicleType {
+    Bicycle,
+    Motorbike,
+    Car,
+    Van,
+    Truck,
+    Refrigerated,
+}
+
+impl VehicleType {
+    pub const ALL: [VehicleType; 6] = [
+        VehicleType::Bicycle,
+        VehicleType::Motorbike,
+        VehicleType::Car,
+        VehicleType::Van,
+        VehicleType::Truck,
+        VehicleType::Refrigerated,
+    ];
+
+    // Bicycles carry no plate
+    pub(crate) fn needs_plate(self) -> bool {
+        !matches!(self, VehicleType::Bicycle)
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct VehicleProfile {
+    pub vehicle_type: VehicleType,
+    // Upper bounds on what a driver may declare as their vehicle's capacity
+    pub max_weight_kg: f64,
+    pub max_volume_cm3: f64,
+    // Used for arrival estimates on planned routes
+    pub average_speed_kmh: f64,
+    // Packages with a class outside this list never go on the vehicle
+    pub handling_classes: Vec<HandlingClass>,
+    // Zones the vehicle may not pick up from or deliver into
+    pub restricted_zone_ids: Vec<String>,
+    // Charged when this is the smallest vehicle able to carry the package
+    pub surcharge: Option<Money>,
+}
+
+thread_local! {
+    static PROFILES: RefCell<BTreeMap<VehicleType, VehicleProfile>> =
+        RefCell::new(VehicleType::ALL.iter().map(|t| (*t, default_profile(*t))).collect());
+}
+
+fn default_profile(vehicle_type: VehicleType) -> VehicleProfile {
+    use HandlingClass::*;
+    let (max_weight_kg, max_volume_cm3, average_speed_kmh, handling_classes, surcharge) = match vehicle_type {
+        VehicleType::Bicycle => (15.0, 60_000.0, 15.0, vec![Fragile, Perishable], 0),
+        VehicleType::Motorbike => (30.0, 120_000.0, 35.0, vec![Fragile, Perishable], 0),
+        VehicleType::Car => (300.0, 1_000_000.0, 30.0, vec![Fragile, Perishable], 0),
+        VehicleType::Van => (1_200.0, 10_000_000.0, 28.0, vec![Fragile, Perishable, Hazardous, Oversized], 5),
+        VehicleType::Truck => (10_000.0, 40_000_000.0, 25.0, vec![Fragile, Perishable, Hazardous, Oversized], 15),
+        VehicleType::Refrigerated => (
+            3_000.0,
+            15_000_000.0,
+            25.0,
+            vec![Fragile, Perishable, TemperatureControlled, Oversized],
+            0,
+        ),
+    };
+    VehicleProfile {
+        vehicle_type,
+        max_weight_kg,
+        max_volume_cm3,
+        average_speed_kmh,
+        handling_classes,
+        restricted_zone_ids: Vec::new(),
+        surcharge: (surcharge > 0).then(|| Money::from_units(surcharge, BASE_CURRENCY)),
+    }
+}
+
+// Admin configuration
+#[update]
+fn set_vehicle_profile(profile: VehicleProfile) -> Result<VehicleProfile, String> {
+    metrics::observe("set_vehicle_profile", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        let limits = [profile.max_weight_kg, profile.max_volume_cm3, profile.average_speed_kmh];
+        if limits.iter().any(|v| !v.is_finite() || *v <= 0.0) {
+            return Err("Capacity limits and speed must be positive".to_string());
+        }
+        if let Some(zone_id) = profile.restricted_zone_ids.iter().find(|z| !zones::zone_exists(z)) {
+            return Err(format!("Zone {} not found", zone_id));
+        }
+        if profile.surcharge.is_some_and(|s| s.currency != BASE_CURRENCY) {
+            return Err(format!("Vehicle surcharges must be in {}", BASE_CURRENCY.symbol()));
+        }
+        let mut profile = profile;
+        profile.handling_classes.sort();
+        profile.handling_classes.dedup();
+        profile.surcharge = profile.surcharge.filter(|s| !s.is_zero());
+        PROFILES.with(|p| p.borrow_mut().insert(profile.vehicle_type, profile.clone()));
+        Ok(profile)
+    })
+}
+
+#[query]
+fn get_vehicle_profiles() -> Vec<VehicleProfile> {
+    PROFILES.with(|p| p.borrow().values().cloned().collect())
+}
+
+pub(crate) fn profile(vehicle_type: VehicleType) -> VehicleProfile {
+    PROFILES.with(|p| p.borrow().get(&vehicle_type).cloned()).unwrap_or_else(|| default_profile(vehicle_type))
+}
+
+// The declared capacity, capped by the vehicle type; unset limits take the type's maximum
+pub(crate) fn limits(vehicle: &VehicleInfo) -> (f64, f64) {
+    let profile = profile(vehicle.vehicle_type);
+    let cap = |declared: Option<f64>, max: f64| declared.filter(|c| *c > 0.0).map_or(max, |c| c.min(max));
+    (
+        cap(Some(vehicle.capacity), profile.max_weight_kg),
+        cap(vehicle.volume_capacity, profile.max_volume_cm3),
+    )
+}
+
+// The driver's vehicle must take every handling class of the package and be allowed
+// in the shipment's pickup and delivery zones
+pub(crate) fn check_vehicle(driver: &Driver, shipment: &Shipment) -> Result<(), String> {
+    let profile = profile(driver.vehicle_info.vehicle_type);
+    if let Some(class) = unsupported_class(&profile, &shipment.package_details) {
+        return Err(format!("{:?} vehicles cannot carry {:?} packages", profile.vehicle_type, class));
+    }
+    let zone_ids = [shipment.pickup_zone_id.as_deref(), shipment.delivery_zone_id.as_deref()];
+    if let Some(zone_id) = restricted_zone(&profile, &zone_ids) {
+        return Err(format!("{:?} vehicles are not allowed in zone {}", profile.vehicle_type, zone_id));
+    }
+    Ok(())
+}
+
+// Surcharge of the smallest vehicle type that can carry the package on this route
+pub(crate) fn surcharge(package: &PackageDetails, zones: &ShipmentZones) -> Option<CostLineItem> {
+    let zone_ids = [zones.pickup_zone_id.as_deref(), zones.delivery_zone_id.as_deref()];
+    let volume = capacity::volume_cm3(package);
+    let profile = PROFILES.with(|p| {
+        p.borrow()
+            .values()
+            .find(|p| {
+                package.weight <= p.max_weight_kg
+                    && volume <= p.max_volume_cm3
+                    && unsupported_class(p, package).is_none()
+                    && restricted_zone(p, &zone_ids).is_none()
+            })
+            .cloned()
+    })?;
+    profile.surcharge.map(|amount| CostLineItem {
+        label: format!("Vehicle: {:?}", profile.vehicle_type),
+        amount,
+    })
+}
+
+fn unsupported_class(profile: &VehicleProfile, package: &PackageDetails) -> Option<HandlingClass> {
+    package
+        .handling_classes()
+        .iter()
+        .find(|c| !profile.handling_classes.contains(c))
+        .copied()
+}
+
+fn restricted_zone<'a>(profile: &VehicleProfile, zone_ids: &[Option<&'a str>]) -> Option<&'a str> {
+    zone_ids
+        .iter()
+        .flatten()
+        .find(|z| profile.restricted_zone_ids.iter().any(|r| r == *z))
+        .copied()
+}
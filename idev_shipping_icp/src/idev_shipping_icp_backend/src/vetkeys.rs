@@ -0,0 +1,198 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::event_store::{self, ShipmentEvent};
+use crate::guards::{self, RateLimitedAction};
+use crate::metrics;
+use crate::permissions::{self, Permission};
+use crate::resource_usage::{self, ResourceFeature};
+use crate::{Shipment, ShipmentStatus, SHIPMENTS};
+
+// Separates these keys from any other use of the canister's vetKD keys
+const INSTRUCTIONS_CONTEXT: &[u8] = b"special_instructions";
+// A door code with room to spare, plus the IBE ciphertext overhead
+const MAX_CIPHERTEXT_BYTES: usize = 4 * 1024;
+// G1 point the derived key is encrypted to
+const TRANSPORT_KEY_BYTES: usize = 48;
+
+// Special instructions are end-to-end encrypted by the client: it encrypts to the
+// shipment id under the canister's public key, and the parties decrypt with a key
+// derived for that id, which only they can request
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct VetKdConfig {
+    // `dfx_test_key` locally, `test_key_1` or `key_1` on mainnet
+    pub key_name: String,
+    // Fee for one derivation; unused cycles are refunded
+    pub derive_cycles: u128,
+}
+
+#[derive(CandidType, Deserialize)]
+enum VetKdCurve {
+    #[serde(rename = "bls12_381_g2")]
+    Bls12381G2,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VetKdKeyId {
+    curve: VetKdCurve,
+    name: String,
+}
+
+#[derive(CandidType)]
+struct VetKdPublicKeyArgs {
+    canister_id: Option<Principal>,
+    context: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(Deserialize, CandidType)]
+struct VetKdPublicKeyResult {
+    public_key: Vec<u8>,
+}
+
+#[derive(CandidType)]
+struct VetKdDeriveKeyArgs {
+    input: Vec<u8>,
+    context: Vec<u8>,
+    transport_public_key: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(Deserialize, CandidType)]
+struct VetKdDeriveKeyResult {
+    encrypted_key: Vec<u8>,
+}
+
+thread_local! {
+    static CONFIG: RefCell<VetKdConfig> = RefCell::new(VetKdConfig {
+        key_name: "key_1".to_string(),
+        derive_cycles: 26_153_846_153,
+    });
+    static PUBLIC_KEYS: RefCell<HashMap<String, Vec<u8>>> = RefCell::new(HashMap::new());
+}
+
+// Admin configuration
+#[update]
+fn set_vetkd_config(config: VetKdConfig) -> Result<VetKdConfig, String> {
+    metrics::observe("set_vetkd_config", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if config.key_name.trim().is_empty() {
+            return Err("Key name cannot be empty".to_string());
+        }
+        CONFIG.with(|c| *c.borrow_mut() = config.clone());
+        Ok(config)
+    })
+}
+
+#[query]
+fn get_vetkd_config() -> VetKdConfig {
+    CONFIG.with(|c| c.borrow().clone())
+}
+
+// What clients encrypt special instructions to
+#[update]
+async fn get_instructions_public_key() -> Result<Vec<u8>, String> {
+    metrics::observe_async("get_instructions_public_key", async move {
+        let key_name = CONFIG.with(|c| c.borrow().key_name.clone());
+        if let Some(key) = PUBLIC_KEYS.with(|keys| keys.borrow().get(&key_name).cloned()) {
+            return Ok(key);
+        }
+        let args = VetKdPublicKeyArgs {
+            canister_id: None,
+            context: INSTRUCTIONS_CONTEXT.to_vec(),
+            key_id: key_id(&key_name),
+        };
+        let (result,): (VetKdPublicKeyResult,) =
+            ic_cdk::call(Principal::management_canister(), "vetkd_public_key", (args,))
+                .await
+                .map_err(|(code, message)| format!("Public key lookup failed: {:?}: {}", code, message))?;
+        PUBLIC_KEYS.with(|keys| keys.borrow_mut().insert(key_name, result.public_key.clone()));
+        Ok(result.public_key)
+    })
+    .await
+}
+
+// The sender stores the ciphertext and drops any plaintext instructions; an
+// empty ciphertext clears them
+#[update]
+fn set_encrypted_instructions(shipment_id: String, ciphertext: Vec<u8>) -> Result<Shipment, String> {
+    metrics::observe("set_encrypted_instructions", || {
+        let caller = ic_cdk::caller();
+        if ciphertext.len() > MAX_CIPHERTEXT_BYTES {
+            return Err(format!("Encrypted instructions must be at most {} bytes", MAX_CIPHERTEXT_BYTES));
+        }
+        SHIPMENTS.with(|shipments| {
+            let mut shipments = shipments.borrow_mut();
+            let shipment = shipments
+                .get_mut(&shipment_id)
+                .ok_or_else(|| "Shipment not found".to_string())?;
+            if shipment.sender_id != caller {
+                return Err("Only the sender can set special instructions".to_string());
+            }
+            if matches!(
+                shipment.status,
+                ShipmentStatus::Delivered | ShipmentStatus::Cancelled | ShipmentStatus::Returned
+            ) {
+                return Err("Shipment is already finished".to_string());
+            }
+            shipment.encrypted_instructions = (!ciphertext.is_empty()).then_some(ciphertext);
+            shipment.package_details.special_instructions = None;
+            shipment.touch(time());
+            let event = ShipmentEvent::InstructionsEncrypted {
+                ciphertext: shipment.encrypted_instructions.clone(),
+            };
+            event_store::record(shipment, caller, event);
+            Ok(shipment.clone())
+        })
+    })
+}
+
+// The shipment's decryption key, encrypted to the caller's transport key.
+// Only the sender, the assigned driver and the recipient can have it.
+#[update]
+async fn derive_instructions_key(shipment_id: String, transport_public_key: Vec<u8>) -> Result<Vec<u8>, String> {
+    metrics::observe_async("derive_instructions_key", async move {
+        let caller = ic_cdk::caller();
+        if transport_public_key.len() != TRANSPORT_KEY_BYTES {
+            return Err(format!("Transport public key must be {} bytes", TRANSPORT_KEY_BYTES));
+        }
+        let sender_id = SHIPMENTS.with(|shipments| {
+            let shipments = shipments.borrow();
+            let shipment = shipments.get(&shipment_id).ok_or_else(|| "Shipment not found".to_string())?;
+            let is_party =
+                shipment.sender_id == caller || shipment.driver_id == Some(caller) || shipment.recipient_id == Some(caller);
+            if !is_party {
+                return Err("Not authorized to read this shipment's instructions".to_string());
+            }
+            Ok(shipment.sender_id)
+        })?;
+        guards::check_rate_limit(caller, RateLimitedAction::DeriveKey)?;
+
+        let config = CONFIG.with(|c| c.borrow().clone());
+        let args = VetKdDeriveKeyArgs {
+            input: shipment_id.as_bytes().to_vec(),
+            context: INSTRUCTIONS_CONTEXT.to_vec(),
+            transport_public_key,
+            key_id: key_id(&config.key_name),
+        };
+        resource_usage::record(sender_id, Some(&shipment_id), ResourceFeature::KeyDerivation, config.derive_cycles, 0);
+        let management = Principal::management_canister();
+        let (result,): (VetKdDeriveKeyResult,) =
+            ic_cdk::api::call::call_with_payment128(management, "vetkd_derive_key", (args,), config.derive_cycles)
+                .await
+                .map_err(|(code, message)| format!("Key derivation failed: {:?}: {}", code, message))?;
+        Ok(result.encrypted_key)
+    })
+    .await
+}
+
+fn key_id(key_name: &str) -> VetKdKeyId {
+    VetKdKeyId {
+        curve: VetKdCurve::Bls12381G2,
+        name: key_name.to_string(),
+    }
+}
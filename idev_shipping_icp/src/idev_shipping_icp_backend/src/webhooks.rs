@@ -0,0 +1,531 @@
+use candid::{CandidType, Deserialize, Principal};
+use hmac::{Hmac, Mac};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use sha2::Sha256;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+use crate::events::ShipmentEventKind;
+use crate::metrics;
+use crate::outbox::{self, AttemptError, OutboxCommand};
+use crate::permissions::{self, Permission};
+use crate::resource_usage::{self, ResourceFeature};
+use crate::settings;
+use crate::{Shipment, USERS};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MIN_SECRET_LENGTH: usize = 16;
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const MAX_LOGS_PER_ENDPOINT: usize = 100;
+const MAX_RESPONSE_BYTES: u64 = 2048;
+
+// Webhook endpoint data structures. The secret never leaves the canister.
+#[derive(Clone, Debug)]
+struct WebhookEndpoint {
+    id: String,
+    owner: Principal,
+    url: String,
+    secret: String,
+    event_types: Vec<ShipmentEventKind>,
+    last_delivery_id: u64,
+    created_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct WebhookEndpointInfo {
+    pub id: String,
+    pub owner: Principal,
+    pub url: String,
+    pub event_types: Vec<ShipmentEventKind>,
+    pub last_delivery_id: u64,
+    pub created_at: u64,
+}
+
+// A signed callback. Integrators recompute
+// hex(HMAC-SHA256(secret, "{delivery_id}.{timestamp}.{payload}")) and reject any
+// delivery id that is not greater than the last one they accepted.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SignedWebhookDelivery {
+    pub endpoint_id: String,
+    pub delivery_id: u64,
+    pub timestamp: u64,
+    pub payload: String,
+    pub signature: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct WebhookDeliveryLog {
+    pub delivery_id: u64,
+    pub event: ShipmentEventKind,
+    pub shipment_id: String,
+    pub state: DeliveryState,
+    pub attempts: u32,
+    pub last_status_code: Option<u16>,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum DeliveryState {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct OutcallBudget {
+    pub enabled: bool,
+    pub max_outcalls_per_hour: u32,
+    pub used_this_hour: u32,
+    pub window_started_at: u64,
+}
+
+thread_local! {
+    static WEBHOOK_ENDPOINTS: RefCell<HashMap<String, WebhookEndpoint>> = RefCell::new(HashMap::new());
+    static WEBHOOK_COUNTER: RefCell<u64> = RefCell::new(0);
+    static DELIVERY_LOGS: RefCell<HashMap<String, VecDeque<WebhookDeliveryLog>>> = RefCell::new(HashMap::new());
+    static OUTCALL_BUDGET: RefCell<OutcallBudget> = RefCell::new(OutcallBudget {
+        enabled: true,
+        max_outcalls_per_hour: 500,
+        used_this_hour: 0,
+        window_started_at: 0,
+    });
+}
+
+// Subscription management
+#[update]
+fn register_webhook(
+    url: String,
+    event_types: Vec<ShipmentEventKind>,
+    secret: String,
+) -> Result<WebhookEndpointInfo, String> {
+    metrics::observe("register_webhook", || {
+        let caller = ic_cdk::caller();
+        if !USERS.with(|users| users.borrow().contains_key(&caller)) {
+            return Err("User not registered".to_string());
+        }
+        permissions::require(&caller, Permission::ManageWebhooks)?;
+        if !url.starts_with("https://") {
+            return Err("Webhook URL must use https".to_string());
+        }
+        if secret.len() < MIN_SECRET_LENGTH {
+            return Err(format!("Webhook secret must be at least {} characters", MIN_SECRET_LENGTH));
+        }
+        if event_types.is_empty() {
+            return Err("Subscribe to at least one event type".to_string());
+        }
+
+        let endpoint_id = WEBHOOK_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            format!("WH{:06}", *c)
+        });
+
+        let endpoint = WebhookEndpoint {
+            id: endpoint_id.clone(),
+            owner: caller,
+            url,
+            secret,
+            event_types,
+            last_delivery_id: 0,
+            created_at: time(),
+        };
+        let info = endpoint_info(&endpoint);
+
+        WEBHOOK_ENDPOINTS.with(|endpoints| {
+            endpoints.borrow_mut().insert(endpoint_id, endpoint);
+        });
+
+        Ok(info)
+    })
+}
+
+#[update]
+fn update_webhook_events(endpoint_id: String, event_types: Vec<ShipmentEventKind>) -> Result<WebhookEndpointInfo, String> {
+    metrics::observe("update_webhook_events", || {
+        let caller = ic_cdk::caller();
+        if event_types.is_empty() {
+            return Err("Subscribe to at least one event type".to_string());
+        }
+
+        WEBHOOK_ENDPOINTS.with(|endpoints| {
+            match endpoints.borrow_mut().get_mut(&endpoint_id) {
+                Some(endpoint) if endpoint.owner == caller => {
+                    endpoint.event_types = event_types;
+                    Ok(endpoint_info(endpoint))
+                },
+                Some(_) => Err("Unauthorized to manage webhook".to_string()),
+                None => Err("Webhook endpoint not found".to_string()),
+            }
+        })
+    })
+}
+
+#[update]
+fn rotate_webhook_secret(endpoint_id: String, secret: String) -> Result<WebhookEndpointInfo, String> {
+    metrics::observe("rotate_webhook_secret", || {
+        let caller = ic_cdk::caller();
+        if secret.len() < MIN_SECRET_LENGTH {
+            return Err(format!("Webhook secret must be at least {} characters", MIN_SECRET_LENGTH));
+        }
+
+        WEBHOOK_ENDPOINTS.with(|endpoints| {
+            match endpoints.borrow_mut().get_mut(&endpoint_id) {
+                Some(endpoint) if endpoint.owner == caller => {
+                    endpoint.secret = secret;
+                    Ok(endpoint_info(endpoint))
+                },
+                Some(_) => Err("Unauthorized to manage webhook".to_string()),
+                None => Err("Webhook endpoint not found".to_string()),
+            }
+        })
+    })
+}
+
+#[update]
+fn delete_webhook(endpoint_id: String) -> Result<(), String> {
+    metrics::observe("delete_webhook", || {
+        let caller = ic_cdk::caller();
+        WEBHOOK_ENDPOINTS.with(|endpoints| {
+            let mut endpoints_map = endpoints.borrow_mut();
+            match endpoints_map.get(&endpoint_id) {
+                Some(endpoint) if endpoint.owner == caller => {
+                    endpoints_map.remove(&endpoint_id);
+                    Ok(())
+                },
+                Some(_) => Err("Unauthorized to manage webhook".to_string()),
+                None => Err("Webhook endpoint not found".to_string()),
+            }
+        })?;
+
+        outbox::discard_where(
+            |e| matches!(&e.command, OutboxCommand::DeliverWebhook { endpoint_id: id, .. } if *id == endpoint_id),
+        );
+        DELIVERY_LOGS.with(|logs| logs.borrow_mut().remove(&endpoint_id));
+        Ok(())
+    })
+}
+
+#[query]
+fn get_my_webhook_endpoints() -> Vec<WebhookEndpointInfo> {
+    let caller = ic_cdk::caller();
+    WEBHOOK_ENDPOINTS.with(|endpoints| {
+        endpoints
+            .borrow()
+            .values()
+            .filter(|e| e.owner == caller)
+            .map(endpoint_info)
+            .collect()
+    })
+}
+
+#[query]
+fn get_webhook_deliveries(endpoint_id: String) -> Result<Vec<WebhookDeliveryLog>, String> {
+    let caller = ic_cdk::caller();
+    let owner = WEBHOOK_ENDPOINTS
+        .with(|endpoints| endpoints.borrow().get(&endpoint_id).map(|e| e.owner))
+        .ok_or_else(|| "Webhook endpoint not found".to_string())?;
+    if owner != caller && !permissions::has(&caller, Permission::ManagePlatform) {
+        return Err("Unauthorized to manage webhook".to_string());
+    }
+
+    Ok(DELIVERY_LOGS.with(|logs| {
+        logs.borrow()
+            .get(&endpoint_id)
+            .map(|l| l.iter().cloned().collect())
+            .unwrap_or_default()
+    }))
+}
+
+// Produce a signed test delivery so integrators can exercise their verification code
+#[update]
+fn send_test_webhook(endpoint_id: String) -> Result<SignedWebhookDelivery, String> {
+    metrics::observe("send_test_webhook", || {
+        let caller = ic_cdk::caller();
+        let is_owner = WEBHOOK_ENDPOINTS.with(|endpoints| {
+            endpoints
+                .borrow()
+                .get(&endpoint_id)
+                .map(|e| e.owner == caller)
+                .unwrap_or(false)
+        });
+        if !is_owner {
+            return Err("Unauthorized to manage webhook".to_string());
+        }
+
+        sign_delivery(&endpoint_id, format!("{{\"event\":\"ping\",\"endpoint_id\":\"{}\"}}", endpoint_id))
+    })
+}
+
+// Check a delivery against the endpoint's current secret; only signatures for issued
+// delivery ids are accepted
+#[query]
+fn verify_webhook_signature(
+    endpoint_id: String,
+    delivery_id: u64,
+    timestamp: u64,
+    payload: String,
+    signature: String,
+) -> Result<bool, String> {
+    let caller = ic_cdk::caller();
+    WEBHOOK_ENDPOINTS.with(|endpoints| {
+        let endpoints_map = endpoints.borrow();
+        let endpoint = endpoints_map
+            .get(&endpoint_id)
+            .ok_or_else(|| "Webhook endpoint not found".to_string())?;
+        if endpoint.owner != caller {
+            return Err("Unauthorized to manage webhook".to_string());
+        }
+        if delivery_id == 0 || delivery_id > endpoint.last_delivery_id {
+            return Ok(false);
+        }
+        Ok(compute_signature(&endpoint.secret, delivery_id, timestamp, &payload) == signature)
+    })
+}
+
+// Admin controls on HTTPS outcall spend
+#[update]
+fn set_webhook_outcall_budget(enabled: bool, max_outcalls_per_hour: u32) -> Result<OutcallBudget, String> {
+    metrics::observe("set_webhook_outcall_budget", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+
+        Ok(OUTCALL_BUDGET.with(|budget| {
+            let mut b = budget.borrow_mut();
+            b.enabled = enabled;
+            b.max_outcalls_per_hour = max_outcalls_per_hour;
+            b.clone()
+        }))
+    })
+}
+
+#[query]
+fn get_webhook_outcall_budget() -> Result<OutcallBudget, String> {
+    let caller = ic_cdk::caller();
+    permissions::require(&caller, Permission::ManagePlatform)?;
+    Ok(OUTCALL_BUDGET.with(|budget| budget.borrow().clone()))
+}
+
+// Replicas must agree on the outcall response, so keep only the status code
+#[query]
+fn webhook_transform(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+// Queue a signed delivery for every endpoint subscribed to this event. Endpoints see
+// events for shipments their owner sent; admin endpoints see all shipments.
+pub(crate) fn enqueue_event(shipment: &Shipment, kind: &ShipmentEventKind) {
+    let targets: Vec<(String, Principal, String)> = WEBHOOK_ENDPOINTS.with(|endpoints| {
+        endpoints
+            .borrow()
+            .values()
+            .filter(|e| e.event_types.contains(kind))
+            .filter(|e| e.owner == shipment.sender_id || permissions::has(&e.owner, Permission::ViewAllShipments))
+            .map(|e| (e.id.clone(), e.owner, e.url.clone()))
+            .collect()
+    });
+
+    let payload = format!(
+        "{{\"event\":\"{:?}\",\"shipment_id\":\"{}\",\"status\":\"{:?}\",\"occurred_at\":{}}}",
+        kind,
+        shipment.id,
+        shipment.status,
+        time()
+    );
+
+    for (endpoint_id, owner, url) in targets {
+        let delivery = match sign_delivery(&endpoint_id, payload.clone()) {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+        log_delivery(
+            &endpoint_id,
+            WebhookDeliveryLog {
+                delivery_id: delivery.delivery_id,
+                event: kind.clone(),
+                shipment_id: shipment.id.clone(),
+                state: DeliveryState::Pending,
+                attempts: 0,
+                last_status_code: None,
+                last_error: None,
+                created_at: time(),
+                completed_at: None,
+            },
+        );
+        outbox::enqueue(
+            None,
+            None,
+            OutboxCommand::DeliverWebhook {
+                endpoint_id,
+                owner,
+                shipment_id: shipment.id.clone(),
+                url,
+                delivery,
+            },
+            settings::webhook_max_attempts(),
+        );
+    }
+}
+
+// Claim one outcall from the hourly budget
+pub(crate) fn take_outcall() -> bool {
+    let now = time();
+    OUTCALL_BUDGET.with(|budget| {
+        let mut b = budget.borrow_mut();
+        if now.saturating_sub(b.window_started_at) >= NANOS_PER_HOUR {
+            b.window_started_at = now;
+            b.used_this_hour = 0;
+        }
+        let available = b.enabled && b.used_this_hour < b.max_outcalls_per_hour;
+        if available {
+            b.used_this_hour += 1;
+        }
+        available
+    })
+}
+
+// Outbox executor; the delivery log follows each attempt
+pub(crate) async fn deliver(
+    endpoint_id: &str,
+    owner: Principal,
+    shipment_id: &str,
+    url: &str,
+    delivery: &SignedWebhookDelivery,
+) -> Result<(), AttemptError> {
+    // Outcalls are billed to the integrator that owns the endpoint
+    let request_bytes = (url.len() + delivery.payload.len() + delivery.signature.len()) as u64;
+    resource_usage::record(
+        owner,
+        Some(shipment_id),
+        ResourceFeature::WebhookOutcall,
+        resource_usage::outcall_cycles(request_bytes, MAX_RESPONSE_BYTES),
+        0,
+    );
+    let result = send(url, delivery).await;
+    let (status_code, error) = match &result {
+        Ok(code) if (200..300).contains(code) => (Some(*code), None),
+        Ok(code) => (Some(*code), Some(format!("HTTP {}", code))),
+        Err(e) => (None, Some(e.clone())),
+    };
+    update_log(endpoint_id, delivery.delivery_id, |log| {
+        log.attempts += 1;
+        log.last_status_code = status_code;
+        log.last_error = error.clone();
+        if error.is_none() {
+            log.state = DeliveryState::Delivered;
+            log.completed_at = Some(time());
+        }
+    });
+    error.map_or(Ok(()), |e| Err(AttemptError::Retry(e)))
+}
+
+// The outbox gave up on the delivery
+pub(crate) fn delivery_failed(endpoint_id: &str, delivery_id: u64) {
+    update_log(endpoint_id, delivery_id, |log| {
+        log.state = DeliveryState::Failed;
+        log.completed_at = Some(time());
+    });
+}
+
+async fn send(url: &str, d: &SignedWebhookDelivery) -> Result<u16, String> {
+    let request = CanisterHttpRequestArgument {
+        url: url.to_string(),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader { name: "Content-Type".to_string(), value: "application/json".to_string() },
+            HttpHeader { name: "X-Webhook-Endpoint".to_string(), value: d.endpoint_id.clone() },
+            HttpHeader { name: "X-Webhook-Delivery".to_string(), value: d.delivery_id.to_string() },
+            HttpHeader { name: "X-Webhook-Timestamp".to_string(), value: d.timestamp.to_string() },
+            HttpHeader { name: "X-Webhook-Signature".to_string(), value: d.signature.clone() },
+        ],
+        body: Some(d.payload.clone().into_bytes()),
+        transform: Some(TransformContext::new(webhook_transform, Vec::new())),
+    };
+
+    match http_request(request).await {
+        Ok((response,)) => response
+            .status
+            .to_string()
+            .parse::<u16>()
+            .map_err(|_| "Invalid status code".to_string()),
+        Err((code, message)) => Err(format!("{:?}: {}", code, message)),
+    }
+}
+
+fn update_log(endpoint_id: &str, delivery_id: u64, f: impl FnOnce(&mut WebhookDeliveryLog)) {
+    DELIVERY_LOGS.with(|logs| {
+        if let Some(log) = logs
+            .borrow_mut()
+            .get_mut(endpoint_id)
+            .and_then(|l| l.iter_mut().find(|l| l.delivery_id == delivery_id))
+        {
+            f(log);
+        }
+    });
+}
+
+fn log_delivery(endpoint_id: &str, entry: WebhookDeliveryLog) {
+    DELIVERY_LOGS.with(|logs| {
+        let mut logs_map = logs.borrow_mut();
+        let log = logs_map.entry(endpoint_id.to_string()).or_default();
+        log.push_back(entry);
+        while log.len() > MAX_LOGS_PER_ENDPOINT {
+            log.pop_front();
+        }
+    });
+}
+
+// Assign the next delivery id for the endpoint and sign the payload with its secret
+fn sign_delivery(endpoint_id: &str, payload: String) -> Result<SignedWebhookDelivery, String> {
+    WEBHOOK_ENDPOINTS.with(|endpoints| {
+        let mut endpoints_map = endpoints.borrow_mut();
+        let endpoint = endpoints_map
+            .get_mut(endpoint_id)
+            .ok_or_else(|| "Webhook endpoint not found".to_string())?;
+
+        endpoint.last_delivery_id += 1;
+        let timestamp = time();
+        let signature = compute_signature(&endpoint.secret, endpoint.last_delivery_id, timestamp, &payload);
+
+        Ok(SignedWebhookDelivery {
+            endpoint_id: endpoint.id.clone(),
+            delivery_id: endpoint.last_delivery_id,
+            timestamp,
+            payload,
+            signature,
+        })
+    })
+}
+
+fn compute_signature(secret: &str, delivery_id: u64, timestamp: u64, payload: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}.{}", delivery_id, timestamp, payload).as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn endpoint_info(endpoint: &WebhookEndpoint) -> WebhookEndpointInfo {
+    WebhookEndpointInfo {
+        id: endpoint.id.clone(),
+        owner: endpoint.owner,
+        url: endpoint.url.clone(),
+        event_types: endpoint.event_types.clone(),
+        last_delivery_id: endpoint.last_delivery_id,
+        created_at: endpoint.created_at,
+    }
+}
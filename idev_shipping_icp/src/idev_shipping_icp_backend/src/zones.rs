@@ -0,0 +1,331 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::metrics;
+use crate::money::{Money, BASE_CURRENCY};
+use crate::permissions::{self, Permission};
+use crate::postal_codes;
+use crate::{Address, Coordinates};
+
+// Delivery zone data structures
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DeliveryZone {
+    pub id: String,
+    pub name: String,
+    pub area: ZoneArea,
+    // Added to the price of shipments delivered into the zone
+    pub surcharge: Option<Money>,
+    // Added to the service level delivery target for shipments into the zone
+    pub sla_extra_hours: u64,
+    pub is_active: bool,
+    pub created_at: u64,
+}
+
+// Postal codes are compared without spaces and case; an entry ending in `*`
+// matches every code with that prefix. Polygons need address coordinates.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum ZoneArea {
+    Polygon(Vec<Coordinates>),
+    PostalCodes { country: String, postal_codes: Vec<String> },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Serviceability {
+    pub serviceable: bool,
+    pub zone_ids: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ShipmentZones {
+    pub pickup_zone_id: Option<String>,
+    pub delivery_zone_id: Option<String>,
+    pub surcharge: Option<(String, Money)>,
+    pub sla_extra_hours: u64,
+}
+
+thread_local! {
+    // Ordered by id so the oldest matching zone wins when zones overlap
+    static DELIVERY_ZONES: RefCell<BTreeMap<String, DeliveryZone>> = RefCell::new(BTreeMap::new());
+    static ZONE_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Admin management of delivery zones
+#[update]
+fn create_delivery_zone(
+    name: String,
+    area: ZoneArea,
+    surcharge: Option<Money>,
+    sla_extra_hours: Option<u64>,
+) -> Result<DeliveryZone, String> {
+    metrics::observe("create_delivery_zone", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        validate_area(&area)?;
+        validate_surcharge(&surcharge)?;
+
+        let zone_id = ZONE_COUNTER.with(|counter| {
+            let mut c = counter.borrow_mut();
+            *c += 1;
+            format!("ZN{:06}", *c)
+        });
+
+        let zone = DeliveryZone {
+            id: zone_id.clone(),
+            name,
+            area,
+            surcharge: surcharge.filter(|s| !s.is_zero()),
+            sla_extra_hours: sla_extra_hours.unwrap_or(0),
+            is_active: true,
+            created_at: time(),
+        };
+
+        DELIVERY_ZONES.with(|zones| {
+            zones.borrow_mut().insert(zone_id, zone.clone());
+        });
+
+        Ok(zone)
+    })
+}
+
+#[update]
+fn update_delivery_zone(
+    zone_id: String,
+    name: Option<String>,
+    area: Option<ZoneArea>,
+    surcharge: Option<Money>,
+    is_active: Option<bool>,
+    sla_extra_hours: Option<u64>,
+) -> Result<DeliveryZone, String> {
+    metrics::observe("update_delivery_zone", || {
+        let caller = ic_cdk::caller();
+        permissions::require(&caller, Permission::ManagePlatform)?;
+        if let Some(area) = &area {
+            validate_area(area)?;
+        }
+        validate_surcharge(&surcharge)?;
+
+        DELIVERY_ZONES.with(|zones| {
+            match zones.borrow_mut().get_mut(&zone_id) {
+                Some(zone) => {
+                    if let Some(name) = name {
+                        zone.name = name;
+                    }
+                    if let Some(area) = area {
+                        zone.area = area;
+                    }
+                    if surcharge.is_some() {
+                        zone.surcharge = surcharge.filter(|s| !s.is_zero());
+                    }
+                    if let Some(is_active) = is_active {
+                        zone.is_active = is_active;
+                    }
+                    if let Some(hours) = sla_extra_hours {
+                        zone.sla_extra_hours = hours;
+                    }
+                    Ok(zone.clone())
+                },
+                None => Err("Delivery zone not found".to_string()),
+            }
+        })
+    })
+}
+
+#[query]
+fn get_delivery_zones() -> Vec<DeliveryZone> {
+    DELIVERY_ZONES.with(|zones| zones.borrow().values().cloned().collect())
+}
+
+#[query]
+fn check_serviceability(address: Address) -> Serviceability {
+    let zone_ids = matching_zones(&address);
+    Serviceability {
+        serviceable: !zone_ids.is_empty() || !has_active_zones(),
+        zone_ids,
+    }
+}
+
+// Resolve the zones of a new shipment. Until the first zone is configured the
+// platform serves everywhere and shipments carry no zone ids.
+pub(crate) fn resolve_shipment_zones(pickup: &Address, delivery: &Address) -> Result<ShipmentZones, String> {
+    if !has_active_zones() {
+        return Ok(ShipmentZones {
+            pickup_zone_id: None,
+            delivery_zone_id: None,
+            surcharge: None,
+            sla_extra_hours: 0,
+        });
+    }
+
+    let pickup_zone_id = matching_zones(pickup)
+        .into_iter()
+        .next()
+        .ok_or_else(|| "Pickup address is outside the service area".to_string())?;
+    let delivery_zone_id = matching_zones(delivery)
+        .into_iter()
+        .next()
+        .ok_or_else(|| "Delivery address is outside the service area".to_string())?;
+
+    let (surcharge, sla_extra_hours) = DELIVERY_ZONES.with(|zones| {
+        zones
+            .borrow()
+            .get(&delivery_zone_id)
+            .map(|z| (z.surcharge.map(|s| (z.name.clone(), s)), z.sla_extra_hours))
+            .unwrap_or((None, 0))
+    });
+
+    Ok(ShipmentZones {
+        pickup_zone_id: Some(pickup_zone_id),
+        delivery_zone_id: Some(delivery_zone_id),
+        surcharge,
+        sla_extra_hours,
+    })
+}
+
+pub(crate) fn len() -> usize {
+    DELIVERY_ZONES.with(|zones| zones.borrow().len())
+}
+
+pub(crate) fn zone_exists(zone_id: &str) -> bool {
+    DELIVERY_ZONES.with(|zones| zones.borrow().contains_key(zone_id))
+}
+
+pub(crate) fn active_zone_ids() -> Vec<String> {
+    DELIVERY_ZONES.with(|zones| {
+        zones
+            .borrow()
+            .values()
+            .filter(|z| z.is_active)
+            .map(|z| z.id.clone())
+            .collect()
+    })
+}
+
+// Active polygon zones, the only kind a bare position such as a driver's can be placed in
+pub(crate) fn polygon_zone_ids() -> Vec<String> {
+    DELIVERY_ZONES.with(|zones| {
+        zones
+            .borrow()
+            .values()
+            .filter(|z| z.is_active && matches!(z.area, ZoneArea::Polygon(_)))
+            .map(|z| z.id.clone())
+            .collect()
+    })
+}
+
+pub(crate) fn polygon_zones_at(point: &Coordinates) -> Vec<String> {
+    DELIVERY_ZONES.with(|zones| {
+        zones
+            .borrow()
+            .values()
+            .filter(|z| z.is_active && matches!(&z.area, ZoneArea::Polygon(v) if polygon_contains(v, point)))
+            .map(|z| z.id.clone())
+            .collect()
+    })
+}
+
+fn has_active_zones() -> bool {
+    DELIVERY_ZONES.with(|zones| zones.borrow().values().any(|z| z.is_active))
+}
+
+// A zone the postal code reference pins the address to comes first
+fn matching_zones(address: &Address) -> Vec<String> {
+    let pinned = postal_codes::zone_for(address);
+    DELIVERY_ZONES.with(|zones| {
+        let zones = zones.borrow();
+        let pinned = pinned.filter(|id| zones.get(id).is_some_and(|z| z.is_active));
+        pinned
+            .iter()
+            .cloned()
+            .chain(
+                zones
+                    .values()
+                    .filter(|z| z.is_active && Some(&z.id) != pinned.as_ref() && area_contains(&z.area, address))
+                    .map(|z| z.id.clone()),
+            )
+            .collect()
+    })
+}
+
+fn area_contains(area: &ZoneArea, address: &Address) -> bool {
+    match area {
+        ZoneArea::PostalCodes { country, postal_codes } => {
+            if !country.trim().eq_ignore_ascii_case(address.country.trim()) {
+                return false;
+            }
+            let code = normalize_postal_code(&address.postal_code);
+            postal_codes.iter().any(|pattern| {
+                let pattern = normalize_postal_code(pattern);
+                match pattern.strip_suffix('*') {
+                    Some(prefix) => code.starts_with(prefix),
+                    None => code == pattern,
+                }
+            })
+        },
+        ZoneArea::Polygon(vertices) => match &address.coordinates {
+            Some(point) => polygon_contains(vertices, point),
+            None => false,
+        },
+    }
+}
+
+// Ray casting on latitude/longitude treated as planar coordinates, which is
+// accurate enough for city-sized zones away from the antimeridian
+fn polygon_contains(vertices: &[Coordinates], point: &Coordinates) -> bool {
+    let mut inside = false;
+    let mut j = vertices.len() - 1;
+    for i in 0..vertices.len() {
+        let (a, b) = (&vertices[i], &vertices[j]);
+        if (a.latitude > point.latitude) != (b.latitude > point.latitude)
+            && point.longitude
+                < (b.longitude - a.longitude) * (point.latitude - a.latitude) / (b.latitude - a.latitude) + a.longitude
+        {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+pub(crate) fn normalize_postal_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+fn validate_area(area: &ZoneArea) -> Result<(), String> {
+    match area {
+        ZoneArea::Polygon(vertices) => {
+            if vertices.len() < 3 {
+                return Err("Zone polygons need at least 3 vertices".to_string());
+            }
+            let in_range = vertices.iter().all(|v| {
+                (-90.0..=90.0).contains(&v.latitude) && (-180.0..=180.0).contains(&v.longitude)
+            });
+            if !in_range {
+                return Err("Zone polygon coordinates are out of range".to_string());
+            }
+        },
+        ZoneArea::PostalCodes { country, postal_codes } => {
+            if country.trim().is_empty() {
+                return Err("Country is required for postal code zones".to_string());
+            }
+            if postal_codes.is_empty() || postal_codes.iter().any(|c| normalize_postal_code(c).is_empty()) {
+                return Err("Postal code zones need at least one non-empty postal code".to_string());
+            }
+        },
+    }
+    Ok(())
+}
+
+fn validate_surcharge(surcharge: &Option<Money>) -> Result<(), String> {
+    match surcharge {
+        Some(s) if s.currency != BASE_CURRENCY => {
+            Err(format!("Zone surcharges must be in {}", BASE_CURRENCY.symbol()))
+        },
+        _ => Ok(()),
+    }
+}
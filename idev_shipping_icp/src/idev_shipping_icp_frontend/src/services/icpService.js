@@ -29,6 +29,13 @@ const idlFactory = ({ IDL }) => {
     'longitude': IDL.Float64,
   });
 
+  const AddressExtras = IDL.Record({
+    'building': IDL.Opt(IDL.Text),
+    'floor': IDL.Opt(IDL.Text),
+    'landmark': IDL.Opt(IDL.Text),
+    'what3words': IDL.Opt(IDL.Text),
+  });
+
   const Address = IDL.Record({
     'street': IDL.Text,
     'city': IDL.Text,
@@ -36,6 +43,7 @@ const idlFactory = ({ IDL }) => {
     'postal_code': IDL.Text,
     'country': IDL.Text,
     'coordinates': IDL.Opt(Coordinates),
+    'extras': IDL.Opt(AddressExtras),
   });
 
   const Dimensions = IDL.Record({
@@ -67,6 +75,7 @@ const idlFactory = ({ IDL }) => {
 
   const PaymentStatus = IDL.Variant({
     'Pending': IDL.Null,
+    'Processing': IDL.Null,
     'Paid': IDL.Null,
     'Failed': IDL.Null,
     'Refunded': IDL.Null,
@@ -97,10 +106,50 @@ const idlFactory = ({ IDL }) => {
     'tracking_history': IDL.Vec(TrackingEvent),
     'payment_status': PaymentStatus,
     'cost': IDL.Float64,
+    'version': IDL.Opt(IDL.Nat64),
+  });
+
+  const CapacityResource = IDL.Variant({
+    'WeightKg': IDL.Null,
+    'VolumeCm3': IDL.Null,
+  });
+
+  const FieldError = IDL.Record({
+    'field': IDL.Text,
+    'message': IDL.Text,
+  });
+
+  const ShippingError = IDL.Variant({
+    'CapacityExceeded': IDL.Record({ 'resource': CapacityResource, 'required': IDL.Float64, 'available': IDL.Float64 }),
+    'RateLimited': IDL.Record({ 'retry_after_secs': IDL.Nat64 }),
+    'Validation': IDL.Record({ 'errors': IDL.Vec(FieldError) }),
+    'Conflict': IDL.Record({ 'expected_version': IDL.Nat64, 'current': Shipment }),
+    'Rejected': IDL.Record({ 'message': IDL.Text }),
+  });
+
+  const ShipmentSummary = IDL.Record({
+    'id': IDL.Text,
+    'short_code': IDL.Opt(IDL.Text),
+    'recipient_name': IDL.Text,
+    'status': ShipmentStatus,
+    'payment_status': PaymentStatus,
+    'cost': IDL.Float64,
+    'estimated_delivery': IDL.Opt(IDL.Nat64),
+    'last_event': IDL.Opt(TrackingEvent),
+    'created_at': IDL.Nat64,
+    'updated_at': IDL.Nat64,
+    'version': IDL.Nat64,
   });
 
   const VehicleInfo = IDL.Record({
-    'vehicle_type': IDL.Text,
+    'vehicle_type': IDL.Variant({
+      'Bicycle': IDL.Null,
+      'Motorbike': IDL.Null,
+      'Car': IDL.Null,
+      'Van': IDL.Null,
+      'Truck': IDL.Null,
+      'Refrigerated': IDL.Null,
+    }),
     'license_plate': IDL.Text,
     'capacity': IDL.Float64,
   });
@@ -149,17 +198,51 @@ const idlFactory = ({ IDL }) => {
     'get_current_user': IDL.Func([], [IDL.Opt(User)], ['query']),
     'create_shipment': IDL.Func([IDL.Text, IDL.Text, Address, Address, PackageDetails], [IDL.Variant({ 'Ok': Shipment, 'Err': IDL.Text })], []),
     'get_shipment': IDL.Func([IDL.Text], [IDL.Opt(Shipment)], ['query']),
-    'get_user_shipments': IDL.Func([], [IDL.Vec(Shipment)], ['query']),
-    'update_shipment_status': IDL.Func([IDL.Text, ShipmentStatus, IDL.Opt(IDL.Text), IDL.Text], [IDL.Variant({ 'Ok': Shipment, 'Err': IDL.Text })], []),
+    'get_user_shipments': IDL.Func([], [IDL.Vec(ShipmentSummary)], ['query']),
+    'v2_update_shipment_status': IDL.Func([IDL.Text, ShipmentStatus, IDL.Opt(IDL.Text), IDL.Text, IDL.Opt(IDL.Nat64)], [IDL.Variant({ 'Ok': Shipment, 'Err': ShippingError })], []),
     'register_driver': IDL.Func([IDL.Text, IDL.Text, VehicleInfo], [IDL.Variant({ 'Ok': Driver, 'Err': IDL.Text })], []),
     'get_available_drivers': IDL.Func([], [IDL.Vec(Driver)], ['query']),
-    'assign_driver_to_shipment': IDL.Func([IDL.Text, IDL.Principal], [IDL.Variant({ 'Ok': Shipment, 'Err': IDL.Text })], []),
+    'v2_assign_driver_to_shipment': IDL.Func([IDL.Text, IDL.Principal, IDL.Opt(IDL.Nat64)], [IDL.Variant({ 'Ok': Shipment, 'Err': ShippingError })], []),
     'create_return_request': IDL.Func([IDL.Text, IDL.Text], [IDL.Variant({ 'Ok': ReturnRequest, 'Err': IDL.Text })], []),
     'get_return_requests': IDL.Func([], [IDL.Vec(ReturnRequest)], ['query']),
     'get_platform_stats': IDL.Func([], [PlatformStats], ['query']),
   });
 };
 
+// Typed errors keep their details on the thrown Error; conflicts carry the shipment as
+// it is now, so callers can show it and retry
+const shippingError = (err) => {
+  if ('CapacityExceeded' in err) {
+    const { resource, required, available } = err.CapacityExceeded;
+    const unit = 'WeightKg' in resource ? 'kg' : 'cm³';
+    const error = new Error(
+      `The driver's vehicle is full: needs ${required.toFixed(2)} ${unit}, ${available.toFixed(2)} ${unit} left`
+    );
+    error.capacity = err.CapacityExceeded;
+    return error;
+  }
+  if ('RateLimited' in err) {
+    const retryAfter = Number(err.RateLimited.retry_after_secs);
+    const error = new Error(`Too many requests; try again in ${retryAfter} seconds`);
+    error.retryAfterSecs = retryAfter;
+    return error;
+  }
+  if ('Validation' in err) {
+    const { errors } = err.Validation;
+    const error = new Error(`Invalid input: ${errors.map((e) => `${e.field} ${e.message}`).join('; ')}`);
+    error.fieldErrors = errors;
+    return error;
+  }
+  if ('Conflict' in err) {
+    const error = new Error('The shipment was changed by someone else; reload and try again');
+    error.current = err.Conflict.current;
+    return error;
+  }
+  return new Error(err.Rejected.message);
+};
+
+const optionalVersion = (version) => (version === undefined || version === null ? [] : [BigInt(version)]);
+
 class ICPService {
   constructor() {
     this.authClient = null;
@@ -287,19 +370,20 @@ class ICPService {
     return await this.actor.get_user_shipments();
   }
 
-  async updateShipmentStatus(shipmentId, status, location, description) {
+  async updateShipmentStatus(shipmentId, status, location, description, expectedVersion) {
     if (!this.actor) throw new Error('Not authenticated');
     
     const statusVariant = { [status]: null };
-    const result = await this.actor.update_shipment_status(
+    const result = await this.actor.v2_update_shipment_status(
       shipmentId,
       statusVariant,
       location ? [location] : [],
-      description
+      description,
+      optionalVersion(expectedVersion)
     );
     
     if ('Err' in result) {
-      throw new Error(result.Err);
+      throw shippingError(result.Err);
     }
     return result.Ok;
   }
@@ -321,16 +405,17 @@ class ICPService {
     return await this.actor.get_available_drivers();
   }
 
-  async assignDriverToShipment(shipmentId, driverId) {
+  async assignDriverToShipment(shipmentId, driverId, expectedVersion) {
     if (!this.actor) throw new Error('Not authenticated');
     
-    const result = await this.actor.assign_driver_to_shipment(
+    const result = await this.actor.v2_assign_driver_to_shipment(
       shipmentId,
-      Principal.fromText(driverId)
+      Principal.fromText(driverId),
+      optionalVersion(expectedVersion)
     );
     
     if ('Err' in result) {
-      throw new Error(result.Err);
+      throw shippingError(result.Err);
     }
     return result.Ok;
   }